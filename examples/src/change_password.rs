@@ -1,7 +1,6 @@
 #![deny(warnings)]
-use rencfs::crypto::Cipher;
+use rencfs::crypto::{Cipher, LockedString};
 use rencfs::encryptedfs::{EncryptedFs, FsError};
-use secrecy::SecretString;
 use std::env::args;
 use std::path::Path;
 use std::str::FromStr;
@@ -16,8 +15,8 @@ async fn main() {
 
     match EncryptedFs::passwd(
         Path::new(&data_dir),
-        SecretString::from_str("old-pass").unwrap(),
-        SecretString::from_str("new-pass").unwrap(),
+        LockedString::from_str("old-pass").unwrap(),
+        LockedString::from_str("new-pass").unwrap(),
         Cipher::ChaCha20Poly1305,
     )
     .await
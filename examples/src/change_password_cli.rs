@@ -4,9 +4,10 @@ use std::io;
 use std::io::Write;
 
 use rpassword::read_password;
-use secrecy::{ExposeSecret, SecretString};
+use secrecy::ExposeSecret;
 use tracing::{error, info};
 
+use rencfs::crypto::LockedString;
 use rencfs::encryptedfs::{EncryptedFs, FsError};
 
 #[tokio::main]
@@ -22,13 +23,13 @@ async fn main() {
     use rencfs::crypto::Cipher;
     print!("Enter old password: ");
     io::stdout().flush().unwrap();
-    let old_password = SecretString::new(read_password().unwrap());
+    let old_password = LockedString::new(read_password().unwrap());
     print!("Enter new password: ");
     io::stdout().flush().unwrap();
-    let new_password = SecretString::new(read_password().unwrap());
+    let new_password = LockedString::new(read_password().unwrap());
     print!("Confirm new password: ");
     io::stdout().flush().unwrap();
-    let new_password2 = SecretString::new(read_password().unwrap());
+    let new_password2 = LockedString::new(read_password().unwrap());
     if new_password.expose_secret() != new_password2.expose_secret() {
         error!("Passwords do not match");
         return;
@@ -6,18 +6,18 @@ use std::str::FromStr;
 use anyhow::Result;
 use secrecy::SecretString;
 
-use rencfs::crypto::Cipher;
+use rencfs::crypto::{Cipher, LockedString};
 use rencfs::encryptedfs::write_all_string_to_fs;
-use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+use rencfs::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider};
 
 const ROOT_INODE: u64 = 1;
 
 struct PasswordProviderImpl {}
 
 impl PasswordProvider for PasswordProviderImpl {
-    fn get_password(&self) -> Option<SecretString> {
+    fn get_password(&self) -> Option<LockedString> {
         // dummy password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-        Some(SecretString::from_str("pass42").unwrap())
+        Some(LockedString::from_str("pass42").unwrap())
     }
 }
 
@@ -28,7 +28,13 @@ async fn main() -> Result<()> {
     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
     let _ = fs::remove_dir_all(data_dir.to_str().unwrap());
     let cipher = Cipher::ChaCha20Poly1305;
-    let fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl {}), cipher).await?;
+    let fs = EncryptedFs::new(
+        data_dir.clone(),
+        Box::new(PasswordProviderImpl {}),
+        cipher,
+        FsOptions::default().with_create(true),
+    )
+    .await?;
 
     let file1 = SecretString::from_str("file1").unwrap();
     let (fh, attr) = fs
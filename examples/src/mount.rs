@@ -5,11 +5,10 @@ use std::path::Path;
 use std::str::FromStr;
 
 use anyhow::Result;
-use secrecy::SecretString;
 use tracing::info;
 
-use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::PasswordProvider;
+use rencfs::crypto::{Cipher, LockedString};
+use rencfs::encryptedfs::{FsOptions, PasswordProvider};
 use rencfs::mount::create_mount_point;
 use rencfs::mount::MountPoint;
 
@@ -26,9 +25,9 @@ async fn main() -> Result<()> {
     println!("data_path: {data_path}");
     struct PasswordProviderImpl {}
     impl PasswordProvider for PasswordProviderImpl {
-        fn get_password(&self) -> Option<SecretString> {
+        fn get_password(&self) -> Option<LockedString> {
             // dummy password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-            Some(SecretString::from_str("pass42").unwrap())
+            Some(LockedString::from_str("pass42").unwrap())
         }
     }
     let mount_point = create_mount_point(
@@ -40,6 +39,7 @@ async fn main() -> Result<()> {
         false,
         false,
         false,
+        FsOptions::default(),
     );
     let handle = mount_point.mount().await?;
     let mut buffer = String::new();
@@ -0,0 +1,12 @@
+use std::env;
+use std::path::Path;
+
+/// Generate `include/encryptedfs.h` from the `extern "C"` functions.
+fn main() {
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    cbindgen::generate(&dir)
+        .expect("generating the header")
+        .write_to_file(Path::new(&dir).join("include").join("encryptedfs.h"));
+}
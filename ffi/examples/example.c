@@ -0,0 +1,54 @@
+/*
+ * Write a file to the volume and list it back.
+ *
+ *   cargo build --release -p rencfs-ffi
+ *   cc ffi/examples/example.c -I ffi/include -L target/release -lencryptedfs -o example
+ *   LD_LIBRARY_PATH=target/release ./example DATA_DIR PASSWORD --create
+ *
+ * Without --create it opens the volume already in DATA_DIR.
+ */
+#include <stdio.h>
+#include <string.h>
+
+#include "encryptedfs.h"
+
+static void print_entry(void *ctx, const char *name, const EncryptedFsStat *stat) {
+    (void) ctx;
+    printf("%s %s %llu\n", stat->is_dir ? "dir " : "file", name, (unsigned long long) stat->size);
+}
+
+int main(int argc, char **argv) {
+    int create = argc == 4 && strcmp(argv[3], "--create") == 0;
+    if (argc != 3 && !create) {
+        fprintf(stderr, "usage: %s DATA_DIR PASSWORD [--create]\n", argv[0]);
+        return 1;
+    }
+    EncryptedFsVolume *volume = create ? encryptedfs_create_volume(argv[1], argv[2], NULL)
+                                       : encryptedfs_open_volume(argv[1], argv[2], NULL);
+    if (volume == NULL) {
+        fprintf(stderr, "open volume: %s\n", encryptedfs_last_error());
+        return 1;
+    }
+
+    EncryptedFsFile *file = encryptedfs_open(volume, "/hello.txt", ENCRYPTEDFS_OPEN_CREATE);
+    if (file == NULL) {
+        fprintf(stderr, "open: %s\n", encryptedfs_last_error());
+        encryptedfs_close_volume(volume);
+        return 1;
+    }
+    const char *data = "Hello, world!";
+    if (encryptedfs_write(file, (const uint8_t *) data, strlen(data)) < 0 || encryptedfs_close(file) != ENCRYPTEDFS_OK) {
+        fprintf(stderr, "write: %s\n", encryptedfs_last_error());
+        encryptedfs_close_volume(volume);
+        return 1;
+    }
+
+    file = encryptedfs_open(volume, "/hello.txt", ENCRYPTEDFS_OPEN_READ);
+    char buf[64] = {0};
+    int64_t len = encryptedfs_read(file, (uint8_t *) buf, sizeof(buf) - 1);
+    encryptedfs_close(file);
+    printf("read %lld bytes: %s\n", (long long) len, buf);
+
+    encryptedfs_read_dir(volume, "/", print_entry, NULL);
+    return encryptedfs_close_volume(volume) == ENCRYPTEDFS_OK ? 0 : 1;
+}
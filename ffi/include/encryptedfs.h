@@ -0,0 +1,267 @@
+#ifndef ENCRYPTEDFS_H
+#define ENCRYPTEDFS_H
+
+/* Generated by cbindgen from src/lib.rs, don't edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define ENCRYPTEDFS_OK 0
+
+/**
+ * The file or dir doesn't exist.
+ */
+#define ENCRYPTEDFS_ERROR_NOT_FOUND -1
+
+#define ENCRYPTEDFS_ERROR_ALREADY_EXISTS -2
+
+/**
+ * Removing a dir that has entries.
+ */
+#define ENCRYPTEDFS_ERROR_NOT_EMPTY -3
+
+#define ENCRYPTEDFS_ERROR_INVALID_PASSWORD -4
+
+/**
+ * The volume or the file can't be changed.
+ */
+#define ENCRYPTEDFS_ERROR_READ_ONLY -5
+
+/**
+ * Like a `NULL` or not UTF-8 argument, or a file where a dir is expected.
+ */
+#define ENCRYPTEDFS_ERROR_INVALID_ARGUMENT -6
+
+/**
+ * The file is open for writing somewhere else.
+ */
+#define ENCRYPTEDFS_ERROR_BUSY -7
+
+#define ENCRYPTEDFS_ERROR_IO -8
+
+#define ENCRYPTEDFS_ERROR_OTHER -9
+
+/**
+ * Open for reading.
+ */
+#define ENCRYPTEDFS_OPEN_READ 0
+
+/**
+ * Open for reading and writing, without truncating.
+ */
+#define ENCRYPTEDFS_OPEN_WRITE 1
+
+/**
+ * Open for reading and writing, created if it doesn't exist and truncated if it does.
+ */
+#define ENCRYPTEDFS_OPEN_CREATE 2
+
+#define ENCRYPTEDFS_SEEK_SET 0
+
+#define ENCRYPTEDFS_SEEK_CUR 1
+
+#define ENCRYPTEDFS_SEEK_END 2
+
+/**
+ * An open file, from [`encryptedfs_open`].
+ */
+typedef struct EncryptedFsFile EncryptedFsFile;
+
+/**
+ * An open volume, from [`encryptedfs_open_volume`].
+ */
+typedef struct EncryptedFsVolume EncryptedFsVolume;
+
+/**
+ * Attributes of a file or dir, times are in seconds since the epoch.
+ */
+typedef struct EncryptedFsStat {
+  uint64_t ino;
+  bool is_dir;
+  uint64_t size;
+  uint16_t perm;
+  uint32_t uid;
+  uint32_t gid;
+  int64_t atime;
+  int64_t mtime;
+  int64_t ctime;
+} EncryptedFsStat;
+
+/**
+ * Called by [`encryptedfs_read_dir`] for each entry, `name` and `stat` are valid only during the call.
+ */
+typedef void (*EncryptedFsDirCallback)(void *ctx,
+                                       const char *name,
+                                       const struct EncryptedFsStat *stat);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a volume in `data_dir`, which must not have one already, and open it. `cipher` is `ChaCha20Poly1305` or
+ * `Aes256Gcm`, `NULL` for the first. Close it with [`encryptedfs_close_volume`].
+ *
+ * # Safety
+ *
+ * The strings must be valid and NUL terminated.
+ */
+struct EncryptedFsVolume *encryptedfs_create_volume(const char *data_dir,
+                                                    const char *password,
+                                                    const char *cipher);
+
+/**
+ * Open the volume in `data_dir`, created with [`encryptedfs_create_volume`] or `rencfs init`. `cipher` is used only
+ * by volumes created before they had a header, `NULL` for `ChaCha20Poly1305`. Close it with
+ * [`encryptedfs_close_volume`].
+ *
+ * # Safety
+ *
+ * The strings must be valid and NUL terminated.
+ */
+struct EncryptedFsVolume *encryptedfs_open_volume(const char *data_dir,
+                                                  const char *password,
+                                                  const char *cipher);
+
+/**
+ * Save everything to storage and close the volume, even if it fails.
+ *
+ * # Safety
+ *
+ * `volume` must be from [`encryptedfs_open_volume`], its files closed, and it's not valid after this.
+ */
+int32_t encryptedfs_close_volume(struct EncryptedFsVolume *volume);
+
+/**
+ * Open the file at `path`, `mode` is one of `ENCRYPTEDFS_OPEN_*`. Close it with [`encryptedfs_close`].
+ *
+ * # Safety
+ *
+ * `volume` must be open and `path` a valid NUL terminated string.
+ */
+struct EncryptedFsFile *encryptedfs_open(const struct EncryptedFsVolume *volume,
+                                         const char *path,
+                                         int32_t mode);
+
+/**
+ * Read up to `len` bytes into `buf`, returns how many, `0` at the end of the file.
+ *
+ * # Safety
+ *
+ * `file` must be open and `buf` valid for `len` bytes.
+ */
+int64_t encryptedfs_read(struct EncryptedFsFile *file, uint8_t *buf, size_t len);
+
+/**
+ * Write up to `len` bytes from `buf`, returns how many. They are saved on [`encryptedfs_flush`] and
+ * [`encryptedfs_close`].
+ *
+ * # Safety
+ *
+ * `file` must be open and `buf` valid for `len` bytes.
+ */
+int64_t encryptedfs_write(struct EncryptedFsFile *file, const uint8_t *buf, size_t len);
+
+/**
+ * Move to `offset` from where `whence` says, one of `ENCRYPTEDFS_SEEK_*`, returns the new position.
+ *
+ * # Safety
+ *
+ * `file` must be open.
+ */
+int64_t encryptedfs_seek(struct EncryptedFsFile *file, int64_t offset, int32_t whence);
+
+/**
+ * Save the writes.
+ *
+ * # Safety
+ *
+ * `file` must be open.
+ */
+int32_t encryptedfs_flush(struct EncryptedFsFile *file);
+
+/**
+ * Save the writes and close the file, even if saving fails.
+ *
+ * # Safety
+ *
+ * `file` must be from [`encryptedfs_open`], and it's not valid after this.
+ */
+int32_t encryptedfs_close(struct EncryptedFsFile *file);
+
+/**
+ * Attributes of the file or dir at `path`, written to `stat`.
+ *
+ * # Safety
+ *
+ * `volume` must be open, `path` a valid NUL terminated string and `stat` valid for writing.
+ */
+int32_t encryptedfs_stat(const struct EncryptedFsVolume *volume,
+                         const char *path,
+                         struct EncryptedFsStat *stat);
+
+/**
+ * Call `callback` with `ctx` for each entry of the dir at `path`, without `.` and `..`.
+ *
+ * # Safety
+ *
+ * `volume` must be open and `path` a valid NUL terminated string.
+ */
+int32_t encryptedfs_read_dir(const struct EncryptedFsVolume *volume,
+                             const char *path,
+                             EncryptedFsDirCallback callback,
+                             void *ctx);
+
+/**
+ * Create a dir at `path`, its parent must exist.
+ *
+ * # Safety
+ *
+ * `volume` must be open and `path` a valid NUL terminated string.
+ */
+int32_t encryptedfs_mkdir(const struct EncryptedFsVolume *volume, const char *path);
+
+/**
+ * Remove the file or the empty dir at `path`.
+ *
+ * # Safety
+ *
+ * `volume` must be open and `path` a valid NUL terminated string.
+ */
+int32_t encryptedfs_remove(const struct EncryptedFsVolume *volume, const char *path);
+
+/**
+ * Move the file or dir at `from` to `to`, replacing what's there.
+ *
+ * # Safety
+ *
+ * `volume` must be open and the paths valid NUL terminated strings.
+ */
+int32_t encryptedfs_rename(const struct EncryptedFsVolume *volume,
+                           const char *from,
+                           const char *to);
+
+/**
+ * Truncate or extend with zeros the file at `path` to `size`.
+ *
+ * # Safety
+ *
+ * `volume` must be open and `path` a valid NUL terminated string.
+ */
+int32_t encryptedfs_truncate(const struct EncryptedFsVolume *volume,
+                             const char *path,
+                             uint64_t size);
+
+/**
+ * Message of the last error on this thread, `NULL` if there was none. It's valid until the next error on the thread.
+ */
+const char *encryptedfs_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ENCRYPTEDFS_H */
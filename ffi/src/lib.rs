@@ -0,0 +1,658 @@
+//! C bindings for [`rencfs`], to open a volume and work with its files by path, from C, C++, Python, Go and others,
+//! without mounting it. The header is `include/encryptedfs.h`, generated on build.
+//!
+//! Functions returning `int32_t` give `0` on success or one of the negative `ENCRYPTEDFS_ERROR_*` codes, the ones
+//! returning `int64_t` give a count or position, or a negative code, and the ones returning a pointer give `NULL` on
+//! error. The message of the last error on the thread is in [`encryptedfs_last_error`].
+//!
+//! Calls block until done. A volume can be used from several threads, a file from one at a time. Close the files before
+//! their volume.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rencfs::crypto::{Cipher, LockedString};
+use rencfs::encryptedfs::{
+    BlockingEncryptedFile, EncryptedFile, EncryptedFs, FileAttr, FileType, FsError, FsOptions,
+};
+use tokio::runtime::Runtime;
+
+pub const ENCRYPTEDFS_OK: i32 = 0;
+/// The file or dir doesn't exist.
+pub const ENCRYPTEDFS_ERROR_NOT_FOUND: i32 = -1;
+pub const ENCRYPTEDFS_ERROR_ALREADY_EXISTS: i32 = -2;
+/// Removing a dir that has entries.
+pub const ENCRYPTEDFS_ERROR_NOT_EMPTY: i32 = -3;
+pub const ENCRYPTEDFS_ERROR_INVALID_PASSWORD: i32 = -4;
+/// The volume or the file can't be changed.
+pub const ENCRYPTEDFS_ERROR_READ_ONLY: i32 = -5;
+/// Like a `NULL` or not UTF-8 argument, or a file where a dir is expected.
+pub const ENCRYPTEDFS_ERROR_INVALID_ARGUMENT: i32 = -6;
+/// The file is open for writing somewhere else.
+pub const ENCRYPTEDFS_ERROR_BUSY: i32 = -7;
+pub const ENCRYPTEDFS_ERROR_IO: i32 = -8;
+pub const ENCRYPTEDFS_ERROR_OTHER: i32 = -9;
+
+/// Open for reading.
+pub const ENCRYPTEDFS_OPEN_READ: i32 = 0;
+/// Open for reading and writing, without truncating.
+pub const ENCRYPTEDFS_OPEN_WRITE: i32 = 1;
+/// Open for reading and writing, created if it doesn't exist and truncated if it does.
+pub const ENCRYPTEDFS_OPEN_CREATE: i32 = 2;
+
+pub const ENCRYPTEDFS_SEEK_SET: i32 = 0;
+pub const ENCRYPTEDFS_SEEK_CUR: i32 = 1;
+pub const ENCRYPTEDFS_SEEK_END: i32 = 2;
+
+/// An open volume, from [`encryptedfs_open_volume`].
+pub struct EncryptedFsVolume {
+    runtime: Runtime,
+    fs: Arc<EncryptedFs>,
+}
+
+/// An open file, from [`encryptedfs_open`].
+pub struct EncryptedFsFile(BlockingEncryptedFile);
+
+/// Attributes of a file or dir, times are in seconds since the epoch.
+#[repr(C)]
+pub struct EncryptedFsStat {
+    pub ino: u64,
+    pub is_dir: bool,
+    pub size: u64,
+    pub perm: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+}
+
+/// Called by [`encryptedfs_read_dir`] for each entry, `name` and `stat` are valid only during the call.
+pub type EncryptedFsDirCallback = Option<
+    unsafe extern "C" fn(ctx: *mut c_void, name: *const c_char, stat: *const EncryptedFsStat),
+>;
+
+/// Create a volume in `data_dir`, which must not have one already, and open it. `cipher` is `ChaCha20Poly1305` or
+/// `Aes256Gcm`, `NULL` for the first. Close it with [`encryptedfs_close_volume`].
+///
+/// # Safety
+///
+/// The strings must be valid and NUL terminated.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_create_volume(
+    data_dir: *const c_char,
+    password: *const c_char,
+    cipher: *const c_char,
+) -> *mut EncryptedFsVolume {
+    call(|| open_volume(data_dir, password, cipher, true)).map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// Open the volume in `data_dir`, created with [`encryptedfs_create_volume`] or `rencfs init`. `cipher` is used only
+/// by volumes created before they had a header, `NULL` for `ChaCha20Poly1305`. Close it with
+/// [`encryptedfs_close_volume`].
+///
+/// # Safety
+///
+/// The strings must be valid and NUL terminated.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_open_volume(
+    data_dir: *const c_char,
+    password: *const c_char,
+    cipher: *const c_char,
+) -> *mut EncryptedFsVolume {
+    call(|| open_volume(data_dir, password, cipher, false)).map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// Save everything to storage and close the volume, even if it fails.
+///
+/// # Safety
+///
+/// `volume` must be from [`encryptedfs_open_volume`], its files closed, and it's not valid after this.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_close_volume(volume: *mut EncryptedFsVolume) -> i32 {
+    code(call(|| {
+        if volume.is_null() {
+            return Err(null_arg("volume"));
+        }
+        let volume = Box::from_raw(volume);
+        volume.runtime.block_on(volume.fs.sync_storage())?;
+        Ok(())
+    }))
+}
+
+/// Open the file at `path`, `mode` is one of `ENCRYPTEDFS_OPEN_*`. Close it with [`encryptedfs_close`].
+///
+/// # Safety
+///
+/// `volume` must be open and `path` a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_open(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+    mode: i32,
+) -> *mut EncryptedFsFile {
+    call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        let fs = volume.fs.clone();
+        let file = volume.runtime.block_on(async {
+            match mode {
+                ENCRYPTEDFS_OPEN_READ => EncryptedFile::open(fs, path).await,
+                ENCRYPTEDFS_OPEN_WRITE => {
+                    let ino = fs.resolve_path(path).await?.ino;
+                    EncryptedFile::from_ino(fs, ino, true).await
+                }
+                ENCRYPTEDFS_OPEN_CREATE => EncryptedFile::create(fs, path).await,
+                _ => Err(FsError::InvalidInput("unknown open mode")),
+            }
+        })?;
+        Ok(Box::new(EncryptedFsFile(file.into_blocking())))
+    })
+    .map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// Read up to `len` bytes into `buf`, returns how many, `0` at the end of the file.
+///
+/// # Safety
+///
+/// `file` must be open and `buf` valid for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_read(
+    file: *mut EncryptedFsFile,
+    buf: *mut u8,
+    len: usize,
+) -> i64 {
+    count(call(|| {
+        let file = file_arg(file)?;
+        if buf.is_null() {
+            return Err(null_arg("buf"));
+        }
+        Ok(file.0.read(std::slice::from_raw_parts_mut(buf, len))? as u64)
+    }))
+}
+
+/// Write up to `len` bytes from `buf`, returns how many. They are saved on [`encryptedfs_flush`] and
+/// [`encryptedfs_close`].
+///
+/// # Safety
+///
+/// `file` must be open and `buf` valid for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_write(
+    file: *mut EncryptedFsFile,
+    buf: *const u8,
+    len: usize,
+) -> i64 {
+    count(call(|| {
+        let file = file_arg(file)?;
+        if buf.is_null() {
+            return Err(null_arg("buf"));
+        }
+        Ok(file.0.write(std::slice::from_raw_parts(buf, len))? as u64)
+    }))
+}
+
+/// Move to `offset` from where `whence` says, one of `ENCRYPTEDFS_SEEK_*`, returns the new position.
+///
+/// # Safety
+///
+/// `file` must be open.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_seek(
+    file: *mut EncryptedFsFile,
+    offset: i64,
+    whence: i32,
+) -> i64 {
+    count(call(|| {
+        let file = file_arg(file)?;
+        let pos = match whence {
+            ENCRYPTEDFS_SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| {
+                Error(ENCRYPTEDFS_ERROR_INVALID_ARGUMENT, "negative offset".into())
+            })?),
+            ENCRYPTEDFS_SEEK_CUR => SeekFrom::Current(offset),
+            ENCRYPTEDFS_SEEK_END => SeekFrom::End(offset),
+            _ => {
+                return Err(Error(
+                    ENCRYPTEDFS_ERROR_INVALID_ARGUMENT,
+                    "unknown whence".into(),
+                ))
+            }
+        };
+        Ok(file.0.seek(pos)?)
+    }))
+}
+
+/// Save the writes.
+///
+/// # Safety
+///
+/// `file` must be open.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_flush(file: *mut EncryptedFsFile) -> i32 {
+    code(call(|| Ok(file_arg(file)?.0.flush()?)))
+}
+
+/// Save the writes and close the file, even if saving fails.
+///
+/// # Safety
+///
+/// `file` must be from [`encryptedfs_open`], and it's not valid after this.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_close(file: *mut EncryptedFsFile) -> i32 {
+    code(call(|| {
+        if file.is_null() {
+            return Err(null_arg("file"));
+        }
+        let mut file = Box::from_raw(file);
+        Ok(file.0.flush()?)
+    }))
+}
+
+/// Attributes of the file or dir at `path`, written to `stat`.
+///
+/// # Safety
+///
+/// `volume` must be open, `path` a valid NUL terminated string and `stat` valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_stat(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+    stat: *mut EncryptedFsStat,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        if stat.is_null() {
+            return Err(null_arg("stat"));
+        }
+        let attr = volume.runtime.block_on(volume.fs.metadata(path))?;
+        stat.write(to_stat(&attr));
+        Ok(())
+    }))
+}
+
+/// Call `callback` with `ctx` for each entry of the dir at `path`, without `.` and `..`.
+///
+/// # Safety
+///
+/// `volume` must be open and `path` a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_read_dir(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+    callback: EncryptedFsDirCallback,
+    ctx: *mut c_void,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        let callback = callback.ok_or_else(|| null_arg("callback"))?;
+        for entry in volume.runtime.block_on(volume.fs.read_dir_path(path))? {
+            let name = CString::new(secrecy::ExposeSecret::expose_secret(&entry.name).as_str())
+                .map_err(|_| Error(ENCRYPTEDFS_ERROR_OTHER, "name has a NUL".into()))?;
+            let stat = to_stat(&entry.attr);
+            callback(ctx, name.as_ptr(), &stat);
+        }
+        Ok(())
+    }))
+}
+
+/// Create a dir at `path`, its parent must exist.
+///
+/// # Safety
+///
+/// `volume` must be open and `path` a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_mkdir(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        volume.runtime.block_on(volume.fs.create_dir_path(path))?;
+        Ok(())
+    }))
+}
+
+/// Remove the file or the empty dir at `path`.
+///
+/// # Safety
+///
+/// `volume` must be open and `path` a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_remove(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        Ok(volume.runtime.block_on(volume.fs.remove_path(path))?)
+    }))
+}
+
+/// Move the file or dir at `from` to `to`, replacing what's there.
+///
+/// # Safety
+///
+/// `volume` must be open and the paths valid NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_rename(
+    volume: *const EncryptedFsVolume,
+    from: *const c_char,
+    to: *const c_char,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let from = str_arg(from, "from")?;
+        let to = str_arg(to, "to")?;
+        Ok(volume.runtime.block_on(volume.fs.rename_path(from, to))?)
+    }))
+}
+
+/// Truncate or extend with zeros the file at `path` to `size`.
+///
+/// # Safety
+///
+/// `volume` must be open and `path` a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn encryptedfs_truncate(
+    volume: *const EncryptedFsVolume,
+    path: *const c_char,
+    size: u64,
+) -> i32 {
+    code(call(|| {
+        let volume = volume_arg(volume)?;
+        let path = str_arg(path, "path")?;
+        Ok(volume
+            .runtime
+            .block_on(volume.fs.set_len_path(path, size))?)
+    }))
+}
+
+/// Message of the last error on this thread, `NULL` if there was none. It's valid until the next error on the thread.
+#[no_mangle]
+pub extern "C" fn encryptedfs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// One of the `ENCRYPTEDFS_ERROR_*` codes and the message.
+struct Error(i32, String);
+
+impl From<FsError> for Error {
+    fn from(err: FsError) -> Self {
+        let code = match err {
+            FsError::NotFound(_) | FsError::InodeNotFound | FsError::NoVolume => {
+                ENCRYPTEDFS_ERROR_NOT_FOUND
+            }
+            FsError::AlreadyExists => ENCRYPTEDFS_ERROR_ALREADY_EXISTS,
+            FsError::NotEmpty => ENCRYPTEDFS_ERROR_NOT_EMPTY,
+            FsError::InvalidPassword => ENCRYPTEDFS_ERROR_INVALID_PASSWORD,
+            FsError::ReadOnly | FsError::Excluded => ENCRYPTEDFS_ERROR_READ_ONLY,
+            FsError::InvalidInput(_) | FsError::InvalidInodeType => {
+                ENCRYPTEDFS_ERROR_INVALID_ARGUMENT
+            }
+            FsError::AlreadyOpenForWrite => ENCRYPTEDFS_ERROR_BUSY,
+            FsError::Io { .. } => ENCRYPTEDFS_ERROR_IO,
+            _ => ENCRYPTEDFS_ERROR_OTHER,
+        };
+        Self(code, err.to_string())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        // errors from the volume are kept inside
+        if err.get_ref().is_some_and(|inner| inner.is::<FsError>()) {
+            let message = err.to_string();
+            let err = *err.into_inner().unwrap().downcast::<FsError>().unwrap();
+            return Self(Self::from(err).0, message);
+        }
+        let code = match err.kind() {
+            io::ErrorKind::NotFound => ENCRYPTEDFS_ERROR_NOT_FOUND,
+            io::ErrorKind::PermissionDenied => ENCRYPTEDFS_ERROR_READ_ONLY,
+            io::ErrorKind::InvalidInput => ENCRYPTEDFS_ERROR_INVALID_ARGUMENT,
+            _ => ENCRYPTEDFS_ERROR_IO,
+        };
+        Self(code, err.to_string())
+    }
+}
+
+/// Run `f`, keeping the message if it fails or panics.
+fn call<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, i32> {
+    let res = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(Error(ENCRYPTEDFS_ERROR_OTHER, "panicked".into())));
+    res.map_err(|Error(code, message)| {
+        let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+        code
+    })
+}
+
+fn code(res: Result<(), i32>) -> i32 {
+    res.err().unwrap_or(ENCRYPTEDFS_OK)
+}
+
+fn count(res: Result<u64, i32>) -> i64 {
+    res.map_or_else(i64::from, |len| i64::try_from(len).unwrap_or(i64::MAX))
+}
+
+fn null_arg(name: &str) -> Error {
+    Error(
+        ENCRYPTEDFS_ERROR_INVALID_ARGUMENT,
+        format!("{name} is NULL"),
+    )
+}
+
+/// For [`encryptedfs_create_volume`] and [`encryptedfs_open_volume`].
+unsafe fn open_volume(
+    data_dir: *const c_char,
+    password: *const c_char,
+    cipher: *const c_char,
+    create: bool,
+) -> Result<Box<EncryptedFsVolume>, Error> {
+    let data_dir = Path::new(str_arg(data_dir, "data_dir")?);
+    let password = LockedString::new(str_arg(password, "password")?.to_string());
+    let cipher = if cipher.is_null() {
+        Cipher::ChaCha20Poly1305
+    } else {
+        Cipher::from_str(str_arg(cipher, "cipher")?)
+            .map_err(|_| Error(ENCRYPTEDFS_ERROR_INVALID_ARGUMENT, "unknown cipher".into()))?
+    };
+    let runtime = Runtime::new()?;
+    let fs = runtime.block_on(async {
+        if create {
+            EncryptedFs::create_volume(data_dir, password, cipher, FsOptions::default()).await
+        } else {
+            EncryptedFs::open_volume(data_dir, password, cipher, FsOptions::default()).await
+        }
+    })?;
+    Ok(Box::new(EncryptedFsVolume { runtime, fs }))
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(null_arg(name));
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| {
+        Error(
+            ENCRYPTEDFS_ERROR_INVALID_ARGUMENT,
+            format!("{name} is not UTF-8"),
+        )
+    })
+}
+
+unsafe fn volume_arg<'a>(ptr: *const EncryptedFsVolume) -> Result<&'a EncryptedFsVolume, Error> {
+    ptr.as_ref().ok_or_else(|| null_arg("volume"))
+}
+
+unsafe fn file_arg<'a>(ptr: *mut EncryptedFsFile) -> Result<&'a mut EncryptedFsFile, Error> {
+    ptr.as_mut().ok_or_else(|| null_arg("file"))
+}
+
+fn to_stat(attr: &FileAttr) -> EncryptedFsStat {
+    EncryptedFsStat {
+        ino: attr.ino,
+        is_dir: attr.kind == FileType::Directory,
+        size: attr.size,
+        perm: attr.perm,
+        uid: attr.uid,
+        gid: attr.gid,
+        atime: secs(attr.atime),
+        mtime: secs(attr.mtime),
+        ctime: secs(attr.ctime),
+    }
+}
+
+fn secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| {
+        i64::try_from(since.as_secs()).unwrap_or(i64::MAX)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe extern "C" fn collect(
+        ctx: *mut c_void,
+        name: *const c_char,
+        stat: *const EncryptedFsStat,
+    ) {
+        let names = &mut *ctx.cast::<Vec<(String, u64)>>();
+        names.push((
+            CStr::from_ptr(name).to_str().unwrap().to_string(),
+            (*stat).size,
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::too_many_lines)]
+    fn test_ffi() {
+        let data_dir = std::env::temp_dir().join(format!("rencfs-ffi-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let data_dir = c(data_dir.to_str().unwrap());
+        unsafe {
+            assert!(
+                encryptedfs_open_volume(data_dir.as_ptr(), c("pass").as_ptr(), ptr::null())
+                    .is_null()
+            );
+            assert_eq!(
+                "there is no volume in the data directory",
+                CStr::from_ptr(encryptedfs_last_error()).to_str().unwrap()
+            );
+            let volume =
+                encryptedfs_create_volume(data_dir.as_ptr(), c("pass").as_ptr(), ptr::null());
+            assert!(!volume.is_null());
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_mkdir(volume, c("/docs").as_ptr())
+            );
+            assert_eq!(
+                ENCRYPTEDFS_ERROR_ALREADY_EXISTS,
+                encryptedfs_mkdir(volume, c("/docs").as_ptr())
+            );
+            assert_eq!(
+                "already exists",
+                CStr::from_ptr(encryptedfs_last_error()).to_str().unwrap()
+            );
+
+            let file = encryptedfs_open(volume, c("/docs/a").as_ptr(), ENCRYPTEDFS_OPEN_CREATE);
+            assert!(!file.is_null());
+            assert_eq!(11, encryptedfs_write(file, b"hello world".as_ptr(), 11));
+            assert_eq!(6, encryptedfs_seek(file, -5, ENCRYPTEDFS_SEEK_END));
+            let mut buf = [0; 16];
+            assert_eq!(5, encryptedfs_read(file, buf.as_mut_ptr(), buf.len()));
+            assert_eq!(b"world", &buf[..5]);
+            assert_eq!(0, encryptedfs_read(file, buf.as_mut_ptr(), buf.len()));
+            assert_eq!(ENCRYPTEDFS_OK, encryptedfs_close(file));
+
+            let file = encryptedfs_open(volume, c("/docs/a").as_ptr(), ENCRYPTEDFS_OPEN_READ);
+            assert!(encryptedfs_write(file, b"x".as_ptr(), 1) < 0);
+            assert_eq!(ENCRYPTEDFS_OK, encryptedfs_close(file));
+            assert!(
+                encryptedfs_open(volume, c("/missing").as_ptr(), ENCRYPTEDFS_OPEN_READ).is_null()
+            );
+
+            let mut stat = std::mem::zeroed::<EncryptedFsStat>();
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_stat(volume, c("/docs").as_ptr(), &mut stat)
+            );
+            assert!(stat.is_dir);
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_truncate(volume, c("/docs/a").as_ptr(), 5)
+            );
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_rename(volume, c("/docs/a").as_ptr(), c("/docs/b").as_ptr())
+            );
+            let mut names: Vec<(String, u64)> = vec![];
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_read_dir(
+                    volume,
+                    c("/docs").as_ptr(),
+                    Some(collect),
+                    ptr::addr_of_mut!(names).cast()
+                )
+            );
+            assert_eq!(vec![("b".to_string(), 5)], names);
+
+            assert_eq!(
+                ENCRYPTEDFS_ERROR_NOT_EMPTY,
+                encryptedfs_remove(volume, c("/docs").as_ptr())
+            );
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_remove(volume, c("/docs/b").as_ptr())
+            );
+            assert_eq!(
+                ENCRYPTEDFS_OK,
+                encryptedfs_remove(volume, c("/docs").as_ptr())
+            );
+            assert_eq!(
+                ENCRYPTEDFS_ERROR_INVALID_ARGUMENT,
+                encryptedfs_mkdir(volume, ptr::null())
+            );
+            assert_eq!(ENCRYPTEDFS_OK, encryptedfs_close_volume(volume));
+
+            assert!(
+                encryptedfs_open_volume(data_dir.as_ptr(), c("wrong").as_ptr(), ptr::null())
+                    .is_null()
+            );
+            assert!(
+                encryptedfs_create_volume(data_dir.as_ptr(), c("pass").as_ptr(), ptr::null())
+                    .is_null()
+            );
+            let volume =
+                encryptedfs_open_volume(data_dir.as_ptr(), c("pass").as_ptr(), ptr::null());
+            assert!(!volume.is_null());
+            assert_eq!(ENCRYPTEDFS_OK, encryptedfs_close_volume(volume));
+        }
+        fs::remove_dir_all(data_dir.to_str().unwrap()).unwrap();
+    }
+}
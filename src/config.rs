@@ -0,0 +1,196 @@
+//! Named volumes from a TOML file, so `rencfs mount work` is enough instead of the whole command line.
+//!
+//! ```toml
+//! [volumes.work]
+//! data-dir = "~/.work-encrypted"
+//! mount-point = "~/work"
+//! cipher = "Aes256Gcm"
+//! read-only = true
+//! map-uid = ["1000:2000"]
+//! ```
+//!
+//! Keys are the long names of the `mount` arguments, like `password-file`, and `cipher` or the `log-*` ones. They are put on the command line
+//! before the ones given, so those override them.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Command;
+use serde::Deserialize;
+use toml::Value;
+
+/// Arguments of the main command, the others are of `mount`.
+const MAIN_ARGS: [&str; 4] = ["cipher", "log-level", "log-file", "log-format"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    volumes: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+/// What it reads when `--config` is not given, `$XDG_CONFIG_HOME/rencfs.toml` or `~/.config/rencfs.toml`.
+pub(crate) fn default_path() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default()
+        .join("rencfs.toml")
+}
+
+/// Arguments for the volume `name`, the ones for `cli` itself and the ones for `mount`.
+pub(crate) fn volume_args(
+    path: &Path,
+    name: &str,
+    cli: &Command,
+) -> Result<(Vec<OsString>, Vec<OsString>)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&content).with_context(|| format!("invalid {}", path.display()))?;
+    let volume = config
+        .volumes
+        .get(name)
+        .ok_or_else(|| anyhow!("no volume {name} in {}", path.display()))?;
+    let mount = cli.find_subcommand("mount").unwrap();
+    let is_arg = |cmd: &Command, key: &str, global: bool| {
+        cmd.get_arguments().any(|arg| {
+            arg.get_long() == Some(key) && !arg.is_positional() && (!global || arg.is_global_set())
+        })
+    };
+
+    let mut main_args = vec![];
+    let mut mount_args = vec![];
+    for (key, value) in volume {
+        let args = if MAIN_ARGS.contains(&key.as_str()) && is_arg(cli, key, false) {
+            &mut main_args
+        } else if is_arg(mount, key, false) || is_arg(cli, key, true) {
+            &mut mount_args
+        } else {
+            bail!(
+                "unknown option {key} for volume {name} in {}",
+                path.display()
+            );
+        };
+        push_arg(args, key, value).with_context(|| format!("{key} of volume {name}"))?;
+    }
+    Ok((main_args, mount_args))
+}
+
+fn push_arg(args: &mut Vec<OsString>, key: &str, value: &Value) -> Result<()> {
+    match value {
+        Value::Boolean(true) => args.push(format!("--{key}").into()),
+        // it's off when not given
+        Value::Boolean(false) => {}
+        Value::Array(values) => {
+            for value in values {
+                if value.is_array() || value.is_table() {
+                    bail!("nested values are not supported");
+                }
+                push_arg(args, key, value)?;
+            }
+        }
+        Value::String(value) => {
+            args.push(format!("--{key}").into());
+            args.push(expand_home(value));
+        }
+        Value::Integer(_) | Value::Float(_) => {
+            args.push(format!("--{key}").into());
+            args.push(value.to_string().into());
+        }
+        Value::Datetime(_) | Value::Table(_) => bail!("must be a string, number, bool or array"),
+    }
+    Ok(())
+}
+
+/// `~/` is the home dir, like in a shell.
+fn expand_home(value: &str) -> OsString {
+    match (value.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest).into(),
+        _ => value.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use clap::{Arg, ArgAction};
+
+    use super::*;
+
+    fn cli() -> Command {
+        Command::new("rencfs")
+            .arg(Arg::new("cipher").long("cipher"))
+            .arg(Arg::new("keyfile").long("keyfile").global(true))
+            .subcommand(
+                Command::new("mount")
+                    .arg(Arg::new("data-dir").long("data-dir"))
+                    .arg(
+                        Arg::new("read-only")
+                            .long("read-only")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(Arg::new("dedup").long("dedup").action(ArgAction::SetTrue))
+                    .arg(Arg::new("max-size").long("max-size"))
+                    .arg(
+                        Arg::new("map-uid")
+                            .long("map-uid")
+                            .action(ArgAction::Append),
+                    ),
+            )
+    }
+
+    fn args(config: &str, name: &str) -> Result<(Vec<OsString>, Vec<OsString>)> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+        volume_args(file.path(), name, &cli())
+    }
+
+    #[test]
+    fn test_volume_args() {
+        let (main_args, mount_args) = args(
+            r#"
+            [volumes.work]
+            cipher = "Aes256Gcm"
+            data-dir = "/data"
+            keyfile = "/key"
+            read-only = true
+            dedup = false
+            max-size = 1024
+            map-uid = ["1000:2000", "1001:2001"]
+            "#,
+            "work",
+        )
+        .unwrap();
+        assert_eq!(main_args, ["--cipher", "Aes256Gcm"]);
+        assert_eq!(
+            mount_args,
+            [
+                "--data-dir",
+                "/data",
+                "--keyfile",
+                "/key",
+                "--map-uid",
+                "1000:2000",
+                "--map-uid",
+                "1001:2001",
+                "--max-size",
+                "1024",
+                "--read-only",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_volume_args_errors() {
+        let config = "[volumes.work]\ndata-dir = \"/data\"\n";
+        assert!(args(config, "home").is_err());
+        assert!(args("[volumes.work]\nfoo = 1\n", "work").is_err());
+        assert!(args("[volumes.work]\ndata-dir = { path = \"/data\" }\n", "work").is_err());
+        assert!(args(config, "work").is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+//! Socket of a running mount, in `$XDG_RUNTIME_DIR/rencfs` next to the pid file of `--daemon`, that `stats`,
+//! `log-filter` and `ctl` connect to. It takes a command per line and answers with text, then closes the connection.
+//! Answers to commands that only do something are `ok`, or `error` and why, separated by a tab. Both ends check
+//! the other one runs as the same user, as the password can be sent over it.
+//!
+//! The commands are `stats`, `log-filter DIRECTIVES`, `lock`, `unlock` followed by a line with the password,
+//! `flush` and `unmount`.
+
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use rencfs::crypto::LockedString;
+use rencfs::encryptedfs::{EncryptedFs, FsError};
+use rencfs::metrics;
+
+use crate::daemon;
+
+/// Longest password `unlock` reads.
+const MAX_PASSWORD_LEN: usize = 4096;
+
+/// Removed on exit.
+static SOCKETS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Asked with `unmount`, the mount is unmounted by the one serving the mounts, which sends back how it went.
+pub(crate) struct UnmountRequest {
+    pub(crate) mountpoint: String,
+    pub(crate) done: mpsc::Sender<io::Result<()>>,
+}
+
+/// Where the socket of the mount at `mountpoint` is, it fails if the dir it's in can be accessed by others.
+pub(crate) fn socket_path(mountpoint: &str) -> io::Result<PathBuf> {
+    daemon::default_path(mountpoint, "sock")
+}
+
+/// Answer on the socket of `mountpoint` until the process exits. A socket left by a crashed mount is replaced.
+/// `unmount` is sent the requests to unmount it.
+pub(crate) fn listen(
+    mountpoint: &str,
+    fs: Arc<EncryptedFs>,
+    unmount: mpsc::UnboundedSender<UnmountRequest>,
+) -> io::Result<()> {
+    let path = socket_path(mountpoint)?;
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is used by another mount", path.display()),
+        ));
+    }
+    let _ = std::fs::remove_file(&path);
+    // only for us from when it's created, it tells what's going on in the volume and takes the password
+    // SAFETY: it can't fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    // SAFETY: it can't fail
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    SOCKETS.lock().unwrap().push(path);
+    let mountpoint = mountpoint.to_string();
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let fs = fs.clone();
+                    let mountpoint = mountpoint.clone();
+                    let unmount = unmount.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle(stream, &mountpoint, &fs, &unmount).await {
+                            debug!(err = %err, "control connection failed");
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!(err = %err, "accepting on control socket");
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(
+    stream: UnixStream,
+    mountpoint: &str,
+    fs: &EncryptedFs,
+    unmount: &mpsc::UnboundedSender<UnmountRequest>,
+) -> io::Result<()> {
+    check_peer(&stream)?;
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+    read.read_line(&mut line).await?;
+    let answer = match line.trim() {
+        "stats" => render_stats(mountpoint, fs).await,
+        "lock" => {
+            // the rotation needs the key until it finishes
+            if fs.is_key_rotation_in_progress() {
+                "error\tthe key is being rotated\n".to_string()
+            } else {
+                fs.lock().await;
+                "ok\n".to_string()
+            }
+        }
+        "unlock" => {
+            let password = LockedString::read_line(&mut read, MAX_PASSWORD_LEN).await?;
+            unlock(mountpoint, fs, &password).await
+        }
+        "flush" => match flush(fs).await {
+            Ok(()) => "ok\n".to_string(),
+            Err(err) => {
+                error!(err = %err, "flushing");
+                format!("error\t{err}\n")
+            }
+        },
+        "unmount" => {
+            let (done, mut res) = mpsc::channel(1);
+            let request = UnmountRequest {
+                mountpoint: mountpoint.to_string(),
+                done,
+            };
+            if unmount.send(request).is_err() {
+                "error\tthe process is exiting\n".to_string()
+            } else {
+                match res.recv().await {
+                    Some(Ok(())) => "ok\n".to_string(),
+                    Some(Err(err)) => format!("error\t{err}\n"),
+                    None => "error\tit's not mounted anymore\n".to_string(),
+                }
+            }
+        }
+        command => match command.split_once(' ') {
+            Some(("log-filter", directives)) => match crate::set_log_filter(directives.trim()) {
+                Ok(()) => {
+                    info!(directives, "log filter changed");
+                    "ok\n".to_string()
+                }
+                Err(err) => format!("error\tinvalid log filter: {err}\n"),
+            },
+            _ => format!("error\tunknown command {command}\n"),
+        },
+    };
+    write.write_all(answer.as_bytes()).await?;
+    write.shutdown().await
+}
+
+/// Take the password if the mount is locked. It's kept only if it's the right one.
+async fn unlock(mountpoint: &str, fs: &EncryptedFs, password: &LockedString) -> String {
+    if !fs.is_locked() {
+        return "ok\n".to_string();
+    }
+    crate::keep_password(mountpoint, password);
+    match fs.ensure_unlocked().await {
+        Ok(()) => "ok\n".to_string(),
+        // the password provider was asked to forget it
+        Err(FsError::Locked) => "error\tinvalid password\n".to_string(),
+        Err(err) => format!("error\t{err}\n"),
+    }
+}
+
+/// Flush the files open for write and put everything in the storage, if any.
+async fn flush(fs: &EncryptedFs) -> Result<(), FsError> {
+    fs.flush_all().await?;
+    fs.sync_storage().await
+}
+
+/// Send `command` to the mount at `mountpoint` and return what it answered.
+pub(crate) async fn send(mountpoint: &str, command: &str) -> io::Result<String> {
+    let path = socket_path(mountpoint)?;
+    let mut stream = UnixStream::connect(&path).await.map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("cannot connect to {}, is it mounted? {err}", path.display()),
+        )
+    })?;
+    // the password is sent to it
+    check_peer(&stream)?;
+    stream.write_all(format!("{command}\n").as_bytes()).await?;
+    let mut answer = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut answer).await?;
+    Ok(answer)
+}
+
+/// The other end must be a process of ours.
+fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    let uid = stream.peer_cred()?.uid();
+    if uid != daemon::euid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("the other end of the control socket is uid {uid}, not us"),
+        ));
+    }
+    Ok(())
+}
+
+/// Remove the socket of `mountpoint`, after it's unmounted.
+pub(crate) fn close(mountpoint: &str) {
+    let Ok(path) = socket_path(mountpoint) else {
+        return;
+    };
+    SOCKETS.lock().unwrap().retain(|socket| *socket != path);
+    let _ = std::fs::remove_file(path);
+}
+
+/// Remove the sockets of the mounts of the process.
+pub(crate) fn cleanup() {
+    for path in SOCKETS.lock().unwrap().drain(..) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Log the statistics of the mounts each time we get `SIGUSR1`.
+pub(crate) fn dump_on_signal(mounts: Vec<(String, Arc<EncryptedFs>)>) -> io::Result<()> {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            for (mountpoint, fs) in &mounts {
+                info!("Statistics\n{}", render_stats(mountpoint, fs).await);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Statistics of the mount as lines of tab separated fields, the first one is what the line is about. Operations
+/// are counted for all the mounts of the process.
+pub(crate) async fn render_stats(mountpoint: &str, fs: &EncryptedFs) -> String {
+    let mut out = format!("mountpoint\t{mountpoint}\n");
+    match fs.stats().await {
+        Ok(stats) => {
+            let _ = writeln!(
+                out,
+                "handles\t{} read\t{} write",
+                stats.read_handles, stats.write_handles
+            );
+            let _ = writeln!(out, "inodes\t{}", stats.inodes);
+            for cache in stats.caches {
+                let _ = writeln!(out, "cache\t{}\t{}/{}", cache.name, cache.len, cache.cap);
+            }
+        }
+        Err(err) => {
+            warn!(err = %err, "getting statistics");
+            let _ = writeln!(out, "error\t{err}");
+        }
+    }
+    for (op, stats) in metrics::ops() {
+        let quantile = |q| {
+            stats
+                .latency
+                .quantile(q)
+                .map_or_else(|| "-".to_string(), format_duration)
+        };
+        let _ = writeln!(
+            out,
+            "op\t{op}\t{} calls\t{} errors\tp50 {}\tp90 {}\tp99 {}",
+            stats.latency.count(),
+            stats.errors.get(),
+            quantile(0.5),
+            quantile(0.9),
+            quantile(0.99),
+        );
+    }
+    out
+}
+
+/// As an upper bound, like `<=250us`.
+fn format_duration(duration: Duration) -> String {
+    if duration == Duration::MAX {
+        ">1s".to_string()
+    } else if duration < Duration::from_millis(1) {
+        format!("<={}us", duration.as_micros())
+    } else {
+        format!("<={}ms", duration.as_millis())
+    }
+}
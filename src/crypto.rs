@@ -1,8 +1,17 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Seek, Write};
+use std::mem::{self, ManuallyDrop};
 use std::num::ParseIntError;
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use argon2::Argon2;
 use base64::alphabet::STANDARD;
@@ -13,12 +22,14 @@ use hex::FromHexError;
 use num_format::{Locale, ToFormattedString};
 use rand_chacha::rand_core::{CryptoRng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
-use ring::aead::{AES_256_GCM, CHACHA20_POLY1305};
-use secrecy::{ExposeSecret, SecretString, SecretVec};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use secrecy::zeroize::{Zeroize, Zeroizing};
+use secrecy::{ExposeSecret, Secret, SecretString, SecretVec};
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumIter, EnumString};
 use thiserror::Error;
-use tracing::{debug, error, instrument};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use tracing::{debug, error, instrument, warn};
 
 use crate::crypto::read::{CryptoRead, CryptoReadSeek, RingCryptoRead};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek, RingCryptoWrite, RingCryptoWriteSeek};
@@ -27,6 +38,8 @@ use crate::{fs_util, stream_util};
 
 pub mod buf_mut;
 pub mod read;
+pub mod shamir;
+pub mod totp;
 pub mod write;
 
 pub static BASE64: GeneralPurpose = GeneralPurpose::new(&STANDARD, NO_PAD);
@@ -219,16 +232,285 @@ pub fn decrypt_file_name(name: &str, cipher: Cipher, key: &SecretVec<u8>) -> Res
     decrypt(&name, cipher, key)
 }
 
+/// Cost of Argon2id, which derives the key from the password. The higher, the slower it is to guess the password,
+/// for us too each time it's unlocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory used, in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    /// Lanes computed in parallel.
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The defaults of the `argon2` crate, which data dirs were created with before they could be chosen.
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Cost that takes about `target` to derive a key on this machine. It keeps the memory and lanes of `self` and
+    /// picks the iterations, with less memory only if even one iteration takes longer.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn calibrate(self, target: Duration) -> Result<Self> {
+        let password = SecretString::new("calibrate".to_string());
+        let mut salt = [0_u8; 16];
+        create_rng().fill_bytes(&mut salt);
+        let mut params = Self {
+            iterations: 1,
+            ..self
+        };
+        loop {
+            let start = Instant::now();
+            derive_key_with(&password, Cipher::ChaCha20Poly1305, &salt, &params)?;
+            let elapsed = start.elapsed().max(Duration::from_micros(1));
+            // Argon2 needs at least 8 KiB for each lane
+            if elapsed <= target || params.memory_kib / 2 < 8 * params.parallelism {
+                // it takes about the same for each iteration
+                params.iterations = (target.as_secs_f64() / elapsed.as_secs_f64())
+                    .floor()
+                    .clamp(1.0, f64::from(u32::MAX)) as u32;
+                return Ok(params);
+            }
+            params.memory_kib /= 2;
+        }
+    }
+}
+
+#[instrument(skip(password, salt))]
+#[allow(clippy::missing_errors_doc)]
+pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<LockedVec> {
+    derive_key_with(password, cipher, salt, &KdfParams::default())
+}
+
+/// Like [`derive_key`], with the cost of the data dir.
 #[instrument(skip(password, salt))]
 #[allow(clippy::missing_errors_doc)]
-pub fn derive_key(password: &SecretString, cipher: Cipher, salt: &[u8]) -> Result<SecretVec<u8>> {
+pub fn derive_key_with(
+    password: &SecretString,
+    cipher: Cipher,
+    salt: &[u8],
+    kdf: &KdfParams,
+) -> Result<LockedVec> {
     let mut dk = vec![];
     let key_len = cipher.key_len();
     dk.resize(key_len, 0);
-    Argon2::default()
+    let params = argon2::Params::new(
+        kdf.memory_kib,
+        kdf.iterations,
+        kdf.parallelism,
+        Some(key_len),
+    )
+    .map_err(|err| Error::GenericString(err.to_string()))?;
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
         .hash_password_into(password.expose_secret().as_bytes(), salt, &mut dk)
         .map_err(|err| Error::GenericString(err.to_string()))?;
-    Ok(SecretVec::new(dk))
+    Ok(Locked::new(dk))
+}
+
+/// A secret kept locked in RAM while it's alive, so it's never written to swap. When dropped it's zeroized, then
+/// unlocked. Clones are locked too.
+///
+/// Locking is best effort, if the limit of locked memory is reached it stays unlocked.
+pub struct Locked<T: Zeroize + AsRef<[u8]>> {
+    secret: ManuallyDrop<Secret<T>>,
+    /// Start and length of what was locked, the content of `T` is on the heap so it's not moved with it.
+    region: (usize, usize),
+}
+
+/// [`SecretVec`] locked in RAM.
+pub type LockedVec = Locked<Vec<u8>>;
+/// [`SecretString`] locked in RAM.
+pub type LockedString = Locked<String>;
+
+impl<T: Zeroize + AsRef<[u8]>> Locked<T> {
+    pub fn new(secret: T) -> Self {
+        let bytes = secret.as_ref();
+        let region = (bytes.as_ptr() as usize, bytes.len());
+        lock_pages(region);
+        Self {
+            secret: ManuallyDrop::new(Secret::new(secret)),
+            region,
+        }
+    }
+}
+
+impl LockedVec {
+    /// `len` random bytes, like a new key. The memory is locked before they are generated in it.
+    #[must_use]
+    pub fn random(len: usize) -> Self {
+        let mut bytes = vec![0; len];
+        let region = (bytes.as_ptr() as usize, bytes.len());
+        lock_pages(region);
+        create_rng().fill_bytes(&mut bytes);
+        Self {
+            secret: ManuallyDrop::new(Secret::new(bytes)),
+            region,
+        }
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Deref for Locked<T> {
+    type Target = Secret<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.secret
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> ExposeSecret<T> for Locked<T> {
+    fn expose_secret(&self) -> &T {
+        self.secret.expose_secret()
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]> + Clone> Clone for Locked<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.expose_secret().clone())
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> Drop for Locked<T> {
+    fn drop(&mut self) {
+        // zeroized before it's unlocked, so it's never swapped
+        // SAFETY: it's not used after this
+        unsafe { ManuallyDrop::drop(&mut self.secret) };
+        unlock_pages(self.region);
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> fmt::Debug for Locked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Locked([REDACTED])")
+    }
+}
+
+impl LockedString {
+    /// Read a line from `read` into locked memory, without its end. It fails if it's longer than `max` bytes, so it's
+    /// never moved to a bigger buffer, which would leave a copy behind.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn read_line(read: &mut (impl AsyncBufRead + Unpin), max: usize) -> io::Result<Self> {
+        // with room for the line end
+        let mut line = Zeroizing::new(String::with_capacity(max + 2));
+        let region = (line.as_ptr() as usize, line.capacity());
+        lock_pages(region);
+        let res = read.take(max as u64 + 2).read_line(&mut line).await;
+        let len = line.trim_end_matches(['\r', '\n']).len();
+        if let Err(err) = res {
+            drop(line);
+            unlock_pages(region);
+            return Err(err);
+        }
+        if len > max {
+            drop(line);
+            unlock_pages(region);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "line is too long",
+            ));
+        }
+        line.truncate(len);
+        Ok(Self {
+            secret: ManuallyDrop::new(Secret::new(mem::take(&mut *line))),
+            region,
+        })
+    }
+}
+
+impl Serialize for LockedVec {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.expose_secret().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LockedVec {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl FromStr for LockedString {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Self::new(s.to_string()))
+    }
+}
+
+/// How many [`Locked`] secrets are on each locked page, by its address. Pages are shared by secrets, so a page is
+/// unlocked only after the last one on it is dropped.
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn pages((start, len): (usize, usize)) -> impl Iterator<Item = usize> {
+    let page = page_size();
+    let first = start / page * page;
+    let end = if len == 0 { first } else { start + len };
+    (first..end).step_by(page)
+}
+
+fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| {
+        #[cfg(unix)]
+        // SAFETY: it only reads a setting
+        #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        #[cfg(not(unix))]
+        let size = 4096;
+        size.max(1)
+    })
+}
+
+fn lock_pages(region: (usize, usize)) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    for page in pages(region) {
+        let count = locked.entry(page).or_insert(0);
+        #[cfg(unix)]
+        // SAFETY: the page is mapped, it holds the secret, mlock() only changes how it's kept
+        if *count == 0
+            && unsafe { libc::mlock(page as *const libc::c_void, page_size()) } != 0
+            && !WARNED.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                err = %io::Error::last_os_error(),
+                "cannot lock secrets in memory, they might be swapped, try to increase RLIMIT_MEMLOCK"
+            );
+        }
+        *count += 1;
+    }
+}
+
+fn unlock_pages(region: (usize, usize)) {
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    for page in pages(region) {
+        let Some(count) = locked.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // it fails only if it was never locked or it was unmapped since, nothing to do then
+            #[cfg(unix)]
+            // SAFETY: munlock() only changes how the page is kept
+            unsafe {
+                libc::munlock(page as *const libc::c_void, page_size());
+            }
+        }
+    }
 }
 
 #[allow(clippy::missing_errors_doc)]
@@ -261,6 +543,172 @@ pub fn hash_file_name(name: &SecretString) -> String {
     }
 }
 
+/// Mix the content of a keyfile into the password, the result is what keys are derived from. Without the
+/// keyfile the data dir can't be brute-forced by guessing the password, the password can also be empty
+/// to unlock only with the keyfile.
+#[allow(clippy::missing_errors_doc)]
+pub fn password_with_keyfile(password: &SecretString, keyfile: &Path) -> Result<LockedString> {
+    let mut hasher = blake3::Hasher::new_derive_key("rencfs keyfile");
+    io::copy(&mut File::open(keyfile)?, &mut hasher)?;
+    let mut keyfile_key: [u8; blake3::KEY_LEN] = hasher.finalize().into();
+    let mut hash: [u8; 32] =
+        blake3::keyed_hash(&keyfile_key, password.expose_secret().as_bytes()).into();
+    let res = Locked::new(hex::encode(hash));
+    keyfile_key.zeroize();
+    hash.zeroize();
+    Ok(res)
+}
+
+/// A random key to unlock the data dir when the password is lost, as 8 groups of 8 hex digits separated by `-`, to
+/// be written down. It's given like a password, exactly as it's shown.
+#[must_use]
+pub fn generate_recovery_key() -> LockedString {
+    let mut bytes = [0_u8; 32];
+    create_rng().fill_bytes(&mut bytes);
+    let mut hex = hex::encode(bytes);
+    bytes.zeroize();
+    let key = Locked::new(
+        (0..hex.len())
+            .step_by(8)
+            .map(|i| &hex[i..i + 8])
+            .collect::<Vec<_>>()
+            .join("-"),
+    );
+    hex.zeroize();
+    key
+}
+
+/// Derive from the master key the key used to name objects in
+/// [`StorageLayout::Flat`](crate::encryptedfs::StorageLayout::Flat).
+#[must_use]
+pub fn derive_object_names_key(key: &SecretVec<u8>) -> LockedVec {
+    let mut names_key = vec![0; blake3::KEY_LEN];
+    blake3::derive_key(
+        "rencfs flat layout object names",
+        key.expose_secret(),
+        &mut names_key,
+    );
+    Locked::new(names_key)
+}
+
+/// Name of the object identified by `label`. It's a keyed hash so it doesn't reveal what the object holds.
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn hash_object_name(names_key: &SecretVec<u8>, label: &str) -> String {
+    let names_key: &[u8; blake3::KEY_LEN] = names_key
+        .expose_secret()
+        .as_slice()
+        .try_into()
+        .expect("invalid object names key length");
+    let hash: [u8; 32] = blake3::keyed_hash(names_key, label.as_bytes()).into();
+    hex::encode(hash)
+}
+
+/// Derive from the master key the key used to identify and encrypt deduplicated chunks, see
+/// [`FsOptions::dedup`](crate::encryptedfs::FsOptions::dedup).
+#[must_use]
+pub fn derive_dedup_key(key: &SecretVec<u8>) -> LockedVec {
+    let mut dedup_key = vec![0; blake3::KEY_LEN];
+    blake3::derive_key("rencfs dedup chunks", key.expose_secret(), &mut dedup_key);
+    Locked::new(dedup_key)
+}
+
+/// Id of a chunk of content. It's a keyed hash so identical content gets the same id only in the same volume, and
+/// the id doesn't reveal what the chunk holds.
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn hash_chunk(dedup_key: &SecretVec<u8>, data: &[u8]) -> [u8; 32] {
+    let dedup_key: &[u8; blake3::KEY_LEN] = dedup_key
+        .expose_secret()
+        .as_slice()
+        .try_into()
+        .expect("invalid dedup key length");
+    blake3::keyed_hash(dedup_key, data).into()
+}
+
+/// Key the chunk with `id` is encrypted with. It depends only on the id, so a chunk shared by several files is
+/// encrypted once, whatever keys the files have.
+#[must_use]
+pub fn derive_chunk_key(dedup_key: &SecretVec<u8>, id: &[u8; 32], cipher: Cipher) -> LockedVec {
+    let mut hasher = blake3::Hasher::new_derive_key("rencfs dedup chunk key");
+    hasher.update(dedup_key.expose_secret());
+    hasher.update(id);
+    let mut key = vec![0; cipher.key_len()];
+    hasher.finalize_xof().fill(&mut key);
+    Locked::new(key)
+}
+
+/// Derive from the master key the key chaining the records of the audit log, see
+/// [`FsOptions::audit_log`](crate::encryptedfs::FsOptions::audit_log).
+#[must_use]
+pub fn derive_audit_key(key: &SecretVec<u8>) -> LockedVec {
+    let mut audit_key = vec![0; blake3::KEY_LEN];
+    blake3::derive_key(
+        "rencfs audit log chain",
+        key.expose_secret(),
+        &mut audit_key,
+    );
+    Locked::new(audit_key)
+}
+
+/// MAC of a record of the audit log as stored, chained to the one of the record before it, so records can't be
+/// removed or moved without it being seen.
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn chain_audit_record(audit_key: &SecretVec<u8>, prev: &[u8; 32], record: &[u8]) -> [u8; 32] {
+    let audit_key: &[u8; blake3::KEY_LEN] = audit_key
+        .expose_secret()
+        .as_slice()
+        .try_into()
+        .expect("invalid audit key length");
+    let mut hasher = blake3::Hasher::new_keyed(audit_key);
+    hasher.update(prev);
+    hasher.update(record);
+    hasher.finalize().into()
+}
+
+/// Encrypt `data` as block `index` of what [`create_read`] reads, with a nonce derived from `nonce_key`, `context`,
+/// `index` and `data` instead of a random one. The same block is encrypted the same each time, so it only tells which
+/// blocks are the same, see [`crate::reverse`].
+#[allow(clippy::missing_panics_doc)]
+#[must_use]
+pub fn encrypt_block_deterministic(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    nonce_key: &SecretVec<u8>,
+    context: &[u8],
+    index: u64,
+    data: &[u8],
+) -> Vec<u8> {
+    let algorithm = match cipher {
+        Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        Cipher::Aes256Gcm => &AES_256_GCM,
+    };
+    let nonce_key: &[u8; blake3::KEY_LEN] = nonce_key
+        .expose_secret()
+        .as_slice()
+        .try_into()
+        .expect("invalid nonce key length");
+    let mut hasher = blake3::Hasher::new_keyed(nonce_key);
+    hasher.update(&(context.len() as u64).to_le_bytes());
+    hasher.update(context);
+    hasher.update(&index.to_le_bytes());
+    hasher.update(data);
+    let hash: [u8; 32] = hasher.finalize().into();
+    let nonce: [u8; NONCE_LEN] = hash[..NONCE_LEN].try_into().unwrap();
+    let key =
+        LessSafeKey::new(UnboundKey::new(algorithm, key.expose_secret()).expect("unbound key"));
+    let mut buf = data.to_vec();
+    let tag = key
+        .seal_in_place_separate_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(index.to_le_bytes()),
+            &mut buf,
+        )
+        .expect("sealing");
+    [&nonce[..], &buf, tag.as_ref()].concat()
+}
+
 #[must_use]
 pub fn hash(data: &[u8]) -> [u8; 32] {
     let mut hasher = blake3::Hasher::new();
@@ -276,6 +724,33 @@ pub fn hash_reader<R: Read + ?Sized>(r: &mut R) -> io::Result<[u8; 32]> {
     Ok(hasher.finalize().into())
 }
 
+/// Hash of the authentication tags of the chunks written by [`create_write`], in order. It changes if any chunk
+/// is changed, moved or replaced with an older one. Only the tags are read, seeking over the chunks.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn hash_chunk_tags<R: Read + Seek>(r: &mut R, cipher: Cipher) -> io::Result<[u8; 32]> {
+    let tag_len = match cipher {
+        Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305.tag_len(),
+        Cipher::Aes256Gcm => AES_256_GCM.tag_len(),
+    } as u64;
+    let block_len = (ring::aead::NONCE_LEN + write::BLOCK_SIZE) as u64 + tag_len;
+    let len = r.seek(io::SeekFrom::End(0))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    let mut tag = vec![0; usize::try_from(tag_len).unwrap()];
+    let mut end = 0;
+    while end < len {
+        end = (end + block_len).min(len);
+        // a truncated chunk shorter than the tag is covered by the length
+        let start = end.saturating_sub(tag_len);
+        r.seek(io::SeekFrom::Start(start))?;
+        let tag = &mut tag[..usize::try_from(end - start).unwrap()];
+        r.read_exact(tag)?;
+        hasher.update(tag);
+    }
+    Ok(hasher.finalize().into())
+}
+
 #[must_use]
 pub fn hash_secret_string(data: &SecretString) -> [u8; 32] {
     hash(data.expose_secret().as_bytes())
@@ -373,3 +848,55 @@ where
     File::open(parent)?.sync_all()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locked_unlocked_on_drop() {
+        let page = page_size();
+        let secret = LockedVec::new(vec![7; page * 3]);
+        // the second page holds only this secret
+        let start = secret.expose_secret().as_ptr() as usize;
+        let own = (start / page + 1) * page;
+        assert_eq!(Some(&1), LOCKED_PAGES.lock().unwrap().get(&own));
+
+        let clone = secret.clone();
+        assert_eq!(secret.expose_secret(), clone.expose_secret());
+        assert_ne!(start, clone.expose_secret().as_ptr() as usize);
+        drop(clone);
+        assert_eq!(Some(&1), LOCKED_PAGES.lock().unwrap().get(&own));
+
+        drop(secret);
+        assert_eq!(None, LOCKED_PAGES.lock().unwrap().get(&own));
+    }
+
+    #[test]
+    fn test_locked_random() {
+        let key = LockedVec::random(32);
+        assert_eq!(32, key.expose_secret().len());
+        assert_ne!(key.expose_secret(), LockedVec::random(32).expose_secret());
+        let page = page_size();
+        let page = key.expose_secret().as_ptr() as usize / page * page;
+        assert!(LOCKED_PAGES.lock().unwrap().contains_key(&page));
+
+        // same as a Vec
+        let serialized = bincode::serialize(&key).unwrap();
+        assert_eq!(bincode::serialize(key.expose_secret()).unwrap(), serialized);
+        let deserialized: LockedVec = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(key.expose_secret(), deserialized.expose_secret());
+    }
+
+    #[tokio::test]
+    async fn test_locked_read_line() {
+        let mut read = &b"secret\r\nnext\n"[..];
+        let line = LockedString::read_line(&mut read, 6).await.unwrap();
+        assert_eq!("secret", line.expose_secret());
+        assert_eq!(b"next\n", read);
+
+        let mut read = &b"too long\n"[..];
+        let err = LockedString::read_line(&mut read, 6).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -60,6 +60,7 @@ macro_rules! decrypt_block {
                     io::Error::new(io::ErrorKind::Other, "error opening within")
                 })?;
                 len = plaintext.len();
+                $crate::metrics::BYTES_DECRYPTED.add(len as u64);
             }
             len
         };
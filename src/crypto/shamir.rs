@@ -0,0 +1,166 @@
+//! Shamir's secret sharing over GF(256), used to split the secret that unlocks a data dir into shares so
+//! any `threshold` of them can unlock it, but fewer reveal nothing about it.
+//!
+//! A share is formatted as `threshold-index-hex`.
+
+use rand_chacha::rand_core::RngCore;
+use secrecy::zeroize::Zeroize;
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+
+use crate::crypto;
+use crate::crypto::{Error, Result};
+
+/// Split `secret` into `shares` shares, any `threshold` of them are needed to combine it back.
+#[allow(clippy::missing_errors_doc)]
+pub fn split(secret: &SecretVec<u8>, shares: u8, threshold: u8) -> Result<Vec<SecretString>> {
+    if threshold == 0 || threshold > shares {
+        return Err(Error::Generic(
+            "threshold must be between 1 and the number of shares",
+        ));
+    }
+    let mut rng = crypto::create_rng();
+    let mut ys = vec![vec![0_u8; secret.expose_secret().len()]; shares as usize];
+    let mut coefficients = vec![0_u8; threshold as usize];
+    for (i, byte) in secret.expose_secret().iter().enumerate() {
+        // polynomial of degree threshold - 1 with the secret byte as the constant term
+        coefficients[0] = *byte;
+        rng.fill_bytes(&mut coefficients[1..]);
+        for (x, y) in (1..=shares).zip(ys.iter_mut()) {
+            // Horner's method
+            y[i] = coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, coefficient| gf_mul(acc, x) ^ coefficient);
+        }
+    }
+    coefficients.zeroize();
+    Ok((1..=shares)
+        .zip(ys)
+        .map(|(x, mut y)| {
+            let share = SecretString::new(format!("{threshold}-{x}-{}", hex::encode(&y)));
+            y.zeroize();
+            share
+        })
+        .collect())
+}
+
+/// Combine shares created by [`split`] back into the secret.
+#[allow(clippy::missing_errors_doc)]
+pub fn combine(shares: &[SecretString]) -> Result<SecretVec<u8>> {
+    let shares = shares
+        .iter()
+        .map(|share| parse(share))
+        .collect::<Result<Vec<_>>>()?;
+    let Some((threshold, _, first)) = shares.first() else {
+        return Err(Error::Generic("no shares"));
+    };
+    let len = first.len();
+    if shares
+        .iter()
+        .any(|(t, _, y)| t != threshold || y.len() != len)
+    {
+        return Err(Error::Generic("shares are not from the same secret"));
+    }
+    let shares = &shares[..(*threshold as usize).min(shares.len())];
+    if shares.len() < *threshold as usize {
+        return Err(Error::Generic("not enough shares"));
+    }
+    let xs: Vec<u8> = shares.iter().map(|(_, x, _)| *x).collect();
+    if (1..xs.len()).any(|i| xs[..i].contains(&xs[i])) {
+        return Err(Error::Generic("duplicate shares"));
+    }
+    let mut secret = vec![0_u8; len];
+    for (i, (_, xi, yi)) in shares.iter().enumerate() {
+        // Lagrange basis polynomial evaluated at 0, in GF(256) subtraction is xor
+        let basis = xs
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .fold(1, |acc, (_, xj)| gf_mul(acc, gf_div(*xj, xj ^ xi)));
+        for (byte, y) in secret.iter_mut().zip(yi.iter()) {
+            *byte ^= gf_mul(*y, basis);
+        }
+    }
+    Ok(SecretVec::new(secret))
+}
+
+/// How many shares are needed to combine the secret `share` is part of.
+#[allow(clippy::missing_errors_doc)]
+pub fn threshold(share: &SecretString) -> Result<u8> {
+    parse(share).map(|(threshold, _, _)| threshold)
+}
+
+fn parse(share: &SecretString) -> Result<(u8, u8, Vec<u8>)> {
+    let invalid = || Error::Generic("invalid share");
+    let mut parts = share.expose_secret().trim().splitn(3, '-');
+    let threshold: u8 = parts
+        .next()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    let x: u8 = parts
+        .next()
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    let y = hex::decode(parts.next().ok_or_else(invalid)?).map_err(|_| invalid())?;
+    if threshold == 0 || x == 0 {
+        return Err(invalid());
+    }
+    Ok((threshold, x, y))
+}
+
+/// Multiply in GF(256) with the AES polynomial, without branching on the values.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0;
+    for _ in 0..8 {
+        res ^= a & 0_u8.wrapping_sub(b & 1);
+        let carry = 0_u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    res
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^254 is the inverse of b
+    let mut inv = b;
+    for _ in 0..6 {
+        inv = gf_mul(gf_mul(inv, inv), b);
+    }
+    gf_mul(a, gf_mul(inv, inv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gf_div() {
+        for a in 1..=255 {
+            assert_eq!(1, gf_div(a, a));
+            assert_eq!(a, gf_mul(gf_div(a, 3), 3));
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret = SecretVec::new((0..32).collect());
+        let shares = split(&secret, 5, 3).unwrap();
+        assert_eq!(5, shares.len());
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<_> = subset.iter().map(|i| shares[*i].clone()).collect();
+            assert_eq!(
+                secret.expose_secret(),
+                combine(&subset).unwrap().expose_secret()
+            );
+        }
+        assert_eq!(
+            secret.expose_secret(),
+            combine(&shares).unwrap().expose_secret()
+        );
+        assert!(combine(&shares[..2]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err());
+        assert!(split(&secret, 2, 3).is_err());
+    }
+}
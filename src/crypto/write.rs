@@ -13,7 +13,7 @@ use tracing::error;
 
 use crate::crypto::buf_mut::BufMut;
 use crate::crypto::read::ExistingNonceSequence;
-use crate::{crypto, decrypt_block, stream_util};
+use crate::{crypto, decrypt_block, metrics, stream_util};
 
 mod bench;
 mod test;
@@ -66,6 +66,7 @@ impl<W: Write> RingCryptoWrite<W> {
 
     fn encrypt_and_write(&mut self) -> io::Result<()> {
         let data = self.buf.as_mut();
+        metrics::BYTES_ENCRYPTED.add(data.len() as u64);
         let aad = Aad::from(self.block_index.to_le_bytes());
         let tag = self
             .sealing_key
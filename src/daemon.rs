@@ -0,0 +1,290 @@
+//! Run `mount` in the background, like `--daemon`.
+//!
+//! We fork before anything else starts threads, the parent waits on a pipe until the child unlocked and mounted,
+//! so errors and prompts still go to the terminal and the exit status of the parent tells if it's mounted.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// How long [`stop`] waits for the daemon to unmount.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Write end of the pipe to the parent, taken when we're ready.
+static READY: Mutex<Option<OwnedFd>> = Mutex::new(None);
+/// Removed on exit.
+static PID_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Fork, only the child returns. The parent exits with 0 after the child called [`ready`], or with the status
+/// of the child if that exits first.
+///
+/// Must be called before any threads are started, as only the calling thread is left in the child.
+pub(crate) fn fork() -> io::Result<()> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the 2 fds
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: we own them from now on
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // commands we run, like `umount`, must not keep the parent waiting
+    set_cloexec(&read)?;
+    set_cloexec(&write)?;
+
+    // SAFETY: there is only one thread
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            *READY.lock().unwrap() = Some(write);
+            Ok(())
+        }
+        pid => {
+            drop(write);
+            let mut byte = [0_u8; 1];
+            let n = File::from(read).read(&mut byte).unwrap_or(0);
+            if n == 1 {
+                process::exit(0);
+            }
+            // it closed the pipe without being ready
+            let mut status = 0;
+            // SAFETY: `status` is a valid int
+            if unsafe { libc::waitpid(pid, &mut status, 0) } == pid && libc::WIFEXITED(status) {
+                process::exit(libc::WEXITSTATUS(status).max(1));
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Detach from the terminal, write our pid to `pid_file`, redirect the output, and with it the logs, to `log_file`
+/// and let the parent exit.
+pub(crate) fn ready(pid_file: &Path, log_file: &Path) -> io::Result<()> {
+    for path in [pid_file, log_file] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    // links are not followed, so they can't point us to a file of someone else
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(log_file)?;
+    check_owned(&log.metadata()?, log_file)?;
+    write_pid_file(pid_file)?;
+    let _ = PID_FILE.set(pid_file.to_path_buf());
+
+    // SAFETY: no arguments, we are not a group leader as we were forked
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let null = File::open("/dev/null")?;
+    io::stdout().flush()?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+
+    if let Some(write) = READY.lock().unwrap().take() {
+        File::from(write).write_all(b"1")?;
+    }
+    Ok(())
+}
+
+/// Remove the pid file, if [`ready`] wrote one.
+pub(crate) fn cleanup() {
+    if let Some(pid_file) = PID_FILE.get() {
+        let _ = fs::remove_file(pid_file);
+    }
+}
+
+/// Stop the daemon with its pid in `pid_file` and wait for it to unmount and exit. `false` if there is no such
+/// daemon, like when the pid file was left after a crash. The pid file and its dir must be ours, and the pid must be
+/// of a rencfs process, so we don't stop something else.
+pub(crate) fn stop(pid_file: &Path) -> io::Result<bool> {
+    let Ok(metadata) = fs::symlink_metadata(pid_file) else {
+        return Ok(false);
+    };
+    if !metadata.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a file", pid_file.display()),
+        ));
+    }
+    check_owned(&metadata, pid_file)?;
+    let dir = match pid_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    check_owned(&fs::metadata(dir)?, dir)?;
+    let mut pid = String::new();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(pid_file)?
+        .read_to_string(&mut pid)?;
+    let Some(pid) = pid.trim().parse().ok().filter(|pid| *pid > 0) else {
+        return Ok(false);
+    };
+    if !is_rencfs(pid)? {
+        // the pid was taken by another process after a crash
+        let _ = fs::remove_file(pid_file);
+        return Ok(false);
+    }
+    // SAFETY: it only sends a signal
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        let err = io::Error::last_os_error();
+        return if err.raw_os_error() == Some(libc::ESRCH) {
+            let _ = fs::remove_file(pid_file);
+            Ok(false)
+        } else {
+            Err(err)
+        };
+    }
+    for _ in 0..STOP_TIMEOUT.as_millis() / STOP_POLL.as_millis() {
+        // SAFETY: signal 0 only checks it still exists
+        if unsafe { libc::kill(pid, 0) } != 0 {
+            return Ok(true);
+        }
+        thread::sleep(STOP_POLL);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{pid} didn't exit after {STOP_TIMEOUT:?}"),
+    ))
+}
+
+/// If `pid` is a process of ours running the same executable as us.
+fn is_rencfs(pid: libc::pid_t) -> io::Result<bool> {
+    let proc = PathBuf::from(format!("/proc/{pid}"));
+    let exe = match fs::read_link(proc.join("exe")) {
+        Ok(exe) => exe,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if fs::metadata(&proc)?.uid() != euid() {
+        return Ok(false);
+    }
+    // it's ` (deleted)` at the end after an upgrade
+    let ours = std::env::current_exe()?;
+    let name = |exe: &Path| {
+        exe.file_name().map(|name| {
+            name.to_string_lossy()
+                .trim_end_matches(" (deleted)")
+                .to_string()
+        })
+    };
+    Ok(name(&exe).is_some() && name(&exe) == name(&ours))
+}
+
+/// Where to put the pid file or the log if not given, like `$XDG_RUNTIME_DIR/rencfs/home-user-mnt.pid` for
+/// `/home/user/mnt`. It fails if the dir is not ours or others can get in it, see [`runtime_dir`].
+pub(crate) fn default_path(mountpoint: &str, extension: &str) -> io::Result<PathBuf> {
+    let mountpoint = fs::canonicalize(mountpoint).unwrap_or_else(|_| PathBuf::from(mountpoint));
+    let name = mountpoint
+        .to_string_lossy()
+        .trim_matches('/')
+        .replace('/', "-");
+    Ok(runtime_dir()?.join(format!("{name}.{extension}")))
+}
+
+/// `$XDG_RUNTIME_DIR/rencfs`, or `rencfs-UID` in the temp dir without it, created only for us. Both it and
+/// `$XDG_RUNTIME_DIR` must be dirs of ours that only we can get in, anyone could have made them otherwise.
+fn runtime_dir() -> io::Result<PathBuf> {
+    let dir = if let Some(runtime) = std::env::var_os("XDG_RUNTIME_DIR") {
+        let runtime = PathBuf::from(runtime);
+        check_private_dir(&runtime)?;
+        runtime.join("rencfs")
+    } else {
+        // the temp dir is shared, so each user has its own
+        std::env::temp_dir().join(format!("rencfs-{}", euid()))
+    };
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => {}
+    }
+    check_private_dir(&dir)?;
+    Ok(dir)
+}
+
+/// It's a dir, not a link to one, of ours and only we can get in.
+fn check_private_dir(dir: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a dir", dir.display()),
+        ));
+    }
+    check_owned(&metadata, dir)?;
+    if metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} can be accessed by others, its mode must be 0700",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn check_owned(metadata: &fs::Metadata, path: &Path) -> io::Result<()> {
+    if metadata.uid() != euid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not ours", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+/// Write our pid in a new file, one left by a crash is replaced. It's never written through a link.
+fn write_pid_file(pid_file: &Path) -> io::Result<()> {
+    let create = || {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(pid_file)
+    };
+    let mut file = match create() {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            // removes the link, if it's one, not what it points to
+            fs::remove_file(pid_file)?;
+            create()?
+        }
+        res => res?,
+    };
+    file.write_all(format!("{}\n", process::id()).as_bytes())
+}
+
+pub(crate) fn euid() -> u32 {
+    // SAFETY: it can't fail
+    unsafe { libc::geteuid() }
+}
+
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: the fd is valid
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn redirect(file: &File, to: libc::c_int) -> io::Result<()> {
+    // SAFETY: both fds are valid
+    if unsafe { libc::dup2(file.as_raw_fd(), to) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,13 +1,13 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::fs::{DirEntry, File, OpenOptions, ReadDir};
+use std::fs::{DirEntry, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::num::{NonZeroUsize, ParseIntError};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::{Arc, LazyLock, Weak};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, io};
 
 use argon2::password_hash::rand_core::RngCore;
@@ -16,7 +16,9 @@ use futures_util::TryStreamExt;
 use lru::LruCache;
 use num_format::{Locale, ToFormattedString};
 use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
 use thiserror::Error;
 use tokio::runtime::Runtime;
 use tokio::sync::{Mutex, RwLock};
@@ -27,19 +29,79 @@ use tracing::{debug, error, instrument, warn};
 use crate::arc_hashmap::ArcHashMap;
 use crate::crypto::read::{CryptoRead, CryptoReadSeek};
 use crate::crypto::write::{CryptoWrite, CryptoWriteSeek};
-use crate::crypto::Cipher;
+use crate::crypto::{Cipher, KdfParams, Locked, LockedString, LockedVec};
 use crate::expire_value::{ExpireValue, ValueProvider};
-use crate::{crypto, fs_util, stream_util};
+use crate::storage::Storage;
+use crate::{crypto, fs_util, metrics, stream_util};
+use journal::JournalOp;
 
+mod audit;
+mod auto_lock;
+mod backup;
 mod bench;
+mod benchmark;
+mod case;
+mod chaff;
+mod cipher_migration;
+mod damage;
+mod dedup;
+mod dir_lock;
+mod file;
+mod fsck;
+mod header;
+mod info;
+mod ingest;
+mod integrity;
+mod journal;
+mod key_rotation;
+mod key_slots;
+mod mirror;
+mod overlay;
+mod passthrough;
+mod quota;
+mod salvage;
+mod scrub;
+mod snapshots;
+mod stats;
+mod totp;
+mod upgrade;
+mod versions;
+mod volume;
+pub use audit::{AuditEvent, AuditRecord, AuditTarget};
+pub use backup::ArchiveReport;
+pub use benchmark::{BenchmarkResult, BenchmarkSettings};
+pub use damage::CorruptionRecord;
+pub use file::{BlockingEncryptedFile, EncryptedFile};
+pub use fsck::{CheckIssue, CheckReport};
+pub use header::VolumeHeader;
+pub use info::{VolumeInfo, VolumeUsage};
+pub use ingest::ImportReport;
+pub use integrity::{VerifyIssue, VerifyReport};
+pub use key_slots::{KeySlot, KeySlotKind};
+pub use passthrough::{PassthroughAction, PassthroughRule};
+pub use quota::Quota;
+pub use salvage::RecoverReport;
+pub use snapshots::Snapshot;
+pub use stats::{CacheOccupancy, FsStats};
+pub use upgrade::FORMAT_VERSION;
+pub use versions::Version;
 #[cfg(test)]
 mod test;
 
 pub(crate) const INODES_DIR: &str = "inodes";
 pub(crate) const CONTENTS_DIR: &str = "contents";
+/// Suffix of the temp file a file open for write is written to, see [`EncryptedFs::commit_contents`].
+pub(crate) const CONTENTS_TMP_SUFFIX: &str = ".tmp";
 pub(crate) const SECURITY_DIR: &str = "security";
+pub(crate) const OBJECTS_DIR: &str = "objects";
 pub(crate) const KEY_ENC_FILENAME: &str = "key.enc";
 pub(crate) const KEY_SALT_FILENAME: &str = "key.salt";
+/// Old master key, exists only while a key rotation is in progress.
+pub(crate) const KEY_OLD_ENC_FILENAME: &str = "key.old.enc";
+/// Last inode re-encrypted by the key rotation.
+pub(crate) const KEY_ROTATION_PROGRESS_FILENAME: &str = "key.rotation";
+/// Ciphers we migrate from and to, exists only while a cipher migration is in progress.
+pub(crate) const CIPHER_MIGRATION_FILENAME: &str = "cipher.migration";
 
 pub(crate) const LS_DIR: &str = "ls";
 pub(crate) const HASH_DIR: &str = "hash";
@@ -274,8 +336,16 @@ pub enum FsError {
     Other(&'static str),
     #[error("invalid password")]
     InvalidPassword,
+    #[error("invalid TOTP code")]
+    InvalidTotpCode,
+    #[error("filesystem is locked")]
+    Locked,
+    #[error("read-only filesystem")]
+    ReadOnly,
     #[error("invalid structure of data directory")]
     InvalidDataDirStructure,
+    #[error("there is no volume in the data directory")]
+    NoVolume,
     #[error("crypto error: {source}")]
     Crypto {
         #[from]
@@ -302,6 +372,22 @@ pub enum FsError {
     },
     #[error("max filesize exceeded, max allowed {0}")]
     MaxFilesizeExceeded(usize),
+    #[error("unsupported format version {0} of data directory, supported up to {1}")]
+    UnsupportedFormatVersion(u32, u32),
+    #[error(
+        "format version {0} of data directory needs to be migrated, open it without read-only"
+    )]
+    FormatMigrationNeeded(u32),
+    #[error("no space left, quota of {0} bytes exceeded")]
+    QuotaExceeded(u64),
+    #[error("excluded by a passthrough rule")]
+    Excluded,
+    #[error("data directory is in use by another process{}", .0.map_or_else(String::new, |pid| format!(" ({pid})")))]
+    DataDirInUse(Option<u32>),
+    #[error("audit log is not intact at record {0}: {1}")]
+    AuditLogBroken(u64, &'static str),
+    #[error("content tree can't be trusted, {0}, rebuild it if you trust the files as they are")]
+    ContentTreeBroken(&'static str),
 }
 
 #[derive(Debug, Clone)]
@@ -479,40 +565,79 @@ enum WriteHandleContextOperation {
     Create { ino: u64 },
 }
 
-impl WriteHandleContextOperation {
-    const fn get_ino(&self) -> u64 {
-        match *self {
-            Self::Create { ino, .. } => ino,
-        }
-    }
-}
-
 struct WriteHandleContext {
     ino: u64,
     attr: TimesAndSizeFileAttr,
+    /// Writes to the temp file from [`EncryptedFs::contents_tmp_path`], `None` until the first write after a commit.
     writer: Option<Box<dyn CryptoWriteSeek<File>>>,
 }
 
 struct KeyProvider {
     key_path: PathBuf,
     salt_path: PathBuf,
-    password_provider: Box<dyn PasswordProvider>,
+    // where to look for the key of a hidden volume
+    objects_dir: PathBuf,
+    password_provider: Arc<dyn PasswordProvider>,
+    cipher: Cipher,
+}
+
+/// Provides the old master key while a key rotation is in progress.
+struct OldKeyProvider {
+    key_path: PathBuf,
+    salt_path: PathBuf,
+    password_provider: Arc<dyn PasswordProvider>,
     cipher: Cipher,
 }
 
 #[async_trait]
-impl ValueProvider<SecretVec<u8>, FsError> for KeyProvider {
-    async fn provide(&self) -> Result<SecretVec<u8>, FsError> {
+impl ValueProvider<LockedVec, FsError> for OldKeyProvider {
+    async fn provide(&self) -> Result<LockedVec, FsError> {
         let password = self
             .password_provider
             .get_password()
             .ok_or(FsError::InvalidPassword)?;
-        read_or_create_key(&self.key_path, &self.salt_path, &password, self.cipher)
+        read_key(&self.key_path, &self.salt_path, &password, self.cipher)
+    }
+}
+
+#[async_trait]
+impl ValueProvider<LockedVec, FsError> for KeyProvider {
+    async fn provide(&self) -> Result<LockedVec, FsError> {
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        read_or_create_key(
+            &self.key_path,
+            &self.salt_path,
+            &self.objects_dir,
+            &password,
+            self.cipher,
+        )
     }
 }
 
 pub trait PasswordProvider: Send + Sync + 'static {
-    fn get_password(&self) -> Option<SecretString>;
+    /// Locked in memory, see [`Locked`].
+    fn get_password(&self) -> Option<LockedString>;
+
+    /// Current code from the authenticator app, needed only if [`EncryptedFs::enroll_totp`] was used.
+    fn get_totp_code(&self) -> Option<SecretString> {
+        None
+    }
+
+    /// Called when the filesystem is locked, see [`EncryptedFs::lock`], or the password was wrong. If you keep the
+    /// password, drop it and get it from the user again.
+    fn forget_password(&self) {}
+}
+
+/// For operations on the data dir that are given the password directly.
+struct StaticPasswordProvider(LockedString);
+
+impl PasswordProvider for StaticPasswordProvider {
+    fn get_password(&self) -> Option<LockedString> {
+        Some(self.0.clone())
+    }
 }
 
 struct DirEntryNameCacheProvider {}
@@ -523,6 +648,14 @@ impl ValueProvider<Mutex<LruCache<String, SecretString>>, FsError> for DirEntryN
     }
 }
 
+struct DirEntryHashCacheProvider {}
+#[async_trait]
+impl ValueProvider<Mutex<DirEntryHashCache>, FsError> for DirEntryHashCacheProvider {
+    async fn provide(&self) -> Result<Mutex<DirEntryHashCache>, FsError> {
+        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+    }
+}
+
 struct DirEntryMetaCacheProvider {}
 #[async_trait]
 impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvider {
@@ -533,13 +666,404 @@ impl ValueProvider<Mutex<DirEntryMetaCache>, FsError> for DirEntryMetaCacheProvi
 
 struct AttrCacheProvider {}
 #[async_trait]
-impl ValueProvider<RwLock<LruCache<u64, FileAttr>>, FsError> for AttrCacheProvider {
-    async fn provide(&self) -> Result<RwLock<LruCache<u64, FileAttr>>, FsError> {
+impl ValueProvider<RwLock<AttrCache>, FsError> for AttrCacheProvider {
+    async fn provide(&self) -> Result<RwLock<AttrCache>, FsError> {
         Ok(RwLock::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
     }
 }
 
-type DirEntryMetaCache = LruCache<String, (u64, FileType)>;
+struct FileKeysCacheProvider {}
+#[async_trait]
+impl ValueProvider<Mutex<FileKeysCache>, FsError> for FileKeysCacheProvider {
+    async fn provide(&self) -> Result<Mutex<FileKeysCache>, FsError> {
+        Ok(Mutex::new(LruCache::new(NonZeroUsize::new(2000).unwrap())))
+    }
+}
+
+/// With when they were read, they are used for [`FsOptions::attr_ttl`].
+type AttrCache = LruCache<u64, (FileAttr, Instant)>;
+
+/// Path of the `ls` entry -> (ino, kind) and when it was read, used for [`FsOptions::entry_ttl`].
+type DirEntryMetaCache = LruCache<String, ((u64, FileType), Instant)>;
+
+/// Path of the `hash` entry -> (ino, kind, encrypted name), [`None`] if there is no such entry, and when it was
+/// read, used for [`FsOptions::entry_ttl`].
+type DirEntryHashCache = LruCache<String, (Option<(u64, FileType, String)>, Instant)>;
+
+/// Per-file keys, `None` for files created before we had them, they use the master key.
+type FileKeysCache = LruCache<u64, Option<Arc<LockedVec>>>;
+
+/// Entries of a directory in [`StorageLayout::Flat`], encrypted name -> (ino, kind).
+type DirIndex = BTreeMap<String, (u64, FileType)>;
+
+/// Where [`EncryptedFs::create_directory_entry`] reads an entry from, depends on [`StorageLayout`].
+enum RawDirEntry {
+    /// A file in the `ls` directory.
+    Ls(io::Result<DirEntry>),
+    /// An entry from the [`DirIndex`], with the encrypted name.
+    Index(String, u64, FileType),
+}
+
+/// How the encrypted objects are laid out in the data dir.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, EnumIter, EnumString, Display)]
+pub enum StorageLayout {
+    /// `inodes` and `contents` directories, each directory keeps its entries as separate files
+    /// in `ls` and `hash` subdirectories.
+    #[default]
+    Hierarchical,
+    /// All objects live in a single `objects` directory, named by a keyed hash. The entries of a directory
+    /// are kept in one encrypted index padded to fixed size classes, so the data dir reveals neither
+    /// the tree depth nor the number of files per directory.
+    Flat,
+}
+
+impl StorageLayout {
+    const fn dirs(self) -> &'static [&'static str] {
+        match self {
+            Self::Hierarchical => &[INODES_DIR, CONTENTS_DIR, SECURITY_DIR],
+            Self::Flat => &[OBJECTS_DIR, SECURITY_DIR],
+        }
+    }
+
+    /// Layout of an existing data dir, if any.
+    fn detect(data_dir: &Path) -> Option<Self> {
+        if data_dir.join(OBJECTS_DIR).is_dir() {
+            Some(Self::Flat)
+        } else if data_dir.join(INODES_DIR).is_dir() {
+            Some(Self::Hierarchical)
+        } else {
+            None
+        }
+    }
+}
+
+/// Of [`FsOptions::attr_ttl`] and [`FsOptions::entry_ttl`] when they are not set.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(1);
+
+/// Smallest size class used when [`FsOptions::pad_file_sizes`] is enabled.
+pub(crate) const PADDING_MIN_SIZE: u64 = 4096;
+
+/// Optional behaviour of [`EncryptedFs`]. Everything is disabled by default.
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct FsOptions {
+    /// Pad the content of files with zeros up to fixed size classes, so the size of the encrypted files
+    /// from data dir doesn't leak the exact size of the plaintext. The real size is kept in the encrypted inode.
+    pub pad_file_sizes: bool,
+    /// Layout used when creating a new data dir. An existing data dir keeps the layout it was created with.
+    pub layout: StorageLayout,
+    /// Create the volume if the data dir doesn't have one yet. Without it opening a missing or empty data dir fails
+    /// with [`FsError::NoVolume`], so a mistyped path doesn't look like an empty volume.
+    pub create: bool,
+    /// Start a key rotation in background after it's created, see [`EncryptedFs::rotate_key`].
+    pub rotate_key: bool,
+    /// Lock after this much time without activity, see [`EncryptedFs::lock`].
+    pub auto_lock: Option<Duration>,
+    /// Reject all operations that would change the data dir, with [`FsError::ReadOnly`]. Access times are not
+    /// updated either. The data dir must already exist.
+    pub read_only: bool,
+    /// Owners shown when mounted, see [`IdMap`].
+    pub id_map: IdMap,
+    /// Applied instead of the umask of the process creating files and directories when mounted.
+    pub umask: Option<u32>,
+    /// Permissions of new files when mounted, whatever the mode asked for. The umask is not applied to it.
+    pub file_mode: Option<u32>,
+    /// Like [`FsOptions::file_mode`], for directories.
+    pub dir_mode: Option<u32>,
+    /// Copy the data dir next to it, as `DATA_DIR.backup-vVERSION`, before migrating it to a newer format.
+    pub backup_before_migrate: bool,
+    /// Read and authenticate all chunks in background, at most this many bytes per second, once a day. Bad chunks
+    /// go to [`EncryptedFs::corruption_log`].
+    pub scrub_bandwidth: Option<u64>,
+    /// Keep this many prior versions of each file when it's overwritten or removed, see
+    /// [`EncryptedFs::versions`]. The oldest ones are removed first.
+    pub keep_versions: Option<usize>,
+    /// Remove the versions older than this. With only one of them set the other one is not limited.
+    pub version_retention: Option<Duration>,
+    /// Split the content of files into chunks when it's written and store each chunk once, so blocks that are the
+    /// same in several files take space only once. Chunks are named by a keyed hash of their plaintext. Only for
+    /// [`StorageLayout::Hierarchical`].
+    pub dedup: bool,
+    /// Compress each chunk with zstd at this level before it's encrypted, unless it doesn't compress. Files are
+    /// stored in chunks then, like with [`FsOptions::dedup`], and chunks are shared too. Only for
+    /// [`StorageLayout::Hierarchical`].
+    pub compression: Option<i32>,
+    /// Keep the data dir in this storage too, the data dir is then a local cache of it. It's brought up to date
+    /// from the storage when created, and what changes is put back in background, as it was between two
+    /// operations, see [`EncryptedFs::sync_storage`].
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Show what's in this volume too, opened read-only, under what's in this one. Files are copied up here when
+    /// written, what's removed here stays hidden, and entries here win over the ones there with the same name,
+    /// except dirs which are merged. A volume used with a base must be opened with it after.
+    pub base: Option<Arc<EncryptedFs>>,
+    /// Refuse writes that would make the sum of the sizes of all files bigger than this, with
+    /// [`FsError::QuotaExceeded`]. Sizes are of the plaintext, not of what's in the data dir. See
+    /// [`EncryptedFs::quota`].
+    pub max_size: Option<u64>,
+    /// Take a lock in the data dir while it's open, exclusive or shared when [`FsOptions::read_only`], so it can't be
+    /// mounted by another process at the same time, which would corrupt it. Opening it fails with
+    /// [`FsError::DataDirInUse`] while someone else has it.
+    pub lock_data_dir: bool,
+    /// Cost of deriving the key from the password, used only when the data dir is created. It's kept in its
+    /// header, see [`EncryptedFs::header`].
+    pub kdf: KdfParams,
+    /// How long decrypted attributes of inodes are kept in memory, and by the kernel when mounted, before they are
+    /// read again, [`DEFAULT_TTL`] if not set. What's changed through us updates them, so it's only about changes
+    /// made to the data dir from outside, like by a sync of [`FsOptions::storage`].
+    pub attr_ttl: Option<Duration>,
+    /// Like [`FsOptions::attr_ttl`], for directory entries looked up by name.
+    pub entry_ttl: Option<Duration>,
+    /// Look up names whatever their case, `Foo` finds `foo`, which is listed as it was created. The index of the
+    /// data dir is changed for it the first time it's opened with it, which fails if a directory has names that
+    /// differ only in case, and it stays case-insensitive after.
+    pub case_insensitive: bool,
+    /// When a file is removed, overwrite its inode, which has its key, and its content with random bytes and truncate
+    /// them before they are removed, so they can't be recovered from an image of the storage taken after. The old
+    /// copies replaced when they are rewritten are overwritten too. Content still used by snapshots or versions is kept.
+    pub secure_delete: bool,
+    /// Append each operation that changes something while mounted, with its path, who did it, when and how it went,
+    /// to a log in the data dir, each record encrypted by itself and chained to the one before, so removing, moving
+    /// or cutting records is detected. Read it with [`EncryptedFs::audit_log`].
+    pub audit_log: bool,
+    /// Only with [`FsOptions::audit_log`], also log operations that only read, like opening for read and listing.
+    pub audit_reads: bool,
+}
+
+impl FsOptions {
+    #[must_use]
+    pub const fn with_pad_file_sizes(mut self, pad_file_sizes: bool) -> Self {
+        self.pad_file_sizes = pad_file_sizes;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_layout(mut self, layout: StorageLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_rotate_key(mut self, rotate_key: bool) -> Self {
+        self.rotate_key = rotate_key;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_auto_lock(mut self, auto_lock: Option<Duration>) -> Self {
+        self.auto_lock = auto_lock;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    #[must_use]
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn with_id_map(mut self, id_map: IdMap) -> Self {
+        self.id_map = id_map;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_umask(mut self, umask: Option<u32>) -> Self {
+        self.umask = umask;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_file_mode(mut self, file_mode: Option<u32>) -> Self {
+        self.file_mode = file_mode;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_dir_mode(mut self, dir_mode: Option<u32>) -> Self {
+        self.dir_mode = dir_mode;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_backup_before_migrate(mut self, backup_before_migrate: bool) -> Self {
+        self.backup_before_migrate = backup_before_migrate;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_scrub_bandwidth(mut self, scrub_bandwidth: Option<u64>) -> Self {
+        self.scrub_bandwidth = scrub_bandwidth;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_keep_versions(mut self, keep_versions: Option<usize>) -> Self {
+        self.keep_versions = keep_versions;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_version_retention(mut self, version_retention: Option<Duration>) -> Self {
+        self.version_retention = version_retention;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Option<i32>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    #[must_use]
+    pub fn with_storage(mut self, storage: Option<Arc<dyn Storage>>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    #[must_use]
+    pub fn with_base(mut self, base: Option<Arc<EncryptedFs>>) -> Self {
+        self.base = base;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_lock_data_dir(mut self, lock_data_dir: bool) -> Self {
+        self.lock_data_dir = lock_data_dir;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_kdf(mut self, kdf: KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_attr_ttl(mut self, attr_ttl: Option<Duration>) -> Self {
+        self.attr_ttl = attr_ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_entry_ttl(mut self, entry_ttl: Option<Duration>) -> Self {
+        self.entry_ttl = entry_ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_secure_delete(mut self, secure_delete: bool) -> Self {
+        self.secure_delete = secure_delete;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_audit_log(mut self, audit_log: bool) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_audit_reads(mut self, audit_reads: bool) -> Self {
+        self.audit_reads = audit_reads;
+        self
+    }
+}
+
+/// Show files as owned by other users and groups than the ones stored in the inodes, so a data dir created by one
+/// user can be mounted and used by another, like on another machine, without changing the owner of each file.
+/// New owners are mapped back when stored.
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    /// Pairs of stored and shown uid.
+    pub uids: Vec<(u32, u32)>,
+    /// Pairs of stored and shown gid.
+    pub gids: Vec<(u32, u32)>,
+    /// Show everything as owned by the user and group that mounted it, whatever is stored.
+    pub to_mounter: bool,
+}
+
+impl IdMap {
+    #[must_use]
+    pub fn uid(&self, stored: u32) -> u32 {
+        if self.to_mounter {
+            return mounter_ids().0;
+        }
+        map_id(&self.uids, stored)
+    }
+
+    #[must_use]
+    pub fn gid(&self, stored: u32) -> u32 {
+        if self.to_mounter {
+            return mounter_ids().1;
+        }
+        map_id(&self.gids, stored)
+    }
+
+    #[must_use]
+    pub fn stored_uid(&self, shown: u32) -> u32 {
+        map_id_back(&self.uids, shown)
+    }
+
+    #[must_use]
+    pub fn stored_gid(&self, shown: u32) -> u32 {
+        map_id_back(&self.gids, shown)
+    }
+
+    /// Owner of `attr` as shown.
+    #[must_use]
+    pub fn map_attr(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = self.uid(attr.uid);
+        attr.gid = self.gid(attr.gid);
+        attr
+    }
+}
+
+fn map_id(map: &[(u32, u32)], stored: u32) -> u32 {
+    map.iter()
+        .find(|(from, _)| *from == stored)
+        .map_or(stored, |(_, to)| *to)
+}
+
+fn map_id_back(map: &[(u32, u32)], shown: u32) -> u32 {
+    map.iter()
+        .find(|(_, to)| *to == shown)
+        .map_or(shown, |(from, _)| *from)
+}
+
+#[allow(clippy::missing_const_for_fn)]
+fn mounter_ids() -> (u32, u32) {
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    unsafe {
+        (libc::getuid(), libc::getgid())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+    (0, 0)
+}
 
 /// Encrypted FS that stores encrypted files in a dedicated directory with a specific structure based on `inode`.
 pub struct EncryptedFs {
@@ -548,9 +1072,17 @@ pub struct EncryptedFs {
     read_handles: RwLock<HashMap<u64, Mutex<ReadHandleContext>>>,
     current_handle: AtomicU64,
     cipher: Cipher,
+    options: FsOptions,
+    layout: StorageLayout,
+    // names in the `hash` index are in lower case, see [`FsOptions::case_insensitive`]
+    case_insensitive: bool,
+    // used to name the objects in [`StorageLayout::Flat`]
+    object_names_key: LockedVec,
     // (ino, fh)
     opened_files_for_read: RwLock<HashMap<u64, HashSet<u64>>>,
     opened_files_for_write: RwLock<HashMap<u64, u64>>,
+    // of the [`EncryptedFile`]s dropped while open, waited for by [`Self::flush_all`]
+    releases: std::sync::Mutex<file::Releases>,
     // used for rw ops of actual serialization
     // use std::sync::RwLock instead of tokio::sync::RwLock because we need to use it also in sync code in `DirectoryEntryIterator` and `DirectoryEntryPlusIterator`
     serialize_inode_locks: Arc<ArcHashMap<u64, RwLock<bool>>>,
@@ -560,33 +1092,178 @@ pub struct EncryptedFs {
     serialize_dir_entries_ls_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     serialize_dir_entries_hash_locks: Arc<ArcHashMap<String, RwLock<bool>>>,
     read_write_locks: ArcHashMap<u64, RwLock<bool>>,
-    key: ExpireValue<SecretVec<u8>, FsError, KeyProvider>,
+    key: ExpireValue<LockedVec, FsError, KeyProvider>,
+    old_key: ExpireValue<LockedVec, FsError, OldKeyProvider>,
+    // only one key rotation at a time
+    key_rotation_lock: Mutex<()>,
+    password_provider: Arc<dyn PasswordProvider>,
+    // old cipher to try when decrypting metadata, while migrating to another one
+    migrating_from: Option<Cipher>,
     self_weak: std::sync::Mutex<Option<Weak<Self>>>,
-    attr_cache: ExpireValue<RwLock<LruCache<u64, FileAttr>>, FsError, AttrCacheProvider>,
+    attr_cache: ExpireValue<RwLock<AttrCache>, FsError, AttrCacheProvider>,
     dir_entries_name_cache:
         ExpireValue<Mutex<LruCache<String, SecretString>>, FsError, DirEntryNameCacheProvider>,
     dir_entries_meta_cache:
         ExpireValue<Mutex<DirEntryMetaCache>, FsError, DirEntryMetaCacheProvider>,
+    dir_entries_hash_cache:
+        ExpireValue<Mutex<DirEntryHashCache>, FsError, DirEntryHashCacheProvider>,
+    file_keys_cache: ExpireValue<Mutex<FileKeysCache>, FsError, FileKeysCacheProvider>,
+    locked: AtomicBool,
+    last_access: std::sync::Mutex<Instant>,
+    /// Loaded when it's not read-only, see [`EncryptedFs::verify`].
+    content_tree: Mutex<Option<integrity::ContentTree>>,
+    corruption_log_lock: Mutex<()>,
+    /// Where the audit log ends, `None` until it's first read. Locked while it's written.
+    audit_chain: Mutex<Option<audit::AuditHead>>,
+    /// Paths of inodes, for [`FsOptions::audit_log`].
+    audit_paths: std::sync::Mutex<LruCache<u64, String>>,
+    versions_lock: Mutex<()>,
+    /// Counted the first time a file is deduplicated, see [`FsOptions::dedup`].
+    dedup_refs: Mutex<Option<dedup::ChunkRefs>>,
+    storage_sync_lock: Mutex<()>,
+    /// Entered by the operations that change the data dir, see [`EncryptedFs::sync_storage`].
+    changes: mirror::ChangeGate,
+    /// Written since it was opened, see [`EncryptedFs::write_chaff`].
+    chaff: std::sync::Mutex<Vec<PathBuf>>,
+    /// Loaded the first time it's needed, see [`FsOptions::base`].
+    overlay_index: Mutex<Option<overlay::OverlayIndex>>,
+    /// Dirs merged with the base since it was opened.
+    overlay_merged: std::sync::Mutex<HashSet<u64>>,
+    overlay_merge_locks: ArcHashMap<u64, Mutex<bool>>,
+    /// Counted when it's opened, if there is a [`FsOptions::max_size`].
+    quota_used: std::sync::Mutex<Option<u64>>,
+    /// Loaded the first time it's needed, see [`PassthroughRule`].
+    passthrough: Mutex<Option<passthrough::Passthrough>>,
+    /// Held until it's dropped, see [`FsOptions::lock_data_dir`].
+    _data_dir_lock: Option<dir_lock::DataDirLock>,
+}
+
+impl Debug for EncryptedFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedFs")
+            .field("data_dir", &self.data_dir)
+            .finish_non_exhaustive()
+    }
 }
 
 impl EncryptedFs {
+    /// Open the volume in `data_dir`, or create it with [`FsOptions::create`]. `cipher` is used for a new volume, one
+    /// that has a [`VolumeHeader`] is opened with the cipher it was created with.
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn new(
         data_dir: PathBuf,
         password_provider: Box<dyn PasswordProvider>,
         cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        if data_dir
+            .join(SECURITY_DIR)
+            .join(CIPHER_MIGRATION_FILENAME)
+            .exists()
+        {
+            return Err(FsError::Other(
+                "cipher migration in progress, run it again to finish it",
+            ));
+        }
+        Self::new_internal(data_dir, password_provider, cipher, options, None, true).await
+    }
+
+    /// `migrating_from` is the cipher to fall back to when decrypting, used by [`EncryptedFs::migrate_cipher`],
+    /// which also doesn't ask for a TOTP code, like other changes that need only the password.
+    #[allow(clippy::too_many_lines)]
+    async fn new_internal(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        options: FsOptions,
+        migrating_from: Option<Cipher>,
+        check_totp: bool,
     ) -> FsResult<Arc<Self>> {
+        // a mistyped path would look like an empty volume, refuse before the lock file is created in it. A storage
+        // might have one to pull
+        if !options.create && options.storage.is_none() && !has_volume(&data_dir) {
+            return Err(FsError::NoVolume);
+        }
+        // before anything changes it
+        let data_dir_lock = if options.lock_data_dir && (!options.read_only || data_dir.is_dir()) {
+            fs::create_dir_all(&data_dir)?;
+            dir_lock::DataDirLock::acquire(&data_dir, options.read_only)?
+        } else {
+            None
+        };
+        if let Some(storage) = &options.storage {
+            let (storage, dir) = (storage.clone(), data_dir.clone());
+            mirror::blocking(move || mirror::pull_storage(&*storage, &dir)).await?;
+            if !options.create && !has_volume(&data_dir) {
+                return Err(FsError::NoVolume);
+            }
+        }
+
+        let layout = if options.read_only {
+            if options.rotate_key {
+                return Err(FsError::InvalidInput(
+                    "can't rotate key on a read-only filesystem",
+                ));
+            }
+            check_structure(&data_dir, false).await?;
+            StorageLayout::detect(&data_dir).ok_or(FsError::InvalidDataDirStructure)?
+        } else {
+            ensure_structure_created(&data_dir.clone(), options.layout).await?
+        };
+        if options.dedup && layout == StorageLayout::Flat {
+            return Err(FsError::InvalidInput(
+                "deduplication is not supported for flat layout",
+            ));
+        }
+        if options.compression.is_some() && layout == StorageLayout::Flat {
+            return Err(FsError::InvalidInput(
+                "compression is not supported for flat layout",
+            ));
+        }
+        if options.base.is_some() && options.read_only {
+            return Err(FsError::InvalidInput("a base needs a writable volume"));
+        }
+        let security_dir = data_dir.join(SECURITY_DIR);
+        if !options.read_only
+            && !security_dir.join(KEY_ENC_FILENAME).exists()
+            && header::read_header(&security_dir)?.is_none()
+        {
+            // new data dir, before the key is created with its cost
+            header::write_header(&security_dir, &VolumeHeader::new(cipher, options.kdf))?;
+        }
+        header::check_chunk_size(&security_dir)?;
+        // the one it was created with, while migrating we're given the new one
+        let cipher = if migrating_from.is_none() {
+            header::volume_cipher(&security_dir, cipher)?
+        } else {
+            cipher
+        };
+        let password_provider: Arc<dyn PasswordProvider> = Arc::from(password_provider);
         let key_provider = KeyProvider {
-            key_path: data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
-            salt_path: data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
-            password_provider,
+            key_path: security_dir.join(KEY_ENC_FILENAME),
+            salt_path: security_dir.join(KEY_SALT_FILENAME),
+            objects_dir: data_dir.join(OBJECTS_DIR),
+            password_provider: password_provider.clone(),
             cipher,
         };
         let key = ExpireValue::new(key_provider, Duration::from_secs(10 * 60));
-
-        ensure_structure_created(&data_dir.clone()).await?;
-        key.get().await?; // this will check the password
+        let old_key_provider = OldKeyProvider {
+            key_path: security_dir.join(KEY_OLD_ENC_FILENAME),
+            salt_path: security_dir.join(KEY_SALT_FILENAME),
+            password_provider: password_provider.clone(),
+            cipher,
+        };
+        let old_key = ExpireValue::new(old_key_provider, Duration::from_secs(10 * 60));
+        let case_insensitive = case::is_case_insensitive(&security_dir);
+        if options.read_only && options.case_insensitive && !case_insensitive {
+            return Err(FsError::InvalidInput(
+                "can't make a read-only filesystem case-insensitive",
+            ));
+        }
+        let case_insensitive = case_insensitive || options.case_insensitive;
+        // this will check the password
+        let object_names_key = crypto::derive_object_names_key(&*key.get().await?);
 
         let fs = Self {
             data_dir,
@@ -594,13 +1271,22 @@ impl EncryptedFs {
             read_handles: RwLock::new(HashMap::new()),
             current_handle: AtomicU64::new(1),
             cipher,
+            options,
+            layout,
+            case_insensitive,
+            object_names_key,
             opened_files_for_read: RwLock::new(HashMap::new()),
             opened_files_for_write: RwLock::new(HashMap::new()),
+            releases: std::sync::Mutex::default(),
             serialize_inode_locks: Arc::new(ArcHashMap::default()),
             serialize_update_inode_locks: ArcHashMap::default(),
             serialize_dir_entries_ls_locks: Arc::new(ArcHashMap::default()),
             serialize_dir_entries_hash_locks: Arc::new(ArcHashMap::default()),
             key,
+            old_key,
+            key_rotation_lock: Mutex::new(()),
+            password_provider,
+            migrating_from,
             self_weak: std::sync::Mutex::new(None),
             read_write_locks: ArcHashMap::default(),
             // todo: take duration from param
@@ -615,6 +1301,34 @@ impl EncryptedFs {
                 DirEntryMetaCacheProvider {},
                 Duration::from_secs(10 * 60),
             ),
+            dir_entries_hash_cache: ExpireValue::new(
+                DirEntryHashCacheProvider {},
+                Duration::from_secs(10 * 60),
+            ),
+            // todo: take duration from param
+            file_keys_cache: ExpireValue::new(
+                FileKeysCacheProvider {},
+                Duration::from_secs(10 * 60),
+            ),
+            locked: AtomicBool::new(false),
+            last_access: std::sync::Mutex::new(Instant::now()),
+            content_tree: Mutex::new(None),
+            corruption_log_lock: Mutex::new(()),
+            audit_chain: Mutex::new(None),
+            audit_paths: std::sync::Mutex::new(LruCache::new(
+                NonZeroUsize::new(10 * 1024).unwrap(),
+            )),
+            versions_lock: Mutex::new(()),
+            dedup_refs: Mutex::new(None),
+            storage_sync_lock: Mutex::new(()),
+            changes: mirror::ChangeGate::default(),
+            chaff: std::sync::Mutex::new(vec![]),
+            overlay_index: Mutex::new(None),
+            overlay_merged: std::sync::Mutex::new(HashSet::new()),
+            overlay_merge_locks: ArcHashMap::default(),
+            quota_used: std::sync::Mutex::new(None),
+            passthrough: Mutex::new(None),
+            _data_dir_lock: data_dir_lock,
         };
 
         let arc = Arc::new(fs);
@@ -623,17 +1337,75 @@ impl EncryptedFs {
             .expect("cannot obtain lock")
             .replace(Arc::downgrade(&arc));
 
-        arc.ensure_root_exists().await?;
+        if check_totp {
+            arc.check_totp().await?;
+        }
+        arc.migrate_format()?;
+        if !arc.options.read_only {
+            let created = !arc.exists(ROOT_INODE);
+            arc.ensure_root_exists().await?;
+            arc.load_content_tree(created).await?;
+            arc.replay_journal().await?;
+            arc.remove_stale_contents_tmp()?;
+            arc.make_case_insensitive().await?;
+        }
+        arc.count_usage().await?;
+
+        if !arc.options.read_only && (arc.options.rotate_key || arc.is_key_rotation_in_progress()) {
+            let fs = arc.clone();
+            tokio::spawn(async move {
+                if let Err(err) = fs.rotate_key().await {
+                    error!(err = %err, "rotating key");
+                }
+            });
+        }
+        if let Some(timeout) = arc.options.auto_lock {
+            arc.spawn_auto_lock(timeout);
+        }
+        if let Some(bandwidth) = arc.options.scrub_bandwidth {
+            arc.spawn_scrub(bandwidth);
+        }
+        if arc.options.storage.is_some() && !arc.options.read_only {
+            arc.spawn_storage_sync();
+        }
 
         Ok(arc)
     }
 
+    /// If it was opened with [`FsOptions::read_only`], then nothing can be changed.
+    #[must_use]
+    pub const fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    /// See [`FsOptions::attr_ttl`].
+    #[must_use]
+    pub fn attr_ttl(&self) -> Duration {
+        self.options.attr_ttl.unwrap_or(DEFAULT_TTL)
+    }
+
+    /// See [`FsOptions::entry_ttl`].
+    #[must_use]
+    pub fn entry_ttl(&self) -> Duration {
+        self.options.entry_ttl.unwrap_or(DEFAULT_TTL)
+    }
+
+    const fn check_writable(&self) -> FsResult<()> {
+        if self.options.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
     pub fn exists(&self, ino: u64) -> bool {
         self.ino_file(ino).is_file()
     }
 
     pub fn is_dir(&self, ino: u64) -> bool {
-        self.contents_path(ino).is_dir()
+        match self.layout {
+            StorageLayout::Hierarchical => self.contents_path(ino).is_dir(),
+            StorageLayout::Flat => self.dir_index_path(ino).is_file(),
+        }
     }
 
     pub fn is_file(&self, ino: u64) -> bool {
@@ -652,15 +1424,23 @@ impl EncryptedFs {
         read: bool,
         write: bool,
     ) -> FsResult<(u64, FileAttr)> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         if name.expose_secret() == "." || name.expose_secret() == ".." {
             return Err(FsError::InvalidInput("name cannot be '.' or '..'"));
         }
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
+        self.merge_dir(parent).await?;
         if self.exists_by_name(parent, name)? {
             return Err(FsError::AlreadyExists);
         }
+        let plaintext = match self.passthrough_action(parent, name).await? {
+            Some(PassthroughAction::Exclude) => return Err(FsError::Excluded),
+            Some(PassthroughAction::Plaintext) => create_attr.kind == FileType::RegularFile,
+            None => false,
+        };
 
         // spawn on a dedicated runtime to not interfere with other more priority tasks
         let self_clone = self
@@ -676,20 +1456,50 @@ impl EncryptedFs {
             .spawn(async move {
                 let mut attr: FileAttr = create_attr.into();
                 attr.ino = self_clone.generate_next_inode();
+                let encrypted_name = self_clone.encrypt_entry_name(&name_clone).await?;
+                let journal = self_clone
+                    .journal_begin(&JournalOp::Create {
+                        parent,
+                        name: name_clone.expose_secret().clone(),
+                        encrypted_name: encrypted_name.clone(),
+                        ino: attr.ino,
+                        kind: attr.kind,
+                    })
+                    .await?;
 
                 let fs = self_clone;
                 let mut join_set = JoinSet::new();
 
                 // write inode
                 let self_clone = fs.clone();
-                self_clone.write_inode_to_storage(&attr).await?;
+                if attr.kind == FileType::RegularFile {
+                    // each file has its own key, wrapped by the master key in the inode
+                    let key = LockedVec::random(self_clone.cipher.key_len());
+                    self_clone
+                        .write_inode_with_key_to_storage(&attr, Some(&key))
+                        .await?;
+                    if plaintext {
+                        self_clone.set_plaintext(attr.ino).await?;
+                    }
+                } else {
+                    self_clone.write_inode_to_storage(&attr).await?;
+                }
 
                 match attr.kind {
                     FileType::RegularFile => {
                         let self_clone = fs.clone();
                         join_set.spawn(async move {
                             // create in contents directory
-                            let file = File::create(self_clone.contents_path(attr.ino))?;
+                            let mut file = File::create(self_clone.contents_path(attr.ino))?;
+                            if self_clone.options.pad_file_sizes && !plaintext {
+                                let mut writer = crypto::create_write(
+                                    file,
+                                    self_clone.cipher,
+                                    &*self_clone.file_key(attr.ino).await?,
+                                );
+                                stream_util::fill_zeros(&mut writer, padded_size(0))?;
+                                file = writer.finish()?;
+                            }
                             // sync_all file and parent
                             // these operations are a bit slow, but are needed to make sure the file is correctly created
                             // i.e. creating 100 files takes 0.965 sec with sync_all and 0.130 sec without
@@ -701,6 +1511,7 @@ impl EncryptedFs {
                                     .expect("oops, we don't have a parent"),
                             )?
                             .sync_all()?;
+                            self_clone.update_content_leaf(attr.ino).await?;
                             Ok::<(), FsError>(())
                         });
                     }
@@ -708,14 +1519,7 @@ impl EncryptedFs {
                         let self_clone = fs.clone();
                         let attr_clone = attr;
                         join_set.spawn(async move {
-                            // create in contents directory
-                            let contents_dir = self_clone.contents_path(attr.ino);
-                            fs::create_dir(contents_dir.clone())?;
-                            // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
-                            fs::create_dir(contents_dir.join(LS_DIR))?;
-                            // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
-                            // this optimizes the search process as we don't need to decrypt all file names and search
-                            fs::create_dir(contents_dir.join(HASH_DIR))?;
+                            self_clone.create_dir_storage(attr.ino).await?;
 
                             // add "." and ".." entries
                             self_clone
@@ -746,15 +1550,17 @@ impl EncryptedFs {
                 // edd entry in parent directory, used for listing
                 let self_clone = fs.clone();
                 let attr_clone = attr;
+                let entry_name = name_clone.clone();
                 join_set.spawn(async move {
                     self_clone
-                        .insert_directory_entry(
+                        .insert_directory_entry_as(
                             parent,
                             &DirectoryEntry {
                                 ino: attr_clone.ino,
-                                name: name_clone,
+                                name: entry_name,
                                 kind: attr_clone.kind,
                             },
+                            encrypted_name,
                         )
                         .await?;
                     Ok::<(), FsError>(())
@@ -779,6 +1585,8 @@ impl EncryptedFs {
                 while let Some(res) = join_set.join_next().await {
                     res??;
                 }
+                Self::journal_end(&journal)?;
+                fs.audit_learn_path(parent, &name_clone, attr.ino);
 
                 let self_clone = fs.clone();
                 let handle = if attr.kind == FileType::RegularFile {
@@ -811,33 +1619,81 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
-        if !hash_path.is_file() {
+        self.merge_dir(parent).await?;
+        let Some((ino, _, _)) = self.read_hash_entry(parent, name).await? else {
             return Ok(None);
+        };
+        self.audit_learn_path(parent, name, ino);
+        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+    }
+
+    /// Inode, kind and encrypted name of the entry, from the hash index.
+    async fn read_hash_entry(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<(u64, FileType, String)>> {
+        let hash_path = self.hash_entry_path(parent, name);
+        let key = hash_path.to_str().unwrap().to_string();
+        // try from cache
+        let lock = self.dir_entries_hash_cache.get().await?;
+        let cached = match lock.lock().await.get(&key) {
+            Some((entry, read)) if read.elapsed() < self.entry_ttl() => Some(entry.clone()),
+            _ => None,
+        };
+        metrics::DIR_ENTRY_HASH_CACHE.lookup(cached.is_some());
+        if let Some(entry) = cached {
+            return Ok(entry);
         }
-        let lock = self
+        let read = Instant::now();
+        let serialize_lock = self
             .serialize_dir_entries_hash_locks
-            .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
-                RwLock::new(false)
-            });
-        let guard = lock.read().await;
-        let (ino, _, _): (u64, FileType, String) = bincode::deserialize_from(crypto::create_read(
-            File::open(hash_path)?,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?;
-        drop(guard);
-        self.get_inode_from_cache_or_storage(ino).await.map(Some)
+            .get_or_insert_with(key.clone(), || RwLock::new(false));
+        // until it's cached, so a change made meanwhile is forgotten after
+        let _guard = serialize_lock.read().await;
+        // that it doesn't exist is cached too, build tools look for many files that don't
+        let entry = if hash_path.is_file() {
+            Some(self.deserialize_from_file(&hash_path).await?)
+        } else {
+            None
+        };
+        lock.lock().await.put(key, (entry.clone(), read));
+        Ok(entry)
+    }
+
+    /// Drop what's cached about the entries at these paths from `ls` and `hash` dirs, after they were changed.
+    async fn forget_dir_entries<P: AsRef<Path> + Sync>(&self, paths: &[P]) -> FsResult<()> {
+        let hash_cache = self.dir_entries_hash_cache.get().await?;
+        let meta_cache = self.dir_entries_meta_cache.get().await?;
+        let mut hash_cache = hash_cache.lock().await;
+        let mut meta_cache = meta_cache.lock().await;
+        for path in paths {
+            let key = path.as_ref().to_str().unwrap();
+            hash_cache.pop(key);
+            meta_cache.pop(key);
+        }
+        Ok(())
     }
 
     /// Count children of a directory. This **EXCLUDES** "." and "..".
     #[allow(clippy::missing_errors_doc)]
-    pub fn len(&self, ino: u64) -> FsResult<usize> {
+    pub async fn len(&self, ino: u64) -> FsResult<usize> {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let mut count = fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count();
+        self.merge_dir(ino).await?;
+        let mut count = match self.layout {
+            StorageLayout::Hierarchical => {
+                fs::read_dir(self.contents_path(ino).join(LS_DIR))?.count()
+            }
+            StorageLayout::Flat => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(ino), || RwLock::new(false));
+                let _guard = lock.read().await;
+                self.read_dir_index(ino).await?.len()
+            }
+        };
         if ino == ROOT_INODE {
             // we don't count "."
             count -= 1;
@@ -852,9 +1708,12 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_dir(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
+        self.merge_dir(parent).await?;
 
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
@@ -868,7 +1727,7 @@ impl EncryptedFs {
             return Err(FsError::InvalidInodeType);
         }
         // check if it's empty
-        if self.len(attr.ino)? > 0 {
+        if self.len(attr.ino).await? > 0 {
             return Err(FsError::NotEmpty);
         }
         let self_clone = self
@@ -882,6 +1741,9 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
+                let journal = self_clone
+                    .journal_remove(parent, &name_clone, &attr)
+                    .await?;
                 // remove inode file
                 {
                     let lock = self_clone
@@ -892,11 +1754,12 @@ impl EncryptedFs {
                 }
 
                 // remove contents directory
-                fs::remove_dir_all(self_clone.contents_path(attr.ino))?;
+                self_clone.remove_dir_storage(attr.ino).await?;
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
                     .await?;
+                Self::journal_end(&journal)?;
                 // remove from cache
                 self_clone
                     .attr_cache
@@ -917,18 +1780,22 @@ impl EncryptedFs {
                     )
                     .await?;
 
-                Ok(())
+                Ok::<_, FsError>(())
             })
-            .await?
+            .await??;
+        self.overlay_removed(parent, name, attr.ino).await
     }
 
     /// Delete a file
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::missing_errors_doc)]
     pub async fn remove_file(&self, parent: u64, name: &SecretString) -> FsResult<()> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
+        self.merge_dir(parent).await?;
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
         }
@@ -940,6 +1807,8 @@ impl EncryptedFs {
         if !matches!(attr.kind, FileType::RegularFile) {
             return Err(FsError::InvalidInodeType);
         }
+        self.keep_version(attr.ino, Some((parent, name.expose_secret().clone())))
+            .await?;
         let self_clone = self
             .self_weak
             .lock()
@@ -951,21 +1820,34 @@ impl EncryptedFs {
         let name_clone = name.clone();
         NOD_RT
             .spawn(async move {
+                let journal = self_clone
+                    .journal_remove(parent, &name_clone, &attr)
+                    .await?;
                 // remove inode file
                 {
                     let lock = self_clone
                         .serialize_inode_locks
                         .get_or_insert_with(attr.ino, || RwLock::new(false));
                     let _guard = lock.write();
-                    fs::remove_file(self_clone.ino_file(attr.ino))?;
+                    self_clone.remove_data_file(&self_clone.ino_file(attr.ino))?;
                 }
 
                 // remove from contents directory
-                fs::remove_file(self_clone.contents_path(attr.ino))?;
+                self_clone.remove_data_file(&self_clone.contents_path(attr.ino))?;
+                self_clone.remove_manifest(attr.ino).await?;
+                self_clone.update_content_leaf(attr.ino).await?;
+                self_clone
+                    .file_keys_cache
+                    .get()
+                    .await?
+                    .lock()
+                    .await
+                    .pop(&attr.ino);
                 // remove from parent directory
                 self_clone
                     .remove_directory_entry(parent, &name_clone)
                     .await?;
+                Self::journal_end(&journal)?;
                 // remove from cache
                 self_clone
                     .attr_cache
@@ -986,9 +1868,32 @@ impl EncryptedFs {
                     )
                     .await?;
 
-                Ok(())
+                Ok::<_, FsError>(())
             })
+            .await??;
+        self.resize_usage(attr.size, 0)?;
+        self.passthrough_removed(attr.ino).await?;
+        self.overlay_removed(parent, name, attr.ino).await
+    }
+
+    async fn journal_remove(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        attr: &FileAttr,
+    ) -> FsResult<PathBuf> {
+        let (_, _, encrypted_name) = self
+            .read_hash_entry(parent, name)
             .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        self.journal_begin(&JournalOp::Remove {
+            parent,
+            name: name.expose_secret().clone(),
+            encrypted_name,
+            ino: attr.ino,
+            kind: attr.kind,
+        })
+        .await
     }
 
     #[allow(clippy::missing_panics_doc)]
@@ -1000,8 +1905,7 @@ impl EncryptedFs {
         if !self.is_dir(parent) {
             return Err(FsError::InvalidInodeType);
         }
-        let hash = crypto::hash_file_name(name);
-        let hash_path = self.contents_path(parent).join(HASH_DIR).join(hash);
+        let hash_path = self.hash_entry_path(parent, name);
         Ok(hash_path.is_file())
     }
 
@@ -1010,15 +1914,10 @@ impl EncryptedFs {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
-        }
-
-        let iter = fs::read_dir(ls_dir)?;
+        let entries = self.raw_dir_entries(ino).await?;
         let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
         self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_iterator(iter).await)
+        Ok(self.create_directory_entry_iterator(entries).await)
     }
 
     /// Like [`EncryptedFs::read_dir`] but with [`FileAttr`] so we don't need to query again for those.
@@ -1026,26 +1925,47 @@ impl EncryptedFs {
         if !self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        let ls_dir = self.contents_path(ino).join(LS_DIR);
-        if !ls_dir.is_dir() {
-            return Err(FsError::InvalidInodeType);
-        }
-
-        let iter = fs::read_dir(ls_dir)?;
+        let entries = self.raw_dir_entries(ino).await?;
         let set_attr = SetFileAttr::default().with_atime(SystemTime::now());
         self.set_attr(ino, set_attr).await?;
-        Ok(self.create_directory_entry_plus_iterator(iter).await)
+        Ok(self.create_directory_entry_plus_iterator(entries).await)
+    }
+
+    async fn raw_dir_entries(&self, ino: u64) -> FsResult<Vec<RawDirEntry>> {
+        self.merge_dir(ino).await?;
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                let ls_dir = self.contents_path(ino).join(LS_DIR);
+                if !ls_dir.is_dir() {
+                    return Err(FsError::InvalidInodeType);
+                }
+                Ok(fs::read_dir(ls_dir)?.map(RawDirEntry::Ls).collect())
+            }
+            StorageLayout::Flat => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(ino), || RwLock::new(false));
+                let _guard = lock.read().await;
+                Ok(self
+                    .read_dir_index(ino)
+                    .await?
+                    .into_iter()
+                    .map(|(name, (ino, kind))| RawDirEntry::Index(name, ino, kind))
+                    .collect())
+            }
+        }
     }
 
     async fn create_directory_entry_plus(
         &self,
-        entry: io::Result<DirEntry>,
+        entry: RawDirEntry,
     ) -> FsResult<DirectoryEntryPlus> {
         let entry = self.create_directory_entry(entry).await?;
         let lock = self.serialize_inode_locks.clone();
         let lock_ino = lock.get_or_insert_with(entry.ino, || RwLock::new(false));
         let _ino_guard = lock_ino.read();
-        let attr = self.get_inode_from_cache_or_storage(entry.ino).await?;
+        // the same as getattr would answer, the kernel keeps it instead of asking
+        let attr = self.get_attr(entry.ino).await?;
         Ok(DirectoryEntryPlus {
             ino: entry.ino,
             name: entry.name,
@@ -1056,10 +1976,10 @@ impl EncryptedFs {
 
     async fn create_directory_entry_plus_iterator(
         &self,
-        read_dir: ReadDir,
+        entries: Vec<RawDirEntry>,
     ) -> DirectoryEntryPlusIterator {
         #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
+        let futures: Vec<_> = entries
             .into_iter()
             .map(|entry| {
                 let fs = {
@@ -1078,15 +1998,20 @@ impl EncryptedFs {
         // do these futures in parallel and return them
         let mut res = VecDeque::with_capacity(futures.len());
         for f in futures {
-            res.push_back(f.await.unwrap());
+            // a panic in the task is returned as an error of that entry
+            res.push_back(f.await.map_err(FsError::from).and_then(|entry| entry));
         }
         DirectoryEntryPlusIterator(res)
     }
 
-    async fn create_directory_entry(
-        &self,
-        entry: io::Result<DirEntry>,
-    ) -> FsResult<DirectoryEntry> {
+    async fn create_directory_entry(&self, entry: RawDirEntry) -> FsResult<DirectoryEntry> {
+        let entry = match entry {
+            RawDirEntry::Ls(entry) => entry,
+            RawDirEntry::Index(name, ino, kind) => {
+                let name = self.decrypt_dir_entry_name(&name).await?;
+                return Ok(DirectoryEntry { ino, name, kind });
+            }
+        };
         if entry.is_err() {
             return Err(entry.err().unwrap().into());
         }
@@ -1096,60 +2021,30 @@ impl EncryptedFs {
         }
         let entry = entry.unwrap();
         let name = entry.file_name().to_string_lossy().to_string();
-        let name = {
-            if name == "$." {
-                SecretString::from_str(".").unwrap()
-            } else if name == "$.." {
-                SecretString::from_str("..").unwrap()
-            } else {
-                // try from cache
-                let lock = self.get_dir_entries_name_cache().await?;
-                let mut cache = lock.lock().await;
-                if let Some(name_cached) = cache.get(&name).cloned() {
-                    name_cached
-                } else {
-                    drop(cache);
-                    if let Ok(decrypted_name) =
-                        crypto::decrypt_file_name(&name, self.cipher, &*self.key.get().await?)
-                            .map_err(|err| {
-                                error!(err = %err, "decrypting file name");
-                                err
-                            })
-                    {
-                        lock.lock().await.put(name.clone(), decrypted_name.clone());
-                        decrypted_name
-                    } else {
-                        return Err(FsError::InvalidInput("invalid file name"));
-                    }
-                }
-            }
-        };
+        let name = self.decrypt_dir_entry_name(&name).await?;
         let file_path = entry.path().to_str().unwrap().to_string();
         // try from cache
         let lock = self.dir_entries_meta_cache.get().await?;
         let mut cache = lock.lock().await;
-        if let Some((ino, kind)) = cache.get(&file_path) {
-            return Ok(DirectoryEntry {
-                ino: *ino,
-                name,
-                kind: *kind,
-            });
+        let cached = match cache.get(&file_path) {
+            Some((entry, read)) if read.elapsed() < self.entry_ttl() => Some(*entry),
+            _ => None,
+        };
+        metrics::DIR_ENTRY_META_CACHE.lookup(cached.is_some());
+        if let Some((ino, kind)) = cached {
+            return Ok(DirectoryEntry { ino, name, kind });
         }
         drop(cache);
+        let read = Instant::now();
         let lock = self
             .serialize_dir_entries_ls_locks
             .get_or_insert_with(file_path.clone(), || RwLock::new(false));
         let guard = lock.read().await;
-        let file = File::open(entry.path())?;
-        let res: bincode::Result<(u64, FileType)> = bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ));
+        let res: FsResult<(u64, FileType)> = self.deserialize_from_file(&entry.path()).await;
         drop(guard);
         if let Err(e) = res {
             error!(err = %e, "deserializing directory entry");
-            return Err(e.into());
+            return Err(e);
         }
         let (ino, kind): (u64, FileType) = res.unwrap();
         // add to cache
@@ -1158,19 +2053,50 @@ impl EncryptedFs {
             .await?
             .lock()
             .await
-            .put(file_path, (ino, kind));
+            .put(file_path, ((ino, kind), read));
         Ok(DirectoryEntry { ino, name, kind })
     }
 
+    async fn decrypt_dir_entry_name(&self, name: &str) -> FsResult<SecretString> {
+        if name == "$." {
+            return Ok(SecretString::from_str(".").unwrap());
+        } else if name == "$.." {
+            return Ok(SecretString::from_str("..").unwrap());
+        }
+        // try from cache
+        let lock = self.get_dir_entries_name_cache().await?;
+        let mut cache = lock.lock().await;
+        let cached = cache.get(name).cloned();
+        metrics::DIR_ENTRY_NAME_CACHE.lookup(cached.is_some());
+        if let Some(name_cached) = cached {
+            return Ok(name_cached);
+        }
+        drop(cache);
+        if let Ok(decrypted_name) = self.decrypt_file_name(name).await.map_err(|err| {
+            error!(err = %err, "decrypting file name");
+            err
+        }) {
+            lock.lock()
+                .await
+                .put(name.to_string(), decrypted_name.clone());
+            Ok(decrypted_name)
+        } else {
+            Err(FsError::InvalidInput("invalid file name"))
+        }
+    }
+
     async fn get_dir_entries_name_cache(
         &self,
     ) -> FsResult<Arc<Mutex<LruCache<String, SecretString>>>> {
         self.dir_entries_name_cache.get().await
     }
 
-    async fn create_directory_entry_iterator(&self, read_dir: ReadDir) -> DirectoryEntryIterator {
+    async fn create_directory_entry_iterator(
+        &self,
+        entries: Vec<RawDirEntry>,
+    ) -> DirectoryEntryIterator {
         #[allow(clippy::cast_possible_truncation)]
-        let futures: Vec<_> = read_dir
+        let futures: Vec<_> = entries
             .into_iter()
             .map(|entry| {
                 let fs = {
@@ -1189,7 +2115,8 @@ impl EncryptedFs {
         // do these futures in parallel and return them
         let mut res = VecDeque::with_capacity(futures.len());
         for f in futures {
-            res.push_back(f.await.unwrap());
+            // a panic in the task is returned as an error of that entry
+            res.push_back(f.await.map_err(FsError::from).and_then(|entry| entry));
         }
         DirectoryEntryIterator(res)
     }
@@ -1201,32 +2128,138 @@ impl EncryptedFs {
             .get_or_insert_with(ino, || RwLock::new(false));
         let _guard = lock.read();
 
-        let path = self.ino_file(ino);
-        if !path.is_file() {
-            return Err(FsError::InodeNotFound);
+        let path = self.ino_file(ino);
+        if !path.is_file() {
+            return Err(FsError::InodeNotFound);
+        }
+        let (attr, key) = self.read_inode_file(&path).await?;
+        self.file_keys_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .put(ino, key.map(Arc::new));
+        Ok(attr)
+    }
+
+    /// Read the attr and, for files, its key. Doesn't take any lock.
+    async fn read_inode_file(&self, path: &Path) -> FsResult<(FileAttr, Option<LockedVec>)> {
+        let keys = self.master_keys().await?;
+        let mut res = Err(FsError::InodeNotFound);
+        for (cipher, key) in keys {
+            let file = OpenOptions::new().read(true).open(path).map_err(|err| {
+                error!(err = %err, "opening file");
+                FsError::InodeNotFound
+            })?;
+            let mut reader = crypto::create_read(file, cipher, &key);
+            let attr: FileAttr = match bincode::deserialize_from(&mut reader) {
+                Ok(attr) => attr,
+                Err(err) => {
+                    res = Err(err.into());
+                    continue;
+                }
+            };
+            let key: Option<Vec<u8>> = match bincode::deserialize_from(&mut reader) {
+                Ok(key) => key,
+                // inodes written before we had per-file keys
+                Err(err) if matches!(&*err, bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof) => {
+                    None
+                }
+                Err(err) => return Err(err.into()),
+            };
+            return Ok((attr, key.map(Locked::new)));
+        }
+        res
+    }
+
+    /// Cipher and master key to try when decrypting metadata. The old key is included while a key rotation
+    /// is in progress and the old cipher while migrating to another one.
+    async fn master_keys(&self) -> FsResult<Vec<(Cipher, Arc<LockedVec>)>> {
+        let key = self.key.get().await?;
+        let mut keys = vec![(self.cipher, key.clone())];
+        if self.is_key_rotation_in_progress() {
+            keys.push((self.cipher, self.old_key.get().await?));
+        }
+        if let Some(cipher) = self.migrating_from {
+            keys.push((cipher, key));
+        }
+        Ok(keys)
+    }
+
+    /// Deserialize from a file encrypted with the master key.
+    async fn deserialize_from_file<T: DeserializeOwned>(&self, path: &Path) -> FsResult<T> {
+        let keys = self.master_keys().await?;
+        let mut res = Err(FsError::InvalidInput("no key"));
+        for (cipher, key) in keys {
+            res = bincode::deserialize_from(crypto::create_read(File::open(path)?, cipher, &key))
+                .map_err(Into::into);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    async fn decrypt_file_name(&self, name: &str) -> FsResult<SecretString> {
+        let mut res = Err(FsError::InvalidInput("no key"));
+        for (cipher, key) in self.master_keys().await? {
+            res = crypto::decrypt_file_name(name, cipher, &key).map_err(Into::into);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    /// Key used to encrypt the content of the file, the master key for files created before we had
+    /// per-file keys.
+    async fn file_key(&self, ino: u64) -> FsResult<Arc<LockedVec>> {
+        let cached = self
+            .file_keys_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .get(&ino)
+            .cloned();
+        metrics::FILE_KEY_CACHE.lookup(cached.is_some());
+        let key = if let Some(key) = cached {
+            key
+        } else {
+            let (_, key) = self.read_inode_file(&self.ino_file(ino)).await?;
+            let key = key.map(Arc::new);
+            self.file_keys_cache
+                .get()
+                .await?
+                .lock()
+                .await
+                .put(ino, key.clone());
+            key
+        };
+        match key {
+            Some(key) => Ok(key),
+            // the key rotation gives a key to all of these, until then they still use the old master key
+            None if self.is_key_rotation_in_progress() => self.old_key.get().await,
+            None => self.key.get().await,
         }
-        let file = OpenOptions::new().read(true).open(path).map_err(|err| {
-            error!(err = %err, "opening file");
-            FsError::InodeNotFound
-        })?;
-        Ok(bincode::deserialize_from(crypto::create_read(
-            file,
-            self.cipher,
-            &*self.key.get().await?,
-        ))?)
     }
 
     async fn get_inode_from_cache_or_storage(&self, ino: u64) -> FsResult<FileAttr> {
         let lock = self.attr_cache.get().await?;
         let mut guard = lock.write().await;
-        let attr = guard.get(&ino);
+        let attr = match guard.get(&ino) {
+            Some((attr, read)) if read.elapsed() < self.attr_ttl() => Some(*attr),
+            _ => None,
+        };
+        metrics::ATTR_CACHE.lookup(attr.is_some());
         if let Some(attr) = attr {
-            Ok(*attr)
+            Ok(attr)
         } else {
             drop(guard);
+            let read = Instant::now();
             let attr = self.get_inode_from_storage(ino).await?;
             let mut guard = lock.write().await;
-            guard.put(ino, attr);
+            guard.put(ino, (attr, read));
             Ok(attr)
         }
     }
@@ -1267,6 +2300,8 @@ impl EncryptedFs {
 
     /// Set metadata
     pub async fn set_attr(&self, ino: u64, set_attr: SetFileAttr) -> FsResult<()> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         self.set_attr2(ino, set_attr, false).await
     }
 
@@ -1293,22 +2328,60 @@ impl EncryptedFs {
     }
 
     async fn write_inode_to_storage(&self, attr: &FileAttr) -> Result<(), FsError> {
+        self.write_inode_with_key_to_storage(attr, None).await
+    }
+
+    /// If `key` is `None` we keep the existing key of the file, if any.
+    async fn write_inode_with_key_to_storage(
+        &self,
+        attr: &FileAttr,
+        key: Option<&LockedVec>,
+    ) -> Result<(), FsError> {
         let lock = self
             .serialize_inode_locks
             .get_or_insert_with(attr.ino, || RwLock::new(false));
         let guard = lock.write().await;
-        crypto::atomic_serialize_encrypt_into(
-            &self.ino_file(attr.ino),
-            attr,
-            self.cipher,
-            &*self.key.get().await?,
-        )?;
+        let path = self.ino_file(attr.ino);
+        let key = match key {
+            Some(key) => Some(Arc::new(key.clone())),
+            None if attr.kind == FileType::RegularFile && path.is_file() => {
+                let cached = self
+                    .file_keys_cache
+                    .get()
+                    .await?
+                    .lock()
+                    .await
+                    .get(&attr.ino)
+                    .cloned();
+                match cached {
+                    Some(key) => key,
+                    None => self.read_inode_file(&path).await?.1.map(Arc::new),
+                }
+            }
+            None => None,
+        };
+        let master_key = self.key.get().await?;
+        self.replace_data_file(&path, || {
+            Ok(crypto::atomic_serialize_encrypt_into(
+                &path,
+                &(attr, key.as_ref().map(|key| key.expose_secret())),
+                self.cipher,
+                &master_key,
+            )?)
+        })?;
         drop(guard);
+        self.write_chaff(fs::metadata(&path)?.len())?;
+        self.file_keys_cache
+            .get()
+            .await?
+            .lock()
+            .await
+            .put(attr.ino, key);
         // update cache also
         {
             let lock = self.attr_cache.get().await?;
             let mut guard = lock.write().await;
-            guard.put(attr.ino, *attr);
+            guard.put(attr.ino, (*attr, Instant::now()));
         }
         Ok(())
     }
@@ -1340,8 +2413,16 @@ impl EncryptedFs {
             .get_or_insert_with(ino, || RwLock::new(false));
         let _read_guard = lock.read().await;
 
+        // content might be padded, so we don't read past the real size
+        let size = self.get_attr(ino).await?.size;
+
         let guard = self.read_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        // it might have been released meanwhile
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
 
         if ctx.ino != ino {
             return Err(FsError::InvalidFileHandle);
@@ -1349,10 +2430,15 @@ impl EncryptedFs {
         if self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
-        if buf.is_empty() {
+        if buf.is_empty() || offset >= size {
             // no-op
             return Ok(0);
         }
+        let buf = if offset + buf.len() as u64 > size {
+            &mut buf[..(size - offset) as usize]
+        } else {
+            buf
+        };
 
         // read data
         let len = {
@@ -1370,10 +2456,18 @@ impl EncryptedFs {
                 // we would need to seek after filesize
                 return Ok(0);
             }
-            stream_util::read(reader, buf).map_err(|err| {
-                error!(err = %err, "reading");
-                err
-            })?
+            match stream_util::read(reader, buf) {
+                Ok(len) => len,
+                Err(err) => {
+                    error!(err = %err, "reading");
+                    // it might be left in the middle of a chunk
+                    ctx.reader = Some(self.content_reader(ino).await?);
+                    drop(ctx);
+                    drop(guard);
+                    self.log_corruption(ino, offset, buf.len()).await;
+                    return Err(err.into());
+                }
+            }
         };
 
         ctx.attr.atime = SystemTime::now();
@@ -1388,6 +2482,7 @@ impl EncryptedFs {
             // in case of directory or if the file was crated without being opened we don't use handle
             return Ok(());
         }
+        let _changing = self.changes.enter();
         let mut valid_fh = false;
 
         // read
@@ -1415,7 +2510,9 @@ impl EncryptedFs {
             let set_attr: SetFileAttr = ctx.attr.clone().into();
             let ino = ctx.ino;
             drop(ctx);
-            self.set_attr(ino, set_attr).await?;
+            if !self.options.read_only {
+                self.set_attr(ino, set_attr).await?;
+            }
 
             valid_fh = true;
         }
@@ -1425,14 +2522,11 @@ impl EncryptedFs {
         if let Some(ctx) = ctx {
             let mut ctx = ctx.lock().await;
 
-            let mut writer = ctx.writer.take().unwrap();
             let lock = self
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            let file = writer.finish()?;
-            file.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            self.finish_contents_writer(&mut ctx).await?;
             // write attr only here to avoid serializing it multiple times while writing
             // it will merge time fields with existing data because it might got change while we kept the handle
             let ino = ctx.ino;
@@ -1441,6 +2535,7 @@ impl EncryptedFs {
             self.set_attr(ino, attr.into()).await?;
             drop(write_guard);
             self.opened_files_for_write.write().await.remove(&ino);
+            self.save_content_leaves(Some(ino)).await?;
             self.reset_handles(ino, Some(handle), true).await?;
 
             valid_fh = true;
@@ -1467,6 +2562,27 @@ impl EncryptedFs {
     /// If the file is not opened for writing, it will return an error of type ['FsError::InvalidFileHandle'].
     #[instrument(skip(self, buf))]
     pub async fn write(&self, ino: u64, offset: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.write_at(ino, Some(offset), buf, handle).await
+    }
+
+    /// Writes the contents of `buf` at the end of the file, for `O_APPEND`. The end is taken under the lock of the
+    /// write, so what's written or truncated meanwhile is not overwritten, unlike with an offset from a stale size.
+    #[allow(clippy::missing_errors_doc)]
+    #[instrument(skip(self, buf))]
+    pub async fn append(&self, ino: u64, buf: &[u8], handle: u64) -> FsResult<usize> {
+        self.write_at(ino, None, buf, handle).await
+    }
+
+    /// At the end of the file if `offset` is [`None`].
+    async fn write_at(
+        &self,
+        ino: u64,
+        offset: Option<u64>,
+        buf: &[u8],
+        handle: u64,
+    ) -> FsResult<usize> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         if !self.exists(ino) {
             return Err(FsError::InodeNotFound);
         }
@@ -1480,7 +2596,11 @@ impl EncryptedFs {
         }
         {
             let guard = self.write_handles.read().await;
-            let ctx = guard.get(&handle).unwrap().lock().await;
+            let ctx = guard
+                .get(&handle)
+                .ok_or(FsError::InvalidFileHandle)?
+                .lock()
+                .await;
             if ctx.ino != ino {
                 return Err(FsError::InvalidFileHandle);
             }
@@ -1496,15 +2616,25 @@ impl EncryptedFs {
         let write_guard = lock.write().await;
 
         let guard = self.write_handles.read().await;
-        let mut ctx = guard.get(&handle).unwrap().lock().await;
+        // it might have been released meanwhile
+        let mut ctx = guard
+            .get(&handle)
+            .ok_or(FsError::InvalidFileHandle)?
+            .lock()
+            .await;
 
         // write new data
         let (pos, len) = {
+            let offset = offset.unwrap_or(ctx.attr.size);
             if offset > self.cipher.max_plaintext_len() as u64 {
                 return Err(FsError::MaxFilesizeExceeded(
                     self.cipher.max_plaintext_len(),
                 ));
             }
+            if ctx.writer.is_none() {
+                ctx.writer = Some(self.create_contents_writer(ino).await?);
+            }
+            let size = ctx.attr.size;
             let writer = ctx.writer.as_mut().unwrap();
             let pos = writer.seek(SeekFrom::Start(offset)).map_err(|err| {
                 error!(err = %err, "seeking");
@@ -1521,11 +2651,17 @@ impl EncryptedFs {
             } else {
                 buf
             };
+            let end = size.max(offset + buf.len() as u64);
+            self.resize_usage(size, end)?;
             let len = writer.write(buf).map_err(|err| {
                 error!(err = %err, "writing");
                 err
-            })?;
-            (writer.stream_position()?, len)
+            });
+            let pos = writer.stream_position();
+            // give back what was not written
+            let written = pos.as_ref().map_or(size, |pos| size.max(*pos));
+            self.resize_usage(end, written)?;
+            (pos?, len?)
         };
 
         if pos > ctx.attr.size {
@@ -1552,6 +2688,7 @@ impl EncryptedFs {
             // in the case of directory or if the file was crated without being opened we don't use a handle
             return Ok(());
         }
+        let _changing = self.changes.enter();
         let mut valid_fh = self.read_handles.read().await.get(&handle).is_some();
         if let Some(ctx) = self.write_handles.read().await.get(&handle) {
             let mut ctx = ctx.lock().await;
@@ -1559,9 +2696,7 @@ impl EncryptedFs {
                 .read_write_locks
                 .get_or_insert_with(ctx.ino, || RwLock::new(false));
             let write_guard = lock.write().await;
-            ctx.writer.as_mut().expect("writer is missing").flush()?;
-            File::open(self.contents_path(ctx.ino))?.sync_all()?;
-            File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+            self.finish_contents_writer(&mut ctx).await?;
             drop(write_guard);
             let ino = ctx.ino;
             drop(ctx);
@@ -1576,6 +2711,38 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Flush all files open for write, like before exiting while some are still open. The [`EncryptedFile`]s
+    /// dropped without [`tokio::io::AsyncWriteExt::shutdown`] are released first, it fails if any of them did.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn flush_all(&self) -> FsResult<()> {
+        let _changing = self.changes.enter();
+        let released = self.wait_releases().await;
+        let handles: Vec<u64> = self.write_handles.read().await.keys().copied().collect();
+        for handle in handles {
+            match self.flush(handle).await {
+                Ok(()) => {}
+                // closed meanwhile
+                Err(FsError::InvalidFileHandle) => continue,
+                Err(err) => return Err(err),
+            }
+            // the size and times are written only on release, as the handle is kept
+            let attr = {
+                let handles = self.write_handles.read().await;
+                if let Some(ctx) = handles.get(&handle) {
+                    let ctx = ctx.lock().await;
+                    Some((ctx.ino, ctx.attr.clone()))
+                } else {
+                    None
+                }
+            };
+            if let Some((ino, attr)) = attr {
+                self.set_attr(ino, attr.into()).await?;
+            }
+        }
+        self.save_content_leaves(None).await?;
+        released
+    }
+
     /// Helpful when we want to copy just some portions of the file.
     pub async fn copy_file_range(
         &self,
@@ -1618,6 +2785,9 @@ impl EncryptedFs {
                 "read and write cannot be false at the same time",
             ));
         }
+        if write {
+            self.check_writable()?;
+        }
         if self.is_dir(ino) {
             return Err(FsError::InvalidInodeType);
         }
@@ -1661,6 +2831,8 @@ impl EncryptedFs {
     #[allow(clippy::missing_panics_doc)]
     #[allow(clippy::too_many_lines)]
     pub async fn set_len(&self, ino: u64, size: u64) -> FsResult<()> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         let attr = self.get_attr(ino).await?;
         if matches!(attr.kind, FileType::Directory) {
             return Err(FsError::InvalidInodeType);
@@ -1670,6 +2842,7 @@ impl EncryptedFs {
             // no-op
             return Ok(());
         }
+        self.resize_usage(attr.size, size)?;
 
         let lock = self
             .read_write_locks
@@ -1678,23 +2851,27 @@ impl EncryptedFs {
 
         // flush writers
         self.flush_and_reset_writers(ino).await?;
+        if size < attr.size {
+            self.keep_version(ino, None).await?;
+        }
 
         let file_path = self.contents_path(ino);
-        if size == 0 {
+        let pads = self.pads(ino).await?;
+        if size == 0 && !pads {
             debug!("truncate to zero");
-            // truncate to zero
-            let file = File::create(&file_path)?;
-            file.set_len(0)?;
-            file.sync_all()?;
+            // truncate to zero, with a new file so snapshots keep the old one
+            self.replace_data_file(&file_path, || {
+                Ok(fs_util::open_atomic_write(&file_path)?.commit()?)
+            })?;
         } else {
             debug!("truncate size to {}", size.to_formatted_string(&Locale::en));
 
             let mut file = fs_util::open_atomic_write(&file_path)?;
             {
                 // have a new scope, so we drop the reader before moving new content files
-                let mut reader = self.create_read(File::open(file_path.as_path())?).await?;
+                let mut reader = self.content_reader(ino).await?;
 
-                let mut writer = self.create_write(file).await?;
+                let mut writer = self.content_writer(ino, file).await?;
 
                 let len = if size > attr.size {
                     // increase size, copy existing data until existing size
@@ -1708,11 +2885,17 @@ impl EncryptedFs {
                     // increase size, seek to new size will write zeros
                     stream_util::fill_zeros(&mut writer, size - attr.size)?;
                 }
+                if pads {
+                    stream_util::fill_zeros(&mut writer, padded_size(size) - size)?;
+                }
                 file = writer.finish()?;
             }
-            file.commit()?;
+            self.replace_data_file(&file_path, || Ok(file.commit()?))?;
         }
         File::open(file_path.parent().unwrap())?.sync_all()?;
+        self.content_copied_up(ino).await?;
+        self.dedup_contents(ino).await?;
+        self.update_content_leaf(ino).await?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1753,10 +2936,7 @@ impl EncryptedFs {
             if let Some(lock) = ctx {
                 let mut ctx = lock.lock().await;
 
-                let mut writer = ctx.writer.take().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.finish_contents_writer(&mut ctx).await?;
                 let handle = *handle;
                 let set_attr: SetFileAttr = ctx.attr.clone().into();
                 drop(ctx);
@@ -1766,15 +2946,6 @@ impl EncryptedFs {
                 self.reset_handles(ino, Some(handle), true).await?;
                 let write_handles_guard = self.write_handles.write().await;
                 let mut ctx = write_handles_guard.get(&handle).unwrap().lock().await;
-                let writer = self
-                    .create_write_seek(
-                        OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(&self.contents_path(ino))?,
-                    )
-                    .await?;
-                ctx.writer = Some(Box::new(writer));
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -1783,6 +2954,7 @@ impl EncryptedFs {
     }
 
     #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::too_many_lines)]
     pub async fn rename(
         &self,
         parent: u64,
@@ -1790,6 +2962,8 @@ impl EncryptedFs {
         new_parent: u64,
         new_name: &SecretString,
     ) -> FsResult<()> {
+        self.check_writable()?;
+        let _changing = self.changes.enter();
         if !self.exists(parent) {
             return Err(FsError::InodeNotFound);
         }
@@ -1802,6 +2976,8 @@ impl EncryptedFs {
         if !self.is_dir(new_parent) {
             return Err(FsError::InvalidInodeType);
         }
+        self.merge_dir(parent).await?;
+        self.merge_dir(new_parent).await?;
         if !self.exists_by_name(parent, name)? {
             return Err(FsError::NotFound("name not found"));
         }
@@ -1813,7 +2989,7 @@ impl EncryptedFs {
 
         // Only overwrite an existing directory if it's empty
         if let Ok(Some(new_attr)) = self.find_by_name(new_parent, new_name).await {
-            if new_attr.kind == FileType::Directory && self.len(new_attr.ino)? > 0 {
+            if new_attr.kind == FileType::Directory && self.len(new_attr.ino).await? > 0 {
                 return Err(FsError::NotEmpty);
             }
         }
@@ -1822,6 +2998,31 @@ impl EncryptedFs {
             .find_by_name(parent, name)
             .await?
             .ok_or(FsError::NotFound("name not found"))?;
+        let (_, _, encrypted_name) = self
+            .read_hash_entry(parent, name)
+            .await?
+            .ok_or(FsError::NotFound("name not found"))?;
+        self.check_not_excluded(new_parent, new_name).await?;
+        let new_encrypted_name = self.encrypt_entry_name(new_name).await?;
+        // only the case changed when case-insensitive, it's the same entry
+        let replaced = self
+            .read_hash_entry(new_parent, new_name)
+            .await?
+            .filter(|(ino, _, _)| *ino != attr.ino)
+            .map(|(ino, _, encrypted_name)| (ino, encrypted_name));
+        let journal = self
+            .journal_begin(&JournalOp::Rename {
+                parent,
+                name: name.expose_secret().clone(),
+                encrypted_name,
+                new_parent,
+                new_name: new_name.expose_secret().clone(),
+                new_encrypted_name: new_encrypted_name.clone(),
+                ino: attr.ino,
+                kind: attr.kind,
+                replaced: replaced.clone(),
+            })
+            .await?;
         // remove from parent contents
         self.remove_directory_entry(parent, name).await?;
         // remove from new_parent contents, if exists
@@ -1829,13 +3030,14 @@ impl EncryptedFs {
             self.remove_directory_entry(new_parent, new_name).await?;
         }
         // add to new parent contents
-        self.insert_directory_entry(
+        self.insert_directory_entry_as(
             new_parent,
             &DirectoryEntry {
                 ino: attr.ino,
                 name: new_name.clone(),
                 kind: attr.kind,
             },
+            new_encrypted_name,
         )
         .await?;
 
@@ -1851,6 +3053,14 @@ impl EncryptedFs {
             )
             .await?;
         }
+        Self::journal_end(&journal)?;
+        self.overlay_renamed(
+            (parent, name),
+            (new_parent, new_name),
+            attr.ino,
+            replaced.map(|(ino, _)| ino),
+        )
+        .await?;
 
         let now = SystemTime::now();
         let set_attr = SetFileAttr::default()
@@ -1919,26 +3129,80 @@ impl EncryptedFs {
         ))
     }
 
+    /// Check `password` unlocks the data dir, the volume, a hidden volume or a key slot, without opening it. It's
+    /// what's checked when it's opened, but this doesn't need the TOTP code or to wait for the data dir to be free.
+    ///
+    /// A data dir without a key yet takes any password, the key is created with it on first mount.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check_password(
+        data_dir: &Path,
+        password: &SecretString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = header::volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        let key_path = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let salt_path = data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME);
+        if !key_path.exists() || !salt_path.exists() {
+            return Ok(());
+        }
+        read_or_create_key(
+            &key_path,
+            &salt_path,
+            &data_dir.join(OBJECTS_DIR),
+            password,
+            cipher,
+        )?;
+        Ok(())
+    }
+
     /// Change the password of the filesystem used to access the encryption key.
     pub async fn passwd(
         data_dir: &Path,
-        old_password: SecretString,
-        new_password: SecretString,
+        old_password: LockedString,
+        new_password: LockedString,
         cipher: Cipher,
     ) -> FsResult<()> {
         check_structure(data_dir, false).await?;
+        let cipher = header::volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        let kdf = header::kdf_params(&data_dir.join(SECURITY_DIR))?;
         // decrypt key
         let salt: Vec<u8> = bincode::deserialize_from(File::open(
             data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
         )?)?;
-        let initial_key = crypto::derive_key(&old_password, cipher, &salt)?;
+        let initial_key = crypto::derive_key_with(&old_password, cipher, &salt, &kdf)?;
         let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
         let reader = crypto::create_read(File::open(enc_file)?, cipher, &initial_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        let key = SecretVec::new(key);
+        let key: bincode::Result<LockedVec> = bincode::deserialize_from(reader);
+        let new_key = crypto::derive_key_with(&new_password, cipher, &salt, &kdf)?;
+        let Ok(key) = key else {
+            // it might be the password of a hidden volume
+            let objects_dir = data_dir.join(OBJECTS_DIR);
+            let key = read_hidden_key(&objects_dir, &initial_key, cipher)?
+                .ok_or(FsError::InvalidPassword)?;
+            crypto::atomic_serialize_encrypt_into(
+                &hidden_key_path(&objects_dir, &new_key),
+                &key.expose_secret(),
+                cipher,
+                &new_key,
+            )?;
+            fs::remove_file(hidden_key_path(&objects_dir, &initial_key))?;
+            File::open(objects_dir)?.sync_all()?;
+            return Ok(());
+        };
+        // keep the old key of an unfinished key rotation
+        let old_key_path = data_dir.join(SECURITY_DIR).join(KEY_OLD_ENC_FILENAME);
+        if old_key_path.is_file() {
+            let salt_path = data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME);
+            let old_key = read_key(&old_key_path, &salt_path, &old_password, cipher)?;
+            crypto::atomic_serialize_encrypt_into(
+                &old_key_path,
+                &old_key.expose_secret(),
+                cipher,
+                &new_key,
+            )?;
+        }
         // encrypt it with a new key derived from new password
-        let new_key = crypto::derive_key(&new_password, cipher, &salt)?;
         crypto::atomic_serialize_encrypt_into(
             &data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME),
             &key.expose_secret(),
@@ -1948,6 +3212,52 @@ impl EncryptedFs {
         Ok(())
     }
 
+    /// Create a hidden volume inside the data dir, unlocked by `hidden_password` instead of the outer one.
+    ///
+    /// The data dir needs to use [`StorageLayout::Flat`]. Objects of the hidden volume live in the same pool
+    /// as the outer volume ones, named with a key derived from their own master key. Both volumes keep writing
+    /// random objects while they are used, so with the outer password the objects it doesn't reference can't be
+    /// told apart from them, as long as the hidden volume is not much bigger than those. Mount the data dir with
+    /// `hidden_password` to use it, the root is created on the first mount.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_hidden_volume(
+        data_dir: &Path,
+        outer_password: LockedString,
+        hidden_password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = header::volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        if StorageLayout::detect(data_dir) != Some(StorageLayout::Flat) {
+            return Err(FsError::InvalidInput(
+                "hidden volumes need a data dir with flat layout",
+            ));
+        }
+        let salt: Vec<u8> = bincode::deserialize_from(File::open(
+            data_dir.join(SECURITY_DIR).join(KEY_SALT_FILENAME),
+        )?)?;
+        let enc_file = data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME);
+        let kdf = header::kdf_params(&data_dir.join(SECURITY_DIR))?;
+        // check outer password
+        let outer_key = crypto::derive_key_with(&outer_password, cipher, &salt, &kdf)?;
+        let reader = crypto::create_read(File::open(&enc_file)?, cipher, &outer_key);
+        let _: Vec<u8> = bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
+        if outer_password.expose_secret() == hidden_password.expose_secret() {
+            return Err(FsError::InvalidInput(
+                "hidden password must be different from the outer one",
+            ));
+        }
+        let hidden_key = crypto::derive_key_with(&hidden_password, cipher, &salt, &kdf)?;
+        let path = hidden_key_path(&data_dir.join(OBJECTS_DIR), &hidden_key);
+        if path.exists() {
+            return Err(FsError::AlreadyExists);
+        }
+        // create a random key and encrypt it with the derived key from hidden password
+        let key = LockedVec::random(cipher.key_len());
+        crypto::atomic_serialize_encrypt_into(&path, &key.expose_secret(), cipher, &hidden_key)?;
+        Ok(())
+    }
+
     fn next_handle(&self) -> u64 {
         self.current_handle
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
@@ -1967,8 +3277,6 @@ impl EncryptedFs {
         skip_write_fh: Option<u64>,
         save_attr: bool,
     ) -> FsResult<()> {
-        let path = self.contents_path(ino);
-
         // read
         if let Some(set) = self.opened_files_for_read.read().await.get(&ino) {
             for handle in set
@@ -1982,8 +3290,7 @@ impl EncryptedFs {
                 self.set_attr(ino, set_attr).await?;
                 let attr = self.get_inode_from_storage(ino).await?;
                 let mut ctx = guard.get(handle).unwrap().lock().await;
-                let reader = self.create_read_seek(File::open(&path)?).await?;
-                ctx.reader = Some(Box::new(reader));
+                ctx.reader = Some(self.content_reader(ino).await?);
                 ctx.attr = attr.into();
             }
         }
@@ -1997,10 +3304,7 @@ impl EncryptedFs {
             }
             if let Some(lock) = self.write_handles.read().await.get(fh) {
                 let mut ctx = lock.lock().await;
-                let writer = ctx.writer.as_mut().unwrap();
-                let file = writer.finish()?;
-                file.sync_all()?;
-                File::open(self.contents_path(ctx.ino).parent().unwrap())?.sync_all()?;
+                self.finish_contents_writer(&mut ctx).await?;
                 let set_attr: Option<SetFileAttr> = if save_attr {
                     Some(ctx.attr.clone().into())
                 } else {
@@ -2010,11 +3314,7 @@ impl EncryptedFs {
                 if let Some(set_attr) = set_attr {
                     self.set_attr(ino, set_attr).await?;
                 }
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
                 let mut ctx = lock.lock().await;
-                ctx.writer = Some(Box::new(writer));
                 let attr = self.get_inode_from_storage(ino).await?;
                 ctx.attr = attr.into();
             }
@@ -2029,16 +3329,14 @@ impl EncryptedFs {
         op: ReadHandleContextOperation,
     ) -> FsResult<()> {
         let ino = op.get_ino();
-        let path = self.contents_path(ino);
         let attr = self.get_inode_from_storage(ino).await?;
         match op {
             ReadHandleContextOperation::Create { ino } => {
                 let attr: TimesFileAttr = attr.into();
-                let reader = self.create_read_seek(File::open(&path)?).await?;
                 let ctx = ReadHandleContext {
                     ino,
                     attr,
-                    reader: Some(Box::new(reader)),
+                    reader: Some(self.content_reader(ino).await?),
                 };
                 self.read_handles
                     .write()
@@ -2060,18 +3358,14 @@ impl EncryptedFs {
         handle: u64,
         op: WriteHandleContextOperation,
     ) -> FsResult<()> {
-        let ino = op.get_ino();
-        let path = self.contents_path(ino);
         match op {
             WriteHandleContextOperation::Create { ino } => {
                 let attr = self.get_attr(ino).await?.into();
-                let writer = self
-                    .create_write_seek(OpenOptions::new().read(true).write(true).open(&path)?)
-                    .await?;
+                // created on the first write
                 let ctx = WriteHandleContext {
                     ino,
                     attr,
-                    writer: Some(Box::new(writer)),
+                    writer: None,
                 };
                 self.write_handles
                     .write()
@@ -2099,7 +3393,7 @@ impl EncryptedFs {
             }
             .into();
             attr.ino = ROOT_INODE;
-            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
             unsafe {
                 attr.uid = libc::getuid();
                 attr.gid = libc::getgid();
@@ -2107,10 +3401,7 @@ impl EncryptedFs {
 
             self.write_inode_to_storage(&attr).await?;
 
-            // create in contents directory
-            fs::create_dir(self.contents_path(attr.ino))?;
-            fs::create_dir(self.contents_path(attr.ino).join(LS_DIR))?;
-            fs::create_dir(self.contents_path(attr.ino).join(HASH_DIR))?;
+            self.create_dir_storage(attr.ino).await?;
 
             // add "." entry
             self.insert_directory_entry(
@@ -2132,9 +3423,23 @@ impl EncryptedFs {
         ino_contents_dir: u64,
         entry: &DirectoryEntry,
     ) -> FsResult<()> {
-        let parent_path = self.contents_path(ino_contents_dir);
-        let encrypted_name =
-            crypto::encrypt_file_name(&entry.name, self.cipher, &*self.key.get().await?)?;
+        let encrypted_name = self.encrypt_entry_name(&entry.name).await?;
+        self.insert_directory_entry_as(ino_contents_dir, entry, encrypted_name)
+            .await
+    }
+
+    async fn encrypt_entry_name(&self, name: &SecretString) -> FsResult<String> {
+        crypto::encrypt_file_name(name, self.cipher, &*self.key.get().await?)
+    }
+
+    /// Like [`EncryptedFs::insert_directory_entry`], with the name already encrypted. The same encrypted name
+    /// overwrites the entry.
+    async fn insert_directory_entry_as(
+        &self,
+        ino_contents_dir: u64,
+        entry: &DirectoryEntry,
+        encrypted_name: String,
+    ) -> FsResult<()> {
         // add to LS directory
         let self_clone = self
             .self_weak
@@ -2144,28 +3449,45 @@ impl EncryptedFs {
             .unwrap()
             .upgrade()
             .unwrap();
-        let parent_path_clone = parent_path.clone();
         let encrypted_name_clone = encrypted_name.clone();
         let entry_clone = entry.clone();
+        let encrypted_name_hash = encrypted_name.clone();
         // spawn a task to do concurrently with adding to HASH directory
         let h = tokio::spawn(async move {
-            let file_path = parent_path_clone
-                .join(LS_DIR)
-                .join(encrypted_name_clone.clone());
-            let lock = self_clone
-                .serialize_dir_entries_ls_locks
-                .get_or_insert_with(file_path.to_str().unwrap().to_string(), || {
-                    RwLock::new(false)
-                });
-            let _guard = lock.write().await;
-            // write inode and file type
-            let entry = (entry_clone.ino, entry_clone.kind);
-            crypto::atomic_serialize_encrypt_into(
-                &file_path,
-                &entry,
-                self_clone.cipher,
-                &*self_clone.key.get().await?,
-            )?;
+            match self_clone.layout {
+                StorageLayout::Hierarchical => {
+                    let file_path = self_clone
+                        .contents_path(ino_contents_dir)
+                        .join(LS_DIR)
+                        .join(encrypted_name_clone.clone());
+                    let lock = self_clone
+                        .serialize_dir_entries_ls_locks
+                        .get_or_insert_with(file_path.to_str().unwrap().to_string(), || {
+                            RwLock::new(false)
+                        });
+                    let _guard = lock.write().await;
+                    // write inode and file type
+                    let entry = (entry_clone.ino, entry_clone.kind);
+                    crypto::atomic_serialize_encrypt_into(
+                        &file_path,
+                        &entry,
+                        self_clone.cipher,
+                        &*self_clone.key.get().await?,
+                    )?;
+                }
+                StorageLayout::Flat => {
+                    let lock = self_clone
+                        .serialize_dir_entries_ls_locks
+                        .get_or_insert_with(
+                            self_clone.dir_index_lock_key(ino_contents_dir),
+                            || RwLock::new(false),
+                        );
+                    let _guard = lock.write().await;
+                    let mut index = self_clone.read_dir_index(ino_contents_dir).await?;
+                    index.insert(encrypted_name_clone, (entry_clone.ino, entry_clone.kind));
+                    self_clone.write_dir_index(ino_contents_dir, &index).await?;
+                }
+            }
             Ok::<(), FsError>(())
         });
         // add to HASH directory
@@ -2179,8 +3501,7 @@ impl EncryptedFs {
             .unwrap();
         let entry_hash = entry.clone();
         tokio::spawn(async move {
-            let name = crypto::hash_file_name(&entry_hash.name);
-            let file_path = parent_path.join(HASH_DIR).join(name);
+            let file_path = self_clone.hash_entry_path(ino_contents_dir, &entry_hash.name);
             let lock = self_clone
                 .serialize_dir_entries_hash_locks
                 .get_or_insert_with(file_path.to_str().unwrap().to_string(), || {
@@ -2189,7 +3510,7 @@ impl EncryptedFs {
             let _guard = lock.write().await;
             // write inode and file type
             // we save the encrypted name also because we need it to remove the entry on [`remove_directory_entry`]
-            let entry = (entry_hash.ino, entry_hash.kind, encrypted_name);
+            let entry = (entry_hash.ino, entry_hash.kind, encrypted_name_hash);
             crypto::atomic_serialize_encrypt_into(
                 &file_path,
                 &entry,
@@ -2200,41 +3521,272 @@ impl EncryptedFs {
         })
         .await??;
         h.await??;
+        self.forget_dir_entries(&[
+            &self.hash_entry_path(ino_contents_dir, &entry.name),
+            &self
+                .contents_path(ino_contents_dir)
+                .join(LS_DIR)
+                .join(&encrypted_name),
+        ])
+        .await?;
         Ok(())
     }
 
     fn ino_file(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(INODES_DIR).join(ino.to_string())
+        match self.layout {
+            StorageLayout::Hierarchical => self.data_dir.join(INODES_DIR).join(ino.to_string()),
+            StorageLayout::Flat => self.object_path(&format!("inode:{ino}")),
+        }
     }
 
     fn contents_path(&self, ino: u64) -> PathBuf {
-        self.data_dir.join(CONTENTS_DIR).join(ino.to_string())
+        match self.layout {
+            StorageLayout::Hierarchical => self.data_dir.join(CONTENTS_DIR).join(ino.to_string()),
+            StorageLayout::Flat => self.object_path(&format!("contents:{ino}")),
+        }
+    }
+
+    /// Remove a file of the data dir, overwritten first with [`FsOptions::secure_delete`].
+    fn remove_data_file(&self, path: &Path) -> FsResult<()> {
+        if self.options.secure_delete {
+            fs_util::shred_and_remove(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Replace a file of the data dir with `replace`, which writes a new one and renames it over. A rename leaves the
+    /// old copy in free space, so with [`FsOptions::secure_delete`] we keep a link to it and overwrite it after.
+    fn replace_data_file<T>(
+        &self,
+        path: &Path,
+        replace: impl FnOnce() -> FsResult<T>,
+    ) -> FsResult<T> {
+        if !self.options.secure_delete || !path.is_file() {
+            return replace();
+        }
+        let old = shred_link_path(path);
+        // left by a crash, it's either the copy we replaced or one still linked to the file
+        if old.is_file() {
+            fs_util::shred_and_remove(&old)?;
+        }
+        fs::hard_link(path, &old)?;
+        let res = replace();
+        // if it failed it's still linked to the file and only removed
+        fs_util::shred_and_remove(&old)?;
+        res
+    }
+
+    /// Where the content of a file open for write is written, until it's renamed into place by
+    /// [`EncryptedFs::commit_contents`]. Not numeric in [`StorageLayout::Hierarchical`], so it's not taken for an inode.
+    fn contents_tmp_path(&self, ino: u64) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self
+                .data_dir
+                .join(CONTENTS_DIR)
+                .join(format!("{ino}{CONTENTS_TMP_SUFFIX}")),
+            StorageLayout::Flat => self.object_path(&format!("contents.tmp:{ino}")),
+        }
+    }
+
+    /// Copy the content to the temp file and open a writer on it, so the content is never changed in place.
+    async fn create_contents_writer(&self, ino: u64) -> FsResult<Box<dyn CryptoWriteSeek<File>>> {
+        self.keep_version(ino, None).await?;
+        let tmp_path = self.contents_tmp_path(ino);
+        // a stale one might be linked in a snapshot, copy doesn't replace it
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+        if self.is_deduplicated(ino) || self.has_base_content(ino).await? {
+            // put the chunks together again, or copy it from the base, they are split again when committed
+            let mut reader = self.content_reader(ino).await?;
+            let mut writer = self.content_writer(ino, File::create(&tmp_path)?).await?;
+            io::copy(&mut reader, &mut writer)?;
+            writer.finish()?;
+        } else {
+            fs::copy(self.contents_path(ino), &tmp_path)?;
+        }
+        self.content_writer_seek(
+            ino,
+            OpenOptions::new().read(true).write(true).open(&tmp_path)?,
+        )
+        .await
+    }
+
+    /// Finish the writer of the handle, if anything was written since last time, and commit the content.
+    /// The next write creates a new one.
+    async fn finish_contents_writer(&self, ctx: &mut WriteHandleContext) -> FsResult<()> {
+        let Some(mut writer) = ctx.writer.take() else {
+            return Ok(());
+        };
+        if self.pads(ctx.ino).await? {
+            // seeking after the end of content fills with zeros
+            writer.seek(SeekFrom::Start(padded_size(ctx.attr.size)))?;
+        }
+        let file = writer.finish()?;
+        self.commit_contents(ctx.ino, &file).await
+    }
+
+    /// Sync the temp file and rename it over the content, so after a crash we have either the old or the new
+    /// content, never a mix. If the file was removed meanwhile the temp file is dropped.
+    async fn commit_contents(&self, ino: u64, file: &File) -> FsResult<()> {
+        let tmp_path = self.contents_tmp_path(ino);
+        if !self.ino_file(ino).is_file() {
+            self.remove_data_file(&tmp_path)?;
+            return Ok(());
+        }
+        file.sync_all()?;
+        let path = self.contents_path(ino);
+        self.replace_data_file(&path, || Ok(fs::rename(tmp_path, &path)?))?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        self.write_chaff(fs::metadata(&path)?.len())?;
+        self.content_changed(ino).await?;
+        self.content_copied_up(ino).await?;
+        self.dedup_contents(ino).await
+    }
+
+    /// Remove the temp files of writes interrupted by a crash, their content was never committed.
+    /// In [`StorageLayout::Flat`] we can't tell them apart, they are overwritten when the file is written again.
+    fn remove_stale_contents_tmp(&self) -> FsResult<()> {
+        if self.layout != StorageLayout::Hierarchical {
+            return Ok(());
+        }
+        for entry in fs::read_dir(self.data_dir.join(CONTENTS_DIR))? {
+            let path = entry?.path();
+            if path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.ends_with(CONTENTS_TMP_SUFFIX))
+            {
+                self.remove_data_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Only used in [`StorageLayout::Flat`].
+    fn dir_index_path(&self, ino: u64) -> PathBuf {
+        self.object_path(&format!("dir:{ino}"))
+    }
+
+    fn dir_index_lock_key(&self, ino: u64) -> String {
+        self.dir_index_path(ino).to_str().unwrap().to_string()
+    }
+
+    fn object_path(&self, label: &str) -> PathBuf {
+        self.data_dir
+            .join(OBJECTS_DIR)
+            .join(crypto::hash_object_name(&self.object_names_key, label))
+    }
+
+    /// Should be called while holding the lock from [`EncryptedFs::dir_index_lock_key`].
+    async fn read_dir_index(&self, ino: u64) -> FsResult<DirIndex> {
+        let (index, _padding): (DirIndex, Vec<u8>) = self
+            .deserialize_from_file(&self.dir_index_path(ino))
+            .await?;
+        Ok(index)
+    }
+
+    /// Should be called while holding the lock from [`EncryptedFs::dir_index_lock_key`].
+    async fn write_dir_index(&self, ino: u64, index: &DirIndex) -> FsResult<()> {
+        // pad it so the size doesn't reveal how many entries the directory has
+        let len = bincode::serialized_size(&(index, Vec::<u8>::new()))?;
+        #[allow(clippy::cast_possible_truncation)]
+        let padding = vec![0_u8; (padded_size(len) - len) as usize];
+        crypto::atomic_serialize_encrypt_into(
+            &self.dir_index_path(ino),
+            &(index, padding),
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    async fn create_dir_storage(&self, ino: u64) -> FsResult<()> {
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                // create in contents directory
+                let contents_dir = self.contents_path(ino);
+                fs::create_dir(contents_dir.clone())?;
+                // used to keep encrypted file names used by [`read_dir`] and [`read_dir_plus`]
+                fs::create_dir(contents_dir.join(LS_DIR))?;
+                // used to keep hashes of encrypted file names used by [`exists_by_name`] and [`find_by_name`]
+                // this optimizes the search process as we don't need to decrypt all file names and search
+                fs::create_dir(contents_dir.join(HASH_DIR))?;
+            }
+            StorageLayout::Flat => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(ino), || RwLock::new(false));
+                let _guard = lock.write().await;
+                self.write_dir_index(ino, &DirIndex::new()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the storage of an empty directory.
+    async fn remove_dir_storage(&self, ino: u64) -> FsResult<()> {
+        match self.layout {
+            StorageLayout::Hierarchical => fs::remove_dir_all(self.contents_path(ino))?,
+            StorageLayout::Flat => {
+                for name in ["$.", "$.."] {
+                    let path = self.hash_entry_path(ino, &SecretString::from_str(name).unwrap());
+                    let lock = self
+                        .serialize_dir_entries_hash_locks
+                        .get_or_insert_with(path.to_str().unwrap().to_string(), || {
+                            RwLock::new(false)
+                        });
+                    let _guard = lock.write().await;
+                    if path.is_file() {
+                        fs::remove_file(&path)?;
+                    }
+                    self.forget_dir_entries(&[&path]).await?;
+                }
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(ino), || RwLock::new(false));
+                let _guard = lock.write().await;
+                fs::remove_file(self.dir_index_path(ino))?;
+            }
+        }
+        Ok(())
     }
 
     async fn remove_directory_entry(&self, parent: u64, name: &SecretString) -> FsResult<()> {
-        let parent_path = self.contents_path(parent);
         // remove from HASH
-        let name = crypto::hash_file_name(name);
-        let path = parent_path.join(HASH_DIR).join(name);
+        let path = self.hash_entry_path(parent, name);
         let lock = self
             .serialize_dir_entries_hash_locks
             .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
         let guard = lock.write().await;
-        let (_, _, name): (u64, FileType, String) =
-            bincode::deserialize_from(crypto::create_read(
-                File::open(path.clone())?,
-                self.cipher,
-                &*self.key.get().await?,
-            ))?;
-        fs::remove_file(path)?;
+        let (_, _, name): (u64, FileType, String) = self.deserialize_from_file(&path).await?;
+        fs::remove_file(&path)?;
         drop(guard);
+        self.forget_dir_entries(&[&path, &self.contents_path(parent).join(LS_DIR).join(&name)])
+            .await?;
         // remove from LS
-        let path = parent_path.join(LS_DIR).join(name);
-        let lock = self
-            .serialize_dir_entries_ls_locks
-            .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
-        let _guard = lock.write().await;
-        fs::remove_file(path)?;
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                let path = self.contents_path(parent).join(LS_DIR).join(name);
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(path.to_str().unwrap().to_string(), || RwLock::new(false));
+                let _guard = lock.write().await;
+                fs::remove_file(path)?;
+            }
+            StorageLayout::Flat => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(parent), || RwLock::new(false));
+                let _guard = lock.write().await;
+                let mut index = self.read_dir_index(parent).await?;
+                index.remove(&name);
+                self.write_dir_index(parent, &index).await?;
+            }
+        }
         Ok(())
     }
 
@@ -2254,12 +3806,36 @@ impl EncryptedFs {
     }
 }
 
+/// The link to the old copy of a file [`EncryptedFs::replace_data_file`] keeps until it's shredded, a dot file so
+/// it's skipped like other temp files.
+fn shred_link_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!(
+        ".{}.shred",
+        path.file_name().unwrap().to_string_lossy()
+    ))
+}
+
+fn read_key(
+    key_path: &Path,
+    salt_path: &Path,
+    password: &SecretString,
+    cipher: Cipher,
+) -> FsResult<LockedVec> {
+    let salt: Vec<u8> =
+        bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?;
+    let kdf = header::kdf_params(key_path.parent().unwrap())?;
+    let derived_key = crypto::derive_key_with(password, cipher, &salt, &kdf)?;
+    let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
+    bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)
+}
+
 fn read_or_create_key(
     key_path: &PathBuf,
     salt_path: &PathBuf,
+    objects_dir: &Path,
     password: &SecretString,
     cipher: Cipher,
-) -> FsResult<SecretVec<u8>> {
+) -> FsResult<LockedVec> {
     let salt = if salt_path.exists() {
         bincode::deserialize_from(File::open(salt_path)?).map_err(|_| FsError::InvalidPassword)?
     } else {
@@ -2277,20 +3853,26 @@ fn read_or_create_key(
         File::open(salt_path.parent().expect("oops, we don't have a parent"))?.sync_all()?;
         salt
     };
-    // derive key from password
-    let derived_key = crypto::derive_key(password, cipher, &salt)?;
+    // derive key from password, with the cost chosen when it was created
+    let kdf = header::kdf_params(key_path.parent().unwrap())?;
+    let derived_key = crypto::derive_key_with(password, cipher, &salt, &kdf)?;
     if key_path.exists() {
         // read key
         let reader = crypto::create_read(File::open(key_path)?, cipher, &derived_key);
-        let key: Vec<u8> =
-            bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
-        Ok(SecretVec::new(key))
+        let key: bincode::Result<LockedVec> = bincode::deserialize_from(reader);
+        if let Ok(key) = key {
+            return Ok(key);
+        }
+        // it might be the password of a hidden volume
+        if let Some(key) = read_hidden_key(objects_dir, &derived_key, cipher)? {
+            return Ok(key);
+        }
+        // or the secret of a key slot
+        key_slots::read_key_from_slots(key_path.parent().unwrap(), password)?
+            .ok_or(FsError::InvalidPassword)
     } else {
         // first time, create a random key and encrypt it with the derived key from password
-        let mut key: Vec<u8> = vec![];
-        let key_len = cipher.key_len();
-        key.resize(key_len, 0);
-        crypto::create_rng().fill_bytes(&mut key);
+        let key = LockedVec::random(cipher.key_len());
         let mut writer = crypto::create_write(
             OpenOptions::new()
                 .read(true)
@@ -2305,27 +3887,61 @@ fn read_or_create_key(
         let file = writer.finish()?;
         file.sync_all()?;
         File::open(key_path.parent().unwrap())?.sync_all()?;
-        Ok(SecretVec::new(key))
+        Ok(key)
     }
 }
 
-async fn ensure_structure_created(data_dir: &PathBuf) -> FsResult<()> {
+/// Path of the key of a hidden volume, encrypted with `derived_key`.
+fn hidden_key_path(objects_dir: &Path, derived_key: &SecretVec<u8>) -> PathBuf {
+    objects_dir.join(crypto::hash_object_name(
+        &crypto::derive_object_names_key(derived_key),
+        "key",
+    ))
+}
+
+fn read_hidden_key(
+    objects_dir: &Path,
+    derived_key: &SecretVec<u8>,
+    cipher: Cipher,
+) -> FsResult<Option<LockedVec>> {
+    let path = hidden_key_path(objects_dir, derived_key);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let reader = crypto::create_read(File::open(path)?, cipher, derived_key);
+    let key = bincode::deserialize_from(reader).map_err(|_| FsError::InvalidPassword)?;
+    Ok(Some(key))
+}
+
+/// Returns the layout of the data dir, `layout` is used only if it's newly created.
+async fn ensure_structure_created(
+    data_dir: &PathBuf,
+    layout: StorageLayout,
+) -> FsResult<StorageLayout> {
     if data_dir.exists() {
         check_structure(data_dir, true).await?;
     } else {
         fs::create_dir_all(data_dir)?;
     }
+    let layout = StorageLayout::detect(data_dir).unwrap_or(layout);
 
     // create directories
-    let dirs = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
-    for dir in dirs {
+    for dir in layout.dirs() {
         let path = data_dir.join(dir);
         if !path.exists() {
-            fs::create_dir_all(path)?;
+            fs::create_dir_all(&path)?;
+            if *dir == OBJECTS_DIR {
+                chaff::create_chaff_objects(&path)?;
+            }
         }
     }
 
-    Ok(())
+    Ok(layout)
+}
+
+/// If there is a volume in the data dir, even one whose key isn't created yet.
+fn has_volume(data_dir: &Path) -> bool {
+    data_dir.join(SECURITY_DIR).is_dir()
 }
 
 async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
@@ -2337,16 +3953,25 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
         .await?
         .iter()
         .map(|dir| dir.file_name().to_string_lossy().to_string())
+        // these are there only after they were used
+        .filter(|name| {
+            name != snapshots::SNAPSHOTS_DIR
+                && name != versions::VERSIONS_DIR
+                && name != dedup::DEDUP_DIR
+                && name != mirror::SYNC_STATE_FILENAME
+                && name != dir_lock::LOCK_FILENAME
+        })
         .collect::<Vec<String>>();
     if vec.is_empty() && ignore_empty {
         return Ok(());
     }
-    if vec.len() != 3 {
+    let layout = StorageLayout::detect(data_dir).ok_or(FsError::InvalidDataDirStructure)?;
+    if vec.len() != layout.dirs().len() {
         return Err(FsError::InvalidDataDirStructure);
     }
     // make sure existing structure is ok
     vec.sort_unstable();
-    let mut vec2 = vec![INODES_DIR, CONTENTS_DIR, SECURITY_DIR];
+    let mut vec2 = layout.dirs().to_vec();
     vec2.sort_unstable();
     if vec != vec2
         || !data_dir.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file()
@@ -2361,6 +3986,20 @@ async fn check_structure(data_dir: &Path, ignore_empty: bool) -> FsResult<()> {
     Ok(())
 }
 
+/// Size class for `size` bytes of content, using the [Padmé](https://lbarman.ch/blog/padme/) scheme
+/// so the overhead stays under 12% while only leaking `O(log log size)` bits of the size.
+/// Anything smaller than [`PADDING_MIN_SIZE`] is padded to it.
+pub(crate) const fn padded_size(size: u64) -> u64 {
+    if size <= PADDING_MIN_SIZE {
+        return PADDING_MIN_SIZE;
+    }
+    let e = size.ilog2();
+    // e is over 0 as size is over PADDING_MIN_SIZE
+    let s = e.ilog2() + 1;
+    let mask = (1_u64 << (e - s)) - 1;
+    (size + mask) & !mask
+}
+
 fn merge_attr(attr: &mut FileAttr, set_attr: &SetFileAttr, overwrite_size: bool) {
     if let Some(size) = set_attr.size {
         if overwrite_size {
@@ -2415,7 +4054,7 @@ pub async fn write_all_bytes_to_fs(
 ) -> FsResult<()> {
     let mut pos = 0_usize;
     loop {
-        let len = fs.write(ino, offset, &buf[pos..], fh).await?;
+        let len = fs.write(ino, offset + pos as u64, &buf[pos..], fh).await?;
         pos += len;
         if pos == buf.len() {
             break;
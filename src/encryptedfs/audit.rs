@@ -0,0 +1,413 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::crypto;
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, StorageLayout, ROOT_INODE, SECURITY_DIR};
+use crate::fs_util;
+
+/// The audit log in [`StorageLayout::Hierarchical`].
+const AUDIT_LOG_FILENAME: &str = "audit.log";
+/// Where the audit log ends, in [`StorageLayout::Hierarchical`].
+const AUDIT_HEAD_FILENAME: &str = "audit.head";
+
+/// An operation to log, see [`EncryptedFs::audit`].
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub op: &'static str,
+    /// It only reads, it's logged only with [`crate::encryptedfs::FsOptions::audit_reads`].
+    pub read: bool,
+    pub target: AuditTarget,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+/// What an [`AuditEvent`] is about, its path is found when it's logged.
+#[derive(Debug, Clone)]
+pub enum AuditTarget {
+    Inode(u64),
+    /// The name in the dir with this inode.
+    Entry(u64, SecretString),
+    /// From the name in the first dir to the name in the second one.
+    Rename(u64, SecretString, u64, SecretString),
+}
+
+/// An operation done while mounted, kept by [`EncryptedFs::audit`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub op: String,
+    /// From root.
+    pub path: String,
+    /// Where it was moved by a rename.
+    pub new_path: Option<String>,
+    pub uid: u32,
+    pub pid: u32,
+    /// [`None`] if it succeeded.
+    pub errno: Option<i32>,
+}
+
+/// A record as it's stored, after its length, encrypted.
+#[derive(Serialize, Deserialize)]
+struct AuditFrame {
+    /// From 0.
+    seq: u64,
+    /// MAC of the record before, see [`crypto::chain_audit_record`].
+    prev: [u8; 32],
+    record: AuditRecord,
+}
+
+/// Where the audit log ends, written after each record, so records cut from the end are detected too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(super) struct AuditHead {
+    /// Records in it.
+    seq: u64,
+    /// Bytes of it.
+    len: u64,
+    /// MAC of the last record, zeros when empty.
+    mac: [u8; 32],
+}
+
+/// What's in the log, checked.
+struct VerifiedLog {
+    records: Vec<AuditRecord>,
+    head: AuditHead,
+    /// Bytes after the last record, cut by a crash while it was written.
+    torn: bool,
+}
+
+/// One record per line, tab separated, the time in seconds since the epoch.
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03}\t{}\t{}",
+            time.as_secs(),
+            time.subsec_millis(),
+            self.op,
+            self.path
+        )?;
+        if let Some(new_path) = &self.new_path {
+            write!(f, " -> {new_path}")?;
+        }
+        write!(f, "\tuid={}\tpid={}\t", self.uid, self.pid)?;
+        match self.errno {
+            None => write!(f, "ok"),
+            Some(errno) => write!(f, "errno={errno}"),
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Operations logged while mounted with [`crate::encryptedfs::FsOptions::audit_log`], oldest first. Fails with
+    /// [`FsError::AuditLogBroken`] if a record was removed, moved, changed or cut, or can't be decrypted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn audit_log(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<Vec<AuditRecord>> {
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        let _guard = fs.audit_chain.lock().await;
+        let log = fs.verify_audit_log().await?;
+        if log.torn {
+            return Err(FsError::AuditLogBroken(
+                log.head.seq,
+                "incomplete record at the end, cut by a crash or changed",
+            ));
+        }
+        Ok(log.records)
+    }
+
+    /// If an operation that reads only, or changes something, is logged.
+    #[must_use]
+    pub const fn is_audited(&self, read: bool) -> bool {
+        self.options.audit_log && (!read || self.options.audit_reads)
+    }
+
+    /// Append the event to the audit log, with its result, if [`EncryptedFs::is_audited`]. Each record is encrypted
+    /// by itself, so the log is only appended to. It doesn't fail, the operation is done already.
+    pub async fn audit(&self, event: AuditEvent, errno: Option<i32>) {
+        if !self.is_audited(event.read) {
+            return;
+        }
+        if let Err(err) = self.try_audit(event, errno).await {
+            error!(err = %err, "can't write to audit log");
+        }
+    }
+
+    async fn try_audit(&self, event: AuditEvent, errno: Option<i32>) -> FsResult<()> {
+        let (path, new_path) = match &event.target {
+            AuditTarget::Inode(ino) => (self.audit_path(*ino).await, None),
+            AuditTarget::Entry(parent, name) => (self.audit_entry_path(*parent, name).await, None),
+            AuditTarget::Rename(parent, name, new_parent, new_name) => (
+                self.audit_entry_path(*parent, name).await,
+                Some(self.audit_entry_path(*new_parent, new_name).await),
+            ),
+        };
+        let moved = matches!(event.target, AuditTarget::Rename(..)) || event.op == "rmdir";
+        if moved && errno.is_none() {
+            // it and what was under it are not there anymore
+            let mut paths = self.audit_paths.lock().unwrap();
+            let prefix = format!("{path}/");
+            let moved: Vec<u64> = paths
+                .iter()
+                .filter(|(_, p)| **p == path || p.starts_with(&prefix))
+                .map(|(ino, _)| *ino)
+                .collect();
+            for ino in moved {
+                paths.pop(&ino);
+            }
+        }
+        let record = AuditRecord {
+            time: SystemTime::now(),
+            op: event.op.to_string(),
+            path,
+            new_path,
+            uid: event.uid,
+            pid: event.pid,
+            errno,
+        };
+        let key = self.key.get().await?;
+        let _changing = self.changes.enter();
+        let mut chain = self.audit_chain.lock().await;
+        let head = if let Some(head) = *chain {
+            head
+        } else {
+            // the first time, check what's there, we don't chain to a broken log
+            let log = self.verify_audit_log().await?;
+            if log.torn {
+                warn!("audit log has an incomplete record at the end, from a crash, removing it");
+                let file = OpenOptions::new().write(true).open(self.audit_log_path())?;
+                file.set_len(log.head.len)?;
+                file.sync_all()?;
+            }
+            log.head
+        };
+        let (frame, head) = seal_audit_frame(self.cipher, &key, head, record)?;
+        let path = self.audit_log_path();
+        let created = !path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // in one write, so a crash cuts only the last record
+        file.write_all(&frame)?;
+        file.sync_all()?;
+        if created {
+            File::open(path.parent().unwrap())?.sync_all()?;
+        }
+        crypto::atomic_serialize_encrypt_into(&self.audit_head_path(), &head, self.cipher, &key)?;
+        *chain = Some(head);
+        Ok(())
+    }
+
+    /// Remember where a file is, from when it's looked up or created, so it doesn't need to be searched for when
+    /// it's logged.
+    pub(super) fn audit_learn_path(&self, parent: u64, name: &SecretString, ino: u64) {
+        if !self.options.audit_log {
+            return;
+        }
+        let mut paths = self.audit_paths.lock().unwrap();
+        let parent_path = if parent == ROOT_INODE {
+            Some("/".to_string())
+        } else {
+            paths.get(&parent).cloned()
+        };
+        if let Some(parent_path) = parent_path {
+            paths.put(ino, join_path(&parent_path, name.expose_secret()));
+        }
+    }
+
+    async fn audit_entry_path(&self, parent: u64, name: &SecretString) -> String {
+        join_path(&self.audit_path(parent).await, name.expose_secret())
+    }
+
+    async fn audit_path(&self, ino: u64) -> String {
+        if ino == ROOT_INODE {
+            return "/".to_string();
+        }
+        if let Some(path) = self.audit_paths.lock().unwrap().get(&ino) {
+            return path.clone();
+        }
+        self.path_of(ino).await.map_or_else(
+            // removed
+            || format!("<inode {ino}>"),
+            |path| {
+                self.audit_paths.lock().unwrap().put(ino, path.clone());
+                path
+            },
+        )
+    }
+
+    pub(super) fn audit_log_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                self.data_dir.join(SECURITY_DIR).join(AUDIT_LOG_FILENAME)
+            }
+            StorageLayout::Flat => self.object_path("audit-log"),
+        }
+    }
+
+    fn audit_head_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                self.data_dir.join(SECURITY_DIR).join(AUDIT_HEAD_FILENAME)
+            }
+            StorageLayout::Flat => self.object_path("audit-head"),
+        }
+    }
+
+    /// Decrypt all records and check the chain, up to the head. The log can have one more record than the head, when
+    /// it crashed before the head was written. Should be called while holding `audit_chain`.
+    async fn verify_audit_log(&self) -> FsResult<VerifiedLog> {
+        let path = self.audit_log_path();
+        let head_path = self.audit_head_path();
+        match (path.is_file(), head_path.is_file()) {
+            (false, false) => {
+                return Ok(VerifiedLog {
+                    records: vec![],
+                    head: AuditHead::default(),
+                    torn: false,
+                })
+            }
+            (true, false) => return Err(FsError::AuditLogBroken(0, "its head is missing")),
+            (false, true) => return Err(FsError::AuditLogBroken(0, "it's missing")),
+            (true, true) => {}
+        }
+        let stored_head: AuditHead = self
+            .deserialize_from_file(&head_path)
+            .await
+            .map_err(|_| FsError::AuditLogBroken(0, "its head can't be decrypted"))?;
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let keys = self.master_keys().await?;
+        let audit_keys: Vec<_> = keys
+            .iter()
+            .map(|(cipher, key)| (*cipher, key.clone(), crypto::derive_audit_key(key)))
+            .collect();
+        let mut records = vec![];
+        let mut head = AuditHead::default();
+        let mut at_stored_head = head == stored_head;
+        let mut torn = false;
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let Some(frame) = rest.get(..4).and_then(|len| {
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                rest.get(4..4 + len)
+            }) else {
+                torn = true;
+                break;
+            };
+            let (frame_record, mac) = audit_keys
+                .iter()
+                .find_map(|(cipher, key, audit_key)| {
+                    let frame_record: AuditFrame =
+                        bincode::deserialize_from(crypto::create_read(frame, *cipher, key)).ok()?;
+                    Some((
+                        frame_record,
+                        crypto::chain_audit_record(audit_key, &head.mac, frame),
+                    ))
+                })
+                .ok_or(FsError::AuditLogBroken(
+                    head.seq,
+                    "record can't be decrypted",
+                ))?;
+            if frame_record.seq != head.seq {
+                return Err(FsError::AuditLogBroken(
+                    head.seq,
+                    "records were removed or moved",
+                ));
+            }
+            if frame_record.prev != head.mac {
+                return Err(FsError::AuditLogBroken(head.seq, "record was changed"));
+            }
+            head = AuditHead {
+                seq: head.seq + 1,
+                len: head.len + 4 + frame.len() as u64,
+                mac,
+            };
+            rest = &rest[4 + frame.len()..];
+            records.push(frame_record.record);
+            at_stored_head |= head == stored_head;
+        }
+        let ahead = head.seq.saturating_sub(stored_head.seq);
+        // while the key is rotated the log is chained again, then the head is written
+        let rechained = self.is_key_rotation_in_progress() && head.seq == stored_head.seq;
+        if !(at_stored_head && ahead <= 1 || rechained) {
+            return Err(FsError::AuditLogBroken(
+                head.seq.min(stored_head.seq),
+                "records were cut from the end",
+            ));
+        }
+        Ok(VerifiedLog {
+            records,
+            head,
+            torn,
+        })
+    }
+
+    /// Re-encrypt and chain again the records with the current key, after it was rotated. It fails if the log is
+    /// not intact, so nothing is dropped.
+    pub(super) async fn rewrite_audit_log(&self) -> FsResult<()> {
+        let mut chain = self.audit_chain.lock().await;
+        let path = self.audit_log_path();
+        if !path.is_file() && !self.audit_head_path().is_file() {
+            return Ok(());
+        }
+        let log = self.verify_audit_log().await?;
+        let key = self.key.get().await?;
+        let mut data = vec![];
+        let mut head = AuditHead::default();
+        for record in log.records {
+            let (frame, next) = seal_audit_frame(self.cipher, &key, head, record)?;
+            data.extend_from_slice(&frame);
+            head = next;
+        }
+        let mut file = fs_util::open_atomic_write(&path)?;
+        file.write_all(&data)?;
+        file.commit()?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        crypto::atomic_serialize_encrypt_into(&self.audit_head_path(), &head, self.cipher, &key)?;
+        *chain = Some(head);
+        Ok(())
+    }
+}
+
+/// The record as it's appended to the log, with its length, and the head after it.
+fn seal_audit_frame(
+    cipher: Cipher,
+    key: &SecretVec<u8>,
+    head: AuditHead,
+    record: AuditRecord,
+) -> FsResult<(Vec<u8>, AuditHead)> {
+    let frame = AuditFrame {
+        seq: head.seq,
+        prev: head.mac,
+        record,
+    };
+    let mut buf = vec![];
+    crypto::serialize_encrypt_into(&mut buf, &frame, cipher, key)?;
+    let len =
+        u32::try_from(buf.len()).map_err(|_| FsError::InvalidInput("audit record too big"))?;
+    let mac = crypto::chain_audit_record(&crypto::derive_audit_key(key), &head.mac, &buf);
+    let next = AuditHead {
+        seq: head.seq + 1,
+        len: head.len + 4 + buf.len() as u64,
+        mac,
+    };
+    Ok(([&len.to_le_bytes()[..], &buf].concat(), next))
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{name}")
+    } else {
+        format!("{parent}/{name}")
+    }
+}
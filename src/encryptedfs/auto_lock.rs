@@ -0,0 +1,82 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+
+impl EncryptedFs {
+    /// Wipe the keys and decrypted metadata from memory and ask the [`PasswordProvider`] to forget the password.
+    /// Until the password is given again, [`EncryptedFs::ensure_unlocked`] fails.
+    ///
+    /// Files that are open keep their own key until they are closed.
+    ///
+    /// [`PasswordProvider`]: crate::encryptedfs::PasswordProvider
+    pub async fn lock(&self) {
+        self.wipe_secrets().await;
+        self.password_provider.forget_password();
+        info!("filesystem locked");
+    }
+
+    /// Like [`EncryptedFs::lock`] but the password is kept, like before exiting.
+    pub async fn wipe_secrets(&self) {
+        self.locked.store(true, Ordering::SeqCst);
+        self.key.clear().await;
+        self.old_key.clear().await;
+        self.attr_cache.clear().await;
+        self.dir_entries_name_cache.clear().await;
+        self.dir_entries_meta_cache.clear().await;
+        self.dir_entries_hash_cache.clear().await;
+        self.file_keys_cache.clear().await;
+    }
+
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    /// Call it before each operation, it counts as activity for [`FsOptions::auto_lock`]. If locked, it tries to
+    /// unlock with the password from the [`PasswordProvider`], when that's wrong it's asked to forget it.
+    ///
+    /// [`FsOptions::auto_lock`]: crate::encryptedfs::FsOptions::auto_lock
+    /// [`PasswordProvider`]: crate::encryptedfs::PasswordProvider
+    #[allow(clippy::missing_panics_doc)]
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn ensure_unlocked(&self) -> FsResult<()> {
+        *self.last_access.lock().expect("cannot obtain lock") = Instant::now();
+        if !self.is_locked() {
+            return Ok(());
+        }
+        match self.key.get().await {
+            Ok(_) => {
+                self.locked.store(false, Ordering::SeqCst);
+                info!("filesystem unlocked");
+                Ok(())
+            }
+            Err(FsError::InvalidPassword) => {
+                self.password_provider.forget_password();
+                Err(FsError::Locked)
+            }
+            Err(_) => Err(FsError::Locked),
+        }
+    }
+
+    /// Lock after `timeout` without calls to [`EncryptedFs::ensure_unlocked`].
+    pub(super) fn spawn_auto_lock(self: &Arc<Self>, timeout: Duration) {
+        let fs = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(timeout.min(Duration::from_secs(1))).await;
+                let Some(fs) = fs.upgrade() else {
+                    break;
+                };
+                let idle = fs.last_access.lock().expect("cannot obtain lock").elapsed();
+                // the rotation needs the key until it finishes
+                if idle >= timeout && !fs.is_locked() && !fs.is_key_rotation_in_progress() {
+                    fs.lock().await;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use argon2::password_hash::rand_core::RngCore;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::crypto;
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult,
+    StaticPasswordProvider, ROOT_INODE,
+};
+use crate::{fs_util, stream_util};
+
+/// Start of each archive, in plain text so it's recognized as one.
+const ARCHIVE_MAGIC: &[u8; 8] = b"RENCFSAR";
+/// Version of the archive format, raise it with each change of [`ArchiveHeader`] or [`ArchiveEntry`].
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Written in plain text after [`ARCHIVE_MAGIC`], what's after it is encrypted with a key derived from the password
+/// and `salt`, so the archive doesn't need the data dir to be restored.
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    version: u32,
+    cipher: Cipher,
+    salt: Vec<u8>,
+}
+
+/// Paths are relative to what was exported, with `/` between names, the empty one is what was exported itself.
+#[derive(Serialize, Deserialize)]
+enum ArchiveEntry {
+    Dir {
+        path: String,
+        attr: FileAttr,
+    },
+    /// Followed by `attr.size` bytes of content.
+    File {
+        path: String,
+        attr: FileAttr,
+    },
+    /// So a truncated archive is not taken for a smaller one.
+    End,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveReport {
+    pub dirs: u64,
+    pub files: u64,
+    /// Content of the files.
+    pub bytes: u64,
+}
+
+impl EncryptedFs {
+    /// Write the whole volume, or only what's under `subtree` in it, to a single archive file, so it can be backed
+    /// up where only flat files can be stored. It's encrypted with the same password, see [`EncryptedFs::restore`].
+    ///
+    /// The archive is written to a temp file and renamed into place at the end.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn export(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        subtree: Option<&str>,
+        archive: &Path,
+    ) -> FsResult<ArchiveReport> {
+        let fs = Self::open_read_only(data_dir, password.clone(), cipher).await?;
+        let subtree = subtree.unwrap_or("/");
+        let root = fs.resolve_path(subtree).await?;
+        // a file by itself is put in root with its name
+        let root_path = if root.kind == FileType::Directory {
+            String::new()
+        } else {
+            subtree
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        };
+        info!(archive = %archive.display(), "exporting data dir");
+
+        let mut salt = vec![0; 16];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let mut file = fs_util::open_atomic_write(archive)?;
+        file.write_all(ARCHIVE_MAGIC)?;
+        bincode::serialize_into(
+            &mut file,
+            &ArchiveHeader {
+                version: ARCHIVE_VERSION,
+                cipher,
+                salt: salt.clone(),
+            },
+        )?;
+        let key = crypto::derive_key(&password, cipher, &salt)?;
+        let mut writer = crypto::create_write(file, cipher, &key);
+
+        let mut report = ArchiveReport::default();
+        // depth first, so a dir is always before what's in it
+        let mut stack = vec![(root, root_path)];
+        while let Some((attr, path)) = stack.pop() {
+            if attr.kind == FileType::Directory {
+                bincode::serialize_into(
+                    &mut writer,
+                    &ArchiveEntry::Dir {
+                        path: path.clone(),
+                        attr,
+                    },
+                )?;
+                report.dirs += 1;
+                for entry in fs.child_entries(attr.ino).await? {
+                    let child = fs.get_inode_from_storage(entry.0).await?;
+                    let child_path = if path.is_empty() {
+                        entry.1
+                    } else {
+                        format!("{path}/{}", entry.1)
+                    };
+                    stack.push((child, child_path));
+                }
+            } else {
+                bincode::serialize_into(&mut writer, &ArchiveEntry::File { path, attr })?;
+                fs.export_content(&attr, &mut writer).await?;
+                report.files += 1;
+                report.bytes += attr.size;
+            }
+        }
+        bincode::serialize_into(&mut writer, &ArchiveEntry::End)?;
+        let file = writer.finish()?;
+        file.commit()?;
+        info!(dirs = report.dirs, files = report.files, "export finished");
+        Ok(report)
+    }
+
+    /// Create a new volume in `data_dir`, which must be empty, with what's in an archive made by
+    /// [`EncryptedFs::export`], encrypted with the same password it was exported with. What was exported is put
+    /// in root.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn restore(
+        archive: &Path,
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<ArchiveReport> {
+        if data_dir.exists() && fs::read_dir(data_dir)?.next().is_some() {
+            return Err(FsError::InvalidInput("data dir is not empty"));
+        }
+        let mut file = File::open(archive)?;
+        let mut magic = [0; ARCHIVE_MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || &magic != ARCHIVE_MAGIC {
+            return Err(FsError::InvalidInput("not an archive"));
+        }
+        let header: ArchiveHeader = bincode::deserialize_from(&mut file)?;
+        if header.version != ARCHIVE_VERSION {
+            return Err(FsError::InvalidInput("unsupported archive version"));
+        }
+        let key = crypto::derive_key(&password, header.cipher, &header.salt)?;
+        let mut reader = crypto::create_read(file, header.cipher, &key);
+        // the first one to decrypt tells if the password is right
+        let mut entry: ArchiveEntry =
+            bincode::deserialize_from(&mut reader).map_err(|_| FsError::InvalidPassword)?;
+
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_create(true),
+            None,
+            false,
+        )
+        .await?;
+        info!(archive = %archive.display(), "restoring archive");
+        let mut report = ArchiveReport::default();
+        let mut dirs = HashMap::new();
+        // times of dirs are set at the end, creating entries in them changes them
+        let mut dir_attrs = vec![];
+        loop {
+            match entry {
+                ArchiveEntry::Dir { path, attr } => {
+                    let ino = if path.is_empty() {
+                        ROOT_INODE
+                    } else {
+                        fs.restore_entry(&dirs, &path, &attr, false).await?.0
+                    };
+                    dirs.insert(path, ino);
+                    dir_attrs.push((ino, attr));
+                    report.dirs += 1;
+                }
+                ArchiveEntry::File { path, attr } => {
+                    let (ino, fh) = fs.restore_entry(&dirs, &path, &attr, true).await?;
+                    fs.restore_content(ino, fh, attr.size, &mut reader).await?;
+                    fs.restore_times(ino, &attr).await?;
+                    report.files += 1;
+                    report.bytes += attr.size;
+                }
+                ArchiveEntry::End => break,
+            }
+            entry = bincode::deserialize_from(&mut reader)?;
+        }
+        for (ino, attr) in dir_attrs.into_iter().rev() {
+            fs.restore_times(ino, &attr).await?;
+        }
+        info!(dirs = report.dirs, files = report.files, "restore finished");
+        Ok(report)
+    }
+
+    /// Attributes of the entry at `path` from root, which is `/`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn resolve_path(&self, path: &str) -> FsResult<FileAttr> {
+        let mut attr = self.get_inode_from_storage(ROOT_INODE).await?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if attr.kind != FileType::Directory {
+                return Err(FsError::InvalidInodeType);
+            }
+            attr = self
+                .find_by_name(attr.ino, &SecretString::from(name.to_string()))
+                .await?
+                .ok_or(FsError::NotFound("path in volume"))?;
+        }
+        Ok(attr)
+    }
+
+    /// Inode and name of the entries in a dir, without `.` and `..`.
+    pub(super) async fn child_entries(&self, ino: u64) -> FsResult<Vec<(u64, String)>> {
+        let mut entries = vec![];
+        for entry in self.raw_dir_entries(ino).await? {
+            let entry = self.create_directory_entry(entry).await?;
+            let name = entry.name.expose_secret();
+            if name != "." && name != ".." {
+                entries.push((entry.ino, name.clone()));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn export_content(&self, attr: &FileAttr, w: &mut (impl Write + Send)) -> FsResult<()> {
+        if attr.size == 0 {
+            return Ok(());
+        }
+        let mut reader = self.content_reader(attr.ino).await?;
+        stream_util::copy_exact(&mut reader, w, attr.size)?;
+        Ok(())
+    }
+
+    /// Returns the inode and, for files, the write handle.
+    async fn restore_entry(
+        &self,
+        dirs: &HashMap<String, u64>,
+        path: &str,
+        attr: &FileAttr,
+        write: bool,
+    ) -> FsResult<(u64, u64)> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = *dirs
+            .get(parent)
+            .ok_or(FsError::InvalidInput("entry in archive without its dir"))?;
+        let (fh, created) = self
+            .create(
+                parent,
+                &SecretString::from(name.to_string()),
+                CreateFileAttr {
+                    kind: attr.kind,
+                    perm: attr.perm,
+                    uid: attr.uid,
+                    gid: attr.gid,
+                    rdev: attr.rdev,
+                    flags: attr.flags,
+                },
+                false,
+                write,
+            )
+            .await?;
+        Ok((created.ino, fh))
+    }
+
+    pub(super) async fn restore_content(
+        &self,
+        ino: u64,
+        fh: u64,
+        size: u64,
+        r: &mut (impl Read + Send),
+    ) -> FsResult<()> {
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            #[allow(clippy::cast_possible_truncation)]
+            let buf = &mut buf[..(size - offset).min(BLOCK_SIZE as u64) as usize];
+            r.read_exact(buf)?;
+            let mut pos = 0;
+            while pos < buf.len() {
+                let len = self
+                    .write(ino, offset + pos as u64, &buf[pos..], fh)
+                    .await?;
+                if len == 0 {
+                    return Err(FsError::Other("failed to write all bytes"));
+                }
+                pos += len;
+            }
+            offset += buf.len() as u64;
+        }
+        self.release(fh).await
+    }
+
+    /// Set the times and permissions as they were. [`EncryptedFs::set_attr`] only moves times forward, and ours
+    /// are older.
+    pub(super) async fn restore_times(&self, ino: u64, attr: &FileAttr) -> FsResult<()> {
+        let mut restored = self.get_attr(ino).await?;
+        restored.atime = attr.atime;
+        restored.mtime = attr.mtime;
+        restored.ctime = attr.ctime;
+        restored.crtime = attr.crtime;
+        restored.perm = attr.perm;
+        self.write_inode_to_storage(&restored).await
+    }
+}
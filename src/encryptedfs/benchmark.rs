@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use secrecy::SecretString;
+
+use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsError, FsResult, ROOT_INODE};
+
+/// How much [`EncryptedFs::benchmark`] does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchmarkSettings {
+    /// Of the file read and written.
+    pub file_size: u64,
+    /// Bytes of each read and write.
+    pub io_size: usize,
+    /// Created, looked up, stat'ed and removed for the metadata operations.
+    pub files: usize,
+}
+
+impl Default for BenchmarkSettings {
+    fn default() -> Self {
+        Self {
+            file_size: 64 * 1024 * 1024,
+            io_size: 128 * 1024,
+            files: 1000,
+        }
+    }
+}
+
+/// One of the measurements of [`EncryptedFs::benchmark`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchmarkResult {
+    pub name: &'static str,
+    pub ops: u64,
+    /// Read or written, 0 for metadata operations.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchmarkResult {
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl EncryptedFs {
+    /// Measure sequential and random reads and writes of a file, then creating, looking up, stat'ing, listing and
+    /// removing files. It's meant for a new, empty volume, made only for it, what it creates is in the root.
+    #[allow(clippy::missing_errors_doc)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn benchmark(&self, settings: &BenchmarkSettings) -> FsResult<Vec<BenchmarkResult>> {
+        if settings.io_size == 0 || settings.file_size < settings.io_size as u64 {
+            return Err(FsError::InvalidInput(
+                "file size must be at least the size of a read or write",
+            ));
+        }
+        let io_size = settings.io_size as u64;
+        let blocks = settings.file_size / io_size;
+        let mut results = vec![];
+        let mut buf = vec![0_u8; settings.io_size];
+        rand::thread_rng().fill(&mut buf[..]);
+
+        let name = SecretString::new("bench".to_string());
+        let (handle, attr) = self
+            .create(ROOT_INODE, &name, file_attr(), false, true)
+            .await?;
+        let ino = attr.ino;
+        let start = Instant::now();
+        for i in 0..blocks {
+            self.write(ino, i * io_size, &buf, handle).await?;
+        }
+        self.flush(handle).await?;
+        self.release(handle).await?;
+        results.push(bytes_result("sequential write", blocks, io_size, start));
+
+        let handle = self.open(ino, true, false).await?;
+        let start = Instant::now();
+        for i in 0..blocks {
+            self.read(ino, i * io_size, &mut buf, handle).await?;
+        }
+        self.release(handle).await?;
+        results.push(bytes_result("sequential read", blocks, io_size, start));
+
+        let offsets: Vec<u64> = {
+            let mut rng = rand::thread_rng();
+            (0..blocks)
+                .map(|_| rng.gen_range(0..blocks) * io_size)
+                .collect()
+        };
+        let handle = self.open(ino, false, true).await?;
+        let start = Instant::now();
+        for offset in &offsets {
+            self.write(ino, *offset, &buf, handle).await?;
+        }
+        self.flush(handle).await?;
+        self.release(handle).await?;
+        results.push(bytes_result("random write", blocks, io_size, start));
+
+        let handle = self.open(ino, true, false).await?;
+        let start = Instant::now();
+        for offset in &offsets {
+            self.read(ino, *offset, &mut buf, handle).await?;
+        }
+        self.release(handle).await?;
+        results.push(bytes_result("random read", blocks, io_size, start));
+        self.remove_file(ROOT_INODE, &name).await?;
+
+        let names: Vec<SecretString> = (0..settings.files)
+            .map(|i| SecretString::new(format!("bench-{i}")))
+            .collect();
+        let files = names.len() as u64;
+        let mut inodes = Vec::with_capacity(names.len());
+        let start = Instant::now();
+        for name in &names {
+            let (_, attr) = self
+                .create(ROOT_INODE, name, file_attr(), false, false)
+                .await?;
+            inodes.push(attr.ino);
+        }
+        results.push(ops_result("create", files, start));
+
+        let start = Instant::now();
+        for name in &names {
+            self.find_by_name(ROOT_INODE, name)
+                .await?
+                .ok_or(FsError::NotFound("file just created"))?;
+        }
+        results.push(ops_result("lookup", files, start));
+
+        let start = Instant::now();
+        for ino in &inodes {
+            self.get_attr(*ino).await?;
+        }
+        results.push(ops_result("getattr", files, start));
+
+        let start = Instant::now();
+        let entries = self.read_dir(ROOT_INODE).await?.count() as u64;
+        results.push(ops_result("readdir entry", entries, start));
+
+        let start = Instant::now();
+        for name in &names {
+            self.remove_file(ROOT_INODE, name).await?;
+        }
+        results.push(ops_result("remove", files, start));
+
+        Ok(results)
+    }
+}
+
+const fn file_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+fn bytes_result(name: &'static str, ops: u64, io_size: u64, start: Instant) -> BenchmarkResult {
+    BenchmarkResult {
+        name,
+        ops,
+        bytes: ops * io_size,
+        elapsed: start.elapsed(),
+    }
+}
+
+fn ops_result(name: &'static str, ops: u64, start: Instant) -> BenchmarkResult {
+    BenchmarkResult {
+        name,
+        ops,
+        bytes: 0,
+        elapsed: start.elapsed(),
+    }
+}
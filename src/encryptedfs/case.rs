@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::crypto;
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, StorageLayout, HASH_DIR, SECURITY_DIR};
+
+/// In [`SECURITY_DIR`], the `hash` index of all dirs is of the names in lower case, see
+/// [`crate::encryptedfs::FsOptions::case_insensitive`].
+pub(super) const CASE_INSENSITIVE_FILENAME: &str = "case.insensitive";
+/// In [`SECURITY_DIR`] while the index is being changed to be case-insensitive, it's finished on next open.
+pub(super) const CASE_REINDEX_FILENAME: &str = "case.reindex";
+
+/// If the index of the data dir is case-insensitive, or it's being changed to be.
+pub(super) fn is_case_insensitive(security_dir: &Path) -> bool {
+    security_dir.join(CASE_INSENSITIVE_FILENAME).is_file()
+        || security_dir.join(CASE_REINDEX_FILENAME).is_file()
+}
+
+/// What names are compared by when case-insensitive, `Foo` and `FOO` are the same name.
+fn fold_case(name: &SecretString) -> SecretString {
+    SecretString::new(name.expose_secret().to_lowercase())
+}
+
+impl EncryptedFs {
+    /// Path of the entry from the `hash` directory, used by [`EncryptedFs::exists_by_name`] and
+    /// [`EncryptedFs::find_by_name`]. The name is stored as it was given in the `ls` one, the hash is of it in
+    /// lower case if case-insensitive, so any case finds it.
+    pub(super) fn hash_entry_path(&self, parent: u64, name: &SecretString) -> PathBuf {
+        self.hash_entry_path_as(parent, name, self.case_insensitive)
+    }
+
+    fn hash_entry_path_as(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        case_insensitive: bool,
+    ) -> PathBuf {
+        let hash = if case_insensitive {
+            crypto::hash_file_name(&fold_case(name))
+        } else {
+            crypto::hash_file_name(name)
+        };
+        match self.layout {
+            StorageLayout::Hierarchical => self.contents_path(parent).join(HASH_DIR).join(hash),
+            StorageLayout::Flat => self.object_path(&format!("hash:{parent}:{hash}")),
+        }
+    }
+
+    /// The first time it's opened case-insensitive, move the entries of the `hash` index of all dirs to the hash of
+    /// their names in lower case. Refused, before anything is changed, if a dir has names that differ only in case.
+    /// It stays case-insensitive after.
+    pub(super) async fn make_case_insensitive(&self) -> FsResult<()> {
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        if !self.case_insensitive || security_dir.join(CASE_INSENSITIVE_FILENAME).is_file() {
+            return Ok(());
+        }
+        let mut moves = vec![];
+        for ino in self.walk_tree().await? {
+            if !self.is_dir(ino) {
+                continue;
+            }
+            let mut names = HashSet::new();
+            for entry in self.raw_dir_entries(ino).await? {
+                let entry = self.create_directory_entry(entry).await?;
+                if !names.insert(fold_case(&entry.name).expose_secret().clone()) {
+                    return Err(FsError::InvalidInput(
+                        "a directory has names that differ only in case",
+                    ));
+                }
+                let from = self.hash_entry_path_as(ino, &entry.name, false);
+                let to = self.hash_entry_path(ino, &entry.name);
+                if from != to {
+                    moves.push((from, to));
+                }
+            }
+        }
+        // so after a crash it's finished, and the index is not used case-sensitive meanwhile
+        File::create(security_dir.join(CASE_REINDEX_FILENAME))?;
+        File::open(&security_dir)?.sync_all()?;
+        for (from, to) in &moves {
+            // it was moved before a crash
+            if from.is_file() {
+                fs::rename(from, to)?;
+            }
+        }
+        let dirs: HashSet<_> = moves.iter().filter_map(|(_, to)| to.parent()).collect();
+        for dir in dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        File::create(security_dir.join(CASE_INSENSITIVE_FILENAME))?;
+        fs::remove_file(security_dir.join(CASE_REINDEX_FILENAME))?;
+        File::open(&security_dir)?.sync_all()?;
+        let paths: Vec<_> = moves.into_iter().flat_map(<[_; 2]>::from).collect();
+        self.forget_dir_entries(&paths).await
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use rand_core::RngCore;
+
+use crate::encryptedfs::{EncryptedFs, FsResult, StorageLayout, OBJECTS_DIR};
+use crate::{crypto, fs_util};
+
+/// One write in this many of an object also writes chaff.
+const CHAFF_ODDS: u32 = 8;
+/// Chaff is about the size of the object written, up to this.
+const CHAFF_MAX_LEN: u64 = 8 * 1024 * 1024;
+
+impl EncryptedFs {
+    /// Called after an object of `len` bytes was written, in [`StorageLayout::Flat`]. Sometimes a random object of
+    /// about the same size is written too, or one written since it was opened is rewritten or removed. So the
+    /// objects that are not referenced by the volume keep changing, like the ones of a hidden volume would.
+    ///
+    /// Chaff isn't remembered after it's closed, as the outer password would then tell it apart from a hidden
+    /// volume. What's left of it stays in the pool, about one object in 32 written.
+    pub(super) fn write_chaff(&self, len: u64) -> FsResult<()> {
+        if self.layout != StorageLayout::Flat || self.options.read_only {
+            return Ok(());
+        }
+        let mut rng = crypto::create_rng();
+        if rng.next_u32() % CHAFF_ODDS != 0 {
+            return Ok(());
+        }
+        let len = (len / 2 + u64::from(rng.next_u32()) % (len + 1)).clamp(64, CHAFF_MAX_LEN);
+        let mut chaff = self.chaff.lock().expect("cannot obtain lock");
+        let action = rng.next_u32() % 4;
+        if chaff.is_empty() || action < 2 {
+            let mut name = [0_u8; 32];
+            rng.fill_bytes(&mut name);
+            let path = self.data_dir.join(OBJECTS_DIR).join(hex::encode(name));
+            write_random(&path, len, &mut rng)?;
+            chaff.push(path);
+        } else {
+            #[allow(clippy::cast_possible_truncation)]
+            let i = rng.next_u32() as usize % chaff.len();
+            if action == 2 {
+                fs::remove_file(chaff.swap_remove(i))?;
+            } else {
+                write_random(&chaff[i], len, &mut rng)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill a new flat pool with random objects that aren't referenced by the volume, so the key of a hidden volume
+/// doesn't stand out on its own. More are written while the volume is used, see [`EncryptedFs::write_chaff`].
+pub(super) fn create_chaff_objects(objects_dir: &Path) -> FsResult<()> {
+    let mut rng = crypto::create_rng();
+    let count = 16 + rng.next_u32() % 112;
+    for _ in 0..count {
+        let mut name = [0_u8; 32];
+        rng.fill_bytes(&mut name);
+        // mix small objects, like inodes and entries, with bigger ones, like contents
+        let max_len = if rng.next_u32() % 2 == 0 {
+            512
+        } else {
+            16 * 1024
+        };
+        let len = 64 + rng.next_u32() % max_len;
+        write_random(&objects_dir.join(hex::encode(name)), len.into(), &mut rng)?;
+    }
+    File::open(objects_dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Replaced like the objects of the volume, with a rename.
+fn write_random(path: &Path, len: u64, rng: &mut impl RngCore) -> FsResult<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mut data = vec![0_u8; len as usize];
+    rng.fill_bytes(&mut data);
+    let mut file = fs_util::open_atomic_write(path)?;
+    file.write_all(&data)?;
+    file.commit()?;
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, info};
+
+use crate::crypto::write::CryptoWrite;
+use crate::crypto::{Cipher, LockedString, LockedVec};
+use crate::encryptedfs::{
+    check_structure, header, hidden_key_path, read_hidden_key, read_key, totp, EncryptedFs,
+    FileType, FsError, FsOptions, FsResult, StaticPasswordProvider, CIPHER_MIGRATION_FILENAME,
+    KEY_ENC_FILENAME, KEY_OLD_ENC_FILENAME, KEY_SALT_FILENAME, OBJECTS_DIR, ROOT_INODE,
+    SECURITY_DIR,
+};
+use crate::{crypto, fs_util};
+
+impl EncryptedFs {
+    /// Re-encrypt the data dir, which was created with `from` cipher, with `to` cipher. The master key
+    /// stays the same, only what's encrypted with it is changed.
+    ///
+    /// It needs to be done while the filesystem is not mounted. If interrupted, the data dir can't be
+    /// mounted until this is called again with the same ciphers, it continues from where it stopped.
+    /// At the end everything is read back with `to` cipher to verify it.
+    ///
+    /// If the data dir has a hidden volume, migrate it also, with its password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn migrate_cipher(
+        data_dir: &Path,
+        password: LockedString,
+        from: Cipher,
+        to: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        if from == to {
+            return Err(FsError::InvalidInput("ciphers must be different"));
+        }
+        if from.key_len() != to.key_len() {
+            // the master key is kept
+            return Err(FsError::InvalidInput(
+                "ciphers must have the same key length",
+            ));
+        }
+        if Self::has_deduplicated_content(data_dir)? {
+            return Err(FsError::InvalidInput(
+                "cipher migration is not supported with deduplicated content",
+            ));
+        }
+        let security_dir = data_dir.join(SECURITY_DIR);
+        if security_dir.join(KEY_OLD_ENC_FILENAME).exists() {
+            return Err(FsError::InvalidInput(
+                "can't migrate cipher while a key rotation is in progress",
+            ));
+        }
+        let marker_path = security_dir.join(CIPHER_MIGRATION_FILENAME);
+        let in_progress = marker_path.is_file();
+        if in_progress {
+            let ciphers: (Cipher, Cipher) = bincode::deserialize_from(File::open(&marker_path)?)?;
+            if ciphers != (from, to) {
+                return Err(FsError::InvalidInput(
+                    "another cipher migration is in progress",
+                ));
+            }
+        }
+        // check the password before changing anything
+        let (key_path, key_cipher) = master_key_path(data_dir, &password, from, to)?;
+        if !in_progress {
+            let mut file = fs_util::open_atomic_write(&marker_path)?;
+            bincode::serialize_into(&mut file, &(from, to))?;
+            file.commit()?;
+            File::open(&security_dir)?.sync_all()?;
+        }
+        info!(%from, %to, "migrating cipher");
+
+        let salt: Vec<u8> =
+            bincode::deserialize_from(File::open(security_dir.join(KEY_SALT_FILENAME))?)?;
+        // same key length, so the key derived from password is the same for both
+        let kdf = header::kdf_params(&security_dir)?;
+        let derived_key = crypto::derive_key_with(&password, to, &salt, &kdf)?;
+        let reader = crypto::create_read(File::open(&key_path)?, key_cipher, &derived_key);
+        let key: LockedVec = bincode::deserialize_from(reader)?;
+        if key_cipher == from {
+            crypto::atomic_serialize_encrypt_into(
+                &key_path,
+                &key.expose_secret(),
+                to,
+                &derived_key,
+            )?;
+        }
+        totp::reencrypt_totp_secret(&security_dir, (from, &key), (to, &key))?;
+
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password.clone())),
+            to,
+            FsOptions::default(),
+            Some(from),
+            false,
+        )
+        .await?;
+        for ino in fs.walk_tree().await? {
+            debug!(ino, "migrating cipher");
+            fs.reencrypt_metadata(ino).await?;
+            fs.migrate_file_content(ino, from).await?;
+        }
+        fs.rewrite_content_tree().await?;
+        fs.rewrite_overlay_index().await?;
+        fs.rewrite_passthrough().await?;
+        drop(fs);
+        // it's opened with the one in the header from now on, the marker stays until it's verified
+        if let Some(mut header) = header::read_header(&security_dir)? {
+            header.cipher = to;
+            header::write_header(&security_dir, &header)?;
+        }
+
+        // open it like when mounting, without falling back to the old cipher
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            to,
+            FsOptions::default(),
+            None,
+            false,
+        )
+        .await?;
+        fs.verify_cipher_migration().await?;
+        fs::remove_file(marker_path)?;
+        File::open(security_dir)?.sync_all()?;
+        info!("cipher migrated");
+        Ok(())
+    }
+
+    /// All inodes reachable from root, parents before their children.
+    pub(super) async fn walk_tree(&self) -> FsResult<Vec<u64>> {
+        let mut inodes = vec![];
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([ROOT_INODE]);
+        while let Some(ino) = queue.pop_front() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            inodes.push(ino);
+            if !self.is_dir(ino) {
+                continue;
+            }
+            for entry in self.raw_dir_entries(ino).await? {
+                let entry = self.create_directory_entry(entry).await?;
+                if entry.name.expose_secret() != "." && entry.name.expose_secret() != ".." {
+                    queue.push_back(entry.ino);
+                }
+            }
+        }
+        Ok(inodes)
+    }
+
+    /// Re-encrypt the content with the current cipher, if not already.
+    async fn migrate_file_content(&self, ino: u64, from: Cipher) -> FsResult<()> {
+        let path = self.contents_path(ino);
+        if self.is_dir(ino) || !path.is_file() || self.is_plaintext(ino).await? {
+            return Ok(());
+        }
+        let key = self.file_key(ino).await?;
+        // reading the first block is enough to know which cipher it uses, content files are replaced atomically
+        let mut reader = crypto::create_read(File::open(&path)?, self.cipher, &key);
+        if reader.read(&mut [0_u8; 1]).is_ok() {
+            return Ok(());
+        }
+        let mut file = fs_util::open_atomic_write(&path)?;
+        {
+            // have a new scope, so we drop the reader before moving new content files
+            let mut reader = crypto::create_read(File::open(&path)?, from, &key);
+            let mut writer = crypto::create_write(file, self.cipher, &key);
+            io::copy(&mut reader, &mut writer)?;
+            file = writer.finish()?;
+        }
+        self.replace_data_file(&path, || Ok(file.commit()?))?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        self.update_content_leaf(ino).await
+    }
+
+    /// Read everything back, which fails if something is still encrypted with the old cipher.
+    async fn verify_cipher_migration(&self) -> FsResult<()> {
+        for ino in self.walk_tree().await? {
+            let attr = self.get_inode_from_storage(ino).await?;
+            if attr.kind == FileType::Directory {
+                for entry in self.raw_dir_entries(ino).await? {
+                    let entry = self.create_directory_entry(entry).await?;
+                    // this reads the entry from the hash index
+                    if self.find_by_name(ino, &entry.name).await?.is_none() {
+                        return Err(FsError::Other("directory entry missing from hash index"));
+                    }
+                }
+            } else if self.contents_path(ino).is_file() && !self.is_plaintext(ino).await? {
+                let mut reader = crypto::create_read(
+                    File::open(self.contents_path(ino))?,
+                    self.cipher,
+                    &*self.file_key(ino).await?,
+                );
+                io::copy(&mut reader, &mut io::sink())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of the master key the password opens and the cipher it's currently encrypted with.
+fn master_key_path(
+    data_dir: &Path,
+    password: &SecretString,
+    from: Cipher,
+    to: Cipher,
+) -> FsResult<(PathBuf, Cipher)> {
+    let security_dir = data_dir.join(SECURITY_DIR);
+    let key_path = security_dir.join(KEY_ENC_FILENAME);
+    let salt_path = security_dir.join(KEY_SALT_FILENAME);
+    for cipher in [to, from] {
+        if read_key(&key_path, &salt_path, password, cipher).is_ok() {
+            return Ok((key_path, cipher));
+        }
+    }
+    // it might be the password of a hidden volume
+    let salt: Vec<u8> = bincode::deserialize_from(File::open(salt_path)?)?;
+    let kdf = header::kdf_params(&security_dir)?;
+    let derived_key = crypto::derive_key_with(password, to, &salt, &kdf)?;
+    let objects_dir = data_dir.join(OBJECTS_DIR);
+    for cipher in [to, from] {
+        if let Ok(Some(_)) = read_hidden_key(&objects_dir, &derived_key, cipher) {
+            return Ok((hidden_key_path(&objects_dir, &derived_key), cipher));
+        }
+    }
+    Err(FsError::InvalidPassword)
+}
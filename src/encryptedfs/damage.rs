@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::crypto;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    EncryptedFs, FileType, FsOptions, FsResult, StaticPasswordProvider, StorageLayout, ROOT_INODE,
+    SECURITY_DIR,
+};
+
+/// The corruption log in [`StorageLayout::Hierarchical`].
+const CORRUPTION_LOG_FILENAME: &str = "corruption.log";
+
+/// A chunk that failed authentication while reading, logged by [`EncryptedFs::read`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorruptionRecord {
+    pub ino: u64,
+    /// Path from root, if it could still be found.
+    pub path: Option<String>,
+    pub chunk: u64,
+    /// Offset of the chunk in the plaintext.
+    pub offset: u64,
+    pub time: SystemTime,
+}
+
+/// One record per line, tab separated, so it's easy to parse.
+impl Display for CorruptionRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\tchunk={}\toffset={}",
+            self.ino,
+            self.path.as_deref().unwrap_or("-"),
+            self.chunk,
+            self.offset
+        )
+    }
+}
+
+impl EncryptedFs {
+    /// Chunks that failed authentication while reading, oldest first. Each chunk is logged once.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn corruption_log(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<Vec<CorruptionRecord>> {
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        fs.read_corruption_log().await
+    }
+
+    /// Forget the chunks logged, after the files were restored or removed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn clear_corruption_log(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        // read-only so we don't change anything else, only the password is checked
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        let path = fs.corruption_log_path();
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub(super) async fn open_read_only(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<Arc<Self>> {
+        Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_read_only(true),
+            None,
+            false,
+        )
+        .await
+    }
+
+    fn corruption_log_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(CORRUPTION_LOG_FILENAME),
+            StorageLayout::Flat => self.object_path("corruption-log"),
+        }
+    }
+
+    async fn read_corruption_log(&self) -> FsResult<Vec<CorruptionRecord>> {
+        let path = self.corruption_log_path();
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        self.deserialize_from_file(&path).await
+    }
+
+    /// Find the chunk that failed in a read of `len` bytes from `offset` and log it. It doesn't fail, it's
+    /// called while already failing.
+    pub(super) async fn log_corruption(&self, ino: u64, offset: u64, len: usize) {
+        if let Err(err) = self.try_log_corruption(ino, offset, len).await {
+            error!(err = %err, ino, "can't log corruption");
+        }
+    }
+
+    async fn try_log_corruption(&self, ino: u64, offset: u64, len: usize) -> FsResult<()> {
+        let Some(chunk) = self.find_bad_chunk(ino, offset, len).await? else {
+            return Ok(());
+        };
+        let offset = chunk * BLOCK_SIZE as u64;
+        error!(ino, chunk, offset, "chunk failed authentication");
+        if self.options.read_only {
+            warn!("read-only, corruption is not logged");
+            return Ok(());
+        }
+        let _guard = self.corruption_log_lock.lock().await;
+        let mut log = self.read_corruption_log().await?;
+        if log.iter().any(|r| r.ino == ino && r.chunk == chunk) {
+            return Ok(());
+        }
+        log.push(CorruptionRecord {
+            ino,
+            path: self.path_of(ino).await,
+            chunk,
+            offset,
+            time: SystemTime::now(),
+        });
+        crypto::atomic_serialize_encrypt_into(
+            &self.corruption_log_path(),
+            &log,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    /// Decrypt each chunk in the range by itself until one fails.
+    async fn find_bad_chunk(&self, ino: u64, offset: u64, len: usize) -> FsResult<Option<u64>> {
+        let key = self.file_key(ino).await?;
+        let block_size = BLOCK_SIZE as u64;
+        for chunk in offset / block_size..=(offset + len as u64).saturating_sub(1) / block_size {
+            let mut reader =
+                crypto::create_read_seek(File::open(self.contents_path(ino))?, self.cipher, &key);
+            if reader.seek(SeekFrom::Start(chunk * block_size)).is_err()
+                || reader.read(&mut [0_u8; 1]).is_err()
+            {
+                return Ok(Some(chunk));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Path from root of the file, found by walking the tree, as files don't know their parent.
+    pub(super) async fn path_of(&self, ino: u64) -> Option<String> {
+        let mut parents = HashMap::new();
+        let mut queue = VecDeque::from([ROOT_INODE]);
+        while let Some(dir) = queue.pop_front() {
+            let Ok(entries) = self.raw_dir_entries(dir).await else {
+                continue;
+            };
+            for entry in entries {
+                let Ok(entry) = self.create_directory_entry(entry).await else {
+                    continue;
+                };
+                let name = entry.name.expose_secret();
+                if name == "." || name == ".." || parents.contains_key(&entry.ino) {
+                    continue;
+                }
+                parents.insert(entry.ino, (dir, name.clone()));
+                if entry.ino == ino {
+                    let mut names = vec![];
+                    let mut current = ino;
+                    while let Some((parent, name)) = parents.get(&current) {
+                        names.push(name.as_str());
+                        current = *parent;
+                    }
+                    names.reverse();
+                    return Some(format!("/{}", names.join("/")));
+                }
+                if entry.kind == FileType::Directory {
+                    queue.push_back(entry.ino);
+                }
+            }
+        }
+        None
+    }
+}
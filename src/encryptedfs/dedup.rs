@@ -0,0 +1,395 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use secrecy::SecretVec;
+use serde::{Deserialize, Serialize};
+use tokio::sync::MutexGuard;
+use tracing::{debug, info};
+
+use crate::crypto::read::{CryptoRead, CryptoReadSeek};
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, LockedVec};
+use crate::encryptedfs::{EncryptedFs, FsResult};
+use crate::{crypto, fs_util, stream_util};
+
+/// Where deduplicated content is kept, see [`FsOptions::dedup`](crate::encryptedfs::FsOptions::dedup).
+pub(super) const DEDUP_DIR: &str = "dedup";
+/// Each chunk is a block of content, named by its id in hex.
+pub(super) const CHUNKS_DIR: &str = "chunks";
+/// The chunks of each deduplicated file, named by its inode.
+const MANIFESTS_DIR: &str = "manifests";
+/// First byte of the plaintext of a chunk, it tells how the rest is stored, so chunks written with and without
+/// [`FsOptions::compression`](crate::encryptedfs::FsOptions::compression) can be mixed.
+const CHUNK_RAW: u8 = 0;
+const CHUNK_ZSTD: u8 = 1;
+/// After this many chunks of a file that didn't compress, and none that did, the rest of it is not compressed.
+const INCOMPRESSIBLE_CHUNKS: usize = 4;
+
+/// Content of a file split into chunks, in order.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    len: u64,
+    chunks: Vec<[u8; 32]>,
+}
+
+/// How many manifests use each chunk.
+pub(super) type ChunkRefs = HashMap<[u8; 32], u64>;
+
+impl EncryptedFs {
+    /// After the content of the file was committed, split it into chunks and store each one once, so blocks that
+    /// are the same in several files take space only once. The content file is then left empty. Without
+    /// [`FsOptions::dedup`](crate::encryptedfs::FsOptions::dedup) or
+    /// [`FsOptions::compression`](crate::encryptedfs::FsOptions::compression) the chunks it had before, if any, are
+    /// dropped.
+    pub(super) async fn dedup_contents(&self, ino: u64) -> FsResult<()> {
+        let chunked = self.options.dedup || self.options.compression.is_some();
+        // the chunks are derived from the master key, and are encrypted
+        if !chunked || self.is_key_rotation_in_progress() || self.is_plaintext(ino).await? {
+            return self.remove_manifest(ino).await;
+        }
+        let path = self.contents_path(ino);
+        if !path.is_file() {
+            return Ok(());
+        }
+        let dedup_key = crypto::derive_dedup_key(&*self.key.get().await?);
+        let mut guard = self.dedup_refs.lock().await;
+        let refs = self.chunk_refs(&mut guard).await?;
+        let old = self.read_manifest(ino).await?;
+
+        let mut manifest = Manifest {
+            len: 0,
+            chunks: vec![],
+        };
+        let mut reader =
+            crypto::create_read(File::open(&path)?, self.cipher, &*self.file_key(ino).await?);
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut level = self.options.compression;
+        let (mut compressed, mut incompressible) = (0, 0);
+        loop {
+            let len = stream_util::read(&mut reader, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            let id = crypto::hash_chunk(&dedup_key, &buf[..len]);
+            let count = refs.entry(id).or_default();
+            if *count == 0 {
+                if self.write_chunk(&dedup_key, &id, &buf[..len], level)? {
+                    compressed += 1;
+                } else if level.is_some() {
+                    incompressible += 1;
+                    if compressed == 0 && incompressible >= INCOMPRESSIBLE_CHUNKS {
+                        debug!(ino, "content doesn't compress, storing it as it is");
+                        level = None;
+                    }
+                }
+            }
+            *count += 1;
+            manifest.len += len as u64;
+            manifest.chunks.push(id);
+        }
+        drop(reader);
+        if manifest.chunks.is_empty() {
+            // nothing to share
+            self.remove_manifest_locked(ino, old, refs)?;
+            return Ok(());
+        }
+        debug!(ino, chunks = manifest.chunks.len(), "deduplicated content");
+
+        let manifest_path = self.manifest_path(ino);
+        fs::create_dir_all(manifest_path.parent().unwrap())?;
+        crypto::atomic_serialize_encrypt_into(
+            &manifest_path,
+            &manifest,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        // the content is in the chunks now
+        self.replace_data_file(&path, || Ok(fs_util::open_atomic_write(&path)?.commit()?))?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        self.content_changed(ino).await?;
+        if let Some(old) = old {
+            self.release_chunks(&old.chunks, refs)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the chunks of the file, if it has any.
+    pub(super) async fn remove_manifest(&self, ino: u64) -> FsResult<()> {
+        if !self.is_deduplicated(ino) {
+            return Ok(());
+        }
+        let mut guard = self.dedup_refs.lock().await;
+        let refs = self.chunk_refs(&mut guard).await?;
+        let old = self.read_manifest(ino).await?;
+        self.remove_manifest_locked(ino, old, refs)
+    }
+
+    fn remove_manifest_locked(
+        &self,
+        ino: u64,
+        old: Option<Manifest>,
+        refs: &mut ChunkRefs,
+    ) -> FsResult<()> {
+        let Some(old) = old else {
+            return Ok(());
+        };
+        self.remove_data_file(&self.manifest_path(ino))?;
+        self.release_chunks(&old.chunks, refs)
+    }
+
+    /// Remove the ones no other file uses.
+    fn release_chunks(&self, chunks: &[[u8; 32]], refs: &mut ChunkRefs) -> FsResult<()> {
+        for id in chunks {
+            let Some(count) = refs.get_mut(id) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                refs.remove(id);
+                let path = self.chunk_path(id);
+                if path.is_file() {
+                    self.remove_data_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns if it was compressed, it's not when that doesn't save at least an eighth of it.
+    fn write_chunk(
+        &self,
+        dedup_key: &SecretVec<u8>,
+        id: &[u8; 32],
+        data: &[u8],
+        level: Option<i32>,
+    ) -> FsResult<bool> {
+        let compressed = match level {
+            Some(level) => Some(zstd::bulk::compress(data, level)?)
+                .filter(|compressed| compressed.len() < data.len() - data.len() / 8),
+            None => None,
+        };
+        let path = self.chunk_path(id);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let file = fs_util::open_atomic_write(&path)?;
+        let mut writer = crypto::create_write(
+            file,
+            self.cipher,
+            &crypto::derive_chunk_key(dedup_key, id, self.cipher),
+        );
+        if let Some(compressed) = &compressed {
+            writer.write_all(&[CHUNK_ZSTD])?;
+            writer.write_all(compressed)?;
+        } else {
+            writer.write_all(&[CHUNK_RAW])?;
+            writer.write_all(data)?;
+        }
+        writer.finish()?.commit()?;
+        Ok(compressed.is_some())
+    }
+
+    /// Reader of the plaintext content of the file, from its chunks if it was deduplicated, or from the base if it
+    /// wasn't written in the volume yet.
+    pub(super) async fn content_reader(&self, ino: u64) -> FsResult<Box<dyn CryptoReadSeek<File>>> {
+        if let Some(reader) = self.base_content_reader(ino).await? {
+            return Ok(reader);
+        }
+        if let Some(reader) = self.plaintext_reader(ino).await? {
+            return Ok(reader);
+        }
+        if !self.is_deduplicated(ino) {
+            return Ok(Box::new(crypto::create_read_seek(
+                File::open(self.contents_path(ino))?,
+                self.cipher,
+                &*self.file_key(ino).await?,
+            )));
+        }
+        let manifest_path = self.manifest_path(ino);
+        let manifest: Manifest = self.deserialize_from_file(&manifest_path).await?;
+        Ok(Box::new(ChunkReader {
+            manifest: Some(File::open(manifest_path)?),
+            chunks: manifest.chunks,
+            len: manifest.len,
+            dir: self.data_dir.join(DEDUP_DIR).join(CHUNKS_DIR),
+            dedup_key: crypto::derive_dedup_key(&*self.key.get().await?),
+            cipher: self.cipher,
+            pos: 0,
+            block: None,
+        }))
+    }
+
+    /// If the content of the file is in chunks, the content file is empty then.
+    pub(super) fn is_deduplicated(&self, ino: u64) -> bool {
+        self.manifest_path(ino).is_file()
+    }
+
+    /// If any file was deduplicated. Changing the master key or the cipher is not supported then.
+    pub(super) fn has_deduplicated_content(data_dir: &Path) -> FsResult<bool> {
+        let dir = data_dir.join(DEDUP_DIR).join(MANIFESTS_DIR);
+        Ok(dir.is_dir() && fs::read_dir(dir)?.next().is_some())
+    }
+
+    /// Counted from the manifests the first time they're needed. Chunks no manifest uses are left by a crash,
+    /// they are removed then.
+    async fn chunk_refs<'a>(
+        &self,
+        guard: &'a mut MutexGuard<'_, Option<ChunkRefs>>,
+    ) -> FsResult<&'a mut ChunkRefs> {
+        if guard.is_none() {
+            let mut refs = ChunkRefs::new();
+            let dir = self.data_dir.join(DEDUP_DIR).join(MANIFESTS_DIR);
+            if dir.is_dir() {
+                for entry in fs::read_dir(dir)? {
+                    // skip temp files of atomic writes
+                    let Ok(ino) = entry?.file_name().to_string_lossy().parse::<u64>() else {
+                        continue;
+                    };
+                    if let Some(manifest) = self.read_manifest(ino).await? {
+                        for id in manifest.chunks {
+                            *refs.entry(id).or_default() += 1;
+                        }
+                    }
+                }
+            }
+            self.remove_unused_chunks(&refs)?;
+            guard.replace(refs);
+        }
+        Ok(guard.as_mut().unwrap())
+    }
+
+    fn remove_unused_chunks(&self, refs: &ChunkRefs) -> FsResult<()> {
+        let dir = self.data_dir.join(DEDUP_DIR).join(CHUNKS_DIR);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let used: HashSet<String> = refs.keys().map(hex::encode).collect();
+        let mut removed = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !used.contains(&*entry.file_name().to_string_lossy()) {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            info!(removed, "removed chunks not used by any file");
+        }
+        Ok(())
+    }
+
+    async fn read_manifest(&self, ino: u64) -> FsResult<Option<Manifest>> {
+        let path = self.manifest_path(ino);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(self.deserialize_from_file(&path).await?))
+    }
+
+    fn manifest_path(&self, ino: u64) -> PathBuf {
+        self.data_dir
+            .join(DEDUP_DIR)
+            .join(MANIFESTS_DIR)
+            .join(ino.to_string())
+    }
+
+    fn chunk_path(&self, id: &[u8; 32]) -> PathBuf {
+        self.data_dir
+            .join(DEDUP_DIR)
+            .join(CHUNKS_DIR)
+            .join(hex::encode(id))
+    }
+}
+
+/// Plaintext of a deduplicated file, decrypting its chunks as they are reached.
+struct ChunkReader {
+    /// What the reader was opened on, [`CryptoRead::into_inner`] gives it back.
+    manifest: Option<File>,
+    chunks: Vec<[u8; 32]>,
+    len: u64,
+    dir: PathBuf,
+    dedup_key: LockedVec,
+    cipher: Cipher,
+    pos: u64,
+    /// Index and plaintext of the last chunk read.
+    block: Option<(usize, Vec<u8>)>,
+}
+
+impl ChunkReader {
+    fn load_block(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.block.as_ref().is_none_or(|(i, _)| *i != index) {
+            let id = &self.chunks[index];
+            let mut reader = crypto::create_read(
+                File::open(self.dir.join(hex::encode(id)))?,
+                self.cipher,
+                &crypto::derive_chunk_key(&self.dedup_key, id, self.cipher),
+            );
+            let mut data = vec![];
+            reader.read_to_end(&mut data)?;
+            let data = match data.first() {
+                Some(&CHUNK_RAW) => data.split_off(1),
+                Some(&CHUNK_ZSTD) => zstd::bulk::decompress(&data[1..], BLOCK_SIZE)?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown chunk encoding",
+                    ))
+                }
+            };
+            self.block = Some((index, data));
+        }
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let index = (self.pos / BLOCK_SIZE as u64) as usize;
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = (self.pos % BLOCK_SIZE as u64) as usize;
+        let block = self.load_block(index)?;
+        if offset >= block.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk is shorter than expected",
+            ));
+        }
+        let len = buf.len().min(block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ChunkReader {
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_sign_loss)]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(pos) => self.len as i64 + pos,
+            SeekFrom::Current(pos) => self.pos as i64 + pos,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "new position < 0",
+            ));
+        }
+        // keep in bounds, like the other readers
+        self.pos = (new_pos as u64).min(self.len);
+        Ok(self.pos)
+    }
+}
+
+impl CryptoRead<File> for ChunkReader {
+    fn into_inner(&mut self) -> File {
+        self.manifest.take().unwrap()
+    }
+}
+
+impl CryptoReadSeek<File> for ChunkReader {}
@@ -0,0 +1,62 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::encryptedfs::{FsError, FsResult};
+
+/// In the data dir, it starts with `.` so it's not put in the storage, see
+/// [`FsOptions::storage`](crate::encryptedfs::FsOptions::storage).
+pub(super) const LOCK_FILENAME: &str = ".lock";
+
+/// Held while it's open, see [`FsOptions::lock_data_dir`](crate::encryptedfs::FsOptions::lock_data_dir). The lock is
+/// released by the OS when the file is closed, also when the process dies, so there is nothing to clean up after a
+/// crash.
+pub(super) struct DataDirLock(#[allow(dead_code)] File);
+
+impl DataDirLock {
+    /// Exclusive, or `shared` with other readers. Fails with [`FsError::DataDirInUse`] if it's taken. `None` when
+    /// `shared` and there is no lock file we could create, like on a read-only medium.
+    pub(super) fn acquire(data_dir: &Path, shared: bool) -> FsResult<Option<Self>> {
+        let path = data_dir.join(LOCK_FILENAME);
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(file) => file,
+            // can be locked for read only too
+            Err(err)
+                if shared && matches!(err.raw_os_error(), Some(libc::EACCES | libc::EROFS)) =>
+            {
+                match File::open(&path) {
+                    Ok(file) => file,
+                    Err(_) => return Ok(None),
+                }
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let operation = if shared { libc::LOCK_SH } else { libc::LOCK_EX };
+        // SAFETY: the fd is valid while the file is open
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err.into());
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(FsError::DataDirInUse(pid.trim().parse().ok()));
+        }
+        // for the error of who tries next, with readers it's the last one
+        let _ = file
+            .set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| writeln!(file, "{}", std::process::id()));
+        debug!(shared, "locked data dir");
+        Ok(Some(Self(file)))
+    }
+}
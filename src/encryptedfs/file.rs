@@ -0,0 +1,479 @@
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
+use tokio::runtime::Handle;
+use tokio::task::{JoinError, JoinSet};
+use tracing::error;
+
+use crate::encryptedfs::{EncryptedFs, FileAttr, FileType, FsError, FsResult};
+
+/// Most that's read from the volume at once.
+const MAX_READ: usize = 1024 * 1024;
+
+type Op<T> = Pin<Box<dyn Future<Output = (Handles, io::Result<T>)> + Send>>;
+
+/// One of them is open at a time. Reads from a handle don't see the writes from another one until they are saved, so
+/// the write handle is released before reading.
+#[derive(Clone, Copy, Default)]
+struct Handles {
+    read: Option<u64>,
+    write: Option<u64>,
+}
+
+enum State {
+    Idle(Handles),
+    Reading(Op<Vec<u8>>),
+    Writing(Op<usize>),
+    Flushing(Op<()>),
+    Seeking(Op<u64>),
+}
+
+/// A file in the volume, implementing [`AsyncRead`], [`AsyncWrite`] and [`AsyncSeek`], so it can be streamed without
+/// loading it in memory. See [`BlockingEncryptedFile`] for [`Read`], [`Write`] and [`Seek`].
+///
+/// Writes are saved on [`AsyncWriteExt::flush`] and [`AsyncWriteExt::shutdown`]. Await `shutdown` before dropping it,
+/// it's the only way to know the writes were saved. When it's dropped without it, the operation in progress is finished
+/// and the handles are released in the background, [`EncryptedFs::flush_all`] waits for that and returns the first
+/// error.
+#[allow(clippy::module_name_repetitions)]
+pub struct EncryptedFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    writable: bool,
+    pos: u64,
+    state: State,
+    /// Read from `pos` but not yet returned, when the buffer given was smaller.
+    buffered: Vec<u8>,
+    runtime: Handle,
+}
+
+impl EncryptedFile {
+    /// Open the file at `path` for reading.
+    pub async fn open(fs: Arc<EncryptedFs>, path: &str) -> FsResult<Self> {
+        let attr = fs.resolve_path(path).await?;
+        Self::from_attr(fs, &attr, false)
+    }
+
+    /// Open the file at `path` for reading and writing, it's created if it doesn't exist and truncated if it does.
+    pub async fn create(fs: Arc<EncryptedFs>, path: &str) -> FsResult<Self> {
+        let attr = match fs.resolve_path(path).await {
+            Ok(attr) if attr.kind == FileType::RegularFile => {
+                fs.set_len(attr.ino, 0).await?;
+                attr
+            }
+            Ok(attr) => attr,
+            Err(FsError::NotFound(_)) => fs.create_file_path(path).await?,
+            Err(err) => return Err(err),
+        };
+        Self::from_attr(fs, &attr, true)
+    }
+
+    /// Open the file with inode `ino`, for writing too if `write`. Writes don't truncate it.
+    pub async fn from_ino(fs: Arc<EncryptedFs>, ino: u64, write: bool) -> FsResult<Self> {
+        let attr = fs.get_attr(ino).await?;
+        Self::from_attr(fs, &attr, write)
+    }
+
+    fn from_attr(fs: Arc<EncryptedFs>, attr: &FileAttr, writable: bool) -> FsResult<Self> {
+        if attr.kind != FileType::RegularFile {
+            return Err(FsError::InvalidInodeType);
+        }
+        if writable && fs.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(Self {
+            fs,
+            ino: attr.ino,
+            writable,
+            pos: 0,
+            state: State::Idle(Handles::default()),
+            buffered: vec![],
+            runtime: Handle::current(),
+        })
+    }
+
+    #[must_use]
+    pub const fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Attributes of the file, the size includes writes not yet saved.
+    pub fn metadata(&self) -> impl Future<Output = FsResult<FileAttr>> + Send {
+        let (fs, ino) = (self.fs.clone(), self.ino);
+        async move { fs.get_attr(ino).await }
+    }
+
+    /// Use it with [`Read`], [`Write`] and [`Seek`]. Those can't be called from an async context, they block on the
+    /// runtime the file was opened in.
+    #[must_use]
+    pub const fn into_blocking(self) -> BlockingEncryptedFile {
+        BlockingEncryptedFile(self)
+    }
+
+    /// Wait for the operation in progress, if any, and take the handles.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Handles>> {
+        let (handles, res) = match &mut self.state {
+            State::Idle(handles) => return Poll::Ready(Ok(*handles)),
+            State::Reading(op) => {
+                let (handles, res) = ready!(op.as_mut().poll(cx));
+                (handles, res.map(|data| self.buffered = data))
+            }
+            State::Writing(op) => {
+                let (handles, res) = ready!(op.as_mut().poll(cx));
+                (handles, res.map(|len| self.pos += len as u64))
+            }
+            State::Flushing(op) => ready!(op.as_mut().poll(cx)),
+            State::Seeking(op) => {
+                let (handles, res) = ready!(op.as_mut().poll(cx));
+                (handles, res.map(|pos| self.pos = pos))
+            }
+        };
+        self.state = State::Idle(handles);
+        Poll::Ready(res.map(|()| handles))
+    }
+
+    fn read_op(&self, handles: Handles, len: usize) -> Op<Vec<u8>> {
+        let (fs, ino, pos) = (self.fs.clone(), self.ino, self.pos);
+        Box::pin(async move {
+            let mut handles = handles;
+            let res = async {
+                if let Some(handle) = handles.write.take() {
+                    fs.release(handle).await?;
+                }
+                let handle = match handles.read {
+                    Some(handle) => handle,
+                    None => *handles.read.insert(fs.open(ino, true, false).await?),
+                };
+                let mut buf = vec![0; len.min(MAX_READ)];
+                let len = fs.read(ino, pos, &mut buf, handle).await?;
+                buf.truncate(len);
+                Ok::<_, FsError>(buf)
+            }
+            .await;
+            (handles, res.map_err(io::Error::from))
+        })
+    }
+
+    fn write_op(&self, handles: Handles, data: Vec<u8>) -> Op<usize> {
+        let (fs, ino, pos) = (self.fs.clone(), self.ino, self.pos);
+        Box::pin(async move {
+            let mut handles = handles;
+            let res = async {
+                if let Some(handle) = handles.read.take() {
+                    fs.release(handle).await?;
+                }
+                let handle = match handles.write {
+                    Some(handle) => handle,
+                    None => *handles.write.insert(fs.open(ino, false, true).await?),
+                };
+                fs.write(ino, pos, &data, handle).await
+            }
+            .await;
+            (handles, res.map_err(io::Error::from))
+        })
+    }
+
+    /// Save the writes, and release the read handle too if `close`.
+    fn flush_op(&self, handles: Handles, close: bool) -> Op<()> {
+        let fs = self.fs.clone();
+        Box::pin(async move {
+            let mut handles = handles;
+            let res = async {
+                if let Some(handle) = handles.write.take() {
+                    fs.release(handle).await?;
+                }
+                if close {
+                    if let Some(handle) = handles.read.take() {
+                        fs.release(handle).await?;
+                    }
+                }
+                Ok::<_, FsError>(())
+            }
+            .await;
+            (handles, res.map_err(io::Error::from))
+        })
+    }
+}
+
+impl AsyncRead for EncryptedFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.buffered.is_empty() {
+                let len = this.buffered.len().min(buf.remaining());
+                buf.put_slice(&this.buffered[..len]);
+                this.buffered.drain(..len);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+            let reading = matches!(this.state, State::Reading(_));
+            let handles = ready!(this.poll_idle(cx))?;
+            if reading {
+                // what was read is in the buffer now, it's empty at the end of the file
+                if this.buffered.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            this.state = State::Reading(this.read_op(handles, buf.remaining()));
+        }
+    }
+}
+
+impl AsyncWrite for EncryptedFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.writable {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for writing",
+            )));
+        }
+        loop {
+            if let State::Writing(op) = &mut this.state {
+                let (handles, res) = ready!(op.as_mut().poll(cx));
+                this.state = State::Idle(handles);
+                let len = res?;
+                this.pos += len as u64;
+                return Poll::Ready(Ok(len));
+            }
+            let handles = ready!(this.poll_idle(cx))?;
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            // what was read ahead is stale after this
+            this.buffered.clear();
+            this.state = State::Writing(this.write_op(handles, buf.to_vec()));
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let handles = ready!(this.poll_idle(cx))?;
+        if handles.write.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        this.state = State::Flushing(this.flush_op(handles, false));
+        this.poll_idle(cx).map_ok(|_| ())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let handles = ready!(this.poll_idle(cx))?;
+        if handles.read.is_none() && handles.write.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        this.state = State::Flushing(this.flush_op(handles, true));
+        this.poll_idle(cx).map_ok(|_| ())
+    }
+}
+
+impl AsyncSeek for EncryptedFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let State::Idle(handles) = this.state else {
+            return Err(io::Error::other("another operation is in progress"));
+        };
+        let pos = this.pos;
+        let pos = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let (fs, ino) = (this.fs.clone(), this.ino);
+                this.buffered.clear();
+                this.state = State::Seeking(Box::pin(async move {
+                    let res = fs
+                        .get_attr(ino)
+                        .await
+                        .map_err(io::Error::from)
+                        .and_then(|attr| {
+                            attr.size
+                                .checked_add_signed(offset)
+                                .ok_or_else(invalid_seek)
+                        });
+                    (handles, res)
+                }));
+                return Ok(());
+            }
+        };
+        this.pos = pos.ok_or_else(invalid_seek)?;
+        this.buffered.clear();
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(this.poll_idle(cx))?;
+        Poll::Ready(Ok(this.pos))
+    }
+}
+
+impl Drop for EncryptedFile {
+    fn drop(&mut self) {
+        let state = mem::replace(&mut self.state, State::Idle(Handles::default()));
+        if matches!(
+            state,
+            State::Idle(Handles {
+                read: None,
+                write: None
+            })
+        ) {
+            return;
+        }
+        let fs = self.fs.clone();
+        let release = async move {
+            // the operation in progress has the handles
+            let (handles, res) = match state {
+                State::Idle(handles) => (handles, Ok(())),
+                State::Reading(op) => finish(op).await,
+                State::Writing(op) => finish(op).await,
+                State::Flushing(op) => finish(op).await,
+                State::Seeking(op) => finish(op).await,
+            };
+            for handle in [handles.write, handles.read].into_iter().flatten() {
+                fs.release(handle).await?;
+            }
+            res.map_err(FsError::from)
+        };
+        self.fs
+            .releases
+            .lock()
+            .expect("releases lock is poisoned")
+            .spawn(release, &self.runtime);
+    }
+}
+
+async fn finish<T>(op: Op<T>) -> (Handles, io::Result<()>) {
+    let (handles, res) = op.await;
+    (handles, res.map(|_| ()))
+}
+
+/// Releases of the [`EncryptedFile`]s dropped while open, see [`EncryptedFs::flush_all`].
+#[derive(Default)]
+pub(super) struct Releases {
+    tasks: JoinSet<FsResult<()>>,
+    /// The first one that failed since they were last waited for.
+    failed: Option<FsError>,
+}
+
+impl Releases {
+    fn spawn(
+        &mut self,
+        release: impl Future<Output = FsResult<()>> + Send + 'static,
+        runtime: &Handle,
+    ) {
+        while let Some(res) = self.tasks.try_join_next() {
+            self.done(res);
+        }
+        self.tasks.spawn_on(release, runtime);
+    }
+
+    fn done(&mut self, res: Result<FsResult<()>, JoinError>) {
+        if let Err(err) = res.map_err(FsError::from).and_then(|res| res) {
+            error!(err = %err, "releasing file");
+            self.failed.get_or_insert(err);
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Wait for the [`EncryptedFile`]s dropped while open to be released, returns the first error since last time.
+    pub(super) async fn wait_releases(&self) -> FsResult<()> {
+        let mut tasks = mem::take(
+            &mut self
+                .releases
+                .lock()
+                .expect("releases lock is poisoned")
+                .tasks,
+        );
+        while let Some(res) = tasks.join_next().await {
+            self.releases
+                .lock()
+                .expect("releases lock is poisoned")
+                .done(res);
+        }
+        self.releases
+            .lock()
+            .expect("releases lock is poisoned")
+            .failed
+            .take()
+            .map_or(Ok(()), Err)
+    }
+}
+
+/// [`EncryptedFile`] implementing [`Read`], [`Write`] and [`Seek`], see [`EncryptedFile::into_blocking`]. Writes are
+/// saved on [`Write::flush`].
+#[allow(clippy::module_name_repetitions)]
+pub struct BlockingEncryptedFile(EncryptedFile);
+
+impl BlockingEncryptedFile {
+    #[must_use]
+    pub fn into_inner(self) -> EncryptedFile {
+        self.0
+    }
+}
+
+impl Read for BlockingEncryptedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let runtime = self.0.runtime.clone();
+        runtime.block_on(self.0.read(buf))
+    }
+}
+
+impl Write for BlockingEncryptedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let runtime = self.0.runtime.clone();
+        runtime.block_on(self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let runtime = self.0.runtime.clone();
+        runtime.block_on(self.0.flush())
+    }
+}
+
+impl Seek for BlockingEncryptedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let runtime = self.0.runtime.clone();
+        runtime.block_on(self.0.seek(pos))
+    }
+}
+
+fn invalid_seek() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "invalid seek to a negative or overflowing position",
+    )
+}
+
+impl From<FsError> for io::Error {
+    fn from(err: FsError) -> Self {
+        let kind = match err {
+            FsError::Io { source } => return source,
+            FsError::NotFound(_) | FsError::InodeNotFound => io::ErrorKind::NotFound,
+            FsError::AlreadyExists => io::ErrorKind::AlreadyExists,
+            FsError::InvalidInput(_) | FsError::InvalidInodeType => io::ErrorKind::InvalidInput,
+            FsError::ReadOnly | FsError::Excluded => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        Self::new(kind, err)
+    }
+}
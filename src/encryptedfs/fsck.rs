@@ -0,0 +1,192 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use secrecy::ExposeSecret;
+use tracing::{debug, info};
+
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    EncryptedFs, FileType, FsOptions, FsResult, StaticPasswordProvider, StorageLayout,
+    CONTENTS_DIR, INODES_DIR, ROOT_INODE,
+};
+
+/// Something wrong found by [`EncryptedFs::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckIssue {
+    /// The metadata of the inode can't be decrypted or authenticated.
+    BadInode { ino: u64 },
+    /// The content of the file can't be decrypted or one of its chunks fails authentication.
+    BadContent { ino: u64 },
+    /// An entry in the directory can't be read, or it points to an inode that doesn't exist, then `ino` is set.
+    DanglingEntry { parent: u64, ino: Option<u64> },
+    /// The entry is listed in the directory but it can't be found by name.
+    MissingHashEntry { parent: u64, ino: u64 },
+    /// The inode or content is not reachable from root.
+    Orphan { ino: u64 },
+}
+
+/// One issue per line, tab separated, so it's easy to parse.
+impl Display for CheckIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadInode { ino } => write!(f, "bad-inode\t{ino}"),
+            Self::BadContent { ino } => write!(f, "bad-content\t{ino}"),
+            Self::DanglingEntry { parent, ino } => write!(
+                f,
+                "dangling-entry\t{}\tparent={parent}",
+                ino.map_or_else(|| "-".to_string(), |ino| ino.to_string())
+            ),
+            Self::MissingHashEntry { parent, ino } => {
+                write!(f, "missing-hash-entry\t{ino}\tparent={parent}")
+            }
+            Self::Orphan { ino } => write!(f, "orphan\t{ino}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub dirs: u64,
+    pub files: u64,
+    pub issues: Vec<CheckIssue>,
+}
+
+impl CheckReport {
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl EncryptedFs {
+    /// Check the data dir without changing anything, like `fsck`. The password must open the master key, then all
+    /// that's reachable from root is decrypted and authenticated, including each chunk of the files' content.
+    ///
+    /// Orphans are only detected with [`StorageLayout::Hierarchical`], in [`StorageLayout::Flat`] objects that
+    /// are not ours can't be told apart from the ones of a hidden volume or chaff.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn check(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<CheckReport> {
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_read_only(true),
+            None,
+            false,
+        )
+        .await?;
+        info!("checking data dir");
+        let mut report = CheckReport::default();
+        let visited = fs.check_tree(&mut report).await?;
+        if fs.layout == StorageLayout::Hierarchical {
+            fs.check_orphans(&visited, &mut report)?;
+        }
+        info!(issues = report.issues.len(), "check finished");
+        Ok(report)
+    }
+
+    /// Walk from root and return the inodes found.
+    async fn check_tree(&self, report: &mut CheckReport) -> FsResult<HashSet<u64>> {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([ROOT_INODE]);
+        while let Some(ino) = queue.pop_front() {
+            if !visited.insert(ino) {
+                continue;
+            }
+            debug!(ino, "checking");
+            let Ok(attr) = self.get_inode_from_storage(ino).await else {
+                report.issues.push(CheckIssue::BadInode { ino });
+                continue;
+            };
+            if attr.kind == FileType::Directory {
+                report.dirs += 1;
+                let Ok(entries) = self.raw_dir_entries(ino).await else {
+                    report.issues.push(CheckIssue::BadInode { ino });
+                    continue;
+                };
+                for entry in entries {
+                    let Ok(entry) = self.create_directory_entry(entry).await else {
+                        report.issues.push(CheckIssue::DanglingEntry {
+                            parent: ino,
+                            ino: None,
+                        });
+                        continue;
+                    };
+                    if entry.name.expose_secret() == "." || entry.name.expose_secret() == ".." {
+                        continue;
+                    }
+                    if !self.ino_file(entry.ino).is_file() {
+                        report.issues.push(CheckIssue::DanglingEntry {
+                            parent: ino,
+                            ino: Some(entry.ino),
+                        });
+                        // what's left of it is not an orphan
+                        visited.insert(entry.ino);
+                        continue;
+                    }
+                    if !matches!(self.find_by_name(ino, &entry.name).await, Ok(Some(_))) {
+                        report.issues.push(CheckIssue::MissingHashEntry {
+                            parent: ino,
+                            ino: entry.ino,
+                        });
+                    }
+                    queue.push_back(entry.ino);
+                }
+            } else {
+                report.files += 1;
+                if !self.check_content(ino, attr.size).await {
+                    report.issues.push(CheckIssue::BadContent { ino });
+                }
+            }
+        }
+        Ok(visited)
+    }
+
+    /// Read the whole content, which authenticates each chunk.
+    async fn check_content(&self, ino: u64, size: u64) -> bool {
+        let path = self.contents_path(ino);
+        if !path.is_file() {
+            return size == 0;
+        }
+        // from the chunks, if it was deduplicated
+        let Ok(mut reader) = self.content_reader(ino).await else {
+            return false;
+        };
+        io::copy(&mut reader, &mut io::sink()).is_ok()
+    }
+
+    fn check_orphans(&self, visited: &HashSet<u64>, report: &mut CheckReport) -> FsResult<()> {
+        report.issues.extend(
+            self.orphans(visited)?
+                .into_iter()
+                .map(|ino| CheckIssue::Orphan { ino }),
+        );
+        Ok(())
+    }
+
+    /// Inodes with metadata or content in the data dir that are not in `visited`, sorted. Only for
+    /// [`StorageLayout::Hierarchical`].
+    pub(super) fn orphans(&self, visited: &HashSet<u64>) -> FsResult<Vec<u64>> {
+        let mut orphans = HashSet::new();
+        for dir in [INODES_DIR, CONTENTS_DIR] {
+            for entry in fs::read_dir(self.data_dir.join(dir))? {
+                let name = entry?.file_name();
+                if let Some(ino) = name.to_str().and_then(|name| name.parse::<u64>().ok()) {
+                    if !visited.contains(&ino) {
+                        orphans.insert(ino);
+                    }
+                }
+            }
+        }
+        let mut orphans: Vec<_> = orphans.into_iter().collect();
+        orphans.sort_unstable();
+        Ok(orphans)
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{Cipher, KdfParams};
+use crate::encryptedfs::{check_structure, EncryptedFs, FsError, FsResult, SECURITY_DIR};
+use crate::fs_util;
+
+/// In [`SECURITY_DIR`], not encrypted, so it can be read before unlocking.
+pub(super) const HEADER_FILENAME: &str = "volume.header";
+
+/// How the data dir was created, written once by `init`. Data dirs created before it was added don't have one, they
+/// use the defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeHeader {
+    /// Changed by [`EncryptedFs::migrate_cipher`].
+    pub cipher: Cipher,
+    pub kdf: KdfParams,
+    /// Bytes of plaintext in each encrypted block of the content, always [`BLOCK_SIZE`] for now.
+    pub chunk_size: u64,
+    /// Seconds since the epoch.
+    pub created: u64,
+}
+
+impl VolumeHeader {
+    pub(super) fn new(cipher: Cipher, kdf: KdfParams) -> Self {
+        Self {
+            cipher,
+            kdf,
+            chunk_size: BLOCK_SIZE as u64,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Header of the data dir, [`None`] if it was created before there was one. It doesn't need the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn header(data_dir: &Path) -> FsResult<Option<VolumeHeader>> {
+        check_structure(data_dir, false).await?;
+        read_header(&data_dir.join(SECURITY_DIR))
+    }
+}
+
+pub(super) fn read_header(security_dir: &Path) -> FsResult<Option<VolumeHeader>> {
+    let path = security_dir.join(HEADER_FILENAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize_from(File::open(path)?)?))
+}
+
+pub(super) fn write_header(security_dir: &Path, header: &VolumeHeader) -> FsResult<()> {
+    let mut file = fs_util::open_atomic_write(&security_dir.join(HEADER_FILENAME))?;
+    bincode::serialize_into(&mut file, header)?;
+    file.commit()?;
+    File::open(security_dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Cost of deriving the key from the password in the data dir, the default one if it has no header.
+pub(super) fn kdf_params(security_dir: &Path) -> FsResult<KdfParams> {
+    Ok(read_header(security_dir)?.map_or_else(KdfParams::default, |header| header.kdf))
+}
+
+/// The cipher the data dir was created with, `cipher` if it has no header. The one given for a data dir that has it
+/// is ignored, it couldn't decrypt it anyway.
+pub(super) fn volume_cipher(security_dir: &Path, cipher: Cipher) -> FsResult<Cipher> {
+    Ok(read_header(security_dir)?.map_or(cipher, |header| header.cipher))
+}
+
+/// Refuse data dirs written with a chunk size we can't read.
+pub(super) fn check_chunk_size(security_dir: &Path) -> FsResult<()> {
+    match read_header(security_dir)? {
+        Some(header) if header.chunk_size != BLOCK_SIZE as u64 => Err(FsError::InvalidInput(
+            "the data dir was created with another chunk size",
+        )),
+        _ => Ok(()),
+    }
+}
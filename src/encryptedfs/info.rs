@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::Path;
+
+use crate::encryptedfs::header::{read_header, VolumeHeader};
+use crate::encryptedfs::key_slots::read_key_slots;
+use crate::encryptedfs::upgrade::read_format_version;
+use crate::encryptedfs::{
+    check_structure, EncryptedFs, FileType, FsError, FsResult, StorageLayout, CONTENTS_DIR,
+    ROOT_INODE, SECURITY_DIR,
+};
+
+/// What's known about a data dir without the password, see [`EncryptedFs::info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeInfo {
+    /// [`None`] if it was created before there was one, with the defaults.
+    pub header: Option<VolumeHeader>,
+    /// [`None`] if it was created before the format was versioned.
+    pub format_version: Option<u32>,
+    pub layout: StorageLayout,
+    /// Files and dirs, without root, counted from what's in the data dir. [`None`] for [`StorageLayout::Flat`],
+    /// which hides them among random objects.
+    pub files: Option<u64>,
+    pub dirs: Option<u64>,
+    /// Bytes of everything in the data dir, encrypted, so a bit more than what's in the files.
+    pub stored_size: u64,
+    pub key_slots: usize,
+}
+
+/// What's only known unlocked, see [`EncryptedFs::usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolumeUsage {
+    pub files: u64,
+    pub dirs: u64,
+    /// Sum of the sizes of the files, from their metadata.
+    pub size: u64,
+}
+
+impl EncryptedFs {
+    /// What can be told about the data dir from what's not encrypted in it, it doesn't need the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn info(data_dir: &Path) -> FsResult<VolumeInfo> {
+        check_structure(data_dir, false).await?;
+        let layout = StorageLayout::detect(data_dir).ok_or(FsError::InvalidDataDirStructure)?;
+        let (files, dirs) = match layout {
+            // the content of each inode is named after it, a dir for dirs
+            StorageLayout::Hierarchical => {
+                let (mut files, mut dirs) = (0, 0);
+                for entry in fs::read_dir(data_dir.join(CONTENTS_DIR))? {
+                    let entry = entry?;
+                    match entry.file_name().to_string_lossy().parse::<u64>() {
+                        Ok(ino) if ino != ROOT_INODE => {}
+                        _ => continue,
+                    }
+                    if entry.file_type()?.is_dir() {
+                        dirs += 1;
+                    } else {
+                        files += 1;
+                    }
+                }
+                (Some(files), Some(dirs))
+            }
+            StorageLayout::Flat => (None, None),
+        };
+        Ok(VolumeInfo {
+            header: read_header(&data_dir.join(SECURITY_DIR))?,
+            format_version: read_format_version(data_dir)?,
+            layout,
+            files,
+            dirs,
+            stored_size: dir_size(data_dir)?,
+            key_slots: read_key_slots(&data_dir.join(SECURITY_DIR))?.len(),
+        })
+    }
+
+    /// Count the files and dirs and add up the sizes of the files, from their metadata. Root is not counted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn usage(&self) -> FsResult<VolumeUsage> {
+        let mut usage = VolumeUsage::default();
+        for ino in self.walk_tree().await?.into_iter().skip(1) {
+            let attr = self.get_inode_from_storage(ino).await?;
+            match attr.kind {
+                FileType::Directory => usage.dirs += 1,
+                FileType::RegularFile => {
+                    usage.files += 1;
+                    usage.size += attr.size;
+                }
+            }
+        }
+        Ok(usage)
+    }
+}
+
+fn dir_size(path: &Path) -> FsResult<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
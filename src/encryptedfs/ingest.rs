@@ -0,0 +1,202 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use secrecy::SecretString;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileType, FsError, FsOptions, FsResult, StaticPasswordProvider,
+    ROOT_INODE,
+};
+
+#[derive(Debug, Default, Clone)]
+pub struct ImportReport {
+    pub dirs: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// Content of all files to import, known after the tree was walked.
+    pub total_files: u64,
+    pub total_bytes: u64,
+    /// Symlinks and special files, they are not imported.
+    pub skipped: u64,
+    /// Files whose content read back doesn't match the source, only when verified.
+    pub mismatched: Vec<PathBuf>,
+}
+
+/// A file to import in `parent`.
+struct ImportJob {
+    src: PathBuf,
+    parent: u64,
+    name: String,
+    metadata: fs::Metadata,
+}
+
+impl EncryptedFs {
+    /// Encrypt the plaintext tree in `src` into root of the volume in `data_dir`, which is created if it doesn't exist
+    /// yet. Dirs are created first, then files are copied by `workers` in parallel. `progress` is called after each
+    /// file.
+    ///
+    /// With `verify` the content of each file is read back and compared with the source, the ones that don't match
+    /// are in [`ImportReport::mismatched`]. Names already in root fail with [`FsError::AlreadyExists`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn import(
+        src: &Path,
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        workers: usize,
+        verify: bool,
+        progress: &(dyn Fn(&ImportReport) + Sync),
+    ) -> FsResult<ImportReport> {
+        if !src.is_dir() {
+            return Err(FsError::InvalidInput("source is not a directory"));
+        }
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_create(true),
+            None,
+            false,
+        )
+        .await?;
+        info!(src = %src.display(), "importing directory");
+        let mut report = ImportReport::default();
+        let mut dirs = vec![];
+        let jobs = fs.import_dirs(src, &mut dirs, &mut report).await?;
+        report.total_files = jobs.len() as u64;
+        report.total_bytes = jobs.iter().map(|job| job.metadata.len()).sum();
+        progress(&report);
+
+        let mut set = JoinSet::new();
+        let mut jobs = jobs.into_iter();
+        loop {
+            while set.len() < workers.max(1) {
+                let Some(job) = jobs.next() else {
+                    break;
+                };
+                let fs = fs.clone();
+                set.spawn(async move {
+                    let matches = fs.import_file(&job, verify).await?;
+                    Ok::<_, FsError>((job, matches))
+                });
+            }
+            let Some(res) = set.join_next().await else {
+                break;
+            };
+            let (job, matches) = res??;
+            report.files += 1;
+            report.bytes += job.metadata.len();
+            if !matches {
+                warn!(src = %job.src.display(), "content doesn't match the source");
+                report.mismatched.push(job.src);
+            }
+            progress(&report);
+        }
+        // creating entries in them changes their times
+        for (ino, metadata) in dirs.iter().rev() {
+            fs.import_metadata(*ino, metadata).await?;
+        }
+        info!(
+            files = report.files,
+            skipped = report.skipped,
+            "import finished"
+        );
+        Ok(report)
+    }
+
+    /// Create all dirs, returns the files to copy in them.
+    async fn import_dirs(
+        &self,
+        src: &Path,
+        dirs: &mut Vec<(u64, fs::Metadata)>,
+        report: &mut ImportReport,
+    ) -> FsResult<Vec<ImportJob>> {
+        let mut jobs = vec![];
+        let mut stack = vec![(src.to_path_buf(), ROOT_INODE)];
+        while let Some((path, ino)) = stack.pop() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let metadata = fs::symlink_metadata(entry.path())?;
+                let Some(name) = entry.file_name().to_str().map(ToString::to_string) else {
+                    warn!(path = %entry.path().display(), "skipping name that is not UTF-8");
+                    report.skipped += 1;
+                    continue;
+                };
+                if metadata.is_dir() {
+                    let (_, attr) = self
+                        .create(
+                            ino,
+                            &SecretString::from(name),
+                            import_attr(FileType::Directory, &metadata),
+                            false,
+                            false,
+                        )
+                        .await?;
+                    dirs.push((attr.ino, metadata));
+                    stack.push((entry.path(), attr.ino));
+                    report.dirs += 1;
+                } else if metadata.is_file() {
+                    jobs.push(ImportJob {
+                        src: entry.path(),
+                        parent: ino,
+                        name,
+                        metadata,
+                    });
+                } else {
+                    warn!(path = %entry.path().display(), "skipping symlink or special file");
+                    report.skipped += 1;
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Returns if the content read back matches the source, always `true` without `verify`.
+    async fn import_file(&self, job: &ImportJob, verify: bool) -> FsResult<bool> {
+        let (fh, attr) = self
+            .create(
+                job.parent,
+                &SecretString::from(job.name.clone()),
+                import_attr(FileType::RegularFile, &job.metadata),
+                false,
+                true,
+            )
+            .await?;
+        let size = job.metadata.len();
+        self.restore_content(attr.ino, fh, size, &mut File::open(&job.src)?)
+            .await?;
+        self.import_metadata(attr.ino, &job.metadata).await?;
+        if !verify || size == 0 {
+            return Ok(true);
+        }
+        let mut reader = self.content_reader(attr.ino).await?.take(size);
+        let imported = crypto::hash_reader(&mut reader)?;
+        Ok(imported == crypto::hash_reader(&mut File::open(&job.src)?)?)
+    }
+
+    async fn import_metadata(&self, ino: u64, metadata: &fs::Metadata) -> FsResult<()> {
+        let mut attr = self.get_attr(ino).await?;
+        attr.mtime = metadata.modified()?;
+        attr.atime = metadata.accessed()?;
+        attr.crtime = metadata.created().unwrap_or(attr.mtime);
+        self.restore_times(ino, &attr).await
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn import_attr(kind: FileType, metadata: &fs::Metadata) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: (metadata.mode() & 0o7777) as u16,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: 0,
+        flags: 0,
+    }
+}
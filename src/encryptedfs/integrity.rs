@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    EncryptedFs, FsError, FsOptions, FsResult, StaticPasswordProvider, StorageLayout, SECURITY_DIR,
+};
+
+/// The [`ContentTree`] in [`StorageLayout::Hierarchical`].
+const CONTENT_TREE_FILENAME: &str = "content.tree";
+/// Changes to the [`ContentTree`] since it was written, in [`StorageLayout::Hierarchical`].
+const CONTENT_TREE_LOG_FILENAME: &str = "content.tree.log";
+/// The log is written into the tree after this many records, or as many as the tree has leaves if more.
+const COMPACT_TREE_LOG_AFTER: u64 = 1024;
+
+/// Leaves are the hashes of the chunk tags of each file, see [`crypto::hash_chunk_tags`], the root is a Merkle tree
+/// over them. It's encrypted with the master key, so an attacker can't change it to match the files.
+///
+/// Changes are appended to a log, so a write doesn't rewrite the whole tree. The log is written into the tree
+/// when it gets long and when the data dir is opened.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct ContentTree {
+    leaves: BTreeMap<u64, [u8; 32]>,
+    root: [u8; 32],
+    /// Raised with each change, one lower than seen before means the whole data dir was rolled back.
+    generation: u64,
+    /// Files written since their leaf was last logged, the leaf is computed when they are closed.
+    #[serde(skip)]
+    dirty: HashSet<u64>,
+    /// Records in the log.
+    #[serde(skip)]
+    logged: u64,
+}
+
+/// A record in the log of the [`ContentTree`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum TreeChange {
+    /// The content is being written, if the leaf doesn't follow it crashed before the file was closed.
+    Changing(u64),
+    Leaf(u64, [u8; 32]),
+    Removed(u64),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TreeRecord {
+    generation: u64,
+    change: TreeChange,
+}
+
+impl ContentTree {
+    fn compute_root(&self) -> [u8; 32] {
+        let mut level: Vec<[u8; 32]> = self
+            .leaves
+            .iter()
+            .map(|(ino, leaf)| crypto::hash(&[&ino.to_le_bytes()[..], leaf].concat()))
+            .collect();
+        if level.is_empty() {
+            return crypto::hash(&[]);
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => crypto::hash(&[*left, *right].concat()),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        level[0]
+    }
+
+    fn apply(&mut self, change: TreeChange) {
+        match change {
+            TreeChange::Changing(ino) => {
+                self.dirty.insert(ino);
+            }
+            TreeChange::Leaf(ino, leaf) => {
+                self.dirty.remove(&ino);
+                self.leaves.insert(ino, leaf);
+            }
+            TreeChange::Removed(ino) => {
+                self.dirty.remove(&ino);
+                self.leaves.remove(&ino);
+            }
+        }
+    }
+}
+
+/// Something wrong found by [`EncryptedFs::verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyIssue {
+    /// The content doesn't match the tree, it was changed or replaced with an older version.
+    Modified { ino: u64 },
+    /// The file is not in the tree, it was added from outside or the tree was rolled back.
+    Untracked { ino: u64 },
+    /// The file is in the tree but can't be reached from root.
+    Missing { ino: u64 },
+    /// The tree is older than the generation we expected.
+    RolledBack { generation: u64, expected: u64 },
+}
+
+/// One issue per line, tab separated, so it's easy to parse.
+impl Display for VerifyIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Modified { ino } => write!(f, "modified\t{ino}"),
+            Self::Untracked { ino } => write!(f, "untracked\t{ino}"),
+            Self::Missing { ino } => write!(f, "missing\t{ino}"),
+            Self::RolledBack {
+                generation,
+                expected,
+            } => write!(f, "rolled-back\t{generation}\texpected={expected}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub files: u64,
+    /// Root of the tree, remember it with [`VerifyReport::generation`] to detect a rollback of the whole data dir.
+    pub root: [u8; 32],
+    pub generation: u64,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl EncryptedFs {
+    /// Verify the content of all files against the tree kept while it was mounted, without decrypting it. Files
+    /// replaced with older encrypted versions of them are found, unlike with [`EncryptedFs::check`], as they
+    /// still authenticate.
+    ///
+    /// The tree is kept in the data dir too, so if all of it was rolled back it can only be told by a generation
+    /// older than `min_generation`, from a previous [`VerifyReport`]. Files that were being written when it
+    /// crashed can't be verified, their leaf is set from the content the next time it's mounted.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn verify(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        min_generation: Option<u64>,
+    ) -> FsResult<VerifyReport> {
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_read_only(true),
+            None,
+            false,
+        )
+        .await?;
+        let tree = fs
+            .read_content_tree()
+            .await?
+            .ok_or(FsError::ContentTreeBroken("it's missing"))?;
+        info!("verifying data dir");
+        let mut report = VerifyReport {
+            root: tree.compute_root(),
+            generation: tree.generation,
+            ..VerifyReport::default()
+        };
+        if let Some(expected) = min_generation {
+            if tree.generation < expected {
+                report.issues.push(VerifyIssue::RolledBack {
+                    generation: tree.generation,
+                    expected,
+                });
+            }
+        }
+        let files = fs.file_inodes().await?;
+        for &ino in &files {
+            report.files += 1;
+            if tree.dirty.contains(&ino) {
+                warn!(
+                    ino,
+                    "file was being written when it crashed, can't verify it"
+                );
+                continue;
+            }
+            match tree.leaves.get(&ino) {
+                None => report.issues.push(VerifyIssue::Untracked { ino }),
+                Some(leaf) if *leaf != fs.content_leaf(ino)? => {
+                    report.issues.push(VerifyIssue::Modified { ino });
+                }
+                Some(_) => {}
+            }
+        }
+        report.issues.extend(
+            tree.leaves
+                .keys()
+                .filter(|ino| !files.contains(ino))
+                .map(|&ino| VerifyIssue::Missing { ino }),
+        );
+        info!(issues = report.issues.len(), "verify finished");
+        Ok(report)
+    }
+
+    /// Build the tree again from the files as they are now, for when it's missing or can't be read and the data
+    /// dir can't be opened because of that. Only do it if you trust the files, if any was changed from outside it
+    /// won't be found anymore.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rebuild_content_tree(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        // read-only so nothing else is changed, the tree is written directly
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        info!("building content tree");
+        let mut tree = ContentTree::default();
+        for ino in fs.file_inodes().await? {
+            tree.leaves.insert(ino, fs.content_leaf(ino)?);
+        }
+        // newer than what we can still read of the old one, so it's not taken for a rollback
+        let snapshot = fs.read_tree_snapshot().await.ok().flatten();
+        let logged = fs
+            .read_tree_log()
+            .await
+            .ok()
+            .and_then(|log| log.last().map(|r| r.generation));
+        tree.generation = snapshot
+            .map_or(0, |t| t.generation)
+            .max(logged.unwrap_or(0))
+            + 1;
+        fs.write_content_tree(&mut tree).await
+    }
+
+    fn content_tree_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                self.data_dir.join(SECURITY_DIR).join(CONTENT_TREE_FILENAME)
+            }
+            StorageLayout::Flat => self.object_path("content-tree"),
+        }
+    }
+
+    fn content_tree_log_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(CONTENT_TREE_LOG_FILENAME),
+            StorageLayout::Flat => self.object_path("content-tree-log"),
+        }
+    }
+
+    /// The tree as last written, with the changes from the log applied.
+    async fn read_content_tree(&self) -> FsResult<Option<ContentTree>> {
+        let Some(mut tree) = self.read_tree_snapshot().await? else {
+            if self.content_tree_log_path().is_file() {
+                return Err(FsError::ContentTreeBroken("it's missing"));
+            }
+            return Ok(None);
+        };
+        for record in self.read_tree_log().await? {
+            if record.generation <= tree.generation {
+                // already in the tree, it crashed before the log was removed
+                continue;
+            }
+            if record.generation != tree.generation + 1 {
+                return Err(FsError::ContentTreeBroken(
+                    "records are missing from its log",
+                ));
+            }
+            tree.apply(record.change);
+            tree.generation = record.generation;
+            tree.logged += 1;
+        }
+        Ok(Some(tree))
+    }
+
+    async fn read_tree_snapshot(&self) -> FsResult<Option<ContentTree>> {
+        let path = self.content_tree_path();
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let tree: ContentTree = self
+            .deserialize_from_file(&path)
+            .await
+            .map_err(|_| FsError::ContentTreeBroken("it can't be decrypted"))?;
+        if tree.compute_root() != tree.root {
+            // can't happen without the key, the tree is authenticated
+            return Err(FsError::ContentTreeBroken("its root doesn't match"));
+        }
+        Ok(Some(tree))
+    }
+
+    /// Records in the log, an incomplete one at the end, from a crash while it was appended, is left out.
+    async fn read_tree_log(&self) -> FsResult<Vec<TreeRecord>> {
+        let path = self.content_tree_log_path();
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let keys = self.master_keys().await?;
+        let mut records = vec![];
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let Some(frame) = rest.get(..4).and_then(|len| {
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                rest.get(4..4 + len)
+            }) else {
+                warn!("content tree log has an incomplete record at the end, from a crash");
+                break;
+            };
+            let record = keys
+                .iter()
+                .find_map(|(cipher, key)| {
+                    bincode::deserialize_from(crypto::create_read(frame, *cipher, key)).ok()
+                })
+                .ok_or(FsError::ContentTreeBroken(
+                    "a record in its log can't be decrypted",
+                ))?;
+            records.push(record);
+            rest = &rest[4 + frame.len()..];
+        }
+        Ok(records)
+    }
+
+    /// Write the whole tree and remove the log, it's now in the tree.
+    async fn write_content_tree(&self, tree: &mut ContentTree) -> FsResult<()> {
+        tree.root = tree.compute_root();
+        crypto::atomic_serialize_encrypt_into(
+            &self.content_tree_path(),
+            tree,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        let log = self.content_tree_log_path();
+        if log.is_file() {
+            self.remove_data_file(&log)?;
+        }
+        tree.logged = 0;
+        Ok(())
+    }
+
+    /// Append a change to the log, in one write, so a crash cuts only the last record.
+    async fn log_tree_change(&self, tree: &mut ContentTree, change: TreeChange) -> FsResult<()> {
+        let record = TreeRecord {
+            generation: tree.generation + 1,
+            change,
+        };
+        let mut buf = vec![];
+        crypto::serialize_encrypt_into(&mut buf, &record, self.cipher, &*self.key.get().await?)?;
+        let len = u32::try_from(buf.len())
+            .map_err(|_| FsError::InvalidInput("content tree record too big"))?;
+        let path = self.content_tree_log_path();
+        let created = !path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(&[&len.to_le_bytes()[..], &buf].concat())?;
+        file.sync_all()?;
+        if created {
+            File::open(path.parent().unwrap())?.sync_all()?;
+        }
+        tree.apply(change);
+        tree.generation = record.generation;
+        tree.logged += 1;
+        if tree.logged >= COMPACT_TREE_LOG_AFTER.max(tree.leaves.len() as u64) {
+            self.compact_content_tree(tree).await?;
+        }
+        Ok(())
+    }
+
+    /// Set the leaves of the files still being written from what they have now and write the log into the tree.
+    async fn compact_content_tree(&self, tree: &mut ContentTree) -> FsResult<()> {
+        for ino in std::mem::take(&mut tree.dirty) {
+            if self.contents_path(ino).is_file() {
+                tree.leaves.insert(ino, self.content_leaf(ino)?);
+            } else {
+                tree.leaves.remove(&ino);
+            }
+        }
+        self.write_content_tree(tree).await
+    }
+
+    /// Load the tree, or build it for a data dir just `created`. It fails if it's missing or can't be read, it's
+    /// built again only with [`EncryptedFs::rebuild_content_tree`], as what's in the data dir now can't be trusted.
+    pub(super) async fn load_content_tree(&self, created: bool) -> FsResult<()> {
+        let mut tree = match self.read_content_tree().await? {
+            Some(tree) => tree,
+            None if created || self.file_inodes().await?.is_empty() => {
+                let mut tree = ContentTree {
+                    generation: 1,
+                    ..ContentTree::default()
+                };
+                // with a base its files are already there
+                for ino in self.file_inodes().await? {
+                    tree.leaves.insert(ino, self.content_leaf(ino)?);
+                }
+                self.write_content_tree(&mut tree).await?;
+                tree
+            }
+            None => return Err(FsError::ContentTreeBroken("it's missing")),
+        };
+        if tree.logged > 0 || !tree.dirty.is_empty() {
+            self.compact_content_tree(&mut tree).await?;
+        }
+        *self.content_tree.lock().await = Some(tree);
+        Ok(())
+    }
+
+    /// Call it after new content was committed, the leaf is logged when the file is closed, see
+    /// [`EncryptedFs::save_content_leaves`].
+    pub(super) async fn content_changed(&self, ino: u64) -> FsResult<()> {
+        let mut guard = self.content_tree.lock().await;
+        let Some(tree) = guard.as_mut() else {
+            return Ok(());
+        };
+        if tree.dirty.contains(&ino) {
+            return Ok(());
+        }
+        self.log_tree_change(tree, TreeChange::Changing(ino)).await
+    }
+
+    /// Call it after the content of the file changed or was removed, the leaf is logged now.
+    pub(super) async fn update_content_leaf(&self, ino: u64) -> FsResult<()> {
+        let mut guard = self.content_tree.lock().await;
+        let Some(tree) = guard.as_mut() else {
+            return Ok(());
+        };
+        self.log_content_leaf(tree, ino).await
+    }
+
+    /// Log the leaves of the files changed since they were last logged, all of them if `ino` is `None`.
+    pub(super) async fn save_content_leaves(&self, ino: Option<u64>) -> FsResult<()> {
+        let mut guard = self.content_tree.lock().await;
+        let Some(tree) = guard.as_mut() else {
+            return Ok(());
+        };
+        let dirty: Vec<u64> = tree
+            .dirty
+            .iter()
+            .copied()
+            .filter(|dirty| ino.is_none_or(|ino| ino == *dirty))
+            .collect();
+        for ino in dirty {
+            self.log_content_leaf(tree, ino).await?;
+        }
+        Ok(())
+    }
+
+    async fn log_content_leaf(&self, tree: &mut ContentTree, ino: u64) -> FsResult<()> {
+        let change = if self.contents_path(ino).is_file() {
+            let leaf = self.content_leaf(ino)?;
+            if !tree.dirty.contains(&ino) && tree.leaves.get(&ino) == Some(&leaf) {
+                return Ok(());
+            }
+            TreeChange::Leaf(ino, leaf)
+        } else if tree.leaves.contains_key(&ino) || tree.dirty.contains(&ino) {
+            TreeChange::Removed(ino)
+        } else {
+            return Ok(());
+        };
+        self.log_tree_change(tree, change).await
+    }
+
+    /// Save the tree with the current key, after it was rotated.
+    pub(super) async fn rewrite_content_tree(&self) -> FsResult<()> {
+        let mut guard = self.content_tree.lock().await;
+        let Some(tree) = guard.as_mut() else {
+            return Ok(());
+        };
+        self.compact_content_tree(tree).await
+    }
+
+    fn content_leaf(&self, ino: u64) -> FsResult<[u8; 32]> {
+        let path = self.contents_path(ino);
+        if !path.is_file() {
+            return Ok(crypto::hash(&[]));
+        }
+        Ok(crypto::hash_chunk_tags(
+            &mut File::open(path)?,
+            self.cipher,
+        )?)
+    }
+
+    /// Files reachable from root.
+    async fn file_inodes(&self) -> FsResult<HashSet<u64>> {
+        Ok(self
+            .walk_tree()
+            .await?
+            .into_iter()
+            .filter(|ino| !self.is_dir(*ino))
+            .collect())
+    }
+}
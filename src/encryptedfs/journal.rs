@@ -0,0 +1,229 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rand_core::RngCore;
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::crypto;
+use crate::encryptedfs::{
+    DirectoryEntry, EncryptedFs, FileType, FsResult, StorageLayout, LS_DIR, SECURITY_DIR,
+};
+
+/// Changes of metadata in progress, one file each, encrypted with the master key.
+const JOURNAL_DIR: &str = "journal";
+
+/// A change of metadata that touches several files. It's written before starting and removed when done, so after a
+/// crash it can be replayed when the filesystem is created again. Each step of the replay is skipped if it's already
+/// done, so it's safe to be interrupted too.
+///
+/// Names of the entries are kept together with their encrypted name, so the entry is written with the same name
+/// when replayed and not duplicated.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum JournalOp {
+    /// Undone when replayed, it was not returned yet, so nothing else was written in it.
+    Create {
+        parent: u64,
+        name: String,
+        encrypted_name: String,
+        ino: u64,
+        kind: FileType,
+    },
+    /// Finished when replayed.
+    Remove {
+        parent: u64,
+        name: String,
+        encrypted_name: String,
+        ino: u64,
+        kind: FileType,
+    },
+    /// Finished when replayed, `replaced` is the inode and encrypted name of the entry it overwrites.
+    Rename {
+        parent: u64,
+        name: String,
+        encrypted_name: String,
+        new_parent: u64,
+        new_name: String,
+        new_encrypted_name: String,
+        ino: u64,
+        kind: FileType,
+        replaced: Option<(u64, String)>,
+    },
+}
+
+impl EncryptedFs {
+    /// Write `op` to the journal, call [`EncryptedFs::journal_end`] with the returned path when it's done.
+    pub(super) async fn journal_begin(&self, op: &JournalOp) -> FsResult<PathBuf> {
+        let dir = self.data_dir.join(SECURITY_DIR).join(JOURNAL_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut id = [0_u8; 16];
+        crypto::create_rng().fill_bytes(&mut id);
+        let path = dir.join(hex::encode(id));
+        crypto::atomic_serialize_encrypt_into(&path, op, self.cipher, &*self.key.get().await?)?;
+        Ok(path)
+    }
+
+    pub(super) fn journal_end(path: &Path) -> FsResult<()> {
+        fs::remove_file(path)?;
+        // so a finished create is not undone
+        File::open(path.parent().unwrap())?.sync_all()?;
+        Ok(())
+    }
+
+    /// Replay what was left in the journal by a crash. Ops we can't decrypt belong to the other volume in the data
+    /// dir, they are left for it.
+    pub(super) async fn replay_journal(&self) -> FsResult<()> {
+        let dir = self.data_dir.join(SECURITY_DIR).join(JOURNAL_DIR);
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Ok(op) = self.deserialize_from_file::<JournalOp>(&path).await else {
+                continue;
+            };
+            info!(?op, "replaying from journal");
+            self.replay(op).await?;
+            Self::journal_end(&path)?;
+        }
+        Ok(())
+    }
+
+    async fn replay(&self, op: JournalOp) -> FsResult<()> {
+        match op {
+            JournalOp::Create {
+                parent,
+                name,
+                encrypted_name,
+                ino,
+                kind,
+            }
+            | JournalOp::Remove {
+                parent,
+                name,
+                encrypted_name,
+                ino,
+                kind,
+            } => {
+                self.remove_entry_if(parent, &SecretString::new(name), &encrypted_name, ino)
+                    .await?;
+                self.remove_inode_storage(ino, kind).await?;
+            }
+            JournalOp::Rename {
+                parent,
+                name,
+                encrypted_name,
+                new_parent,
+                new_name,
+                new_encrypted_name,
+                ino,
+                kind,
+                replaced,
+            } => {
+                let new_name = SecretString::new(new_name);
+                self.remove_entry_if(parent, &SecretString::new(name), &encrypted_name, ino)
+                    .await?;
+                if let Some((replaced_ino, replaced_encrypted_name)) = replaced {
+                    self.remove_entry_if(
+                        new_parent,
+                        &new_name,
+                        &replaced_encrypted_name,
+                        replaced_ino,
+                    )
+                    .await?;
+                }
+                if !self.is_dir(new_parent) {
+                    warn!(ino, "the new parent is gone, can't finish rename");
+                    return Ok(());
+                }
+                // same names, so they are overwritten if already there
+                self.insert_directory_entry_as(
+                    new_parent,
+                    &DirectoryEntry {
+                        ino,
+                        name: new_name,
+                        kind,
+                    },
+                    new_encrypted_name,
+                )
+                .await?;
+                if kind == FileType::Directory && self.is_dir(ino) {
+                    self.insert_directory_entry(
+                        ino,
+                        &DirectoryEntry {
+                            ino: new_parent,
+                            name: SecretString::from_str("$..").unwrap(),
+                            kind: FileType::Directory,
+                        },
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the entry if it's still there and points to `ino`.
+    async fn remove_entry_if(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        encrypted_name: &str,
+        ino: u64,
+    ) -> FsResult<()> {
+        if !self.is_dir(parent) {
+            return Ok(());
+        }
+        if matches!(self.read_hash_entry(parent, name).await?, Some((entry_ino, _, _)) if entry_ino == ino)
+        {
+            self.remove_directory_entry(parent, name).await?;
+        }
+        // the hash entry is removed first, the listing might still be there
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                let path = self.contents_path(parent).join(LS_DIR).join(encrypted_name);
+                if path.is_file() {
+                    fs::remove_file(path)?;
+                }
+            }
+            StorageLayout::Flat => {
+                let lock = self
+                    .serialize_dir_entries_ls_locks
+                    .get_or_insert_with(self.dir_index_lock_key(parent), || RwLock::new(false));
+                let _guard = lock.write().await;
+                let mut index = self.read_dir_index(parent).await?;
+                if index.remove(encrypted_name).is_some() {
+                    self.write_dir_index(parent, &index).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove what's left of the inode, its metadata and content.
+    async fn remove_inode_storage(&self, ino: u64, kind: FileType) -> FsResult<()> {
+        let ino_file = self.ino_file(ino);
+        if ino_file.is_file() {
+            fs::remove_file(ino_file)?;
+        }
+        match kind {
+            FileType::RegularFile => {
+                let path = self.contents_path(ino);
+                if path.is_file() {
+                    fs::remove_file(path)?;
+                }
+                self.remove_manifest(ino).await?;
+                self.update_content_leaf(ino).await?;
+            }
+            FileType::Directory => {
+                if self.is_dir(ino) {
+                    self.remove_dir_storage(ino).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
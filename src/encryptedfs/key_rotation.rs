@@ -0,0 +1,443 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{debug, info};
+
+use crate::crypto::write::CryptoWrite;
+use crate::crypto::LockedVec;
+use crate::encryptedfs::{header, key_slots, totp};
+use crate::encryptedfs::{
+    DirIndex, EncryptedFs, FileAttr, FileType, FsError, FsResult, StorageLayout, INODES_DIR,
+    KEY_ENC_FILENAME, KEY_OLD_ENC_FILENAME, KEY_ROTATION_PROGRESS_FILENAME, KEY_SALT_FILENAME,
+    LS_DIR, SECURITY_DIR,
+};
+use crate::{crypto, fs_util};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyRotationProgress {
+    /// Last inode re-encrypted, they are processed in ascending order.
+    last: Option<u64>,
+    /// New key of the file we're re-encrypting, kept until the inode is updated with it.
+    pending: Option<(u64, LockedVec)>,
+}
+
+impl EncryptedFs {
+    /// If a key rotation was started and didn't finish yet. While it's in progress, metadata that wasn't
+    /// re-encrypted yet is decrypted with the old master key.
+    #[must_use]
+    pub fn is_key_rotation_in_progress(&self) -> bool {
+        self.data_dir
+            .join(SECURITY_DIR)
+            .join(KEY_OLD_ENC_FILENAME)
+            .is_file()
+    }
+
+    /// Generate a new master key and re-encrypt all metadata with it. Each file also gets a new key and
+    /// its content is re-encrypted. The filesystem stays usable meanwhile.
+    ///
+    /// Progress is saved after each inode, if interrupted it's resumed next time [`EncryptedFs`] is created
+    /// or when calling this again.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn rotate_key(&self) -> FsResult<()> {
+        if self.layout == StorageLayout::Flat {
+            // names of the objects are derived from the master key
+            return Err(FsError::InvalidInput(
+                "key rotation is not supported for flat layout",
+            ));
+        }
+        if Self::has_deduplicated_content(&self.data_dir)? {
+            // ids and keys of the chunks are derived from the master key
+            return Err(FsError::InvalidInput(
+                "key rotation is not supported with deduplicated content",
+            ));
+        }
+        if !key_slots::read_key_slots(&self.data_dir.join(SECURITY_DIR))?.is_empty() {
+            // we don't have the secrets of the slots to encrypt the new key with them
+            return Err(FsError::InvalidInput(
+                "key rotation is not supported with key slots, remove them first",
+            ));
+        }
+        let Ok(_guard) = self.key_rotation_lock.try_lock() else {
+            return Err(FsError::Other("key rotation already running"));
+        };
+        if !self.is_key_rotation_in_progress() {
+            let _changing = self.changes.enter();
+            self.begin_key_rotation().await?;
+        }
+        info!("rotating key");
+
+        let mut progress = self.read_key_rotation_progress().await?;
+        if let Some((ino, key)) = progress.pending.take() {
+            self.resolve_pending_file_key(ino, &key).await?;
+            self.write_key_rotation_progress(&progress).await?;
+        }
+        let last = progress.last;
+        for ino in self
+            .all_inodes()?
+            .into_iter()
+            .filter(|ino| last.is_none_or(|last| *ino > last))
+        {
+            debug!(ino, "rotating key");
+            let _changing = self.changes.enter();
+            self.rotate_inode(ino, &mut progress).await?;
+            progress.last = Some(ino);
+            self.write_key_rotation_progress(&progress).await?;
+        }
+        // operations that got the old key just before we started could have written metadata with it after
+        // we passed, this pass is cheap as it only reads what's already re-encrypted
+        for ino in self.all_inodes()? {
+            let _changing = self.changes.enter();
+            self.reencrypt_metadata(ino).await?;
+        }
+
+        let _changing = self.changes.enter();
+        self.rewrite_content_tree().await?;
+        self.rewrite_versions().await?;
+        self.rewrite_overlay_index().await?;
+        self.rewrite_passthrough().await?;
+        self.rewrite_audit_log().await?;
+        self.finish_key_rotation().await?;
+        info!("key rotated");
+        Ok(())
+    }
+
+    pub(super) async fn begin_key_rotation(&self) -> FsResult<()> {
+        let password = self
+            .password_provider
+            .get_password()
+            .ok_or(FsError::InvalidPassword)?;
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        let salt: Vec<u8> =
+            bincode::deserialize_from(File::open(security_dir.join(KEY_SALT_FILENAME))?)?;
+        let kdf = header::kdf_params(&security_dir)?;
+        let derived_key = crypto::derive_key_with(&password, self.cipher, &salt, &kdf)?;
+        // keep the old key until everything is re-encrypted
+        let old_key = self.key.get().await?;
+        crypto::atomic_serialize_encrypt_into(
+            &security_dir.join(KEY_OLD_ENC_FILENAME),
+            &old_key.expose_secret(),
+            self.cipher,
+            &derived_key,
+        )?;
+        let key = LockedVec::random(self.cipher.key_len());
+        crypto::atomic_serialize_encrypt_into(
+            &security_dir.join(KEY_ENC_FILENAME),
+            &key,
+            self.cipher,
+            &derived_key,
+        )?;
+        totp::reencrypt_totp_secret(&security_dir, (self.cipher, &old_key), (self.cipher, &key))?;
+        self.key.clear().await;
+        self.old_key.clear().await;
+        Ok(())
+    }
+
+    async fn finish_key_rotation(&self) -> FsResult<()> {
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        // in case we crashed before it was re-encrypted at the beginning
+        totp::reencrypt_totp_secret(
+            &security_dir,
+            (self.cipher, &*self.old_key.get().await?),
+            (self.cipher, &*self.key.get().await?),
+        )?;
+        let progress_path = security_dir.join(KEY_ROTATION_PROGRESS_FILENAME);
+        if progress_path.exists() {
+            fs::remove_file(progress_path)?;
+        }
+        fs::remove_file(security_dir.join(KEY_OLD_ENC_FILENAME))?;
+        File::open(security_dir)?.sync_all()?;
+        self.old_key.clear().await;
+        Ok(())
+    }
+
+    async fn read_key_rotation_progress(&self) -> FsResult<KeyRotationProgress> {
+        let path = self
+            .data_dir
+            .join(SECURITY_DIR)
+            .join(KEY_ROTATION_PROGRESS_FILENAME);
+        if !path.is_file() {
+            return Ok(KeyRotationProgress::default());
+        }
+        Ok(bincode::deserialize_from(crypto::create_read(
+            File::open(path)?,
+            self.cipher,
+            &*self.key.get().await?,
+        ))?)
+    }
+
+    async fn write_key_rotation_progress(&self, progress: &KeyRotationProgress) -> FsResult<()> {
+        crypto::atomic_serialize_encrypt_into(
+            &self
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(KEY_ROTATION_PROGRESS_FILENAME),
+            progress,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    fn all_inodes(&self) -> FsResult<Vec<u64>> {
+        let mut inodes = fs::read_dir(self.data_dir.join(INODES_DIR))?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().parse::<u64>()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            // skip temp files of atomic writes
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        inodes.sort_unstable();
+        Ok(inodes)
+    }
+
+    async fn rotate_inode(&self, ino: u64, progress: &mut KeyRotationProgress) -> FsResult<()> {
+        let attr = match self.get_inode_from_storage(ino).await {
+            Ok(attr) => attr,
+            // removed meanwhile
+            Err(FsError::InodeNotFound) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if attr.kind == FileType::RegularFile {
+            // this writes the inode with the new master key too
+            self.rotate_file_content(ino, progress).await
+        } else {
+            self.reencrypt_metadata(ino).await
+        }
+    }
+
+    /// Re-encrypt the content with a new key and save it in the inode.
+    async fn rotate_file_content(
+        &self,
+        ino: u64,
+        progress: &mut KeyRotationProgress,
+    ) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        let path = self.contents_path(ino);
+        if !self.exists(ino) || !path.is_file() {
+            return Ok(());
+        }
+        self.flush_and_reset_writers(ino).await?;
+
+        let old_key = self.file_key(ino).await?;
+        let key = LockedVec::random(self.cipher.key_len());
+        // save it before changing the content, in case we're interrupted before saving the inode
+        progress.pending = Some((ino, key.clone()));
+        self.write_key_rotation_progress(progress).await?;
+
+        // plaintext content doesn't use the key
+        if !self.is_plaintext(ino).await? {
+            let mut file = fs_util::open_atomic_write(&path)?;
+            {
+                // have a new scope, so we drop the reader before moving new content files
+                let mut reader = crypto::create_read(File::open(&path)?, self.cipher, &old_key);
+                let mut writer = crypto::create_write(file, self.cipher, &key);
+                io::copy(&mut reader, &mut writer)?;
+                file = writer.finish()?;
+            }
+            self.replace_data_file(&path, || Ok(file.commit()?))?;
+            File::open(path.parent().unwrap())?.sync_all()?;
+            self.update_content_leaf(ino).await?;
+        }
+
+        let attr = self.get_inode_from_storage(ino).await?;
+        self.write_inode_with_key_to_storage(&attr, Some(&key))
+            .await?;
+        progress.pending = None;
+
+        // reset handles because the file has changed
+        self.reset_handles(ino, None, false).await?;
+        Ok(())
+    }
+
+    /// Interrupted while re-encrypting the content of a file, make sure the inode has the key the content uses.
+    async fn resolve_pending_file_key(&self, ino: u64, key: &LockedVec) -> FsResult<()> {
+        let lock = self
+            .read_write_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _write_guard = lock.write().await;
+        let path = self.contents_path(ino);
+        if !self.exists(ino) || !path.is_file() {
+            return Ok(());
+        }
+        if self.file_key(ino).await?.expose_secret() == key.expose_secret() {
+            return Ok(());
+        }
+        let mut reader = crypto::create_read(File::open(&path)?, self.cipher, key);
+        let mut buf = vec![];
+        if reader.read_to_end(&mut buf).is_ok() {
+            // the content was re-encrypted but the inode wasn't saved
+            let attr = self.get_inode_from_storage(ino).await?;
+            self.write_inode_with_key_to_storage(&attr, Some(key))
+                .await?;
+            self.reset_handles(ino, None, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Re-encrypt the inode and, for directories, the entries, if not already using the current
+    /// master key and cipher.
+    pub(super) async fn reencrypt_metadata(&self, ino: u64) -> FsResult<()> {
+        if self.is_dir(ino) {
+            match self.layout {
+                StorageLayout::Hierarchical => self.reencrypt_dir_entries(ino).await?,
+                StorageLayout::Flat => self.reencrypt_dir_index(ino).await?,
+            }
+        }
+        let key = self.key.get().await?;
+        let lock = self
+            .serialize_inode_locks
+            .get_or_insert_with(ino, || RwLock::new(false));
+        let _guard = lock.write().await;
+        let path = self.ino_file(ino);
+        if !path.is_file() || self.is_encrypted_with::<FileAttr>(&path, &key) {
+            return Ok(());
+        }
+        let (attr, file_key) = self.read_inode_file(&path).await?;
+        self.replace_data_file(&path, || {
+            Ok(crypto::atomic_serialize_encrypt_into(
+                &path,
+                &(attr, file_key.as_ref().map(ExposeSecret::expose_secret)),
+                self.cipher,
+                &key,
+            )?)
+        })?;
+        Ok(())
+    }
+
+    async fn reencrypt_dir_entries(&self, ino: u64) -> FsResult<()> {
+        let key = self.key.get().await?;
+        let ls_dir = self.contents_path(ino).join(LS_DIR);
+        for entry in fs::read_dir(&ls_dir)? {
+            let entry = entry?;
+            let encrypted_name = entry.file_name().to_string_lossy().to_string();
+            // "$." and "$.." are not encrypted
+            let special = encrypted_name.starts_with('$');
+            let name = if special {
+                SecretString::new(encrypted_name.clone())
+            } else {
+                match self.decrypt_file_name(&encrypted_name).await {
+                    Ok(name) => name,
+                    // temp file of an atomic write or removed meanwhile
+                    Err(_) if !entry.path().is_file() || encrypted_name.starts_with('.') => {
+                        continue
+                    }
+                    Err(err) => return Err(err),
+                }
+            };
+            let ls_path = entry.path();
+            let hash_path = self.hash_entry_path(ino, &name);
+            if (special || crypto::decrypt_file_name(&encrypted_name, self.cipher, &key).is_ok())
+                && self.is_encrypted_with::<(u64, FileType)>(&ls_path, &key)
+                && self.is_encrypted_with::<(u64, FileType, String)>(&hash_path, &key)
+            {
+                continue;
+            }
+
+            let hash_lock = self
+                .serialize_dir_entries_hash_locks
+                .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
+                    RwLock::new(false)
+                });
+            let _hash_guard = hash_lock.write().await;
+            let ls_lock = self
+                .serialize_dir_entries_ls_locks
+                .get_or_insert_with(ls_path.to_str().unwrap().to_string(), || RwLock::new(false));
+            let _ls_guard = ls_lock.write().await;
+            if !ls_path.is_file() {
+                // removed meanwhile
+                continue;
+            }
+            let (entry_ino, kind): (u64, FileType) = self.deserialize_from_file(&ls_path).await?;
+            let new_encrypted_name = if special {
+                encrypted_name.clone()
+            } else {
+                crypto::encrypt_file_name(&name, self.cipher, &key)?
+            };
+            let new_ls_path = ls_dir.join(&new_encrypted_name);
+            crypto::atomic_serialize_encrypt_into(
+                &new_ls_path,
+                &(entry_ino, kind),
+                self.cipher,
+                &key,
+            )?;
+            crypto::atomic_serialize_encrypt_into(
+                &hash_path,
+                &(entry_ino, kind, new_encrypted_name),
+                self.cipher,
+                &key,
+            )?;
+            if new_ls_path != ls_path {
+                fs::remove_file(&ls_path)?;
+            }
+            self.forget_dir_entries(&[&hash_path, &ls_path]).await?;
+        }
+        Ok(())
+    }
+
+    async fn reencrypt_dir_index(&self, ino: u64) -> FsResult<()> {
+        let key = self.key.get().await?;
+        let lock = self
+            .serialize_dir_entries_ls_locks
+            .get_or_insert_with(self.dir_index_lock_key(ino), || RwLock::new(false));
+        let _guard = lock.write().await;
+        if !self.dir_index_path(ino).is_file() {
+            // removed meanwhile
+            return Ok(());
+        }
+        let mut changed =
+            !self.is_encrypted_with::<(DirIndex, Vec<u8>)>(&self.dir_index_path(ino), &key);
+        let mut index = DirIndex::new();
+        for (encrypted_name, (entry_ino, kind)) in self.read_dir_index(ino).await? {
+            let name = self.decrypt_dir_entry_name(&encrypted_name).await?;
+            let hash_path = self.hash_entry_path(ino, &name);
+            // "$." and "$.." are not encrypted
+            let name_changed = !encrypted_name.starts_with('$')
+                && crypto::decrypt_file_name(&encrypted_name, self.cipher, &key).is_err();
+            let new_encrypted_name = if name_changed {
+                crypto::encrypt_file_name(&name, self.cipher, &key)?
+            } else {
+                encrypted_name
+            };
+            changed |= name_changed;
+            if name_changed || !self.is_encrypted_with::<(u64, FileType, String)>(&hash_path, &key)
+            {
+                let hash_lock = self
+                    .serialize_dir_entries_hash_locks
+                    .get_or_insert_with(hash_path.to_str().unwrap().to_string(), || {
+                        RwLock::new(false)
+                    });
+                let _hash_guard = hash_lock.write().await;
+                crypto::atomic_serialize_encrypt_into(
+                    &hash_path,
+                    &(entry_ino, kind, new_encrypted_name.clone()),
+                    self.cipher,
+                    &key,
+                )?;
+                self.forget_dir_entries(&[&hash_path]).await?;
+            }
+            index.insert(new_encrypted_name, (entry_ino, kind));
+        }
+        if changed {
+            self.write_dir_index(ino, &index).await?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn is_encrypted_with<T: DeserializeOwned>(
+        &self,
+        path: &Path,
+        key: &SecretVec<u8>,
+    ) -> bool {
+        File::open(path).is_ok_and(|file| {
+            bincode::deserialize_from::<_, T>(crypto::create_read(file, self.cipher, key)).is_ok()
+        })
+    }
+}
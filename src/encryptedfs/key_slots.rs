@@ -0,0 +1,234 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use argon2::password_hash::rand_core::RngCore;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{Cipher, Locked, LockedString, LockedVec};
+use crate::encryptedfs::header::{kdf_params, volume_cipher};
+use crate::encryptedfs::{
+    check_structure, read_key, EncryptedFs, FsError, FsResult, KEY_ENC_FILENAME,
+    KEY_OLD_ENC_FILENAME, KEY_SALT_FILENAME, SECURITY_DIR,
+};
+use crate::{crypto, fs_util};
+
+/// Directory inside [`SECURITY_DIR`] with the key slots.
+const KEY_SLOTS_DIR: &str = "slots";
+/// Extension of the file with the [`KeySlot`], next to it is the master key encrypted with the slot secret.
+const KEY_SLOT_EXTENSION: &str = "slot";
+const KEY_SLOT_KEY_EXTENSION: &str = "enc";
+
+/// How the secret of a slot is obtained. It holds only what's needed to get the secret, not the secret itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeySlotKind {
+    /// Output of the `hmac-secret` extension of a FIDO2 security key. Values are base64 encoded.
+    Fido2 {
+        rp_id: String,
+        credential_id: String,
+        hmac_salt: String,
+    },
+    /// Secret sealed to the TPM 2.0 of the machine, optionally bound to the values of `pcrs`, like
+    /// `sha256:0,7`. `public` and `private` are the base64 encoded parts of the sealed object.
+    Tpm {
+        pcrs: Option<String>,
+        public: String,
+        private: String,
+    },
+    /// Secret encrypted with the RSA key `key_label` from a PKCS#11 token or HSM, loaded with `module`.
+    /// `wrapped` is base64 encoded.
+    Pkcs11 {
+        module: String,
+        slot: Option<String>,
+        key_label: String,
+        wrapped: String,
+    },
+    /// Another password, so each person sharing the volume can have their own and be revoked alone.
+    Password,
+    /// A keyfile, mixed with a password that can be empty, see [`crypto::password_with_keyfile`].
+    Keyfile,
+    /// Generated with [`crypto::generate_recovery_key`], to be written down and kept offline.
+    RecoveryKey,
+}
+
+impl std::fmt::Display for KeySlotKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Fido2 { .. } => "fido2",
+            Self::Tpm { .. } => "tpm",
+            Self::Pkcs11 { .. } => "pkcs11",
+            Self::Password => "password",
+            Self::Keyfile => "keyfile",
+            Self::RecoveryKey => "recovery-key",
+        })
+    }
+}
+
+/// Another way to unlock the master key, besides the password, like a key slot of LUKS. The master key is also encrypted with a key
+/// derived from the secret of the slot, which is given instead of the password when unlocking.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub name: String,
+    pub kind: KeySlotKind,
+    // kept in the slot, so it doesn't depend on the cipher of the data dir
+    cipher: Cipher,
+    salt: Vec<u8>,
+}
+
+impl EncryptedFs {
+    /// Key slots of the data dir. They are not encrypted, so this doesn't need the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn key_slots(data_dir: &Path) -> FsResult<Vec<KeySlot>> {
+        check_structure(data_dir, false).await?;
+        read_key_slots(&data_dir.join(SECURITY_DIR))
+    }
+
+    /// Add a key slot unlocked by `secret`, the master key is unlocked with `password`.
+    ///
+    /// It returns [`FsError::AlreadyExists`] if there is already a slot with the same name.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn add_key_slot(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        name: &str,
+        kind: KeySlotKind,
+        secret: LockedString,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(FsError::InvalidInput(
+                "slot name can have only letters, digits, '-' and '_'",
+            ));
+        }
+        let security_dir = data_dir.join(SECURITY_DIR);
+        let key = read_key(
+            &security_dir.join(KEY_ENC_FILENAME),
+            &security_dir.join(KEY_SALT_FILENAME),
+            &password,
+            cipher,
+        )?;
+        let slots_dir = security_dir.join(KEY_SLOTS_DIR);
+        fs::create_dir_all(&slots_dir)?;
+        let slot_path = slots_dir.join(name).with_extension(KEY_SLOT_EXTENSION);
+        if slot_path.exists() {
+            return Err(FsError::AlreadyExists);
+        }
+        let mut salt = vec![0; 16];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let slot = KeySlot {
+            name: name.to_string(),
+            kind,
+            cipher,
+            salt,
+        };
+        let derived_key = crypto::derive_key(&secret, cipher, &slot.salt)?;
+        // the slot file is written last, so we only see complete slots
+        crypto::atomic_serialize_encrypt_into(
+            &slots_dir.join(name).with_extension(KEY_SLOT_KEY_EXTENSION),
+            &key.expose_secret(),
+            cipher,
+            &derived_key,
+        )?;
+        let mut file = fs_util::open_atomic_write(&slot_path)?;
+        bincode::serialize_into(&mut file, &slot)?;
+        file.commit()?;
+        File::open(slots_dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Set a new password with the secret of a key slot, like a recovery key, when the password is forgotten. The
+    /// slots keep working.
+    ///
+    /// It can't be done in the middle of a key rotation, the old key is unlocked only by the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn reset_password(
+        data_dir: &Path,
+        secret: LockedString,
+        new_password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        let security_dir = data_dir.join(SECURITY_DIR);
+        if security_dir.join(KEY_OLD_ENC_FILENAME).exists() {
+            return Err(FsError::InvalidInput(
+                "a key rotation is in progress, mount it with the password to finish it first",
+            ));
+        }
+        let key = read_key_from_slots(&security_dir, &secret)?.ok_or(FsError::InvalidPassword)?;
+        let salt: Vec<u8> =
+            bincode::deserialize_from(File::open(security_dir.join(KEY_SALT_FILENAME))?)?;
+        let kdf = kdf_params(&security_dir)?;
+        let new_key = crypto::derive_key_with(&new_password, cipher, &salt, &kdf)?;
+        crypto::atomic_serialize_encrypt_into(
+            &security_dir.join(KEY_ENC_FILENAME),
+            &key.expose_secret(),
+            cipher,
+            &new_key,
+        )?;
+        Ok(())
+    }
+
+    /// Remove a key slot, the password keeps working.
+    ///
+    /// It returns [`FsError::NotFound`] if there is no slot with that name.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_key_slot(data_dir: &Path, name: &str) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let slots_dir = data_dir.join(SECURITY_DIR).join(KEY_SLOTS_DIR);
+        if !read_key_slots(&data_dir.join(SECURITY_DIR))?
+            .iter()
+            .any(|slot| slot.name == name)
+        {
+            return Err(FsError::NotFound("key slot"));
+        }
+        fs::remove_file(slots_dir.join(name).with_extension(KEY_SLOT_EXTENSION))?;
+        fs::remove_file(slots_dir.join(name).with_extension(KEY_SLOT_KEY_EXTENSION))?;
+        File::open(slots_dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+pub(super) fn read_key_slots(security_dir: &Path) -> FsResult<Vec<KeySlot>> {
+    let slots_dir = security_dir.join(KEY_SLOTS_DIR);
+    if !slots_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut slots = vec![];
+    for entry in fs::read_dir(slots_dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == KEY_SLOT_EXTENSION)
+        {
+            slots.push(bincode::deserialize_from(File::open(path)?)?);
+        }
+    }
+    slots.sort_by(|a: &KeySlot, b| a.name.cmp(&b.name));
+    Ok(slots)
+}
+
+/// Try to unlock the master key with `secret` as the secret of each slot.
+pub(super) fn read_key_from_slots(
+    security_dir: &Path,
+    secret: &SecretString,
+) -> FsResult<Option<LockedVec>> {
+    for slot in read_key_slots(security_dir)? {
+        let derived_key = crypto::derive_key(secret, slot.cipher, &slot.salt)?;
+        let path = security_dir
+            .join(KEY_SLOTS_DIR)
+            .join(&slot.name)
+            .with_extension(KEY_SLOT_KEY_EXTENSION);
+        let reader = crypto::create_read(File::open(path)?, slot.cipher, &derived_key);
+        if let Ok(key) = bincode::deserialize_from::<_, Vec<u8>>(reader) {
+            return Ok(Some(Locked::new(key)));
+        }
+    }
+    Ok(None)
+}
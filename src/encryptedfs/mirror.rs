@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{debug, error, info};
+
+use crate::encryptedfs::snapshots::SNAPSHOTS_DIR;
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, CONTENTS_TMP_SUFFIX};
+use crate::fs_util;
+use crate::storage::Storage;
+
+/// What was synced with the storage, kept in the data dir and not put in the storage itself.
+pub(super) const SYNC_STATE_FILENAME: &str = ".storage-sync";
+/// Hard links to the files as they were when the sync started, pushed from here while it's still used.
+const SYNC_STAGING_DIR: &str = ".storage-staging";
+/// How often what changed is put in the storage while it's mounted.
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait before linking again, when an operation changed the data dir meanwhile.
+const SYNC_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncState {
+    objects: HashMap<String, Synced>,
+}
+
+/// An object as it was when last synced, locally and in the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Synced {
+    local: (u64, SystemTime),
+    remote: String,
+}
+
+/// Counts the operations that change the data dir, so [`EncryptedFs::sync_storage`] can link the files while none
+/// runs and the storage gets them as they were between two operations. They don't wait for it, so it's fine to
+/// enter it again from inside one.
+#[derive(Debug, Default)]
+pub(super) struct ChangeGate {
+    started: AtomicU64,
+    finished: AtomicU64,
+    idle: Notify,
+}
+
+/// Held while an operation changes the data dir, see [`ChangeGate::enter`].
+pub(super) struct Changing<'a>(&'a ChangeGate);
+
+impl ChangeGate {
+    pub(super) fn enter(&self) -> Changing<'_> {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        Changing(self)
+    }
+
+    /// Wait until no operation runs, returns how many started until then.
+    async fn idle(&self) -> u64 {
+        loop {
+            let idle = self.idle.notified();
+            let started = self.started.load(Ordering::SeqCst);
+            if self.finished.load(Ordering::SeqCst) == started {
+                return started;
+            }
+            idle.await;
+        }
+    }
+
+    /// No operation started since [`ChangeGate::idle`] returned `started`.
+    fn unchanged_since(&self, started: u64) -> bool {
+        self.started.load(Ordering::SeqCst) == started
+    }
+}
+
+impl Drop for Changing<'_> {
+    fn drop(&mut self) {
+        let finished = self.0.finished.fetch_add(1, Ordering::SeqCst) + 1;
+        if finished == self.0.started.load(Ordering::SeqCst) {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl EncryptedFs {
+    /// Put in [`FsOptions::storage`](crate::encryptedfs::FsOptions::storage) what changed in the data dir since the
+    /// last time. It's done in background too, call it to be sure the storage has everything, like before unmount.
+    ///
+    /// The files are hard linked while no operation changes the data dir, then put from the links, so the storage
+    /// gets them as they were between two operations. Don't call it from inside one, it would wait for itself.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn sync_storage(&self) -> FsResult<()> {
+        let Some(storage) = &self.options.storage else {
+            return Ok(());
+        };
+        if self.options.read_only {
+            return Ok(());
+        }
+        let _guard = self.storage_sync_lock.lock().await;
+        let data_dir = self.data_dir.clone();
+        let staged = loop {
+            let started = self.changes.idle().await;
+            let dir = data_dir.clone();
+            let staged = blocking(move || stage(&dir)).await?;
+            if self.changes.unchanged_since(started) {
+                break staged;
+            }
+            // caught an operation halfway, link them again
+            let dir = data_dir.clone();
+            blocking(move || remove_staging(&dir, false)).await?;
+            tokio::time::sleep(SYNC_RETRY_DELAY).await;
+        };
+        let storage = storage.clone();
+        let secure_delete = self.options.secure_delete;
+        blocking(move || {
+            let res = push_storage(&*storage, &data_dir, &staged);
+            remove_staging(&data_dir, secure_delete)?;
+            res
+        })
+        .await
+    }
+
+    pub(super) fn spawn_storage_sync(self: &Arc<Self>) {
+        let fs = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SYNC_INTERVAL).await;
+                let Some(fs) = fs.upgrade() else {
+                    break;
+                };
+                if let Err(err) = fs.sync_storage().await {
+                    error!(err = %err, "syncing storage");
+                }
+            }
+        });
+    }
+}
+
+/// Bring the data dir up to date with what's in the storage, before it's opened. Objects that changed in the storage
+/// replace the local files, the ones removed from it are removed locally too, unless they changed locally meanwhile.
+pub(super) fn pull_storage(storage: &dyn Storage, data_dir: &Path) -> FsResult<()> {
+    fs::create_dir_all(data_dir)?;
+    let mut state = read_sync_state(data_dir)?;
+    let mut pulled = 0;
+    let mut remote = HashSet::new();
+    for object in storage.list("")? {
+        let path = local_path(data_dir, &object.key);
+        remote.insert(object.key.clone());
+        let synced = state.objects.get(&object.key);
+        if path.is_file() && synced.is_some_and(|synced| synced.remote == object.version) {
+            continue;
+        }
+        debug!(key = object.key, "pulling object");
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = fs_util::open_atomic_write(&path)?;
+        storage.get(&object.key, &mut file)?;
+        file.commit()?;
+        state.objects.insert(
+            object.key,
+            Synced {
+                local: local_version(&path)?,
+                remote: object.version,
+            },
+        );
+        pulled += 1;
+    }
+    let removed: Vec<String> = state
+        .objects
+        .keys()
+        .filter(|key| !remote.contains(*key))
+        .cloned()
+        .collect();
+    for key in removed {
+        let synced = state.objects.remove(&key).unwrap();
+        let path = local_path(data_dir, &key);
+        if path.is_file() && local_version(&path)? == synced.local {
+            fs::remove_file(path)?;
+        }
+    }
+    if pulled > 0 {
+        info!(pulled, "pulled data dir from storage");
+    }
+    write_sync_state(data_dir, &state)
+}
+
+/// Run the storage I/O, which blocks, out of the async runtime.
+pub(super) async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> FsResult<T> + Send + 'static,
+) -> FsResult<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| FsError::Other("storage sync task failed"))?
+}
+
+/// Hard link the files to push in [`SYNC_STAGING_DIR`], nothing is changed in place in the data dir, so they keep the
+/// content they had now. Returns their keys with their version. The logs that are appended to are pushed only up to
+/// the length they had now.
+fn stage(data_dir: &Path) -> FsResult<Vec<(String, (u64, SystemTime))>> {
+    // left by a crash
+    remove_staging(data_dir, false)?;
+    let staging = data_dir.join(SYNC_STAGING_DIR);
+    fs::create_dir_all(&staging)?;
+    let mut staged = vec![];
+    let mut stack = vec![(data_dir.to_path_buf(), String::new())];
+    while let Some((dir, key)) = stack.pop() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // temp files of atomic writes and of files open for write, our state and the staging dir
+            if name.starts_with('.') || name.ends_with(CONTENTS_TMP_SUFFIX) {
+                continue;
+            }
+            let key = if key.is_empty() {
+                // snapshots are hard links, they stay local
+                if name == SNAPSHOTS_DIR {
+                    continue;
+                }
+                name
+            } else {
+                format!("{key}/{name}")
+            };
+            if entry.file_type()?.is_dir() {
+                fs::create_dir_all(local_path(&staging, &key))?;
+                stack.push((entry.path(), key));
+                continue;
+            }
+            let path = local_path(&staging, &key);
+            match fs::hard_link(entry.path(), &path) {
+                Ok(()) => {}
+                // removed meanwhile, by something that doesn't enter the gate, like a background task
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            staged.push((key, local_version(&path)?));
+        }
+    }
+    Ok(staged)
+}
+
+/// With `secure_delete` the links to content that was replaced meanwhile are shredded, the others are only removed.
+fn remove_staging(data_dir: &Path, secure_delete: bool) -> FsResult<()> {
+    let staging = data_dir.join(SYNC_STAGING_DIR);
+    if !staging.exists() {
+        return Ok(());
+    }
+    if secure_delete {
+        let mut stack = vec![staging.clone()];
+        while let Some(dir) = stack.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    stack.push(entry.path());
+                } else {
+                    fs_util::shred_and_remove(&entry.path())?;
+                }
+            }
+        }
+    }
+    fs::remove_dir_all(staging)?;
+    Ok(())
+}
+
+/// Put the files that changed since the last time and remove the ones that were removed, from what was staged.
+fn push_storage(
+    storage: &dyn Storage,
+    data_dir: &Path,
+    staged: &[(String, (u64, SystemTime))],
+) -> FsResult<()> {
+    let staging = data_dir.join(SYNC_STAGING_DIR);
+    let mut state = read_sync_state(data_dir)?;
+    let mut pushed = 0;
+    for (key, version) in staged {
+        if state
+            .objects
+            .get(key)
+            .is_some_and(|synced| synced.local == *version)
+        {
+            continue;
+        }
+        debug!(key, "pushing object");
+        let remote = storage.put(
+            key,
+            &mut File::open(local_path(&staging, key))?.take(version.0),
+        )?;
+        state.objects.insert(
+            key.clone(),
+            Synced {
+                local: *version,
+                remote,
+            },
+        );
+        pushed += 1;
+    }
+    let local: HashSet<&String> = staged.iter().map(|(key, _)| key).collect();
+    let removed: Vec<String> = state
+        .objects
+        .keys()
+        .filter(|key| !local.contains(key))
+        .cloned()
+        .collect();
+    for key in &removed {
+        storage.delete(key)?;
+        state.objects.remove(key);
+    }
+    if pushed > 0 || !removed.is_empty() {
+        debug!(pushed, removed = removed.len(), "synced storage");
+    }
+    write_sync_state(data_dir, &state)
+}
+
+fn local_path(data_dir: &Path, key: &str) -> PathBuf {
+    data_dir.join(key.replace('/', std::path::MAIN_SEPARATOR_STR))
+}
+
+/// Taken when the file is staged, if it's replaced after it's pushed again next time.
+fn local_version(path: &Path) -> FsResult<(u64, SystemTime)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+fn read_sync_state(data_dir: &Path) -> FsResult<SyncState> {
+    let path = data_dir.join(SYNC_STATE_FILENAME);
+    if !path.is_file() {
+        return Ok(SyncState::default());
+    }
+    Ok(bincode::deserialize_from(File::open(path)?)?)
+}
+
+fn write_sync_state(data_dir: &Path, state: &SyncState) -> FsResult<()> {
+    let mut file = fs_util::open_atomic_write(&data_dir.join(SYNC_STATE_FILENAME))?;
+    bincode::serialize_into(&mut file, state)?;
+    file.commit()?;
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::PathBuf;
+
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use tokio::sync::MutexGuard;
+use tracing::debug;
+
+use crate::crypto;
+use crate::crypto::read::CryptoReadSeek;
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, StorageLayout, ROOT_INODE,
+    SECURITY_DIR,
+};
+
+const OVERLAY_INDEX_FILENAME: &str = "overlay";
+
+tokio::task_local! {
+    /// Volume and dir being merged by this task, so what it does in that dir doesn't merge it again.
+    static MERGING: (usize, u64);
+}
+
+/// What in the volume came from [`FsOptions::base`](crate::encryptedfs::FsOptions::base).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct OverlayIndex {
+    /// Entries copied from the base, by inode. Files have their content there until they're written.
+    origins: HashMap<u64, Origin>,
+    /// Entries of the base removed or renamed in the volume, as parent and name, so they aren't merged again.
+    whiteouts: HashSet<(u64, String)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Origin {
+    /// Inode in the base.
+    ino: u64,
+    /// Where the entry is in the volume.
+    parent: u64,
+    name: String,
+}
+
+impl EncryptedFs {
+    /// Add to the dir what's in the same dir in the base, the first time it's used since the volume was opened.
+    /// Entries of the volume win over the ones in the base with the same name, except dirs, which are merged.
+    pub(super) async fn merge_dir(&self, ino: u64) -> FsResult<()> {
+        let Some(base) = &self.options.base else {
+            return Ok(());
+        };
+        let merging = (self.overlay_id(), ino);
+        if MERGING.try_with(|key| *key == merging).unwrap_or(false)
+            || self.overlay_merged.lock().unwrap().contains(&ino)
+        {
+            return Ok(());
+        }
+        let lock = self
+            .overlay_merge_locks
+            .get_or_insert_with(ino, || tokio::sync::Mutex::new(false));
+        let _guard = lock.lock().await;
+        if self.overlay_merged.lock().unwrap().contains(&ino) {
+            return Ok(());
+        }
+        let _changing = self.changes.enter();
+        MERGING
+            .scope(merging, Box::pin(self.merge_from_base(base, ino)))
+            .await?;
+        self.overlay_merged.lock().unwrap().insert(ino);
+        Ok(())
+    }
+
+    async fn merge_from_base(&self, base: &Self, ino: u64) -> FsResult<()> {
+        let base_dir = if ino == ROOT_INODE {
+            ROOT_INODE
+        } else {
+            match self.origin(ino).await? {
+                Some(origin) => origin.ino,
+                None => return Ok(()),
+            }
+        };
+        self.remove_vanished(base, ino).await?;
+        if !base.is_dir(base_dir) {
+            return Ok(());
+        }
+        debug!(ino, "merging dir from base");
+        for (base_ino, name) in base.child_entries(base_dir).await? {
+            if self
+                .overlay_index()
+                .await?
+                .as_ref()
+                .unwrap()
+                .whiteouts
+                .contains(&(ino, name.clone()))
+            {
+                continue;
+            }
+            let base_attr = base.get_inode_from_storage(base_ino).await?;
+            let secret_name = SecretString::from(name.clone());
+            if let Some((child, kind, _)) = self.read_hash_entry(ino, &secret_name).await? {
+                match self.origin(child).await? {
+                    Some(origin) if origin.ino == base_ino => {
+                        if kind == FileType::RegularFile {
+                            self.refresh_from_base(child, &base_attr).await?;
+                        }
+                    }
+                    // both are merged
+                    None if kind == FileType::Directory
+                        && base_attr.kind == FileType::Directory =>
+                    {
+                        self.set_origin(
+                            child,
+                            Origin {
+                                ino: base_ino,
+                                parent: ino,
+                                name,
+                            },
+                        )
+                        .await?;
+                    }
+                    // the one in the volume hides it
+                    _ => {}
+                }
+                continue;
+            }
+            let res = self
+                .create(
+                    ino,
+                    &secret_name,
+                    CreateFileAttr {
+                        kind: base_attr.kind,
+                        perm: base_attr.perm,
+                        uid: base_attr.uid,
+                        gid: base_attr.gid,
+                        rdev: base_attr.rdev,
+                        flags: base_attr.flags,
+                    },
+                    false,
+                    false,
+                )
+                .await;
+            let (_, attr) = match res {
+                // not taken from the base either
+                Err(FsError::Excluded) => continue,
+                res => res?,
+            };
+            self.set_origin(
+                attr.ino,
+                Origin {
+                    ino: base_ino,
+                    parent: ino,
+                    name,
+                },
+            )
+            .await?;
+            self.refresh_from_base(attr.ino, &base_attr).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove what was copied from the base and was removed from there since, unless it was changed in the volume.
+    async fn remove_vanished(&self, base: &Self, ino: u64) -> FsResult<()> {
+        let vanished: Vec<(u64, Origin)> = self
+            .overlay_index()
+            .await?
+            .as_ref()
+            .unwrap()
+            .origins
+            .iter()
+            .filter(|(_, origin)| origin.parent == ino && !base.exists(origin.ino))
+            .map(|(child, origin)| (*child, origin.clone()))
+            .collect();
+        for (child, origin) in vanished {
+            debug!(ino = child, "removed from base");
+            let name = SecretString::from(origin.name);
+            if self.is_dir(child) {
+                // before what's in it, so it's not merged again meanwhile
+                self.remove_origin(child).await?;
+                Box::pin(self.remove_vanished(base, child)).await?;
+                if self.len(child).await? == 0 {
+                    self.remove_dir(ino, &name).await?;
+                }
+            } else {
+                self.remove_file(ino, &name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size and times of the file as in the base, if it changed there. Owner and permissions are kept.
+    async fn refresh_from_base(&self, ino: u64, base_attr: &FileAttr) -> FsResult<()> {
+        let mut attr = self.get_inode_from_storage(ino).await?;
+        if attr.size == base_attr.size && attr.mtime == base_attr.mtime {
+            return Ok(());
+        }
+        attr.size = base_attr.size;
+        attr.atime = base_attr.atime;
+        attr.mtime = base_attr.mtime;
+        attr.ctime = base_attr.ctime;
+        attr.crtime = base_attr.crtime;
+        self.write_inode_to_storage(&attr).await
+    }
+
+    /// If the content of the file is still in the base.
+    pub(super) async fn has_base_content(&self, ino: u64) -> FsResult<bool> {
+        Ok(self.origin(ino).await?.is_some_and(|_| !self.is_dir(ino)))
+    }
+
+    /// Reader of the content in the base, if it's still there.
+    pub(super) async fn base_content_reader(
+        &self,
+        ino: u64,
+    ) -> FsResult<Option<Box<dyn CryptoReadSeek<File>>>> {
+        let Some(origin) = self.origin(ino).await?.filter(|_| !self.is_dir(ino)) else {
+            return Ok(None);
+        };
+        let Some(base) = &self.options.base else {
+            return Err(FsError::InvalidInput(
+                "content is in the base volume, open it with its base",
+            ));
+        };
+        Ok(Some(Box::pin(base.content_reader(origin.ino)).await?))
+    }
+
+    /// Call it after the content of the file was written in the volume, it's not read from the base anymore.
+    pub(super) async fn content_copied_up(&self, ino: u64) -> FsResult<()> {
+        if self.has_overlay() {
+            self.remove_origin(ino).await?;
+        }
+        Ok(())
+    }
+
+    /// Call it after the entry was removed, so if it's in the base it's not merged again.
+    pub(super) async fn overlay_removed(
+        &self,
+        parent: u64,
+        name: &SecretString,
+        ino: u64,
+    ) -> FsResult<()> {
+        if !self.has_overlay() {
+            return Ok(());
+        }
+        let mut guard = self.overlay_index().await?;
+        let index = guard.as_mut().unwrap();
+        let mut changed = index.origins.remove(&ino).is_some();
+        changed |= self.add_whiteout(index, parent, name).await?;
+        if changed {
+            self.write_overlay_index(index).await?;
+        }
+        Ok(())
+    }
+
+    /// Call it after the entry was renamed, `replaced` is what was at the new name before.
+    pub(super) async fn overlay_renamed(
+        &self,
+        (parent, name): (u64, &SecretString),
+        (new_parent, new_name): (u64, &SecretString),
+        ino: u64,
+        replaced: Option<u64>,
+    ) -> FsResult<()> {
+        if !self.has_overlay() {
+            return Ok(());
+        }
+        let mut guard = self.overlay_index().await?;
+        let index = guard.as_mut().unwrap();
+        let mut changed = self.add_whiteout(index, parent, name).await?;
+        if let Some(replaced) = replaced {
+            changed |= index.origins.remove(&replaced).is_some();
+        }
+        if let Some(origin) = index.origins.get_mut(&ino) {
+            origin.parent = new_parent;
+            origin.name.clone_from(new_name.expose_secret());
+            changed = true;
+        }
+        if changed {
+            self.write_overlay_index(index).await?;
+        }
+        Ok(())
+    }
+
+    /// If the base has an entry with that name in the same dir. Not while merging, what's removed then is gone
+    /// from the base already.
+    async fn add_whiteout(
+        &self,
+        index: &mut OverlayIndex,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<bool> {
+        let Some(base) = &self.options.base else {
+            return Ok(false);
+        };
+        if MERGING
+            .try_with(|(id, _)| *id == self.overlay_id())
+            .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+        let base_parent = if parent == ROOT_INODE {
+            ROOT_INODE
+        } else {
+            match index.origins.get(&parent) {
+                Some(origin) => origin.ino,
+                None => return Ok(false),
+            }
+        };
+        if !base.is_dir(base_parent) || base.find_by_name(base_parent, name).await?.is_none() {
+            return Ok(false);
+        }
+        Ok(index
+            .whiteouts
+            .insert((parent, name.expose_secret().clone())))
+    }
+
+    async fn origin(&self, ino: u64) -> FsResult<Option<Origin>> {
+        if !self.has_overlay() {
+            return Ok(None);
+        }
+        Ok(self
+            .overlay_index()
+            .await?
+            .as_ref()
+            .unwrap()
+            .origins
+            .get(&ino)
+            .cloned())
+    }
+
+    async fn set_origin(&self, ino: u64, origin: Origin) -> FsResult<()> {
+        let mut guard = self.overlay_index().await?;
+        let index = guard.as_mut().unwrap();
+        index.origins.insert(ino, origin);
+        self.write_overlay_index(index).await
+    }
+
+    async fn remove_origin(&self, ino: u64) -> FsResult<()> {
+        let mut guard = self.overlay_index().await?;
+        let index = guard.as_mut().unwrap();
+        if index.origins.remove(&ino).is_some() {
+            self.write_overlay_index(index).await?;
+        }
+        Ok(())
+    }
+
+    /// If it has a base now or had one before, then some files may have their content there.
+    fn has_overlay(&self) -> bool {
+        self.options.base.is_some() || self.overlay_index_path().is_file()
+    }
+
+    /// Loaded the first time it's needed.
+    async fn overlay_index(&self) -> FsResult<MutexGuard<'_, Option<OverlayIndex>>> {
+        let mut guard = self.overlay_index.lock().await;
+        if guard.is_none() {
+            let path = self.overlay_index_path();
+            *guard = Some(if path.is_file() {
+                self.deserialize_from_file(&path).await?
+            } else {
+                OverlayIndex::default()
+            });
+        }
+        Ok(guard)
+    }
+
+    async fn write_overlay_index(&self, index: &OverlayIndex) -> FsResult<()> {
+        crypto::atomic_serialize_encrypt_into(
+            &self.overlay_index_path(),
+            index,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    /// Save the index with the current key or cipher, after they changed.
+    pub(super) async fn rewrite_overlay_index(&self) -> FsResult<()> {
+        if !self.overlay_index_path().is_file() {
+            return Ok(());
+        }
+        let mut guard = self.overlay_index().await?;
+        self.write_overlay_index(guard.as_mut().unwrap()).await
+    }
+
+    fn overlay_index_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self
+                .data_dir
+                .join(SECURITY_DIR)
+                .join(OVERLAY_INDEX_FILENAME),
+            StorageLayout::Flat => self.object_path("overlay"),
+        }
+    }
+
+    /// Tells volumes apart while merging, a base is merged while its overlay is.
+    fn overlay_id(&self) -> usize {
+        std::ptr::from_ref(self) as usize
+    }
+}
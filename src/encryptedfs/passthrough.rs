@@ -0,0 +1,377 @@
+use std::collections::HashSet;
+use std::fmt::{self, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+use tokio::sync::MutexGuard;
+use tracing::debug;
+
+use crate::crypto;
+use crate::crypto::read::{CryptoRead, CryptoReadSeek};
+use crate::crypto::write::{CryptoWrite, CryptoWriteSeek};
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    EncryptedFs, FsError, FsOptions, FsResult, StaticPasswordProvider, StorageLayout, ROOT_INODE,
+    SECURITY_DIR,
+};
+
+const PASSTHROUGH_FILENAME: &str = "passthrough";
+
+/// What's done with the files matched by a [`PassthroughRule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+#[allow(clippy::module_name_repetitions)]
+pub enum PassthroughAction {
+    /// The content is stored without encryption, names and metadata are still encrypted.
+    Plaintext,
+    /// Files and dirs can't be created there.
+    Exclude,
+}
+
+/// Trade confidentiality for speed on files that don't need it, like caches. `pattern` is matched against the path
+/// from root, like `.cache/**`, or against the name if it has no `/`, like `*.tmp`. `*` matches anything in a name,
+/// `?` one character and `**` any number of dirs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::module_name_repetitions)]
+pub struct PassthroughRule {
+    pub pattern: String,
+    pub action: PassthroughAction,
+}
+
+impl fmt::Display for PassthroughRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}", self.pattern, self.action)
+    }
+}
+
+impl FromStr for PassthroughRule {
+    type Err = FsError;
+
+    /// `PATTERN` or `PATTERN:ACTION`, the action is plaintext if not given.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, action) = match s.rsplit_once(':') {
+            Some((pattern, action)) => (
+                pattern,
+                PassthroughAction::from_str(action)
+                    .map_err(|_| FsError::InvalidInput("action is plaintext or exclude"))?,
+            ),
+            None => (s, PassthroughAction::Plaintext),
+        };
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return Err(FsError::InvalidInput("pattern is empty"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            action,
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct Passthrough {
+    rules: Vec<PassthroughRule>,
+    /// Files matched by a plaintext rule when they were created, their content stays so.
+    plaintext: HashSet<u64>,
+}
+
+impl EncryptedFs {
+    /// Rules of the volume, the first one that matches applies.
+    #[allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+    pub async fn passthrough_rules(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<Vec<PassthroughRule>> {
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        let rules = fs.passthrough().await?.as_ref().unwrap().rules.clone();
+        Ok(rules)
+    }
+
+    /// Replace the rules of the volume, used from the next time it's opened. They apply to files created after,
+    /// the ones already there are kept as they are.
+    #[allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+    pub async fn set_passthrough_rules(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        rules: Vec<PassthroughRule>,
+    ) -> FsResult<()> {
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default(),
+            None,
+            false,
+        )
+        .await?;
+        let mut guard = fs.passthrough().await?;
+        let passthrough = guard.as_mut().unwrap();
+        passthrough.rules = rules;
+        fs.write_passthrough(passthrough).await
+    }
+
+    /// Action of the first rule matching `name` in `parent`, if any.
+    pub(super) async fn passthrough_action(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<Option<PassthroughAction>> {
+        let rules = self.passthrough().await?.as_ref().unwrap().rules.clone();
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        let mut path = self.dir_path(parent).await?;
+        path.push(name.expose_secret().clone());
+        Ok(rules
+            .iter()
+            .find(|rule| matches_path(&rule.pattern, &path))
+            .map(|rule| rule.action))
+    }
+
+    /// Fails with [`FsError::Excluded`] if an entry can't be created there.
+    pub(super) async fn check_not_excluded(
+        &self,
+        parent: u64,
+        name: &SecretString,
+    ) -> FsResult<()> {
+        if self.passthrough_action(parent, name).await? == Some(PassthroughAction::Exclude) {
+            return Err(FsError::Excluded);
+        }
+        Ok(())
+    }
+
+    pub(super) async fn is_plaintext(&self, ino: u64) -> FsResult<bool> {
+        Ok(self
+            .passthrough()
+            .await?
+            .as_ref()
+            .unwrap()
+            .plaintext
+            .contains(&ino))
+    }
+
+    /// Call it when the file is created, before its content is written.
+    pub(super) async fn set_plaintext(&self, ino: u64) -> FsResult<()> {
+        debug!(ino, "content is stored in plaintext");
+        let mut guard = self.passthrough().await?;
+        let passthrough = guard.as_mut().unwrap();
+        passthrough.plaintext.insert(ino);
+        self.write_passthrough(passthrough).await
+    }
+
+    /// Call it after the file was removed.
+    pub(super) async fn passthrough_removed(&self, ino: u64) -> FsResult<()> {
+        let mut guard = self.passthrough().await?;
+        let passthrough = guard.as_mut().unwrap();
+        if passthrough.plaintext.remove(&ino) {
+            self.write_passthrough(passthrough).await?;
+        }
+        Ok(())
+    }
+
+    /// Sizes of plaintext files are not hidden anyway.
+    pub(super) async fn pads(&self, ino: u64) -> FsResult<bool> {
+        Ok(self.options.pad_file_sizes && !self.is_plaintext(ino).await?)
+    }
+
+    /// Writer of new content of the file, which encrypts it unless it's a plaintext one.
+    pub(super) async fn content_writer<W: Write + Send + Sync + 'static>(
+        &self,
+        ino: u64,
+        w: W,
+    ) -> FsResult<Box<dyn CryptoWrite<W>>> {
+        if self.is_plaintext(ino).await? {
+            return Ok(Box::new(Plain(Some(w))));
+        }
+        Ok(Box::new(crypto::create_write(
+            w,
+            self.cipher,
+            &*self.file_key(ino).await?,
+        )))
+    }
+
+    pub(super) async fn content_writer_seek(
+        &self,
+        ino: u64,
+        file: File,
+    ) -> FsResult<Box<dyn CryptoWriteSeek<File>>> {
+        if self.is_plaintext(ino).await? {
+            return Ok(Box::new(Plain(Some(file))));
+        }
+        Ok(Box::new(crypto::create_write_seek(
+            file,
+            self.cipher,
+            &*self.file_key(ino).await?,
+        )))
+    }
+
+    /// Reader of the content, if it's a plaintext file.
+    pub(super) async fn plaintext_reader(
+        &self,
+        ino: u64,
+    ) -> FsResult<Option<Box<dyn CryptoReadSeek<File>>>> {
+        if !self.is_plaintext(ino).await? {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(Plain(Some(File::open(
+            self.contents_path(ino),
+        )?)))))
+    }
+
+    /// Save the rules with the current key or cipher, after they changed.
+    pub(super) async fn rewrite_passthrough(&self) -> FsResult<()> {
+        if !self.passthrough_path().is_file() {
+            return Ok(());
+        }
+        let mut guard = self.passthrough().await?;
+        self.write_passthrough(guard.as_mut().unwrap()).await
+    }
+
+    /// Names from root, found by following the `..` entries up.
+    async fn dir_path(&self, ino: u64) -> FsResult<Vec<String>> {
+        let mut names = vec![];
+        let mut current = ino;
+        while current != ROOT_INODE {
+            let parent = self
+                .find_by_name(current, &SecretString::from_str("..").unwrap())
+                .await?
+                .ok_or(FsError::InodeNotFound)?
+                .ino;
+            let (_, name) = self
+                .child_entries(parent)
+                .await?
+                .into_iter()
+                .find(|(child, _)| *child == current)
+                .ok_or(FsError::InodeNotFound)?;
+            names.push(name);
+            current = parent;
+        }
+        names.reverse();
+        Ok(names)
+    }
+
+    /// Loaded the first time it's needed.
+    async fn passthrough(&self) -> FsResult<MutexGuard<'_, Option<Passthrough>>> {
+        let mut guard = self.passthrough.lock().await;
+        if guard.is_none() {
+            let path = self.passthrough_path();
+            *guard = Some(if path.is_file() {
+                self.deserialize_from_file(&path).await?
+            } else {
+                Passthrough::default()
+            });
+        }
+        Ok(guard)
+    }
+
+    async fn write_passthrough(&self, passthrough: &Passthrough) -> FsResult<()> {
+        crypto::atomic_serialize_encrypt_into(
+            &self.passthrough_path(),
+            passthrough,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    fn passthrough_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => {
+                self.data_dir.join(SECURITY_DIR).join(PASSTHROUGH_FILENAME)
+            }
+            StorageLayout::Flat => self.object_path(PASSTHROUGH_FILENAME),
+        }
+    }
+}
+
+/// Patterns without `/` match the name, else the whole path.
+fn matches_path(pattern: &str, path: &[String]) -> bool {
+    if !pattern.contains('/') {
+        return path
+            .last()
+            .is_some_and(|name| matches_name(pattern.as_bytes(), name.as_bytes()));
+    }
+    let pattern: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    matches_segments(&pattern, &path)
+}
+
+fn matches_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| {
+            matches_name(first.as_bytes(), name.as_bytes()) && matches_segments(rest, path)
+        }),
+    }
+}
+
+fn matches_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches_name(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_name(rest, &name[1..]),
+    }
+}
+
+/// Content of plaintext files is read and written as it is.
+struct Plain<T>(Option<T>);
+
+impl<T> Plain<T> {
+    fn inner(&mut self) -> io::Result<&mut T> {
+        self.0
+            .as_mut()
+            .ok_or_else(|| io::Error::other("already finished"))
+    }
+}
+
+impl<R: Read> Read for Plain<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner()?.read(buf)
+    }
+}
+
+impl<T: Seek> Seek for Plain<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner()?.seek(pos)
+    }
+}
+
+impl<W: Write> Write for Plain<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner()?.flush()
+    }
+}
+
+impl<R: Read + Send + Sync> CryptoRead<R> for Plain<R> {
+    fn into_inner(&mut self) -> R {
+        self.0.take().unwrap()
+    }
+}
+
+impl<R: Read + Seek + Send + Sync> CryptoReadSeek<R> for Plain<R> {}
+
+impl<W: Write + Send + Sync> CryptoWrite<W> for Plain<W> {
+    fn finish(&mut self) -> io::Result<W> {
+        let mut w = self
+            .0
+            .take()
+            .ok_or_else(|| io::Error::other("already finished"))?;
+        w.flush()?;
+        Ok(w)
+    }
+}
+
+impl<W: Write + Seek + Send + Sync> CryptoWriteSeek<W> for Plain<W> {}
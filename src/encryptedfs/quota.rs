@@ -0,0 +1,62 @@
+use tracing::debug;
+
+use crate::encryptedfs::{EncryptedFs, FileType, FsError, FsResult};
+
+/// See [`EncryptedFs::quota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// As in [`FsOptions::max_size`](crate::encryptedfs::FsOptions::max_size).
+    pub max_size: u64,
+    /// Sum of the sizes of all files.
+    pub used: u64,
+}
+
+impl EncryptedFs {
+    /// The limit and what's used of it, if there is a [`FsOptions::max_size`](crate::encryptedfs::FsOptions::max_size).
+    #[allow(clippy::missing_panics_doc)]
+    pub fn quota(&self) -> Option<Quota> {
+        let max_size = self.options.max_size?;
+        let used = self.quota_used.lock().unwrap().unwrap_or_default();
+        Some(Quota { max_size, used })
+    }
+
+    /// Call before a file changes size. Fails with [`FsError::QuotaExceeded`] if it grows over the quota, shrinking
+    /// is always allowed.
+    pub(super) fn resize_usage(&self, old_size: u64, new_size: u64) -> FsResult<()> {
+        let Some(max_size) = self.options.max_size else {
+            return Ok(());
+        };
+        let mut guard = self.quota_used.lock().unwrap();
+        // still counting, what's changed meanwhile is counted as it is after
+        let Some(used) = guard.as_mut() else {
+            return Ok(());
+        };
+        if new_size > old_size {
+            let grown = *used + (new_size - old_size);
+            if grown > max_size {
+                return Err(FsError::QuotaExceeded(max_size));
+            }
+            *used = grown;
+        } else {
+            *used = used.saturating_sub(old_size - new_size);
+        }
+        Ok(())
+    }
+
+    /// Add up the sizes of all files, when it's opened.
+    pub(super) async fn count_usage(&self) -> FsResult<()> {
+        if self.options.max_size.is_none() {
+            return Ok(());
+        }
+        let mut used = 0;
+        for ino in self.walk_tree().await? {
+            let attr = self.get_inode_from_storage(ino).await?;
+            if attr.kind == FileType::RegularFile {
+                used += attr.size;
+            }
+        }
+        debug!(used, "counted usage");
+        *self.quota_used.lock().unwrap() = Some(used);
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use secrecy::ExposeSecret;
+use tracing::{info, warn};
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult, StaticPasswordProvider,
+    StorageLayout, ROOT_INODE,
+};
+
+/// Where files not reachable from root are put by [`EncryptedFs::recover`], named by their inode.
+const LOST_FOUND_DIR: &str = "lost+found";
+
+#[derive(Debug, Default)]
+pub struct RecoverReport {
+    pub dirs: u64,
+    pub files: u64,
+    /// Chunks that couldn't be decrypted, they are filled with zeros.
+    pub bad_chunks: u64,
+    /// Entries that couldn't be read at all, with what's under them.
+    pub skipped: u64,
+}
+
+impl EncryptedFs {
+    /// Copy all that still decrypts from a corrupted data dir into `dest`, which must be empty, without changing the
+    /// data dir. Bad chunks of a file are filled with zeros, so the rest of it keeps its offsets, and the entries
+    /// that can't be read are skipped. Everything is logged.
+    ///
+    /// With [`StorageLayout::Hierarchical`], files not reachable from root are put in `lost+found`.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn recover(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        dest: &Path,
+    ) -> FsResult<RecoverReport> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(FsError::InvalidInput("destination is not empty"));
+        }
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default().with_read_only(true),
+            None,
+            false,
+        )
+        .await?;
+        fs::create_dir_all(dest)?;
+        info!(dest = %dest.display(), "recovering data dir");
+        let mut report = RecoverReport::default();
+        let visited = fs.recover_tree(dest, &mut report).await?;
+        if fs.layout == StorageLayout::Hierarchical {
+            fs.recover_orphans(&visited, &dest.join(LOST_FOUND_DIR), &mut report)
+                .await?;
+        }
+        info!(
+            files = report.files,
+            bad_chunks = report.bad_chunks,
+            skipped = report.skipped,
+            "recovery finished"
+        );
+        Ok(report)
+    }
+
+    /// Copy all that's reachable from root and return the inodes found.
+    async fn recover_tree(
+        &self,
+        dest: &Path,
+        report: &mut RecoverReport,
+    ) -> FsResult<HashSet<u64>> {
+        let mut visited = HashSet::from([ROOT_INODE]);
+        let mut queue = VecDeque::from([(ROOT_INODE, dest.to_path_buf())]);
+        while let Some((ino, path)) = queue.pop_front() {
+            report.dirs += 1;
+            let Ok(entries) = self.raw_dir_entries(ino).await else {
+                warn!(ino, "skipping directory that can't be listed");
+                report.skipped += 1;
+                continue;
+            };
+            for entry in entries {
+                let Ok(entry) = self.create_directory_entry(entry).await else {
+                    warn!(parent = ino, "skipping entry that can't be read");
+                    report.skipped += 1;
+                    continue;
+                };
+                let name = entry.name.expose_secret();
+                if name == "." || name == ".." || !visited.insert(entry.ino) {
+                    continue;
+                }
+                let Ok(attr) = self.get_inode_from_storage(entry.ino).await else {
+                    warn!(ino = entry.ino, "skipping inode that can't be read");
+                    report.skipped += 1;
+                    continue;
+                };
+                let entry_path = path.join(entry_file_name(name, entry.ino));
+                if attr.kind == FileType::Directory {
+                    fs::create_dir(&entry_path)?;
+                    queue.push_back((entry.ino, entry_path));
+                } else {
+                    self.recover_file(&attr, &entry_path, report).await?;
+                }
+            }
+        }
+        Ok(visited)
+    }
+
+    async fn recover_orphans(
+        &self,
+        visited: &HashSet<u64>,
+        dest: &Path,
+        report: &mut RecoverReport,
+    ) -> FsResult<()> {
+        for ino in self.orphans(visited)? {
+            // directories are left out, what was in them is an orphan too
+            let Ok(attr) = self.get_inode_from_storage(ino).await else {
+                continue;
+            };
+            if attr.kind != FileType::RegularFile {
+                continue;
+            }
+            fs::create_dir_all(dest)?;
+            self.recover_file(&attr, &dest.join(ino.to_string()), report)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Copy the content chunk by chunk, so a bad one doesn't stop us.
+    async fn recover_file(
+        &self,
+        attr: &FileAttr,
+        dest: &Path,
+        report: &mut RecoverReport,
+    ) -> FsResult<()> {
+        let path = self.contents_path(attr.ino);
+        match self.file_key(attr.ino).await {
+            Ok(_) if path.is_file() || attr.size == 0 => {}
+            _ => {
+                warn!(ino = attr.ino, "skipping file without a readable content");
+                report.skipped += 1;
+                return Ok(());
+            }
+        }
+        let mut out = File::create(dest)?;
+        let mut reader = None;
+        let mut buf = vec![0; BLOCK_SIZE];
+        for block in 0..attr.size.div_ceil(BLOCK_SIZE as u64) {
+            let start = block * BLOCK_SIZE as u64;
+            #[allow(clippy::cast_possible_truncation)]
+            let buf = &mut buf[..(attr.size - start).min(BLOCK_SIZE as u64) as usize];
+            if reader.is_none() {
+                reader = Some(self.content_reader(attr.ino).await?);
+            }
+            let current = reader.as_mut().unwrap();
+            let res = current
+                .seek(SeekFrom::Start(start))
+                .and_then(|_| current.read_exact(buf));
+            if let Err(err) = res {
+                warn!(ino = attr.ino, block, err = %err, "filling bad chunk with zeros");
+                report.bad_chunks += 1;
+                buf.fill(0);
+                // start over after it
+                reader = None;
+            }
+            out.write_all(buf)?;
+        }
+        out.sync_all()?;
+        report.files += 1;
+        Ok(())
+    }
+}
+
+/// Names are not ours to trust, they come from a corrupted data dir.
+fn entry_file_name(name: &str, ino: u64) -> PathBuf {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        PathBuf::from(ino.to_string())
+    } else {
+        PathBuf::from(name)
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::crypto;
+use crate::crypto::write::BLOCK_SIZE;
+use crate::encryptedfs::{EncryptedFs, FsResult};
+use crate::stream_util;
+
+/// Pause between two passes over the whole data dir.
+const SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl EncryptedFs {
+    /// Read and authenticate all chunks in background, at most `bandwidth` bytes per second, so bit rot is found
+    /// before someone reads the file. Bad chunks go to the corruption log, see [`EncryptedFs::corruption_log`].
+    ///
+    /// It pauses while locked or while a key rotation is in progress.
+    pub(super) fn spawn_scrub(self: &Arc<Self>, bandwidth: u64) {
+        let fs = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let inodes = {
+                    let Some(fs) = fs.upgrade() else {
+                        break;
+                    };
+                    info!(bandwidth, "scrubbing");
+                    match fs.walk_tree().await {
+                        Ok(inodes) => inodes,
+                        Err(err) => {
+                            error!(err = %err, "scrubbing");
+                            vec![]
+                        }
+                    }
+                };
+                let mut bad_chunks = 0;
+                for ino in inodes {
+                    // don't keep it alive while we wait
+                    loop {
+                        let Some(fs) = fs.upgrade() else {
+                            return;
+                        };
+                        if !fs.is_locked() && !fs.is_key_rotation_in_progress() {
+                            break;
+                        }
+                        drop(fs);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    let Some(fs) = fs.upgrade() else {
+                        return;
+                    };
+                    if fs.is_dir(ino) || !fs.contents_path(ino).is_file() {
+                        continue;
+                    }
+                    match fs.scrub_file(ino, bandwidth).await {
+                        Ok(bad) => bad_chunks += bad,
+                        // removed meanwhile
+                        Err(err) => warn!(ino, err = %err, "can't scrub file"),
+                    }
+                }
+                info!(bad_chunks, "scrub finished");
+                tokio::time::sleep(SCRUB_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Returns the number of bad chunks found. It reads until the end of the content, not the size from the inode
+    /// which might not be updated yet.
+    pub(super) async fn scrub_file(&self, ino: u64, bandwidth: u64) -> FsResult<u64> {
+        // nothing to authenticate
+        if self.is_plaintext(ino).await? {
+            return Ok(0);
+        }
+        let key = self.file_key(ino).await?;
+        // content is replaced by rename, so we read the same version until the end
+        let file = File::open(self.contents_path(ino))?;
+        // the plaintext is shorter
+        let max_len = file.metadata()?.len();
+        let mut reader = crypto::create_read_seek(file.try_clone()?, self.cipher, &key);
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut bad_chunks = 0;
+        let mut chunk = 0;
+        loop {
+            let start = chunk * BLOCK_SIZE as u64;
+            if start >= max_len {
+                break;
+            }
+            let res = reader
+                .seek(SeekFrom::Start(start))
+                .and_then(|_| stream_util::read(&mut reader, &mut buf));
+            let len = match res {
+                Ok(len) => len,
+                Err(err) => {
+                    warn!(ino, chunk, err = %err, "bad chunk found by scrub");
+                    bad_chunks += 1;
+                    self.log_corruption(ino, start, 1).await;
+                    // start over after it
+                    reader = crypto::create_read_seek(file.try_clone()?, self.cipher, &key);
+                    BLOCK_SIZE
+                }
+            };
+            #[allow(clippy::cast_precision_loss)]
+            tokio::time::sleep(Duration::from_secs_f64(len as f64 / bandwidth as f64)).await;
+            if len < BLOCK_SIZE {
+                break;
+            }
+            chunk += 1;
+        }
+        Ok(bad_chunks)
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tracing::info;
+
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::dir_lock::LOCK_FILENAME;
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult};
+use crate::fs_util;
+
+/// Where snapshots are kept in the data dir, each one is a data dir by itself.
+pub(super) const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: SystemTime,
+}
+
+impl EncryptedFs {
+    /// Freeze the data dir as it is now under `name`. Files are hard linked, not copied, as nothing in the data dir
+    /// is changed in place, a change writes a new file that's renamed over the old one. So only what changes after
+    /// takes space.
+    ///
+    /// Take it while not mounted, else it might catch a change halfway. It keeps the password it had, key rotations
+    /// and password changes after don't apply to it.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn create_snapshot(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        name: &str,
+    ) -> FsResult<()> {
+        check_snapshot_name(name)?;
+        // only to check the password
+        Self::open_read_only(data_dir, password, cipher).await?;
+        let path = Self::snapshot_path(data_dir, name);
+        if path.exists() {
+            return Err(FsError::AlreadyExists);
+        }
+        info!(name, "creating snapshot");
+        // so an interrupted one is not taken for a snapshot
+        let partial = data_dir
+            .join(SNAPSHOTS_DIR)
+            .join(format!(".{name}.partial"));
+        if partial.exists() {
+            fs::remove_dir_all(&partial)?;
+        }
+        fs::create_dir_all(&partial)?;
+        for entry in fs::read_dir(data_dir)? {
+            let entry = entry?;
+            // a link to the lock would be the same lock, a snapshot couldn't be mounted while this is
+            if entry.file_name() == SNAPSHOTS_DIR || entry.file_name() == LOCK_FILENAME {
+                continue;
+            }
+            let dst = partial.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                fs_util::link_dir_all(&entry.path(), &dst)?;
+            } else {
+                fs::hard_link(entry.path(), dst)?;
+            }
+        }
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Oldest first.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn snapshots(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<Vec<Snapshot>> {
+        Self::open_read_only(data_dir, password, cipher).await?;
+        let dir = data_dir.join(SNAPSHOTS_DIR);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(ToString::to_string) else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            snapshots.push(Snapshot {
+                name,
+                created: entry.metadata()?.modified()?,
+            });
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.created);
+        Ok(snapshots)
+    }
+
+    /// The space of what changed since is reclaimed.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn delete_snapshot(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        name: &str,
+    ) -> FsResult<()> {
+        check_snapshot_name(name)?;
+        Self::open_read_only(data_dir, password, cipher).await?;
+        let path = Self::snapshot_path(data_dir, name);
+        if !path.is_dir() {
+            return Err(FsError::NotFound("snapshot"));
+        }
+        info!(name, "deleting snapshot");
+        fs::remove_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Data dir of the snapshot, mount it read-only to browse it.
+    #[must_use]
+    pub fn snapshot_path(data_dir: &Path, name: &str) -> PathBuf {
+        data_dir.join(SNAPSHOTS_DIR).join(name)
+    }
+}
+
+fn check_snapshot_name(name: &str) -> FsResult<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+        return Err(FsError::InvalidInput("invalid snapshot name"));
+    }
+    Ok(())
+}
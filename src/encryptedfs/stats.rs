@@ -0,0 +1,85 @@
+use crate::encryptedfs::{EncryptedFs, FsResult};
+
+/// What's going on in an open volume, see [`EncryptedFs::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsStats {
+    /// Open for reading, a file opened for both counts in both.
+    pub read_handles: usize,
+    pub write_handles: usize,
+    /// Files and dirs in the volume.
+    pub inodes: usize,
+    /// Entries in each cache now, by the name it has in [`crate::metrics`].
+    pub caches: Vec<CacheOccupancy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheOccupancy {
+    pub name: &'static str,
+    pub len: usize,
+    /// How many it can hold, the least recently used ones are removed after.
+    pub cap: usize,
+}
+
+impl EncryptedFs {
+    /// Handles, inodes and caches now. The inodes are counted by walking the tree, so it takes a while on big
+    /// volumes.
+    pub async fn stats(&self) -> FsResult<FsStats> {
+        let read_handles = self.read_handles.read().await.len();
+        let write_handles = self.write_handles.read().await.len();
+        let inodes = self.walk_tree().await?.len();
+
+        let mut caches = vec![];
+        {
+            let lock = self.attr_cache.get().await?;
+            let cache = lock.read().await;
+            caches.push(CacheOccupancy {
+                name: "attr",
+                len: cache.len(),
+                cap: cache.cap().get(),
+            });
+        }
+        {
+            let lock = self.dir_entries_name_cache.get().await?;
+            let cache = lock.lock().await;
+            caches.push(CacheOccupancy {
+                name: "dir_entry_name",
+                len: cache.len(),
+                cap: cache.cap().get(),
+            });
+        }
+        {
+            let lock = self.dir_entries_meta_cache.get().await?;
+            let cache = lock.lock().await;
+            caches.push(CacheOccupancy {
+                name: "dir_entry_meta",
+                len: cache.len(),
+                cap: cache.cap().get(),
+            });
+        }
+        {
+            let lock = self.dir_entries_hash_cache.get().await?;
+            let cache = lock.lock().await;
+            caches.push(CacheOccupancy {
+                name: "dir_entry_hash",
+                len: cache.len(),
+                cap: cache.cap().get(),
+            });
+        }
+        {
+            let lock = self.file_keys_cache.get().await?;
+            let cache = lock.lock().await;
+            caches.push(CacheOccupancy {
+                name: "file_key",
+                len: cache.len(),
+                cap: cache.cap().get(),
+            });
+        }
+
+        Ok(FsStats {
+            read_handles,
+            write_handles,
+            inodes,
+            caches,
+        })
+    }
+}
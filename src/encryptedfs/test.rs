@@ -1,19 +1,41 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use rand_core::RngCore;
 use secrecy::{ExposeSecret, SecretString};
 use tracing_test::traced_test;
 
+use crate::crypto::write::{CryptoWrite, BLOCK_SIZE};
+use crate::crypto::{Cipher, KdfParams, LockedString};
+use crate::encryptedfs::dedup::{CHUNKS_DIR, DEDUP_DIR};
+use crate::encryptedfs::journal::JournalOp;
+use crate::encryptedfs::shred_link_path;
+use crate::encryptedfs::upgrade::{read_format_version, FORMAT_VERSION_FILENAME};
 use crate::encryptedfs::write_all_bytes_to_fs;
+use crate::encryptedfs::write_all_string_to_fs;
+use crate::encryptedfs::CIPHER_MIGRATION_FILENAME;
 use crate::encryptedfs::HASH_DIR;
 use crate::encryptedfs::INODES_DIR;
 use crate::encryptedfs::KEY_ENC_FILENAME;
 use crate::encryptedfs::KEY_SALT_FILENAME;
 use crate::encryptedfs::SECURITY_DIR;
+use crate::encryptedfs::{padded_size, FORMAT_VERSION, PADDING_MIN_SIZE};
 use crate::encryptedfs::{
-    DirectoryEntry, DirectoryEntryPlus, FileType, FsError, FsResult, CONTENTS_DIR, ROOT_INODE,
+    AuditEvent, AuditTarget, BenchmarkSettings, CheckIssue, DirectoryEntry, DirectoryEntryPlus,
+    FileType, FsError, FsOptions, FsResult, CONTENTS_DIR, ROOT_INODE,
 };
+use crate::encryptedfs::{
+    EncryptedFs, FileAttr, IdMap, KeySlotKind, PassthroughRule, PasswordProvider, SetFileAttr,
+    StorageLayout, VerifyIssue, OBJECTS_DIR,
+};
+use crate::storage::{LocalStorage, Storage};
 use crate::test_common::run_test;
+use crate::test_common::run_test_with_options;
 use crate::test_common::TestSetup;
 use crate::test_common::{create_attr, get_fs};
 use crate::{crypto, test_common};
@@ -162,6 +184,59 @@ async fn test_write() {
     .await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_append() {
+    run_test(TestSetup { key: "test_append" }, async {
+        let fs = get_fs().await;
+
+        let test_file = SecretString::from_str("test-file").unwrap();
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        let ino = attr.ino;
+        write_all_bytes_to_fs(&fs, ino, 0, b"test-", fh)
+            .await
+            .unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+
+        // at the end, whatever the size was when it was opened
+        let fh = fs.open(ino, false, true).await.unwrap();
+        assert_eq!(fs.append(ino, b"42", fh).await.unwrap(), 2);
+        fs.set_len(ino, 2).await.unwrap();
+        assert_eq!(fs.append(ino, b"37", fh).await.unwrap(), 2);
+        fs.flush(fh).await.unwrap();
+        assert_eq!("te37", test_common::read_to_string(ino, &fs).await);
+
+        // appends at the same time don't overwrite each other
+        let tasks: Vec<_> = (0..10)
+            .map(|i| {
+                let fs = fs.clone();
+                tokio::spawn(async move { fs.append(ino, format!("{i}").as_bytes(), fh).await })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), 1);
+        }
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        let data = test_common::read_to_string(ino, &fs).await;
+        assert_eq!(data.len(), 14);
+        let mut digits: Vec<char> = data[4..].chars().collect();
+        digits.sort_unstable();
+        assert_eq!(digits.into_iter().collect::<String>(), "0123456789");
+    })
+    .await;
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 #[traced_test]
 #[allow(clippy::too_many_lines)]
@@ -2232,12 +2307,3980 @@ async fn test_open() {
     .await;
 }
 
-// #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-// #[traced_test]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_pad_file_sizes() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_pad_file_sizes",
+        },
+        FsOptions::default().with_pad_file_sizes(true),
+        async {
+            let fs = get_fs().await;
+            let content_len = |ino: u64| {
+                fs.data_dir
+                    .join(CONTENTS_DIR)
+                    .join(ino.to_string())
+                    .metadata()
+                    .unwrap()
+                    .len()
+            };
+
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            let empty_len = content_len(attr.ino);
+            assert_ne!(0, empty_len);
+            write_all_bytes_to_fs(&fs, attr.ino, 0, b"test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            // real size is kept, but the stored content has the size of the padding class
+            assert_eq!(7, fs.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(empty_len, content_len(attr.ino));
+
+            // truncate keeps the padding
+            fs.set_len(attr.ino, 4).await.unwrap();
+            assert_eq!("test", test_common::read_to_string(attr.ino, &fs).await);
+            assert_eq!(empty_len, content_len(attr.ino));
+
+            // bigger content moves to a bigger class
+            fs.set_len(attr.ino, PADDING_MIN_SIZE + 1).await.unwrap();
+            assert_eq!(
+                PADDING_MIN_SIZE + 1,
+                fs.get_attr(attr.ino).await.unwrap().size
+            );
+            assert!(content_len(attr.ino) > empty_len);
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_padded_size() {
+    assert_eq!(PADDING_MIN_SIZE, padded_size(0));
+    assert_eq!(PADDING_MIN_SIZE, padded_size(PADDING_MIN_SIZE));
+    assert_eq!(5120, padded_size(5000));
+    assert_eq!(1024 * 1024, padded_size(1024 * 1024));
+    for size in [4097, 12_345, 1_000_000, 123_456_789] {
+        let padded = padded_size(size);
+        assert!(padded >= size);
+        // overhead stays under 12%
+        assert!((padded - size) * 100 / size < 12);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
 #[allow(clippy::too_many_lines)]
-async fn _test_sample() {
-    run_test(TestSetup { key: "test_sample" }, async {
-        let _ = get_fs().await;
-    })
+async fn test_flat_layout() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_flat_layout",
+        },
+        FsOptions::default().with_layout(StorageLayout::Flat),
+        async {
+            let fs = get_fs().await;
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, file_attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, file_attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // only the flat pool and the key are in data dir, no subdirectories in the pool
+            let mut top = std::fs::read_dir(&fs.data_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            top.sort();
+            assert_eq!(vec![OBJECTS_DIR, SECURITY_DIR], top);
+            assert!(std::fs::read_dir(fs.data_dir.join(OBJECTS_DIR))
+                .unwrap()
+                .all(|e| e.unwrap().path().is_file()));
+
+            assert!(fs.is_dir(dir_attr.ino));
+            assert!(fs.is_file(file_attr.ino));
+            assert!(fs.exists_by_name(dir_attr.ino, &test_file).unwrap());
+            assert_eq!(
+                file_attr.ino,
+                fs.find_by_name(dir_attr.ino, &test_file)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            assert_eq!(1, fs.len(dir_attr.ino).await.unwrap());
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(file_attr.ino, &fs).await
+            );
+            let mut names = fs
+                .read_dir(dir_attr.ino)
+                .await
+                .unwrap()
+                .map(|e| e.unwrap().name.expose_secret().clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(vec![".", "..", "test-file"], names);
+
+            // move the file to root and remove the dir
+            let new_name = SecretString::from_str("test-file-2").unwrap();
+            fs.rename(dir_attr.ino, &test_file, ROOT_INODE, &new_name)
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(dir_attr.ino, &test_file).unwrap());
+            assert_eq!(0, fs.len(dir_attr.ino).await.unwrap());
+            fs.remove_dir(ROOT_INODE, &test_dir).await.unwrap();
+            assert!(!fs.is_dir(dir_attr.ino));
+            let entries = fs
+                .read_dir_plus(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|e| e.unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(2, entries.len());
+            let entry = entries
+                .iter()
+                .find(|e| e.name.expose_secret() == "test-file-2")
+                .unwrap();
+            assert_eq!(file_attr.ino, entry.ino);
+            assert_eq!(7, entry.attr.size);
+
+            fs.remove_file(ROOT_INODE, &new_name).await.unwrap();
+            assert!(!fs.exists(file_attr.ino));
+            assert_eq!(0, fs.len(ROOT_INODE).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_per_file_keys() {
+    run_test(
+        TestSetup {
+            key: "test_per_file_keys",
+        },
+        async {
+            let fs = get_fs().await;
+
+            let mut inos = vec![];
+            for name in ["test-file-1", "test-file-2"] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            let key1 = fs.file_key(inos[0]).await.unwrap();
+            let key2 = fs.file_key(inos[1]).await.unwrap();
+            assert_ne!(key1.expose_secret(), key2.expose_secret());
+            let master_key = fs.key.get().await.unwrap();
+            assert_ne!(key1.expose_secret(), master_key.expose_secret());
+
+            // key survives attr changes
+            fs.set_attr(inos[0], SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            fs.attr_cache.get().await.unwrap().write().await.clear();
+            fs.file_keys_cache.get().await.unwrap().lock().await.clear();
+            assert_eq!(
+                key1.expose_secret(),
+                fs.file_key(inos[0]).await.unwrap().expose_secret()
+            );
+            assert_eq!("test-42", test_common::read_to_string(inos[0], &fs).await);
+
+            // content can't be read with the master key
+            let mut reader = crypto::create_read(
+                File::open(fs.contents_path(inos[0])).unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &master_key,
+            );
+            let mut buf = vec![];
+            assert!(reader.read_to_end(&mut buf).is_err());
+        },
+    )
+    .await;
+}
+
+struct TestPasswordProvider(&'static str);
+impl PasswordProvider for TestPasswordProvider {
+    fn get_password(&self) -> Option<LockedString> {
+        Some(LockedString::from_str(self.0).unwrap())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_hidden_volume() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_hidden_volume",
+        },
+        FsOptions::default().with_layout(StorageLayout::Flat),
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+            let outer_file = SecretString::from_str("outer-file").unwrap();
+            fs.create(
+                ROOT_INODE,
+                &outer_file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(
+                EncryptedFs::create_hidden_volume(
+                    &data_dir,
+                    pass("password"),
+                    pass("password"),
+                    Cipher::ChaCha20Poly1305
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            assert!(matches!(
+                EncryptedFs::create_hidden_volume(
+                    &data_dir,
+                    pass("wrong"),
+                    pass("hidden-password"),
+                    Cipher::ChaCha20Poly1305
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            EncryptedFs::create_hidden_volume(
+                &data_dir,
+                pass("password"),
+                pass("hidden-password"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                EncryptedFs::create_hidden_volume(
+                    &data_dir,
+                    pass("password"),
+                    pass("hidden-password"),
+                    Cipher::ChaCha20Poly1305
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            let hidden = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("hidden-password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert!(!hidden.exists_by_name(ROOT_INODE, &outer_file).unwrap());
+            let hidden_file = SecretString::from_str("hidden-file").unwrap();
+            hidden
+                .create(
+                    ROOT_INODE,
+                    &hidden_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(!fs.exists_by_name(ROOT_INODE, &hidden_file).unwrap());
+            assert!(fs.exists_by_name(ROOT_INODE, &outer_file).unwrap());
+            assert_eq!(1, fs.len(ROOT_INODE).await.unwrap());
+
+            // change password of the hidden volume
+            EncryptedFs::passwd(
+                &data_dir,
+                pass("hidden-password"),
+                pass("hidden-password-2"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider("hidden-password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default(),
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            let hidden = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("hidden-password-2")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert!(hidden.exists_by_name(ROOT_INODE, &hidden_file).unwrap());
+
+            // the outer volume keeps writing chaff, which is left in the pool
+            let objects = || fs::read_dir(data_dir.join(OBJECTS_DIR)).unwrap().count();
+            let before = objects() - fs.chaff.lock().unwrap().len();
+            let attr = fs
+                .find_by_name(ROOT_INODE, &outer_file)
+                .await
+                .unwrap()
+                .unwrap();
+            for _ in 0..256 {
+                fs.set_attr(
+                    attr.ino,
+                    SetFileAttr::default().with_mtime(SystemTime::now()),
+                )
+                .await
+                .unwrap();
+            }
+            let chaff = fs.chaff.lock().unwrap().clone();
+            assert!(!chaff.is_empty());
+            assert!(chaff.iter().all(|path| path.is_file()));
+            assert_eq!(before + chaff.len(), objects());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn test_rotate_key() {
+    run_test(
+        TestSetup {
+            key: "test_rotate_key",
+        },
+        async {
+            let fs = get_fs().await;
+
+            let test_dir = SecretString::from_str("test-dir").unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_dir,
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let mut files = vec![];
+            for (parent, name) in [(ROOT_INODE, "test-file"), (dir_attr.ino, "legacy-file")] {
+                let (fh, attr) = fs
+                    .create(
+                        parent,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, name, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                files.push(attr);
+            }
+            // make the second one like files created before we had per-file keys
+            let old_key = fs.key.get().await.unwrap();
+            let legacy = files[1];
+            let content = test_common::read_to_string(legacy.ino, &fs).await;
+            let mut writer = crypto::create_write(
+                File::create(fs.contents_path(legacy.ino)).unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &old_key,
+            );
+            writer.write_all(content.as_bytes()).unwrap();
+            writer.finish().unwrap();
+            let attr = fs.get_inode_from_storage(legacy.ino).await.unwrap();
+            crypto::atomic_serialize_encrypt_into(
+                &fs.ino_file(legacy.ino),
+                &attr,
+                Cipher::ChaCha20Poly1305,
+                &old_key,
+            )
+            .unwrap();
+            fs.file_keys_cache.get().await.unwrap().lock().await.clear();
+            assert_eq!(
+                old_key.expose_secret(),
+                fs.file_key(legacy.ino).await.unwrap().expose_secret()
+            );
+            let old_file_key = fs.file_key(files[0].ino).await.unwrap();
+
+            fs.rotate_key().await.unwrap();
+            assert!(!fs.is_key_rotation_in_progress());
+            let new_key = fs.key.get().await.unwrap();
+            assert_ne!(old_key.expose_secret(), new_key.expose_secret());
+            assert_ne!(
+                old_file_key.expose_secret(),
+                fs.file_key(files[0].ino).await.unwrap().expose_secret()
+            );
+            assert_ne!(
+                new_key.expose_secret(),
+                fs.file_key(legacy.ino).await.unwrap().expose_secret()
+            );
+
+            // everything is readable with a new instance
+            let fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                "test-file",
+                test_common::read_to_string(files[0].ino, &fs).await
+            );
+            assert_eq!(
+                "legacy-file",
+                test_common::read_to_string(legacy.ino, &fs).await
+            );
+            let mut names = fs
+                .read_dir(dir_attr.ino)
+                .await
+                .unwrap()
+                .map(|e| e.unwrap().name.expose_secret().clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(vec![".", "..", "legacy-file"], names);
+            assert_eq!(
+                dir_attr.ino,
+                fs.find_by_name(ROOT_INODE, &test_dir)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .ino
+            );
+            // nothing uses the old key anymore
+            let reader = crypto::create_read(
+                File::open(fs.ino_file(dir_attr.ino)).unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &old_key,
+            );
+            assert!(bincode::deserialize_from::<_, FileAttr>(reader).is_err());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_rotate_key_resume() {
+    run_test(
+        TestSetup {
+            key: "test_rotate_key_resume",
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // interrupted right after it started, it still works with the old key
+            fs.begin_key_rotation().await.unwrap();
+            assert!(fs.is_key_rotation_in_progress());
+            fs.attr_cache.get().await.unwrap().write().await.clear();
+            fs.file_keys_cache.get().await.unwrap().lock().await.clear();
+            assert!(fs.exists_by_name(ROOT_INODE, &test_file).unwrap());
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+
+            // resumed when created again
+            let fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            for _ in 0..100 {
+                if !fs.is_key_rotation_in_progress() {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            assert!(!fs.is_key_rotation_in_progress());
+            assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_migrate_cipher() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_cipher",
+        },
+        check_migrate_cipher(),
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_migrate_cipher_flat() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_migrate_cipher_flat",
+        },
+        FsOptions::default().with_layout(StorageLayout::Flat),
+        check_migrate_cipher(),
+    )
+    .await;
+}
+
+async fn check_migrate_cipher() {
+    let fs = get_fs().await;
+    let data_dir = fs.data_dir.clone();
+    let test_dir = SecretString::from_str("test-dir").unwrap();
+    let test_file = SecretString::from_str("test-file").unwrap();
+    let (_, dir_attr) = fs
+        .create(
+            ROOT_INODE,
+            &test_dir,
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let (fh, attr) = fs
+        .create(
+            dir_attr.ino,
+            &test_file,
+            create_attr(FileType::RegularFile),
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+    write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+        .await
+        .unwrap();
+    fs.release(fh).await.unwrap();
+    drop(fs);
+
+    let pass = |p: &str| LockedString::from_str(p).unwrap();
+    let open = |cipher| {
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            cipher,
+            FsOptions::default(),
+        )
+    };
+    assert!(matches!(
+        EncryptedFs::migrate_cipher(
+            &data_dir,
+            pass("wrong"),
+            Cipher::ChaCha20Poly1305,
+            Cipher::Aes256Gcm
+        )
+        .await,
+        Err(FsError::InvalidPassword)
+    ));
+    // like it was interrupted before doing anything
+    bincode::serialize_into(
+        File::create(data_dir.join(SECURITY_DIR).join(CIPHER_MIGRATION_FILENAME)).unwrap(),
+        &(Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm),
+    )
+    .unwrap();
+    assert!(matches!(
+        open(Cipher::ChaCha20Poly1305).await,
+        Err(FsError::Other(_))
+    ));
+    assert!(matches!(
+        EncryptedFs::migrate_cipher(
+            &data_dir,
+            pass("password"),
+            Cipher::Aes256Gcm,
+            Cipher::ChaCha20Poly1305
+        )
+        .await,
+        Err(FsError::InvalidInput(_))
+    ));
+
+    EncryptedFs::migrate_cipher(
+        &data_dir,
+        pass("password"),
+        Cipher::ChaCha20Poly1305,
+        Cipher::Aes256Gcm,
+    )
+    .await
+    .unwrap();
+    assert!(!data_dir
+        .join(SECURITY_DIR)
+        .join(CIPHER_MIGRATION_FILENAME)
+        .exists());
+    // the header has the new one, the one given is ignored
+    let fs = open(Cipher::ChaCha20Poly1305).await.unwrap();
+    assert_eq!(Cipher::Aes256Gcm, fs.cipher);
+    let dir_attr = fs
+        .find_by_name(ROOT_INODE, &test_dir)
+        .await
+        .unwrap()
+        .unwrap();
+    let attr = fs
+        .find_by_name(dir_attr.ino, &test_file)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("test-42", test_common::read_to_string(attr.ino, &fs).await);
+    assert_eq!(
+        vec!["test-file".to_string()],
+        fs.read_dir(dir_attr.ino)
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name.expose_secret().clone())
+            .filter(|name| name != "." && name != "..")
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_keyfile() {
+    run_test(
+        TestSetup {
+            key: "test_keyfile",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let keyfile = data_dir.with_extension("keyfile");
+            fs::write(&keyfile, b"keyfile content").unwrap();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+            let with_keyfile = |p: &str| crypto::password_with_keyfile(&pass(p), &keyfile).unwrap();
+            assert_eq!(
+                with_keyfile("password").expose_secret(),
+                with_keyfile("password").expose_secret()
+            );
+            assert_ne!(
+                with_keyfile("password").expose_secret(),
+                with_keyfile("").expose_secret()
+            );
+
+            EncryptedFs::passwd(
+                &data_dir,
+                pass("password"),
+                with_keyfile("password"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(
+                EncryptedFs::passwd(
+                    &data_dir,
+                    pass("password"),
+                    pass("password"),
+                    Cipher::ChaCha20Poly1305
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            // another keyfile
+            fs::write(&keyfile, b"other content").unwrap();
+            assert!(matches!(
+                EncryptedFs::passwd(
+                    &data_dir,
+                    with_keyfile("password"),
+                    pass("password"),
+                    Cipher::ChaCha20Poly1305
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            fs::remove_file(keyfile).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_key_slots() {
+    run_test(
+        TestSetup {
+            key: "test_key_slots",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+            let kind = KeySlotKind::Fido2 {
+                rp_id: "rencfs".to_string(),
+                credential_id: "credential".to_string(),
+                hmac_salt: "salt".to_string(),
+            };
+            let add = |password: &str, name: &'static str| {
+                EncryptedFs::add_key_slot(
+                    &data_dir,
+                    pass(password),
+                    Cipher::ChaCha20Poly1305,
+                    name,
+                    kind.clone(),
+                    pass("slot-secret"),
+                )
+            };
+            assert!(matches!(
+                add("wrong", "fido2").await,
+                Err(FsError::InvalidPassword)
+            ));
+            assert!(matches!(
+                add("password", "../fido2").await,
+                Err(FsError::InvalidInput(_))
+            ));
+            add("password", "fido2").await.unwrap();
+            assert!(matches!(
+                add("password", "fido2").await,
+                Err(FsError::AlreadyExists)
+            ));
+            let slots = EncryptedFs::key_slots(&data_dir).await.unwrap();
+            assert_eq!(1, slots.len());
+            assert_eq!("fido2", slots[0].name);
+            assert_eq!(kind, slots[0].kind);
+
+            let open = |password: &'static str| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider(password)),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default(),
+                )
+            };
+            let slot_fs = open("slot-secret").await.unwrap();
+            assert_eq!(
+                fs.key.get().await.unwrap().expose_secret(),
+                slot_fs.key.get().await.unwrap().expose_secret()
+            );
+            assert!(matches!(
+                fs.rotate_key().await,
+                Err(FsError::InvalidInput(_))
+            ));
+            // the slot doesn't depend on the password
+            EncryptedFs::passwd(
+                &data_dir,
+                pass("password"),
+                pass("password-2"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            open("slot-secret").await.unwrap();
+
+            EncryptedFs::remove_key_slot(&data_dir, "fido2")
+                .await
+                .unwrap();
+            assert!(matches!(
+                EncryptedFs::remove_key_slot(&data_dir, "fido2").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                open("slot-secret").await,
+                Err(FsError::InvalidPassword)
+            ));
+            open("password-2").await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_password_and_recovery_key_slots() {
+    run_test(
+        TestSetup {
+            key: "test_password_and_recovery_key_slots",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+            let recovery_key = crypto::generate_recovery_key();
+            assert_eq!(71, recovery_key.expose_secret().len());
+            assert_eq!(7, recovery_key.expose_secret().matches('-').count());
+            let recovery_key: &'static str =
+                Box::leak(recovery_key.expose_secret().clone().into_boxed_str());
+            for (name, kind, secret) in [
+                ("alice", KeySlotKind::Password, "alice-password"),
+                ("recovery", KeySlotKind::RecoveryKey, recovery_key),
+            ] {
+                EncryptedFs::add_key_slot(
+                    &data_dir,
+                    pass("password"),
+                    Cipher::ChaCha20Poly1305,
+                    name,
+                    kind,
+                    pass(secret),
+                )
+                .await
+                .unwrap();
+            }
+            let slots = EncryptedFs::key_slots(&data_dir).await.unwrap();
+            assert_eq!(
+                vec!["alice password", "recovery recovery-key"],
+                slots
+                    .iter()
+                    .map(|slot| format!("{} {}", slot.name, slot.kind))
+                    .collect::<Vec<_>>()
+            );
+
+            let open = |password: &'static str| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider(password)),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default(),
+                )
+            };
+            open("alice-password").await.unwrap();
+            open(recovery_key).await.unwrap();
+
+            // revoking one leaves the others
+            EncryptedFs::remove_key_slot(&data_dir, "alice")
+                .await
+                .unwrap();
+            assert!(matches!(
+                open("alice-password").await,
+                Err(FsError::InvalidPassword)
+            ));
+            open(recovery_key).await.unwrap();
+            open("password").await.unwrap();
+        },
+    )
+    .await;
+}
+
+// #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+// #[traced_test]
+#[allow(clippy::too_many_lines)]
+async fn _test_sample() {
+    run_test(TestSetup { key: "test_sample" }, async {
+        let _ = get_fs().await;
+    })
+    .await;
+}
+
+struct TestTotpPasswordProvider(Option<String>);
+impl PasswordProvider for TestTotpPasswordProvider {
+    fn get_password(&self) -> Option<LockedString> {
+        Some(LockedString::from_str("password").unwrap())
+    }
+
+    fn get_totp_code(&self) -> Option<SecretString> {
+        self.0.clone().map(SecretString::new)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_totp() {
+    run_test(TestSetup { key: "test_totp" }, async {
+        let fs = get_fs().await;
+        let data_dir = fs.data_dir.clone();
+        let secret = crypto::totp::generate_secret();
+        let code = || crypto::totp::code(&secret, SystemTime::now());
+        let enroll = |password: &str, code: String| {
+            EncryptedFs::enroll_totp(
+                &data_dir,
+                LockedString::from_str(password).unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &secret,
+                SecretString::new(code),
+            )
+        };
+        assert!(matches!(
+            enroll("wrong", code()).await,
+            Err(FsError::InvalidPassword)
+        ));
+        assert!(matches!(
+            enroll("password", "abcdef".to_string()).await,
+            Err(FsError::InvalidTotpCode)
+        ));
+        assert!(!EncryptedFs::is_totp_enrolled(&data_dir));
+        enroll("password", code()).await.unwrap();
+        assert!(EncryptedFs::is_totp_enrolled(&data_dir));
+
+        let open = |code: Option<String>| {
+            EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestTotpPasswordProvider(code)),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+        };
+        assert!(matches!(open(None).await, Err(FsError::InvalidTotpCode)));
+        assert!(matches!(
+            open(Some("abcdef".to_string())).await,
+            Err(FsError::InvalidTotpCode)
+        ));
+        open(Some(code())).await.unwrap();
+
+        // it's kept when the master key changes
+        fs.rotate_key().await.unwrap();
+        assert!(matches!(open(None).await, Err(FsError::InvalidTotpCode)));
+        open(Some(code())).await.unwrap();
+
+        EncryptedFs::remove_totp(
+            &data_dir,
+            LockedString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await
+        .unwrap();
+        assert!(!EncryptedFs::is_totp_enrolled(&data_dir));
+        open(None).await.unwrap();
+    })
+    .await;
+}
+
+/// Forgets the password like the CLI does when locked, until it's set again.
+struct TestLockPasswordProvider(Arc<std::sync::Mutex<Option<&'static str>>>);
+impl PasswordProvider for TestLockPasswordProvider {
+    fn get_password(&self) -> Option<LockedString> {
+        self.0
+            .lock()
+            .unwrap()
+            .map(|password| LockedString::from_str(password).unwrap())
+    }
+
+    fn forget_password(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_auto_lock() {
+    run_test(
+        TestSetup {
+            key: "test_auto_lock",
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let password = Arc::new(std::sync::Mutex::new(Some("password")));
+            let fs = EncryptedFs::new(
+                data_dir,
+                Box::new(TestLockPasswordProvider(password.clone())),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_auto_lock(Some(Duration::from_secs(1))),
+            )
+            .await
+            .unwrap();
+            let test_file = SecretString::from_str("test-file").unwrap();
+            fs.ensure_unlocked().await.unwrap();
+            fs.create(
+                ROOT_INODE,
+                &test_file,
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            assert!(fs.is_locked());
+            assert!(password.lock().unwrap().is_none());
+            assert!(matches!(fs.ensure_unlocked().await, Err(FsError::Locked)));
+
+            // a wrong password is forgotten too
+            password.lock().unwrap().replace("wrong");
+            assert!(matches!(fs.ensure_unlocked().await, Err(FsError::Locked)));
+            assert!(password.lock().unwrap().is_none());
+
+            password.lock().unwrap().replace("password");
+            fs.ensure_unlocked().await.unwrap();
+            assert!(!fs.is_locked());
+            assert!(fs
+                .find_by_name(ROOT_INODE, &test_file)
+                .await
+                .unwrap()
+                .is_some());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_flush_all() {
+    run_test(
+        TestSetup {
+            key: "test_flush_all",
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.flush_all().await.unwrap();
+
+            // still open, but another one sees it
+            let other = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            assert_eq!(7, other.get_attr(attr.ino).await.unwrap().size);
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(attr.ino, &other).await
+            );
+            fs.release(fh).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_lock_data_dir() {
+    run_test(
+        TestSetup {
+            key: "test_lock_data_dir",
+        },
+        async {
+            let data_dir = get_fs().await.data_dir.clone();
+            let open = |options: FsOptions| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    options,
+                )
+            };
+            let locked = FsOptions::default().with_lock_data_dir(true);
+            let fs = open(locked.clone()).await.unwrap();
+            assert!(matches!(
+                open(locked.clone()).await,
+                Err(FsError::DataDirInUse(Some(pid))) if pid == std::process::id()
+            ));
+            assert!(matches!(
+                open(locked.clone().with_read_only(true)).await,
+                Err(FsError::DataDirInUse(_))
+            ));
+            // like with --force
+            open(FsOptions::default().with_read_only(true))
+                .await
+                .unwrap();
+            drop(fs);
+
+            // readers share it
+            let reader = open(locked.clone().with_read_only(true)).await.unwrap();
+            open(locked.clone().with_read_only(true)).await.unwrap();
+            assert!(matches!(
+                open(locked.clone()).await,
+                Err(FsError::DataDirInUse(_))
+            ));
+            drop(reader);
+            open(locked).await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_read_only() {
+    run_test(
+        TestSetup {
+            key: "test_read_only",
+        },
+        async {
+            let fs = get_fs().await;
+            let test_file = SecretString::from_str("test-file").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &test_file,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+
+            let ro = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            let name = SecretString::from_str("other").unwrap();
+            assert!(matches!(
+                ro.create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false
+                )
+                .await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                ro.remove_file(ROOT_INODE, &test_file).await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                ro.rename(ROOT_INODE, &test_file, ROOT_INODE, &name).await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                ro.set_len(attr.ino, 0).await,
+                Err(FsError::ReadOnly)
+            ));
+            assert!(matches!(
+                ro.open(attr.ino, true, true).await,
+                Err(FsError::ReadOnly)
+            ));
+
+            // reading doesn't change anything, not even the access time
+            let inode_before = fs::read(fs.ino_file(attr.ino)).unwrap();
+            let fh = ro.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; 7];
+            ro.read(attr.ino, 0, &mut buf, fh).await.unwrap();
+            ro.release(fh).await.unwrap();
+            assert_eq!("test-42", String::from_utf8(buf).unwrap());
+            assert_eq!(inode_before, fs::read(fs.ino_file(attr.ino)).unwrap());
+
+            // the data dir must exist
+            assert!(matches!(
+                EncryptedFs::new(
+                    fs.data_dir.join("missing"),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default().with_read_only(true),
+                )
+                .await,
+                Err(FsError::NoVolume)
+            ));
+        },
+    )
+    .await;
+}
+
+#[test]
+fn test_id_map() {
+    let id_map = IdMap {
+        uids: vec![(1000, 1001)],
+        gids: vec![(100, 200), (101, 201)],
+        to_mounter: false,
+    };
+    assert_eq!(1001, id_map.uid(1000));
+    assert_eq!(1000, id_map.stored_uid(1001));
+    // not mapped
+    assert_eq!(0, id_map.uid(0));
+    assert_eq!(0, id_map.stored_uid(0));
+    assert_eq!(201, id_map.gid(101));
+    assert_eq!(101, id_map.stored_gid(201));
+
+    let attr = id_map.map_attr(FileAttr {
+        uid: 1000,
+        gid: 100,
+        ..FileAttr::from(create_attr(FileType::RegularFile))
+    });
+    assert_eq!((1001, 200), (attr.uid, attr.gid));
+
+    let id_map = IdMap {
+        to_mounter: true,
+        ..IdMap::default()
+    };
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    assert_eq!(uid, id_map.uid(1234));
+    assert_eq!(gid, id_map.gid(1234));
+    // stored as is
+    assert_eq!(uid, id_map.stored_uid(uid));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_check() {
+    run_test(TestSetup { key: "test_check" }, async {
+        let fs = get_fs().await;
+        let dir_name = SecretString::from_str("test-dir").unwrap();
+        let (_, dir_attr) = fs
+            .create(
+                ROOT_INODE,
+                &dir_name,
+                create_attr(FileType::Directory),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+        let (fh, file_attr) = fs
+            .create(
+                dir_attr.ino,
+                &SecretString::from_str("test-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_string_to_fs(&fs, file_attr.ino, 0, "test-42", fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        let (fh, other_attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("other-file").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        let check = |password: &str| {
+            EncryptedFs::check(
+                &fs.data_dir,
+                LockedString::from_str(password).unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+        };
+        let report = check("password").await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!((2, 2), (report.dirs, report.files));
+        assert!(matches!(
+            check("wrong").await,
+            Err(FsError::InvalidPassword)
+        ));
+
+        // corrupt the content
+        let mut content = fs::read(fs.contents_path(file_attr.ino)).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        fs::write(fs.contents_path(file_attr.ino), content).unwrap();
+        // entry pointing to nothing
+        fs::copy(
+            fs.ino_file(other_attr.ino),
+            fs.data_dir.join(INODES_DIR).join("999"),
+        )
+        .unwrap();
+        fs::remove_file(fs.ino_file(other_attr.ino)).unwrap();
+
+        let report = check("password").await.unwrap();
+        assert_eq!(3, report.issues.len());
+        for issue in [
+            CheckIssue::DanglingEntry {
+                parent: ROOT_INODE,
+                ino: Some(other_attr.ino),
+            },
+            CheckIssue::BadContent { ino: file_attr.ino },
+            CheckIssue::Orphan { ino: 999 },
+        ] {
+            assert!(report.issues.contains(&issue));
+        }
+        // and it can be parsed
+        assert_eq!(
+            format!("bad-content\t{}", file_attr.ino),
+            CheckIssue::BadContent { ino: file_attr.ino }.to_string()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_recover() {
+    run_test(
+        TestSetup {
+            key: "test_recover",
+        },
+        async {
+            let fs = get_fs().await;
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // 3 chunks
+            let content = "a".repeat(BLOCK_SIZE) + &"b".repeat(BLOCK_SIZE) + "c";
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            // files in a directory that's lost end up in lost+found
+            let (_, lost_dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("lost-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (fh, orphan_attr) = fs
+                .create(
+                    lost_dir_attr.ino,
+                    &SecretString::from_str("orphan").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, orphan_attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs::remove_file(fs.ino_file(lost_dir_attr.ino)).unwrap();
+
+            // corrupt the second chunk
+            let mut encrypted = fs::read(fs.contents_path(attr.ino)).unwrap();
+            let middle = encrypted.len() / 2;
+            encrypted[middle] ^= 1;
+            fs::write(fs.contents_path(attr.ino), encrypted).unwrap();
+
+            let dest = fs.data_dir.with_extension("recovered");
+            let _ = fs::remove_dir_all(&dest);
+            let report = EncryptedFs::recover(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+                &dest,
+            )
+            .await
+            .unwrap();
+            assert_eq!((2, 1, 1), (report.files, report.bad_chunks, report.skipped));
+            let recovered = fs::read(dest.join("test-dir").join("test-file")).unwrap();
+            let expected = "a".repeat(BLOCK_SIZE) + &"\0".repeat(BLOCK_SIZE) + "c";
+            assert_eq!(expected.as_bytes(), recovered);
+            assert_eq!(
+                "test-42",
+                fs::read_to_string(dest.join("lost+found").join(orphan_attr.ino.to_string()))
+                    .unwrap()
+            );
+
+            // not empty anymore
+            assert!(matches!(
+                EncryptedFs::recover(
+                    &fs.data_dir,
+                    LockedString::from_str("password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    &dest,
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            fs::remove_dir_all(dest).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_export_restore() {
+    run_test(
+        TestSetup {
+            key: "test_export_restore",
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |n: &str| SecretString::from_str(n).unwrap();
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("test-dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let content = "a".repeat(BLOCK_SIZE * 2) + "b";
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &name("test-file"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (fh, _) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("empty"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let mtime = fs.get_attr(attr.ino).await.unwrap().mtime;
+
+            let archive = fs.data_dir.with_extension("archive");
+            let restored = fs.data_dir.with_extension("restored");
+            let _ = fs::remove_dir_all(&restored);
+            let report = EncryptedFs::export(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+                None,
+                &archive,
+            )
+            .await
+            .unwrap();
+            assert_eq!((2, 2), (report.dirs, report.files));
+            assert!(matches!(
+                EncryptedFs::restore(
+                    &archive,
+                    &restored,
+                    LockedString::from_str("wrong").unwrap(),
+                    Cipher::Aes256Gcm,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            // it can be restored with another cipher
+            EncryptedFs::restore(
+                &archive,
+                &restored,
+                LockedString::from_str("password").unwrap(),
+                Cipher::Aes256Gcm,
+            )
+            .await
+            .unwrap();
+            let restored_fs = EncryptedFs::new(
+                restored.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::Aes256Gcm,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            let dir = restored_fs
+                .find_by_name(ROOT_INODE, &name("test-dir"))
+                .await
+                .unwrap()
+                .unwrap();
+            let file = restored_fs
+                .find_by_name(dir.ino, &name("test-file"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                content,
+                test_common::read_to_string(file.ino, &restored_fs).await
+            );
+            assert_eq!(mtime, file.mtime);
+            assert!(restored_fs
+                .exists_by_name(ROOT_INODE, &name("empty"))
+                .unwrap());
+            drop(restored_fs);
+
+            // not empty anymore
+            assert!(matches!(
+                EncryptedFs::restore(
+                    &archive,
+                    &restored,
+                    LockedString::from_str("password").unwrap(),
+                    Cipher::Aes256Gcm,
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+            fs::remove_dir_all(&restored).unwrap();
+
+            // only a subtree, put in root
+            EncryptedFs::export(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+                Some("/test-dir"),
+                &archive,
+            )
+            .await
+            .unwrap();
+            let report = EncryptedFs::restore(
+                &archive,
+                &restored,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert_eq!((1, 1), (report.dirs, report.files));
+            let restored_fs = EncryptedFs::new(
+                restored.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            assert!(restored_fs
+                .exists_by_name(ROOT_INODE, &name("test-file"))
+                .unwrap());
+            drop(restored_fs);
+            fs::remove_dir_all(restored).unwrap();
+            fs::remove_file(archive).unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_import() {
+    run_test(TestSetup { key: "test_import" }, async {
+        let fs = get_fs().await;
+        let src = fs.data_dir.with_extension("plaintext");
+        let imported = fs.data_dir.with_extension("imported");
+        let _ = fs::remove_dir_all(&src);
+        let _ = fs::remove_dir_all(&imported);
+        let content = "a".repeat(BLOCK_SIZE * 2) + "b";
+        fs::create_dir_all(src.join("dir").join("sub")).unwrap();
+        fs::write(src.join("dir").join("sub").join("file"), &content).unwrap();
+        fs::write(src.join("empty"), "").unwrap();
+        for i in 0..5 {
+            fs::write(src.join(format!("file-{i}")), i.to_string()).unwrap();
+        }
+        let mtime = fs::metadata(src.join("dir").join("sub").join("file"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        let calls = AtomicU64::new(0);
+        let report = EncryptedFs::import(
+            &src,
+            &imported,
+            LockedString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+            3,
+            true,
+            &|_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!((2, 7, 0), (report.dirs, report.files, report.skipped));
+        assert_eq!(report.total_bytes, report.bytes);
+        assert!(report.mismatched.is_empty());
+        // once after walking the tree and after each file
+        assert_eq!(8, calls.load(Ordering::SeqCst));
+
+        let imported_fs = EncryptedFs::new(
+            imported.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default().with_read_only(true),
+        )
+        .await
+        .unwrap();
+        let name = |n: &str| SecretString::from_str(n).unwrap();
+        let dir = imported_fs
+            .find_by_name(ROOT_INODE, &name("dir"))
+            .await
+            .unwrap()
+            .unwrap();
+        let sub = imported_fs
+            .find_by_name(dir.ino, &name("sub"))
+            .await
+            .unwrap()
+            .unwrap();
+        let file = imported_fs
+            .find_by_name(sub.ino, &name("file"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            content,
+            test_common::read_to_string(file.ino, &imported_fs).await
+        );
+        assert_eq!(mtime, file.mtime);
+        let file = imported_fs
+            .find_by_name(ROOT_INODE, &name("file-3"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "3",
+            test_common::read_to_string(file.ino, &imported_fs).await
+        );
+        drop(imported_fs);
+
+        // names already there
+        assert!(matches!(
+            EncryptedFs::import(
+                &src,
+                &imported,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+                1,
+                false,
+                &|_| {},
+            )
+            .await,
+            Err(FsError::AlreadyExists)
+        ));
+        fs::remove_dir_all(imported).unwrap();
+        fs::remove_dir_all(src).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_snapshots() {
+    run_test(
+        TestSetup {
+            key: "test_snapshots",
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |n: &str| SecretString::from_str(n).unwrap();
+            let password = || LockedString::from_str("password").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("a"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "before", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (fh, _) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("b"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            EncryptedFs::create_snapshot(&fs.data_dir, password(), Cipher::ChaCha20Poly1305, "s1")
+                .await
+                .unwrap();
+            assert!(matches!(
+                EncryptedFs::create_snapshot(
+                    &fs.data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    "s1"
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                EncryptedFs::create_snapshot(
+                    &fs.data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    "../s2"
+                )
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            // change it after
+            let fh = fs.open(attr.ino, false, true).await.unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "after!", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.remove_file(ROOT_INODE, &name("b")).await.unwrap();
+            fs.set_len(attr.ino, 0).await.unwrap();
+
+            let snapshots =
+                EncryptedFs::snapshots(&fs.data_dir, password(), Cipher::ChaCha20Poly1305)
+                    .await
+                    .unwrap();
+            assert_eq!(
+                vec!["s1"],
+                snapshots
+                    .iter()
+                    .map(|s| s.name.as_str())
+                    .collect::<Vec<_>>()
+            );
+            let snapshot = EncryptedFs::new(
+                EncryptedFs::snapshot_path(&fs.data_dir, "s1"),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                "before",
+                test_common::read_to_string(attr.ino, &snapshot).await
+            );
+            assert!(snapshot.exists_by_name(ROOT_INODE, &name("b")).unwrap());
+            drop(snapshot);
+            assert_eq!("", test_common::read_to_string(attr.ino, &fs).await);
+            // the data dir still opens with the snapshot in it
+            EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+
+            EncryptedFs::delete_snapshot(&fs.data_dir, password(), Cipher::ChaCha20Poly1305, "s1")
+                .await
+                .unwrap();
+            assert!(
+                EncryptedFs::snapshots(&fs.data_dir, password(), Cipher::ChaCha20Poly1305)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
+            assert!(matches!(
+                EncryptedFs::delete_snapshot(
+                    &fs.data_dir,
+                    password(),
+                    Cipher::ChaCha20Poly1305,
+                    "s1"
+                )
+                .await,
+                Err(FsError::NotFound(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_versions() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_versions",
+        },
+        FsOptions::default().with_keep_versions(Some(2)),
+        async {
+            let fs = get_fs().await;
+            let password = || LockedString::from_str("password").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "1", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            for content in ["22", "333", "4444"] {
+                let fh = fs.open(attr.ino, false, true).await.unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, content, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+            }
+            let versions =
+                EncryptedFs::versions(&fs.data_dir, password(), Cipher::ChaCha20Poly1305, "/a")
+                    .await
+                    .unwrap();
+            // only the last 2
+            assert_eq!(
+                vec![2, 3],
+                versions.iter().map(|v| v.size).collect::<Vec<_>>()
+            );
+
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("a").unwrap())
+                .await
+                .unwrap();
+            let versions =
+                EncryptedFs::versions(&fs.data_dir, password(), Cipher::ChaCha20Poly1305, "/a")
+                    .await
+                    .unwrap();
+            assert_eq!(
+                vec![(3, false), (4, true)],
+                versions
+                    .iter()
+                    .map(|v| (v.size, v.removed.is_some()))
+                    .collect::<Vec<_>>()
+            );
+            let data_dir = fs.data_dir.clone();
+            let read = || async {
+                let fs = EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default().with_read_only(true),
+                )
+                .await
+                .unwrap();
+                let attr = fs
+                    .find_by_name(ROOT_INODE, &SecretString::from_str("a").unwrap())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                test_common::read_to_string(attr.ino, &fs).await
+            };
+            // created again
+            EncryptedFs::restore_version(
+                &data_dir,
+                password(),
+                Cipher::ChaCha20Poly1305,
+                "/a",
+                versions[1].id,
+            )
+            .await
+            .unwrap();
+            assert_eq!("4444", read().await);
+            // replaced, keeping what it had
+            EncryptedFs::restore_version(
+                &data_dir,
+                password(),
+                Cipher::ChaCha20Poly1305,
+                "/a",
+                versions[0].id,
+            )
+            .await
+            .unwrap();
+            assert_eq!("333", read().await);
+            let versions =
+                EncryptedFs::versions(&data_dir, password(), Cipher::ChaCha20Poly1305, "/a")
+                    .await
+                    .unwrap();
+            assert_eq!(4, versions.last().unwrap().size);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_dedup() {
+    run_test_with_options(
+        TestSetup { key: "test_dedup" },
+        FsOptions::default().with_dedup(true),
+        async {
+            let fs = get_fs().await;
+            let chunks = || {
+                fs::read_dir(fs.data_dir.join(DEDUP_DIR).join(CHUNKS_DIR))
+                    .map_or(0, Iterator::count)
+            };
+            let content = "a".repeat(BLOCK_SIZE) + &"b".repeat(BLOCK_SIZE) + "c";
+            let mut inos = vec![];
+            for name in ["a", "b"] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &SecretString::from_str(name).unwrap(),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                    .await
+                    .unwrap();
+                fs.release(fh).await.unwrap();
+                inos.push(attr.ino);
+            }
+            // stored once
+            assert_eq!(3, chunks());
+            assert_eq!(0, fs::metadata(fs.contents_path(inos[0])).unwrap().len());
+            for ino in &inos {
+                assert_eq!(content, test_common::read_to_string(*ino, &fs).await);
+            }
+
+            let fh = fs.open(inos[1], false, true).await.unwrap();
+            write_all_string_to_fs(&fs, inos[1], 0, "x", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(4, chunks());
+            assert_eq!(
+                format!("x{}", &content[1..]),
+                test_common::read_to_string(inos[1], &fs).await
+            );
+            assert_eq!(content, test_common::read_to_string(inos[0], &fs).await);
+
+            fs.set_len(inos[0], BLOCK_SIZE as u64).await.unwrap();
+            assert_eq!(
+                "a".repeat(BLOCK_SIZE),
+                test_common::read_to_string(inos[0], &fs).await
+            );
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("b").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(1, chunks());
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("a").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(0, chunks());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_compression() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_compression",
+        },
+        FsOptions::default().with_compression(Some(3)),
+        async {
+            let fs = get_fs().await;
+            let mut random = vec![0; BLOCK_SIZE];
+            crypto::create_rng().fill_bytes(&mut random);
+            let content = [vec![b'a'; BLOCK_SIZE], random, b"c".to_vec()].concat();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_bytes_to_fs(&fs, attr.ino, 0, &content, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            let mut sizes = fs::read_dir(fs.data_dir.join(DEDUP_DIR).join(CHUNKS_DIR))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .collect::<Vec<_>>();
+            sizes.sort_unstable();
+            assert_eq!(3, sizes.len());
+            // the repeated block compressed, the random one is kept as it is
+            assert!(sizes[1] < sizes[2] / 2);
+            assert!(sizes[2] > BLOCK_SIZE as u64);
+
+            // read with the same options as a volume that doesn't compress
+            let ro = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default().with_read_only(true),
+            )
+            .await
+            .unwrap();
+            let fh = ro.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; content.len()];
+            test_common::read_exact(&ro, attr.ino, 0, &mut buf, fh).await;
+            assert_eq!(content, buf);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_storage() {
+    let storage_dir = tempfile::tempdir().unwrap();
+    let storage: Arc<dyn Storage> =
+        Arc::new(LocalStorage::new(storage_dir.path().to_path_buf()).unwrap());
+    run_test_with_options(
+        TestSetup {
+            key: "test_storage",
+        },
+        FsOptions::default().with_storage(Some(storage.clone())),
+        async {
+            let fs = get_fs().await;
+            let name = SecretString::from_str("a").unwrap();
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "hello", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            // waits for the operations changing the data dir
+            let changing = fs.changes.enter();
+            let sync = tokio::spawn({
+                let fs = fs.clone();
+                async move { fs.sync_storage().await }
+            });
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(!sync.is_finished());
+            drop(changing);
+            sync.await.unwrap().unwrap();
+            assert!(!fs.data_dir.join(".storage-staging").exists());
+            let keys = || {
+                storage
+                    .list("")
+                    .unwrap()
+                    .into_iter()
+                    .map(|object| object.key)
+                    .collect::<Vec<_>>()
+            };
+            assert!(keys().contains(&format!("{SECURITY_DIR}/{KEY_ENC_FILENAME}")));
+
+            // a new data dir is pulled from the storage
+            let data_dir = tempfile::tempdir().unwrap();
+            let other = EncryptedFs::new(
+                data_dir.path().join("data"),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default()
+                    .with_storage(Some(storage.clone()))
+                    .with_read_only(true),
+            )
+            .await
+            .unwrap();
+            let pulled = other
+                .find_by_name(ROOT_INODE, &name)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                "hello",
+                test_common::read_to_string(pulled.ino, &other).await
+            );
+
+            fs.remove_file(ROOT_INODE, &name).await.unwrap();
+            fs.sync_storage().await.unwrap();
+            assert!(!keys().contains(&format!("{INODES_DIR}/{}", attr.ino)));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_overlay() {
+    run_test(
+        TestSetup {
+            key: "test_overlay",
+        },
+        async {
+            let base = get_fs().await;
+            let name = |n: &str| SecretString::from_str(n).unwrap();
+            let create_file =
+                |fs: Arc<EncryptedFs>, parent, n: &'static str, content: &'static str| async move {
+                    let (fh, attr) = fs
+                        .create(
+                            parent,
+                            &name(n),
+                            create_attr(FileType::RegularFile),
+                            false,
+                            true,
+                        )
+                        .await
+                        .unwrap();
+                    write_all_string_to_fs(&fs, attr.ino, 0, content, fh)
+                        .await
+                        .unwrap();
+                    fs.release(fh).await.unwrap();
+                    attr.ino
+                };
+            let a = create_file(base.clone(), ROOT_INODE, "a", "base").await;
+            create_file(base.clone(), ROOT_INODE, "b", "b").await;
+            let (_, dir) = base
+                .create(
+                    ROOT_INODE,
+                    &name("d"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            create_file(base.clone(), dir.ino, "x", "x").await;
+
+            let data_dir = tempfile::tempdir().unwrap();
+            let open = || {
+                EncryptedFs::new(
+                    data_dir.path().join("data"),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default()
+                        .with_create(true)
+                        .with_base(Some(base.clone())),
+                )
+            };
+            let overlay = open().await.unwrap();
+            let find = |fs: &Arc<EncryptedFs>, parent, n: &str| {
+                let fs = fs.clone();
+                let n = name(n);
+                async move { fs.find_by_name(parent, &n).await.unwrap() }
+            };
+            let attr = find(&overlay, ROOT_INODE, "a").await.unwrap();
+            assert_eq!(
+                "base",
+                test_common::read_to_string(attr.ino, &overlay).await
+            );
+            let d = find(&overlay, ROOT_INODE, "d").await.unwrap();
+            let x = find(&overlay, d.ino, "x").await.unwrap();
+            assert_eq!("x", test_common::read_to_string(x.ino, &overlay).await);
+
+            // copied up when written
+            let fh = overlay.open(attr.ino, false, true).await.unwrap();
+            write_all_string_to_fs(&overlay, attr.ino, 0, "over", fh)
+                .await
+                .unwrap();
+            overlay.release(fh).await.unwrap();
+            assert_eq!(
+                "over",
+                test_common::read_to_string(attr.ino, &overlay).await
+            );
+            assert_eq!("base", test_common::read_to_string(a, &base).await);
+
+            overlay.remove_file(ROOT_INODE, &name("b")).await.unwrap();
+            assert!(base
+                .find_by_name(ROOT_INODE, &name("b"))
+                .await
+                .unwrap()
+                .is_some());
+            drop(overlay);
+
+            // what's removed stays hidden, new entries of the base show up
+            create_file(base.clone(), ROOT_INODE, "n", "new").await;
+            let overlay = open().await.unwrap();
+            assert!(find(&overlay, ROOT_INODE, "b").await.is_none());
+            let attr = find(&overlay, ROOT_INODE, "a").await.unwrap();
+            assert_eq!(
+                "over",
+                test_common::read_to_string(attr.ino, &overlay).await
+            );
+            let n = find(&overlay, ROOT_INODE, "n").await.unwrap();
+            assert_eq!("new", test_common::read_to_string(n.ino, &overlay).await);
+
+            // removed from the base
+            base.remove_file(ROOT_INODE, &name("n")).await.unwrap();
+            drop(overlay);
+            let overlay = open().await.unwrap();
+            assert!(find(&overlay, ROOT_INODE, "n").await.is_none());
+            assert_eq!(2, overlay.len(ROOT_INODE).await.unwrap());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_quota() {
+    run_test_with_options(
+        TestSetup { key: "test_quota" },
+        FsOptions::default().with_max_size(Some(250)),
+        async {
+            let fs = get_fs().await;
+            let used = || fs.quota().unwrap().used;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("a").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, &"a".repeat(200), fh)
+                .await
+                .unwrap();
+            assert_eq!(200, used());
+            assert!(matches!(
+                fs.write(attr.ino, 200, &[0; 100], fh).await,
+                Err(FsError::QuotaExceeded(250))
+            ));
+            // overwriting doesn't take more
+            write_all_string_to_fs(&fs, attr.ino, 100, &"b".repeat(100), fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(200, used());
+
+            assert!(matches!(
+                fs.set_len(attr.ino, 300).await,
+                Err(FsError::QuotaExceeded(250))
+            ));
+            fs.set_len(attr.ino, 50).await.unwrap();
+            assert_eq!(50, used());
+            fs.set_len(attr.ino, 250).await.unwrap();
+            assert_eq!(250, used());
+            fs.remove_file(ROOT_INODE, &SecretString::from_str("a").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(0, used());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_passthrough() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let password = || LockedString::from_str("password").unwrap();
+    let open = || {
+        EncryptedFs::new(
+            data_dir.clone(),
+            Box::new(TestPasswordProvider("password")),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default()
+                .with_create(true)
+                .with_pad_file_sizes(true),
+        )
+    };
+    drop(open().await.unwrap());
+    let rules = vec![
+        PassthroughRule::from_str(".cache/**").unwrap(),
+        PassthroughRule::from_str("*.tmp:exclude").unwrap(),
+    ];
+    EncryptedFs::set_passthrough_rules(
+        &data_dir,
+        password(),
+        Cipher::ChaCha20Poly1305,
+        rules.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        rules,
+        EncryptedFs::passthrough_rules(&data_dir, password(), Cipher::ChaCha20Poly1305)
+            .await
+            .unwrap()
+    );
+
+    let fs = open().await.unwrap();
+    let name = |n: &str| SecretString::from_str(n).unwrap();
+    let (_, cache) = fs
+        .create(
+            ROOT_INODE,
+            &name(".cache"),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+    let mut inos = vec![];
+    for parent in [cache.ino, ROOT_INODE] {
+        let (fh, attr) = fs
+            .create(
+                parent,
+                &name("a"),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        write_all_string_to_fs(&fs, attr.ino, 0, "hello", fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+        assert_eq!("hello", test_common::read_to_string(attr.ino, &fs).await);
+        inos.push(attr.ino);
+    }
+    // stored as it is, and not padded
+    assert_eq!(
+        "hello",
+        fs::read_to_string(fs.contents_path(inos[0])).unwrap()
+    );
+    assert_ne!(
+        b"hello".to_vec(),
+        fs::read(fs.contents_path(inos[1])).unwrap()
+    );
+    fs.set_len(inos[0], 2).await.unwrap();
+    assert_eq!("he", test_common::read_to_string(inos[0], &fs).await);
+
+    assert!(matches!(
+        fs.create(
+            ROOT_INODE,
+            &name("b.tmp"),
+            create_attr(FileType::RegularFile),
+            false,
+            false,
+        )
+        .await,
+        Err(FsError::Excluded)
+    ));
+    assert!(matches!(
+        fs.rename(ROOT_INODE, &name("a"), ROOT_INODE, &name("a.tmp"))
+            .await,
+        Err(FsError::Excluded)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_journal_replay() {
+    run_test(
+        TestSetup {
+            key: "test_journal_replay",
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |n: &str| SecretString::from_str(n).unwrap();
+            let (fh, renamed_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("a"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, renamed_attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            let (_, removed_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("c"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+
+            // crash in the middle of a rename a -> b, after the old entry was removed
+            fs.journal_begin(&JournalOp::Rename {
+                parent: ROOT_INODE,
+                name: "a".to_string(),
+                encrypted_name: fs
+                    .read_hash_entry(ROOT_INODE, &name("a"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .2,
+                new_parent: ROOT_INODE,
+                new_name: "b".to_string(),
+                new_encrypted_name: fs.encrypt_entry_name(&name("b")).await.unwrap(),
+                ino: renamed_attr.ino,
+                kind: FileType::RegularFile,
+                replaced: None,
+            })
+            .await
+            .unwrap();
+            fs.remove_directory_entry(ROOT_INODE, &name("a"))
+                .await
+                .unwrap();
+            // while removing c, after the entry was removed
+            fs.journal_begin(&JournalOp::Remove {
+                parent: ROOT_INODE,
+                name: "c".to_string(),
+                encrypted_name: fs
+                    .read_hash_entry(ROOT_INODE, &name("c"))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .2,
+                ino: removed_attr.ino,
+                kind: FileType::RegularFile,
+            })
+            .await
+            .unwrap();
+            fs.remove_directory_entry(ROOT_INODE, &name("c"))
+                .await
+                .unwrap();
+            // and creating d, which has only the inode written
+            let mut created_attr: FileAttr = create_attr(FileType::RegularFile).into();
+            created_attr.ino = 42;
+            fs.journal_begin(&JournalOp::Create {
+                parent: ROOT_INODE,
+                name: "d".to_string(),
+                encrypted_name: fs.encrypt_entry_name(&name("d")).await.unwrap(),
+                ino: created_attr.ino,
+                kind: FileType::RegularFile,
+            })
+            .await
+            .unwrap();
+            fs.write_inode_to_storage(&created_attr).await.unwrap();
+
+            let fs = EncryptedFs::new(
+                fs.data_dir.clone(),
+                Box::new(TestPasswordProvider("password")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            let attr = fs.find_by_name(ROOT_INODE, &name("b")).await.unwrap();
+            assert_eq!(renamed_attr.ino, attr.unwrap().ino);
+            assert_eq!(
+                "test-42",
+                test_common::read_to_string(renamed_attr.ino, &fs).await
+            );
+            assert!(!fs.exists_by_name(ROOT_INODE, &name("a")).unwrap());
+            assert!(!fs.exists(removed_attr.ino));
+            assert!(!fs.contents_path(removed_attr.ino).exists());
+            assert!(!fs.exists(created_attr.ino));
+            let names: Vec<_> = fs
+                .read_dir(ROOT_INODE)
+                .await
+                .unwrap()
+                .map(|entry| entry.unwrap().name.expose_secret().clone())
+                .filter(|name| name != "." && name != "..")
+                .collect();
+            assert_eq!(vec!["b"], names);
+            assert_eq!(
+                0,
+                fs::read_dir(fs.data_dir.join(SECURITY_DIR).join("journal"))
+                    .unwrap()
+                    .count()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_atomic_write() {
+    run_test(
+        TestSetup {
+            key: "test_atomic_write",
+        },
+        async {
+            let fs = get_fs().await;
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "old", fh)
+                .await
+                .unwrap();
+            assert!(!fs.contents_tmp_path(attr.ino).exists());
+            let committed = fs::read(fs.contents_path(attr.ino)).unwrap();
+
+            // until flushed the content is not touched
+            assert_eq!(3, fs.write(attr.ino, 0, b"new", fh).await.unwrap());
+            assert!(fs.contents_tmp_path(attr.ino).exists());
+            assert_eq!(committed, fs::read(fs.contents_path(attr.ino)).unwrap());
+
+            fs.release(fh).await.unwrap();
+            assert!(!fs.contents_tmp_path(attr.ino).exists());
+            assert_eq!("new", test_common::read_to_string(attr.ino, &fs).await);
+
+            // left by a crash
+            fs::write(fs.contents_tmp_path(attr.ino), "garbage").unwrap();
+            fs.remove_stale_contents_tmp().unwrap();
+            assert!(!fs.contents_tmp_path(attr.ino).exists());
+            assert_eq!("new", test_common::read_to_string(attr.ino, &fs).await);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_migrate_format() {
+    run_test(
+        TestSetup {
+            key: "test_migrate_format",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let version_path = data_dir.join(SECURITY_DIR).join(FORMAT_VERSION_FILENAME);
+            assert_eq!(Some(FORMAT_VERSION), read_format_version(&data_dir).unwrap());
+            let open = |options: FsOptions| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    options,
+                )
+            };
+
+            // from before versioning
+            fs::remove_file(&version_path).unwrap();
+            open(FsOptions::default().with_read_only(true))
+                .await
+                .unwrap();
+            assert_eq!(None, read_format_version(&data_dir).unwrap());
+            open(FsOptions::default().with_backup_before_migrate(true))
+                .await
+                .unwrap();
+            assert_eq!(Some(FORMAT_VERSION), read_format_version(&data_dir).unwrap());
+            let mut backup_name = data_dir.file_name().unwrap().to_os_string();
+            backup_name.push(".backup-v0");
+            let backup = data_dir.with_file_name(backup_name);
+            assert!(backup.join(SECURITY_DIR).join(KEY_ENC_FILENAME).is_file());
+            assert!(!backup.join(SECURITY_DIR).join(FORMAT_VERSION_FILENAME).exists());
+            fs::remove_dir_all(backup).unwrap();
+
+            // from the future
+            fs::write(&version_path, format!("{}\n", FORMAT_VERSION + 1)).unwrap();
+            assert!(matches!(
+                open(FsOptions::default()).await,
+                Err(FsError::UnsupportedFormatVersion(version, FORMAT_VERSION)) if version == FORMAT_VERSION + 1
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_verify() {
+    run_test(TestSetup { key: "test_verify" }, async {
+        let fs = get_fs().await;
+        let mut inos = vec![];
+        for name in ["test-file-1", "test-file-2"] {
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "old", fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            inos.push(attr.ino);
+        }
+        let old = fs::read(fs.contents_path(inos[0])).unwrap();
+        let fh = fs.open(inos[0], false, true).await.unwrap();
+        write_all_string_to_fs(&fs, inos[0], 0, "new", fh)
+            .await
+            .unwrap();
+        fs.release(fh).await.unwrap();
+
+        let verify = |min_generation: Option<u64>| {
+            EncryptedFs::verify(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+                min_generation,
+            )
+        };
+        let report = verify(None).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(2, report.files);
+        let generation = report.generation;
+
+        // rolled back to an older version, which still authenticates
+        fs::write(fs.contents_path(inos[0]), old).unwrap();
+        assert_eq!("old", test_common::read_to_string(inos[0], &fs).await);
+        let report = verify(Some(generation + 1)).await.unwrap();
+        assert_eq!(
+            vec![
+                VerifyIssue::RolledBack {
+                    generation,
+                    expected: generation + 1
+                },
+                VerifyIssue::Modified { ino: inos[0] }
+            ],
+            report.issues
+        );
+
+        // removed files are dropped from the tree
+        let root = report.root;
+        fs.remove_file(ROOT_INODE, &SecretString::from_str("test-file-2").unwrap())
+            .await
+            .unwrap();
+        let report = verify(None).await.unwrap();
+        assert_ne!(root, report.root);
+        assert_eq!(vec![VerifyIssue::Modified { ino: inos[0] }], report.issues);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_content_tree_log() {
+    run_test(
+        TestSetup {
+            key: "test_content_tree_log",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let tree_path = data_dir.join(SECURITY_DIR).join("content.tree");
+            let log_path = data_dir.join(SECURITY_DIR).join("content.tree.log");
+            let tree = fs::read(&tree_path).unwrap();
+            let verify = || {
+                EncryptedFs::verify(
+                    &data_dir,
+                    LockedString::from_str("password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                    None,
+                )
+            };
+            let open = || {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default(),
+                )
+            };
+
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            // still being written, like after a crash, it can't be verified
+            let report = verify().await.unwrap();
+            assert!(report.is_ok());
+            fs.release(fh).await.unwrap();
+            let report = verify().await.unwrap();
+            assert!(report.is_ok());
+            assert_eq!(1, report.files);
+            // only appended to the log
+            assert_eq!(tree, fs::read(&tree_path).unwrap());
+            assert!(log_path.is_file());
+
+            // written into the tree when opened
+            drop(open().await.unwrap());
+            assert!(!log_path.exists());
+            assert_ne!(tree, fs::read(&tree_path).unwrap());
+            assert_eq!(report.root, verify().await.unwrap().root);
+
+            // not built again if it can't be read, the files can't be trusted
+            let mut data = fs::read(&tree_path).unwrap();
+            *data.last_mut().unwrap() ^= 1;
+            fs::write(&tree_path, data).unwrap();
+            assert!(matches!(open().await, Err(FsError::ContentTreeBroken(_))));
+            assert!(matches!(verify().await, Err(FsError::ContentTreeBroken(_))));
+            fs::remove_file(&tree_path).unwrap();
+            assert!(matches!(open().await, Err(FsError::ContentTreeBroken(_))));
+            EncryptedFs::rebuild_content_tree(
+                &data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            let rebuilt = verify().await.unwrap();
+            assert!(rebuilt.is_ok());
+            assert_eq!(report.root, rebuilt.root);
+            open().await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_corruption_log() {
+    run_test(
+        TestSetup {
+            key: "test_corruption_log",
+        },
+        async {
+            let fs = get_fs().await;
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str("test-dir").unwrap(),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            // 3 chunks
+            let content = "a".repeat(BLOCK_SIZE) + &"b".repeat(BLOCK_SIZE) + "c";
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &SecretString::from_str("test-file").unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+
+            // corrupt the second chunk
+            let mut encrypted = fs::read(fs.contents_path(attr.ino)).unwrap();
+            let middle = encrypted.len() / 2;
+            encrypted[middle] ^= 1;
+            fs::write(fs.contents_path(attr.ino), encrypted).unwrap();
+
+            let fh = fs.open(attr.ino, true, false).await.unwrap();
+            let mut buf = vec![0; content.len()];
+            assert!(fs.read(attr.ino, 0, &mut buf, fh).await.is_err());
+            // the reader still works for the good chunks
+            let mut buf = vec![0; 1];
+            fs.read(attr.ino, 2 * BLOCK_SIZE as u64, &mut buf, fh)
+                .await
+                .unwrap();
+            assert_eq!(b"c", &buf[..]);
+            // logged once
+            let mut buf = vec![0; 1];
+            assert!(fs
+                .read(attr.ino, BLOCK_SIZE as u64, &mut buf, fh)
+                .await
+                .is_err());
+            fs.release(fh).await.unwrap();
+
+            let log = EncryptedFs::corruption_log(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert_eq!(1, log.len());
+            assert_eq!(
+                (attr.ino, Some("/test-dir/test-file"), 1, BLOCK_SIZE as u64),
+                (
+                    log[0].ino,
+                    log[0].path.as_deref(),
+                    log[0].chunk,
+                    log[0].offset
+                )
+            );
+
+            EncryptedFs::clear_corruption_log(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert!(EncryptedFs::corruption_log(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap()
+            .is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_scrub() {
+    run_test(TestSetup { key: "test_scrub" }, async {
+        let fs = get_fs().await;
+        let mut inos = vec![];
+        for name in ["test-file-1", "test-file-2"] {
+            // 3 chunks
+            let content = "a".repeat(BLOCK_SIZE) + &"b".repeat(BLOCK_SIZE) + "c";
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &SecretString::from_str(name).unwrap(),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, &content, fh)
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            inos.push(attr.ino);
+        }
+
+        // corrupt the second chunk of the first file
+        let mut encrypted = fs::read(fs.contents_path(inos[0])).unwrap();
+        let middle = encrypted.len() / 2;
+        encrypted[middle] ^= 1;
+        fs::write(fs.contents_path(inos[0]), encrypted).unwrap();
+
+        assert_eq!(1, fs.scrub_file(inos[0], u64::MAX).await.unwrap());
+        assert_eq!(0, fs.scrub_file(inos[1], u64::MAX).await.unwrap());
+        let log = EncryptedFs::corruption_log(
+            &fs.data_dir,
+            LockedString::from_str("password").unwrap(),
+            Cipher::ChaCha20Poly1305,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![(inos[0], 1)],
+            log.iter().map(|r| (r.ino, r.chunk)).collect::<Vec<_>>()
+        );
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_path_api() {
+    run_test(
+        TestSetup {
+            key: "test_path_api",
+        },
+        async {
+            let fs = get_fs().await;
+
+            let attr = fs.create_dir_path("/docs/").await.unwrap();
+            assert_eq!(FileType::Directory, attr.kind);
+            assert!(matches!(
+                fs.create_dir_path("/docs").await,
+                Err(FsError::AlreadyExists)
+            ));
+            assert!(matches!(
+                fs.create_file_path("/missing/a").await,
+                Err(FsError::NotFound(_))
+            ));
+            assert!(matches!(
+                fs.create_dir_path("/").await,
+                Err(FsError::InvalidInput(_))
+            ));
+
+            let attr = fs.write_file("/docs/a", b"hello world").await.unwrap();
+            assert_eq!(11, attr.size);
+            fs.write_path("/docs/a", 6, b"there").await.unwrap();
+            let mut buf = [0; 5];
+            assert_eq!(5, fs.read_path("/docs/a", 6, &mut buf).await.unwrap());
+            assert_eq!(b"there", &buf);
+            // past the end
+            assert_eq!(2, fs.read_path("/docs/a", 9, &mut buf).await.unwrap());
+            assert_eq!(
+                b"hello there",
+                fs.read_file("/docs/a").await.unwrap().as_slice()
+            );
+            // replaced, not overwritten
+            fs.write_file("/docs/a", b"bye").await.unwrap();
+            assert_eq!(b"bye", fs.read_file("/docs/a").await.unwrap().as_slice());
+            fs.set_len_path("/docs/a", 5).await.unwrap();
+            assert_eq!(
+                b"bye\0\0",
+                fs.read_file("/docs/a").await.unwrap().as_slice()
+            );
+            assert!(matches!(
+                fs.read_file("/docs").await,
+                Err(FsError::InvalidInodeType)
+            ));
+
+            fs.rename_path("/docs/a", "/b").await.unwrap();
+            assert!(fs.read_dir_path("/docs").await.unwrap().is_empty());
+            let names: Vec<_> = fs
+                .read_dir_path("/")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name.expose_secret().clone())
+                .collect();
+            assert_eq!(2, names.len());
+            assert!(names.contains(&"b".to_string()) && names.contains(&"docs".to_string()));
+            assert_eq!(5, fs.metadata("/b").await.unwrap().size);
+
+            fs.remove_path("/b").await.unwrap();
+            fs.remove_path("/docs").await.unwrap();
+            assert!(matches!(fs.metadata("/b").await, Err(FsError::NotFound(_))));
+            assert!(fs.read_dir_path("/").await.unwrap().is_empty());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_encrypted_file() {
+    use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use crate::encryptedfs::EncryptedFile;
+
+    run_test(
+        TestSetup {
+            key: "test_encrypted_file",
+        },
+        async {
+            let fs = get_fs().await;
+
+            // bigger than what's read at once
+            let mut content = vec![0; 3 * 1024 * 1024 + 11];
+            crypto::create_rng().fill_bytes(&mut content);
+            let mut file = EncryptedFile::create(fs.clone(), "/a").await.unwrap();
+            file.write_all(&content).await.unwrap();
+            // reads see the writes
+            file.seek(SeekFrom::Start(5)).await.unwrap();
+            let mut buf = [0; 3];
+            file.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&content[5..8], &buf);
+            file.seek(SeekFrom::End(-2)).await.unwrap();
+            file.write_all(b"xyz").await.unwrap();
+            content.truncate(content.len() - 2);
+            content.extend_from_slice(b"xyz");
+            file.shutdown().await.unwrap();
+            assert_eq!(content.len() as u64, fs.metadata("/a").await.unwrap().size);
+
+            let mut file = EncryptedFile::open(fs.clone(), "/a").await.unwrap();
+            let mut read = vec![];
+            file.read_to_end(&mut read).await.unwrap();
+            assert!(read == content);
+            assert_eq!(
+                content.len() as u64 - 3,
+                file.seek(SeekFrom::Current(-3)).await.unwrap()
+            );
+            let mut buf = vec![];
+            file.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(b"xyz", buf.as_slice());
+            assert!(file.seek(SeekFrom::Current(-100_000_000)).await.is_err());
+            assert!(file.write_all(b"no").await.is_err());
+            file.shutdown().await.unwrap();
+
+            // create truncates
+            let file = EncryptedFile::create(fs.clone(), "/a").await.unwrap();
+            let (content, mut file) = tokio::task::spawn_blocking(move || {
+                let mut file = file.into_blocking();
+                file.write_all(b"hello world").unwrap();
+                file.flush().unwrap();
+                file.seek(SeekFrom::Start(6)).unwrap();
+                let mut content = String::new();
+                file.read_to_string(&mut content).unwrap();
+                (content, file.into_inner())
+            })
+            .await
+            .unwrap();
+            assert_eq!("world", content);
+            file.shutdown().await.unwrap();
+            assert_eq!(b"hello world", fs.read_file("/a").await.unwrap().as_slice());
+
+            assert!(matches!(
+                EncryptedFile::open(fs.clone(), "/").await,
+                Err(FsError::InvalidInodeType)
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_encrypted_file_dropped() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+    use crate::encryptedfs::EncryptedFile;
+
+    run_test(
+        TestSetup {
+            key: "test_encrypted_file_dropped",
+        },
+        async {
+            let fs = get_fs().await;
+
+            // dropped after the writes, without shutdown
+            let mut file = EncryptedFile::create(fs.clone(), "/a").await.unwrap();
+            file.write_all(b"hello").await.unwrap();
+            drop(file);
+            fs.flush_all().await.unwrap();
+            assert_eq!(b"hello", fs.read_file("/a").await.unwrap().as_slice());
+
+            // dropped while writing
+            let mut file = EncryptedFile::create(fs.clone(), "/b").await.unwrap();
+            let content = vec![7; 1024 * 1024];
+            std::future::poll_fn(|cx: &mut Context<'_>| {
+                let _ = Pin::new(&mut file).poll_write(cx, &content);
+                Poll::Ready(())
+            })
+            .await;
+            drop(file);
+            fs.flush_all().await.unwrap();
+            // a write may take only part of the buffer
+            let written = fs.read_file("/b").await.unwrap();
+            assert!(!written.is_empty() && content.starts_with(&written));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_stats() {
+    run_test(TestSetup { key: "test_stats" }, async {
+        let fs = get_fs().await;
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("a").unwrap(),
+                create_attr(FileType::RegularFile),
+                true,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("d").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        fs.get_attr(attr.ino).await.unwrap();
+
+        let stats = fs.stats().await.unwrap();
+        assert_eq!(1, stats.read_handles);
+        assert_eq!(1, stats.write_handles);
+        // with root
+        assert_eq!(3, stats.inodes);
+        let attr_cache = stats
+            .caches
+            .iter()
+            .find(|cache| cache.name == "attr")
+            .unwrap();
+        assert!(attr_cache.len > 0 && attr_cache.len <= attr_cache.cap);
+
+        fs.release(fh).await.unwrap();
+        let stats = fs.stats().await.unwrap();
+        assert_eq!(0, stats.read_handles);
+        assert_eq!(0, stats.write_handles);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_benchmark() {
+    run_test(
+        TestSetup {
+            key: "test_benchmark",
+        },
+        async {
+            let fs = get_fs().await;
+            let settings = BenchmarkSettings {
+                file_size: 4 * BLOCK_SIZE as u64,
+                io_size: BLOCK_SIZE,
+                files: 10,
+            };
+            let results = fs.benchmark(&settings).await.unwrap();
+            let names: Vec<_> = results.iter().map(|result| result.name).collect();
+            assert_eq!(
+                vec![
+                    "sequential write",
+                    "sequential read",
+                    "random write",
+                    "random read",
+                    "create",
+                    "lookup",
+                    "getattr",
+                    "readdir entry",
+                    "remove"
+                ],
+                names
+            );
+            assert_eq!(4 * BLOCK_SIZE as u64, results[0].bytes);
+            assert_eq!(10, results[4].ops);
+            // what it created is removed
+            assert_eq!(
+                0,
+                fs.read_dir(ROOT_INODE)
+                    .await
+                    .unwrap()
+                    .filter(|entry| {
+                        entry
+                            .as_ref()
+                            .unwrap()
+                            .name
+                            .expose_secret()
+                            .starts_with("bench")
+                    })
+                    .count()
+            );
+
+            assert!(matches!(
+                fs.benchmark(&BenchmarkSettings {
+                    file_size: 1,
+                    io_size: BLOCK_SIZE,
+                    files: 1,
+                })
+                .await,
+                Err(FsError::InvalidInput(_))
+            ));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_header() {
+    let kdf = KdfParams {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
+    run_test_with_options(
+        TestSetup { key: "test_header" },
+        FsOptions::default().with_kdf(kdf),
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let header = EncryptedFs::header(&data_dir).await.unwrap().unwrap();
+            assert_eq!(Cipher::ChaCha20Poly1305, header.cipher);
+            assert_eq!(kdf, header.kdf);
+            assert_eq!(BLOCK_SIZE as u64, header.chunk_size);
+            assert!(header.created > 0);
+
+            // the key is derived with the cost in the header
+            EncryptedFs::passwd(
+                &data_dir,
+                LockedString::from_str("password").unwrap(),
+                LockedString::from_str("password-2").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            let reopened = EncryptedFs::new(
+                data_dir.clone(),
+                Box::new(TestPasswordProvider("password-2")),
+                Cipher::ChaCha20Poly1305,
+                FsOptions::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                fs.key.get().await.unwrap().expose_secret(),
+                reopened.key.get().await.unwrap().expose_secret()
+            );
+            // it's not rewritten when opened
+            assert_eq!(
+                header,
+                EncryptedFs::header(&data_dir).await.unwrap().unwrap()
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_create_volume() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data_dir = data_dir.path().join("data");
+    let pass = |p: &str| LockedString::from_str(p).unwrap();
+    // a mistyped path is not taken for an empty volume
+    assert!(matches!(
+        EncryptedFs::open_volume(
+            &data_dir,
+            pass("password"),
+            Cipher::ChaCha20Poly1305,
+            FsOptions::default(),
+        )
+        .await,
+        Err(FsError::NoVolume)
+    ));
+    assert!(!data_dir.exists());
+
+    drop(
+        EncryptedFs::create_volume(
+            &data_dir,
+            pass("password"),
+            Cipher::Aes256Gcm,
+            FsOptions::default(),
+        )
+        .await
+        .unwrap(),
+    );
+    assert!(matches!(
+        EncryptedFs::create_volume(
+            &data_dir,
+            pass("password"),
+            Cipher::Aes256Gcm,
+            FsOptions::default(),
+        )
+        .await,
+        Err(FsError::AlreadyExists)
+    ));
+
+    // the cipher given is ignored, it's the one in the header
+    EncryptedFs::passwd(
+        &data_dir,
+        pass("password"),
+        pass("password-2"),
+        Cipher::ChaCha20Poly1305,
+    )
+    .await
+    .unwrap();
+    let fs = EncryptedFs::open_volume(
+        &data_dir,
+        pass("password-2"),
+        Cipher::ChaCha20Poly1305,
+        FsOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(Cipher::Aes256Gcm, fs.cipher);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_info() {
+    run_test(TestSetup { key: "test_info" }, async {
+        let fs = get_fs().await;
+        let (fh, attr) = fs
+            .create(
+                ROOT_INODE,
+                &SecretString::from_str("a").unwrap(),
+                create_attr(FileType::RegularFile),
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        fs.write(attr.ino, 0, b"hello", fh).await.unwrap();
+        fs.flush(fh).await.unwrap();
+        fs.release(fh).await.unwrap();
+        fs.create(
+            ROOT_INODE,
+            &SecretString::from_str("d").unwrap(),
+            create_attr(FileType::Directory),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let info = EncryptedFs::info(&fs.data_dir).await.unwrap();
+        assert_eq!(Cipher::ChaCha20Poly1305, info.header.unwrap().cipher);
+        assert_eq!(Some(FORMAT_VERSION), info.format_version);
+        assert_eq!(StorageLayout::Hierarchical, info.layout);
+        assert_eq!(Some(1), info.files);
+        assert_eq!(Some(1), info.dirs);
+        assert!(info.stored_size > 5);
+        assert_eq!(0, info.key_slots);
+
+        let usage = fs.usage().await.unwrap();
+        assert_eq!(1, usage.files);
+        assert_eq!(1, usage.dirs);
+        assert_eq!(5, usage.size);
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_check_password() {
+    run_test(
+        TestSetup {
+            key: "test_check_password",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+
+            EncryptedFs::check_password(&data_dir, &pass("password"), Cipher::ChaCha20Poly1305)
+                .await
+                .unwrap();
+            assert!(matches!(
+                EncryptedFs::check_password(&data_dir, &pass("wrong"), Cipher::ChaCha20Poly1305)
+                    .await,
+                Err(FsError::InvalidPassword)
+            ));
+
+            // the password of a key slot too
+            EncryptedFs::add_key_slot(
+                &data_dir,
+                pass("password"),
+                Cipher::ChaCha20Poly1305,
+                "backup",
+                KeySlotKind::Password,
+                pass("slot-password"),
+            )
+            .await
+            .unwrap();
+            EncryptedFs::check_password(
+                &data_dir,
+                &pass("slot-password"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_reset_password() {
+    run_test(
+        TestSetup {
+            key: "test_reset_password",
+        },
+        async {
+            let fs = get_fs().await;
+            let data_dir = fs.data_dir.clone();
+            let pass = |p: &str| LockedString::from_str(p).unwrap();
+            EncryptedFs::add_key_slot(
+                &data_dir,
+                pass("password"),
+                Cipher::ChaCha20Poly1305,
+                "recovery",
+                KeySlotKind::RecoveryKey,
+                pass("recovery-key"),
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(
+                EncryptedFs::reset_password(
+                    &data_dir,
+                    pass("wrong"),
+                    pass("new-password"),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await,
+                Err(FsError::InvalidPassword)
+            ));
+            EncryptedFs::reset_password(
+                &data_dir,
+                pass("recovery-key"),
+                pass("new-password"),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+
+            let open = |password: &'static str| {
+                EncryptedFs::new(
+                    data_dir.clone(),
+                    Box::new(TestPasswordProvider(password)),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default(),
+                )
+            };
+            assert!(matches!(
+                open("password").await,
+                Err(FsError::InvalidPassword)
+            ));
+            open("new-password").await.unwrap();
+            // the recovery key keeps working
+            open("recovery-key").await.unwrap();
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_cache_ttl() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_cache_ttl",
+        },
+        FsOptions::default()
+            .with_attr_ttl(Some(Duration::from_secs(3600)))
+            .with_entry_ttl(Some(Duration::from_secs(3600))),
+        async {
+            let fs = get_fs().await;
+            assert_eq!(Duration::from_secs(3600), fs.attr_ttl());
+            assert_eq!(Duration::from_secs(3600), fs.entry_ttl());
+            let a = SecretString::from_str("a").unwrap();
+            let b = SecretString::from_str("b").unwrap();
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &a,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &a).await.unwrap().unwrap().ino
+            );
+
+            // what's cached is updated on changes, however long it's kept
+            fs.rename(ROOT_INODE, &a, ROOT_INODE, &b).await.unwrap();
+            assert!(fs.find_by_name(ROOT_INODE, &a).await.unwrap().is_none());
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &b).await.unwrap().unwrap().ino
+            );
+            fs.remove_file(ROOT_INODE, &b).await.unwrap();
+            assert!(fs.find_by_name(ROOT_INODE, &b).await.unwrap().is_none());
+            let (_, attr2) = fs
+                .create(
+                    ROOT_INODE,
+                    &b,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                attr2.ino,
+                fs.find_by_name(ROOT_INODE, &b).await.unwrap().unwrap().ino
+            );
+            fs.set_attr(attr2.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            assert_eq!(0o600, fs.get_attr(attr2.ino).await.unwrap().perm);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_negative_lookup_cache() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_negative_lookup_cache",
+        },
+        FsOptions::default().with_entry_ttl(Some(Duration::from_secs(3600))),
+        async {
+            let fs = get_fs().await;
+            let a = SecretString::from_str("a").unwrap();
+            let b = SecretString::from_str("b").unwrap();
+            assert!(fs.find_by_name(ROOT_INODE, &a).await.unwrap().is_none());
+            assert!(fs.find_by_name(ROOT_INODE, &b).await.unwrap().is_none());
+
+            // it's forgotten when created
+            let (_, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &a,
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &a).await.unwrap().unwrap().ino
+            );
+
+            // and when renamed to it
+            fs.rename(ROOT_INODE, &a, ROOT_INODE, &b).await.unwrap();
+            assert!(fs.find_by_name(ROOT_INODE, &a).await.unwrap().is_none());
+            assert_eq!(
+                attr.ino,
+                fs.find_by_name(ROOT_INODE, &b).await.unwrap().unwrap().ino
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_case_insensitive() {
+    run_test(
+        TestSetup {
+            key: "test_case_insensitive",
+        },
+        async {
+            let fs = get_fs().await;
+            let name = |name: &str| SecretString::from_str(name).unwrap();
+            let (_, dir) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("Dir"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            let (_, file) = fs
+                .create(
+                    dir.ino,
+                    &name("File.TXT"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert!(fs
+                .find_by_name(dir.ino, &name("file.txt"))
+                .await
+                .unwrap()
+                .is_none());
+            let open = |case_insensitive| {
+                EncryptedFs::new(
+                    fs.data_dir.clone(),
+                    Box::new(TestPasswordProvider("password")),
+                    Cipher::ChaCha20Poly1305,
+                    FsOptions::default().with_case_insensitive(case_insensitive),
+                )
+            };
+
+            // names that differ only in case can't be told apart
+            fs.create(
+                dir.ino,
+                &name("FILE.txt"),
+                create_attr(FileType::RegularFile),
+                false,
+                false,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(open(true).await, Err(FsError::InvalidInput(_))));
+            fs.remove_file(dir.ino, &name("FILE.txt")).await.unwrap();
+
+            // an existing data dir is changed, and stays case-insensitive
+            open(true).await.unwrap();
+            let fs = open(false).await.unwrap();
+            let found = fs.find_by_name(ROOT_INODE, &name("dIR")).await.unwrap();
+            assert_eq!(dir.ino, found.unwrap().ino);
+            let found = fs.find_by_name(dir.ino, &name("file.txt")).await.unwrap();
+            assert_eq!(file.ino, found.unwrap().ino);
+            assert!(fs.exists_by_name(dir.ino, &name("FILE.TXT")).unwrap());
+            assert!(matches!(
+                fs.create(
+                    dir.ino,
+                    &name("file.txt"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    false,
+                )
+                .await,
+                Err(FsError::AlreadyExists)
+            ));
+
+            // the case is kept, and can be changed
+            let names = |fs: Arc<EncryptedFs>| async move {
+                fs.read_dir(dir.ino)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.unwrap().name.expose_secret().clone())
+                    .filter(|name| name != "." && name != "..")
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(names(fs.clone()).await, vec!["File.TXT"]);
+            fs.rename(dir.ino, &name("FILE.TXT"), dir.ino, &name("file.txt"))
+                .await
+                .unwrap();
+            assert!(fs.exists(file.ino));
+            assert_eq!(names(fs.clone()).await, vec!["file.txt"]);
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_secure_delete() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_secure_delete",
+        },
+        FsOptions::default().with_secure_delete(true),
+        async {
+            let fs = get_fs().await;
+            let name = |name: &str| SecretString::from_str(name).unwrap();
+            let mut inodes = vec![];
+            for file in ["a", "b"] {
+                let (fh, attr) = fs
+                    .create(
+                        ROOT_INODE,
+                        &name(file),
+                        create_attr(FileType::RegularFile),
+                        false,
+                        true,
+                    )
+                    .await
+                    .unwrap();
+                write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                    .await
+                    .unwrap();
+                fs.flush(fh).await.unwrap();
+                fs.release(fh).await.unwrap();
+                inodes.push(attr.ino);
+            }
+
+            // truncated before removed, which is seen through files still open
+            let ino_file = File::open(fs.ino_file(inodes[0])).unwrap();
+            let contents = File::open(fs.contents_path(inodes[0])).unwrap();
+            assert!(contents.metadata().unwrap().len() > 0);
+            fs.remove_file(ROOT_INODE, &name("a")).await.unwrap();
+            assert!(!fs.ino_file(inodes[0]).exists());
+            assert!(!fs.contents_path(inodes[0]).exists());
+            assert_eq!(0, ino_file.metadata().unwrap().len());
+            assert_eq!(0, contents.metadata().unwrap().len());
+
+            // content still used elsewhere is kept
+            let contents = fs.contents_path(inodes[1]);
+            let snapshot = fs.data_dir.join("snapshot");
+            fs::hard_link(&contents, &snapshot).unwrap();
+            let len = fs::metadata(&contents).unwrap().len();
+            fs.remove_file(ROOT_INODE, &name("b")).await.unwrap();
+            assert!(!contents.exists());
+            assert_eq!(len, fs::metadata(&snapshot).unwrap().len());
+            fs::remove_file(snapshot).unwrap();
+
+            // the copies replaced when rewritten are overwritten too
+            let (fh, attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("c"),
+                    create_attr(FileType::RegularFile),
+                    true,
+                    true,
+                )
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-42", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            let ino_file = File::open(fs.ino_file(attr.ino)).unwrap();
+            let contents = File::open(fs.contents_path(attr.ino)).unwrap();
+            assert!(ino_file.metadata().unwrap().len() > 0);
+            assert!(contents.metadata().unwrap().len() > 0);
+            fs.set_attr(attr.ino, SetFileAttr::default().with_perm(0o600))
+                .await
+                .unwrap();
+            write_all_string_to_fs(&fs, attr.ino, 0, "test-43", fh)
+                .await
+                .unwrap();
+            fs.flush(fh).await.unwrap();
+            fs.release(fh).await.unwrap();
+            assert_eq!(0, ino_file.metadata().unwrap().len());
+            assert_eq!(0, contents.metadata().unwrap().len());
+            assert_eq!("test-43", test_common::read_to_string(attr.ino, &fs).await);
+            assert!(!shred_link_path(&fs.ino_file(attr.ino)).exists());
+            assert!(!shred_link_path(&fs.contents_path(attr.ino)).exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_audit_log() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_audit_log",
+        },
+        FsOptions::default().with_audit_log(true),
+        async {
+            let fs = get_fs().await;
+            let name = |name: &str| SecretString::from_str(name).unwrap();
+            let event = |op, read, target| AuditEvent {
+                op,
+                read,
+                target,
+                uid: 1000,
+                pid: 42,
+            };
+            let (_, dir_attr) = fs
+                .create(
+                    ROOT_INODE,
+                    &name("docs"),
+                    create_attr(FileType::Directory),
+                    false,
+                    false,
+                )
+                .await
+                .unwrap();
+            fs.audit(
+                event("mkdir", false, AuditTarget::Entry(ROOT_INODE, name("docs"))),
+                None,
+            )
+            .await;
+            let (fh, attr) = fs
+                .create(
+                    dir_attr.ino,
+                    &name("a"),
+                    create_attr(FileType::RegularFile),
+                    false,
+                    true,
+                )
+                .await
+                .unwrap();
+            fs.release(fh).await.unwrap();
+            fs.audit(
+                event("create", false, AuditTarget::Entry(dir_attr.ino, name("a"))),
+                None,
+            )
+            .await;
+            // not logged without audit_reads
+            fs.audit(event("read", true, AuditTarget::Inode(attr.ino)), None)
+                .await;
+            fs.rename(dir_attr.ino, &name("a"), ROOT_INODE, &name("b"))
+                .await
+                .unwrap();
+            fs.audit(
+                event(
+                    "rename",
+                    false,
+                    AuditTarget::Rename(dir_attr.ino, name("a"), ROOT_INODE, name("b")),
+                ),
+                None,
+            )
+            .await;
+            // found where it was moved
+            fs.audit(event("write", false, AuditTarget::Inode(attr.ino)), None)
+                .await;
+            fs.audit(
+                event("unlink", false, AuditTarget::Entry(ROOT_INODE, name("c"))),
+                Some(libc::ENOENT),
+            )
+            .await;
+
+            let log = EncryptedFs::audit_log(
+                &fs.data_dir,
+                LockedString::from_str("password").unwrap(),
+                Cipher::ChaCha20Poly1305,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                vec![
+                    ("mkdir", "/docs", None, None),
+                    ("create", "/docs/a", None, None),
+                    ("rename", "/docs/a", Some("/b"), None),
+                    ("write", "/b", None, None),
+                    ("unlink", "/c", None, Some(libc::ENOENT)),
+                ],
+                log.iter()
+                    .map(|record| (
+                        record.op.as_str(),
+                        record.path.as_str(),
+                        record.new_path.as_deref(),
+                        record.errno
+                    ))
+                    .collect::<Vec<_>>()
+            );
+            assert!(log
+                .iter()
+                .all(|record| record.uid == 1000 && record.pid == 42));
+            // encrypted
+            let stored = fs::read(fs.audit_log_path()).unwrap();
+            assert!(!stored.windows(b"/docs".len()).any(|w| w == b"/docs"));
+        },
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[traced_test]
+async fn test_audit_log_tampered() {
+    run_test_with_options(
+        TestSetup {
+            key: "test_audit_log_tampered",
+        },
+        FsOptions::default().with_audit_log(true),
+        async {
+            let fs = get_fs().await;
+            for op in ["mkdir", "create", "unlink"] {
+                fs.audit(
+                    AuditEvent {
+                        op,
+                        read: false,
+                        target: AuditTarget::Inode(ROOT_INODE),
+                        uid: 1000,
+                        pid: 42,
+                    },
+                    None,
+                )
+                .await;
+            }
+            let path = fs.audit_log_path();
+            let stored = fs::read(&path).unwrap();
+            let mut frames = vec![];
+            let mut rest = &stored[..];
+            while !rest.is_empty() {
+                let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+                frames.push(rest[..4 + len].to_vec());
+                rest = &rest[4 + len..];
+            }
+            assert_eq!(3, frames.len());
+            let audit_log = || async {
+                EncryptedFs::audit_log(
+                    &fs.data_dir,
+                    LockedString::from_str("password").unwrap(),
+                    Cipher::ChaCha20Poly1305,
+                )
+                .await
+            };
+            assert_eq!(3, audit_log().await.unwrap().len());
+
+            let tampered = [
+                // removed
+                [&frames[0][..], &frames[2]].concat(),
+                // moved
+                [&frames[0][..], &frames[2], &frames[1]].concat(),
+                // cut
+                [&frames[0][..], &frames[1]].concat(),
+                // cut in the middle of a record
+                stored[..stored.len() - 1].to_vec(),
+                // changed
+                {
+                    let mut data = stored.clone();
+                    data[frames[0].len() + 10] ^= 1;
+                    data
+                },
+            ];
+            for data in tampered {
+                fs::write(&path, data).unwrap();
+                assert!(matches!(
+                    audit_log().await,
+                    Err(FsError::AuditLogBroken(..))
+                ));
+            }
+            fs::remove_file(&path).unwrap();
+            assert!(matches!(
+                audit_log().await,
+                Err(FsError::AuditLogBroken(..))
+            ));
+        },
+    )
     .await;
 }
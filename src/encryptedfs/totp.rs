@@ -0,0 +1,147 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::SystemTime;
+
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+
+use crate::crypto;
+use crate::crypto::{Cipher, LockedString, LockedVec};
+use crate::encryptedfs::header::volume_cipher;
+use crate::encryptedfs::{
+    check_structure, read_key, EncryptedFs, FsError, FsResult, KEY_ENC_FILENAME, KEY_SALT_FILENAME,
+    SECURITY_DIR,
+};
+
+/// TOTP secret, encrypted with the master key.
+const TOTP_ENC_FILENAME: &str = "totp.enc";
+
+impl EncryptedFs {
+    /// Require a TOTP code, besides the password, when creating [`EncryptedFs`]. The code is given by
+    /// [`PasswordProvider::get_totp_code`].
+    ///
+    /// `code` is the current code for `secret`, so we know the authenticator app was set up correctly.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn enroll_totp(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        secret: &SecretVec<u8>,
+        code: SecretString,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        let key = read_master_key(data_dir, &password, cipher)?;
+        if !crypto::totp::verify(secret, &code, SystemTime::now()) {
+            return Err(FsError::InvalidTotpCode);
+        }
+        crypto::atomic_serialize_encrypt_into(
+            &data_dir.join(SECURITY_DIR).join(TOTP_ENC_FILENAME),
+            &secret.expose_secret(),
+            cipher,
+            &key,
+        )?;
+        Ok(())
+    }
+
+    /// Don't require a TOTP code anymore.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn remove_totp(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+    ) -> FsResult<()> {
+        check_structure(data_dir, false).await?;
+        let cipher = volume_cipher(&data_dir.join(SECURITY_DIR), cipher)?;
+        read_master_key(data_dir, &password, cipher)?;
+        let security_dir = data_dir.join(SECURITY_DIR);
+        let path = security_dir.join(TOTP_ENC_FILENAME);
+        if !path.exists() {
+            return Err(FsError::NotFound("TOTP"));
+        }
+        fs::remove_file(path)?;
+        File::open(security_dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// If a TOTP code is needed, besides the password, to create [`EncryptedFs`] for the data dir.
+    #[must_use]
+    pub fn is_totp_enrolled(data_dir: &Path) -> bool {
+        data_dir
+            .join(SECURITY_DIR)
+            .join(TOTP_ENC_FILENAME)
+            .is_file()
+    }
+
+    /// Check the code given by the password provider, if the volume needs one.
+    pub(super) async fn check_totp(&self) -> FsResult<()> {
+        let security_dir = self.data_dir.join(SECURITY_DIR);
+        // while rotating the key it might still be encrypted with the old one
+        let mut secret = None;
+        for (cipher, key) in self.master_keys().await? {
+            secret = read_totp_secret(&security_dir, &key, cipher)?;
+            if secret.is_some() {
+                break;
+            }
+        }
+        let Some(secret) = secret else {
+            return Ok(());
+        };
+        let code = self
+            .password_provider
+            .get_totp_code()
+            .ok_or(FsError::InvalidTotpCode)?;
+        if !crypto::totp::verify(&secret, &code, SystemTime::now()) {
+            return Err(FsError::InvalidTotpCode);
+        }
+        Ok(())
+    }
+}
+
+fn read_master_key(
+    data_dir: &Path,
+    password: &SecretString,
+    cipher: Cipher,
+) -> FsResult<LockedVec> {
+    let security_dir = data_dir.join(SECURITY_DIR);
+    read_key(
+        &security_dir.join(KEY_ENC_FILENAME),
+        &security_dir.join(KEY_SALT_FILENAME),
+        password,
+        cipher,
+    )
+}
+
+/// Read the TOTP secret, [`None`] if there is none for the volume `key` is the master key of.
+fn read_totp_secret(
+    security_dir: &Path,
+    key: &SecretVec<u8>,
+    cipher: Cipher,
+) -> FsResult<Option<SecretVec<u8>>> {
+    let path = security_dir.join(TOTP_ENC_FILENAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let reader = crypto::create_read(File::open(path)?, cipher, key);
+    // a hidden volume has another master key
+    Ok(bincode::deserialize_from::<_, Vec<u8>>(reader)
+        .ok()
+        .map(SecretVec::new))
+}
+
+/// Encrypt the TOTP secret with another master key or cipher. Nothing to do if it's not encrypted with `from`,
+/// so it's safe to call it again after a crash.
+pub(super) fn reencrypt_totp_secret(
+    security_dir: &Path,
+    from: (Cipher, &SecretVec<u8>),
+    to: (Cipher, &SecretVec<u8>),
+) -> FsResult<()> {
+    if let Some(secret) = read_totp_secret(security_dir, from.1, from.0)? {
+        crypto::atomic_serialize_encrypt_into(
+            &security_dir.join(TOTP_ENC_FILENAME),
+            &secret.expose_secret(),
+            to.0,
+            to.1,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::encryptedfs::{EncryptedFs, FsError, FsResult, ROOT_INODE, SECURITY_DIR};
+use crate::fs_util;
+
+/// Version of the data dir format written by this version of the crate. Raise it with each change of how things
+/// are stored and add a step from the previous one in [`EncryptedFs::migrate_step`].
+pub const FORMAT_VERSION: u32 = 1;
+/// Oldest format that can be read as it is, so it can be opened read-only without migrating. Raise it when a
+/// step changes how something is read.
+const MIN_READ_ONLY_FORMAT_VERSION: u32 = 0;
+/// Format version of the data dir, in plain text so it's known before reading anything else.
+pub(super) const FORMAT_VERSION_FILENAME: &str = "format.version";
+
+/// Format version of the data dir, `None` if it was created before it was versioned.
+pub(super) fn read_format_version(data_dir: &Path) -> FsResult<Option<u32>> {
+    let path = data_dir.join(SECURITY_DIR).join(FORMAT_VERSION_FILENAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?.trim().parse()?))
+}
+
+fn write_format_version(data_dir: &Path, version: u32) -> FsResult<()> {
+    let mut file =
+        fs_util::open_atomic_write(&data_dir.join(SECURITY_DIR).join(FORMAT_VERSION_FILENAME))?;
+    writeln!(file, "{version}")?;
+    file.commit()?;
+    Ok(())
+}
+
+/// Where the data dir is copied before migrating from `version`, next to it.
+fn backup_path(data_dir: &Path, version: u32) -> PathBuf {
+    let mut name = data_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".backup-v{version}"));
+    data_dir.with_file_name(name)
+}
+
+impl EncryptedFs {
+    /// Upgrade the data dir step by step to [`FORMAT_VERSION`]. The version is written after each step, so an
+    /// interrupted migration continues from the last step on next mount. With
+    /// [`FsOptions::backup_before_migrate`] the data dir is copied first.
+    ///
+    /// Read-only it's only checked that we can read it.
+    ///
+    /// [`FsOptions::backup_before_migrate`]: crate::encryptedfs::FsOptions::backup_before_migrate
+    // while no step changed how things are read the minimum is 0
+    #[allow(clippy::absurd_extreme_comparisons)]
+    pub(super) fn migrate_format(&self) -> FsResult<()> {
+        let version = match read_format_version(&self.data_dir)? {
+            Some(version) => version,
+            // just created
+            None if !self.exists(ROOT_INODE) => {
+                if !self.options.read_only {
+                    write_format_version(&self.data_dir, FORMAT_VERSION)?;
+                }
+                return Ok(());
+            }
+            None => 0,
+        };
+        if version > FORMAT_VERSION {
+            return Err(FsError::UnsupportedFormatVersion(version, FORMAT_VERSION));
+        }
+        if version == FORMAT_VERSION {
+            return Ok(());
+        }
+        if self.options.read_only {
+            if version >= MIN_READ_ONLY_FORMAT_VERSION {
+                return Ok(());
+            }
+            return Err(FsError::FormatMigrationNeeded(version));
+        }
+
+        if self.options.backup_before_migrate {
+            let backup = backup_path(&self.data_dir, version);
+            // made by a migration that was interrupted
+            if !backup.exists() {
+                info!(backup = %backup.display(), "backing up data dir before migrating");
+                // so an interrupted copy is not taken for a backup
+                let mut partial = backup.clone().into_os_string();
+                partial.push(".partial");
+                let partial = PathBuf::from(partial);
+                if partial.exists() {
+                    fs::remove_dir_all(&partial)?;
+                }
+                fs_util::copy_dir_all(&self.data_dir, &partial)?;
+                fs::rename(partial, backup)?;
+            }
+        }
+        for from in version..FORMAT_VERSION {
+            info!(from, to = from + 1, "migrating data dir format");
+            self.migrate_step(from)?;
+            write_format_version(&self.data_dir, from + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Upgrade from `from` to the next version.
+    // later steps will change the data dir and can fail
+    #[allow(clippy::unused_self, clippy::unnecessary_wraps)]
+    fn migrate_step(&self, from: u32) -> FsResult<()> {
+        match from {
+            // from before versioning, nothing changed but the version file
+            0 => Ok(()),
+            _ => unreachable!("no migration from format version {from}"),
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use argon2::password_hash::rand_core::RngCore;
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::crypto;
+use crate::crypto::write::CryptoWrite;
+use crate::crypto::{Cipher, LockedString, LockedVec};
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult,
+    StaticPasswordProvider, StorageLayout,
+};
+use crate::fs_util;
+
+/// Where versions are kept in [`StorageLayout::Hierarchical`], with their index.
+pub(super) const VERSIONS_DIR: &str = "versions";
+const VERSIONS_INDEX_FILENAME: &str = "index";
+
+/// Content a file had before it was overwritten or removed, see [`FsOptions::keep_versions`].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub id: u64,
+    pub ino: u64,
+    /// Parent and name of the file, if it was removed. Else it's still where the file is.
+    pub removed: Option<(u64, String)>,
+    pub size: u64,
+    pub time: SystemTime,
+    /// The content keeps the key the file had.
+    key: LockedVec,
+    /// To create it again like it was, if it was removed.
+    perm: u16,
+    uid: u32,
+    gid: u32,
+}
+
+/// Without the key.
+impl fmt::Debug for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Version")
+            .field("id", &self.id)
+            .field("ino", &self.ino)
+            .field("removed", &self.removed)
+            .field("size", &self.size)
+            .field("time", &self.time)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One version per line, tab separated, so it's easy to parse.
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let kind = if self.removed.is_some() {
+            "removed"
+        } else {
+            "overwritten"
+        };
+        write!(f, "{}\t{kind}\t{time}\tsize={}", self.id, self.size)
+    }
+}
+
+impl EncryptedFs {
+    /// Versions of the file at `path` from root, oldest first, also of the ones removed from there.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn versions(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        path: &str,
+    ) -> FsResult<Vec<Version>> {
+        let fs = Self::open_read_only(data_dir, password, cipher).await?;
+        fs.versions_of(path).await
+    }
+
+    /// Put back the content the file at `path` had in version `id`. If it was removed it's created again, else
+    /// its current content is kept as a version too before it's replaced.
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn restore_version(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        path: &str,
+        id: u64,
+    ) -> FsResult<()> {
+        let fs = Self::new_internal(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            FsOptions::default(),
+            None,
+            false,
+        )
+        .await?;
+        let version = fs
+            .versions_of(path)
+            .await?
+            .into_iter()
+            .find(|version| version.id == id)
+            .ok_or(FsError::NotFound("version"))?;
+        info!(path, id, "restoring version");
+        let (parent, name) = fs.resolve_parent(path).await?;
+        let name = SecretString::from(name.to_string());
+        let (ino, fh) = if let Some(attr) = fs.find_by_name(parent, &name).await? {
+            if attr.kind != FileType::RegularFile {
+                return Err(FsError::InvalidInodeType);
+            }
+            fs.add_version(&attr, None).await?;
+            fs.set_len(attr.ino, 0).await?;
+            (attr.ino, fs.open(attr.ino, false, true).await?)
+        } else {
+            let (fh, attr) = fs
+                .create(
+                    parent,
+                    &name,
+                    CreateFileAttr {
+                        kind: FileType::RegularFile,
+                        perm: version.perm,
+                        uid: version.uid,
+                        gid: version.gid,
+                        rdev: 0,
+                        flags: 0,
+                    },
+                    false,
+                    true,
+                )
+                .await?;
+            (attr.ino, fh)
+        };
+        let mut reader = crypto::create_read(
+            File::open(fs.version_path(version.id))?,
+            fs.cipher,
+            &version.key,
+        )
+        .take(version.size);
+        fs.restore_content(ino, fh, version.size, &mut reader).await
+    }
+
+    /// Keep the content of the file as a version, if enabled, before it's changed. `removed` is the parent and
+    /// name when it's removed.
+    pub(super) async fn keep_version(
+        &self,
+        ino: u64,
+        removed: Option<(u64, String)>,
+    ) -> FsResult<()> {
+        if self.options.keep_versions.is_none() && self.options.version_retention.is_none() {
+            return Ok(());
+        }
+        // as stored, without what's being written in open handles
+        let attr = self.get_inode_from_cache_or_storage(ino).await?;
+        self.add_version(&attr, removed).await
+    }
+
+    async fn add_version(&self, attr: &FileAttr, removed: Option<(u64, String)>) -> FsResult<()> {
+        let ino = attr.ino;
+        let path = self.contents_path(ino);
+        // nothing written yet
+        if !path.is_file() || (attr.size == 0 && removed.is_none()) {
+            return Ok(());
+        }
+        let key = self.file_key(ino).await?;
+        let _guard = self.versions_lock.lock().await;
+        let mut versions = self.read_versions().await?;
+        let id = crypto::create_rng().next_u64();
+        if self.layout == StorageLayout::Hierarchical {
+            fs::create_dir_all(self.data_dir.join(VERSIONS_DIR))?;
+        }
+        if self.is_deduplicated(ino)
+            || self.has_base_content(ino).await?
+            || self.is_plaintext(ino).await?
+        {
+            // the chunks are released when the file changes, the base can change, and versions are encrypted,
+            // keep a copy
+            let file = fs_util::open_atomic_write(&self.version_path(id))?;
+            let mut writer = crypto::create_write(file, self.cipher, &key);
+            io::copy(&mut self.content_reader(ino).await?, &mut writer)?;
+            writer.finish()?.commit()?;
+        } else {
+            // the content is never changed in place, so it stays as it is now
+            fs::hard_link(path, self.version_path(id))?;
+        }
+        debug!(ino, id, "keeping version");
+        versions.push(Version {
+            id,
+            ino,
+            removed,
+            size: attr.size,
+            time: SystemTime::now(),
+            key: (*key).clone(),
+            perm: attr.perm,
+            uid: attr.uid,
+            gid: attr.gid,
+        });
+        self.prune_versions(&mut versions)?;
+        self.write_versions(&versions).await
+    }
+
+    /// Remove the versions older than [`FsOptions::version_retention`] and the ones over
+    /// [`FsOptions::keep_versions`] for each file.
+    fn prune_versions(&self, versions: &mut Vec<Version>) -> FsResult<()> {
+        let now = SystemTime::now();
+        let mut kept = vec![];
+        // newest first, so we know how many of each file we kept already
+        for version in versions.drain(..).rev() {
+            let expired = self.options.version_retention.is_some_and(|retention| {
+                now.duration_since(version.time).unwrap_or_default() > retention
+            });
+            let over = self.options.keep_versions.is_some_and(|keep| {
+                kept.iter()
+                    .filter(|v: &&Version| v.ino == version.ino)
+                    .count()
+                    >= keep
+            });
+            if expired || over {
+                let path = self.version_path(version.id);
+                if path.is_file() {
+                    fs::remove_file(path)?;
+                }
+            } else {
+                kept.push(version);
+            }
+        }
+        kept.reverse();
+        *versions = kept;
+        Ok(())
+    }
+
+    /// Also of the files removed from there, with the versions they had before.
+    async fn versions_of(&self, path: &str) -> FsResult<Vec<Version>> {
+        let (parent, name) = self.resolve_parent(path).await?;
+        let mut inos: HashSet<u64> = self
+            .find_by_name(parent, &SecretString::from(name.to_string()))
+            .await?
+            .map(|attr| attr.ino)
+            .into_iter()
+            .collect();
+        let versions = self.read_versions().await?;
+        inos.extend(
+            versions
+                .iter()
+                .filter(|version| version.removed == Some((parent, name.to_string())))
+                .map(|version| version.ino),
+        );
+        Ok(versions
+            .into_iter()
+            .filter(|version| inos.contains(&version.ino))
+            .collect())
+    }
+
+    /// The parent inode and the name of `path`.
+    async fn resolve_parent<'a>(&self, path: &'a str) -> FsResult<(u64, &'a str)> {
+        let (parent, name) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("path of a file is needed"));
+        }
+        Ok((self.resolve_path(parent).await?.ino, name))
+    }
+
+    fn version_path(&self, id: u64) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self.data_dir.join(VERSIONS_DIR).join(id.to_string()),
+            StorageLayout::Flat => self.object_path(&format!("version:{id}")),
+        }
+    }
+
+    fn versions_index_path(&self) -> PathBuf {
+        match self.layout {
+            StorageLayout::Hierarchical => self
+                .data_dir
+                .join(VERSIONS_DIR)
+                .join(VERSIONS_INDEX_FILENAME),
+            StorageLayout::Flat => self.object_path("versions"),
+        }
+    }
+
+    async fn read_versions(&self) -> FsResult<Vec<Version>> {
+        let path = self.versions_index_path();
+        if !path.is_file() {
+            return Ok(vec![]);
+        }
+        self.deserialize_from_file(&path).await
+    }
+
+    async fn write_versions(&self, versions: &[Version]) -> FsResult<()> {
+        crypto::atomic_serialize_encrypt_into(
+            &self.versions_index_path(),
+            versions,
+            self.cipher,
+            &*self.key.get().await?,
+        )?;
+        Ok(())
+    }
+
+    /// Save the index with the current key, after it was rotated.
+    pub(super) async fn rewrite_versions(&self) -> FsResult<()> {
+        let _guard = self.versions_lock.lock().await;
+        if !self.versions_index_path().is_file() {
+            return Ok(());
+        }
+        let versions = self.read_versions().await?;
+        self.write_versions(&versions).await
+    }
+}
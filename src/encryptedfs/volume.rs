@@ -0,0 +1,202 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{
+    has_volume, CreateFileAttr, DirectoryEntryPlus, EncryptedFs, FileAttr, FileType, FsError,
+    FsOptions, FsResult, StaticPasswordProvider,
+};
+
+/// Working with the files by their path, from the root of the volume which is `/`, for when it's used as a library
+/// instead of mounted. These open and release the handles themselves, for more control use the methods taking an inode.
+impl EncryptedFs {
+    /// Create a volume in `data_dir` and open it, it fails with [`FsError::AlreadyExists`] if there is one already.
+    /// Like [`EncryptedFs::new`] with [`FsOptions::create`], but with the password given directly.
+    pub async fn create_volume(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        if has_volume(data_dir) {
+            return Err(FsError::AlreadyExists);
+        }
+        Self::open_volume(data_dir, password, cipher, options.with_create(true)).await
+    }
+
+    /// Open the volume in `data_dir`, it fails with [`FsError::NoVolume`] if there is none, unless
+    /// [`FsOptions::create`] is set. Like [`EncryptedFs::new`] but with the password given directly.
+    pub async fn open_volume(
+        data_dir: &Path,
+        password: LockedString,
+        cipher: Cipher,
+        options: FsOptions,
+    ) -> FsResult<Arc<Self>> {
+        Self::new(
+            data_dir.to_path_buf(),
+            Box::new(StaticPasswordProvider(password)),
+            cipher,
+            options,
+        )
+        .await
+    }
+
+    /// Attributes of the file or dir at `path`, including changes not yet saved by open handles.
+    pub async fn metadata(&self, path: &str) -> FsResult<FileAttr> {
+        let ino = self.resolve_path(path).await?.ino;
+        self.get_attr(ino).await
+    }
+
+    /// Entries of the dir at `path`, without `.` and `..`.
+    pub async fn read_dir_path(&self, path: &str) -> FsResult<Vec<DirectoryEntryPlus>> {
+        let ino = self.resolve_path(path).await?.ino;
+        let mut entries = vec![];
+        for entry in self.read_dir_plus(ino).await? {
+            let entry = entry?;
+            let name = entry.name.expose_secret();
+            if name != "." && name != ".." {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Create an empty file at `path`, its dir must exist. It's owned by the current user with mode `0644`.
+    pub async fn create_file_path(&self, path: &str) -> FsResult<FileAttr> {
+        self.create_path(path, FileType::RegularFile).await
+    }
+
+    /// Create a dir at `path`, its parent must exist. It's owned by the current user with mode `0755`.
+    pub async fn create_dir_path(&self, path: &str) -> FsResult<FileAttr> {
+        self.create_path(path, FileType::Directory).await
+    }
+
+    async fn create_path(&self, path: &str, kind: FileType) -> FsResult<FileAttr> {
+        let (parent, name) = self.parent_and_name(path).await?;
+        let attr = CreateFileAttr {
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            uid: *crate::UID,
+            gid: *crate::GID,
+            rdev: 0,
+            flags: 0,
+        };
+        let (_, attr) = self.create(parent, &name, attr, false, false).await?;
+        Ok(attr)
+    }
+
+    /// Remove the file or the empty dir at `path`.
+    pub async fn remove_path(&self, path: &str) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(path).await?;
+        if self.resolve_path(path).await?.kind == FileType::Directory {
+            self.remove_dir(parent, &name).await
+        } else {
+            self.remove_file(parent, &name).await
+        }
+    }
+
+    /// Move the file or dir at `from` to `to`, replacing what's there like [`EncryptedFs::rename`].
+    pub async fn rename_path(&self, from: &str, to: &str) -> FsResult<()> {
+        let (parent, name) = self.parent_and_name(from).await?;
+        let (new_parent, new_name) = self.parent_and_name(to).await?;
+        self.rename(parent, &name, new_parent, &new_name).await
+    }
+
+    /// Read from `offset` of the file at `path` until `buf` is full or the end of the file, returns how much was read.
+    pub async fn read_path(&self, path: &str, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let ino = self.file_ino(path).await?;
+        let handle = self.open(ino, true, false).await?;
+        let res = async {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let len = self
+                    .read(ino, offset + filled as u64, &mut buf[filled..], handle)
+                    .await?;
+                if len == 0 {
+                    break;
+                }
+                filled += len;
+            }
+            Ok(filled)
+        }
+        .await;
+        self.release(handle).await?;
+        res
+    }
+
+    /// Write `buf` at `offset` of the file at `path`, it's saved when this returns.
+    pub async fn write_path(&self, path: &str, offset: u64, buf: &[u8]) -> FsResult<()> {
+        let ino = self.file_ino(path).await?;
+        let handle = self.open(ino, false, true).await?;
+        let res = async {
+            let mut written = 0;
+            while written < buf.len() {
+                written += self
+                    .write(ino, offset + written as u64, &buf[written..], handle)
+                    .await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.release(handle).await?;
+        res
+    }
+
+    /// Truncate or extend with zeros the file at `path` to `size`.
+    pub async fn set_len_path(&self, path: &str, size: u64) -> FsResult<()> {
+        let ino = self.file_ino(path).await?;
+        self.set_len(ino, size).await
+    }
+
+    /// The whole content of the file at `path`. Use [`EncryptedFs::read_path`] for parts of big files.
+    #[allow(clippy::cast_possible_truncation)]
+    pub async fn read_file(&self, path: &str) -> FsResult<Vec<u8>> {
+        let size = self.metadata(path).await?.size;
+        let mut buf = vec![0; size as usize];
+        let len = self.read_path(path, 0, &mut buf).await?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Replace the content of the file at `path` with `data`, it's created if it doesn't exist.
+    pub async fn write_file(&self, path: &str, data: &[u8]) -> FsResult<FileAttr> {
+        match self.file_ino(path).await {
+            Ok(ino) => self.set_len(ino, 0).await?,
+            Err(FsError::NotFound(_)) => {
+                self.create_file_path(path).await?;
+            }
+            Err(err) => return Err(err),
+        }
+        self.write_path(path, 0, data).await?;
+        self.metadata(path).await
+    }
+
+    /// Inode of the regular file at `path`, fails with [`FsError::InvalidInodeType`] for a dir.
+    async fn file_ino(&self, path: &str) -> FsResult<u64> {
+        let attr = self.resolve_path(path).await?;
+        if attr.kind == FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok(attr.ino)
+    }
+
+    /// Inode of the dir `path` is in and the name of the last part.
+    async fn parent_and_name(&self, path: &str) -> FsResult<(u64, SecretString)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(FsError::InvalidInput("path is the root"));
+        }
+        let parent = self.resolve_path(parent).await?;
+        if parent.kind != FileType::Directory {
+            return Err(FsError::InvalidInodeType);
+        }
+        Ok((parent.ino, SecretString::from(name.to_string())))
+    }
+}
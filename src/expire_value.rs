@@ -80,8 +80,10 @@ impl<
         None
     }
 
+    /// Next [`ExpireValue::get`] will call the provider, even if there are still references to the old value.
     pub async fn clear(&self) {
         self.cache.clear().await;
+        self.weak.write().await.take();
     }
 }
 
@@ -0,0 +1,92 @@
+//! FIDO2 security keys, through the `fido2-cred` and `fido2-assert` tools from libfido2.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::RngCore;
+
+use rencfs::crypto;
+use rencfs::crypto::LockedString;
+use rencfs::encryptedfs::KeySlotKind;
+
+const RP_ID: &str = "rencfs";
+
+/// Create a credential with the `hmac-secret` extension on the security key and get its secret.
+pub(crate) fn enroll(device: &str) -> Result<(KeySlotKind, LockedString)> {
+    let user_id = STANDARD.encode(random_bytes());
+    let output = run(
+        "fido2-cred",
+        &["-M", "-h", "-i", "-", device],
+        &[&client_data_hash(), RP_ID, "rencfs", &user_id],
+    )?;
+    // client data hash, rp id, format, authenticator data, credential id, ...
+    let credential_id = output
+        .get(4)
+        .ok_or_else(|| anyhow!("unexpected output from fido2-cred"))?
+        .clone();
+    let kind = KeySlotKind::Fido2 {
+        rp_id: RP_ID.to_string(),
+        credential_id,
+        hmac_salt: STANDARD.encode(random_bytes()),
+    };
+    let secret = secret(device, &kind)?;
+    Ok((kind, secret))
+}
+
+/// Get the `hmac-secret` of the credential from the slot, it needs the security key to be touched.
+pub(crate) fn secret(device: &str, kind: &KeySlotKind) -> Result<LockedString> {
+    let KeySlotKind::Fido2 {
+        rp_id,
+        credential_id,
+        hmac_salt,
+    } = kind
+    else {
+        return Err(anyhow!("not a FIDO2 key slot"));
+    };
+    let output = run(
+        "fido2-assert",
+        &["-G", "-h", "-i", "-", device],
+        &[&client_data_hash(), rp_id, credential_id, hmac_salt],
+    )?;
+    // hmac-secret is the last one
+    output
+        .last()
+        .map(|secret| LockedString::new(secret.clone()))
+        .ok_or_else(|| anyhow!("unexpected output from fido2-assert"))
+}
+
+fn run(program: &str, args: &[&str], input: &[&str]) -> Result<Vec<String>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("cannot run {program}, is libfido2 installed? {err}"))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n", input.join("\n")).as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{program} failed with {}", output.status));
+    }
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// We don't verify attestations or signatures, so this can be random.
+fn client_data_hash() -> String {
+    STANDARD.encode(random_bytes())
+}
+
+fn random_bytes() -> [u8; 32] {
+    let mut bytes = [0; 32];
+    crypto::create_rng().fill_bytes(&mut bytes);
+    bytes
+}
@@ -2,6 +2,8 @@
 use atomic_write_file::unix::OpenOptionsExt;
 use atomic_write_file::AtomicWriteFile;
 use futures_util::TryStreamExt;
+use rand::RngCore;
+use std::io::Write;
 use std::path::Path;
 use std::{fs, io};
 use tokio_stream::wrappers::ReadDirStream;
@@ -36,9 +38,66 @@ pub async fn rename_dir_content(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Recursively copies the content of a directory to another, which is created if it doesn't exist.
+pub fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recursively hard links the files of a directory into another, which is created if it doesn't exist.
+pub fn link_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_dir_all(&entry.path(), &dst)?;
+        } else {
+            fs::hard_link(entry.path(), dst)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn open_atomic_write(file: &Path) -> io::Result<AtomicWriteFile> {
     let mut opt = AtomicWriteFile::options();
     #[cfg(unix)]
     opt.preserve_mode(true).preserve_owner(true);
     opt.open(file)
 }
+
+/// Overwrite the file with random bytes and truncate it, synced each time, then remove it, so what was in it can't
+/// be read from the storage after. If it has other hard links, like from snapshots, it's only removed, they still
+/// use the content.
+pub fn shred_and_remove(file: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(file)?;
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::MetadataExt::nlink(&metadata) > 1;
+    #[cfg(not(unix))]
+    let linked = false;
+    if !linked {
+        let mut f = fs::OpenOptions::new().write(true).open(file)?;
+        let mut buf = vec![0_u8; 64 * 1024];
+        let mut left = metadata.len();
+        while left > 0 {
+            #[allow(clippy::cast_possible_truncation)]
+            let len = left.min(buf.len() as u64) as usize;
+            rand::thread_rng().fill_bytes(&mut buf[..len]);
+            f.write_all(&buf[..len])?;
+            left -= len as u64;
+        }
+        f.sync_all()?;
+        f.set_len(0)?;
+        f.sync_all()?;
+    }
+    fs::remove_file(file)
+}
@@ -1,6 +1,8 @@
 use keyring::Entry;
 use secrecy::{ExposeSecret, SecretString};
 
+use rencfs::crypto::LockedString;
+
 const KEYRING_SERVICE: &str = "rencfs";
 const KEYRING_USER: &str = "encrypted_fs";
 
@@ -14,7 +16,7 @@ pub(crate) fn remove(suffix: &str) -> Result<(), keyring::Error> {
     entry.delete_password()
 }
 
-pub(crate) fn get(suffix: &str) -> Result<SecretString, keyring::Error> {
+pub(crate) fn get(suffix: &str) -> Result<LockedString, keyring::Error> {
     let entry = Entry::new(KEYRING_SERVICE, &format!("{KEYRING_USER}.{suffix}"))?;
-    Ok(SecretString::new(entry.get_password()?))
+    Ok(LockedString::new(entry.get_password()?))
 }
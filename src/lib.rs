@@ -38,10 +38,9 @@
 //! use std::io;
 //!
 //! use anyhow::Result;
-//! use secrecy::SecretString;
 //!
-//! use rencfs::crypto::Cipher;
-//! use rencfs::encryptedfs::PasswordProvider;
+//! use rencfs::crypto::{Cipher, LockedString};
+//! use rencfs::encryptedfs::{FsOptions, PasswordProvider};
 //! use rencfs::mount::create_mount_point;
 //! use rencfs::mount::MountPoint;
 //!
@@ -56,9 +55,9 @@
 //!     let data_path = args.next().expect("data_path expected");
 //!     use tracing::info;struct PasswordProviderImpl {}
 //!     impl PasswordProvider for PasswordProviderImpl {
-//!         fn get_password(&self) -> Option<SecretString> {
+//!         fn get_password(&self) -> Option<LockedString> {
 //!             // dummy password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-//!             Some(SecretString::from_str("pass42").unwrap())
+//!             Some(LockedString::from_str("pass42").unwrap())
 //!         }
 //!     }
 //!     let mount_point = create_mount_point(
@@ -70,6 +69,7 @@
 //!         false,
 //!         false,
 //!         false,
+//!         FsOptions::default().with_create(true),
 //!     );
 //!     let handle = mount_point.mount().await?;
 //!     let mut buffer = String::new();
@@ -95,8 +95,8 @@
 //! use std::fs;
 //! use std::str::FromStr;
 //! use secrecy::SecretString;
-//! use rencfs::encryptedfs::{EncryptedFs, FileType, PasswordProvider, CreateFileAttr};
-//! use rencfs::crypto::Cipher;
+//! use rencfs::encryptedfs::{EncryptedFs, FileType, FsOptions, PasswordProvider, CreateFileAttr};
+//! use rencfs::crypto::{Cipher, LockedString};
 //! use anyhow::Result;
 //! use std::path::Path;
 //! use rencfs::encryptedfs::write_all_string_to_fs;
@@ -105,9 +105,9 @@
 //!
 //! struct PasswordProviderImpl {}
 //! impl PasswordProvider for PasswordProviderImpl {
-//!     fn get_password(&self) -> Option<SecretString> {
+//!     fn get_password(&self) -> Option<LockedString> {
 //!         // dummy password, use some secure way to get the password like with [keyring](https://crates.io/crates/keyring) crate
-//!         Some(SecretString::from_str("pass42").unwrap())
+//!         Some(LockedString::from_str("pass42").unwrap())
 //!     }
 //! }
 //!
@@ -118,7 +118,7 @@
 //!     let data_dir = Path::new("/tmp/rencfs_data_test").to_path_buf();
 //!     let  _ = fs::remove_dir_all(data_dir.to_str().unwrap());
 //!     let cipher = Cipher::ChaCha20Poly1305;
-//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher ).await?;
+//!     let mut fs = EncryptedFs::new(data_dir.clone(), Box::new(PasswordProviderImpl{}), cipher, FsOptions::default().with_create(true)).await?;
 //!
 //!     let  file1 = SecretString::from_str("file1").unwrap();
 //!     let (fh, attr) = fs.create(ROOT_INODE, &file1, file_attr(), false, true).await?;
@@ -147,13 +147,62 @@
 //!     }
 //! }
 //! ```
+//! ## Or by path, without inodes and handles
+//!
+//! The methods taking a path, like [`encryptedfs::EncryptedFs::write_file`] and
+//! [`encryptedfs::EncryptedFs::read_dir_path`], open and release the handles themselves. Errors are
+//! [`encryptedfs::FsError`].
+//!
+//! ### Example
+//!
+//! ```
+//! use std::fs;
+//! use std::path::Path;
+//! use std::str::FromStr;
+//!
+//! use anyhow::Result;
+//! use secrecy::ExposeSecret;
+//!
+//! use rencfs::crypto::{Cipher, LockedString};
+//! use rencfs::encryptedfs::{EncryptedFs, FsError, FsOptions};
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<()> {
+//!     let data_dir = Path::new("/tmp/rencfs_data_test_path");
+//!     let _ = fs::remove_dir_all(data_dir);
+//!     let fs = EncryptedFs::create_volume(
+//!         data_dir,
+//!         LockedString::from_str("pass42").unwrap(),
+//!         Cipher::ChaCha20Poly1305,
+//!         FsOptions::default(),
+//!     )
+//!     .await?;
+//!
+//!     fs.create_dir_path("/docs").await?;
+//!     fs.write_file("/docs/hello.txt", b"Hello, world!").await?;
+//!     fs.write_path("/docs/hello.txt", 7, b"there").await?;
+//!     let mut buf = [0; 5];
+//!     fs.read_path("/docs/hello.txt", 7, &mut buf).await?;
+//!     assert_eq!(b"there", &buf);
+//!     assert_eq!(b"Hello, there!", fs.read_file("/docs/hello.txt").await?.as_slice());
+//!     assert_eq!(13, fs.metadata("/docs/hello.txt").await?.size);
+//!     for entry in fs.read_dir_path("/docs").await? {
+//!         println!("{} {}", entry.name.expose_secret(), entry.attr.size);
+//!     }
+//!     assert!(matches!(fs.remove_path("/docs").await, Err(FsError::NotEmpty)));
+//!     fs.remove_path("/docs/hello.txt").await?;
+//!     fs.remove_path("/docs").await?;
+//!     fs::remove_dir_all(data_dir)?;
+//!
+//!     Ok(())
+//! }
+//! ```
 //! ## Change password from code
 //!
 //! ### Example
 //! ```no_run
-//! use rencfs::crypto::Cipher;
+//! use rencfs::crypto::{Cipher, LockedString};
 //! use rencfs::encryptedfs::{EncryptedFs, FsError};
-//! use secrecy::SecretString;
 //! use std::env::args;
 //! use std::path::Path;
 //! use std::str::FromStr;
@@ -168,8 +217,8 @@
 //!
 //!     match EncryptedFs::passwd(
 //!         Path::new(&data_dir),
-//!         SecretString::from_str("old-pass").unwrap(),
-//!         SecretString::from_str("new-pass").unwrap(),
+//!         LockedString::from_str("old-pass").unwrap(),
+//!         LockedString::from_str("new-pass").unwrap(),
 //!         Cipher::ChaCha20Poly1305,
 //!     )
 //!     .await
@@ -192,9 +241,10 @@
 //! use std::str::FromStr;
 //!
 //! use rpassword::read_password;
-//! use secrecy::{ExposeSecret, SecretString};
+//! use secrecy::ExposeSecret;
 //! use tracing::{error, info};
 //!
+//! use rencfs::crypto::LockedString;
 //! use rencfs::encryptedfs::{EncryptedFs, FsError};
 //! #[tokio::main]
 //! async fn main() {
@@ -209,13 +259,13 @@
 //!     use rencfs::crypto::Cipher;
 //!     print!("Enter old password: ");
 //!     io::stdout().flush().unwrap();
-//!     let old_password = SecretString::new(read_password().unwrap());
+//!     let old_password = LockedString::new(read_password().unwrap());
 //!     print!("Enter new password: ");
 //!     io::stdout().flush().unwrap();
-//!     let new_password = SecretString::new(read_password().unwrap());
+//!     let new_password = LockedString::new(read_password().unwrap());
 //!     print!("Confirm new password: ");
 //!     io::stdout().flush().unwrap();
-//!     let new_password2 = SecretString::new(read_password().unwrap());
+//!     let new_password2 = LockedString::new(read_password().unwrap());
 //!     if new_password.expose_secret() != new_password2.expose_secret() {
 //!         error!("Passwords do not match");
 //!         return;
@@ -305,7 +355,11 @@ pub mod crypto;
 pub mod encryptedfs;
 pub mod expire_value;
 pub mod fs_util;
+pub mod metrics;
 pub mod mount;
+pub mod reverse;
+pub mod serve;
+pub mod storage;
 pub mod stream_util;
 pub(crate) mod test_common;
 
@@ -1,15 +1,24 @@
 #![deny(warnings)]
-use std::io::Write;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::io::{IsTerminal, Read, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::os::fd::FromRawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{env, io, panic, process};
 
 use anyhow::Result;
-use clap::{crate_authors, crate_name, crate_version, Arg, ArgAction, ArgMatches, Command};
+use clap::{
+    crate_authors, crate_name, crate_version, Arg, ArgAction, ArgGroup, ArgMatches, Command,
+};
 use ctrlc::set_handler;
+use rand_core::RngCore;
 use rpassword::read_password;
-use secrecy::{ExposeSecret, SecretString};
+use secrecy::{ExposeSecret, SecretString, SecretVec};
 use strum::IntoEnumIterator;
 use thiserror::Error;
 use tokio::sync::Mutex;
@@ -17,16 +26,45 @@ use tokio::{fs, task};
 use tracing::level_filters::LevelFilter;
 use tracing::{error, info, warn, Level};
 use tracing_appender::non_blocking::WorkerGuard;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
 
-use rencfs::crypto::Cipher;
-use rencfs::encryptedfs::{EncryptedFs, FsError, PasswordProvider};
-use rencfs::mount::MountPoint;
-use rencfs::{is_debug, mount};
+use rencfs::crypto;
+use rencfs::crypto::{Cipher, KdfParams, LockedString};
+use rencfs::encryptedfs::{
+    BenchmarkSettings, EncryptedFs, FsError, FsOptions, IdMap, KeySlotKind, PassthroughRule,
+    PasswordProvider, StorageLayout,
+};
+use rencfs::reverse::ReverseFs;
+use rencfs::{is_debug, mount, serve, storage};
 
+mod config;
+mod control;
+mod daemon;
+mod fido2;
 mod keyring;
+mod pkcs11;
+mod systemd;
+mod tpm;
 
-static mut PASS: Option<SecretString> = None;
+/// Passwords of the mounted volumes, by mount point, when they can't be kept in the keyring.
+static PASS: std::sync::Mutex<BTreeMap<String, LockedString>> =
+    std::sync::Mutex::new(BTreeMap::new());
+type PasswordLines = Arc<std::sync::Mutex<VecDeque<LockedString>>>;
+/// Passwords given with `--password-file` or `--password-fd`, used in order instead of prompting. When mounting
+/// several volumes, the ones of the volume being mounted.
+static PASSWORD_INPUT: std::sync::Mutex<Option<PasswordLines>> = std::sync::Mutex::new(None);
+/// It can be read only once, each volume takes the next line.
+static PASSWORD_FD: OnceLock<PasswordLines> = OnceLock::new();
+/// To change what's logged at runtime.
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Exit status when the password is wrong, so scripts can tell it from other failures.
+const EXIT_INVALID_PASSWORD: i32 = 2;
+/// Exit status when reading or writing failed.
+const EXIT_IO: i32 = 3;
 
 #[derive(Debug, Error)]
 enum ExitStatusError {
@@ -34,36 +72,77 @@ enum ExitStatusError {
     Failure(i32),
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let matches = get_cli_args();
+    // before the runtime and the logs start their threads
+    if let Some(("mount", matches)) = matches.subcommand() {
+        if matches.get_flag("daemon") {
+            daemon::fork()?;
+        }
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(&matches))
+}
 
+async fn run(matches: &ArgMatches) -> Result<()> {
     let str = matches.get_one::<String>("log-level").unwrap().as_str();
     let log_level = Level::from_str(str);
     if log_level.is_err() {
         panic!("Invalid log level");
     }
     let log_level = log_level.unwrap();
-    let guard = log_init(log_level);
+    let log_file = matches.get_one::<String>("log-file").map(Path::new);
+    let (daemon, trace_ops) = match matches.subcommand() {
+        Some(("mount", matches)) => (
+            matches.get_flag("daemon"),
+            cfg!(feature = "trace-ops") && matches.get_flag("trace-ops"),
+        ),
+        _ => (false, false),
+    };
+    let directives = matches.get_one::<String>("log-filter").map(String::as_str);
+    let filter = log_filter(log_level, directives, trace_ops).map_err(|err| {
+        eprintln!("Error: invalid log filter: {err}");
+        ExitStatusError::Failure(1)
+    })?;
+    // the output of --daemon goes to its log after, without colors either
+    let ansi = log_file.is_none() && io::stdout().is_terminal() && !daemon;
+    let json = matches.get_one::<String>("log-format").unwrap() == "json";
+    let guard = log_init(filter, log_file, ansi, json).map_err(|err| {
+        eprintln!("Error: cannot open log file: {err}");
+        ExitStatusError::Failure(1)
+    })?;
+    disable_core_dumps();
 
-    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     {
         error!("he he, not yet ready for this platform, but soon my friend, soon :)");
         info!("Bye!");
         return Ok(());
     }
-    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "windows"
+    )))]
     {
         error!("sorry but this platform is not supported!");
         info!("Bye!");
         return Ok(());
     }
 
-    let mount_point = match matches.subcommand() {
-        Some(("mount", matches)) => {
-            Some(matches.get_one::<String>("mount-point").unwrap().as_str())
-        }
-        _ => None,
+    let mount_points: Vec<String> = match matches.subcommand() {
+        Some(("mount", _)) => get_volumes_cli_args()
+            .iter()
+            .map(|matches| {
+                let (_, matches) = matches.subcommand().unwrap();
+                matches.get_one::<String>("mount-point").unwrap().clone()
+            })
+            .collect(),
+        _ => vec![],
     };
 
     let res = task::spawn_blocking(|| {
@@ -83,8 +162,8 @@ async fn main() -> Result<()> {
                 process::exit(*code);
             }
             error!("{err}");
-            if let Some(mount_point) = mount_point {
-                let _ = umount(mount_point).map_err(|err| {
+            for mount_point in &mount_points {
+                let _ = umount(mount_point).await.map_err(|err| {
                     warn!("Cannot umount, maybe it was not mounted: {err}");
                     err
                 });
@@ -93,8 +172,8 @@ async fn main() -> Result<()> {
         }
         Ok(Err(err)) => {
             error!("{err:#?}");
-            if let Some(mount_point) = mount_point {
-                let _ = umount(mount_point).map_err(|err| {
+            for mount_point in &mount_points {
+                let _ = umount(mount_point).await.map_err(|err| {
                     warn!("Cannot umount, maybe it was not mounted: {err}");
                     err
                 });
@@ -104,8 +183,8 @@ async fn main() -> Result<()> {
         }
         Err(err) => {
             error!("{err}");
-            if let Some(mount_point) = mount_point {
-                let _ = umount(mount_point).map_err(|err| {
+            for mount_point in &mount_points {
+                let _ = umount(mount_point).await.map_err(|err| {
                     warn!("Cannot umount, maybe it was not mounted: {err}");
                     err
                 });
@@ -116,12 +195,122 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Keys and passwords are in memory, so they must not end up in a core dump.
+fn disable_core_dumps() {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: we pass a valid rlimit
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        warn!(err = %io::Error::last_os_error(), "cannot disable core dumps");
+    }
+    // core_pattern might pipe dumps to a handler that ignores the limit, this also prevents other processes of
+    // the user from attaching with ptrace and reading our memory
+    #[cfg(target_os = "linux")]
+    // SAFETY: it only changes a flag of the process
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0) } != 0 {
+        warn!(err = %io::Error::last_os_error(), "cannot disable core dumps");
+    }
+    #[cfg(target_os = "freebsd")]
+    {
+        let mut disable = libc::PROC_TRACE_CTL_DISABLE;
+        // SAFETY: it only changes a flag of the process, `data` points to an int as it expects
+        if unsafe {
+            libc::procctl(
+                libc::P_PID,
+                0,
+                libc::PROC_TRACE_CTL,
+                std::ptr::addr_of_mut!(disable).cast(),
+            )
+        } != 0
+        {
+            warn!(err = %io::Error::last_os_error(), "cannot disable core dumps");
+        }
+    }
+}
+
 #[allow(clippy::too_many_lines)]
+/// The arguments given, with the ones of the first volume when mounting named ones.
 fn get_cli_args() -> ArgMatches {
+    get_volumes_cli_args().swap_remove(0)
+}
+
+/// The arguments for each volume to mount, the ones given override the ones in the config. Only the arguments given
+/// if there are no volumes.
+fn get_volumes_cli_args() -> Vec<ArgMatches> {
+    let cli = cli();
+    let matches = cli.clone().get_matches();
+    let Some(("mount", mount)) = matches.subcommand() else {
+        return vec![matches];
+    };
+    let Some(volumes) = mount.get_many::<String>("volume") else {
+        return vec![matches];
+    };
+    let volumes: Vec<_> = volumes.collect();
+    if volumes.len() > 1 {
+        for arg in ["mount-point", "data-dir"] {
+            if mount.contains_id(arg) {
+                cli.clone()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        format!("--{arg} can't be given with several volumes, each has its own"),
+                    )
+                    .exit();
+            }
+        }
+    }
+    let path = matches
+        .get_one::<String>("config")
+        .map_or_else(config::default_path, PathBuf::from);
+    volumes
+        .into_iter()
+        .map(|volume| {
+            let (main_args, mount_args) = match config::volume_args(&path, volume, &cli) {
+                Ok(args) => args,
+                Err(err) => {
+                    eprintln!("Error: {err:#}");
+                    process::exit(1);
+                }
+            };
+            // before the given ones, so those override them
+            let mut args: Vec<OsString> = env::args_os().collect();
+            let at = args.iter().position(|arg| arg == "mount").unwrap();
+            args.splice(at + 1..at + 1, mount_args);
+            args.splice(1..1, main_args);
+            let matches = cli.clone().get_matches_from(args);
+            let (_, mount) = matches.subcommand().unwrap();
+            for arg in ["mount-point", "data-dir"] {
+                if !mount.contains_id(arg) {
+                    cli.clone()
+                        .error(
+                            clap::error::ErrorKind::MissingRequiredArgument,
+                            format!(
+                                "--{arg} is not given and not in volume {volume} of {}",
+                                path.display()
+                            ),
+                        )
+                        .exit();
+                }
+            }
+            matches
+        })
+        .collect()
+}
+
+fn cli() -> Command {
     Command::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .arg_required_else_help(true)
+        .args_override_self(true)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .global(true)
+                .value_name("PATH")
+                .help(format!("TOML file with the volumes to mount by name, default {}", config::default_path().display())),
+        )
         .arg(
             Arg::new("log-level")
                 .long("log-level")
@@ -130,25 +319,145 @@ fn get_cli_args() -> ArgMatches {
                 .default_value("INFO")
                 .help("Log level, possible values: TRACE, DEBUG, INFO, WARN, ERROR"),
         )
+        .arg(
+            Arg::new("log-filter")
+                .long("log-filter")
+                .value_name("DIRECTIVES")
+                .help("What to log by target and level, like RUST_LOG, for example info,rencfs::mount=debug. It takes precedence over --log-level and RUST_LOG. A running mount can get new ones with the log-filter subcommand"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("PATH")
+                .help("Append the logs to this file instead of writing them to stdout"),
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Format of the logs, json is an object per line, with the fields of each event, for journald or ELK. Each FUSE operation is logged at DEBUG, with its inode, duration and result"),
+        )
         .arg(
             Arg::new("cipher")
                 .long("cipher")
                 .short('c')
                 .value_name("cipher")
                 .default_value("ChaCha20Poly1305")
-                .help(format!("Cipher used for encryption, possible values: {}",
+                .help(format!("Cipher used for encryption of new volumes, the ones with a header use the one they were created with, possible values: {}",
                               Cipher::iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
                 )
         )
+        .arg(
+            Arg::new("keyfile")
+                .long("keyfile")
+                .short('k')
+                .global(true)
+                .value_name("KEYFILE")
+                .help("File mixed with the password to derive the key, it's needed each time you unlock the data dir. The password can be left empty to use only the keyfile"),
+        )
+        .arg(
+            Arg::new("password-file")
+                .long("password-file")
+                .global(true)
+                .value_name("PATH")
+                .conflicts_with("password-fd")
+                .help("Read the password from this file instead of prompting for it, for scripts. When more are needed, like old and new password, each is on its own line"),
+        )
+        .arg(
+            Arg::new("password-fd")
+                .long("password-fd")
+                .global(true)
+                .value_name("FD")
+                .value_parser(clap::value_parser!(i32).range(0..))
+                .help("Like --password-file, but read from this open file descriptor, like a pipe"),
+        )
         .subcommand_required(true)
+        .subcommand(
+            Command::new("init")
+                .about("Create a new data dir with the cipher, key derivation cost and layout given, they are kept in its header with the chunk size, which is 16 KiB for now. Mount doesn't create it")
+                .arg(
+                    Arg::new("data-dir")
+                        .long("data-dir")
+                        .short('d')
+                        .required(true)
+                        .value_name("DATA_DIR")
+                        .help("Where to store the encrypted data"),
+                )
+                .arg(
+                    Arg::new("shares")
+                        .long("shares")
+                        .value_name("SHARES")
+                        .value_parser(clap::value_parser!(u8).range(1..))
+                        .requires("threshold")
+                        .help("Instead of a password, unlock with shares of a random secret. How many shares to create"),
+                )
+                .arg(
+                    Arg::new("threshold")
+                        .long("threshold")
+                        .value_name("THRESHOLD")
+                        .value_parser(clap::value_parser!(u8).range(1..))
+                        .requires("shares")
+                        .help("How many of the shares are needed to unlock"),
+                )
+                .arg(
+                    Arg::new("flat-layout")
+                        .long("flat-layout")
+                        .action(ArgAction::SetTrue)
+                        .help("Keep all encrypted objects in a flat pool so it doesn't reveal the directory structure"),
+                )
+                .arg(
+                    Arg::new("recovery-key")
+                        .long("recovery-key")
+                        .action(ArgAction::SetTrue)
+                        .help("Also generate a recovery key and show it once, to unlock it or set a new password with `recover --recovery-key` if the password is forgotten"),
+                )
+                .arg(
+                    Arg::new("kdf-memory")
+                        .long("kdf-memory")
+                        .value_name("KIB")
+                        .value_parser(clap::value_parser!(u32).range(8..))
+                        .help(format!("Memory used by Argon2id to derive the key from the password, in KiB, default {}", KdfParams::default().memory_kib)),
+                )
+                .arg(
+                    Arg::new("kdf-iterations")
+                        .long("kdf-iterations")
+                        .value_name("ITERATIONS")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help(format!("Iterations of Argon2id, default {}", KdfParams::default().iterations)),
+                )
+                .arg(
+                    Arg::new("kdf-target-ms")
+                        .long("kdf-target-ms")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .conflicts_with("kdf-iterations")
+                        .help("Pick the iterations of Argon2id so deriving the key takes about this long on this machine, like 500. Memory is lowered only if even one iteration takes longer"),
+                )
+                .arg(
+                    Arg::new("kdf-parallelism")
+                        .long("kdf-parallelism")
+                        .value_name("LANES")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help(format!("Lanes of Argon2id computed in parallel, default {}", KdfParams::default().parallelism)),
+                )
+        )
         .subcommand(
             Command::new("mount")
                 .about("Mount the filesystem exposing decrypted content from data dir")
+                .args_override_self(true)
+                .arg(
+                    Arg::new("volume")
+                        .value_name("VOLUME")
+                        .num_args(1..)
+                        .help("Names of volumes in --config to take the arguments from, the ones given here override them. Several are mounted together, by the same process"),
+                )
                 .arg(
                     Arg::new("mount-point")
                         .long("mount-point")
                         .short('m')
-                        .required(true)
+                        .required_unless_present("volume")
                         .value_name("MOUNT_POINT")
                         .help("Act as a client, and mount FUSE at given path"),
                 )
@@ -156,10 +465,24 @@ fn get_cli_args() -> ArgMatches {
                     Arg::new("data-dir")
                         .long("data-dir")
                         .short('d')
-                        .required(true)
+                        .required_unless_present("volume")
                         .value_name("DATA_DIR")
                         .help("Where to store the encrypted data"),
                 )
+                .arg(
+                    Arg::new("snapshot")
+                        .long("snapshot")
+                        .value_name("NAME")
+                        .conflicts_with_all(["rotate-key", "backup-before-migrate"])
+                        .help("Mount a snapshot of the data dir instead, made with snapshot create. It's always read-only"),
+                )
+                .arg(
+                    Arg::new("reverse")
+                        .long("reverse")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["snapshot", "volume"])
+                        .help("Mount read-only an encrypted view of the plaintext directory given as --data-dir, for backup and sync tools to copy. It's encrypted on the fly, the same each time. The key is kept in .rencfs-reverse in it, created on the first mount. Decrypt the copy with reverse-decrypt"),
+                )
                 .arg(
                     Arg::new("umount-on-start")
                         .long("umount-on-start")
@@ -167,6 +490,33 @@ fn get_cli_args() -> ArgMatches {
                         .action(ArgAction::SetTrue)
                         .help("If we should try to umount the mountpoint before starting the FUSE server. This can be useful when the previous run crashed or was forced kll and the mountpoint is still mounted."),
                 )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Mount even if another process has the data dir mounted, which can corrupt it. Only if you're sure that one is not writing to it anymore"),
+                )
+                .arg(
+                    Arg::new("daemon")
+                        .long("daemon")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("auto-lock")
+                        .help("Go to the background after it's unlocked and mounted, the command exits when it's ready. Logs are written to --daemon-log"),
+                )
+                .arg(
+                    Arg::new("pid-file")
+                        .long("pid-file")
+                        .value_name("PID_FILE")
+                        .requires("daemon")
+                        .help("Where to write the pid with --daemon, by default in $XDG_RUNTIME_DIR/rencfs, named after the mount point"),
+                )
+                .arg(
+                    Arg::new("daemon-log")
+                        .long("daemon-log")
+                        .value_name("LOG_FILE")
+                        .requires("daemon")
+                        .help("Where the logs go with --daemon, by default next to the pid file"),
+                )
                 .arg(
                     Arg::new("allow-root")
                         .long("allow-root")
@@ -196,7 +546,305 @@ fn get_cli_args() -> ArgMatches {
                         .action(ArgAction::SetTrue)
                         .help("If it should allow setting SUID and SGID when files are created. Default is false and it will unset those flags when creating files"),
                 )
+                .arg(
+                    Arg::new("backend")
+                        .long("backend")
+                        .value_name("NAME")
+                        .value_parser(
+                            mount::backends()
+                                .iter()
+                                .map(|backend| backend.name())
+                                .collect::<Vec<_>>(),
+                        )
+                        .help("How to mount it, the default is the first one built in"),
+                )
+                .arg(
+                    Arg::new("fido2")
+                        .long("fido2")
+                        .value_name("DEVICE")
+                        .conflicts_with_all(["shares", "tpm", "pkcs11-module"])
+                        .help("Unlock with a FIDO2 security key enrolled with enroll-fido2 instead of the password, like /dev/hidraw0"),
+                )
+                .arg(
+                    Arg::new("password-attempts")
+                        .long("password-attempts")
+                        .value_name("N")
+                        .default_value("3")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help("How many times to ask for the password when it's wrong, waiting twice as long after each one. It exits with 2 when the last one is wrong too"),
+                )
+                .arg(
+                    Arg::new("use-keyring")
+                        .long("use-keyring")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["fido2", "tpm", "shares", "pkcs11-module"])
+                        .help("Get the password from the OS keyring, like Secret Service or macOS Keychain. If it's not there, it's saved for next mounts after you enter it"),
+                )
+                .arg(
+                    Arg::new("pkcs11-module")
+                        .long("pkcs11-module")
+                        .value_name("MODULE")
+                        .conflicts_with_all(["shares", "tpm"])
+                        .help("Unlock with the key slot added by enroll-pkcs11 for this module instead of the password, it prompts for the PIN of the token"),
+                )
+                .arg(
+                    Arg::new("pkcs11-slot")
+                        .long("pkcs11-slot")
+                        .value_name("SLOT")
+                        .requires("pkcs11-module")
+                        .help("Slot of the token, by default the one used by enroll-pkcs11"),
+                )
+                .arg(
+                    Arg::new("tpm")
+                        .long("tpm")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("shares")
+                        .help("Unlock with the secret sealed to the TPM by enroll-tpm instead of the password, so it can be mounted at boot"),
+                )
+                .arg(
+                    Arg::new("shares")
+                        .long("shares")
+                        .action(ArgAction::SetTrue)
+                        .help("Unlock with shares created by init --shares instead of a password"),
+                )
+                .arg(
+                    Arg::new("read-only")
+                        .long("read-only")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("rotate-key")
+                        .help("Mount read-only, nothing in the data dir is changed, not even access times. Useful for backups or to just browse it"),
+                )
+                .arg(
+                    Arg::new("map-uid")
+                        .long("map-uid")
+                        .value_name("STORED:SHOWN")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_id_pair)
+                        .help("Show files owned by the STORED uid as owned by SHOWN, new files with SHOWN are stored with STORED. Can be given multiple times"),
+                )
+                .arg(
+                    Arg::new("map-gid")
+                        .long("map-gid")
+                        .value_name("STORED:SHOWN")
+                        .action(ArgAction::Append)
+                        .value_parser(parse_id_pair)
+                        .help("Like --map-uid, for groups"),
+                )
+                .arg(
+                    Arg::new("owned-by-mounter")
+                        .long("owned-by-mounter")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["map-uid", "map-gid"])
+                        .help("Show all files as owned by the user and group that mounts, whoever created the data dir"),
+                )
+                .arg(
+                    Arg::new("umask")
+                        .long("umask")
+                        .value_name("OCTAL")
+                        .value_parser(parse_mode)
+                        .help("Umask for new files and directories, instead of the one of the process creating them, like 002 for a shared directory"),
+                )
+                .arg(
+                    Arg::new("file-mode")
+                        .long("file-mode")
+                        .value_name("OCTAL")
+                        .value_parser(parse_mode)
+                        .help("Permissions of new files, like 660, whatever the process creating them asks for"),
+                )
+                .arg(
+                    Arg::new("dir-mode")
+                        .long("dir-mode")
+                        .value_name("OCTAL")
+                        .value_parser(parse_mode)
+                        .help("Permissions of new directories, like 2770, whatever the process creating them asks for"),
+                )
+                .arg(
+                    Arg::new("attr-timeout")
+                        .long("attr-timeout")
+                        .value_name("SECONDS")
+                        .value_parser(parse_secs)
+                        .help("How long the kernel and rencfs keep attributes of files before reading them again, like 0.5, default 1. What's changed through the mount is always seen, longer makes `ls -l` and `find` faster but changes to the data dir from outside are seen later"),
+                )
+                .arg(
+                    Arg::new("entry-timeout")
+                        .long("entry-timeout")
+                        .value_name("SECONDS")
+                        .value_parser(parse_secs)
+                        .help("Like --attr-timeout, for names looked up in directories"),
+                )
+                .arg(
+                    Arg::new("options")
+                        .long("options")
+                        .short('O')
+                        .value_name("OPTIONS")
+                        .value_delimiter(',')
+                        .action(ArgAction::Append)
+                        .value_parser(parse_mount_option)
+                        .help("Options like the ones of mount -o, separated by commas: noexec, nosuid, nodev, fsname=NAME and subtype=NAME for how mount and df show it, attr_timeout=SECONDS and entry_timeout=SECONDS like --attr-timeout and --entry-timeout"),
+                )
+                .arg(
+                    Arg::new("case-insensitive")
+                        .long("case-insensitive")
+                        .action(ArgAction::SetTrue)
+                        .help("Find names whatever their case, they are listed as they were created, like on Windows and macOS. The data dir stays case-insensitive after, it's refused if a directory has names that differ only in case"),
+                )
+                .arg(
+                    Arg::new("auto-lock")
+                        .long("auto-lock")
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Lock after this many seconds without activity. Keys are wiped from memory and access is denied until you enter the password again in the terminal"),
+                )
+                .arg(
+                    Arg::new("pad-file-sizes")
+                        .long("pad-file-sizes")
+                        .action(ArgAction::SetTrue)
+                        .help("Pad encrypted files to fixed size classes so the data dir doesn't reveal the exact size of files"),
+                )
+                .arg(
+                    Arg::new("secure-delete")
+                        .long("secure-delete")
+                        .action(ArgAction::SetTrue)
+                        .help("When a file is removed, overwrite its key and content with random bytes before removing them, so they can't be recovered from the storage after. Slower to remove big files"),
+                )
+                .arg(
+                    Arg::new("audit-log")
+                        .long("audit-log")
+                        .action(ArgAction::SetTrue)
+                        .help("Log each operation that changes something, with its path, uid, pid, time and result, encrypted in the data dir. Read it with the audit-log command"),
+                )
+                .arg(
+                    Arg::new("audit-reads")
+                        .long("audit-reads")
+                        .action(ArgAction::SetTrue)
+                        .requires("audit-log")
+                        .help("Also log opening for read, reading and listing, which makes the log a lot bigger"),
+                )
+                .arg(
+                    Arg::new("rotate-key")
+                        .long("rotate-key")
+                        .action(ArgAction::SetTrue)
+                        .help("Generate a new master key and re-encrypt all data with it in background while mounted. An interrupted rotation is resumed on next mount"),
+                )
+                .arg(
+                    Arg::new("scrub-bandwidth")
+                        .long("scrub-bandwidth")
+                        .value_name("BYTES_PER_SEC")
+                        .value_parser(parse_size)
+                        .help("Read and authenticate all chunks in background at most this fast, like 1M, so bit rot is found before you read the files. Bad chunks go to the corruption log"),
+                )
+                .arg(
+                    Arg::new("storage")
+                        .long("storage")
+                        .value_name("LOCATION")
+                        .conflicts_with("snapshot")
+                        .help("Keep the data dir in this storage too, the data dir is then a local cache of it. It's pulled from there when mounted and what changes is put back every few seconds and on unmount. A path of a directory, file://PATH, s3://BUCKET/PREFIX, with credentials and region from the AWS_* env vars, or sftp://[USER@]HOST[:PORT]/PATH"),
+                )
+                .arg(
+                    Arg::new("max-size")
+                        .long("max-size")
+                        .value_name("BYTES")
+                        .value_parser(parse_size)
+                        .help("Refuse writes that would make the files take more than this, like 10G, with no space left on device. The free space shown is what's left of it"),
+                )
+                .arg(
+                    Arg::new("base")
+                        .long("base")
+                        .value_name("DATA_DIR")
+                        .conflicts_with_all(["read-only", "snapshot"])
+                        .help("Show what's in this data dir too, under what's in the mounted one. It's never changed, files are copied up when written and what's removed stays hidden. It needs the same password, and the mounted data dir must be mounted with it after"),
+                )
+                .arg(
+                    Arg::new("dedup")
+                        .long("dedup")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("rotate-key")
+                        .help("Store blocks that are the same in several files only once. Files are split into chunks each time they are written. Not for the flat layout"),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .value_name("LEVEL")
+                        .num_args(0..=1)
+                        .default_missing_value("3")
+                        .value_parser(clap::value_parser!(i32).range(1..=22))
+                        .conflicts_with("rotate-key")
+                        .help("Compress content with zstd before encrypting it, at this level or 3. Blocks that don't compress are kept as they are. Files are split into chunks like with --dedup"),
+                )
+                .arg(
+                    Arg::new("keep-versions")
+                        .long("keep-versions")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Keep the content files had before they were overwritten or removed, at most this many for each file. See the versions command"),
+                )
+                .arg(
+                    Arg::new("retention")
+                        .long("retention")
+                        .value_name("DAYS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Keep versions of files for this many days, with or without --keep-versions"),
+                )
+                .arg(
+                    Arg::new("backup-before-migrate")
+                        .long("backup-before-migrate")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("read-only")
+                        .help("If the data dir was created by an older version and needs to be migrated, copy it first next to it, as DATA_DIR.backup-vVERSION"),
+                )
+                .args(metrics_args())
+                .args(trace_ops_args())
+        ).subcommand(
+        Command::new("umount")
+            .about("Unmount the filesystem. If it was mounted with --daemon that process is stopped, so it unmounts cleanly")
+            .arg(
+                Arg::new("mount-point")
+                    .long("mount-point")
+                    .short('m')
+                    .required(true)
+                    .value_name("MOUNT_POINT")
+                    .help("Where it's mounted"),
+            )
+            .arg(
+                Arg::new("pid-file")
+                    .long("pid-file")
+                    .value_name("PID_FILE")
+                    .help("The one given to mount with --daemon, if any"),
+            )
         ).subcommand(
+        Command::new("stats")
+            .about("Print statistics of a running mount, its open handles, inodes, caches and how long FUSE operations take. The mount logs them too on SIGUSR1")
+            .arg(
+                Arg::new("mount-point")
+                    .long("mount-point")
+                    .short('m')
+                    .required(true)
+                    .value_name("MOUNT_POINT")
+                    .help("Where it's mounted"),
+            )
+    ).subcommand(
+        Command::new("ctl")
+            .about("Manage a running mount through its control socket, without signals")
+            .arg(
+                Arg::new("mount-point")
+                    .long("mount-point")
+                    .short('m')
+                    .required(true)
+                    .value_name("MOUNT_POINT")
+                    .help("Where it's mounted"),
+            )
+            .subcommand_required(true)
+            .subcommand(Command::new("lock").about("Wipe the keys from memory and deny access until it's unlocked"))
+            .subcommand(Command::new("unlock").about("Ask for the password and unlock it"))
+            .subcommand(Command::new("stats").about("Print the same as the stats command"))
+            .subcommand(Command::new("flush").about("Write the files open for write, and everything to --storage if any"))
+            .subcommand(Command::new("unmount").about("Unmount it, the process exits after its last mount"))
+            .subcommand(
+                Command::new("log-filter")
+                    .about("Change what it logs, like RUST_LOG")
+                    .arg(Arg::new("directives").required(true).value_name("DIRECTIVES")),
+            )
+    ).subcommand(
         Command::new("passwd")
             .about("Change password for the master key used to encrypt the data")
             .arg(
@@ -207,293 +855,3288 @@ fn get_cli_args() -> ArgMatches {
                     .value_name("DATA_DIR")
                     .help("Where to store the encrypted data"),
             )
-    )
-        .get_matches()
-}
-
-async fn async_main() -> Result<()> {
-    let matches = get_cli_args();
-
-    let cipher: String = matches.get_one::<String>("cipher").unwrap().to_string();
-    let cipher = Cipher::from_str(cipher.as_str());
-    if cipher.is_err() {
-        error!("Invalid cipher");
-        return Err(ExitStatusError::Failure(1).into());
-    }
-    let cipher = cipher.unwrap();
-
-    match matches.subcommand() {
-        Some(("change-password", matches)) => run_change_password(cipher, matches).await?,
-        Some(("mount", matches)) => run_mount(cipher, matches).await?,
-        None => {
-            error!("No subcommand provided");
-            return Err(ExitStatusError::Failure(1).into());
-        }
-        _ => {
-            error!("Invalid subcommand");
-            return Err(ExitStatusError::Failure(1).into());
-        }
-    }
-
-    Ok(())
-}
-
-async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
-    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
-
-    // read password from stdin
-    print!("Enter old password: ");
-    io::stdout().flush().unwrap();
-    let password = SecretString::new(read_password().unwrap());
-    print!("Enter new password: ");
-    io::stdout().flush().unwrap();
-    let new_password = SecretString::new(read_password().unwrap());
-    print!("Confirm new password: ");
-    io::stdout().flush().unwrap();
-    let new_password2 = SecretString::new(read_password().unwrap());
-    if new_password.expose_secret() != new_password2.expose_secret() {
-        println!("Passwords do not match");
-        return Err(ExitStatusError::Failure(1).into());
-    }
-    println!("Changing password...");
-    EncryptedFs::passwd(Path::new(&data_dir), password, new_password, cipher)
-        .await
-        .map_err(|err| {
-            match err {
-                FsError::InvalidPassword => {
-                    println!("Invalid old password");
-                }
-                FsError::InvalidDataDirStructure => {
-                    println!("Invalid structure of data directory");
-                }
-                _ => {
-                    error!(err = %err);
-                }
-            }
-            ExitStatusError::Failure(1)
-        })?;
-    println!("Password changed successfully");
-
-    Ok(())
-}
-
-async fn run_mount(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
-    let mountpoint: String = matches
-        .get_one::<String>("mount-point")
-        .unwrap()
-        .to_string();
-
-    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
-
-    // when running from IDE we can't read from stdin with rpassword, get it from env var
-    let mut password =
-        SecretString::new(env::var("RENCFS_PASSWORD").unwrap_or_else(|_| String::new()));
-    if password.expose_secret().is_empty() {
+            .arg(
+                Arg::new("new-keyfile")
+                    .long("new-keyfile")
+                    .value_name("KEYFILE")
+                    .help("Keyfile to use with the new password, by default the one from --keyfile is kept"),
+            )
+            .arg(
+                Arg::new("old-password-file")
+                    .long("old-password-file")
+                    .value_name("PATH")
+                    .conflicts_with("old-password-fd")
+                    .help("Read the old password from the first line of this file instead of prompting for it"),
+            )
+            .arg(
+                Arg::new("old-password-fd")
+                    .long("old-password-fd")
+                    .value_name("FD")
+                    .value_parser(clap::value_parser!(i32).range(0..))
+                    .help("Like --old-password-file, but read from this open file descriptor, like a pipe"),
+            )
+            .arg(
+                Arg::new("new-password-file")
+                    .long("new-password-file")
+                    .value_name("PATH")
+                    .conflicts_with("new-password-fd")
+                    .help("Read the new password from the first line of this file instead of prompting for it, it's not confirmed"),
+            )
+            .arg(
+                Arg::new("new-password-fd")
+                    .long("new-password-fd")
+                    .value_name("FD")
+                    .value_parser(clap::value_parser!(i32).range(0..))
+                    .help("Like --new-password-file, but read from this open file descriptor, like a pipe"),
+            )
+    ).subcommand(
+        Command::new("hidden-volume")
+            .about("Create a hidden volume inside a data dir with flat layout, unlocked by a second password")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+    ).subcommand(
+        Command::new("enroll-fido2")
+            .about("Add a FIDO2 security key with hmac-secret support to unlock the data dir, the password keeps working")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("device")
+                    .long("device")
+                    .required(true)
+                    .value_name("DEVICE")
+                    .help("The security key, like /dev/hidraw0, see fido2-token -L"),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .default_value("fido2")
+                    .value_name("NAME")
+                    .help("Name of the key slot"),
+            )
+    ).subcommand(
+        Command::new("enroll-tpm")
+            .about("Seal a secret to the TPM 2.0 of this machine to unlock the data dir without a password, the password keeps working")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("pcrs")
+                    .long("pcrs")
+                    .value_name("PCRS")
+                    .help("Bind the secret to the current values of these PCRs, like sha256:0,7. It can't be unsealed if the firmware, bootloader or secure boot state changes"),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .default_value("tpm")
+                    .value_name("NAME")
+                    .help("Name of the key slot"),
+            )
+    ).subcommand(
+        Command::new("enroll-pkcs11")
+            .about("Add a key slot unlocked by an RSA key kept in a PKCS#11 token or HSM, the password keeps working")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("pkcs11-module")
+                    .long("pkcs11-module")
+                    .required(true)
+                    .value_name("MODULE")
+                    .help("PKCS#11 module of the token, like /usr/lib/opensc-pkcs11.so"),
+            )
+            .arg(
+                Arg::new("pkcs11-slot")
+                    .long("pkcs11-slot")
+                    .value_name("SLOT")
+                    .help("Slot of the token, by default the first one with a token"),
+            )
+            .arg(
+                Arg::new("key-label")
+                    .long("key-label")
+                    .required(true)
+                    .value_name("LABEL")
+                    .help("Label of the RSA key pair on the token"),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .default_value("pkcs11")
+                    .value_name("NAME")
+                    .help("Name of the key slot"),
+            )
+    ).subcommand(
+        Command::new("enroll-totp")
+            .about("Require a code from an authenticator app, besides the password, to mount the data dir")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+    ).subcommand(
+        Command::new("remove-totp")
+            .about("Don't require a code from an authenticator app to mount the data dir anymore")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+    ).subcommand(
+        Command::new("add-key-slot")
+            .about("Add another way to unlock the data dir, like a key slot of LUKS, so each one who uses it can have their own and be revoked alone. The password keeps working")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .required(true)
+                    .value_name("NAME")
+                    .help("Name of the key slot, to revoke it later"),
+            )
+            .arg(
+                Arg::new("kind")
+                    .long("kind")
+                    .value_parser(["password", "keyfile", "recovery-key"])
+                    .default_value("password")
+                    .help("password asks for a new one, keyfile unlocks with --slot-keyfile and an empty password, recovery-key generates one and shows it once"),
+            )
+            .arg(
+                Arg::new("slot-keyfile")
+                    .long("slot-keyfile")
+                    .value_name("KEYFILE")
+                    .required_if_eq("kind", "keyfile")
+                    .help("The keyfile of the slot, mount with --keyfile KEYFILE and an empty password to use it"),
+            )
+    ).subcommand(
+        Command::new("key-slots")
+            .about("List the key slots of the data dir, their name and kind. It doesn't need the password")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+    ).subcommand(
+        Command::new("revoke-key-slot")
+            .about("Remove a key slot, what unlocked it doesn't work anymore. The password keeps working")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("name")
+                    .long("name")
+                    .required(true)
+                    .value_name("NAME")
+                    .help("Name of the key slot"),
+            )
+    ).subcommand(
+        Command::new("migrate-cipher")
+            .about("Re-encrypt a data dir created with --cipher using another cipher. The filesystem must not be mounted meanwhile")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("to")
+                    .long("to")
+                    .required(true)
+                    .value_name("cipher")
+                    .help(format!("Cipher to migrate to, possible values: {}",
+                                  Cipher::iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+                    )
+            )
+    ).subcommand(
+        Command::new("info")
+            .about("Print the cipher, KDF cost, format version, creation date, approximate number of files and size of the data dir without mounting it. It doesn't need the password")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("unlock")
+                    .long("unlock")
+                    .action(ArgAction::SetTrue)
+                    .help("Ask for the password to also print the exact number of files and their size, from their metadata"),
+            )
+    ).subcommand(
+        Command::new("check")
+            .about("Check the data dir for corruption without changing it, it's better to not be mounted meanwhile. Prints one issue per line and exits with 1 if any")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+    ).subcommand(
+        Command::new("bench")
+            .about("Measure sequential and random reads and writes and metadata operations on a temporary volume, with the cipher and settings given, to compare them on this machine")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where to create the temporary volume, on the disk to measure. It's removed after"),
+            )
+            .arg(
+                Arg::new("file-size")
+                    .long("file-size")
+                    .value_name("BYTES")
+                    .value_parser(parse_size)
+                    .default_value("64M")
+                    .help("Of the file read and written, with K, M or G suffix"),
+            )
+            .arg(
+                Arg::new("io-size")
+                    .long("io-size")
+                    .value_name("BYTES")
+                    .value_parser(parse_size)
+                    .default_value("128K")
+                    .help("Of each read and write, with K, M or G suffix"),
+            )
+            .arg(
+                Arg::new("files")
+                    .long("files")
+                    .value_name("FILES")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("1000")
+                    .help("How many files to create, look up, stat and remove for the metadata operations"),
+            )
+            .arg(
+                Arg::new("pad-file-sizes")
+                    .long("pad-file-sizes")
+                    .action(ArgAction::SetTrue)
+                    .help("Like with mount"),
+            )
+            .arg(
+                Arg::new("flat-layout")
+                    .long("flat-layout")
+                    .action(ArgAction::SetTrue)
+                    .help("Like with mount"),
+            )
+            .arg(
+                Arg::new("dedup")
+                    .long("dedup")
+                    .action(ArgAction::SetTrue)
+                    .help("Like with mount"),
+            )
+            .arg(
+                Arg::new("compress")
+                    .long("compress")
+                    .value_name("LEVEL")
+                    .num_args(0..=1)
+                    .default_missing_value("3")
+                    .value_parser(clap::value_parser!(i32).range(1..=22))
+                    .help("Like with mount"),
+            )
+    ).subcommand(
+        Command::new("recover")
+            .about("Copy all that still decrypts from a corrupted data dir, decrypted, to another directory. Bad chunks are filled with zeros and logged. With --recovery-key set a new password instead")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("dest")
+                    .long("dest")
+                    .required_unless_present("recovery-key")
+                    .value_name("DEST")
+                    .help("Empty directory to copy the files to"),
+            )
+            .arg(
+                Arg::new("recovery-key")
+                    .long("recovery-key")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("dest")
+                    .help("Ask for the recovery key, or the secret of another key slot, and a new password to set, for when the password is forgotten"),
+            )
+    ).subcommand(
+        Command::new("verify")
+            .about("Verify the content of all files against the tree kept while mounted, which finds files replaced with older versions. Prints one issue per line, then the root and generation of the tree, and exits with 1 if any")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("min-generation")
+                    .long("min-generation")
+                    .value_name("GENERATION")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Generation printed by a previous verify, an older one means the whole data dir was rolled back"),
+            )
+            .arg(
+                Arg::new("rebuild")
+                    .long("rebuild")
+                    .action(ArgAction::SetTrue)
+                    .help("Build the tree again from the files as they are now, when it's missing or can't be read, then verify. Only if you trust the files, changes made to them from outside won't be found anymore"),
+            )
+    ).subcommand(
+        Command::new("corruption-log")
+            .about("Print the chunks that failed authentication while reading, one per line as inode, path, chunk index and offset")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("clear")
+                    .long("clear")
+                    .action(ArgAction::SetTrue)
+                    .help("Forget them, after the files were restored or removed"),
+            )
+    ).subcommand(
+        Command::new("audit-log")
+            .about("Print the operations logged while mounted with --audit-log, one per line as time, operation, path, uid, pid and result, oldest first")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("op")
+                    .long("op")
+                    .value_name("OPERATION")
+                    .help("Only this operation, like unlink or rename"),
+            )
+            .arg(
+                Arg::new("path")
+                    .long("path")
+                    .value_name("PATH")
+                    .help("Only on this path or under it, like /docs"),
+            )
+            .arg(
+                Arg::new("uid")
+                    .long("uid")
+                    .value_name("UID")
+                    .value_parser(clap::value_parser!(u32))
+                    .help("Only by this user"),
+            )
+            .arg(
+                Arg::new("since")
+                    .long("since")
+                    .value_name("SECONDS")
+                    .value_parser(clap::value_parser!(u64))
+                    .help("Only from this time on, in seconds since the epoch"),
+            )
+            .arg(
+                Arg::new("errors")
+                    .long("errors")
+                    .action(ArgAction::SetTrue)
+                    .help("Only the ones that failed"),
+            )
+    ).subcommand(
+        Command::new("export")
+            .about("Write the volume, or a subtree of it, to a single archive file encrypted with the same password, without mounting it")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("archive")
+                    .long("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("File to write the archive to"),
+            )
+            .arg(
+                Arg::new("subtree")
+                    .long("subtree")
+                    .value_name("PATH")
+                    .help("Path in the volume to export only what's under it, like /docs"),
+            )
+    ).subcommand(
+        Command::new("restore")
+            .about("Create a new volume from an archive made by export, with the password it was exported with")
+            .arg(
+                Arg::new("archive")
+                    .long("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive made by export"),
+            )
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Empty directory to create the volume in"),
+            )
+    ).subcommand(
+        Command::new("reverse-decrypt")
+            .about("Decrypt a copy of the view mounted with mount --reverse, with the password it was mounted with")
+            .arg(
+                Arg::new("src")
+                    .long("src")
+                    .required(true)
+                    .value_name("SRC")
+                    .help("Copy of the encrypted view, with .rencfs-reverse in it"),
+            )
+            .arg(
+                Arg::new("out")
+                    .long("out")
+                    .required(true)
+                    .value_name("OUT")
+                    .help("Where to put the plaintext, it's created if it doesn't exist"),
+            )
+    ).subcommand(
+        Command::new("import")
+            .about("Encrypt an existing plaintext directory into the data dir, which is created if it's empty, with files copied in parallel")
+            .arg(
+                Arg::new("src")
+                    .long("src")
+                    .required(true)
+                    .value_name("SRC")
+                    .help("Plaintext directory to import, what's in it is put in root of the volume"),
+            )
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("workers")
+                    .long("workers")
+                    .value_name("WORKERS")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Files copied in parallel, defaults to the number of CPUs"),
+            )
+            .arg(
+                Arg::new("verify")
+                    .long("verify")
+                    .action(ArgAction::SetTrue)
+                    .help("Read back each file and compare it with the source, exits with 1 if any doesn't match"),
+            )
+    ).subcommand(
+        Command::new("snapshot")
+            .about("Manage snapshots of the data dir, take them while it's not mounted. Mount one with mount --snapshot NAME")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("create")
+                    .about("Freeze the data dir as it is now, only what changes after takes space")
+                    .arg(Arg::new("name").required(true).value_name("NAME")),
+            )
+            .subcommand(Command::new("list").about("Print the snapshots, oldest first"))
+            .subcommand(
+                Command::new("delete")
+                    .about("Delete the snapshot and reclaim its space")
+                    .arg(Arg::new("name").required(true).value_name("NAME")),
+            )
+    ).subcommand(
+        Command::new("versions")
+            .about("Manage versions of files kept with mount --keep-versions or --retention")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("list")
+                    .about("Print the versions of the file at PATH in the volume, also of the ones removed from there, oldest first")
+                    .arg(Arg::new("path").required(true).value_name("PATH")),
+            )
+            .subcommand(
+                Command::new("restore")
+                    .about("Put back the content the file had in version ID. Its current content is kept as a version too")
+                    .arg(Arg::new("path").required(true).value_name("PATH"))
+                    .arg(
+                        Arg::new("id")
+                            .required(true)
+                            .value_name("ID")
+                            .value_parser(clap::value_parser!(u64)),
+                    ),
+            )
+    ).subcommand(
+        Command::new("passthrough")
+            .about("Manage rules for files whose content is stored without encryption, or which can't be created at all, like caches. They apply from the next mount, to files created after")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .subcommand_required(true)
+            .subcommand(Command::new("list").about("Print the rules, as pattern and action, in the order they are tried"))
+            .subcommand(
+                Command::new("add")
+                    .about("Add a rule after the others. PATTERN is matched against the path from root, like '.cache/**', or the name if it has no '/', like '*.tmp'. ACTION is plaintext, the default, or exclude")
+                    .arg(Arg::new("rule").required(true).value_name("PATTERN[:ACTION]")),
+            )
+            .subcommand(
+                Command::new("remove")
+                    .about("Remove the rules with this pattern")
+                    .arg(Arg::new("pattern").required(true).value_name("PATTERN")),
+            )
+    ).subcommand(
+        Command::new("serve")
+            .about("Give access to the decrypted files over the network instead of mounting them, for where FUSE can't be used")
+            .arg(
+                Arg::new("data-dir")
+                    .long("data-dir")
+                    .short('d')
+                    .required(true)
+                    .value_name("DATA_DIR")
+                    .help("Where the encrypted data is stored"),
+            )
+            .arg(
+                Arg::new("webdav")
+                    .long("webdav")
+                    .group("servers")
+                    .value_name("ADDR")
+                    .value_parser(clap::value_parser!(SocketAddr))
+                    .help("Serve over WebDAV on this address, like 127.0.0.1:4918. There is no authentication, anyone who can connect to it can read and change the files"),
+            )
+            .arg(
+                Arg::new("nfs")
+                    .long("nfs")
+                    .group("servers")
+                    .value_name("ADDR")
+                    .value_parser(clap::value_parser!(SocketAddr))
+                    .help("Serve over NFSv3 on this IPv4 address, like 127.0.0.1:11111. There is no authentication, anyone who can connect to it can read and change the files"),
+            )
+            .arg(
+                Arg::new("9p")
+                    .long("9p")
+                    .group("servers")
+                    .value_name("ADDR")
+                    .value_parser(clap::value_parser!(SocketAddr))
+                    .help("Serve over 9P2000.L on this address, for VMs and WSL to mount with their 9p driver. There is no authentication, anyone who can connect to it can read and change the files"),
+            )
+            .arg(
+                Arg::new("sftp")
+                    .long("sftp")
+                    .group("servers")
+                    .value_name("ADDR")
+                    .value_parser(clap::value_parser!(SocketAddr))
+                    .requires("sftp-authorized-keys")
+                    .requires("sftp-host-key")
+                    .help("Serve over SFTP on this address, like 127.0.0.1:2222. Clients log in with a key from --sftp-authorized-keys"),
+            )
+            .arg(
+                Arg::new("sftp-authorized-keys")
+                    .long("sftp-authorized-keys")
+                    .value_name("FILE")
+                    .requires("sftp")
+                    .help("The public keys that can log in over SFTP, one per line like in ~/.ssh/authorized_keys"),
+            )
+            .arg(
+                Arg::new("sftp-host-key")
+                    .long("sftp-host-key")
+                    .value_name("FILE")
+                    .requires("sftp")
+                    .help("The private key that identifies the SFTP server to clients, it's generated if the file doesn't exist. Keep it outside the data dir"),
+            )
+            .args(api_args())
+            .group(ArgGroup::new("servers").multiple(true).required(true))
+            .arg(
+                Arg::new("read-only")
+                    .long("read-only")
+                    .action(ArgAction::SetTrue)
+                    .help("Nothing in the data dir is changed, clients can only read the files"),
+            )
+    )
+}
+
+/// Args for `serve --api`, when built with the `api` feature.
+#[cfg(feature = "api")]
+fn api_args() -> Vec<Arg> {
+    vec![
+        Arg::new("api")
+            .long("api")
+            .group("servers")
+            .value_name("ADDR")
+            .value_parser(clap::value_parser!(SocketAddr))
+            .requires("api-token-file")
+            .help("Serve an HTTP API on this address, like 127.0.0.1:8080. Requests need the token from --api-token-file"),
+        Arg::new("api-token-file")
+            .long("api-token-file")
+            .value_name("FILE")
+            .requires("api")
+            .help("The token clients send as `Authorization: Bearer <token>`, it's generated if the file doesn't exist. Keep it outside the data dir"),
+    ]
+}
+
+#[cfg(not(feature = "api"))]
+fn api_args() -> Vec<Arg> {
+    vec![]
+}
+
+/// Args for `mount --metrics-addr`, when built with the `metrics` feature.
+#[cfg(feature = "metrics")]
+fn metrics_args() -> Vec<Arg> {
+    vec![Arg::new("metrics-addr")
+        .long("metrics-addr")
+        .value_name("ADDR")
+        .value_parser(clap::value_parser!(SocketAddr))
+        .help("Serve Prometheus metrics on this address, like 127.0.0.1:9100, with FUSE operations, their duration and errors, bytes encrypted and decrypted and cache hits. With several volumes they are for all of them")]
+}
+
+#[cfg(not(feature = "metrics"))]
+fn metrics_args() -> Vec<Arg> {
+    vec![]
+}
+
+/// Args for `mount --trace-ops`, when built with the `trace-ops` feature.
+#[cfg(feature = "trace-ops")]
+fn trace_ops_args() -> Vec<Arg> {
+    vec![Arg::new("trace-ops")
+        .long("trace-ops")
+        .action(ArgAction::SetTrue)
+        .help("Log each FUSE operation with its arguments and how long it took, at TRACE with the rencfs::ops target. The arguments have the names of the files in clear, so keep the logs safe")]
+}
+
+#[cfg(not(feature = "trace-ops"))]
+fn trace_ops_args() -> Vec<Arg> {
+    vec![]
+}
+
+async fn async_main() -> Result<()> {
+    let matches = get_cli_args();
+
+    let cipher = parse_cipher(&matches)?;
+    read_password_input(&matches)?;
+
+    match matches.subcommand() {
+        Some(("passwd", matches)) => run_change_password(cipher, matches).await?,
+        Some(("init", matches)) => run_init(cipher, matches).await?,
+        Some(("mount", _)) => run_mount(&get_volumes_cli_args()).await?,
+        Some(("umount", matches)) => run_umount(matches).await?,
+        Some(("stats", matches)) => run_stats(matches).await?,
+        Some(("log-filter", matches)) => run_log_filter(matches).await?,
+        Some(("ctl", matches)) => run_ctl(matches).await?,
+        Some(("hidden-volume", matches)) => run_hidden_volume(cipher, matches).await?,
+        Some(("enroll-fido2", matches)) => run_enroll_fido2(cipher, matches).await?,
+        Some(("enroll-tpm", matches)) => run_enroll_tpm(cipher, matches).await?,
+        Some(("enroll-pkcs11", matches)) => run_enroll_pkcs11(cipher, matches).await?,
+        Some(("enroll-totp", matches)) => run_enroll_totp(cipher, matches).await?,
+        Some(("remove-totp", matches)) => run_remove_totp(cipher, matches).await?,
+        Some(("add-key-slot", matches)) => run_add_key_slot(cipher, matches).await?,
+        Some(("key-slots", matches)) => run_key_slots(matches).await?,
+        Some(("revoke-key-slot", matches)) => run_revoke_key_slot(matches).await?,
+        Some(("migrate-cipher", matches)) => run_migrate_cipher(cipher, matches).await?,
+        Some(("info", matches)) => run_info(cipher, matches).await?,
+        Some(("check", matches)) => run_check(cipher, matches).await?,
+        Some(("bench", matches)) => run_bench(cipher, matches).await?,
+        Some(("recover", matches)) => run_recover(cipher, matches).await?,
+        Some(("verify", matches)) => run_verify(cipher, matches).await?,
+        Some(("corruption-log", matches)) => run_corruption_log(cipher, matches).await?,
+        Some(("audit-log", matches)) => run_audit_log(cipher, matches).await?,
+        Some(("export", matches)) => run_export(cipher, matches).await?,
+        Some(("restore", matches)) => run_restore(cipher, matches).await?,
+        Some(("reverse-decrypt", matches)) => run_reverse_decrypt(matches).await?,
+        Some(("import", matches)) => run_import(cipher, matches).await?,
+        Some(("snapshot", matches)) => run_snapshot(cipher, matches).await?,
+        Some(("versions", matches)) => run_versions(cipher, matches).await?,
+        Some(("passthrough", matches)) => run_passthrough(cipher, matches).await?,
+        Some(("serve", matches)) => run_serve(cipher, matches).await?,
+        None => {
+            error!("No subcommand provided");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        _ => {
+            error!("Invalid subcommand");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_cipher(matches: &ArgMatches) -> Result<Cipher> {
+    let cipher: String = matches.get_one::<String>("cipher").unwrap().to_string();
+    let cipher = Cipher::from_str(cipher.as_str());
+    if cipher.is_err() {
+        error!("Invalid cipher");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    Ok(cipher.unwrap())
+}
+
+async fn run_change_password(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    let password = match read_password_arg(matches, "old-password")? {
+        Some(password) => password,
+        // read password from stdin
+        None => prompt_password("Enter old password: ")?,
+    };
+    let new_password = match read_password_arg(matches, "new-password")? {
+        Some(password) => password,
+        None => {
+            let new_password = prompt_password("Enter new password: ")?;
+            if !confirm_password("Confirm new password: ", &new_password)? {
+                println!("Passwords do not match");
+                return Err(ExitStatusError::Failure(1).into());
+            }
+            new_password
+        }
+    };
+    let keyfile = matches.get_one::<String>("keyfile");
+    let password = with_keyfile(password, keyfile)?;
+    let new_password = with_keyfile(
+        new_password,
+        matches.get_one::<String>("new-keyfile").or(keyfile),
+    )?;
+    println!("Changing password...");
+    EncryptedFs::passwd(Path::new(&data_dir), password, new_password, cipher)
+        .await
+        .map_err(|err| match err {
+            FsError::InvalidPassword => {
+                println!("Invalid old password");
+                ExitStatusError::Failure(EXIT_INVALID_PASSWORD)
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+                ExitStatusError::Failure(1)
+            }
+            FsError::Io { .. } => {
+                error!(err = %err);
+                ExitStatusError::Failure(EXIT_IO)
+            }
+            _ => {
+                error!(err = %err);
+                ExitStatusError::Failure(1)
+            }
+        })?;
+    println!("Password changed successfully");
+
+    Ok(())
+}
+
+async fn run_hidden_volume(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter outer password: ")?;
+    let hidden_password = prompt_password("Enter hidden password: ")?;
+    if !confirm_password("Confirm hidden password: ", &hidden_password)? {
+        println!("Passwords do not match");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let keyfile = matches.get_one::<String>("keyfile");
+    let password = with_keyfile(password, keyfile)?;
+    let hidden_password = with_keyfile(hidden_password, keyfile)?;
+    println!("Creating hidden volume...");
+    EncryptedFs::create_hidden_volume(Path::new(&data_dir), password, hidden_password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid outer password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::AlreadyExists => {
+                    println!("Hidden volume already exists");
+                }
+                FsError::InvalidInput(msg) => {
+                    println!("{msg}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Hidden volume created, mount the data dir with the hidden password to use it");
+
+    Ok(())
+}
+
+async fn run_enroll_fido2(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let device = matches.get_one::<String>("device").unwrap();
+    let name = matches.get_one::<String>("name").unwrap();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    println!("Touch the security key, twice...");
+    let (kind, secret) = fido2::enroll(device).map_err(|err| {
+        println!("{err}");
+        ExitStatusError::Failure(1)
+    })?;
+    add_key_slot(&data_dir, password, cipher, name, kind, secret).await?;
+    println!("Security key enrolled, mount with --fido2 {device} to use it");
+
+    Ok(())
+}
+
+async fn run_enroll_tpm(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let name = matches.get_one::<String>("name").unwrap();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let (kind, secret) =
+        tpm::seal(matches.get_one::<String>("pcrs").map(String::as_str)).map_err(|err| {
+            println!("{err}");
+            ExitStatusError::Failure(1)
+        })?;
+    add_key_slot(&data_dir, password, cipher, name, kind, secret).await?;
+    println!("Secret sealed to the TPM, mount with --tpm to use it");
+
+    Ok(())
+}
+
+async fn run_enroll_pkcs11(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let name = matches.get_one::<String>("name").unwrap();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let pin = read_pkcs11_pin();
+    let (kind, secret) = pkcs11::wrap(
+        matches.get_one::<String>("pkcs11-module").unwrap(),
+        matches.get_one::<String>("pkcs11-slot").map(String::as_str),
+        matches.get_one::<String>("key-label").unwrap(),
+        &pin,
+    )
+    .map_err(|err| {
+        println!("{err}");
+        ExitStatusError::Failure(1)
+    })?;
+    add_key_slot(&data_dir, password, cipher, name, kind, secret).await?;
+    println!("Key slot added, mount with --pkcs11-module to use it");
+
+    Ok(())
+}
+
+async fn run_enroll_totp(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let secret = crypto::totp::generate_secret();
+    println!(
+        "Add this secret to your authenticator app: {}",
+        crypto::totp::encode_secret(&secret).expose_secret()
+    );
+    println!(
+        "or show this URI as a QR code: {}",
+        crypto::totp::uri(&secret, &data_dir).expose_secret()
+    );
+    let code = read_totp_code();
+    EncryptedFs::enroll_totp(Path::new(&data_dir), password, cipher, &secret, code)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::InvalidTotpCode => {
+                    println!("Invalid code, check the time on this machine and on your phone");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("TOTP enrolled, the code is asked each time you mount");
+
+    Ok(())
+}
+
+async fn run_remove_totp(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    EncryptedFs::remove_totp(Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::NotFound(_) => {
+                    println!("TOTP is not enrolled");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("TOTP removed");
+
+    Ok(())
+}
+
+async fn run_add_key_slot(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let name = matches.get_one::<String>("name").unwrap();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let (kind, secret) = match matches.get_one::<String>("kind").unwrap().as_str() {
+        "keyfile" => (
+            KeySlotKind::Keyfile,
+            with_keyfile(
+                LockedString::new(String::new()),
+                matches.get_one::<String>("slot-keyfile"),
+            )?,
+        ),
+        "recovery-key" => (KeySlotKind::RecoveryKey, crypto::generate_recovery_key()),
+        _ => {
+            let secret = prompt_password("Enter password of the slot: ")?;
+            if !confirm_password("Confirm password of the slot: ", &secret)? {
+                println!("Passwords do not match");
+                return Err(ExitStatusError::Failure(1).into());
+            }
+            (KeySlotKind::Password, secret)
+        }
+    };
+    let recovery_key = (kind == KeySlotKind::RecoveryKey).then(|| secret.clone());
+    add_key_slot(&data_dir, password, cipher, name, kind, secret).await?;
+    if let Some(recovery_key) = recovery_key {
+        println!("Write down the recovery key and keep it offline, it's not shown again. Give it as the password to unlock the data dir:");
+        println!("{}", recovery_key.expose_secret());
+    } else {
+        println!("Key slot {name} added");
+    }
+
+    Ok(())
+}
+
+async fn run_key_slots(matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let slots = EncryptedFs::key_slots(Path::new(&data_dir))
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    for slot in slots {
+        println!("{}\t{}", slot.name, slot.kind);
+    }
+
+    Ok(())
+}
+
+async fn run_revoke_key_slot(matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let name = matches.get_one::<String>("name").unwrap();
+    EncryptedFs::remove_key_slot(Path::new(&data_dir), name)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::NotFound(_) => {
+                    println!("There is no key slot {name}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Key slot {name} revoked");
+
+    Ok(())
+}
+
+fn read_totp_code() -> SecretString {
+    print!("Enter TOTP code: ");
+    io::stdout().flush().unwrap();
+    SecretString::new(read_password().unwrap())
+}
+
+fn read_pkcs11_pin() -> SecretString {
+    print!("Enter PIN of the token: ");
+    io::stdout().flush().unwrap();
+    SecretString::new(read_password().unwrap())
+}
+
+async fn add_key_slot(
+    data_dir: &str,
+    password: LockedString,
+    cipher: Cipher,
+    name: &str,
+    kind: KeySlotKind,
+    secret: LockedString,
+) -> Result<()> {
+    EncryptedFs::add_key_slot(Path::new(data_dir), password, cipher, name, kind, secret)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::AlreadyExists => {
+                    println!("Key slot {name} already exists");
+                }
+                FsError::InvalidInput(msg) => {
+                    println!("{msg}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    Ok(())
+}
+
+/// Secret of the first key slot `unlock` can get it for, it returns [`None`] for slots it doesn't handle.
+async fn key_slot_secret<F>(data_dir: &str, unlock: F) -> Result<LockedString>
+where
+    F: Fn(&KeySlotKind) -> Option<Result<LockedString>>,
+{
+    let slots = EncryptedFs::key_slots(Path::new(data_dir))
+        .await
+        .map_err(|err| {
+            error!(err = %err, "cannot read key slots");
+            ExitStatusError::Failure(1)
+        })?;
+    for slot in &slots {
+        match unlock(&slot.kind) {
+            Some(Ok(secret)) => return Ok(secret),
+            Some(Err(err)) => warn!(err = %err, slot = slot.name, "cannot unlock key slot"),
+            None => {}
+        }
+    }
+    error!("No key slot can be unlocked");
+    Err(ExitStatusError::Failure(1).into())
+}
+
+async fn run_migrate_cipher(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let Ok(to) = Cipher::from_str(matches.get_one::<String>("to").unwrap()) else {
+        error!("Invalid cipher");
+        return Err(ExitStatusError::Failure(1).into());
+    };
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    println!("Migrating cipher...");
+    EncryptedFs::migrate_cipher(Path::new(&data_dir), password, cipher, to)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::InvalidInput(msg) => {
+                    println!("{msg}");
+                }
+                _ => {
+                    error!(err = %err);
+                    println!("Migration was interrupted, run it again to continue");
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!("Cipher migrated, use --cipher {to} from now on");
+
+    Ok(())
+}
+
+async fn run_info(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let info = EncryptedFs::info(Path::new(&data_dir))
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    match &info.header {
+        Some(header) => {
+            println!("cipher\t{}", header.cipher);
+            println!(
+                "kdf\targon2id\t{} KiB memory\t{} iterations\t{} lanes",
+                header.kdf.memory_kib, header.kdf.iterations, header.kdf.parallelism
+            );
+            println!("chunk size\t{}", header.chunk_size);
+            // as seconds since epoch, so it's easy to parse
+            println!("created\t{}", header.created);
+        }
+        // created before there were headers, with the defaults
+        None => {
+            let kdf = KdfParams::default();
+            println!("cipher\tunknown, the one given with --cipher");
+            println!(
+                "kdf\targon2id\t{} KiB memory\t{} iterations\t{} lanes",
+                kdf.memory_kib, kdf.iterations, kdf.parallelism
+            );
+            println!("created\tunknown");
+        }
+    }
+    match info.format_version {
+        Some(version) => println!("format version\t{version}"),
+        None => println!("format version\t0"),
+    }
+    println!(
+        "layout\t{}",
+        match info.layout {
+            StorageLayout::Hierarchical => "hierarchical",
+            StorageLayout::Flat => "flat",
+        }
+    );
+    println!("key slots\t{}", info.key_slots);
+    println!("stored size\t{}", info.stored_size);
+
+    if matches.get_flag("unlock") {
+        #[allow(clippy::items_after_statements)]
+        struct PasswordProviderImpl {
+            password: LockedString,
+            totp_code: Option<SecretString>,
+        }
+        #[allow(clippy::items_after_statements)]
+        impl PasswordProvider for PasswordProviderImpl {
+            fn get_password(&self) -> Option<LockedString> {
+                Some(self.password.clone())
+            }
+
+            fn get_totp_code(&self) -> Option<SecretString> {
+                self.totp_code.clone()
+            }
+        }
         // read password from stdin
-        print!("Enter password: ");
-        io::stdout().flush().unwrap();
-        password = SecretString::new(read_password().unwrap());
+        let password = prompt_password("Enter password: ")?;
+        let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+        let totp_code = EncryptedFs::is_totp_enrolled(Path::new(&data_dir)).then(read_totp_code);
+        let fs = EncryptedFs::new(
+            PathBuf::from(&data_dir),
+            Box::new(PasswordProviderImpl {
+                password,
+                totp_code,
+            }),
+            cipher,
+            FsOptions::default().with_read_only(true),
+        )
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+        let usage = fs.usage().await.map_err(|err| {
+            error!(err = %err);
+            ExitStatusError::Failure(1)
+        })?;
+        println!("files\t{}", usage.files);
+        println!("dirs\t{}", usage.dirs);
+        println!("size\t{}", usage.size);
+    } else if let (Some(files), Some(dirs)) = (info.files, info.dirs) {
+        println!("files\t~{files}");
+        println!("dirs\t~{dirs}");
+    } else {
+        println!("files\tunknown with the flat layout, use --unlock");
+    }
+
+    Ok(())
+}
+
+async fn run_check(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = EncryptedFs::check(Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    for issue in &report.issues {
+        println!("{issue}");
+    }
+    eprintln!(
+        "{} directories, {} files, {} issues",
+        report.dirs,
+        report.files,
+        report.issues.len()
+    );
+    if !report.is_ok() {
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    Ok(())
+}
+
+async fn run_bench(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir = matches.get_one::<String>("data-dir").unwrap();
+    let settings = BenchmarkSettings {
+        file_size: *matches.get_one::<u64>("file-size").unwrap(),
+        #[allow(clippy::cast_possible_truncation)]
+        io_size: *matches.get_one::<u64>("io-size").unwrap() as usize,
+        files: *matches.get_one::<usize>("files").unwrap(),
+    };
+    std::fs::create_dir_all(data_dir)?;
+    // removed when dropped
+    let dir = tempfile::Builder::new()
+        .prefix(".rencfs-bench-")
+        .tempdir_in(data_dir)?;
+
+    #[allow(clippy::items_after_statements)]
+    struct PasswordProviderImpl(LockedString);
+    #[allow(clippy::items_after_statements)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<LockedString> {
+            Some(self.0.clone())
+        }
+    }
+    // not kept anywhere, the volume is thrown away
+    let mut password = [0; 32];
+    crypto::create_rng().fill_bytes(&mut password);
+    let password = LockedString::new(hex::encode(password));
+    let options = FsOptions::default()
+        .with_create(true)
+        .with_pad_file_sizes(matches.get_flag("pad-file-sizes"))
+        .with_dedup(matches.get_flag("dedup"))
+        .with_compression(matches.get_one::<i32>("compress").copied())
+        .with_layout(if matches.get_flag("flat-layout") {
+            StorageLayout::Flat
+        } else {
+            StorageLayout::Hierarchical
+        });
+    let fs = EncryptedFs::new(
+        dir.path().join("data"),
+        Box::new(PasswordProviderImpl(password)),
+        cipher,
+        options,
+    )
+    .await
+    .map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    println!(
+        "{cipher}, a file of {} bytes read and written {} bytes at a time, {} files for metadata operations",
+        settings.file_size, settings.io_size, settings.files
+    );
+    let results = fs.benchmark(&settings).await.map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    for result in results {
+        if result.bytes > 0 {
+            println!(
+                "{:<18}{:>10.1} MiB/s{:>12.0} ops/s",
+                result.name,
+                result.bytes_per_sec() / (1024.0 * 1024.0),
+                result.ops_per_sec()
+            );
+        } else {
+            println!("{:<18}{:>28.0} ops/s", result.name, result.ops_per_sec());
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_verify(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let map_err = |err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::ContentTreeBroken(_) => {
+                println!("The {err}, with --rebuild");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    };
+    if matches.get_flag("rebuild") {
+        EncryptedFs::rebuild_content_tree(Path::new(&data_dir), password.clone(), cipher)
+            .await
+            .map_err(map_err)?;
+    }
+    let report = EncryptedFs::verify(
+        Path::new(&data_dir),
+        password,
+        cipher,
+        matches.get_one::<u64>("min-generation").copied(),
+    )
+    .await
+    .map_err(map_err)?;
+    for issue in &report.issues {
+        println!("{issue}");
+    }
+    eprintln!(
+        "{} files, {} issues, root {} generation {}",
+        report.files,
+        report.issues.len(),
+        hex::encode(report.root),
+        report.generation
+    );
+    if !report.is_ok() {
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    Ok(())
+}
+
+async fn run_corruption_log(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let map_err = |err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    };
+    if matches.get_flag("clear") {
+        EncryptedFs::clear_corruption_log(Path::new(&data_dir), password, cipher)
+            .await
+            .map_err(map_err)?;
+        println!("Corruption log cleared");
+        return Ok(());
+    }
+    let log = EncryptedFs::corruption_log(Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(map_err)?;
+    for record in &log {
+        println!("{record}");
+    }
+    eprintln!("{} corrupted chunks", log.len());
+
+    Ok(())
+}
+
+async fn run_audit_log(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let log = EncryptedFs::audit_log(Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::AuditLogBroken(..) => {
+                    println!("Audit log was tampered with: {err}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    let op = matches.get_one::<String>("op");
+    let path = matches.get_one::<String>("path").map(|path| {
+        let path = path.trim_end_matches('/');
+        (path.to_string(), format!("{path}/"))
+    });
+    let uid = matches.get_one::<u32>("uid");
+    let since = matches
+        .get_one::<u64>("since")
+        .map(|since| UNIX_EPOCH + Duration::from_secs(*since));
+    let under = |p: &str| {
+        path.as_ref()
+            .is_none_or(|(path, prefix)| p == path || p.starts_with(prefix.as_str()))
+    };
+    let mut count = 0;
+    for record in log.iter().filter(|record| {
+        op.is_none_or(|op| record.op == *op)
+            && (under(&record.path) || record.new_path.as_deref().is_some_and(under))
+            && uid.is_none_or(|uid| record.uid == *uid)
+            && since.is_none_or(|since| record.time >= since)
+            && (!matches.get_flag("errors") || record.errno.is_some())
+    }) {
+        println!("{record}");
+        count += 1;
+    }
+    eprintln!("{count} operations");
+
+    Ok(())
+}
+
+async fn run_export(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let archive: String = matches.get_one::<String>("archive").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = EncryptedFs::export(
+        Path::new(&data_dir),
+        password,
+        cipher,
+        matches.get_one::<String>("subtree").map(String::as_str),
+        Path::new(&archive),
+    )
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::NotFound(_) | FsError::InvalidInodeType => {
+                println!("Subtree not found in volume");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    println!(
+        "Exported {} files in {} directories, {} bytes",
+        report.files, report.dirs, report.bytes
+    );
+
+    Ok(())
+}
+
+async fn run_restore(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let archive: String = matches.get_one::<String>("archive").unwrap().to_string();
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = EncryptedFs::restore(Path::new(&archive), Path::new(&data_dir), password, cipher)
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidInput(msg) => {
+                    println!("{msg}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!(
+        "Restored {} files in {} directories, {} bytes",
+        report.files, report.dirs, report.bytes
+    );
+
+    Ok(())
+}
+
+async fn run_reverse_decrypt(matches: &ArgMatches) -> Result<()> {
+    let src: String = matches.get_one::<String>("src").unwrap().to_string();
+    let out: String = matches.get_one::<String>("out").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = task::spawn_blocking(move || {
+        ReverseFs::decrypt(Path::new(&src), Path::new(&out), &password)
+    })
+    .await?
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::NotFound(_) => {
+                println!("Not a copy of a view mounted with --reverse");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    for path in &report.skipped {
+        println!("Skipped {}, it can't be decrypted", path.display());
+    }
+    println!(
+        "Decrypted {} files in {} directories, {} bytes",
+        report.files, report.dirs, report.bytes
+    );
+
+    Ok(())
+}
+
+async fn run_import(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let src: String = matches.get_one::<String>("src").unwrap().to_string();
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let workers = matches
+        .get_one::<usize>("workers")
+        .copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, NonZeroUsize::get));
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let is_new = !Path::new(&data_dir).exists()
+        || fs::read_dir(&data_dir).await?.next_entry().await?.is_none();
+    if is_new && !confirm_password("Confirm password: ", &password)? {
+        println!("Passwords do not match");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = EncryptedFs::import(
+        Path::new(&src),
+        Path::new(&data_dir),
+        password,
+        cipher,
+        workers,
+        matches.get_flag("verify"),
+        &|report| {
+            eprint!(
+                "\rImported {}/{} files, {}/{} bytes",
+                report.files, report.total_files, report.bytes, report.total_bytes
+            );
+        },
+    )
+    .await
+    .map_err(|err| {
+        eprintln!();
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::AlreadyExists => {
+                println!("A name from the source is already in root of the volume");
+            }
+            FsError::InvalidInput(msg) => {
+                println!("{msg}");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+    eprintln!();
+    for path in &report.mismatched {
+        println!("mismatched\t{}", path.display());
+    }
+    println!(
+        "Imported {} files in {} directories, {} skipped",
+        report.files, report.dirs, report.skipped
+    );
+    if !report.mismatched.is_empty() {
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    Ok(())
+}
+
+async fn run_snapshot(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let data_dir = Path::new(&data_dir);
 
-        if !PathBuf::new().join(data_dir.clone()).is_dir()
-            || fs::read_dir(&data_dir)
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let map_err = |err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::AlreadyExists => {
+                println!("Snapshot already exists");
+            }
+            FsError::NotFound(_) => {
+                println!("Snapshot not found");
+            }
+            FsError::InvalidInput(msg) => {
+                println!("{msg}");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    };
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let name = matches.get_one::<String>("name").unwrap();
+            EncryptedFs::create_snapshot(data_dir, password, cipher, name)
+                .await
+                .map_err(map_err)?;
+            println!("Snapshot {name} created");
+        }
+        Some(("list", _)) => {
+            let snapshots = EncryptedFs::snapshots(data_dir, password, cipher)
                 .await
-                .unwrap()
-                .next_entry()
+                .map_err(map_err)?;
+            for snapshot in snapshots {
+                // as seconds since epoch, so it's easy to parse
+                let created = snapshot
+                    .created
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                println!("{}\t{created}", snapshot.name);
+            }
+        }
+        Some(("delete", matches)) => {
+            let name = matches.get_one::<String>("name").unwrap();
+            EncryptedFs::delete_snapshot(data_dir, password, cipher, name)
                 .await
-                .unwrap()
-                .is_none()
-        {
-            // first run, ask to confirm password
-            print!("Confirm password: ");
-            io::stdout().flush().unwrap();
-            let confirm_password = SecretString::new(read_password().unwrap());
-            if password.expose_secret() != confirm_password.expose_secret() {
-                error!("Passwords do not match");
+                .map_err(map_err)?;
+            println!("Snapshot {name} deleted");
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
+
+async fn run_versions(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let data_dir = Path::new(&data_dir);
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let map_err = |err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::NotFound(what) => {
+                println!("Not found: {what}");
+            }
+            FsError::InvalidInodeType => {
+                println!("Not a file");
+            }
+            FsError::InvalidInput(msg) => {
+                println!("{msg}");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    };
+    match matches.subcommand() {
+        Some(("list", matches)) => {
+            let path = matches.get_one::<String>("path").unwrap();
+            let versions = EncryptedFs::versions(data_dir, password, cipher, path)
+                .await
+                .map_err(map_err)?;
+            for version in versions {
+                println!("{version}");
+            }
+        }
+        Some(("restore", matches)) => {
+            let path = matches.get_one::<String>("path").unwrap();
+            let id = *matches.get_one::<u64>("id").unwrap();
+            EncryptedFs::restore_version(data_dir, password, cipher, path, id)
+                .await
+                .map_err(map_err)?;
+            println!("Version {id} of {path} restored");
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
+
+async fn run_passthrough(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let data_dir = Path::new(&data_dir);
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let map_err = |err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::InvalidInput(msg) => {
+                println!("{msg}");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    };
+    let mut rules = EncryptedFs::passthrough_rules(data_dir, password.clone(), cipher)
+        .await
+        .map_err(map_err)?;
+    let done = match matches.subcommand() {
+        Some(("list", _)) => {
+            for rule in rules {
+                println!("{rule}");
+            }
+            return Ok(());
+        }
+        Some(("add", matches)) => {
+            let rule = PassthroughRule::from_str(matches.get_one::<String>("rule").unwrap())
+                .map_err(map_err)?;
+            let done = format!("Rule {rule} added");
+            rules.push(rule);
+            done
+        }
+        Some(("remove", matches)) => {
+            let pattern = matches.get_one::<String>("pattern").unwrap();
+            let len = rules.len();
+            rules.retain(|rule| rule.pattern != pattern.trim_start_matches('/'));
+            if rules.len() == len {
+                println!("No rule with pattern {pattern}");
                 return Err(ExitStatusError::Failure(1).into());
             }
+            format!("Rules with pattern {pattern} removed")
+        }
+        _ => unreachable!("subcommand is required"),
+    };
+    EncryptedFs::set_passthrough_rules(data_dir, password, cipher, rules)
+        .await
+        .map_err(map_err)?;
+    println!("{done}");
+
+    Ok(())
+}
+
+async fn run_serve(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let totp_code = EncryptedFs::is_totp_enrolled(Path::new(&data_dir)).then(read_totp_code);
+
+    #[allow(clippy::items_after_statements)]
+    struct PasswordProviderImpl {
+        password: LockedString,
+        totp_code: Option<SecretString>,
+    }
+    #[allow(clippy::items_after_statements)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<LockedString> {
+            Some(self.password.clone())
+        }
+
+        fn get_totp_code(&self) -> Option<SecretString> {
+            self.totp_code.clone()
+        }
+    }
+    let fs = EncryptedFs::new(
+        PathBuf::from(&data_dir),
+        Box::new(PasswordProviderImpl {
+            password,
+            totp_code,
+        }),
+        cipher,
+        FsOptions::default().with_read_only(matches.get_flag("read-only")),
+    )
+    .await
+    .map_err(|err| {
+        match err {
+            FsError::InvalidPassword => {
+                println!("Invalid password");
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+            }
+            FsError::NoVolume => {
+                println!(
+                    "There is no volume in {data_dir}, create it with `rencfs init --data-dir {data_dir}`"
+                );
+            }
+            FsError::ContentTreeBroken(_) => {
+                println!("The {err}, with `rencfs verify --rebuild --data-dir {data_dir}`");
+            }
+            _ => {
+                error!(err = %err);
+            }
+        }
+        ExitStatusError::Failure(1)
+    })?;
+
+    // the servers stop together, on the signal or when one of them fails
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let shutdown = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        }
+    };
+    let webdav = async {
+        let Some(addr) = matches.get_one::<SocketAddr>("webdav").copied() else {
+            return Ok(());
+        };
+        warn_if_exposed(addr);
+        info!(%addr, "serving over WebDAV");
+        let res = serve::serve_webdav(fs.clone(), addr, shutdown()).await;
+        stop.send_replace(true);
+        res.map_err(|err| error!(err = %err, %addr, "serving over WebDAV"))
+    };
+    let nfs = async {
+        let Some(addr) = matches.get_one::<SocketAddr>("nfs").copied() else {
+            return Ok(());
+        };
+        warn_if_exposed(addr);
+        info!(%addr, "serving over NFS");
+        let res = serve::serve_nfs(fs.clone(), addr, shutdown()).await;
+        stop.send_replace(true);
+        res.map_err(|err| error!(err = %err, %addr, "serving over NFS"))
+    };
+    let p9 = async {
+        let Some(addr) = matches.get_one::<SocketAddr>("9p").copied() else {
+            return Ok(());
+        };
+        warn_if_exposed(addr);
+        info!(%addr, "serving over 9P");
+        let res = serve::serve_9p(fs.clone(), addr, shutdown()).await;
+        stop.send_replace(true);
+        res.map_err(|err| error!(err = %err, %addr, "serving over 9P"))
+    };
+    let sftp = async {
+        let Some(addr) = matches.get_one::<SocketAddr>("sftp").copied() else {
+            return Ok(());
+        };
+        let host_key = Path::new(matches.get_one::<String>("sftp-host-key").unwrap());
+        let authorized_keys = Path::new(matches.get_one::<String>("sftp-authorized-keys").unwrap());
+        info!(%addr, "serving over SFTP");
+        let res = serve::serve_sftp(fs.clone(), addr, host_key, authorized_keys, shutdown()).await;
+        stop.send_replace(true);
+        res.map_err(|err| error!(err = %err, %addr, "serving over SFTP"))
+    };
+    let api = async {
+        #[cfg(feature = "api")]
+        if let Some(addr) = matches.get_one::<SocketAddr>("api").copied() {
+            let token = read_api_token(Path::new(
+                matches.get_one::<String>("api-token-file").unwrap(),
+            ))
+            .map_err(|err| {
+                stop.send_replace(true);
+                error!(err = %err, "reading API token");
+            })?;
+            info!(%addr, "serving the API");
+            let res = serve::serve_api(fs.clone(), addr, token, shutdown()).await;
+            stop.send_replace(true);
+            return res.map_err(|err| error!(err = %err, %addr, "serving the API"));
+        }
+        Ok::<_, ()>(())
+    };
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let signal = async {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received signal to exit");
+                stop.send_replace(true);
+            }
+            _ = terminate.recv() => {
+                info!("Received signal to exit");
+                stop.send_replace(true);
+            }
+            () = shutdown() => {}
+        }
+        systemd::notify("STOPPING=1");
+    };
+    let watchdog = async {
+        tokio::select! {
+            () = systemd::watchdog(|| async { true }) => {}
+            () = shutdown() => {}
+        }
+    };
+    // the servers start listening right away
+    systemd::ready();
+    let (webdav, nfs, p9, sftp, api, (), ()) =
+        tokio::join!(webdav, nfs, p9, sftp, api, signal, watchdog);
+    // so the storage has everything before we exit, with the files the servers dropped while open
+    fs.flush_all().await.map_err(|err| {
+        error!(err = %err, "flushing open files");
+        ExitStatusError::Failure(1)
+    })?;
+    fs.sync_storage().await.map_err(|err| {
+        error!(err = %err, "syncing storage");
+        ExitStatusError::Failure(1)
+    })?;
+    if webdav.is_err() || nfs.is_err() || p9.is_err() || sftp.is_err() || api.is_err() {
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    Ok(())
+}
+
+fn warn_if_exposed(addr: SocketAddr) {
+    if !addr.ip().is_loopback() {
+        warn!(%addr, "anyone who can connect can read and change the files");
+    }
+}
+
+/// The token for the API, a new random one is saved if the file doesn't exist.
+#[cfg(feature = "api")]
+fn read_api_token(path: &Path) -> io::Result<SecretString> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if path.exists() {
+        return Ok(SecretString::new(
+            std::fs::read_to_string(path)?.trim().to_string(),
+        ));
+    }
+    let mut token = [0; 32];
+    crypto::create_rng().fill_bytes(&mut token);
+    let token = hex::encode(token);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(token.as_bytes())?;
+    info!(path = %path.display(), "generated API token");
+    Ok(SecretString::new(token))
+}
+
+async fn run_recover(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    if matches.get_flag("recovery-key") {
+        return run_reset_password(cipher, &data_dir, matches).await;
+    }
+    let dest: String = matches.get_one::<String>("dest").unwrap().to_string();
+
+    // read password from stdin
+    let password = prompt_password("Enter password: ")?;
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let report = EncryptedFs::recover(Path::new(&data_dir), password, cipher, Path::new(&dest))
+        .await
+        .map_err(|err| {
+            match err {
+                FsError::InvalidPassword => {
+                    println!("Invalid password");
+                }
+                FsError::InvalidDataDirStructure => {
+                    println!("Invalid structure of data directory");
+                }
+                FsError::InvalidInput(msg) => {
+                    println!("{msg}");
+                }
+                _ => {
+                    error!(err = %err);
+                }
+            }
+            ExitStatusError::Failure(1)
+        })?;
+    println!(
+        "Recovered {} files in {} directories, {} bad chunks filled with zeros, {} entries skipped",
+        report.files, report.dirs, report.bad_chunks, report.skipped
+    );
+
+    Ok(())
+}
+
+async fn run_reset_password(cipher: Cipher, data_dir: &str, matches: &ArgMatches) -> Result<()> {
+    // read password from stdin
+    let recovery_key = prompt_password("Enter recovery key: ")?;
+    let new_password = prompt_password("Enter new password: ")?;
+    if !confirm_password("Confirm new password: ", &new_password)? {
+        println!("Passwords do not match");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let new_password = with_keyfile(new_password, matches.get_one::<String>("keyfile"))?;
+    EncryptedFs::reset_password(Path::new(data_dir), recovery_key, new_password, cipher)
+        .await
+        .map_err(|err| match err {
+            FsError::InvalidPassword => {
+                println!("Invalid recovery key");
+                ExitStatusError::Failure(EXIT_INVALID_PASSWORD)
+            }
+            FsError::InvalidDataDirStructure => {
+                println!("Invalid structure of data directory");
+                ExitStatusError::Failure(1)
+            }
+            FsError::InvalidInput(msg) => {
+                println!("{msg}");
+                ExitStatusError::Failure(1)
+            }
+            _ => {
+                error!(err = %err);
+                ExitStatusError::Failure(1)
+            }
+        })?;
+    println!("Password changed successfully");
+
+    Ok(())
+}
+
+async fn run_init(cipher: Cipher, matches: &ArgMatches) -> Result<()> {
+    let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    if !is_empty_dir(Path::new(&data_dir)).await? {
+        println!("Data dir is not empty");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    let mut shares = None;
+    let password = if let Some(count) = matches.get_one::<u8>("shares") {
+        let threshold = *matches.get_one::<u8>("threshold").unwrap();
+        let mut secret = vec![0; cipher.key_len()];
+        crypto::create_rng().fill_bytes(&mut secret);
+        let secret = SecretVec::new(secret);
+        shares = Some(
+            crypto::shamir::split(&secret, *count, threshold).map_err(|err| {
+                println!("{err}");
+                ExitStatusError::Failure(1)
+            })?,
+        );
+        LockedString::new(hex::encode(secret.expose_secret()))
+    } else {
+        // read password from stdin
+        let password = prompt_password("Enter password: ")?;
+        if !confirm_password("Confirm password: ", &password)? {
+            println!("Passwords do not match");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+        password
+    };
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+
+    #[allow(clippy::items_after_statements)]
+    struct PasswordProviderImpl(LockedString);
+    #[allow(clippy::items_after_statements)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<LockedString> {
+            Some(self.0.clone())
+        }
+    }
+    let defaults = KdfParams::default();
+    let kdf = KdfParams {
+        memory_kib: matches
+            .get_one::<u32>("kdf-memory")
+            .copied()
+            .unwrap_or(defaults.memory_kib),
+        iterations: matches
+            .get_one::<u32>("kdf-iterations")
+            .copied()
+            .unwrap_or(defaults.iterations),
+        parallelism: matches
+            .get_one::<u32>("kdf-parallelism")
+            .copied()
+            .unwrap_or(defaults.parallelism),
+    };
+    let kdf = match matches.get_one::<u64>("kdf-target-ms") {
+        Some(ms) => {
+            println!("Measuring how long it takes to derive the key...");
+            kdf.calibrate(Duration::from_millis(*ms)).map_err(|err| {
+                error!(err = %err, "calibrating the key derivation");
+                ExitStatusError::Failure(1)
+            })?
+        }
+        None => kdf,
+    };
+    let options = FsOptions::default()
+        .with_create(true)
+        .with_kdf(kdf)
+        .with_layout(if matches.get_flag("flat-layout") {
+            StorageLayout::Flat
+        } else {
+            StorageLayout::Hierarchical
+        });
+    EncryptedFs::new(
+        PathBuf::from(&data_dir),
+        Box::new(PasswordProviderImpl(password.clone())),
+        cipher,
+        options,
+    )
+    .await
+    .map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    let recovery_key = if matches.get_flag("recovery-key") {
+        let recovery_key = crypto::generate_recovery_key();
+        EncryptedFs::add_key_slot(
+            Path::new(&data_dir),
+            password,
+            cipher,
+            "recovery",
+            KeySlotKind::RecoveryKey,
+            recovery_key.clone(),
+        )
+        .await
+        .map_err(|err| {
+            error!(err = %err, "adding recovery key");
+            ExitStatusError::Failure(1)
+        })?;
+        Some(recovery_key)
+    } else {
+        None
+    };
+    println!(
+        "Data dir created with {cipher}, Argon2id with {} KiB, {} iterations and {} lanes",
+        kdf.memory_kib, kdf.iterations, kdf.parallelism
+    );
+    if let Some(recovery_key) = recovery_key {
+        println!("Write down the recovery key and keep it offline, it's not shown again. If you forget the password, set a new one with it with `rencfs recover --recovery-key --data-dir {data_dir}`:");
+        println!("{}", recovery_key.expose_secret());
+    }
+    if let Some(shares) = shares {
+        println!("Give each share to a different person, any {} of them are needed to mount it with --shares, they are not shown again:",
+            matches.get_one::<u8>("threshold").unwrap());
+        for share in shares {
+            println!("{}", share.expose_secret());
+        }
+    }
+
+    Ok(())
+}
+
+/// If `path` doesn't exist or has nothing in it.
+async fn is_empty_dir(path: &Path) -> io::Result<bool> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    Ok(fs::read_dir(path).await?.next_entry().await?.is_none())
+}
+
+/// Prompt for shares until there are enough to combine the secret, used as password.
+fn read_shares() -> Result<LockedString> {
+    let mut shares = vec![];
+    loop {
+        print!("Enter share {}: ", shares.len() + 1);
+        io::stdout().flush().unwrap();
+        shares.push(SecretString::new(read_password().unwrap()));
+        let threshold = crypto::shamir::threshold(&shares[0]).map_err(|err| {
+            println!("{err}");
+            ExitStatusError::Failure(1)
+        })?;
+        if shares.len() >= threshold as usize {
+            break;
+        }
+    }
+    let secret = crypto::shamir::combine(&shares).map_err(|err| {
+        println!("{err}");
+        ExitStatusError::Failure(1)
+    })?;
+    Ok(LockedString::new(hex::encode(secret.expose_secret())))
+}
+
+/// Read the passwords given with `--password-file` or `--password-fd`, one per line.
+fn read_password_input(matches: &ArgMatches) -> Result<()> {
+    let content = if let Some(path) = matches.get_one::<String>("password-file") {
+        std::fs::read_to_string(path)
+    } else if let Some(fd) = matches.get_one::<i32>("password-fd") {
+        if let Some(lines) = PASSWORD_FD.get() {
+            *PASSWORD_INPUT.lock().unwrap() = Some(lines.clone());
+            return Ok(());
         }
+        // SAFETY: it's given to us to read from and we don't use it after
+        let mut file = unsafe { std::fs::File::from_raw_fd(*fd) };
+        let mut content = String::new();
+        file.read_to_string(&mut content).map(|_| content)
+    } else {
+        *PASSWORD_INPUT.lock().unwrap() = None;
+        return Ok(());
+    };
+    let content = LockedString::new(content.map_err(|err| {
+        error!(err = %err, "cannot read password");
+        ExitStatusError::Failure(1)
+    })?);
+    let lines: PasswordLines = Arc::new(std::sync::Mutex::new(
+        content
+            .expose_secret()
+            .lines()
+            .map(|line| LockedString::new(line.to_string()))
+            .collect(),
+    ));
+    if matches.contains_id("password-fd") {
+        let _ = PASSWORD_FD.set(lines.clone());
+    }
+    *PASSWORD_INPUT.lock().unwrap() = Some(lines);
+    Ok(())
+}
+
+/// First line of `--{name}-file` or `--{name}-fd`, [`None`] if neither was given.
+fn read_password_arg(matches: &ArgMatches, name: &str) -> Result<Option<LockedString>> {
+    let content = if let Some(path) = matches.get_one::<String>(&format!("{name}-file")) {
+        std::fs::read_to_string(path)
+    } else if let Some(fd) = matches.get_one::<i32>(&format!("{name}-fd")) {
+        // SAFETY: it's given to us to read from and we don't use it after
+        let mut file = unsafe { std::fs::File::from_raw_fd(*fd) };
+        let mut content = String::new();
+        file.read_to_string(&mut content).map(|_| content)
+    } else {
+        return Ok(None);
+    };
+    let content = LockedString::new(content.map_err(|err| {
+        error!(err = %err, "cannot read {name}");
+        ExitStatusError::Failure(EXIT_IO)
+    })?);
+    let password = LockedString::new(
+        content
+            .expose_secret()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    );
+    Ok(Some(password))
+}
+
+/// Next password from `--password-file` or `--password-fd`, or else prompt for it.
+fn prompt_password(prompt: &str) -> Result<LockedString> {
+    let lines = PASSWORD_INPUT.lock().unwrap().clone();
+    if let Some(lines) = lines {
+        return Ok(lines.lock().unwrap().pop_front().ok_or_else(|| {
+            error!("Not enough passwords given, each one should be on its own line");
+            ExitStatusError::Failure(1)
+        })?);
+    }
+    print!("{prompt}");
+    io::stdout().flush().unwrap();
+    Ok(LockedString::new(read_password().unwrap()))
+}
+
+/// Prompt again for the password and check it's the same. When it's not typed it can't be mistyped, so we don't
+/// ask for it twice.
+fn confirm_password(prompt: &str, password: &LockedString) -> Result<bool> {
+    if PASSWORD_INPUT.lock().unwrap().is_some() {
+        return Ok(true);
     }
-    // save password in keyring
+    let confirm_password = prompt_password(prompt)?;
+    Ok(password.expose_secret() == confirm_password.expose_secret())
+}
+
+/// Keep the password for when the key needs to be read again, in keyring if we can, else in memory.
+fn keep_password(mountpoint: &str, password: &LockedString) {
     info!("Save password in keyring");
-    let res = keyring::save(&password, "password").map_err(|err| {
+    let res = keyring::save(password, &password_entry(mountpoint)).map_err(|err| {
         warn!(err = %err);
     });
     if res.is_err() {
         // maybe we don't have a security manager, keep it in mem
-        unsafe {
-            warn!("Cannot save password in keyring, keep it in memory");
-            PASS = Some(password.clone());
+        warn!("Cannot save password in keyring, keep it in memory");
+        PASS.lock()
+            .unwrap()
+            .insert(mountpoint.to_string(), password.clone());
+    }
+}
+
+/// Where [`keep_password`] saves it in the keyring, each mounted volume has its own.
+fn password_entry(mountpoint: &str) -> String {
+    format!("password.{mountpoint}")
+}
+
+/// Ask for the password each time the filesystem is locked, until then it denies access.
+fn unlock_prompt(
+    mountpoint: &str,
+    locked: &std::sync::mpsc::Receiver<()>,
+    keyfile: Option<&String>,
+) {
+    while locked.recv().is_ok() {
+        print!("Filesystem at {mountpoint} locked, enter password to unlock: ");
+        io::stdout().flush().unwrap();
+        let password = read_password()
+            .map_err(Into::into)
+            .and_then(|password| with_keyfile(LockedString::new(password), keyfile));
+        match password {
+            Ok(password) => keep_password(mountpoint, &password),
+            Err(err) => error!(err = %err, "cannot read password, it stays locked"),
         }
     }
+}
 
-    if matches.get_flag("umount-on-start") {
-        let _ = umount(mountpoint.as_str()).map_err(|err| {
-            warn!("Cannot umount, maybe it was not mounted: {err}");
-            err
-        });
+/// Mix the keyfile, if any, into the password.
+fn with_keyfile(password: LockedString, keyfile: Option<&String>) -> Result<LockedString> {
+    let Some(keyfile) = keyfile else {
+        return Ok(password);
+    };
+    Ok(
+        crypto::password_with_keyfile(&password, Path::new(keyfile)).map_err(|err| {
+            error!(err = %err, "cannot read keyfile");
+            ExitStatusError::Failure(1)
+        })?,
+    )
+}
+
+/// Parse `STORED:SHOWN` for `--map-uid` and `--map-gid`.
+fn parse_id_pair(value: &str) -> Result<(u32, u32), String> {
+    let (stored, shown) = value
+        .split_once(':')
+        .ok_or_else(|| "expected STORED:SHOWN".to_string())?;
+    Ok((
+        stored.parse().map_err(|err| format!("{stored}: {err}"))?,
+        shown.parse().map_err(|err| format!("{shown}: {err}"))?,
+    ))
+}
+
+/// Parse permissions in octal for `--umask`, `--file-mode` and `--dir-mode`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        Ok(_) => Err("must be at most 7777".to_string()),
+        Err(err) => Err(format!("{value}: {err}")),
     }
+}
 
-    #[allow(clippy::items_after_statements)]
-    struct PasswordProviderImpl {}
-    #[allow(clippy::items_after_statements)]
-    impl PasswordProvider for PasswordProviderImpl {
-        fn get_password(&self) -> Option<SecretString> {
-            unsafe {
-                if PASS.is_some() {
-                    info!("Get password from memory");
-                    PASS.clone()
-                } else {
-                    info!("Get password from keyring");
-                    keyring::get("password")
-                        .map_err(|err| {
-                            error!(err = %err, "cannot get password from keyring");
-                            err
-                        })
-                        .ok()
+/// Parse bytes with an optional `K`, `M` or `G` suffix, powers of 1024, for `--scrub-bandwidth` and `--max-size`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    match number.parse::<u64>() {
+        Ok(0) => Err("must be greater than 0".to_string()),
+        Ok(number) => number
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("{value}: too large")),
+        Err(err) => Err(format!("{value}: {err}")),
+    }
+}
+
+/// Seconds, with a fraction, like `0.5`.
+fn parse_secs(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(Duration::from_secs_f64(secs)),
+        Ok(_) => Err(format!("{value}: must be 0 or more")),
+        Err(err) => Err(format!("{value}: {err}")),
+    }
+}
+
+/// One of `--options`.
+#[derive(Debug, Clone)]
+enum MountOpt {
+    NoExec,
+    NoSuid,
+    NoDev,
+    FsName(String),
+    Subtype(String),
+    AttrTimeout(Duration),
+    EntryTimeout(Duration),
+}
+
+/// Parse an option of `--options`, named like for `mount -o`.
+fn parse_mount_option(value: &str) -> Result<MountOpt, String> {
+    match value.split_once('=') {
+        Some((_, "")) => Err(format!("{value}: needs a value")),
+        Some(("fsname", name)) => Ok(MountOpt::FsName(name.to_string())),
+        Some(("subtype", name)) => Ok(MountOpt::Subtype(name.to_string())),
+        Some(("attr_timeout", secs)) => Ok(MountOpt::AttrTimeout(parse_secs(secs)?)),
+        Some(("entry_timeout", secs)) => Ok(MountOpt::EntryTimeout(parse_secs(secs)?)),
+        None if value == "noexec" => Ok(MountOpt::NoExec),
+        None if value == "nosuid" => Ok(MountOpt::NoSuid),
+        None if value == "nodev" => Ok(MountOpt::NoDev),
+        _ => Err(format!("{value}: unknown mount option")),
+    }
+}
+
+/// Mount the volumes one after the other, so their prompts don't mix, and serve them until we get a signal.
+async fn run_mount(volumes: &[ArgMatches]) -> Result<()> {
+    let (_, mount) = volumes[0].subcommand().unwrap();
+    if mount.get_flag("reverse") {
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+        return run_mount_reverse(&volumes[0]).await;
+        #[cfg(not(all(any(target_os = "linux", target_os = "macos"), feature = "fuser")))]
+        {
+            error!("--reverse needs the fuser feature");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    }
+
+    // the one of the mount runs this first, then unmounts and exits
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        daemon::cleanup();
+        control::cleanup();
+    }));
+    mount::set_panic_hook();
+
+    let mut mountpoints = vec![];
+    let mut mount_handles = vec![];
+    for matches in volumes {
+        let (_, mount) = matches.subcommand().unwrap();
+        mountpoints.push(mount.get_one::<String>("mount-point").unwrap().clone());
+        let res = async {
+            let cipher = parse_cipher(matches)?;
+            read_password_input(matches)?;
+            let (_, matches) = matches.subcommand().unwrap();
+            mount_volume(cipher, matches, volumes.len() > 1).await
+        }
+        .await;
+        match res {
+            Ok(mount_handle) => mount_handles.push(mount_handle),
+            Err(err) => {
+                // all or nothing
+                for (mountpoint, mount_handle) in mountpoints.iter().zip(mount_handles) {
+                    remove_pass(mountpoint);
+                    let _ = mount_handle.umount().await;
                 }
+                return Err(err);
             }
         }
     }
-    let mount_point = mount::create_mount_point(
-        Path::new(&mountpoint),
-        Path::new(&data_dir),
-        Box::new(PasswordProviderImpl {}),
-        cipher,
-        matches.get_flag("allow-root"),
-        matches.get_flag("allow-other"),
-        matches.get_flag("direct-io"),
-        matches.get_flag("suid"),
-    );
-    let mount_handle = mount_point.mount().await.map_err(|err| {
-        error!(err = %err);
-        ExitStatusError::Failure(1)
-    })?;
-    let mount_handle = Arc::new(Mutex::new(Some(Some(mount_handle))));
-    let mount_handle_clone = mount_handle.clone();
-    // cleanup on process kill
+
+    // the first volume has the arguments of the process
+    let (_, matches) = volumes[0].subcommand().unwrap();
+    if matches.get_flag("daemon") {
+        let default_path = |extension| {
+            daemon::default_path(&mountpoints[0], extension).map_err(|err| {
+                error!(err = %err, "cannot go to the background");
+                ExitStatusError::Failure(1)
+            })
+        };
+        let pid_file = matches
+            .get_one::<String>("pid-file")
+            .map_or_else(|| default_path("pid"), |path| Ok(PathBuf::from(path)))?;
+        let log_file = matches
+            .get_one::<String>("daemon-log")
+            .map_or_else(|| default_path("log"), |path| Ok(PathBuf::from(path)))?;
+        info!(pid_file = %pid_file.display(), log_file = %log_file.display(), "Mounted, going to the background");
+        daemon::ready(&pid_file, &log_file).map_err(|err| {
+            error!(err = %err, "cannot go to the background");
+            ExitStatusError::Failure(1)
+        })?;
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = matches.get_one::<SocketAddr>("metrics-addr").copied() {
+        info!(%addr, "serving metrics");
+        tokio::spawn(async move {
+            // until the process exits
+            if let Err(err) = rencfs::metrics::serve_metrics(addr, std::future::pending()).await {
+                error!(err = %err, %addr, "serving metrics");
+            }
+        });
+    }
+    let mounts: Vec<_> = mountpoints
+        .iter()
+        .cloned()
+        .zip(mount_handles.iter().map(mount::MountHandle::fs))
+        .collect();
+    let (unmount_tx, mut unmount_rx) = tokio::sync::mpsc::unbounded_channel();
+    for (mountpoint, fs) in &mounts {
+        if let Err(err) = control::listen(mountpoint, fs.clone(), unmount_tx.clone()) {
+            warn!(err = %err, "cannot listen on control socket, stats won't work");
+        }
+    }
+    control::dump_on_signal(mounts)?;
+    systemd::ready();
+    let watched = mountpoints.clone();
+    tokio::spawn(systemd::watchdog(move || {
+        let watched = watched.clone();
+        // hangs if a filesystem does
+        async move {
+            for mountpoint in watched {
+                if fs::metadata(mountpoint).await.is_err() {
+                    return false;
+                }
+            }
+            true
+        }
+    }));
+    let mount_handles: Vec<_> = mountpoints.into_iter().zip(mount_handles).collect();
+    let mount_handles = Arc::new(Mutex::new(mount_handles));
+    let rt = tokio::runtime::Handle::current();
+    let handles = mount_handles.clone();
+    // cleanup on process kill, on SIGINT, SIGTERM and SIGHUP
     set_handler(move || {
         // can't use tracing methods here as guard cannot be dropper to flush content before we exit
         eprintln!("Received signal to exit");
+        systemd::notify("STOPPING=1");
         let mut status: Option<ExitStatusError> = None;
-        remove_pass();
-        eprintln!("Unmounting {}", mountpoint);
-        // create new tokio runtime
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        let _ = rt
-            .block_on(async {
-                let res = mount_handle_clone
-                    .lock()
-                    .await
-                    .replace(None)
-                    .unwrap()
-                    .unwrap()
-                    .umount()
-                    .await;
-                if res.is_err() {
-                    umount(mountpoint.as_str())?;
-                }
-                Ok::<(), io::Error>(())
-            })
-            .map_err(|err| {
+        let mount_handles: Vec<_> = rt.block_on(handles.lock()).drain(..).collect();
+        for (mountpoint, mount_handle) in mount_handles {
+            remove_pass(&mountpoint);
+            eprintln!("Unmounting {mountpoint}");
+            // it unmounts, flushes and wipes the keys in the runtime serving the mount
+            let _ = rt.block_on(mount_handle.umount()).map_err(|err| {
                 eprintln!("Error: {}", err);
                 status.replace(ExitStatusError::Failure(1));
                 err
             });
+        }
+        daemon::cleanup();
+        control::cleanup();
         eprintln!("Bye!");
         process::exit(status.map_or(0, |x| match x {
             ExitStatusError::Failure(status) => status,
         }));
     })?;
 
-    task::spawn_blocking(|| {
-        let rt = tokio::runtime::Handle::current();
-        rt.block_on(async {
-            tokio::time::sleep(tokio::time::Duration::from_secs(u64::MAX)).await;
-        })
+    // until we get a signal, or the last one is unmounted through its control socket
+    while let Some(request) = unmount_rx.recv().await {
+        let mut handles = mount_handles.lock().await;
+        let Some(index) = handles
+            .iter()
+            .position(|(mountpoint, _)| *mountpoint == request.mountpoint)
+        else {
+            continue;
+        };
+        let (mountpoint, mount_handle) = handles.remove(index);
+        let last = handles.is_empty();
+        drop(handles);
+        info!("Unmounting {mountpoint}");
+        remove_pass(&mountpoint);
+        control::close(&mountpoint);
+        let res = mount_handle.umount().await;
+        if let Err(err) = &res {
+            error!(err = %err, "unmounting {mountpoint}");
+        }
+        let _ = request.done.send(res).await;
+        if last {
+            // so it's answered before we exit
+            let _ = tokio::time::timeout(Duration::from_secs(1), request.done.closed()).await;
+            systemd::notify("STOPPING=1");
+            daemon::cleanup();
+            control::cleanup();
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Mount the encrypted view of a plaintext dir, until we get a signal. The first time the key is created.
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+async fn run_mount_reverse(matches: &ArgMatches) -> Result<()> {
+    let cipher = parse_cipher(matches)?;
+    read_password_input(matches)?;
+    let (_, matches) = matches.subcommand().unwrap();
+    let mountpoint: String = matches
+        .get_one::<String>("mount-point")
+        .unwrap()
+        .to_string();
+    let source = PathBuf::from(matches.get_one::<String>("data-dir").unwrap());
+    if !source.is_dir() {
+        println!("{} is not a directory", source.display());
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    let password = prompt_password("Enter password: ")?;
+    let initialized = ReverseFs::is_initialized(&source);
+    if !initialized && !confirm_password("Confirm password: ", &password)? {
+        println!("Passwords do not match");
+        return Err(ExitStatusError::Failure(1).into());
+    }
+    let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    let fs = task::spawn_blocking(move || {
+        if !initialized {
+            info!("Creating the key of the encrypted view");
+            ReverseFs::init(&source, &password, cipher, KdfParams::default())?;
+        }
+        ReverseFs::open(&source, &password)
     })
-    .await?;
+    .await?
+    .map_err(|err| match err {
+        FsError::InvalidPassword => {
+            println!("Invalid password");
+            ExitStatusError::Failure(EXIT_INVALID_PASSWORD)
+        }
+        _ => {
+            error!(err = %err);
+            ExitStatusError::Failure(1)
+        }
+    })?;
+
+    if matches.get_flag("umount-on-start") {
+        let _ = umount(mountpoint.as_str()).await.map_err(|err| {
+            warn!("Cannot umount, maybe it was not mounted: {err}");
+            err
+        });
+    }
+    let mount_handle = mount::mount_reverse(
+        PathBuf::from(&mountpoint),
+        fs,
+        matches.get_flag("allow-root"),
+        matches.get_flag("allow-other"),
+    )
+    .await
+    .map_err(|err| {
+        error!(err = %err, "cannot mount");
+        ExitStatusError::Failure(1)
+    })?;
+    systemd::ready();
+
+    // until we get a signal, on SIGINT, SIGTERM and SIGHUP
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    set_handler(move || {
+        let _ = tx.send(());
+    })?;
+    rx.recv().await;
+    systemd::notify("STOPPING=1");
+    info!("Unmounting {mountpoint}");
+    mount_handle.umount().await?;
 
     Ok(())
 }
 
-fn remove_pass() {
-    unsafe {
-        if PASS.is_none() {
-            info!("Delete key from keyring");
-            keyring::remove("password")
-                .map_err(|err| {
+/// Unlock and mount one volume, `several` when there are more to prompt for.
+async fn mount_volume(
+    cipher: Cipher,
+    matches: &ArgMatches,
+    several: bool,
+) -> Result<mount::MountHandle> {
+    let mountpoint: String = matches
+        .get_one::<String>("mount-point")
+        .unwrap()
+        .to_string();
+
+    let mut data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
+    let snapshot = matches.get_one::<String>("snapshot");
+    if let Some(name) = snapshot {
+        data_dir = EncryptedFs::snapshot_path(Path::new(&data_dir), name)
+            .to_string_lossy()
+            .to_string();
+        if !Path::new(&data_dir).is_dir() {
+            println!("Snapshot not found");
+            return Err(ExitStatusError::Failure(1).into());
+        }
+    }
+    let storage = match matches.get_one::<String>("storage") {
+        Some(location) => {
+            // it might connect, which blocks
+            let location = location.clone();
+            Some(
+                tokio::task::spawn_blocking(move || storage::open(&location))
+                    .await?
+                    .map_err(|err| {
+                        error!(err = %err, "opening storage");
+                        ExitStatusError::Failure(1)
+                    })?,
+            )
+        }
+        None => None,
+    };
+    // with a typo in --data-dir we would create a new one, which looks like the data is gone. With a storage it's
+    // pulled from there
+    if storage.is_none() && is_empty_dir(Path::new(&data_dir)).await? {
+        println!(
+            "There is no volume in {data_dir}, create it with `rencfs init --data-dir {data_dir}`"
+        );
+        return Err(ExitStatusError::Failure(1).into());
+    }
+
+    // when running from IDE we can't read from stdin with rpassword, get it from env var
+    let mut password = LockedString::new(if PASSWORD_INPUT.lock().unwrap().is_some() {
+        String::new()
+    } else {
+        env::var("RENCFS_PASSWORD").unwrap_or_else(|_| String::new())
+    });
+    let from_slot = matches.get_one::<String>("fido2").is_some()
+        || matches.get_flag("tpm")
+        || matches.get_one::<String>("pkcs11-module").is_some();
+    // keep it between mounts, for each data dir
+    let keyring_entry = matches.get_flag("use-keyring").then(|| {
+        let data_dir =
+            std::fs::canonicalize(&data_dir).unwrap_or_else(|_| PathBuf::from(&data_dir));
+        format!("volume.{}", data_dir.display())
+    });
+    let from_keyring = keyring_entry
+        .as_ref()
+        .and_then(|entry| keyring::get(entry).ok());
+    let prompt = if several {
+        format!("Enter password for {mountpoint}: ")
+    } else {
+        "Enter password: ".to_string()
+    };
+    let mut prompted = false;
+    if let Some(saved) = from_keyring.clone() {
+        info!("Got password from keyring");
+        password = saved;
+    } else if let Some(device) = matches.get_one::<String>("fido2") {
+        println!("Touch the security key...");
+        password = key_slot_secret(&data_dir, |kind| match kind {
+            KeySlotKind::Fido2 { .. } => Some(fido2::secret(device, kind)),
+            _ => None,
+        })
+        .await?;
+    } else if let Some(module) = matches.get_one::<String>("pkcs11-module") {
+        let pin = read_pkcs11_pin();
+        let slot = matches.get_one::<String>("pkcs11-slot");
+        password = key_slot_secret(&data_dir, |kind| match kind {
+            KeySlotKind::Pkcs11 {
+                module: slot_module,
+                slot: slot_slot,
+                key_label,
+                wrapped,
+            } if slot_module == module => {
+                let kind = KeySlotKind::Pkcs11 {
+                    module: module.clone(),
+                    slot: slot.cloned().or_else(|| slot_slot.clone()),
+                    key_label: key_label.clone(),
+                    wrapped: wrapped.clone(),
+                };
+                Some(pkcs11::unwrap(&kind, &pin))
+            }
+            _ => None,
+        })
+        .await?;
+    } else if matches.get_flag("tpm") {
+        password = key_slot_secret(&data_dir, |kind| match kind {
+            KeySlotKind::Tpm { .. } => Some(tpm::unseal(kind)),
+            _ => None,
+        })
+        .await?;
+    } else if matches.get_flag("shares") {
+        password = read_shares()?;
+    } else if password.expose_secret().is_empty() {
+        // read password from stdin
+        prompted = PASSWORD_INPUT.lock().unwrap().is_none();
+        password = prompt_password(&prompt)?;
+    }
+    if !from_slot && from_keyring.is_none() {
+        password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+    }
+    // ask again if it was mistyped, a storage is pulled only when mounting so there is nothing to check yet
+    if prompted && !is_empty_dir(Path::new(&data_dir)).await? {
+        let attempts = *matches.get_one::<u32>("password-attempts").unwrap();
+        let mut attempt = 1;
+        loop {
+            match EncryptedFs::check_password(Path::new(&data_dir), &password, cipher).await {
+                Ok(()) => break,
+                Err(FsError::InvalidPassword) if attempt < attempts => {
+                    // slow down guessing
+                    let backoff = Duration::from_secs(1 << (attempt - 1));
+                    println!("Invalid password, try again in {}s", backoff.as_secs());
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                    password = prompt_password(&prompt)?;
+                    password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+                }
+                Err(FsError::InvalidPassword) => {
+                    println!("Invalid password");
+                    return Err(ExitStatusError::Failure(EXIT_INVALID_PASSWORD).into());
+                }
+                Err(err) => {
                     error!(err = %err);
-                })
-                .ok();
+                    return Err(ExitStatusError::Failure(1).into());
+                }
+            }
+        }
+    }
+    keep_password(&mountpoint, &password);
+
+    let totp_code = EncryptedFs::is_totp_enrolled(Path::new(&data_dir)).then(read_totp_code);
+
+    if matches.get_flag("umount-on-start") {
+        let _ = umount(mountpoint.as_str()).await.map_err(|err| {
+            warn!("Cannot umount, maybe it was not mounted: {err}");
+            err
+        });
+    }
+
+    let auto_lock = matches.get_one::<u64>("auto-lock").map(|secs| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let keyfile = matches.get_one::<String>("keyfile").cloned();
+        let mountpoint = mountpoint.clone();
+        std::thread::spawn(move || unlock_prompt(&mountpoint, &rx, keyfile.as_ref()));
+        (Duration::from_secs(*secs), tx)
+    });
+
+    #[allow(clippy::items_after_statements)]
+    struct PasswordProviderImpl {
+        mountpoint: String,
+        totp_code: Option<SecretString>,
+        // the base is never locked, it must not forget the password of the mount
+        lockable: bool,
+        // to ask for the password in the terminal, with --auto-lock
+        locked: Option<std::sync::mpsc::Sender<()>>,
+    }
+    #[allow(clippy::items_after_statements)]
+    impl PasswordProvider for PasswordProviderImpl {
+        fn get_password(&self) -> Option<LockedString> {
+            let pass = PASS.lock().unwrap().get(&self.mountpoint).cloned();
+            if pass.is_some() {
+                info!("Get password from memory");
+                pass
+            } else {
+                info!("Get password from keyring");
+                keyring::get(&password_entry(&self.mountpoint))
+                    .map_err(|err| {
+                        error!(err = %err, "cannot get password from keyring");
+                        err
+                    })
+                    .ok()
+            }
+        }
+
+        fn get_totp_code(&self) -> Option<SecretString> {
+            self.totp_code.clone()
+        }
+
+        fn forget_password(&self) {
+            if !self.lockable {
+                return;
+            }
+            // it's called again while locked, ask only once
+            let had_password = PASS.lock().unwrap().remove(&self.mountpoint).is_some()
+                | keyring::remove(&password_entry(&self.mountpoint)).is_ok();
+            if let (true, Some(locked)) = (had_password, &self.locked) {
+                let _ = locked.send(());
+            }
+        }
+    }
+    let base = match matches.get_one::<String>("base") {
+        Some(base) => Some(
+            EncryptedFs::new(
+                PathBuf::from(base),
+                Box::new(PasswordProviderImpl {
+                    mountpoint: mountpoint.clone(),
+                    totp_code: EncryptedFs::is_totp_enrolled(Path::new(base)).then(read_totp_code),
+                    lockable: false,
+                    locked: None,
+                }),
+                cipher,
+                FsOptions::default()
+                    .with_read_only(true)
+                    .with_lock_data_dir(!matches.get_flag("force")),
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err, "opening base");
+                ExitStatusError::Failure(1)
+            })?,
+        ),
+        None => None,
+    };
+    let mut flags = mount::MountFlags::default();
+    let mut attr_ttl = matches.get_one::<Duration>("attr-timeout").copied();
+    let mut entry_ttl = matches.get_one::<Duration>("entry-timeout").copied();
+    for option in matches.get_many::<MountOpt>("options").unwrap_or_default() {
+        match option.clone() {
+            MountOpt::NoExec => flags.noexec = true,
+            MountOpt::NoSuid => flags.nosuid = true,
+            MountOpt::NoDev => flags.nodev = true,
+            MountOpt::FsName(name) => flags.fsname = Some(name),
+            MountOpt::Subtype(name) => flags.subtype = Some(name),
+            // the ones given by themselves win
+            MountOpt::AttrTimeout(ttl) => attr_ttl = attr_ttl.or(Some(ttl)),
+            MountOpt::EntryTimeout(ttl) => entry_ttl = entry_ttl.or(Some(ttl)),
+        }
+    }
+    let config = mount::MountConfig {
+        mountpoint: PathBuf::from(&mountpoint),
+        data_dir: PathBuf::from(&data_dir),
+        password_provider: Box::new(PasswordProviderImpl {
+            mountpoint: mountpoint.clone(),
+            totp_code,
+            lockable: true,
+            locked: auto_lock.as_ref().map(|(_, tx)| tx.clone()),
+        }),
+        cipher,
+        allow_root: matches.get_flag("allow-root"),
+        allow_other: matches.get_flag("allow-other"),
+        direct_io: matches.get_flag("direct-io"),
+        suid_support: matches.get_flag("suid"),
+        options: FsOptions::default()
+            .with_pad_file_sizes(matches.get_flag("pad-file-sizes"))
+            .with_secure_delete(matches.get_flag("secure-delete"))
+            .with_audit_log(matches.get_flag("audit-log"))
+            .with_audit_reads(matches.get_flag("audit-reads"))
+            .with_rotate_key(matches.get_flag("rotate-key"))
+            .with_auto_lock(auto_lock.map(|(timeout, _)| timeout))
+            .with_read_only(matches.get_flag("read-only") || snapshot.is_some())
+            .with_backup_before_migrate(matches.get_flag("backup-before-migrate"))
+            .with_scrub_bandwidth(matches.get_one::<u64>("scrub-bandwidth").copied())
+            .with_dedup(matches.get_flag("dedup"))
+            .with_storage(storage)
+            .with_base(base)
+            .with_max_size(matches.get_one::<u64>("max-size").copied())
+            .with_lock_data_dir(!matches.get_flag("force"))
+            .with_compression(matches.get_one::<i32>("compress").copied())
+            .with_keep_versions(matches.get_one::<usize>("keep-versions").copied())
+            .with_version_retention(
+                matches
+                    .get_one::<u64>("retention")
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            )
+            .with_id_map(IdMap {
+                uids: matches
+                    .get_many::<(u32, u32)>("map-uid")
+                    .unwrap_or_default()
+                    .copied()
+                    .collect(),
+                gids: matches
+                    .get_many::<(u32, u32)>("map-gid")
+                    .unwrap_or_default()
+                    .copied()
+                    .collect(),
+                to_mounter: matches.get_flag("owned-by-mounter"),
+            })
+            .with_attr_ttl(attr_ttl)
+            .with_entry_ttl(entry_ttl)
+            .with_case_insensitive(matches.get_flag("case-insensitive"))
+            .with_umask(matches.get_one::<u32>("umask").copied())
+            .with_file_mode(matches.get_one::<u32>("file-mode").copied())
+            .with_dir_mode(matches.get_one::<u32>("dir-mode").copied()),
+        flags,
+    };
+    let backend = mount::backend(matches.get_one::<String>("backend").map(String::as_str))
+        .map_err(|err| {
+            error!(err = %err);
+            ExitStatusError::Failure(1)
+        })?;
+    let mount_handle = backend.mount(config).await.map_err(|err| {
+        error!(err = %err);
+        if matches!(err, FsError::DataDirInUse(_)) {
+            error!("Unmount it there first, or add --force if you're sure it's not writing to it");
+        } else if let (Some(entry), Some(_)) = (&keyring_entry, &from_keyring) {
+            // it might be from before the password was changed
+            warn!("Removing password saved in keyring, try again to enter it");
+            let _ = keyring::remove(entry);
+        }
+        if matches!(err, FsError::InvalidPassword) {
+            ExitStatusError::Failure(EXIT_INVALID_PASSWORD)
         } else {
-            info!("Remove key from memory");
-            PASS = None;
+            ExitStatusError::Failure(1)
+        }
+    })?;
+    if let (Some(entry), None) = (&keyring_entry, &from_keyring) {
+        info!("Save password in keyring for next mounts");
+        if let Err(err) = keyring::save(&password, entry) {
+            warn!(err = %err, "cannot save password in keyring");
         }
     }
+    Ok(mount_handle)
 }
 
-fn umount(mountpoint: &str) -> io::Result<()> {
-    // try normal umount
-    if process::Command::new("umount")
-        .arg(mountpoint)
-        .output()?
-        .status
-        .success()
-    {
-        return Ok(());
+async fn run_umount(matches: &ArgMatches) -> Result<()> {
+    let mountpoint = matches.get_one::<String>("mount-point").unwrap();
+    let stopped = matches
+        .get_one::<String>("pid-file")
+        .map_or_else(
+            || daemon::default_path(mountpoint, "pid"),
+            |path| Ok(PathBuf::from(path)),
+        )
+        .and_then(|pid_file| daemon::stop(&pid_file))
+        .map_err(|err| {
+            error!(err = %err, "cannot stop the daemon");
+            ExitStatusError::Failure(1)
+        })?;
+    if !stopped {
+        umount(mountpoint).await.map_err(|err| {
+            error!(err = %err);
+            ExitStatusError::Failure(1)
+        })?;
     }
-    // force umount
-    if process::Command::new("umount")
-        .arg("-f")
-        .arg(mountpoint)
-        .output()?
-        .status
-        .success()
-    {
-        return Ok(());
+    println!("Unmounted {mountpoint}");
+    Ok(())
+}
+
+async fn run_stats(matches: &ArgMatches) -> Result<()> {
+    let mountpoint = matches.get_one::<String>("mount-point").unwrap();
+    let stats = control::send(mountpoint, "stats").await.map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    print!("{stats}");
+    Ok(())
+}
+
+async fn run_log_filter(matches: &ArgMatches) -> Result<()> {
+    let mountpoint = matches.get_one::<String>("mount-point").unwrap();
+    let directives = matches.get_one::<String>("directives").unwrap();
+    let answer = control::send(mountpoint, &format!("log-filter {directives}"))
+        .await
+        .map_err(|err| {
+            error!(err = %err);
+            ExitStatusError::Failure(1)
+        })?;
+    if let Some(err) = answer.strip_prefix("error\t") {
+        error!("{}", err.trim_end());
+        return Err(ExitStatusError::Failure(1).into());
     }
-    // lazy umount
-    if process::Command::new("umount")
-        .arg("-l")
-        .arg(mountpoint)
-        .output()?
-        .status
-        .success()
-    {
-        Ok(())
+    Ok(())
+}
+
+async fn run_ctl(matches: &ArgMatches) -> Result<()> {
+    let mountpoint = matches.get_one::<String>("mount-point").unwrap();
+    let command = match matches.subcommand() {
+        Some(("unlock", _)) => {
+            let password = prompt_password("Enter password: ")?;
+            let password = with_keyfile(password, matches.get_one::<String>("keyfile"))?;
+            format!("unlock\n{}", password.expose_secret())
+        }
+        Some(("log-filter", matches)) => format!(
+            "log-filter {}",
+            matches.get_one::<String>("directives").unwrap()
+        ),
+        Some((command, _)) => command.to_string(),
+        None => unreachable!(),
+    };
+    let answer = control::send(mountpoint, &command).await.map_err(|err| {
+        error!(err = %err);
+        ExitStatusError::Failure(1)
+    })?;
+    match answer.strip_prefix("error\t").map(str::trim_end) {
+        Some("invalid password") => {
+            println!("Invalid password");
+            Err(ExitStatusError::Failure(EXIT_INVALID_PASSWORD).into())
+        }
+        Some(err) => {
+            error!("{err}");
+            Err(ExitStatusError::Failure(1).into())
+        }
+        None if answer == "ok\n" => Ok(()),
+        None => {
+            print!("{answer}");
+            Ok(())
+        }
+    }
+}
+
+fn remove_pass(mountpoint: &str) {
+    if PASS.lock().unwrap().remove(mountpoint).is_some() {
+        info!("Remove key from memory");
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("cannot umount {}", mountpoint),
-        )
-        .into())
+        info!("Delete key from keyring");
+        keyring::remove(&password_entry(mountpoint))
+            .map_err(|err| {
+                error!(err = %err);
+            })
+            .ok();
     }
 }
 
+async fn umount(mountpoint: &str) -> io::Result<()> {
+    if mount::umount(Path::new(mountpoint)).await? == mount::Unmounted::Lazily {
+        warn!("{mountpoint} was busy, it's unmounted lazily");
+    }
+    Ok(())
+}
+
 #[allow(clippy::missing_panics_doc)]
-pub fn log_init(level: Level) -> WorkerGuard {
-    let directive = format!("rencfs={}", level.as_str())
-        .parse()
-        .expect("cannot parse log directive");
-    let filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env()
-        .unwrap()
-        .add_directive(directive);
+/// Logs go to `file`, or else to stdout, as JSON objects, one per line, or as text, with colors if `ansi`.
+/// What to log, `directives` like `RUST_LOG` if given, else `RUST_LOG` with `level` for our targets. With
+/// `trace_ops` each FUSE operation is logged with its arguments.
+fn log_filter(
+    level: Level,
+    directives: Option<&str>,
+    trace_ops: bool,
+) -> Result<EnvFilter, ParseError> {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+    let mut filter = match directives {
+        Some(directives) => builder.parse(directives)?,
+        None => builder
+            .from_env_lossy()
+            .add_directive(format!("rencfs={}", level.as_str()).parse()?),
+    };
+    if trace_ops {
+        filter = filter.add_directive("rencfs::ops=trace".parse()?);
+    }
+    Ok(filter)
+}
 
-    let (writer, guard) = tracing_appender::non_blocking(io::stdout());
-    let builder = tracing_subscriber::fmt()
+/// Replace the filter of the logs, for the `log-filter` command of the control socket.
+pub(crate) fn set_log_filter(directives: &str) -> Result<(), String> {
+    let filter = log_filter(Level::INFO, Some(directives), false).map_err(|err| err.to_string())?;
+    LOG_FILTER
+        .get()
+        .ok_or_else(|| "logs are not initialized".to_string())?
+        .reload(filter)
+        .map_err(|err| err.to_string())
+}
+
+pub fn log_init(
+    filter: EnvFilter,
+    file: Option<&Path>,
+    ansi: bool,
+    json: bool,
+) -> io::Result<WorkerGuard> {
+    let (writer, guard) = match file {
+        Some(path) => {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .mode(0o600)
+                .open(path)?;
+            tracing_appender::non_blocking(file)
+        }
+        None => tracing_appender::non_blocking(io::stdout()),
+    };
+    // so it can be changed while mounted
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = LOG_FILTER.set(handle);
+    let layer = tracing_subscriber::fmt::layer()
         .with_writer(writer)
-        .with_env_filter(filter);
-    // .with_max_level(level);
-    if is_debug() {
-        builder.pretty().init();
+        .with_ansi(ansi);
+    let layer = if json {
+        // the span of the FUSE operation too, with its arguments
+        layer.json().with_current_span(true).boxed()
+    } else if is_debug() {
+        layer.pretty().boxed()
     } else {
-        builder.init();
-    }
+        layer.boxed()
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .init();
 
-    guard
+    Ok(guard)
 }
@@ -0,0 +1,322 @@
+//! Counters and histograms of what the filesystems of the process do, FUSE operations, bytes encrypted and
+//! decrypted and hits of the caches. They are cheap to update, so they are always kept. [`render`] gives them in the
+//! Prometheus text format, served by [`serve_metrics`] with the `metrics` feature.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the buckets of [`Histogram`], in microseconds.
+const BUCKETS_US: [u64; 14] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000,
+];
+
+/// Bytes of plaintext encrypted, for content and metadata.
+pub static BYTES_ENCRYPTED: Counter = Counter::new();
+/// Bytes of plaintext decrypted, for content and metadata.
+pub static BYTES_DECRYPTED: Counter = Counter::new();
+/// Decrypted attributes of inodes.
+pub static ATTR_CACHE: CacheStats = CacheStats::new();
+/// Decrypted names of directory entries.
+pub static DIR_ENTRY_NAME_CACHE: CacheStats = CacheStats::new();
+/// Inode and kind of directory entries.
+pub static DIR_ENTRY_META_CACHE: CacheStats = CacheStats::new();
+/// Directory entries looked up by name.
+pub static DIR_ENTRY_HASH_CACHE: CacheStats = CacheStats::new();
+/// Unwrapped keys of files.
+pub static FILE_KEY_CACHE: CacheStats = CacheStats::new();
+
+/// By name of the FUSE operation.
+static OPS: Mutex<BTreeMap<&'static str, Arc<OpStats>>> = Mutex::new(BTreeMap::new());
+
+/// Only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How many durations fall in each bucket of [`BUCKETS_US`], and over.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS_US.len() + 1],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn observe(&self, duration: Duration) {
+        let us = duration.as_micros() as u64;
+        let i = BUCKETS_US.partition_point(|bound| *bound < us);
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    #[must_use]
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Ordering::Relaxed))
+    }
+
+    /// Upper bound of the bucket the `q` quantile falls in, like 0.99, or [`None`] if nothing was observed. Over the
+    /// last bucket it's [`Duration::MAX`].
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(
+                    BUCKETS_US
+                        .get(i)
+                        .map_or(Duration::MAX, |us| Duration::from_micros(*us)),
+                );
+            }
+        }
+        Some(Duration::MAX)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub hits: Counter,
+    pub misses: Counter,
+}
+
+impl CacheStats {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            hits: Counter::new(),
+            misses: Counter::new(),
+        }
+    }
+
+    /// Count a lookup, found or not.
+    pub fn lookup(&self, hit: bool) {
+        if hit {
+            self.hits.inc();
+        } else {
+            self.misses.inc();
+        }
+    }
+}
+
+/// Of one FUSE operation.
+#[derive(Debug, Default)]
+pub struct OpStats {
+    pub latency: Histogram,
+    pub errors: Counter,
+}
+
+/// Count the FUSE operation `op`, which took `duration`.
+#[allow(clippy::missing_panics_doc)]
+pub fn record_op(op: &'static str, duration: Duration, failed: bool) {
+    let stats = OPS.lock().unwrap().entry(op).or_default().clone();
+    stats.latency.observe(duration);
+    if failed {
+        stats.errors.inc();
+    }
+}
+
+/// The FUSE operations done so far, by name.
+#[must_use]
+#[allow(clippy::missing_panics_doc)]
+pub fn ops() -> Vec<(&'static str, Arc<OpStats>)> {
+    OPS.lock()
+        .unwrap()
+        .iter()
+        .map(|(op, stats)| (*op, stats.clone()))
+        .collect()
+}
+
+/// All the metrics in the Prometheus text format.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn render() -> String {
+    let mut out = String::new();
+    let ops = ops();
+
+    out.push_str("# HELP rencfs_fuse_op_duration_seconds Duration of FUSE operations.\n");
+    out.push_str("# TYPE rencfs_fuse_op_duration_seconds histogram\n");
+    for (op, stats) in &ops {
+        let mut cumulative = 0;
+        for (i, bound) in BUCKETS_US.iter().enumerate() {
+            cumulative += stats.latency.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "rencfs_fuse_op_duration_seconds_bucket{{op=\"{op}\",le=\"{}\"}} {cumulative}",
+                *bound as f64 / 1_000_000.0
+            );
+        }
+        let count = stats.latency.count();
+        let _ = writeln!(
+            out,
+            "rencfs_fuse_op_duration_seconds_bucket{{op=\"{op}\",le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "rencfs_fuse_op_duration_seconds_sum{{op=\"{op}\"}} {}",
+            stats.latency.sum().as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "rencfs_fuse_op_duration_seconds_count{{op=\"{op}\"}} {count}"
+        );
+    }
+
+    out.push_str("# HELP rencfs_fuse_op_errors_total FUSE operations that returned an error.\n");
+    out.push_str("# TYPE rencfs_fuse_op_errors_total counter\n");
+    for (op, stats) in &ops {
+        let _ = writeln!(
+            out,
+            "rencfs_fuse_op_errors_total{{op=\"{op}\"}} {}",
+            stats.errors.get()
+        );
+    }
+
+    out.push_str("# HELP rencfs_bytes_encrypted_total Bytes of plaintext encrypted.\n");
+    out.push_str("# TYPE rencfs_bytes_encrypted_total counter\n");
+    let _ = writeln!(
+        out,
+        "rencfs_bytes_encrypted_total {}",
+        BYTES_ENCRYPTED.get()
+    );
+    out.push_str("# HELP rencfs_bytes_decrypted_total Bytes of plaintext decrypted.\n");
+    out.push_str("# TYPE rencfs_bytes_decrypted_total counter\n");
+    let _ = writeln!(
+        out,
+        "rencfs_bytes_decrypted_total {}",
+        BYTES_DECRYPTED.get()
+    );
+
+    let caches = [
+        ("attr", &ATTR_CACHE),
+        ("dir_entry_name", &DIR_ENTRY_NAME_CACHE),
+        ("dir_entry_meta", &DIR_ENTRY_META_CACHE),
+        ("dir_entry_hash", &DIR_ENTRY_HASH_CACHE),
+        ("file_key", &FILE_KEY_CACHE),
+    ];
+    out.push_str("# HELP rencfs_cache_hits_total Lookups found in a cache.\n");
+    out.push_str("# TYPE rencfs_cache_hits_total counter\n");
+    for (cache, stats) in caches {
+        let _ = writeln!(
+            out,
+            "rencfs_cache_hits_total{{cache=\"{cache}\"}} {}",
+            stats.hits.get()
+        );
+    }
+    out.push_str("# HELP rencfs_cache_misses_total Lookups not found in a cache.\n");
+    out.push_str("# TYPE rencfs_cache_misses_total counter\n");
+    for (cache, stats) in caches {
+        let _ = writeln!(
+            out,
+            "rencfs_cache_misses_total{{cache=\"{cache}\"}} {}",
+            stats.misses.get()
+        );
+    }
+
+    out
+}
+
+/// Serve [`render`] on `addr` for Prometheus to scrape, at any path, until `shutdown` completes.
+#[cfg(feature = "metrics")]
+#[allow(clippy::missing_errors_doc)]
+pub async fn serve_metrics(
+    addr: std::net::SocketAddr,
+    shutdown: impl std::future::Future<Output = ()> + Send,
+) -> std::io::Result<()> {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::header::CONTENT_TYPE;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use tracing::debug;
+
+    let listener = crate::serve::bind(addr).await?;
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            () = &mut shutdown => return Ok(()),
+        };
+        tokio::spawn(async move {
+            let service = service_fn(|_req| async {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                        .body(Full::new(Bytes::from(render())))
+                        .unwrap(),
+                )
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(%peer, err = %err, "metrics connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Histogram;
+
+    #[test]
+    fn quantile() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for _ in 0..90 {
+            histogram.observe(Duration::from_micros(80));
+        }
+        for _ in 0..10 {
+            histogram.observe(Duration::from_millis(3));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(100)));
+        assert_eq!(histogram.quantile(0.9), Some(Duration::from_micros(100)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_millis(5)));
+    }
+}
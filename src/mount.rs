@@ -1,33 +1,41 @@
 use crate::crypto::Cipher;
-use crate::encryptedfs::{FsResult, PasswordProvider};
+use crate::encryptedfs::{EncryptedFs, FsError, FsOptions, FsResult, PasswordProvider};
 use async_trait::async_trait;
 use futures_util::FutureExt;
 use std::future::Future;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::task;
+use tracing::{error, warn};
 
-#[cfg(target_os = "linux")]
-mod linux;
-#[cfg(target_os = "linux")]
-use linux::MountHandleInnerImpl;
-#[cfg(target_os = "linux")]
-use linux::MountPointImpl;
-
-#[cfg(target_os = "macos")]
-mod macos;
-#[cfg(target_os = "macos")]
-use macos::MountHandleInnerImpl;
-#[cfg(target_os = "macos")]
-use macos::MountPointImpl;
-
-#[cfg(target_os = "windows")]
-mod windows;
-#[cfg(target_os = "windows")]
-use windows::MountHandleInnerImpl;
-#[cfg(target_os = "windows")]
-use windows::MountPointImpl;
+#[cfg(any(
+    all(any(target_os = "linux", target_os = "freebsd"), feature = "fuse3"),
+    all(any(target_os = "linux", target_os = "macos"), feature = "fuser")
+))]
+mod access;
+#[cfg(all(any(target_os = "linux", target_os = "freebsd"), feature = "fuse3"))]
+mod fuse3;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+mod fuser;
+mod guard;
+#[cfg(any(
+    all(any(target_os = "linux", target_os = "freebsd"), feature = "fuse3"),
+    all(any(target_os = "linux", target_os = "macos"), feature = "fuser")
+))]
+mod ops;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+mod reverse;
+#[cfg(all(any(target_os = "linux", target_os = "freebsd"), feature = "fuse3"))]
+pub use self::fuse3::Fuse3Backend;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+pub use self::fuser::FuserBackend;
+pub use self::guard::set_panic_hook;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+pub use self::reverse::{mount_reverse, ReverseMountHandle};
 
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
@@ -43,20 +51,257 @@ pub trait MountPoint {
         allow_other: bool,
         direct_io: bool,
         suid_support: bool,
+        options: FsOptions,
     ) -> Self
     where
         Self: Sized;
     async fn mount(mut self) -> FsResult<MountHandle>;
 }
 
+/// What a [`MountBackend`] mounts, see [`create_mount_point`] for the fields.
+#[allow(clippy::module_name_repetitions)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct MountConfig {
+    pub mountpoint: PathBuf,
+    pub data_dir: PathBuf,
+    pub password_provider: Box<dyn PasswordProvider>,
+    pub cipher: Cipher,
+    pub allow_root: bool,
+    pub allow_other: bool,
+    pub direct_io: bool,
+    pub suid_support: bool,
+    pub options: FsOptions,
+    pub flags: MountFlags,
+}
+
+/// Standard options of `mount`, like given with `-o`, so admins can harden it and it's shown well by `mount` and `df`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountFlags {
+    /// Programs can't be run from it.
+    pub noexec: bool,
+    /// The setuid and setgid bits are ignored.
+    pub nosuid: bool,
+    /// Device files can't be opened.
+    pub nodev: bool,
+    /// Shown as the source by `mount` and `df`, `rencfs` if not given.
+    pub fsname: Option<String>,
+    /// Shown as the type `fuse.SUBTYPE`.
+    pub subtype: Option<String>,
+}
+
+impl MountFlags {
+    #[must_use]
+    pub fn fsname(&self) -> &str {
+        self.fsname.as_deref().unwrap_or("rencfs")
+    }
+}
+
+/// A way to expose the filesystem to the OS, like FUSE with `Fuse3Backend` or `FuserBackend`. Others can be added behind cargo
+/// features, the ones built in are in [`backends`].
+#[async_trait]
+#[allow(clippy::module_name_repetitions)]
+pub trait MountBackend: Send + Sync {
+    /// To select it with [`backend`], like `fuse3`.
+    fn name(&self) -> &'static str;
+
+    async fn mount(&self, config: MountConfig) -> FsResult<MountHandle>;
+}
+
+/// The backends built in for this platform, the first one is the default.
+#[must_use]
+pub fn backends() -> Vec<Box<dyn MountBackend>> {
+    vec![
+        #[cfg(all(any(target_os = "linux", target_os = "freebsd"), feature = "fuse3"))]
+        Box::new(Fuse3Backend),
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), feature = "fuser"))]
+        Box::new(FuserBackend),
+    ]
+}
+
+/// The backend called `name`, or the default one if `None`.
+pub fn backend(name: Option<&str>) -> FsResult<Box<dyn MountBackend>> {
+    let mut backends = backends().into_iter();
+    match name {
+        Some(name) => backends
+            .find(|backend| backend.name() == name)
+            .ok_or(FsError::InvalidInput("unknown mount backend")),
+        None => backends
+            .next()
+            .ok_or(FsError::Other("no mount backend for this platform")),
+    }
+}
+
+/// How many times [`umount`] tries while the mount point is busy, before it's detached lazily.
+const UMOUNT_ATTEMPTS: u32 = 5;
+const UMOUNT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// How long [`MountHandle::umount`] waits for the files still open after it was detached lazily.
+const LAZY_UMOUNT_WAIT: Duration = Duration::from_secs(10);
+
 #[allow(clippy::module_name_repetitions)]
 pub struct MountHandle {
-    inner: MountHandleInnerImpl,
+    mountpoint: PathBuf,
+    fs: Arc<EncryptedFs>,
+    inner: Box<dyn MountHandleInner>,
+    /// For [`set_panic_hook`], until it's unmounted.
+    _registered: guard::Registered,
 }
 impl MountHandle {
-    pub async fn umount(self) -> io::Result<()> {
-        self.inner.unmount().await
+    /// For a [`MountBackend`] to return that it mounted `fs` at `mountpoint`.
+    pub fn new(
+        mountpoint: PathBuf,
+        fs: Arc<EncryptedFs>,
+        inner: impl MountHandleInner + 'static,
+    ) -> Self {
+        Self {
+            _registered: guard::Registered::new(mountpoint.clone(), Arc::downgrade(&fs)),
+            mountpoint,
+            fs,
+            inner: Box::new(inner),
+        }
+    }
+
+    /// The filesystem it serves, like for [`EncryptedFs::stats`].
+    #[must_use]
+    pub fn fs(&self) -> Arc<EncryptedFs> {
+        self.fs.clone()
+    }
+
+    /// Unmount it and wait for the backend to finish, which writes everything to the storage. When it's busy it's
+    /// retried, then detached lazily, like [`umount`], and the files still open are flushed. The keys are wiped
+    /// from memory after.
+    pub async fn umount(mut self) -> io::Result<()> {
+        if let Err(err) = self.inner.unmount().await {
+            warn!(err = %err, "backend cannot unmount");
+        }
+        let res = if is_mounted(&self.mountpoint).await {
+            match umount(&self.mountpoint).await {
+                Ok(Unmounted::Clean) => self.inner.await,
+                Ok(Unmounted::Lazily) => {
+                    if let Err(err) = self.fs.flush_all().await {
+                        error!(err = %err, "flushing open files");
+                    }
+                    if let Ok(res) = tokio::time::timeout(LAZY_UMOUNT_WAIT, self.inner).await {
+                        res
+                    } else {
+                        warn!("files are still open, not waiting for them anymore");
+                        self.fs.sync_storage().await.map_err(io::Error::from)
+                    }
+                }
+                Err(err) => Err(err),
+            }
+        } else {
+            self.inner.await
+        };
+        self.fs.wipe_secrets().await;
+        res
+    }
+}
+
+/// If something else than its parent is mounted there, or it doesn't answer.
+async fn is_mounted(mountpoint: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = tokio::fs::metadata(mountpoint.join("..")).await;
+    match (tokio::fs::metadata(mountpoint).await, parent) {
+        (Ok(mountpoint), Ok(parent)) => mountpoint.dev() != parent.dev(),
+        _ => true,
+    }
+}
+
+/// How [`umount`] did it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmounted {
+    /// Nothing was using it.
+    Clean,
+    /// It was still busy. On Linux it's gone for new accesses and the files open keep working until closed, macOS and
+    /// FreeBSD can't do that, so it's forced and those fail.
+    Lazily,
+}
+
+/// Unmount `mountpoint`, also when mounted by another process. While it's busy it tries again a few times, then
+/// it's detached lazily. Users can only do it for their own mounts, on Linux with `fusermount3`.
+#[allow(clippy::missing_errors_doc)]
+pub async fn umount(mountpoint: &Path) -> io::Result<Unmounted> {
+    for attempt in 1..=UMOUNT_ATTEMPTS {
+        let path = mountpoint.to_path_buf();
+        match task::spawn_blocking(move || sys_umount(&path, false)).await? {
+            Ok(()) => return Ok(Unmounted::Clean),
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                warn!(attempt, mountpoint = %mountpoint.display(), "busy, trying again");
+                tokio::time::sleep(UMOUNT_RETRY_DELAY).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let path = mountpoint.to_path_buf();
+    task::spawn_blocking(move || sys_umount(&path, true)).await??;
+    Ok(Unmounted::Lazily)
+}
+
+#[cfg(target_os = "linux")]
+fn sys_umount(mountpoint: &Path, lazy: bool) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    // SAFETY: no arguments
+    if unsafe { libc::geteuid() } != 0 {
+        return fusermount(mountpoint, lazy);
+    }
+    let path = std::ffi::CString::new(mountpoint.as_os_str().as_bytes())?;
+    let flags = if lazy { libc::MNT_DETACH } else { 0 };
+    // SAFETY: `path` is a valid C string
+    if unsafe { libc::umount2(path.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Users can only unmount with the setuid helper of FUSE.
+#[cfg(target_os = "linux")]
+fn fusermount(mountpoint: &Path, lazy: bool) -> io::Result<()> {
+    let mut not_found = None;
+    for binary in ["fusermount3", "fusermount"] {
+        let mut command = std::process::Command::new(binary);
+        command.arg("-u");
+        if lazy {
+            command.arg("-z");
+        }
+        let output = match command.arg(mountpoint).output() {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                not_found = Some(err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(if stderr.contains("busy") {
+            io::Error::from_raw_os_error(libc::EBUSY)
+        } else {
+            io::Error::other(stderr.trim().to_string())
+        });
     }
+    Err(not_found.unwrap())
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn sys_umount(mountpoint: &Path, force: bool) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(mountpoint.as_os_str().as_bytes())?;
+    let flags = if force { libc::MNT_FORCE } else { 0 };
+    // SAFETY: `path` is a valid C string
+    if unsafe { libc::unmount(path.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn sys_umount(_mountpoint: &Path, _lazy: bool) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
 }
 
 impl Future for MountHandle {
@@ -67,9 +312,49 @@ impl Future for MountHandle {
     }
 }
 
+/// What a backend mounted, completes when it's unmounted.
 #[async_trait]
-pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
-    async fn unmount(mut self) -> io::Result<()>;
+#[allow(clippy::module_name_repetitions)]
+pub trait MountHandleInner: Future<Output = io::Result<()>> + Send + Unpin {
+    /// Unmount it the way of the backend, if it has one that keeps serving until the OS is done with it. The
+    /// session ends by itself after. [`MountHandle::umount`] does it if it's still mounted after.
+    async fn unmount(&mut self) -> io::Result<()>;
+}
+
+/// Mounts with the default [`MountBackend`].
+#[allow(clippy::module_name_repetitions)]
+pub struct MountPointImpl(MountConfig);
+
+#[async_trait]
+impl MountPoint for MountPointImpl {
+    fn new(
+        mountpoint: PathBuf,
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        allow_root: bool,
+        allow_other: bool,
+        direct_io: bool,
+        suid_support: bool,
+        options: FsOptions,
+    ) -> Self {
+        Self(MountConfig {
+            mountpoint,
+            data_dir,
+            password_provider,
+            cipher,
+            allow_root,
+            allow_other,
+            direct_io,
+            suid_support,
+            options,
+            flags: MountFlags::default(),
+        })
+    }
+
+    async fn mount(mut self) -> FsResult<MountHandle> {
+        backend(None)?.mount(self.0).await
+    }
 }
 
 /// **`mountpoint`** where it wil mount the filesystem  
@@ -80,8 +365,10 @@ pub(crate) trait MountHandleInner: Future<Output = io::Result<()>> {
 /// **`allow_root`** allow root to access the file system  
 /// **`allow_other`** allow other users to access the file system  
 /// **`direct_io`** use direct I/O (bypass page cache for open files)
-/// **`suid_support`** if it should allow setting `SUID` and `SGID` when files are created. On `false` it will unset those flags when creating files
+/// **`suid_support`** if it should allow setting `SUID` and `SGID` when files are created. On `false` it will unset those flags when creating files  
+/// **`options`** optional features of the filesystem, see [`FsOptions`]
 ///
+/// It's mounted with the default [`MountBackend`], use [`backend`] to pick another one.
 #[must_use]
 #[allow(clippy::fn_params_excessive_bools)]
 pub fn create_mount_point(
@@ -93,6 +380,7 @@ pub fn create_mount_point(
     allow_other: bool,
     direct_io: bool,
     suid_support: bool,
+    options: FsOptions,
 ) -> impl MountPoint {
     MountPointImpl::new(
         mountpoint.to_path_buf(),
@@ -103,5 +391,6 @@ pub fn create_mount_point(
         allow_other,
         direct_io,
         suid_support,
+        options,
     )
 }
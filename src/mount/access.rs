@@ -0,0 +1,153 @@
+//! Permission checks and modes of new files, the same for all the FUSE backends.
+
+use crate::encryptedfs::{CreateFileAttr, FileAttr, FileType};
+
+// the mode bits as in the `mode` we get, `mode_t` is `u16` on macOS
+#[allow(clippy::unnecessary_cast)]
+pub const S_IFMT: u32 = libc::S_IFMT as u32;
+#[allow(clippy::unnecessary_cast)]
+pub const S_IFREG: u32 = libc::S_IFREG as u32;
+#[allow(clippy::unnecessary_cast)]
+pub const S_IFDIR: u32 = libc::S_IFDIR as u32;
+#[allow(clippy::unnecessary_cast)]
+pub const S_ISUID: u32 = libc::S_ISUID as u32;
+#[allow(clippy::unnecessary_cast)]
+pub const S_ISGID: u32 = libc::S_ISGID as u32;
+
+/// Permissions for a new file or directory, from the mode asked for and the mount options. `forced` is the mode
+/// set for all new files or directories, see [`crate::encryptedfs::FsOptions::file_mode`].
+#[allow(clippy::cast_possible_truncation)]
+pub const fn creation_mode(
+    mode: u32,
+    forced: Option<u32>,
+    umask: Option<u32>,
+    suid_support: bool,
+) -> u16 {
+    let mode = match (forced, umask) {
+        // keep SETGID inherited from the parent
+        (Some(perm), _) => perm | (mode & S_ISGID),
+        (None, Some(umask)) => mode & !umask,
+        (None, None) => mode,
+    };
+    if suid_support {
+        mode as u16
+    } else {
+        (mode & !(S_ISUID | S_ISGID)) as u16
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub const fn creation_gid(parent: &FileAttr, gid: u32) -> u32 {
+    if parent.perm & libc::S_ISGID as u16 != 0 {
+        return parent.gid;
+    }
+
+    gid
+}
+
+/// Groups of the process, only known on Linux, from `/proc`.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub fn get_groups(pid: u32) -> Vec<u32> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+
+        let path = format!("/proc/{pid}/task/{pid}/status");
+        let file = File::open(path).unwrap();
+        for line in BufReader::new(file).lines() {
+            let line = line.unwrap();
+            if line.starts_with("Groups:") {
+                return line["Groups: ".len()..]
+                    .split(' ')
+                    .filter(|x| !x.trim().is_empty())
+                    .map(|x| x.parse::<u32>().unwrap())
+                    .collect();
+            }
+        }
+    }
+
+    vec![]
+}
+
+#[allow(clippy::cast_possible_truncation)]
+pub const fn clear_suid_sgid(mut perm: u16) -> u16 {
+    perm &= !libc::S_ISUID as u16;
+    // SGID is only suppose to be cleared if XGRP is set
+    if perm & libc::S_IXGRP as u16 != 0 {
+        perm &= !libc::S_ISGID as u16;
+    }
+    perm
+}
+
+pub fn as_file_kind(mut mode: u32) -> FileType {
+    mode &= S_IFMT;
+
+    if mode == S_IFREG {
+        FileType::RegularFile
+        // } else if mode == libc::S_IFLNK as u32 {
+        //     return FileType::Symlink;
+    } else if mode == S_IFDIR {
+        FileType::Directory
+    } else {
+        unimplemented!("{mode}");
+    }
+}
+
+pub const fn dir_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::Directory,
+        perm: 0o777,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+pub const fn file_attr() -> CreateFileAttr {
+    CreateFileAttr {
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub fn check_access(
+    #[allow(clippy::similar_names)] file_uid: u32,
+    #[allow(clippy::similar_names)] file_gid: u32,
+    file_mode: u16,
+    uid: u32,
+    gid: u32,
+    mut access_mask: i32,
+) -> bool {
+    // F_OK tests for existence of file
+    if access_mask == libc::F_OK {
+        return true;
+    }
+    let file_mode = i32::from(file_mode);
+
+    // root is allowed to read & write anything
+    if uid == 0 {
+        // root only allowed to exec if one of the X bits is set
+        access_mask &= libc::X_OK;
+        access_mask -= access_mask & (file_mode >> 6);
+        access_mask -= access_mask & (file_mode >> 3);
+        access_mask -= access_mask & file_mode;
+        return access_mask == 0;
+    }
+
+    if uid == file_uid {
+        access_mask -= access_mask & (file_mode >> 6);
+    } else if gid == file_gid {
+        access_mask -= access_mask & (file_mode >> 3);
+    } else {
+        access_mask -= access_mask & file_mode;
+    }
+
+    access_mask == 0
+}
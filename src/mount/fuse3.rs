@@ -0,0 +1,1616 @@
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
+use std::io;
+use std::iter::Skip;
+use std::num::NonZeroU32;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use fuse3::raw::prelude::{
+    DirectoryEntry, DirectoryEntryPlus, ReplyAttr, ReplyCopyFileRange, ReplyCreated, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplyInit, ReplyOpen, ReplyStatFs, ReplyWrite,
+};
+use fuse3::raw::{Filesystem, MountHandle, Request, Session};
+use fuse3::{Errno, Inode, MountOptions, Result, SetAttr, Timestamp};
+use futures_util::stream::Iter;
+use futures_util::{stream, FutureExt};
+use libc::{
+    EACCES, EEXIST, EFBIG, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM,
+};
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, error, instrument, trace, warn};
+use tracing::{info, Level};
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    AuditTarget, EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult, IdMap,
+    PasswordProvider, SetFileAttr,
+};
+use crate::mount;
+use crate::mount::access;
+use crate::mount::access::{
+    as_file_kind, check_access, clear_suid_sgid, creation_gid, dir_attr, file_attr, get_groups,
+    S_IFDIR, S_IFMT, S_IFREG, S_ISGID, S_ISUID,
+};
+use crate::mount::ops;
+use crate::mount::{MountBackend, MountConfig, MountFlags, MountHandleInner};
+
+const STATFS: ReplyStatFs = ReplyStatFs {
+    blocks: 1,
+    bfree: 0,
+    bavail: 0,
+    files: 1,
+    ffree: 0,
+    bsize: 4096,
+    namelen: u32::MAX,
+    frsize: 0,
+};
+
+/// Set by the kernel on the open from an `exec`, only on Linux, it's another flag on other platforms.
+const FMODE_EXEC: i32 = 0x20;
+
+// const MAX_NAME_LENGTH: u32 = 255 - ENCRYPT_FILENAME_OVERHEAD_CHARS as u32;
+
+// Flags returned by the open request
+const FOPEN_DIRECT_IO: u32 = 1 << 0; // bypass page cache for this open file
+
+pub struct DirectoryEntryIterator(crate::encryptedfs::DirectoryEntryIterator, u64);
+
+impl Iterator for DirectoryEntryIterator {
+    type Item = Result<DirectoryEntry>;
+
+    #[instrument(name = "DirectoryEntryIterator::next", skip(self))]
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next() {
+            Some(Ok(entry)) => {
+                let kind = if entry.kind == FileType::Directory {
+                    fuse3::raw::prelude::FileType::Directory
+                } else {
+                    fuse3::raw::prelude::FileType::RegularFile
+                };
+                self.1 += 1;
+                Some(Ok(DirectoryEntry {
+                    inode: entry.ino,
+                    kind,
+                    name: OsString::from(entry.name.expose_secret()),
+                    #[allow(clippy::cast_possible_wrap)]
+                    offset: self.1 as i64,
+                }))
+            }
+            Some(Err(FsError::Io { source, .. })) => {
+                error!(err = %source);
+                Some(Err(source.into()))
+            }
+            Some(Err(err)) => {
+                error!(err = %err);
+                Some(Err(EIO.into()))
+            }
+            None => None,
+        }
+    }
+}
+
+/// With the TTLs of entries and attributes.
+pub struct DirectoryEntryPlusIterator(
+    crate::encryptedfs::DirectoryEntryPlusIterator,
+    u64,
+    IdMap,
+    Duration,
+    Duration,
+);
+
+impl Iterator for DirectoryEntryPlusIterator {
+    type Item = Result<DirectoryEntryPlus>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next() {
+            Some(Ok(entry)) => {
+                let kind = if entry.kind == FileType::Directory {
+                    fuse3::raw::prelude::FileType::Directory
+                } else {
+                    fuse3::raw::prelude::FileType::RegularFile
+                };
+                self.1 += 1;
+                Some(Ok(DirectoryEntryPlus {
+                    inode: entry.ino,
+                    generation: 0,
+                    kind,
+                    name: OsString::from(entry.name.expose_secret()),
+                    #[allow(clippy::cast_possible_wrap)]
+                    offset: self.1 as i64,
+                    attr: self.2.map_attr(entry.attr).into(),
+                    entry_ttl: self.3,
+                    attr_ttl: self.4,
+                }))
+            }
+            Some(Err(FsError::Io { source, .. })) => {
+                error!(err = %source);
+                Some(Err(source.into()))
+            }
+            Some(Err(err)) => {
+                error!(err = %err);
+                Some(Err(EIO.into()))
+            }
+            None => None,
+        }
+    }
+}
+
+pub struct EncryptedFsFuse3 {
+    fs: Arc<EncryptedFs>,
+    direct_io: bool,
+    suid_support: bool,
+    id_map: IdMap,
+    umask: Option<u32>,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+}
+
+impl EncryptedFsFuse3 {
+    pub async fn new(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        direct_io: bool,
+        #[allow(unused_variables)] suid_support: bool,
+        options: FsOptions,
+    ) -> FsResult<Self> {
+        // #[cfg(feature = "abi-7-26")]
+        // {
+        //     Ok(Self {
+        //         fs: EncryptedFs::new(data_dir, password_provider, cipher).await?,
+        //         direct_io,
+        //         suid_support,
+        //     })
+        // }
+        // #[cfg(not(feature = "abi-7-26"))]
+        // {
+        Ok(Self {
+            id_map: options.id_map.clone(),
+            umask: options.umask,
+            file_mode: options.file_mode,
+            dir_mode: options.dir_mode,
+            fs: EncryptedFs::new(data_dir, password_provider, cipher, options).await?,
+            direct_io,
+            suid_support,
+        })
+        // }
+    }
+
+    fn get_fs(&self) -> Arc<EncryptedFs> {
+        self.fs.clone()
+    }
+
+    /// Attributes with the owner as shown, see [`IdMap`].
+    async fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        Ok(self.id_map.map_attr(self.fs.get_attr(ino).await?))
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &SecretString) -> FsResult<Option<FileAttr>> {
+        Ok(self
+            .fs
+            .find_by_name(parent, name)
+            .await?
+            .map(|attr| self.id_map.map_attr(attr)))
+    }
+
+    /// Deny access while the filesystem is locked, see [`EncryptedFs::lock`].
+    async fn check_unlocked(&self) -> Result<()> {
+        self.fs.ensure_unlocked().await.map_err(|err| {
+            debug!(err = %err);
+            EACCES.into()
+        })
+    }
+
+    /// Permissions for a new file or directory, from the mode asked for and the mount options.
+    const fn creation_mode(&self, mode: u32, is_dir: bool) -> u16 {
+        let forced = if is_dir {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        access::creation_mode(mode, forced, self.umask, self.suid_support)
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn create_nod(
+        &self,
+        parent: u64,
+        mut mode: u32,
+        req: &Request,
+        name: &OsStr,
+        read: bool,
+        write: bool,
+    ) -> std::result::Result<(u64, FileAttr), c_int> {
+        let parent_attr = match self.get_attr(parent).await {
+            Err(err) => {
+                error!(err = %err);
+                return Err(ENOENT);
+            }
+            Ok(parent_attr) => parent_attr,
+        };
+
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            req.uid,
+            req.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES);
+        }
+
+        if req.uid != 0 {
+            mode &= !(S_ISUID | S_ISGID);
+        }
+
+        let kind = as_file_kind(mode);
+        let mut attr = if kind == FileType::Directory {
+            dir_attr()
+        } else {
+            file_attr()
+        };
+        attr.perm = self.creation_mode(mode, kind == FileType::Directory);
+        attr.uid = self.id_map.stored_uid(req.uid);
+        attr.gid = self.id_map.stored_gid(creation_gid(&parent_attr, req.gid));
+
+        let (fh, attr) = self
+            .get_fs()
+            .create(
+                parent,
+                &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                attr,
+                read,
+                write,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::Excluded => EPERM,
+                    FsError::Io { source, .. } => {
+                        if source.to_string().to_lowercase().contains("too long") {
+                            ENAMETOOLONG
+                        } else {
+                            EIO
+                        }
+                    }
+                    _ => EIO,
+                }
+            })?;
+        Ok((fh, self.id_map.map_attr(attr)))
+    }
+}
+
+impl From<FileAttr> for fuse3::raw::prelude::FileAttr {
+    fn from(from: FileAttr) -> Self {
+        Self {
+            ino: from.ino,
+            size: from.size,
+            blocks: from.blocks,
+            atime: from.atime.into(),
+            mtime: from.mtime.into(),
+            ctime: from.ctime.into(),
+            kind: if from.kind == FileType::Directory {
+                fuse3::raw::prelude::FileType::Directory
+            } else {
+                fuse3::raw::prelude::FileType::RegularFile
+            },
+            perm: from.perm,
+            nlink: from.nlink,
+            uid: from.uid,
+            gid: from.gid,
+            rdev: from.rdev,
+            blksize: from.blksize,
+        }
+    }
+}
+
+impl Filesystem for EncryptedFsFuse3 {
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::INFO))]
+    async fn init(&self, req: Request) -> Result<ReplyInit> {
+        trace!("");
+
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(1024 * 1024).unwrap(),
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn destroy(&self, req: Request) {
+        trace!("");
+        // so the storage has everything before we exit
+        if let Err(err) = self.get_fs().sync_storage().await {
+            error!(err = %err, "syncing storage");
+        }
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::DEBUG), ret(level = Level::DEBUG))]
+    async fn lookup(&self, req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        ops::observe("lookup", parent, ops::args!(parent, name), async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            // if name.len() > MAX_NAME_LENGTH as usize {
+            //     warn!(name = %name.to_str().unwrap(), "name too long");
+            //     return Err(ENAMETOOLONG.into());
+            // }
+
+            match self.get_attr(parent).await {
+                Err(err) => {
+                    error!(parent, err = %err, "not found");
+                    return Err(ENOENT.into());
+                }
+                Ok(parent_attr) => {
+                    if !check_access(
+                        parent_attr.uid,
+                        parent_attr.gid,
+                        parent_attr.perm,
+                        req.uid,
+                        req.gid,
+                        libc::X_OK,
+                    ) {
+                        return Err(EACCES.into());
+                    }
+                }
+            }
+
+            let attr = match self
+                .find_by_name(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                Ok(Some(attr)) => attr,
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(ENOENT.into());
+                }
+                Ok(None) => ops::negative_entry_attr(),
+            };
+
+            Ok(ReplyEntry {
+                ttl: self.fs.entry_ttl(),
+                attr: attr.into(),
+                generation: 0,
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn forget(&self, req: Request, inode: Inode, nlookup: u64) {
+        trace!("");
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn getattr(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: Option<u64>,
+        flags: u32,
+    ) -> Result<ReplyAttr> {
+        ops::observe("getattr", inode, ops::args!(inode, fh), async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            match self.get_attr(inode).await {
+                Err(err) => {
+                    error!(err = %err);
+                    Err(ENOENT.into())
+                }
+                Ok(attr) => Ok(ReplyAttr {
+                    ttl: self.fs.attr_ttl(),
+                    attr: attr.into(),
+                }),
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_truncation)]
+    async fn setattr(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: Option<u64>,
+        set_attr: SetAttr,
+    ) -> Result<ReplyAttr> {
+        let event = ops::write_event("setattr", AuditTarget::Inode(inode), req.uid, req.pid);
+        let args = ops::args!(inode, fh, set_attr);
+        ops::audited(&self.fs, event, inode, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+            debug!("{set_attr:#?}");
+
+            let attr = self.get_attr(inode).await.map_err(|err| {
+                error!(err = %err);
+                Errno::from(ENOENT)
+            })?;
+
+            let mut set_attr2 = SetFileAttr::default();
+
+            if let Some(mode) = set_attr.mode {
+                debug!("chmod mode={mode:o}");
+                let mut set_attr2 = SetFileAttr::default();
+                if req.uid != 0 && req.uid != attr.uid {
+                    return Err(EPERM.into());
+                }
+                if req.uid != 0 && req.gid != attr.gid && !get_groups(req.pid).contains(&attr.gid) {
+                    // If SGID is set and the file belongs to a group that the caller is not part of
+                    // then the SGID bit is supposed to be cleared during chmod
+                    set_attr2 = set_attr2.with_perm((mode & !S_ISGID) as u16);
+                } else {
+                    set_attr2 = set_attr2.with_perm(mode as u16);
+                }
+                set_attr2 = set_attr2.with_atime(SystemTime::now());
+                self.get_fs()
+                    .set_attr(inode, set_attr2)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(EIO)
+                    })?;
+                return Ok(ReplyAttr {
+                    ttl: self.fs.attr_ttl(),
+                    attr: self
+                        .get_attr(inode)
+                        .await
+                        .map_err(|_err| Errno::from(ENOENT))?
+                        .into(),
+                });
+            }
+
+            if set_attr.uid.is_some() || set_attr.gid.is_some() {
+                debug!(?set_attr.uid, ?set_attr.gid, "chown");
+                let mut set_attr2 = SetFileAttr::default();
+                if let Some(gid) = set_attr2.gid {
+                    // Non-root users can only change gid to a group they're in
+                    if req.uid != 0 && !get_groups(req.pid).contains(&gid) {
+                        return Err(EPERM.into());
+                    }
+                }
+                if let Some(uid) = set_attr2.uid {
+                    if req.uid != 0
+                        // but no-op changes by the owner are not an error
+                        && !(uid == attr.uid && req.uid == attr.uid)
+                    {
+                        return Err(EPERM.into());
+                    }
+                }
+                // Only owner may change the group
+                if set_attr2.gid.is_some() && req.uid != 0 && req.uid != attr.uid {
+                    return Err(EPERM.into());
+                }
+
+                set_attr2 = set_attr2.with_perm(attr.perm);
+                if attr.perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
+                    // SUID & SGID are suppose to be cleared when chown'ing an executable file
+                    set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
+                }
+
+                if let Some(uid) = set_attr2.uid {
+                    set_attr2 = set_attr2.with_uid(self.id_map.stored_uid(uid));
+                    // Clear SETUID on owner change
+                    let perm = *set_attr2.perm.as_ref().unwrap();
+                    set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISUID as u16));
+                }
+                if let Some(gid) = set_attr2.gid {
+                    set_attr2 = set_attr2.with_gid(self.id_map.stored_gid(gid));
+                    // Clear SETGID unless user is root
+                    if req.uid != 0 {
+                        let perm = *set_attr2.perm.as_ref().unwrap();
+                        set_attr2 = set_attr2.with_perm(perm & !(libc::S_ISGID as u16));
+                    }
+                }
+                set_attr2 = set_attr2.with_atime(SystemTime::now());
+                self.get_fs()
+                    .set_attr(inode, set_attr2)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(EIO)
+                    })?;
+                return Ok(ReplyAttr {
+                    ttl: self.fs.attr_ttl(),
+                    attr: self
+                        .get_attr(inode)
+                        .await
+                        .map_err(|_err| Errno::from(ENOENT))?
+                        .into(),
+                });
+            }
+
+            if let Some(size) = set_attr.size {
+                debug!(size, "truncate");
+
+                self.get_fs().set_len(inode, size).await.map_err(|err| {
+                    error!(err = %err);
+                    match err {
+                        FsError::QuotaExceeded(_) => Errno::from(ENOSPC),
+                        _ => Errno::from(EIO),
+                    }
+                })?;
+                set_attr2 = set_attr2.with_size(size);
+
+                // Clear SETUID & SETGID on truncate
+                set_attr2 = set_attr2.with_perm(clear_suid_sgid(attr.perm));
+            }
+
+            if let Some(atime) = set_attr.atime {
+                debug!(?atime, "utimens");
+
+                if attr.uid != req.uid
+                    && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+                {
+                    return Err(EACCES.into());
+                }
+
+                set_attr2 = set_attr2.with_atime(system_time_from_timestamp(atime));
+                set_attr2 = set_attr2.with_ctime(SystemTime::now());
+            }
+
+            if let Some(mtime) = set_attr.mtime {
+                debug!(?mtime, "utimens");
+
+                if attr.uid != req.uid
+                    && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+                {
+                    return Err(EACCES.into());
+                }
+
+                set_attr2 = set_attr2.with_mtime(system_time_from_timestamp(mtime));
+                set_attr2 = set_attr2.with_ctime(SystemTime::now());
+            }
+
+            self.get_fs()
+                .set_attr(inode, set_attr2)
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(EIO)
+                })?;
+
+            Ok(ReplyAttr {
+                ttl: self.fs.attr_ttl(),
+                attr: self
+                    .get_attr(inode)
+                    .await
+                    .map_err(|_err| Errno::from(ENOENT))?
+                    .into(),
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn mknod(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<ReplyEntry> {
+        let event = ops::write_event("mknod", ops::entry(parent, name), req.uid, req.pid);
+        let args = ops::args!(parent, name, mode);
+        ops::audited(&self.fs, event, parent, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+            debug!("mode={mode:o}");
+
+            let file_type = mode & S_IFMT;
+
+            if file_type != S_IFREG
+                // && file_type != libc::S_IFLNK as u32
+                && file_type != S_IFDIR
+            {
+                // TODO
+                warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
+                return Err(libc::ENOSYS.into());
+            }
+
+            self.create_nod(parent, mode, &req, name, false, false)
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    Errno::from(err)
+                })
+                .map(|(_, attr)| {
+                    Ok(ReplyEntry {
+                        ttl: self.fs.entry_ttl(),
+                        attr: attr.into(),
+                        generation: 0,
+                    })
+                })?
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn mkdir(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<ReplyEntry> {
+        let event = ops::write_event("mkdir", ops::entry(parent, name), req.uid, req.pid);
+        let args = ops::args!(parent, name, mode);
+        ops::audited(&self.fs, event, parent, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+            debug!("mode={mode:o}");
+
+            let parent_attr = match self.get_attr(parent).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(ENOENT.into());
+                }
+                Ok(parent_attr) => parent_attr,
+            };
+
+            if !check_access(
+                parent_attr.uid,
+                parent_attr.gid,
+                parent_attr.perm,
+                req.uid,
+                req.gid,
+                libc::W_OK,
+            ) {
+                return Err(EACCES.into());
+            }
+
+            let mut attr = dir_attr();
+
+            let mut mode = mode;
+            if req.uid != 0 {
+                mode &= !(S_ISUID | S_ISGID);
+            }
+            #[allow(clippy::cast_possible_truncation)]
+            if parent_attr.perm & libc::S_ISGID as u16 != 0 {
+                mode |= S_ISGID;
+            }
+            attr.perm = self.creation_mode(mode, true);
+
+            attr.uid = self.id_map.stored_uid(req.uid);
+            attr.gid = self.id_map.stored_gid(creation_gid(&parent_attr, req.gid));
+
+            let (_, attr) = self
+                .get_fs()
+                .create(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                    attr,
+                    false,
+                    false,
+                )
+                .await
+                .map_err(|err| {
+                    error!(err = %err);
+                    match err {
+                        FsError::Excluded => Errno::from(EPERM),
+                        _ => Errno::from(ENOENT),
+                    }
+                })?;
+            Ok(ReplyEntry {
+                ttl: self.fs.entry_ttl(),
+                attr: self.id_map.map_attr(attr).into(),
+                generation: 0,
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn unlink(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let event = ops::write_event("unlink", ops::entry(parent, name), req.uid, req.pid);
+        let args = ops::args!(parent, name);
+        ops::audited(&self.fs, event, parent, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            let parent_attr = match self.get_attr(parent).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(ENOENT.into());
+                }
+                Ok(attr) => attr,
+            };
+
+            if !check_access(
+                parent_attr.uid,
+                parent_attr.gid,
+                parent_attr.perm,
+                req.uid,
+                req.gid,
+                libc::W_OK,
+            ) {
+                return Err(EACCES.into());
+            }
+
+            let attr = match self
+                .find_by_name(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                Ok(Some(attr)) => attr,
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(ENOENT.into());
+                }
+                _ => return Err(ENOENT.into()),
+            };
+
+            let uid = req.uid;
+            // "Sticky bit" handling
+            #[allow(clippy::cast_possible_truncation)]
+            if parent_attr.perm & libc::S_ISVTX as u16 != 0
+                && uid != 0
+                && uid != parent_attr.uid
+                && uid != attr.uid
+            {
+                return Err(EACCES.into());
+            }
+
+            if let Err(err) = self
+                .get_fs()
+                .remove_file(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                error!(err = %err);
+                return Err(ENOENT.into());
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn rmdir(&self, req: Request, parent: Inode, name: &OsStr) -> Result<()> {
+        let event = ops::write_event("rmdir", ops::entry(parent, name), req.uid, req.pid);
+        let args = ops::args!(parent, name);
+        ops::audited(&self.fs, event, parent, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            let Ok(parent_attr) = self.get_attr(parent).await else {
+                error!(parent, "not found");
+                return Err(ENOENT.into());
+            };
+
+            if !check_access(
+                parent_attr.uid,
+                parent_attr.gid,
+                parent_attr.perm,
+                req.uid,
+                req.gid,
+                libc::W_OK,
+            ) {
+                return Err(EACCES.into());
+            }
+
+            let Ok(Some(attr)) = self
+                .find_by_name(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            else {
+                error!(parent, name = name.to_str().unwrap());
+                return Err(ENOENT.into());
+            };
+
+            if attr.kind != FileType::Directory {
+                return Err(ENOTDIR.into());
+            }
+
+            let uid = req.uid;
+            // "Sticky bit" handling
+            #[allow(clippy::cast_possible_truncation)]
+            if parent_attr.perm & libc::S_ISVTX as u16 != 0
+                && uid != 0
+                && uid != parent_attr.uid
+                && uid != attr.uid
+            {
+                return Err(EACCES.into());
+            }
+
+            if let Err(err) = self
+                .get_fs()
+                .remove_dir(
+                    parent,
+                    &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                )
+                .await
+            {
+                error!(err = %err);
+                return match err {
+                    FsError::NotEmpty => Err(EISDIR.into()),
+                    _ => Err(EIO.into()),
+                };
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name, new_name), fields(name = name.to_str().unwrap(), new_name = new_name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn rename(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        new_parent: Inode,
+        new_name: &OsStr,
+    ) -> Result<()> {
+        let event = ops::write_event(
+            "rename",
+            AuditTarget::Rename(
+                parent,
+                SecretString::new(name.to_string_lossy().into_owned()),
+                new_parent,
+                SecretString::new(new_name.to_string_lossy().into_owned()),
+            ),
+            req.uid,
+            req.pid,
+        );
+        ops::audited(
+            &self.fs,
+            event,
+            parent,
+            ops::args!(parent, name, new_parent, new_name),
+            async {
+                trace!("");
+                self.check_unlocked().await?;
+
+                let Ok(Some(attr)) = self
+                    .find_by_name(
+                        parent,
+                        &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                    )
+                    .await
+                else {
+                    error!(
+                        parent,
+                        name = name.to_str().unwrap(),
+                        new_name = new_name.to_str().unwrap()
+                    );
+                    return Err(ENOENT.into());
+                };
+
+                let Ok(parent_attr) = self.get_attr(parent).await else {
+                    error!(parent, "parent not found");
+                    return Err(ENOENT.into());
+                };
+
+                if !check_access(
+                    parent_attr.uid,
+                    parent_attr.gid,
+                    parent_attr.perm,
+                    req.uid,
+                    req.gid,
+                    libc::W_OK,
+                ) {
+                    return Err(EACCES.into());
+                }
+
+                // "Sticky bit" handling
+                #[allow(clippy::cast_possible_truncation)]
+                if parent_attr.perm & libc::S_ISVTX as u16 != 0
+                    && req.uid != 0
+                    && req.uid != parent_attr.uid
+                    && req.uid != attr.uid
+                {
+                    return Err(EACCES.into());
+                }
+
+                let Ok(new_parent_attr) = self.get_attr(new_parent).await else {
+                    error!(new_parent, "not found");
+                    return Err(ENOENT.into());
+                };
+
+                if !check_access(
+                    new_parent_attr.uid,
+                    new_parent_attr.gid,
+                    new_parent_attr.perm,
+                    req.uid,
+                    req.gid,
+                    libc::W_OK,
+                ) {
+                    return Err(EACCES.into());
+                }
+
+                // "Sticky bit" handling in new_parent
+                #[allow(clippy::cast_possible_truncation)]
+                if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
+                    if let Ok(Some(new_attrs)) = self
+                        .find_by_name(
+                            new_parent,
+                            &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                        )
+                        .await
+                    {
+                        if req.uid != 0
+                            && req.uid != new_parent_attr.uid
+                            && req.uid != new_attrs.uid
+                        {
+                            return Err(EACCES.into());
+                        }
+                    }
+                }
+
+                // Only move an existing directory to a new parent, if we have write access to it,
+                // because that will change the ".." link in it
+                if attr.kind == FileType::Directory
+                    && parent != new_parent
+                    && !check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, libc::W_OK)
+                {
+                    return Err(EACCES.into());
+                }
+
+                match self
+                    .get_fs()
+                    .rename(
+                        parent,
+                        &SecretString::from_str(name.to_str().unwrap()).unwrap(),
+                        new_parent,
+                        &SecretString::from_str(new_name.to_str().unwrap()).unwrap(),
+                    )
+                    .await
+                {
+                    Ok(()) => Ok(()),
+                    Err(FsError::NotEmpty) => Err(ENOTEMPTY.into()),
+                    Err(FsError::Excluded) => Err(EPERM.into()),
+                    _ => Err(ENOENT.into()),
+                }
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn open(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        #[allow(clippy::cast_possible_wrap)]
+        let event = if ops::opens_for_read(flags as i32) {
+            ops::read_event("open", AuditTarget::Inode(inode), req.uid, req.pid)
+        } else {
+            ops::write_event("open", AuditTarget::Inode(inode), req.uid, req.pid)
+        };
+        let args = ops::args!(inode, flags);
+        ops::audited(&self.fs, event, inode, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            #[allow(clippy::cast_possible_wrap)]
+            let (access_mask, read, write) = match flags as i32 & libc::O_ACCMODE {
+                libc::O_RDONLY => {
+                    // Behavior is undefined, but most filesystems return EACCES
+                    if flags & libc::O_TRUNC as u32 != 0 {
+                        return Err(EACCES.into());
+                    }
+                    if cfg!(target_os = "linux") && flags & FMODE_EXEC as u32 != 0 {
+                        // Open is from internal exec syscall
+                        (libc::X_OK, true, false)
+                    } else {
+                        (libc::R_OK, true, false)
+                    }
+                }
+                libc::O_WRONLY => (libc::W_OK, false, true),
+                libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+                // Exactly one access mode flag must be specified
+                _ => {
+                    return Err(libc::EINVAL.into());
+                }
+            };
+
+            // let _create = flags & libc::O_CREAT as u32 != 0;
+            let truncate = flags & libc::O_TRUNC as u32 != 0;
+            // let _append = flags & libc::O_APPEND as u32 != 0;
+
+            let attr = self.get_attr(inode).await.map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
+            //
+            if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+                if truncate {
+                    self.get_fs().set_len(attr.ino, 0).await.map_err(|err| {
+                        error!(err = %err);
+                        EIO
+                    })?;
+                }
+                let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
+                let fh = self
+                    .get_fs()
+                    .open(inode, read, write)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        EIO
+                    })?;
+                Ok(ReplyOpen {
+                    fh,
+                    flags: open_flags,
+                })
+            } else {
+                Err(EACCES.into())
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO))]
+    async fn read(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let event = ops::read_event("read", AuditTarget::Inode(inode), req.uid, req.pid);
+        let args = ops::args!(inode, fh, offset, size);
+        ops::audited(&self.fs, event, inode, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            let mut buf = vec![0; size as usize];
+            match self.get_fs().read(inode, offset, &mut buf, fh).await {
+                Err(err) => {
+                    error!(err = %err);
+                    Err(EIO.into())
+                }
+                Ok(len) => Ok(ReplyData {
+                    data: Bytes::copy_from_slice(buf[..len].as_ref()),
+                }),
+            }
+        })
+        .await
+    }
+
+    #[instrument(skip(self, data), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn write(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        write_flags: u32,
+        flags: u32,
+    ) -> Result<ReplyWrite> {
+        let event = ops::write_event("write", AuditTarget::Inode(inode), req.uid, req.pid);
+        ops::audited(
+            &self.fs,
+            event,
+            inode,
+            ops::args!(inode, fh, offset, data.len(), flags),
+            async {
+                trace!("");
+                self.check_unlocked().await?;
+                debug!(size = data.len());
+
+                // the offset is from the size the kernel has, which is stale if it was changed meanwhile
+                #[allow(clippy::cast_possible_wrap)]
+                let res = if flags as i32 & libc::O_APPEND != 0 {
+                    self.get_fs().append(inode, data, fh).await
+                } else {
+                    self.get_fs().write(inode, offset, data, fh).await
+                };
+                let len = res.map_err(|err| {
+                    error!(err = %err);
+                    match err {
+                        FsError::MaxFilesizeExceeded(_) => EFBIG,
+                        FsError::QuotaExceeded(_) => ENOSPC,
+                        _ => EIO,
+                    }
+                })?;
+
+                Ok(ReplyWrite {
+                    #[allow(clippy::cast_possible_truncation)]
+                    written: len as u32,
+                })
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn statfs(&self, req: Request, inode: u64) -> Result<ReplyStatFs> {
+        ops::observe("statfs", inode, ops::args!(inode), async {
+            trace!("");
+            let Some(quota) = self.get_fs().quota() else {
+                warn!("implementation is a stub");
+                return Ok(STATFS);
+            };
+            let bsize = u64::from(STATFS.bsize);
+            let free = quota.max_size.saturating_sub(quota.used) / bsize;
+            Ok(ReplyStatFs {
+                blocks: quota.max_size / bsize,
+                bfree: free,
+                bavail: free,
+                frsize: STATFS.bsize,
+                ..STATFS
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn release(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh: u64,
+        flags: u32,
+        lock_owner: u64,
+        flush: bool,
+    ) -> Result<()> {
+        ops::observe(
+            "release",
+            inode,
+            ops::args!(inode, fh, flags, flush),
+            async {
+                trace!("");
+
+                let fs = self.get_fs();
+
+                if flush {
+                    if let Err(err) = fs.flush(fh).await {
+                        error!(err = %err);
+                        return Err(EIO.into());
+                    }
+                }
+
+                let is_write_handle = fs.is_write_handle(fh);
+
+                if let Err(err) = fs.release(fh).await {
+                    error!(err = %err);
+                    return Err(EIO.into());
+                }
+
+                if is_write_handle.await {
+                    let attr = fs.get_attr(inode).await.map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(ENOENT)
+                    })?;
+                    let mut set_attr = SetFileAttr::default();
+
+                    // XXX: In theory we should only need to do this when WRITE_KILL_PRIV is set for 7.31+
+                    // However, xfstests fail in that case
+                    set_attr = set_attr.with_perm(clear_suid_sgid(attr.perm));
+                    fs.set_attr(inode, set_attr).await.map_err(|err| {
+                        error!(err = %err, "replace attr");
+                        Errno::from(EIO)
+                    })?;
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn flush(&self, req: Request, inode: Inode, fh: u64, lock_owner: u64) -> Result<()> {
+        ops::observe("flush", inode, ops::args!(inode, fh), async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            if let Err(err) = self.get_fs().flush(fh).await {
+                error!(err = %err, fh);
+                return Err(EIO.into());
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    #[allow(clippy::cast_possible_wrap)]
+    async fn opendir(&self, req: Request, inode: Inode, flags: u32) -> Result<ReplyOpen> {
+        ops::observe("opendir", inode, ops::args!(inode, flags), async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            let (access_mask, _read, _write) = match flags as i32 & libc::O_ACCMODE {
+                libc::O_RDONLY => {
+                    // Behavior is undefined, but most filesystems return EACCES
+                    if flags & libc::O_TRUNC as u32 != 0 {
+                        return Err(EACCES.into());
+                    }
+                    (libc::R_OK, true, false)
+                }
+                libc::O_WRONLY => (libc::W_OK, false, true),
+                libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+                // Exactly one access mode flag must be specified
+                _ => {
+                    return Err(libc::EINVAL.into());
+                }
+            };
+
+            let attr = match self.get_attr(inode).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(ENOENT.into());
+                }
+                Ok(attr) => attr,
+            };
+
+            if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, access_mask) {
+                let open_flags = if self.direct_io { FOPEN_DIRECT_IO } else { 0 };
+                Ok(ReplyOpen {
+                    fh: 0, // we don't use handles for directories
+                    flags: open_flags,
+                })
+            } else {
+                Err(EACCES.into())
+            }
+        })
+        .await
+    }
+
+    type DirEntryStream<'a>
+        = Iter<Skip<DirectoryEntryIterator>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdir(
+        &self,
+        req: Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'_>>> {
+        let event = ops::read_event("readdir", AuditTarget::Inode(inode), req.uid, req.pid);
+        let args = ops::args!(inode, fh, offset);
+        ops::audited(&self.fs, event, inode, args, async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            #[allow(clippy::cast_sign_loss)]
+            let iter = match self.get_fs().read_dir(inode).await {
+                Err(err) => {
+                    error!(err = %err);
+                    return Err(EIO.into());
+                }
+                Ok(iter) => iter,
+            };
+            let iter = DirectoryEntryIterator(iter, 0);
+
+            Ok(ReplyDirectory {
+                #[allow(clippy::cast_possible_truncation)]
+                #[allow(clippy::cast_sign_loss)]
+                entries: stream::iter(iter.skip(offset as usize)),
+            })
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn releasedir(&self, req: Request, inode: Inode, fh: u64, flags: u32) -> Result<()> {
+        ops::observe("releasedir", inode, ops::args!(inode, fh), async {
+            trace!("");
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
+        ops::observe("access", inode, ops::args!(inode, mask), async {
+            trace!("");
+            self.check_unlocked().await?;
+
+            self.get_attr(inode).await.map_or_else(
+                |_| Err(ENOENT.into()),
+                |attr| {
+                    #[allow(clippy::cast_possible_wrap)]
+                    if check_access(attr.uid, attr.gid, attr.perm, req.uid, req.gid, mask as i32) {
+                        Ok(())
+                    } else {
+                        Err(EACCES.into())
+                    }
+                },
+            )
+        })
+        .await
+    }
+
+    #[instrument(skip(self, name), fields(name = name.to_str().unwrap()), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn create(
+        &self,
+        req: Request,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+    ) -> Result<ReplyCreated> {
+        let event = ops::write_event("create", ops::entry(parent, name), req.uid, req.pid);
+        ops::audited(
+            &self.fs,
+            event,
+            parent,
+            ops::args!(parent, name, mode, flags),
+            async {
+                trace!("");
+                self.check_unlocked().await?;
+
+                #[allow(clippy::cast_possible_wrap)]
+                let (read, write) = match flags as i32 & libc::O_ACCMODE {
+                    libc::O_RDONLY => (true, false),
+                    libc::O_WRONLY => (false, true),
+                    libc::O_RDWR => (true, true),
+                    // Exactly one access mode flag must be specified
+                    _ => {
+                        return Err(libc::EINVAL.into());
+                    }
+                };
+
+                let (handle, attr) = self
+                    .create_nod(parent, mode, &req, name, read, write)
+                    .await
+                    .map_err(|err| {
+                        error!(err = %err);
+                        Errno::from(ENOENT)
+                    })?;
+                Ok(ReplyCreated {
+                    ttl: self.fs.entry_ttl(),
+                    attr: attr.into(),
+                    generation: 0,
+                    fh: handle,
+                    flags: 0,
+                })
+            },
+        )
+        .await
+    }
+
+    type DirEntryPlusStream<'a>
+        = Iter<Skip<DirectoryEntryPlusIterator>>
+    where
+        Self: 'a;
+
+    #[instrument(skip(self), err(level = Level::DEBUG))]
+    async fn readdirplus(
+        &self,
+        req: Request,
+        parent: u64,
+        fh: u64,
+        offset: u64,
+        lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'_>>> {
+        let event = ops::read_event("readdirplus", AuditTarget::Inode(parent), req.uid, req.pid);
+        ops::audited(
+            &self.fs,
+            event,
+            parent,
+            ops::args!(parent, fh, offset),
+            async {
+                trace!("");
+                self.check_unlocked().await?;
+
+                #[allow(clippy::cast_sign_loss)]
+                let iter = match self.get_fs().read_dir_plus(parent).await {
+                    Err(err) => {
+                        error!(err = %err);
+                        return Err(EIO.into());
+                    }
+                    Ok(iter) => iter,
+                };
+                let iter = DirectoryEntryPlusIterator(
+                    iter,
+                    0,
+                    self.id_map.clone(),
+                    self.fs.entry_ttl(),
+                    self.fs.attr_ttl(),
+                );
+
+                Ok(ReplyDirectoryPlus {
+                    #[allow(clippy::cast_possible_truncation)]
+                    entries: stream::iter(iter.skip(offset as usize)),
+                })
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self), err(level = Level::INFO), ret(level = Level::DEBUG))]
+    async fn copy_file_range(
+        &self,
+        req: Request,
+        inode: Inode,
+        fh_in: u64,
+        off_in: u64,
+        inode_out: Inode,
+        fh_out: u64,
+        off_out: u64,
+        length: u64,
+        flags: u64,
+    ) -> Result<ReplyCopyFileRange> {
+        let event = ops::write_event(
+            "copy_file_range",
+            AuditTarget::Inode(inode_out),
+            req.uid,
+            req.pid,
+        );
+        ops::audited(
+            &self.fs,
+            event,
+            inode,
+            ops::args!(inode, fh_in, off_in, inode_out, fh_out, off_out, length),
+            async {
+                trace!("");
+                self.check_unlocked().await?;
+
+                #[allow(clippy::cast_possible_truncation)]
+                match self
+                    .get_fs()
+                    .copy_file_range(
+                        inode,
+                        off_in,
+                        inode_out,
+                        off_out,
+                        length as usize,
+                        fh_in,
+                        fh_out,
+                    )
+                    .await
+                {
+                    Err(FsError::QuotaExceeded(_)) => Err(ENOSPC.into()),
+                    Err(err) => {
+                        error!(err = %err);
+                        Err(EIO.into())
+                    }
+                    Ok(len) => Ok(ReplyCopyFileRange { copied: len as u64 }),
+                }
+            },
+        )
+        .await
+    }
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn system_time_from_timestamp(t: Timestamp) -> SystemTime {
+    UNIX_EPOCH + Duration::new(t.sec as u64, t.nsec)
+}
+
+/// Mounts with FUSE, using `fuse3`, on Linux and FreeBSD with `fusefs`.
+pub struct Fuse3Backend;
+
+#[async_trait]
+impl MountBackend for Fuse3Backend {
+    fn name(&self) -> &'static str {
+        "fuse3"
+    }
+
+    async fn mount(&self, config: MountConfig) -> FsResult<mount::MountHandle> {
+        let mountpoint = config.mountpoint.clone();
+        let (handle, fs) = mount_fuse(
+            config.mountpoint,
+            config.data_dir,
+            config.password_provider,
+            config.cipher,
+            config.allow_root,
+            config.allow_other,
+            config.direct_io,
+            config.suid_support,
+            config.options,
+            &config.flags,
+        )
+        .await?;
+        Ok(mount::MountHandle::new(
+            mountpoint,
+            fs,
+            MountHandleInnerImpl { inner: handle },
+        ))
+    }
+}
+
+struct MountHandleInnerImpl {
+    inner: MountHandle,
+}
+
+impl Future for MountHandleInnerImpl {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.poll_unpin(cx)
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MountHandleInnerImpl {
+    async fn unmount(&mut self) -> io::Result<()> {
+        // its own stops serving first, then the OS can't flush to us and it fails when busy
+        Ok(())
+    }
+}
+
+#[instrument(skip(password_provider))]
+async fn mount_fuse(
+    mountpoint: PathBuf,
+    data_dir: PathBuf,
+    password_provider: Box<dyn PasswordProvider>,
+    cipher: Cipher,
+    allow_root: bool,
+    allow_other: bool,
+    direct_io: bool,
+    suid_support: bool,
+    options: FsOptions,
+    flags: &MountFlags,
+) -> FsResult<(MountHandle, Arc<EncryptedFs>)> {
+    let mut mount_options = &mut MountOptions::default();
+    {
+        #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+        unsafe {
+            mount_options = mount_options.uid(libc::getuid()).gid(libc::getgid());
+        }
+    }
+    let mut mount_options = mount_options
+        .read_only(options.read_only)
+        // so we get the mode before the umask of the caller is applied, and apply ours
+        .dont_mask(options.umask.is_some())
+        .allow_root(allow_root)
+        .allow_other(allow_other)
+        .fs_name(flags.fsname())
+        .clone();
+    // it has no setters for them, `fusermount3` takes them with the others
+    let mut custom = vec![];
+    if flags.noexec {
+        custom.push("noexec".to_string());
+    }
+    if flags.nosuid {
+        custom.push("nosuid".to_string());
+    }
+    if flags.nodev {
+        custom.push("nodev".to_string());
+    }
+    if let Some(subtype) = &flags.subtype {
+        custom.push(format!("subtype={subtype}"));
+    }
+    if !custom.is_empty() {
+        mount_options.custom_options(custom.join(","));
+    }
+    let mount_path = OsStr::new(mountpoint.to_str().unwrap());
+
+    info!("Checking password and mounting FUSE filesystem");
+    let fs = EncryptedFsFuse3::new(
+        data_dir,
+        password_provider,
+        cipher,
+        direct_io,
+        suid_support,
+        options,
+    )
+    .await?;
+    let encryptedfs = fs.get_fs();
+    let handle = Session::new(mount_options)
+        .mount_with_unprivileged(fs, mount_path)
+        .await?;
+    Ok((handle, encryptedfs))
+}
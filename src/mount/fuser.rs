@@ -0,0 +1,1473 @@
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_DONT_MASK};
+#[cfg(target_os = "linux")]
+use fuser::consts::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO};
+use fuser::{
+    Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    Session, SessionUnmounter, TimeOrNow,
+};
+use futures_util::FutureExt;
+use libc::{
+    EACCES, EEXIST, EFBIG, EINVAL, EIO, ENAMETOOLONG, ENOENT, ENOSPC, ENOSYS, ENOTDIR, ENOTEMPTY,
+    EPERM, EROFS,
+};
+use secrecy::{ExposeSecret, SecretString};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::crypto::Cipher;
+use crate::encryptedfs::{
+    AuditEvent, AuditTarget, EncryptedFs, FileAttr, FileType, FsError, FsOptions, FsResult, IdMap,
+    PasswordProvider, SetFileAttr,
+};
+use crate::mount;
+use crate::mount::access;
+use crate::mount::access::{
+    as_file_kind, check_access, clear_suid_sgid, creation_gid, dir_attr, file_attr, get_groups,
+    S_IFDIR, S_IFMT, S_IFREG, S_ISGID, S_ISUID,
+};
+use crate::mount::ops;
+use crate::mount::{MountBackend, MountConfig, MountHandleInner};
+
+const BLOCK_SIZE: u32 = 4096;
+
+/// Set by the kernel on the open from an `exec`, only on Linux, it's another flag on other platforms.
+const FMODE_EXEC: i32 = 0x20;
+
+/// Who made the request, taken out of [`Request`] as that can't be moved to the task handling it.
+#[derive(Debug, Clone, Copy)]
+struct Caller {
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl Caller {
+    /// An operation by it that changes `target`, see [`ops::audited`].
+    const fn write_event(self, op: &'static str, target: AuditTarget) -> AuditEvent {
+        ops::write_event(op, target, self.uid, self.pid)
+    }
+
+    /// An operation by it that only reads `target`.
+    const fn read_event(self, op: &'static str, target: AuditTarget) -> AuditEvent {
+        ops::read_event(op, target, self.uid, self.pid)
+    }
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+}
+
+/// What `setattr` asked to change, the times set to now are resolved already.
+#[derive(Debug, Default)]
+struct SetAttr {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+    // only sent by macOS
+    crtime: Option<SystemTime>,
+    flags: Option<u32>,
+}
+
+/// The operations, like in the fuse3 backend but as plain async fns, [`FuserFilesystem`] runs them on the runtime
+/// and replies.
+struct EncryptedFsFuser {
+    fs: Arc<EncryptedFs>,
+    direct_io: bool,
+    suid_support: bool,
+    id_map: IdMap,
+    umask: Option<u32>,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+}
+
+impl EncryptedFsFuser {
+    async fn new(
+        data_dir: PathBuf,
+        password_provider: Box<dyn PasswordProvider>,
+        cipher: Cipher,
+        direct_io: bool,
+        suid_support: bool,
+        options: FsOptions,
+    ) -> FsResult<Self> {
+        Ok(Self {
+            id_map: options.id_map.clone(),
+            umask: options.umask,
+            file_mode: options.file_mode,
+            dir_mode: options.dir_mode,
+            fs: EncryptedFs::new(data_dir, password_provider, cipher, options).await?,
+            direct_io,
+            suid_support,
+        })
+    }
+
+    /// Attributes with the owner as shown, see [`IdMap`].
+    async fn get_attr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        self.fs
+            .get_attr(ino)
+            .await
+            .map(|attr| self.id_map.map_attr(attr))
+            .map_err(|err| {
+                error!(ino, err = %err);
+                ENOENT
+            })
+    }
+
+    async fn find_by_name(&self, parent: u64, name: &SecretString) -> Result<FileAttr, c_int> {
+        match self.fs.find_by_name(parent, name).await {
+            Ok(Some(attr)) => Ok(self.id_map.map_attr(attr)),
+            Ok(None) => Err(ENOENT),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    /// Deny access while the filesystem is locked, see [`EncryptedFs::lock`].
+    async fn check_unlocked(&self) -> Result<(), c_int> {
+        self.fs.ensure_unlocked().await.map_err(|err| {
+            debug!(err = %err);
+            EACCES
+        })
+    }
+
+    /// The parent dir, if the caller can add or remove entries in it.
+    async fn writable_dir(&self, caller: Caller, ino: u64) -> Result<FileAttr, c_int> {
+        let attr = self.get_attr(ino).await?;
+        if !check_access(
+            attr.uid,
+            attr.gid,
+            attr.perm,
+            caller.uid,
+            caller.gid,
+            libc::W_OK,
+        ) {
+            return Err(EACCES);
+        }
+        Ok(attr)
+    }
+
+    /// Permissions for a new file or directory, from the mode asked for and the mount options.
+    const fn creation_mode(&self, mode: u32, is_dir: bool) -> u16 {
+        let forced = if is_dir {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        access::creation_mode(mode, forced, self.umask, self.suid_support)
+    }
+
+    /// [`None`] if there is no such entry.
+    async fn lookup(
+        &self,
+        caller: Caller,
+        parent: u64,
+        name: SecretString,
+    ) -> Result<Option<FileAttr>, c_int> {
+        self.check_unlocked().await?;
+        let parent_attr = self.get_attr(parent).await?;
+        if !check_access(
+            parent_attr.uid,
+            parent_attr.gid,
+            parent_attr.perm,
+            caller.uid,
+            caller.gid,
+            libc::X_OK,
+        ) {
+            return Err(EACCES);
+        }
+        match self.fs.find_by_name(parent, &name).await {
+            Ok(attr) => Ok(attr.map(|attr| self.id_map.map_attr(attr))),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    async fn getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        self.check_unlocked().await?;
+        self.get_attr(ino).await
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::too_many_lines)]
+    async fn setattr(&self, caller: Caller, ino: u64, set: SetAttr) -> Result<FileAttr, c_int> {
+        self.check_unlocked().await?;
+        debug!("{set:#?}");
+        let attr = self.get_attr(ino).await?;
+        let mut set_attr = SetFileAttr::default();
+
+        if let Some(mode) = set.mode {
+            debug!("chmod mode={mode:o}");
+            if caller.uid != 0 && caller.uid != attr.uid {
+                return Err(EPERM);
+            }
+            let mode = if caller.uid != 0
+                && caller.gid != attr.gid
+                && !get_groups(caller.pid).contains(&attr.gid)
+            {
+                // If SGID is set and the file belongs to a group that the caller is not part of
+                // then the SGID bit is supposed to be cleared during chmod
+                mode & !S_ISGID
+            } else {
+                mode
+            };
+            set_attr = set_attr
+                .with_perm(mode as u16)
+                .with_ctime(SystemTime::now());
+        }
+
+        if set.uid.is_some() || set.gid.is_some() {
+            debug!(?set.uid, ?set.gid, "chown");
+            if let Some(gid) = set.gid {
+                // Non-root users can only change gid to a group they're in, and only the owner
+                if caller.uid != 0
+                    && (caller.uid != attr.uid
+                        || (gid != caller.gid && !get_groups(caller.pid).contains(&gid)))
+                {
+                    return Err(EPERM);
+                }
+            }
+            if let Some(uid) = set.uid {
+                // but no-op changes by the owner are not an error
+                if caller.uid != 0 && !(uid == attr.uid && caller.uid == attr.uid) {
+                    return Err(EPERM);
+                }
+            }
+            let mut perm = attr.perm;
+            if perm & (libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH) as u16 != 0 {
+                // SUID & SGID are suppose to be cleared when chown'ing an executable file
+                perm = clear_suid_sgid(perm);
+            }
+            if let Some(uid) = set.uid {
+                set_attr = set_attr.with_uid(self.id_map.stored_uid(uid));
+                // Clear SETUID on owner change
+                perm &= !(libc::S_ISUID as u16);
+            }
+            if let Some(gid) = set.gid {
+                set_attr = set_attr.with_gid(self.id_map.stored_gid(gid));
+                // Clear SETGID unless user is root
+                if caller.uid != 0 {
+                    perm &= !(libc::S_ISGID as u16);
+                }
+            }
+            set_attr = set_attr.with_perm(perm).with_ctime(SystemTime::now());
+        }
+
+        if let Some(size) = set.size {
+            debug!(size, "truncate");
+            self.fs.set_len(ino, size).await.map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::QuotaExceeded(_) => ENOSPC,
+                    FsError::ReadOnly => EROFS,
+                    _ => EIO,
+                }
+            })?;
+            // Clear SETUID & SETGID on truncate
+            set_attr = set_attr
+                .with_size(size)
+                .with_perm(clear_suid_sgid(set_attr.perm.unwrap_or(attr.perm)));
+        }
+
+        if set.atime.is_some() || set.mtime.is_some() || set.crtime.is_some() {
+            debug!(?set.atime, ?set.mtime, ?set.crtime, "utimens");
+            if attr.uid != caller.uid
+                && !check_access(
+                    attr.uid,
+                    attr.gid,
+                    attr.perm,
+                    caller.uid,
+                    caller.gid,
+                    libc::W_OK,
+                )
+            {
+                return Err(EACCES);
+            }
+            if let Some(atime) = set.atime {
+                set_attr = set_attr.with_atime(atime);
+            }
+            if let Some(mtime) = set.mtime {
+                set_attr = set_attr.with_mtime(mtime);
+            }
+            if let Some(crtime) = set.crtime {
+                set_attr = set_attr.with_crtime(crtime);
+            }
+            set_attr = set_attr.with_ctime(SystemTime::now());
+        }
+
+        if let Some(flags) = set.flags {
+            // chflags, like UF_HIDDEN set by the Finder
+            debug!(flags, "chflags");
+            if caller.uid != 0 && caller.uid != attr.uid {
+                return Err(EPERM);
+            }
+            set_attr = set_attr.with_flags(flags);
+        }
+
+        self.fs.set_attr(ino, set_attr).await.map_err(|err| {
+            error!(err = %err);
+            EIO
+        })?;
+        self.get_attr(ino).await
+    }
+
+    #[instrument(skip(self, name), err(level = tracing::Level::INFO))]
+    async fn create_nod(
+        &self,
+        caller: Caller,
+        parent: u64,
+        name: SecretString,
+        mut mode: u32,
+        read: bool,
+        write: bool,
+    ) -> Result<(u64, FileAttr), c_int> {
+        self.check_unlocked().await?;
+        debug!("mode={mode:o}");
+        let file_type = mode & S_IFMT;
+        if file_type != S_IFREG && file_type != S_IFDIR {
+            warn!("implementation is incomplete. Only supports regular files and directories. Got mode={mode:o}");
+            return Err(ENOSYS);
+        }
+        let parent_attr = self.writable_dir(caller, parent).await?;
+
+        if caller.uid != 0 {
+            mode &= !(S_ISUID | S_ISGID);
+        }
+        let kind = as_file_kind(mode);
+        #[allow(clippy::cast_possible_truncation)]
+        if kind == FileType::Directory && parent_attr.perm & libc::S_ISGID as u16 != 0 {
+            mode |= S_ISGID;
+        }
+        let mut attr = if kind == FileType::Directory {
+            dir_attr()
+        } else {
+            file_attr()
+        };
+        attr.perm = self.creation_mode(mode, kind == FileType::Directory);
+        attr.uid = self.id_map.stored_uid(caller.uid);
+        attr.gid = self
+            .id_map
+            .stored_gid(creation_gid(&parent_attr, caller.gid));
+
+        let (fh, attr) = self
+            .fs
+            .create(parent, &name, attr, read, write)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::AlreadyExists => EEXIST,
+                    FsError::Excluded => EPERM,
+                    FsError::ReadOnly => EROFS,
+                    FsError::QuotaExceeded(_) => ENOSPC,
+                    FsError::Io { source, .. }
+                        if source.to_string().to_lowercase().contains("too long") =>
+                    {
+                        ENAMETOOLONG
+                    }
+                    _ => EIO,
+                }
+            })?;
+        Ok((fh, self.id_map.map_attr(attr)))
+    }
+
+    async fn remove(
+        &self,
+        caller: Caller,
+        parent: u64,
+        name: SecretString,
+        dir: bool,
+    ) -> Result<(), c_int> {
+        self.check_unlocked().await?;
+        let parent_attr = self.writable_dir(caller, parent).await?;
+        let attr = self.find_by_name(parent, &name).await?;
+        if dir && attr.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+        // "Sticky bit" handling
+        #[allow(clippy::cast_possible_truncation)]
+        if parent_attr.perm & libc::S_ISVTX as u16 != 0
+            && caller.uid != 0
+            && caller.uid != parent_attr.uid
+            && caller.uid != attr.uid
+        {
+            return Err(EACCES);
+        }
+        let res = if dir {
+            self.fs.remove_dir(parent, &name).await
+        } else {
+            self.fs.remove_file(parent, &name).await
+        };
+        res.map_err(|err| {
+            error!(err = %err);
+            match err {
+                FsError::NotEmpty => ENOTEMPTY,
+                FsError::ReadOnly => EROFS,
+                _ => EIO,
+            }
+        })
+    }
+
+    async fn rename(
+        &self,
+        caller: Caller,
+        parent: u64,
+        name: SecretString,
+        new_parent: u64,
+        new_name: SecretString,
+    ) -> Result<(), c_int> {
+        self.check_unlocked().await?;
+        let attr = self.find_by_name(parent, &name).await?;
+        let parent_attr = self.writable_dir(caller, parent).await?;
+        // "Sticky bit" handling
+        #[allow(clippy::cast_possible_truncation)]
+        if parent_attr.perm & libc::S_ISVTX as u16 != 0
+            && caller.uid != 0
+            && caller.uid != parent_attr.uid
+            && caller.uid != attr.uid
+        {
+            return Err(EACCES);
+        }
+        let new_parent_attr = self.writable_dir(caller, new_parent).await?;
+        // "Sticky bit" handling in new_parent
+        #[allow(clippy::cast_possible_truncation)]
+        if new_parent_attr.perm & libc::S_ISVTX as u16 != 0 {
+            if let Ok(new_attr) = self.find_by_name(new_parent, &new_name).await {
+                if caller.uid != 0
+                    && caller.uid != new_parent_attr.uid
+                    && caller.uid != new_attr.uid
+                {
+                    return Err(EACCES);
+                }
+            }
+        }
+        // Only move an existing directory to a new parent, if we have write access to it,
+        // because that will change the ".." link in it
+        if attr.kind == FileType::Directory
+            && parent != new_parent
+            && !check_access(
+                attr.uid,
+                attr.gid,
+                attr.perm,
+                caller.uid,
+                caller.gid,
+                libc::W_OK,
+            )
+        {
+            return Err(EACCES);
+        }
+        match self.fs.rename(parent, &name, new_parent, &new_name).await {
+            Ok(()) => Ok(()),
+            Err(FsError::NotEmpty) => Err(ENOTEMPTY),
+            Err(FsError::Excluded) => Err(EPERM),
+            Err(FsError::ReadOnly) => Err(EROFS),
+            Err(err) => {
+                error!(err = %err);
+                Err(ENOENT)
+            }
+        }
+    }
+
+    async fn open(&self, caller: Caller, ino: u64, flags: i32) -> Result<(u64, u32), c_int> {
+        self.check_unlocked().await?;
+        let (access_mask, read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => {
+                // Behavior is undefined, but most filesystems return EACCES
+                if flags & libc::O_TRUNC != 0 {
+                    return Err(EACCES);
+                }
+                if cfg!(target_os = "linux") && flags & FMODE_EXEC != 0 {
+                    // Open is from internal exec syscall
+                    (libc::X_OK, true, false)
+                } else {
+                    (libc::R_OK, true, false)
+                }
+            }
+            libc::O_WRONLY => (libc::W_OK, false, true),
+            libc::O_RDWR => (libc::R_OK | libc::W_OK, true, true),
+            // Exactly one access mode flag must be specified
+            _ => return Err(EINVAL),
+        };
+        let attr = self.get_attr(ino).await?;
+        if !check_access(
+            attr.uid,
+            attr.gid,
+            attr.perm,
+            caller.uid,
+            caller.gid,
+            access_mask,
+        ) {
+            return Err(EACCES);
+        }
+        if flags & libc::O_TRUNC != 0 {
+            self.fs.set_len(ino, 0).await.map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
+        }
+        let fh = self.fs.open(ino, read, write).await.map_err(|err| {
+            error!(err = %err);
+            match err {
+                FsError::ReadOnly => EROFS,
+                _ => EIO,
+            }
+        })?;
+        Ok((fh, self.open_flags()))
+    }
+
+    const fn open_flags(&self) -> u32 {
+        if self.direct_io {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
+
+    async fn read(&self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.check_unlocked().await?;
+        let mut buf = vec![0; size as usize];
+        let len = self
+            .fs
+            .read(ino, offset, &mut buf, fh)
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    async fn write(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        data: Vec<u8>,
+        append: bool,
+    ) -> Result<u32, c_int> {
+        self.check_unlocked().await?;
+        debug!(size = data.len());
+        // the offset is from the size the kernel has, which is stale if it was changed meanwhile
+        let res = if append {
+            self.fs.append(ino, &data, fh).await
+        } else {
+            self.fs.write(ino, offset, &data, fh).await
+        };
+        let len = res.map_err(|err| {
+            error!(err = %err);
+            match err {
+                FsError::MaxFilesizeExceeded(_) => EFBIG,
+                FsError::QuotaExceeded(_) => ENOSPC,
+                FsError::ReadOnly => EROFS,
+                _ => EIO,
+            }
+        })?;
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(len as u32)
+    }
+
+    async fn flush(&self, fh: u64) -> Result<(), c_int> {
+        self.fs.flush(fh).await.map_err(|err| {
+            error!(err = %err, fh);
+            EIO
+        })
+    }
+
+    async fn release(&self, ino: u64, fh: u64, flush: bool) -> Result<(), c_int> {
+        if flush {
+            self.flush(fh).await?;
+        }
+        let is_write_handle = self.fs.is_write_handle(fh).await;
+        self.fs.release(fh).await.map_err(|err| {
+            error!(err = %err);
+            EIO
+        })?;
+        if is_write_handle {
+            let attr = self.fs.get_attr(ino).await.map_err(|err| {
+                error!(err = %err);
+                ENOENT
+            })?;
+            let set_attr = SetFileAttr::default().with_perm(clear_suid_sgid(attr.perm));
+            self.fs.set_attr(ino, set_attr).await.map_err(|err| {
+                error!(err = %err, "replace attr");
+                EIO
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn opendir(&self, caller: Caller, ino: u64, flags: i32) -> Result<(u64, u32), c_int> {
+        self.check_unlocked().await?;
+        let access_mask = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => libc::R_OK,
+            libc::O_WRONLY => libc::W_OK,
+            libc::O_RDWR => libc::R_OK | libc::W_OK,
+            _ => return Err(EINVAL),
+        };
+        let attr = self.get_attr(ino).await?;
+        if !check_access(
+            attr.uid,
+            attr.gid,
+            attr.perm,
+            caller.uid,
+            caller.gid,
+            access_mask,
+        ) {
+            return Err(EACCES);
+        }
+        // we don't use handles for directories
+        Ok((0, self.open_flags()))
+    }
+
+    /// Entries from `offset` until `reply` is full.
+    async fn readdir(
+        &self,
+        ino: u64,
+        offset: usize,
+        reply: &mut ReplyDirectory,
+    ) -> Result<(), c_int> {
+        self.check_unlocked().await?;
+        let iter = self.fs.read_dir(ino).await.map_err(|err| {
+            error!(err = %err);
+            EIO
+        })?;
+        for (i, entry) in iter.enumerate().skip(offset) {
+            let entry = entry.map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
+            let kind = if entry.kind == FileType::Directory {
+                fuser::FileType::Directory
+            } else {
+                fuser::FileType::RegularFile
+            };
+            #[allow(clippy::cast_possible_wrap)]
+            if reply.add(entry.ino, (i + 1) as i64, kind, entry.name.expose_secret()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::readdir`] with the attributes of the entries, so the kernel doesn't ask for each of them.
+    async fn readdirplus(
+        &self,
+        ino: u64,
+        offset: usize,
+        reply: &mut ReplyDirectoryPlus,
+    ) -> Result<(), c_int> {
+        self.check_unlocked().await?;
+        let iter = self.fs.read_dir_plus(ino).await.map_err(|err| {
+            error!(err = %err);
+            EIO
+        })?;
+        let ttl = self.fs.entry_ttl();
+        for (i, entry) in iter.enumerate().skip(offset) {
+            let entry = entry.map_err(|err| {
+                error!(err = %err);
+                EIO
+            })?;
+            let attr = self.id_map.map_attr(entry.attr).into();
+            #[allow(clippy::cast_possible_wrap)]
+            if reply.add(
+                entry.ino,
+                (i + 1) as i64,
+                entry.name.expose_secret(),
+                &ttl,
+                &attr,
+                0,
+            ) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn access(&self, caller: Caller, ino: u64, mask: i32) -> Result<(), c_int> {
+        self.check_unlocked().await?;
+        let attr = self.get_attr(ino).await?;
+        if check_access(attr.uid, attr.gid, attr.perm, caller.uid, caller.gid, mask) {
+            Ok(())
+        } else {
+            Err(EACCES)
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_file_range(
+        &self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u32, c_int> {
+        self.check_unlocked().await?;
+        #[allow(clippy::cast_possible_truncation)]
+        let copied = self
+            .fs
+            .copy_file_range(
+                ino_in,
+                offset_in,
+                ino_out,
+                offset_out,
+                len as usize,
+                fh_in,
+                fh_out,
+            )
+            .await
+            .map_err(|err| {
+                error!(err = %err);
+                match err {
+                    FsError::QuotaExceeded(_) => ENOSPC,
+                    FsError::ReadOnly => EROFS,
+                    _ => EIO,
+                }
+            })?;
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(copied as u32)
+    }
+}
+
+impl From<FileAttr> for fuser::FileAttr {
+    fn from(from: FileAttr) -> Self {
+        Self {
+            ino: from.ino,
+            size: from.size,
+            blocks: from.blocks,
+            atime: from.atime,
+            mtime: from.mtime,
+            ctime: from.ctime,
+            crtime: from.crtime,
+            kind: if from.kind == FileType::Directory {
+                fuser::FileType::Directory
+            } else {
+                fuser::FileType::RegularFile
+            },
+            perm: from.perm,
+            nlink: from.nlink,
+            uid: from.uid,
+            gid: from.gid,
+            rdev: from.rdev,
+            blksize: from.blksize,
+            // they are BSD flags, only used on macOS
+            flags: from.flags,
+        }
+    }
+}
+
+fn time_or_now(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+/// The name as we store it, names not in UTF-8 can't be stored.
+fn secret_name(name: &OsStr) -> Result<SecretString, c_int> {
+    name.to_str()
+        .map(|name| SecretString::new(name.to_string()))
+        .ok_or(EINVAL)
+}
+
+/// Bridges the callbacks of `fuser`, called from its session thread, to [`EncryptedFsFuser`] on the runtime, each
+/// request is handled in its own task so slow ones don't hold the others.
+struct FuserFilesystem {
+    inner: Arc<EncryptedFsFuser>,
+    rt: Handle,
+}
+
+macro_rules! spawn_reply {
+    ($self:ident, $reply:ident, $name:literal, $ino:expr, $args:expr, |$inner:ident| $op:expr, |$ok:pat_param| $done:expr) => {{
+        let $inner = $self.inner.clone();
+        let args = $args;
+        $self.rt.spawn(async move {
+            match ops::observe($name, $ino, args, $op).await {
+                Ok($ok) => $done,
+                Err(err) => $reply.error(err),
+            }
+        });
+    }};
+    // also kept in the audit log
+    ($self:ident, $reply:ident, $event:expr, $ino:expr, $args:expr, |$inner:ident| $op:expr, |$ok:pat_param| $done:expr) => {{
+        let $inner = $self.inner.clone();
+        let fs = $inner.fs.clone();
+        let event = $event;
+        let args = $args;
+        $self.rt.spawn(async move {
+            match ops::audited(&fs, event, $ino, args, $op).await {
+                Ok($ok) => $done,
+                Err(err) => $reply.error(err),
+            }
+        });
+    }};
+}
+
+impl fuser::Filesystem for FuserFilesystem {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Err(max) = config.set_max_write(1024 * 1024) {
+            debug!(max, "max_write is lower");
+        }
+        if self.inner.umask.is_some() {
+            // so we get the mode before the umask of the caller is applied, and apply ours
+            if let Err(err) = config.add_capabilities(FUSE_DONT_MASK) {
+                warn!(err, "umask of the caller will be applied too");
+            }
+        }
+        // attributes with the entries, when the kernel thinks they'll be used, like for `ls -l`
+        #[cfg(target_os = "linux")]
+        if let Err(err) = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO) {
+            debug!(err, "no readdirplus");
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        // so the storage has everything before we exit
+        if let Err(err) = self.rt.block_on(self.inner.fs.sync_storage()) {
+            error!(err = %err, "syncing storage");
+        }
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let args = ops::args!(parent, name);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        let ttl = self.inner.fs.entry_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            "lookup",
+            parent,
+            args,
+            |inner| inner.lookup(caller, parent, name),
+            |attr| reply.entry(
+                &ttl,
+                &attr.unwrap_or_else(ops::negative_entry_attr).into(),
+                0
+            )
+        );
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let ttl = self.inner.fs.attr_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            "getattr",
+            ino,
+            ops::args!(ino),
+            |inner| inner.getattr(ino),
+            |attr| reply.attr(&ttl, &attr.into())
+        );
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let caller = Caller::from(req);
+        let set = SetAttr {
+            mode,
+            uid,
+            gid,
+            size,
+            atime: atime.map(time_or_now),
+            mtime: mtime.map(time_or_now),
+            crtime,
+            flags,
+        };
+        let ttl = self.inner.fs.attr_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("setattr", AuditTarget::Inode(ino)),
+            ino,
+            ops::args!(ino, set),
+            |inner| inner.setattr(caller, ino, set),
+            |attr| reply.attr(&ttl, &attr.into())
+        );
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let args = ops::args!(parent, name, mode);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        let ttl = self.inner.fs.entry_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("mknod", AuditTarget::Entry(parent, name.clone())),
+            parent,
+            args,
+            |inner| inner.create_nod(caller, parent, name, mode, false, false),
+            |(_, attr)| reply.entry(&ttl, &attr.into(), 0)
+        );
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let args = ops::args!(parent, name, mode);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        let mode = mode | S_IFDIR;
+        let ttl = self.inner.fs.entry_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("mkdir", AuditTarget::Entry(parent, name.clone())),
+            parent,
+            args,
+            |inner| inner.create_nod(caller, parent, name, mode, false, false),
+            |(_, attr)| reply.entry(&ttl, &attr.into(), 0)
+        );
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let args = ops::args!(parent, name);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("unlink", AuditTarget::Entry(parent, name.clone())),
+            parent,
+            args,
+            |inner| inner.remove(caller, parent, name, false),
+            |()| reply.ok()
+        );
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let args = ops::args!(parent, name);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("rmdir", AuditTarget::Entry(parent, name.clone())),
+            parent,
+            args,
+            |inner| inner.remove(caller, parent, name, true),
+            |()| reply.ok()
+        );
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let args = ops::args!(parent, name, new_parent, new_name);
+        let caller = Caller::from(req);
+        let (name, new_name) = match (secret_name(name), secret_name(new_name)) {
+            (Ok(name), Ok(new_name)) => (name, new_name),
+            (Err(err), _) | (_, Err(err)) => return reply.error(err),
+        };
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event(
+                "rename",
+                AuditTarget::Rename(parent, name.clone(), new_parent, new_name.clone())
+            ),
+            parent,
+            args,
+            |inner| inner.rename(caller, parent, name, new_parent, new_name),
+            |()| reply.ok()
+        );
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let caller = Caller::from(req);
+        let event = if ops::opens_for_read(flags) {
+            caller.read_event("open", AuditTarget::Inode(ino))
+        } else {
+            caller.write_event("open", AuditTarget::Inode(ino))
+        };
+        spawn_reply!(
+            self,
+            reply,
+            event,
+            ino,
+            ops::args!(ino, flags),
+            |inner| inner.open(caller, ino, flags),
+            |(fh, flags)| reply.opened(fh, flags)
+        );
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        #[allow(clippy::cast_sign_loss)]
+        let offset = offset as u64;
+        let caller = Caller::from(req);
+        spawn_reply!(
+            self,
+            reply,
+            caller.read_event("read", AuditTarget::Inode(ino)),
+            ino,
+            ops::args!(ino, fh, offset, size),
+            |inner| inner.read(ino, fh, offset, size),
+            |data| reply.data(&data)
+        );
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        #[allow(clippy::cast_sign_loss)]
+        let offset = offset as u64;
+        let data = data.to_vec();
+        let append = flags & libc::O_APPEND != 0;
+        let caller = Caller::from(req);
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("write", AuditTarget::Inode(ino)),
+            ino,
+            ops::args!(ino, fh, offset, data.len()),
+            |inner| inner.write(ino, fh, offset, data, append),
+            |written| reply.written(written)
+        );
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        spawn_reply!(
+            self,
+            reply,
+            "flush",
+            ino,
+            ops::args!(ino, fh),
+            |inner| async move {
+                inner.check_unlocked().await?;
+                inner.flush(fh).await
+            },
+            |()| reply.ok()
+        );
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        spawn_reply!(
+            self,
+            reply,
+            "release",
+            ino,
+            ops::args!(ino, fh, flush),
+            |inner| inner.release(ino, fh, flush),
+            |()| {
+                reply.ok();
+            }
+        );
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        spawn_reply!(
+            self,
+            reply,
+            "fsync",
+            ino,
+            ops::args!(ino, fh),
+            |inner| async move {
+                inner.check_unlocked().await?;
+                inner.flush(fh).await
+            },
+            |()| reply.ok()
+        );
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let caller = Caller::from(req);
+        spawn_reply!(
+            self,
+            reply,
+            "opendir",
+            ino,
+            ops::args!(ino, flags),
+            |inner| inner.opendir(caller, ino, flags),
+            |(fh, flags)| reply.opened(fh, flags)
+        );
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let offset = offset as usize;
+        let args = ops::args!(ino, offset);
+        let caller = Caller::from(req);
+        let event = caller.read_event("readdir", AuditTarget::Inode(ino));
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            match ops::audited(
+                &inner.fs,
+                event,
+                ino,
+                args,
+                inner.readdir(ino, offset, &mut reply),
+            )
+            .await
+            {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
+            }
+        });
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let offset = offset as usize;
+        let args = ops::args!(ino, offset);
+        let caller = Caller::from(req);
+        let event = caller.read_event("readdirplus", AuditTarget::Inode(ino));
+        let inner = self.inner.clone();
+        self.rt.spawn(async move {
+            match ops::audited(
+                &inner.fs,
+                event,
+                ino,
+                args,
+                inner.readdirplus(ino, offset, &mut reply),
+            )
+            .await
+            {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
+            }
+        });
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let bsize = u64::from(BLOCK_SIZE);
+        let (blocks, free) = self.inner.fs.quota().map_or((1, 0), |quota| {
+            (
+                quota.max_size / bsize,
+                quota.max_size.saturating_sub(quota.used) / bsize,
+            )
+        });
+        reply.statfs(blocks, free, free, 1, 0, BLOCK_SIZE, 255, BLOCK_SIZE);
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        spawn_reply!(
+            self,
+            reply,
+            "access",
+            ino,
+            ops::args!(ino, mask),
+            |inner| inner.access(caller, ino, mask),
+            |()| {
+                reply.ok();
+            }
+        );
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let args = ops::args!(parent, name, mode, flags);
+        let caller = Caller::from(req);
+        let name = match secret_name(name) {
+            Ok(name) => name,
+            Err(err) => return reply.error(err),
+        };
+        let (read, write) = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => (true, false),
+            libc::O_WRONLY => (false, true),
+            libc::O_RDWR => (true, true),
+            // Exactly one access mode flag must be specified
+            _ => return reply.error(EINVAL),
+        };
+        let open_flags = self.inner.open_flags();
+        let ttl = self.inner.fs.entry_ttl();
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("create", AuditTarget::Entry(parent, name.clone())),
+            parent,
+            args,
+            |inner| inner.create_nod(caller, parent, name, mode, read, write),
+            |(fh, attr)| reply.created(&ttl, &attr.into(), 0, fh, open_flags)
+        );
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        #[allow(clippy::cast_sign_loss)]
+        let (offset_in, offset_out) = (offset_in as u64, offset_out as u64);
+        let caller = Caller::from(req);
+        spawn_reply!(
+            self,
+            reply,
+            caller.write_event("copy_file_range", AuditTarget::Inode(ino_out)),
+            ino_in,
+            ops::args!(ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len),
+            |inner| inner
+                .copy_file_range(ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len),
+            |copied| reply.written(copied)
+        );
+    }
+}
+
+/// Mounts with FUSE using `fuser`, with macFUSE on macOS.
+#[allow(clippy::module_name_repetitions)]
+pub struct FuserBackend;
+
+#[async_trait]
+impl MountBackend for FuserBackend {
+    fn name(&self) -> &'static str {
+        "fuser"
+    }
+
+    async fn mount(&self, config: MountConfig) -> FsResult<mount::MountHandle> {
+        let mut mount_options = vec![MountOption::FSName(config.flags.fsname().to_string())];
+        if let Some(subtype) = &config.flags.subtype {
+            mount_options.push(MountOption::Subtype(subtype.clone()));
+        }
+        if config.flags.noexec {
+            mount_options.push(MountOption::NoExec);
+        }
+        if config.flags.nosuid {
+            mount_options.push(MountOption::NoSuid);
+        }
+        if config.flags.nodev {
+            mount_options.push(MountOption::NoDev);
+        }
+        if config.options.read_only {
+            mount_options.push(MountOption::RO);
+        }
+        if config.allow_root {
+            mount_options.push(MountOption::AllowRoot);
+        }
+        if config.allow_other {
+            mount_options.push(MountOption::AllowOther);
+        }
+        #[cfg(target_os = "macos")]
+        {
+            // the Finder would keep its metadata in `._` files next to each file otherwise
+            mount_options.push(MountOption::CUSTOM("noappledouble".to_string()));
+            if let Some(name) = config.mountpoint.file_name().and_then(OsStr::to_str) {
+                mount_options.push(MountOption::CUSTOM(format!("volname={name}")));
+            }
+        }
+
+        info!("Checking password and mounting FUSE filesystem");
+        let inner = EncryptedFsFuser::new(
+            config.data_dir,
+            config.password_provider,
+            config.cipher,
+            config.direct_io,
+            config.suid_support,
+            config.options,
+        )
+        .await?;
+        let encryptedfs = inner.fs.clone();
+        let fs = FuserFilesystem {
+            inner: Arc::new(inner),
+            rt: Handle::current(),
+        };
+        let inner = start_session(fs, config.mountpoint.clone(), mount_options).await?;
+        Ok(mount::MountHandle::new(
+            config.mountpoint,
+            encryptedfs,
+            inner,
+        ))
+    }
+}
+
+/// Mount `fs` and serve it from its own thread, until it's unmounted.
+pub(super) async fn start_session<FS: Filesystem + Send + 'static>(
+    fs: FS,
+    mountpoint: PathBuf,
+    mount_options: Vec<MountOption>,
+) -> FsResult<MountHandleInnerImpl> {
+    let mut session =
+        tokio::task::spawn_blocking(move || Session::new(fs, &mountpoint, &mount_options))
+            .await??;
+    let unmounter = session.unmount_callable();
+    let (tx, done) = oneshot::channel();
+    thread::Builder::new()
+        .name("fuser".to_string())
+        .spawn(move || {
+            let res = session.run();
+            // unmounts and calls destroy
+            drop(session);
+            let _ = tx.send(res);
+        })?;
+    Ok(MountHandleInnerImpl { unmounter, done })
+}
+
+pub(super) struct MountHandleInnerImpl {
+    unmounter: SessionUnmounter,
+    done: oneshot::Receiver<io::Result<()>>,
+}
+
+impl Future for MountHandleInnerImpl {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.done
+            .poll_unpin(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(io::Error::other("FUSE session thread died"))))
+    }
+}
+
+#[async_trait]
+impl MountHandleInner for MountHandleInnerImpl {
+    async fn unmount(&mut self) -> io::Result<()> {
+        self.unmounter.unmount()
+    }
+}
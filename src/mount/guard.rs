@@ -0,0 +1,113 @@
+//! So a panic doesn't leave the mount point dead, with the keys still in memory.
+
+use std::collections::HashMap;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use tracing::error;
+
+use crate::encryptedfs::EncryptedFs;
+
+/// How long each step of the cleanup can take, the panicking code might hold a lock it needs.
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Mount = (PathBuf, Weak<EncryptedFs>);
+
+static MOUNTED: Mutex<Option<HashMap<u64, Mount>>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps the mount known to the panic hook, until dropped.
+pub struct Registered(u64);
+
+impl Registered {
+    pub fn new(mountpoint: PathBuf, fs: Weak<EncryptedFs>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        MOUNTED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(HashMap::new)
+            .insert(id, (mountpoint, fs));
+        Self(id)
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let Some(mounted) = MOUNTED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            mounted.remove(&self.0);
+        }
+    }
+}
+
+/// On a panic, in a FUSE handler or anywhere else, flush the files open for write, write everything to the storage,
+/// wipe the keys from memory and unmount lazily what is mounted, then exit with `101`, like a panic does. Without it
+/// the process would end, or with `panic = "abort"` abort, leaving the mount point dead until unmounted by hand.
+///
+/// The hook set before runs first, so the panic is still printed.
+pub fn set_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let mounted: Vec<_> = MOUNTED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+            .map_or_else(Vec::new, |mounted| {
+                mounted.drain().map(|(_, mount)| mount).collect()
+            });
+        if mounted.is_empty() {
+            return;
+        }
+        // this might be a thread of the runtime, which can't block on another one
+        let _ = thread::spawn(move || cleanup(mounted)).join();
+        process::exit(101);
+    }));
+}
+
+fn cleanup(mounted: Vec<Mount>) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(err) => {
+            error!(err = %err, "cannot clean up after panic");
+            return;
+        }
+    };
+    for (mountpoint, fs) in mounted {
+        error!(mountpoint = %mountpoint.display(), "panicked, unmounting");
+        if let Some(fs) = fs.upgrade() {
+            rt.block_on(async {
+                match tokio::time::timeout(STEP_TIMEOUT, fs.flush_all()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!(err = %err, "flushing open files"),
+                    Err(_) => error!("timeout flushing open files"),
+                }
+                match tokio::time::timeout(STEP_TIMEOUT, fs.sync_storage()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!(err = %err, "syncing storage"),
+                    Err(_) => error!("timeout syncing storage"),
+                }
+                if tokio::time::timeout(STEP_TIMEOUT, fs.wipe_secrets())
+                    .await
+                    .is_err()
+                {
+                    error!("timeout wiping keys");
+                }
+            });
+        }
+        if let Err(err) = super::sys_umount(&mountpoint, true) {
+            error!(err = %err, "unmounting");
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Each FUSE operation is timed and logged as a structured event, with the `rencfs::ops` target at debug level, so
+//! it can be filtered and shipped on its own. With the `trace-ops` feature, at trace level, like with `--trace-ops`, its
+//! arguments are logged too.
+//! It's also counted in [`metrics`]. Operations mounted with [`crate::encryptedfs::FsOptions::audit_log`] are also
+//! kept in the audit log, see [`audited`].
+
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io;
+use std::os::raw::c_int;
+use std::time::{Instant, UNIX_EPOCH};
+
+use secrecy::SecretString;
+use tracing::{debug, trace};
+
+use crate::encryptedfs::{AuditEvent, AuditTarget, EncryptedFs, FileAttr, FileType};
+use crate::metrics;
+
+/// Target of the events.
+const TARGET: &str = "rencfs::ops";
+
+/// If the events are logged with the arguments of the operations.
+#[cfg(feature = "trace-ops")]
+pub(super) fn enabled() -> bool {
+    tracing::enabled!(target: TARGET, tracing::Level::TRACE)
+}
+
+/// The arguments have the names of the files in clear, they are never logged without the `trace-ops` feature.
+#[cfg(not(feature = "trace-ops"))]
+pub(super) const fn enabled() -> bool {
+    false
+}
+
+/// Arguments of the operation as `name=value` for [`observe`], only formatted when they are logged.
+macro_rules! args {
+    ($($arg:expr),* $(,)?) => {
+        $crate::mount::ops::enabled().then(|| {
+            [$(format!("{}={:?}", stringify!($arg), $arg)),*].join(" ")
+        })
+    };
+}
+pub(super) use args;
+
+/// Run `handler` of the operation `op` on `ino` and log how it went, with `args` from [`args!`] if any.
+pub(super) async fn observe<T: Send, E: Copy + Into<c_int> + Send>(
+    op: &'static str,
+    ino: u64,
+    args: Option<String>,
+    handler: impl Future<Output = Result<T, E>> + Send,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let res = handler.await;
+    let elapsed = start.elapsed();
+    metrics::record_op(op, elapsed, res.is_err());
+    #[allow(clippy::cast_possible_truncation)]
+    let duration_us = elapsed.as_micros() as u64;
+    match (&res, args) {
+        (Ok(_), None) => debug!(target: TARGET, op, ino, duration_us, result = "ok"),
+        (Ok(_), Some(args)) => {
+            trace!(target: TARGET, op, ino, args, duration_us, result = "ok");
+        }
+        (Err(err), args) => {
+            let errno: c_int = (*err).into();
+            let error = io::Error::from_raw_os_error(errno);
+            if let Some(args) = args {
+                trace!(target: TARGET, op, ino, args, duration_us, result = "error", errno, error = %error);
+            } else {
+                debug!(target: TARGET, op, ino, duration_us, result = "error", errno, error = %error);
+            }
+        }
+    }
+    res
+}
+
+/// Like [`observe`], also appending the operation to the audit log of `fs` with how it went, if it's audited.
+pub(super) async fn audited<T: Send, E: Copy + Into<c_int> + Send>(
+    fs: &EncryptedFs,
+    event: AuditEvent,
+    ino: u64,
+    args: Option<String>,
+    handler: impl Future<Output = Result<T, E>> + Send,
+) -> Result<T, E> {
+    let res = observe(event.op, ino, args, handler).await;
+    if fs.is_audited(event.read) {
+        fs.audit(event, res.as_ref().err().map(|err| (*err).into()))
+            .await;
+    }
+    res
+}
+
+/// An operation by `uid` and `pid` that changes `target`, for [`audited`].
+pub(super) const fn write_event(
+    op: &'static str,
+    target: AuditTarget,
+    uid: u32,
+    pid: u32,
+) -> AuditEvent {
+    AuditEvent {
+        op,
+        read: false,
+        target,
+        uid,
+        pid,
+    }
+}
+
+/// Like [`write_event`], for one that only reads.
+pub(super) const fn read_event(
+    op: &'static str,
+    target: AuditTarget,
+    uid: u32,
+    pid: u32,
+) -> AuditEvent {
+    AuditEvent {
+        op,
+        read: true,
+        target,
+        uid,
+        pid,
+    }
+}
+
+/// The entry `name` in `parent`, names not in UTF-8 are logged lossy.
+pub(super) fn entry(parent: u64, name: &OsStr) -> AuditTarget {
+    AuditTarget::Entry(
+        parent,
+        SecretString::new(name.to_string_lossy().into_owned()),
+    )
+}
+
+/// If opening with `flags` only reads the file.
+pub(super) const fn opens_for_read(flags: c_int) -> bool {
+    flags & libc::O_ACCMODE == libc::O_RDONLY && flags & libc::O_TRUNC == 0
+}
+
+/// What a lookup replies when there is no such entry, inode 0, so the kernel remembers it doesn't exist for the TTL
+/// of entries instead of asking again.
+pub(super) fn negative_entry_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
+    }
+}
@@ -0,0 +1,162 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use fuser::{
+    Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
+    ReplyStatfs, Request,
+};
+use libc::{EACCES, EIO, ENOENT, O_ACCMODE, O_RDONLY};
+use tracing::{error, info, warn};
+
+use crate::encryptedfs::{FileType, FsError, FsResult};
+use crate::mount::fuser::{start_session, MountHandleInnerImpl};
+use crate::mount::{is_mounted, umount, MountHandleInner};
+use crate::reverse::ReverseFs;
+
+/// The view changes only when the plaintext does, which we don't know of.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Serves a [`ReverseFs`] read-only.
+struct ReverseFilesystem(ReverseFs);
+
+impl Filesystem for ReverseFilesystem {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.0.lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr.into(), 0),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.0.get_attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr.into()),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, _ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & O_ACCMODE == O_RDONLY {
+            reply.opened(0, 0);
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        #[allow(clippy::cast_sign_loss)]
+        match self.0.read(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.0.read_dir(ino) {
+            Ok(entries) => entries,
+            Err(err) => return reply.error(errno(&err)),
+        };
+        let dots = [
+            (ino, fuser::FileType::Directory, OsStr::new(".")),
+            (ino, fuser::FileType::Directory, OsStr::new("..")),
+        ];
+        let entries = entries.iter().map(|(name, attr)| {
+            let kind = if attr.kind == FileType::Directory {
+                fuser::FileType::Directory
+            } else {
+                fuser::FileType::RegularFile
+            };
+            (attr.ino, kind, name.as_os_str())
+        });
+        #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        for (i, (ino, kind, name)) in dots
+            .into_iter()
+            .chain(entries)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            #[allow(clippy::cast_possible_wrap)]
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(0, 0, 0, 0, 0, 4096, 255, 4096);
+    }
+}
+
+fn errno(err: &FsError) -> c_int {
+    match err {
+        FsError::Io { source } => source.raw_os_error().unwrap_or(EIO),
+        FsError::NotFound(_) | FsError::InodeNotFound => ENOENT,
+        _ => {
+            error!(err = %err);
+            EIO
+        }
+    }
+}
+
+/// Mount the encrypted view of [`ReverseFs::source`] read-only at `mountpoint`, with `fuser`.
+#[allow(clippy::missing_errors_doc)]
+pub async fn mount_reverse(
+    mountpoint: PathBuf,
+    fs: ReverseFs,
+    allow_root: bool,
+    allow_other: bool,
+) -> FsResult<ReverseMountHandle> {
+    let mut mount_options = vec![
+        MountOption::FSName("rencfs-reverse".to_string()),
+        MountOption::RO,
+    ];
+    if allow_root {
+        mount_options.push(MountOption::AllowRoot);
+    }
+    if allow_other {
+        mount_options.push(MountOption::AllowOther);
+    }
+    info!(source = %fs.source().display(), "mounting encrypted view");
+    let inner = start_session(ReverseFilesystem(fs), mountpoint.clone(), mount_options).await?;
+    Ok(ReverseMountHandle { mountpoint, inner })
+}
+
+/// What [`mount_reverse`] mounted, completes when it's unmounted.
+pub struct ReverseMountHandle {
+    mountpoint: PathBuf,
+    inner: MountHandleInnerImpl,
+}
+
+impl ReverseMountHandle {
+    /// Unmount it and wait for the session to end, like [`crate::mount::MountHandle::umount`].
+    #[allow(clippy::missing_errors_doc)]
+    pub async fn umount(mut self) -> io::Result<()> {
+        if let Err(err) = self.inner.unmount().await {
+            warn!(err = %err, "cannot unmount");
+        }
+        if is_mounted(&self.mountpoint).await {
+            umount(&self.mountpoint).await?;
+        }
+        self.inner.await
+    }
+}
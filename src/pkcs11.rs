@@ -0,0 +1,98 @@
+//! Wrap secrets with an RSA key kept in a PKCS#11 token or HSM, through `pkcs11-tool` from OpenSC.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::RngCore;
+use secrecy::{ExposeSecret, SecretString};
+
+use rencfs::crypto;
+use rencfs::crypto::LockedString;
+use rencfs::encryptedfs::KeySlotKind;
+
+const MECHANISM: &str = "RSA-PKCS-OAEP";
+
+/// Create a random secret and encrypt it with the key `key_label` from the token. Only the token can decrypt it.
+pub(crate) fn wrap(
+    module: &str,
+    slot: Option<&str>,
+    key_label: &str,
+    pin: &SecretString,
+) -> Result<(KeySlotKind, LockedString)> {
+    let mut secret = [0_u8; 32];
+    crypto::create_rng().fill_bytes(&mut secret);
+    let secret = LockedString::new(hex::encode(secret));
+    let wrapped = run(
+        module,
+        slot,
+        key_label,
+        pin,
+        "--encrypt",
+        secret.expose_secret().as_bytes(),
+    )?;
+    let kind = KeySlotKind::Pkcs11 {
+        module: module.to_string(),
+        slot: slot.map(ToString::to_string),
+        key_label: key_label.to_string(),
+        wrapped: STANDARD.encode(wrapped),
+    };
+    Ok((kind, secret))
+}
+
+/// Decrypt the secret of the slot with the token.
+pub(crate) fn unwrap(kind: &KeySlotKind, pin: &SecretString) -> Result<LockedString> {
+    let KeySlotKind::Pkcs11 {
+        module,
+        slot,
+        key_label,
+        wrapped,
+    } = kind
+    else {
+        return Err(anyhow!("not a PKCS#11 key slot"));
+    };
+    let secret = run(
+        module,
+        slot.as_deref(),
+        key_label,
+        pin,
+        "--decrypt",
+        &STANDARD.decode(wrapped)?,
+    )?;
+    Ok(LockedString::new(String::from_utf8(secret)?))
+}
+
+/// Data goes through pipes, so the secret is never written to disk.
+fn run(
+    module: &str,
+    slot: Option<&str>,
+    key_label: &str,
+    pin: &SecretString,
+    operation: &str,
+    input: &[u8],
+) -> Result<Vec<u8>> {
+    let mut command = Command::new("pkcs11-tool");
+    command
+        .args(["--module", module, "--label", key_label])
+        .args(["--mechanism", MECHANISM, operation])
+        .args(["--input-file", "/dev/stdin", "--output-file", "/dev/stdout"])
+        // so the PIN doesn't show in the process list
+        .args(["--login", "--pin", "env:RENCFS_PKCS11_PIN"])
+        .env("RENCFS_PKCS11_PIN", pin.expose_secret())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    if let Some(slot) = slot {
+        command.args(["--slot", slot]);
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|err| anyhow!("cannot run pkcs11-tool, is OpenSC installed? {err}"))?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("pkcs11-tool failed with {}", output.status));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,588 @@
+//! An encrypted view of a plaintext directory, like the reverse mode of gocryptfs, see [`ReverseFs`]. It's mounted
+//! read-only with `mount --reverse`, so backup and sync tools can copy it as it is, without keeping an encrypted copy
+//! of the files locally too. [`ReverseFs::decrypt`] gets the plaintext back from such a copy.
+//!
+//! The view is encrypted on the fly from the plaintext and it's the same each time for the same plaintext, so only
+//! what changed is copied again. Content is in blocks like the files in a data dir, with a nonce derived from each
+//! block and a key derived from the path of the file. Names are encrypted with a nonce derived from the name and the
+//! dir it's in. So the copy tells which blocks of a file didn't change, and nothing about what's in them.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, UNIX_EPOCH};
+
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::NO_PAD;
+use base64::engine::GeneralPurpose;
+use base64::Engine;
+use rand_chacha::rand_core::RngCore;
+use ring::aead::NONCE_LEN;
+use secrecy::{ExposeSecret, SecretString, SecretVec};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::crypto::write::BLOCK_SIZE;
+use crate::crypto::{Cipher, KdfParams, Locked, LockedVec};
+use crate::encryptedfs::{FileAttr, FileType, FsError, FsResult, ROOT_INODE};
+use crate::{crypto, fs_util};
+
+/// In the root of the plaintext dir, it's shown as it is in the view so the copy can be decrypted with the password.
+pub const REVERSE_CONFIG_FILENAME: &str = ".rencfs-reverse";
+
+/// Names in the view, `/` can't be in them.
+static NAMES: GeneralPurpose = GeneralPurpose::new(&URL_SAFE, NO_PAD);
+
+/// Longest name most filesystems take, longer encrypted names are not shown.
+const NAME_MAX: usize = 255;
+
+/// Both ciphers have 16 bytes tags.
+const TAG_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct ReverseConfig {
+    cipher: Cipher,
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    /// The master key, encrypted with the one derived from the password.
+    key: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct DecryptReport {
+    pub dirs: u64,
+    pub files: u64,
+    /// Content of the files.
+    pub bytes: u64,
+    /// Entries that can't be decrypted, like temporary files of the sync tool. They are not restored.
+    pub skipped: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct Inodes {
+    paths: HashMap<u64, PathBuf>,
+    inos: HashMap<PathBuf, u64>,
+}
+
+/// The encrypted view of `source`, with inodes for what was looked up. Only dirs and regular files are shown, and
+/// nothing can be changed.
+pub struct ReverseFs {
+    source: PathBuf,
+    cipher: Cipher,
+    key: LockedVec,
+    names_key: LockedVec,
+    nonce_key: LockedVec,
+    inodes: RwLock<Inodes>,
+}
+
+impl ReverseFs {
+    /// Create the config in `source`, with a new random key encrypted with the password.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn init(
+        source: &Path,
+        password: &SecretString,
+        cipher: Cipher,
+        kdf: KdfParams,
+    ) -> FsResult<()> {
+        let path = source.join(REVERSE_CONFIG_FILENAME);
+        if path.exists() {
+            return Err(FsError::AlreadyExists);
+        }
+        let mut salt = vec![0; 32];
+        crypto::create_rng().fill_bytes(&mut salt);
+        let derived = crypto::derive_key_with(password, cipher, &salt, &kdf)?;
+        let key = LockedVec::random(cipher.key_len());
+        let mut encrypted = vec![];
+        crypto::serialize_encrypt_into(&mut encrypted, key.expose_secret(), cipher, &derived)?;
+        let config = ReverseConfig {
+            cipher,
+            kdf,
+            salt,
+            key: encrypted,
+        };
+        let mut file = fs_util::open_atomic_write(&path)?;
+        bincode::serialize_into(&mut file, &config)?;
+        file.commit()?;
+        File::open(source)?.sync_all()?;
+        Ok(())
+    }
+
+    /// If [`ReverseFs::init`] was done for `source`.
+    #[must_use]
+    pub fn is_initialized(source: &Path) -> bool {
+        source.join(REVERSE_CONFIG_FILENAME).is_file()
+    }
+
+    /// Unlock the view of `source`, or of a copy of it, with the config in its root.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn open(source: &Path, password: &SecretString) -> FsResult<Self> {
+        let path = source.join(REVERSE_CONFIG_FILENAME);
+        if !path.is_file() {
+            return Err(FsError::NotFound("reverse config"));
+        }
+        let config: ReverseConfig = bincode::deserialize_from(File::open(path)?)?;
+        let derived = crypto::derive_key_with(password, config.cipher, &config.salt, &config.kdf)?;
+        let key: Vec<u8> = bincode::deserialize_from(crypto::create_read(
+            &config.key[..],
+            config.cipher,
+            &derived,
+        ))
+        .map_err(|_| FsError::InvalidPassword)?;
+        let key = Locked::new(key);
+        let mut inodes = Inodes::default();
+        inodes.paths.insert(ROOT_INODE, PathBuf::new());
+        inodes.inos.insert(PathBuf::new(), ROOT_INODE);
+        Ok(Self {
+            source: source.to_path_buf(),
+            cipher: config.cipher,
+            names_key: derive(&key, "rencfs reverse names", b"", config.cipher.key_len()),
+            nonce_key: derive(&key, "rencfs reverse nonces", b"", blake3::KEY_LEN),
+            key,
+            inodes: RwLock::new(inodes),
+        })
+    }
+
+    #[must_use]
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Attributes of what's at `ino` in the view, the size is the encrypted one.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn get_attr(&self, ino: u64) -> FsResult<FileAttr> {
+        let path = self.path(ino)?;
+        let metadata = fs::metadata(self.source.join(&path))?;
+        Self::attr(ino, &path, &metadata)
+    }
+
+    /// Find the encrypted `name` in the dir with `parent`.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> FsResult<FileAttr> {
+        let dir = self.path(parent)?;
+        let plain = if dir.as_os_str().is_empty() && name == REVERSE_CONFIG_FILENAME {
+            OsString::from(REVERSE_CONFIG_FILENAME)
+        } else {
+            self.decrypt_name(&dir, name)
+                .ok_or(FsError::NotFound("name"))?
+        };
+        let path = dir.join(plain);
+        let metadata = fs::symlink_metadata(self.source.join(&path))?;
+        if !is_shown(&metadata) {
+            return Err(FsError::NotFound("name"));
+        }
+        let ino = self.ino(&path);
+        Self::attr(ino, &path, &metadata)
+    }
+
+    /// The entries of the dir with `ino`, with their encrypted names. Names which would be too long encrypted are
+    /// left out, with a warning.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read_dir(&self, ino: u64) -> FsResult<Vec<(OsString, FileAttr)>> {
+        let dir = self.path(ino)?;
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.source.join(&dir))? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !is_shown(&metadata) {
+                continue;
+            }
+            let name = entry.file_name();
+            let path = dir.join(&name);
+            let shown = if is_config(&path) {
+                name
+            } else {
+                let Some(name) = self.encrypt_name(&dir, &name) else {
+                    warn!(path = %path.display(), "name too long once encrypted, it's not shown");
+                    continue;
+                };
+                OsString::from(name)
+            };
+            let ino = self.ino(&path);
+            entries.push((shown, Self::attr(ino, &path, &metadata)?));
+        }
+        Ok(entries)
+    }
+
+    /// Up to `size` bytes of the encrypted content of the file with `ino`, from `offset`. Only the blocks in that
+    /// range are encrypted.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn read(&self, ino: u64, offset: u64, size: usize) -> FsResult<Vec<u8>> {
+        let path = self.path(ino)?;
+        let file = File::open(self.source.join(&path))?;
+        let mut out = vec![];
+        if is_config(&path) {
+            out.resize(size, 0);
+            let len = read_at(&file, offset, &mut out)?;
+            out.truncate(len);
+            return Ok(out);
+        }
+        let key = self.file_key(&path);
+        let context = path.as_os_str().as_bytes();
+        let encrypted_block = (BLOCK_SIZE + NONCE_LEN + TAG_LEN) as u64;
+        let mut index = offset / encrypted_block;
+        #[allow(clippy::cast_possible_truncation)]
+        let mut skip = (offset % encrypted_block) as usize;
+        let mut block = vec![0; BLOCK_SIZE];
+        while out.len() < size {
+            let len = read_at(&file, index * BLOCK_SIZE as u64, &mut block)?;
+            if len == 0 {
+                break;
+            }
+            let sealed = crypto::encrypt_block_deterministic(
+                self.cipher,
+                &key,
+                &self.nonce_key,
+                context,
+                index,
+                &block[..len],
+            );
+            if skip >= sealed.len() {
+                break;
+            }
+            out.extend_from_slice(&sealed[skip..]);
+            skip = 0;
+            if len < BLOCK_SIZE {
+                break;
+            }
+            index += 1;
+        }
+        out.truncate(size);
+        Ok(out)
+    }
+
+    /// Decrypt a copy of the view at `copy` to `out`, which is created if it doesn't exist. The config is copied too,
+    /// so the restored dir has the same view.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn decrypt(copy: &Path, out: &Path, password: &SecretString) -> FsResult<DecryptReport> {
+        let fs = Self::open(copy, password)?;
+        fs::create_dir_all(out)?;
+        let mut report = DecryptReport::default();
+        fs.decrypt_dir(Path::new(""), copy, out, &mut report)?;
+        Ok(report)
+    }
+
+    /// `dir` is the plaintext path of `encrypted`, names in it are encrypted with it.
+    fn decrypt_dir(
+        &self,
+        dir: &Path,
+        encrypted: &Path,
+        out: &Path,
+        report: &mut DecryptReport,
+    ) -> FsResult<()> {
+        for entry in fs::read_dir(encrypted)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let file_type = entry.file_type()?;
+            let plain = if dir.as_os_str().is_empty() && name == REVERSE_CONFIG_FILENAME {
+                name
+            } else if let Some(plain) = self.decrypt_name(dir, &name) {
+                plain
+            } else {
+                warn!(path = %entry.path().display(), "name can't be decrypted, it's skipped");
+                report.skipped.push(entry.path());
+                continue;
+            };
+            let to = out.join(&plain);
+            if file_type.is_dir() {
+                fs::create_dir_all(&to)?;
+                report.dirs += 1;
+                self.decrypt_dir(&dir.join(&plain), &entry.path(), &to, report)?;
+            } else if file_type.is_file() {
+                let from = File::open(entry.path())?;
+                let mut file = File::create(&to)?;
+                report.bytes += if is_config(&dir.join(&plain)) {
+                    io::copy(&mut &from, &mut file)?
+                } else {
+                    let key = self.file_key(&dir.join(&plain));
+                    io::copy(&mut crypto::create_read(from, self.cipher, &key), &mut file)?
+                };
+                file.sync_all()?;
+                report.files += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, ino: u64) -> FsResult<PathBuf> {
+        self.inodes
+            .read()
+            .unwrap()
+            .paths
+            .get(&ino)
+            .cloned()
+            .ok_or(FsError::InodeNotFound)
+    }
+
+    /// The inode of the path, a new one the first time it's seen. They are kept until it's unmounted.
+    fn ino(&self, path: &Path) -> u64 {
+        if let Some(ino) = self.inodes.read().unwrap().inos.get(path) {
+            return *ino;
+        }
+        let mut inodes = self.inodes.write().unwrap();
+        if let Some(ino) = inodes.inos.get(path) {
+            return *ino;
+        }
+        let ino = ROOT_INODE + inodes.paths.len() as u64;
+        inodes.paths.insert(ino, path.to_path_buf());
+        inodes.inos.insert(path.to_path_buf(), ino);
+        ino
+    }
+
+    fn attr(ino: u64, path: &Path, metadata: &Metadata) -> FsResult<FileAttr> {
+        let (kind, size) = if metadata.is_dir() {
+            (FileType::Directory, metadata.len())
+        } else if is_config(path) {
+            (FileType::RegularFile, metadata.len())
+        } else {
+            (FileType::RegularFile, encrypted_size(metadata.len()))
+        };
+        let mtime = metadata.modified()?;
+        #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let ctime =
+            UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: metadata.accessed().unwrap_or(mtime),
+            mtime,
+            ctime,
+            crtime: metadata.created().unwrap_or(mtime),
+            kind,
+            // nothing can be changed
+            perm: (metadata.mode() & 0o555) as u16,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        })
+    }
+
+    /// [`None`] if it would be too long, or the name is not in UTF-8.
+    fn encrypt_name(&self, dir: &Path, name: &OsStr) -> Option<String> {
+        let name = name.to_str()?;
+        let sealed = crypto::encrypt_block_deterministic(
+            self.cipher,
+            &self.names_key,
+            &self.nonce_key,
+            dir.as_os_str().as_bytes(),
+            0,
+            name.as_bytes(),
+        );
+        let name = NAMES.encode(sealed);
+        (name.len() <= NAME_MAX).then_some(name)
+    }
+
+    /// [`None`] if it's not a name encrypted in `dir`.
+    fn decrypt_name(&self, dir: &Path, name: &OsStr) -> Option<OsString> {
+        let sealed = NAMES.decode(name.as_bytes()).ok()?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let mut plain = String::new();
+        crypto::create_read(&sealed[..], self.cipher, &self.names_key)
+            .read_to_string(&mut plain)
+            .ok()?;
+        // the nonce binds it to the dir, so a name moved to another dir is not taken
+        let plain = OsString::from(plain);
+        (self.encrypt_name(dir, &plain)?.as_bytes() == name.as_bytes()).then_some(plain)
+    }
+
+    /// Each file has its own key, so files with the same content at different paths are not the same encrypted.
+    fn file_key(&self, path: &Path) -> LockedVec {
+        derive(
+            &self.key,
+            "rencfs reverse file key",
+            path.as_os_str().as_bytes(),
+            self.cipher.key_len(),
+        )
+    }
+}
+
+/// Size of the content of a file encrypted, each block has a nonce and a tag.
+#[must_use]
+pub fn encrypted_size(size: u64) -> u64 {
+    let blocks = size.div_ceil(BLOCK_SIZE as u64);
+    size + blocks * (NONCE_LEN + TAG_LEN) as u64
+}
+
+fn derive(key: &SecretVec<u8>, context: &str, data: &[u8], len: usize) -> LockedVec {
+    let mut hasher = blake3::Hasher::new_derive_key(context);
+    hasher.update(key.expose_secret());
+    hasher.update(data);
+    let mut derived = vec![0; len];
+    hasher.finalize_xof().fill(&mut derived);
+    Locked::new(derived)
+}
+
+fn is_config(path: &Path) -> bool {
+    path == Path::new(REVERSE_CONFIG_FILENAME)
+}
+
+/// Symlinks and special files are not shown.
+fn is_shown(metadata: &Metadata) -> bool {
+    metadata.is_dir() || metadata.is_file()
+}
+
+/// Like [`FileExt::read_exact_at`], but it stops at the end of the file.
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read_at(&mut buf[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn setup(source: &Path) -> ReverseFs {
+        let password = SecretString::new("password".to_string());
+        ReverseFs::init(
+            source,
+            &password,
+            Cipher::ChaCha20Poly1305,
+            KdfParams::default(),
+        )
+        .unwrap();
+        ReverseFs::open(source, &password).unwrap()
+    }
+
+    /// What a copy tool would get from the view, into `copy`.
+    fn copy_view(fs: &ReverseFs, ino: u64, copy: &Path) {
+        fs::create_dir_all(copy).unwrap();
+        for (name, attr) in fs.read_dir(ino).unwrap() {
+            if attr.kind == FileType::Directory {
+                copy_view(fs, attr.ino, &copy.join(name));
+            } else {
+                #[allow(clippy::cast_possible_truncation)]
+                let data = fs.read(attr.ino, 0, attr.size as usize + 10).unwrap();
+                assert_eq!(data.len() as u64, attr.size);
+                File::create(copy.join(name))
+                    .unwrap()
+                    .write_all(&data)
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("docs")).unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 7).map(|i| (i % 251) as u8).collect();
+        fs::write(source.join("docs").join("big"), &data).unwrap();
+        fs::write(source.join("empty"), b"").unwrap();
+        let fs = setup(&source);
+
+        let copy = dir.path().join("copy");
+        copy_view(&fs, ROOT_INODE, &copy);
+        // nothing of the plaintext is in the names
+        assert!(!copy.join("docs").exists());
+        assert!(copy.join(REVERSE_CONFIG_FILENAME).is_file());
+
+        let out = dir.path().join("out");
+        let password = SecretString::new("password".to_string());
+        let report = ReverseFs::decrypt(&copy, &out, &password).unwrap();
+        assert_eq!(report.dirs, 1);
+        assert_eq!(report.files, 3);
+        assert!(report.skipped.is_empty());
+        assert_eq!(fs::read(out.join("docs").join("big")).unwrap(), data);
+        assert_eq!(fs::read(out.join("empty")).unwrap(), b"");
+
+        assert!(matches!(
+            ReverseFs::decrypt(&copy, &out, &SecretString::new("wrong".to_string())),
+            Err(FsError::InvalidPassword)
+        ));
+    }
+
+    #[test]
+    fn test_same_each_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 3).map(|i| (i % 13) as u8).collect();
+        fs::write(dir.path().join("file"), &data).unwrap();
+        let fs = setup(dir.path());
+        let entries = fs.read_dir(ROOT_INODE).unwrap();
+        let (name, attr) = entries
+            .iter()
+            .find(|(name, _)| name != REVERSE_CONFIG_FILENAME)
+            .unwrap();
+        #[allow(clippy::cast_possible_truncation)]
+        let whole = fs.read(attr.ino, 0, attr.size as usize).unwrap();
+
+        let fs = ReverseFs::open(dir.path(), &SecretString::new("password".to_string())).unwrap();
+        assert_eq!(fs.lookup(ROOT_INODE, name).unwrap().size, attr.size);
+        let ino = fs.lookup(ROOT_INODE, name).unwrap().ino;
+        #[allow(clippy::cast_possible_truncation)]
+        let again = fs.read(ino, 0, attr.size as usize).unwrap();
+        assert_eq!(whole, again);
+        // from the middle of a block
+        let offset = BLOCK_SIZE + 5;
+        assert_eq!(
+            fs.read(ino, offset as u64, 150).unwrap(),
+            whole[offset..offset + 150]
+        );
+
+        // only the block changed is different
+        let mut changed = data;
+        changed[BLOCK_SIZE + 1] ^= 1;
+        fs::write(dir.path().join("file"), &changed).unwrap();
+        #[allow(clippy::cast_possible_truncation)]
+        let after = fs.read(ino, 0, attr.size as usize).unwrap();
+        let block = BLOCK_SIZE + NONCE_LEN + TAG_LEN;
+        assert_eq!(after[..block], whole[..block]);
+        assert_ne!(after[block..block * 2], whole[block..block * 2]);
+        assert_eq!(after[block * 2..], whole[block * 2..]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        fs::write(dir.path().join("a").join("file"), b"a").unwrap();
+        fs::create_dir(dir.path().join("b")).unwrap();
+        fs::write(dir.path().join("b").join("file"), b"b").unwrap();
+        let fs = setup(dir.path());
+
+        let dirs = fs.read_dir(ROOT_INODE).unwrap();
+        let inos: Vec<_> = dirs
+            .iter()
+            .filter(|(_, attr)| attr.kind == FileType::Directory)
+            .map(|(_, attr)| attr.ino)
+            .collect();
+        assert_eq!(inos.len(), 2);
+        let (name_a, _) = fs.read_dir(inos[0]).unwrap().remove(0);
+        let (name_b, _) = fs.read_dir(inos[1]).unwrap().remove(0);
+        // the same name in different dirs
+        assert_ne!(name_a, name_b);
+        assert!(fs.lookup(inos[0], &name_a).is_ok());
+        assert!(matches!(
+            fs.lookup(inos[1], &name_a),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(matches!(
+            fs.lookup(ROOT_INODE, OsStr::new("file")),
+            Err(FsError::NotFound(_))
+        ));
+        assert!(fs
+            .lookup(ROOT_INODE, OsStr::new(REVERSE_CONFIG_FILENAME))
+            .is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+//! Access to the decrypted files over the network, without mounting them, for where FUSE can't be used.
+
+use std::collections::HashSet;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::encryptedfs::{EncryptedFs, FsResult};
+
+#[cfg(feature = "api")]
+mod api;
+mod nfs;
+mod p9;
+mod sftp;
+mod webdav;
+
+#[cfg(feature = "api")]
+#[allow(clippy::module_name_repetitions)]
+pub use api::serve_api;
+#[allow(clippy::module_name_repetitions)]
+pub use nfs::serve_nfs;
+#[allow(clippy::module_name_repetitions)]
+pub use p9::serve_9p;
+#[allow(clippy::module_name_repetitions)]
+pub use sftp::serve_sftp;
+#[allow(clippy::module_name_repetitions)]
+pub use webdav::serve_webdav;
+
+/// The first fd passed with systemd socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets from systemd socket activation not taken yet.
+static ACTIVATED: OnceLock<Mutex<Vec<RawFd>>> = OnceLock::new();
+
+/// Listen on `addr`, or take the socket systemd passed for it with socket activation, like from
+/// `ListenStream=127.0.0.1:4918`.
+pub(crate) async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    if let Some(listener) = take_activated(addr) {
+        listener.set_nonblocking(true)?;
+        info!(%addr, "using socket from systemd");
+        return TcpListener::from_std(listener);
+    }
+    TcpListener::bind(addr).await
+}
+
+/// The socket from systemd listening on `addr`, if any.
+fn take_activated(addr: SocketAddr) -> Option<std::net::TcpListener> {
+    let mut fds = ACTIVATED
+        .get_or_init(|| Mutex::new(listen_fds()))
+        .lock()
+        .unwrap();
+    let i = fds.iter().position(|fd| {
+        // SAFETY: it's open, we only look at it
+        let listener = ManuallyDrop::new(unsafe { std::net::TcpListener::from_raw_fd(*fd) });
+        listener.local_addr().is_ok_and(|local| local == addr)
+    })?;
+    // SAFETY: it's ours from now on, no other one takes it
+    Some(unsafe { std::net::TcpListener::from_raw_fd(fds.remove(i)) })
+}
+
+/// The fds systemd passed to us, see `sd_listen_fds(3)`.
+fn listen_fds() -> Vec<RawFd> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .filter(|_| for_us)
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: it's open as systemd passed it, commands we run must not get it
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            let _ = set_cloexec(&listener);
+            listener.into_raw_fd()
+        })
+        .collect()
+}
+
+fn set_cloexec(listener: &std::net::TcpListener) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the fd is valid
+    if unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Handles opened by all connections, so they're released when it stops, even if the client didn't close them.
+#[derive(Clone, Default)]
+struct OpenHandles(Arc<Mutex<HashSet<u64>>>);
+
+impl OpenHandles {
+    fn add(&self, handle: u64) {
+        self.0.lock().unwrap().insert(handle);
+    }
+
+    /// Only if it wasn't released already.
+    async fn release(&self, fs: &EncryptedFs, handle: u64) -> FsResult<()> {
+        if self.0.lock().unwrap().remove(&handle) {
+            fs.release(handle).await?;
+        }
+        Ok(())
+    }
+
+    async fn release_all(&self, fs: &EncryptedFs) {
+        let handles: Vec<u64> = self.0.lock().unwrap().drain().collect();
+        for handle in handles {
+            if let Err(err) = fs.release(handle).await {
+                error!(err = %err, "releasing file");
+            }
+        }
+    }
+}
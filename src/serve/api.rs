@@ -0,0 +1,497 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures_util::stream;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, error};
+
+use crate::encryptedfs::{EncryptedFile, EncryptedFs, FileAttr, FileType, FsError};
+use crate::serve::bind;
+
+/// Files are read in parts this big.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+type Body = UnsyncBoxBody<Bytes, io::Error>;
+
+/// Serve the files over an HTTP API on `addr`, until `shutdown` completes. Requests need the header
+/// `Authorization: Bearer <token>`. The paths are from the root of the volume:
+///
+/// - `GET /v1/stat/<path>`, the attributes as JSON.
+/// - `GET /v1/list/<path>`, the entries of the dir as JSON.
+/// - `GET /v1/files/<path>?offset=&length=`, the content of the file, streamed.
+/// - `PUT /v1/files/<path>?offset=`, write the body to the file, streamed. It's created if it doesn't exist, and
+///   truncated first unless `offset` is given.
+/// - `POST /v1/dirs/<path>`, create the dir.
+/// - `DELETE /v1/files/<path>`, remove the file or the empty dir.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub async fn serve_api(
+    fs: Arc<EncryptedFs>,
+    addr: SocketAddr,
+    token: SecretString,
+    shutdown: impl Future<Output = ()> + Send,
+) -> io::Result<()> {
+    let listener = bind(addr).await?;
+    let api = Arc::new(Api { fs, token });
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            () = &mut shutdown => return Ok(()),
+        };
+        debug!(%peer, "api connection");
+        let api = api.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let api = api.clone();
+                async move { Ok::<_, Infallible>(api.handle(req).await) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(%peer, err = %err, "api connection failed");
+            }
+        });
+    }
+}
+
+struct Api {
+    fs: Arc<EncryptedFs>,
+    token: SecretString,
+}
+
+#[derive(Serialize)]
+struct Stat {
+    ino: u64,
+    kind: &'static str,
+    size: u64,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    /// Seconds since the epoch.
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    #[serde(flatten)]
+    stat: Stat,
+}
+
+/// Sent with the status when a request fails.
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+impl Api {
+    async fn handle(&self, req: Request<Incoming>) -> Response<Body> {
+        if !self.authorized(&req) {
+            return error(
+                StatusCode::UNAUTHORIZED,
+                "missing or wrong token".to_string(),
+            );
+        }
+        let uri = req.uri().clone();
+        let query = Query(uri.query().unwrap_or_default());
+        let Some((kind, path)) = uri
+            .path()
+            .strip_prefix("/v1/")
+            .map(|rest| rest.split_once('/').unwrap_or((rest, "")))
+        else {
+            return error(StatusCode::NOT_FOUND, "unknown endpoint".to_string());
+        };
+        let Ok(path) = percent_decode_str(path).decode_utf8() else {
+            return error(StatusCode::BAD_REQUEST, "path is not UTF-8".to_string());
+        };
+        let path = path.into_owned();
+        let res = match (req.method(), kind) {
+            (&Method::GET, "stat") => self.stat(&path).await,
+            (&Method::GET, "list") => self.list(&path).await,
+            (&Method::GET, "files") => self.read(&path, &query).await,
+            (&Method::PUT, "files") => self.write(&path, &query, req.into_body()).await,
+            (&Method::POST, "dirs") => self.create_dir(&path).await,
+            (&Method::DELETE, "files") => self.remove(&path).await,
+            _ => return error(StatusCode::NOT_FOUND, "unknown endpoint".to_string()),
+        };
+        res.unwrap_or_else(|(status, message)| error(status, message))
+    }
+
+    fn authorized(&self, req: &Request<Incoming>) -> bool {
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                ring::constant_time::verify_slices_are_equal(
+                    token.as_bytes(),
+                    self.token.expose_secret().as_bytes(),
+                )
+                .is_ok()
+            })
+    }
+
+    async fn stat(&self, path: &str) -> ApiResult {
+        let attr = self.fs.metadata(path).await.map_err(api_error)?;
+        Ok(json(&stat(&attr)))
+    }
+
+    async fn list(&self, path: &str) -> ApiResult {
+        let entries: Vec<_> = self
+            .fs
+            .read_dir_path(path)
+            .await
+            .map_err(api_error)?
+            .into_iter()
+            .map(|entry| Entry {
+                name: entry.name.expose_secret().clone(),
+                stat: stat(&entry.attr),
+            })
+            .collect();
+        Ok(json(&entries))
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn read(&self, path: &str, query: &Query<'_>) -> ApiResult {
+        let attr = self.fs.resolve_path(path).await.map_err(api_error)?;
+        if attr.kind == FileType::Directory {
+            return Err((StatusCode::BAD_REQUEST, "is a dir".to_string()));
+        }
+        let offset = query.u64("offset")?.unwrap_or(0).min(attr.size);
+        let length = query
+            .u64("length")?
+            .unwrap_or(u64::MAX)
+            .min(attr.size - offset);
+        let mut file = EncryptedFile::from_ino(self.fs.clone(), attr.ino, false)
+            .await
+            .map_err(api_error)?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let chunks = stream::unfold(file.take(length), |mut reader| async move {
+            let mut buf = vec![0; CHUNK_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(len) => {
+                    buf.truncate(len);
+                    Some((Ok(Frame::data(Bytes::from(buf))), reader))
+                }
+                Err(err) => {
+                    error!(err = %err, "reading file");
+                    Some((Err(err), reader.into_inner().take(0)))
+                }
+            }
+        });
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, length)
+            .body(BodyExt::boxed_unsync(StreamBody::new(chunks)))
+            .unwrap())
+    }
+
+    async fn write(&self, path: &str, query: &Query<'_>, mut body: Incoming) -> ApiResult {
+        let offset = query.u64("offset")?;
+        let attr = match self.fs.resolve_path(path).await {
+            Ok(attr) if attr.kind == FileType::Directory => {
+                return Err((StatusCode::BAD_REQUEST, "is a dir".to_string()));
+            }
+            Ok(attr) => attr,
+            Err(FsError::NotFound(_)) => self.fs.create_file_path(path).await.map_err(api_error)?,
+            Err(err) => return Err(api_error(err)),
+        };
+        if offset.is_none() {
+            self.fs.set_len(attr.ino, 0).await.map_err(api_error)?;
+        }
+        let handle = self
+            .fs
+            .open(attr.ino, false, true)
+            .await
+            .map_err(api_error)?;
+        let res = async {
+            let mut offset = offset.unwrap_or(0);
+            while let Some(frame) = body.frame().await {
+                let frame = frame.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+                let Ok(data) = frame.into_data() else {
+                    continue;
+                };
+                let mut written = 0;
+                while written < data.len() {
+                    written += self
+                        .fs
+                        .write(attr.ino, offset + written as u64, &data[written..], handle)
+                        .await
+                        .map_err(api_error)?;
+                }
+                offset += data.len() as u64;
+            }
+            Ok::<_, (StatusCode, String)>(())
+        }
+        .await;
+        // saved when it's released
+        let released = self.fs.release(handle).await.map_err(api_error);
+        res?;
+        released?;
+        self.stat(path).await
+    }
+
+    async fn create_dir(&self, path: &str) -> ApiResult {
+        let attr = self.fs.create_dir_path(path).await.map_err(api_error)?;
+        Ok(json(&stat(&attr)))
+    }
+
+    async fn remove(&self, path: &str) -> ApiResult {
+        self.fs.remove_path(path).await.map_err(api_error)?;
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(empty())
+            .unwrap())
+    }
+}
+
+type ApiResult = Result<Response<Body>, (StatusCode, String)>;
+
+/// Parameters after `?` in the URL.
+struct Query<'a>(&'a str);
+
+impl Query<'_> {
+    fn u64(&self, name: &str) -> Result<Option<u64>, (StatusCode, String)> {
+        self.0
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| {
+                value
+                    .parse()
+                    .map_err(|_| (StatusCode::BAD_REQUEST, format!("invalid {name}")))
+            })
+            .transpose()
+    }
+}
+
+fn stat(attr: &FileAttr) -> Stat {
+    Stat {
+        ino: attr.ino,
+        kind: if attr.kind == FileType::Directory {
+            "dir"
+        } else {
+            "file"
+        },
+        size: attr.size,
+        perm: attr.perm,
+        uid: attr.uid,
+        gid: attr.gid,
+        atime: secs(attr.atime),
+        mtime: secs(attr.mtime),
+        ctime: secs(attr.ctime),
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn json(value: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, error: String) -> Response<Body> {
+    let mut res = json(&ApiError { error });
+    *res.status_mut() = status;
+    res
+}
+
+fn full(data: Vec<u8>) -> Body {
+    Full::new(Bytes::from(data))
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+fn empty() -> Body {
+    full(vec![])
+}
+
+fn api_error(err: FsError) -> (StatusCode, String) {
+    let message = err.to_string();
+    let status = match err {
+        FsError::NotFound(_) | FsError::InodeNotFound => StatusCode::NOT_FOUND,
+        FsError::AlreadyExists | FsError::NotEmpty => StatusCode::CONFLICT,
+        FsError::ReadOnly | FsError::Excluded => StatusCode::FORBIDDEN,
+        FsError::InvalidInodeType | FsError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        FsError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        FsError::MaxFilesizeExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        err => {
+            error!(err = %err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::test_common::{get_fs, run_test, TestSetup};
+
+    const TOKEN: &str = "secret";
+
+    async fn request(
+        addr: SocketAddr,
+        method: &str,
+        uri: &str,
+        token: &str,
+        body: &[u8],
+    ) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = format!(
+            "{method} {uri} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut res = vec![];
+        stream.read_to_end(&mut res).await.unwrap();
+        let end = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&res[9..12]).unwrap().parse().unwrap();
+        (status, res[end + 4..].to_vec())
+    }
+
+    fn value(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        run_test(TestSetup { key: "test_api" }, async {
+            let fs = get_fs().await;
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+            let server = tokio::spawn(serve_api(
+                fs.clone(),
+                addr,
+                SecretString::from(TOKEN.to_string()),
+                async {
+                    let _ = stopped.await;
+                },
+            ));
+            while TcpStream::connect(addr).await.is_err() {
+                tokio::task::yield_now().await;
+            }
+
+            assert_eq!(request(addr, "GET", "/v1/list/", "wrong", b"").await.0, 401);
+
+            let (status, body) = request(addr, "POST", "/v1/dirs/docs", TOKEN, b"").await;
+            assert_eq!(status, 200);
+            assert_eq!(value(&body)["kind"], "dir");
+            assert_eq!(
+                request(addr, "POST", "/v1/dirs/docs", TOKEN, b"").await.0,
+                409
+            );
+
+            let (status, body) = request(
+                addr,
+                "PUT",
+                "/v1/files/docs/a%20b.txt",
+                TOKEN,
+                b"hello world",
+            )
+            .await;
+            assert_eq!(status, 200);
+            assert_eq!(value(&body)["size"], 11);
+            let (status, body) = request(
+                addr,
+                "PUT",
+                "/v1/files/docs/a%20b.txt?offset=6",
+                TOKEN,
+                b"there",
+            )
+            .await;
+            assert_eq!(status, 200);
+            assert_eq!(value(&body)["size"], 11);
+
+            let (status, body) = request(addr, "GET", "/v1/files/docs/a%20b.txt", TOKEN, b"").await;
+            assert_eq!(status, 200);
+            assert_eq!(body, b"hello there");
+            let (status, body) = request(
+                addr,
+                "GET",
+                "/v1/files/docs/a%20b.txt?offset=6&length=3",
+                TOKEN,
+                b"",
+            )
+            .await;
+            assert_eq!(status, 200);
+            assert_eq!(body, b"the");
+
+            let (status, body) = request(addr, "GET", "/v1/list/docs", TOKEN, b"").await;
+            assert_eq!(status, 200);
+            let entries = value(&body);
+            assert_eq!(entries.as_array().unwrap().len(), 1);
+            assert_eq!(entries[0]["name"], "a b.txt");
+            assert_eq!(entries[0]["kind"], "file");
+
+            let (status, body) = request(addr, "GET", "/v1/stat/docs/a%20b.txt", TOKEN, b"").await;
+            assert_eq!(status, 200);
+            assert_eq!(value(&body)["size"], 11);
+            assert_eq!(
+                request(addr, "GET", "/v1/stat/missing", TOKEN, b"").await.0,
+                404
+            );
+
+            assert_eq!(
+                request(addr, "DELETE", "/v1/files/docs", TOKEN, b"")
+                    .await
+                    .0,
+                409
+            );
+            assert_eq!(
+                request(addr, "DELETE", "/v1/files/docs/a%20b.txt", TOKEN, b"")
+                    .await
+                    .0,
+                204
+            );
+            assert_eq!(
+                request(addr, "DELETE", "/v1/files/docs", TOKEN, b"")
+                    .await
+                    .0,
+                204
+            );
+            let (_, body) = request(addr, "GET", "/v1/list/", TOKEN, b"").await;
+            assert_eq!(value(&body), serde_json::json!([]));
+
+            stop.send(()).unwrap();
+            server.await.unwrap().unwrap();
+        })
+        .await;
+    }
+}
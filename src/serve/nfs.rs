@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use nfsserve::nfs::{
+    fattr3, fileid3, filename3, ftype3, nfspath3, nfsstat3, nfstime3, sattr3, set_atime, set_gid3,
+    set_mode3, set_mtime, set_size3, set_uid3, specdata3,
+};
+use nfsserve::tcp::{NFSTcp, NFSTcpListener};
+use nfsserve::vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, error};
+
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, SetFileAttr, ROOT_INODE,
+};
+
+/// Files not used for this long are released, so what was written to them is saved.
+const IDLE: Duration = Duration::from_secs(2);
+
+/// Export the files over `NFSv3` on `addr`, an IPv4 address, until `shutdown` completes. Files are identified by their
+/// inodes. There is no authentication, anyone who can connect can read and change the files, so listen on
+/// localhost.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub async fn serve_nfs(
+    fs: Arc<EncryptedFs>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send,
+) -> io::Result<()> {
+    if addr.is_ipv6() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only IPv4 addresses are supported",
+        ));
+    }
+    let nfs = NfsFs {
+        fs,
+        files: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let listener = NFSTcpListener::bind(&addr.to_string(), nfs.clone()).await?;
+    let res = tokio::select! {
+        res = listener.handle_forever() => res,
+        () = nfs.close_idle() => Ok(()),
+        () = shutdown => Ok(()),
+    };
+    nfs.close_all().await;
+    res
+}
+
+/// NFS has no open and close, files are opened when they're first read or written, and kept open until they're not
+/// used for a while.
+#[derive(Clone)]
+struct NfsFs {
+    fs: Arc<EncryptedFs>,
+    files: Arc<Mutex<HashMap<u64, Arc<tokio::sync::Mutex<OpenFile>>>>>,
+}
+
+struct OpenFile {
+    handle: Option<u64>,
+    write: bool,
+    /// Written since it was opened.
+    dirty: bool,
+    used: Instant,
+}
+
+impl NfsFs {
+    fn file(&self, ino: u64) -> Arc<tokio::sync::Mutex<OpenFile>> {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_insert_with(|| {
+                Arc::new(tokio::sync::Mutex::new(OpenFile {
+                    handle: None,
+                    write: false,
+                    dirty: false,
+                    used: Instant::now(),
+                }))
+            })
+            .clone()
+    }
+
+    /// Handle of the file, opened for write too if `write`.
+    async fn handle(&self, ino: u64, file: &mut OpenFile, write: bool) -> FsResult<u64> {
+        file.used = Instant::now();
+        match file.handle {
+            Some(handle) if file.write || !write => return Ok(handle),
+            Some(handle) => {
+                file.handle = None;
+                self.fs.release(handle).await?;
+            }
+            None => {}
+        }
+        let handle = self.fs.open(ino, true, write).await?;
+        file.handle = Some(handle);
+        file.write = write;
+        file.dirty = false;
+        Ok(handle)
+    }
+
+    /// Release the file if it's open, before it's changed other than by writing to it.
+    async fn close(&self, ino: u64) -> FsResult<()> {
+        let file = self.files.lock().unwrap().get(&ino).cloned();
+        if let Some(file) = file {
+            let handle = file.lock().await.handle.take();
+            if let Some(handle) = handle {
+                self.fs.release(handle).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn close_idle(&self) {
+        loop {
+            tokio::time::sleep(IDLE / 2).await;
+            let files: Vec<_> = self.files.lock().unwrap().values().cloned().collect();
+            for file in files {
+                // in use
+                let Ok(mut file) = file.try_lock() else {
+                    continue;
+                };
+                if file.used.elapsed() < IDLE {
+                    continue;
+                }
+                if let Some(handle) = file.handle.take() {
+                    if let Err(err) = self.fs.release(handle).await {
+                        error!(err = %err, "releasing file");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn close_all(&self) {
+        let files: Vec<_> = self.files.lock().unwrap().drain().collect();
+        for (_, file) in files {
+            let handle = file.lock().await.handle.take();
+            if let Some(handle) = handle {
+                if let Err(err) = self.fs.release(handle).await {
+                    error!(err = %err, "releasing file");
+                }
+            }
+        }
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        kind: FileType,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let mut create_attr = CreateFileAttr {
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            uid: *crate::UID,
+            gid: *crate::GID,
+            rdev: 0,
+            flags: 0,
+        };
+        if let set_mode3::mode(mode) = attr.mode {
+            create_attr.perm = perm(mode);
+        }
+        if let set_uid3::uid(uid) = attr.uid {
+            create_attr.uid = uid;
+        }
+        if let set_gid3::gid(gid) = attr.gid {
+            create_attr.gid = gid;
+        }
+        let (_, attr) = self
+            .fs
+            .create(dirid, &name(filename)?, create_attr, false, false)
+            .await
+            .map_err(nfs_error)?;
+        Ok((attr.ino, fattr(&attr)))
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for NfsFs {
+    fn capabilities(&self) -> VFSCapabilities {
+        if self.fs.is_read_only() {
+            VFSCapabilities::ReadOnly
+        } else {
+            VFSCapabilities::ReadWrite
+        }
+    }
+
+    fn root_dir(&self) -> fileid3 {
+        ROOT_INODE
+    }
+
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        if !self.fs.is_dir(dirid) {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        let name = name(filename)?;
+        if name.expose_secret() == "." {
+            return Ok(dirid);
+        }
+        let attr = self
+            .fs
+            .find_by_name(dirid, &name)
+            .await
+            .map_err(nfs_error)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        Ok(attr.ino)
+    }
+
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let attr = self.fs.get_attr(id).await.map_err(nfs_error)?;
+        Ok(fattr(&attr))
+    }
+
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        // else it would save the times it has when it's released
+        self.close(id).await.map_err(nfs_error)?;
+        if let set_size3::size(size) = setattr.size {
+            self.fs.set_len(id, size).await.map_err(nfs_error)?;
+        }
+        let mut set_attr = SetFileAttr::default();
+        if let set_mode3::mode(mode) = setattr.mode {
+            set_attr = set_attr.with_perm(perm(mode));
+        }
+        if let set_uid3::uid(uid) = setattr.uid {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if let set_gid3::gid(gid) = setattr.gid {
+            set_attr = set_attr.with_gid(gid);
+        }
+        match setattr.atime {
+            set_atime::DONT_CHANGE => {}
+            set_atime::SET_TO_SERVER_TIME => set_attr = set_attr.with_atime(SystemTime::now()),
+            set_atime::SET_TO_CLIENT_TIME(time) => {
+                set_attr = set_attr.with_atime(system_time(time));
+            }
+        }
+        match setattr.mtime {
+            set_mtime::DONT_CHANGE => {}
+            set_mtime::SET_TO_SERVER_TIME => set_attr = set_attr.with_mtime(SystemTime::now()),
+            set_mtime::SET_TO_CLIENT_TIME(time) => {
+                set_attr = set_attr.with_mtime(system_time(time));
+            }
+        }
+        self.fs.set_attr(id, set_attr).await.map_err(nfs_error)?;
+        self.getattr(id).await
+    }
+
+    async fn read(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        if self.fs.is_dir(id) {
+            return Err(nfsstat3::NFS3ERR_ISDIR);
+        }
+        let file = self.file(id);
+        let mut file = file.lock().await;
+        if file.dirty {
+            // the handle reads what the file had when it was opened
+            if let Some(handle) = file.handle.take() {
+                self.fs.release(handle).await.map_err(nfs_error)?;
+            }
+        }
+        let handle = self.handle(id, &mut file, false).await.map_err(nfs_error)?;
+        let mut buf = vec![0; count as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            let len = self
+                .fs
+                .read(id, offset + read as u64, &mut buf[read..], handle)
+                .await
+                .map_err(nfs_error)?;
+            if len == 0 {
+                break;
+            }
+            read += len;
+        }
+        buf.truncate(read);
+        let size = self.fs.get_attr(id).await.map_err(nfs_error)?.size;
+        Ok((buf, offset + read as u64 >= size))
+    }
+
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        if self.fs.is_dir(id) {
+            return Err(nfsstat3::NFS3ERR_ISDIR);
+        }
+        let file = self.file(id);
+        let mut file = file.lock().await;
+        let handle = self.handle(id, &mut file, true).await.map_err(nfs_error)?;
+        let mut written = 0;
+        while written < data.len() {
+            written += self
+                .fs
+                .write(id, offset + written as u64, &data[written..], handle)
+                .await
+                .map_err(nfs_error)?;
+        }
+        file.dirty = true;
+        drop(file);
+        self.getattr(id).await
+    }
+
+    async fn create(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        let (id, _) = self
+            .create(dirid, filename, FileType::RegularFile, &attr)
+            .await?;
+        if let set_size3::size(size) = attr.size {
+            if size > 0 {
+                self.fs.set_len(id, size).await.map_err(nfs_error)?;
+            }
+        }
+        Ok((id, self.getattr(id).await?))
+    }
+
+    async fn create_exclusive(
+        &self,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        let (id, _) = self
+            .create(dirid, filename, FileType::RegularFile, &sattr3::default())
+            .await?;
+        Ok(id)
+    }
+
+    async fn mkdir(
+        &self,
+        dirid: fileid3,
+        dirname: &filename3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.create(dirid, dirname, FileType::Directory, &sattr3::default())
+            .await
+    }
+
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let name = name(filename)?;
+        let attr = self
+            .fs
+            .find_by_name(dirid, &name)
+            .await
+            .map_err(nfs_error)?
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        if attr.kind == FileType::Directory {
+            self.fs.remove_dir(dirid, &name).await.map_err(nfs_error)
+        } else {
+            self.close(attr.ino).await.map_err(nfs_error)?;
+            self.files.lock().unwrap().remove(&attr.ino);
+            self.fs.remove_file(dirid, &name).await.map_err(nfs_error)
+        }
+    }
+
+    async fn rename(
+        &self,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        let to_name = name(to_filename)?;
+        // it's replaced
+        if let Some(attr) = self
+            .fs
+            .find_by_name(to_dirid, &to_name)
+            .await
+            .map_err(nfs_error)?
+        {
+            self.close(attr.ino).await.map_err(nfs_error)?;
+        }
+        self.fs
+            .rename(from_dirid, &name(from_filename)?, to_dirid, &to_name)
+            .await
+            .map_err(nfs_error)
+    }
+
+    async fn readdir(
+        &self,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        if !self.fs.is_dir(dirid) {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        let mut entries = vec![];
+        for entry in self.fs.read_dir_plus(dirid).await.map_err(nfs_error)? {
+            let entry = entry.map_err(nfs_error)?;
+            let name = entry.name.expose_secret();
+            if name != "." && name != ".." {
+                entries.push(DirEntry {
+                    fileid: entry.ino,
+                    name: name.as_bytes().into(),
+                    attr: fattr(&entry.attr),
+                });
+            }
+        }
+        // so it continues after `start_after` even if entries were added or removed meanwhile
+        entries.sort_by_key(|entry| entry.fileid);
+        entries.retain(|entry| entry.fileid > start_after);
+        let end = entries.len() <= max_entries;
+        entries.truncate(max_entries);
+        debug!(dirid, len = entries.len(), end, "readdir");
+        Ok(ReadDirResult { entries, end })
+    }
+
+    async fn symlink(
+        &self,
+        _dirid: fileid3,
+        _linkname: &filename3,
+        _symlink: &nfspath3,
+        _attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    }
+
+    async fn readlink(&self, _id: fileid3) -> Result<nfspath3, nfsstat3> {
+        Err(nfsstat3::NFS3ERR_INVAL)
+    }
+}
+
+fn name(filename: &filename3) -> Result<SecretString, nfsstat3> {
+    let name = String::from_utf8(filename.0.clone()).map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+    Ok(SecretString::from(name))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn perm(mode: u32) -> u16 {
+    (mode & 0o7777) as u16
+}
+
+fn fattr(attr: &FileAttr) -> fattr3 {
+    fattr3 {
+        ftype: if attr.kind == FileType::Directory {
+            ftype3::NF3DIR
+        } else {
+            ftype3::NF3REG
+        },
+        mode: u32::from(attr.perm),
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size,
+        used: attr.size,
+        rdev: specdata3::default(),
+        fsid: 0,
+        fileid: attr.ino,
+        atime: nfs_time(attr.atime),
+        mtime: nfs_time(attr.mtime),
+        ctime: nfs_time(attr.ctime),
+    }
+}
+
+fn nfs_time(time: SystemTime) -> nfstime3 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nfstime3 {
+        seconds: u32::try_from(since_epoch.as_secs()).unwrap_or(u32::MAX),
+        nseconds: since_epoch.subsec_nanos(),
+    }
+}
+
+fn system_time(time: nfstime3) -> SystemTime {
+    UNIX_EPOCH + Duration::new(u64::from(time.seconds), time.nseconds)
+}
+
+fn nfs_error(err: FsError) -> nfsstat3 {
+    match err {
+        FsError::NotFound(_) | FsError::InodeNotFound => nfsstat3::NFS3ERR_NOENT,
+        FsError::AlreadyExists => nfsstat3::NFS3ERR_EXIST,
+        FsError::NotEmpty => nfsstat3::NFS3ERR_NOTEMPTY,
+        FsError::ReadOnly => nfsstat3::NFS3ERR_ROFS,
+        FsError::Excluded => nfsstat3::NFS3ERR_PERM,
+        FsError::QuotaExceeded(_) => nfsstat3::NFS3ERR_NOSPC,
+        FsError::MaxFilesizeExceeded(_) => nfsstat3::NFS3ERR_FBIG,
+        FsError::InvalidInput(_) | FsError::InvalidInodeType => nfsstat3::NFS3ERR_INVAL,
+        err => {
+            error!(err = %err);
+            nfsstat3::NFS3ERR_IO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_common::{get_fs, run_test, TestSetup};
+
+    fn nfs_name(name: &str) -> filename3 {
+        name.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn test_nfs() {
+        run_test(TestSetup { key: "test_nfs" }, async {
+            let nfs = NfsFs {
+                fs: get_fs().await,
+                files: Arc::new(Mutex::new(HashMap::new())),
+            };
+
+            let (dir, attr) = nfs.mkdir(ROOT_INODE, &nfs_name("docs")).await.unwrap();
+            assert!(matches!(attr.ftype, ftype3::NF3DIR));
+            assert_eq!(
+                nfs.lookup(ROOT_INODE, &nfs_name("docs")).await.unwrap(),
+                dir
+            );
+            let (file, _) = NFSFileSystem::create(&nfs, dir, &nfs_name("a.txt"), sattr3::default())
+                .await
+                .unwrap();
+            nfs.write(file, 0, b"hello ").await.unwrap();
+            let attr = nfs.write(file, 6, b"world").await.unwrap();
+            assert_eq!(attr.size, 11);
+            // what's written is seen before the file is released
+            assert_eq!(
+                nfs.read(file, 0, 100).await.unwrap(),
+                (b"hello world".to_vec(), true)
+            );
+            assert_eq!(nfs.read(file, 6, 2).await.unwrap(), (b"wo".to_vec(), false));
+
+            let attr = nfs
+                .setattr(
+                    file,
+                    sattr3 {
+                        size: set_size3::size(5),
+                        mode: set_mode3::mode(0o600),
+                        ..sattr3::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!((attr.size, attr.mode), (5, 0o600));
+
+            let (other, _) =
+                NFSFileSystem::create(&nfs, dir, &nfs_name("b.txt"), sattr3::default())
+                    .await
+                    .unwrap();
+            let entries = nfs.readdir(dir, 0, 1).await.unwrap();
+            assert_eq!(entries.entries.len(), 1);
+            assert!(!entries.end);
+            let rest = nfs
+                .readdir(dir, entries.entries[0].fileid, 10)
+                .await
+                .unwrap();
+            assert_eq!(rest.entries.len(), 1);
+            assert!(rest.end);
+            let mut all = vec![entries.entries[0].fileid, rest.entries[0].fileid];
+            all.sort_unstable();
+            assert_eq!(all, vec![file.min(other), file.max(other)]);
+
+            nfs.rename(dir, &nfs_name("a.txt"), dir, &nfs_name("b.txt"))
+                .await
+                .unwrap();
+            assert_eq!(nfs.lookup(dir, &nfs_name("b.txt")).await.unwrap(), file);
+            assert!(matches!(
+                nfs.remove(ROOT_INODE, &nfs_name("docs")).await,
+                Err(nfsstat3::NFS3ERR_NOTEMPTY)
+            ));
+            nfs.remove(dir, &nfs_name("b.txt")).await.unwrap();
+            nfs.remove(ROOT_INODE, &nfs_name("docs")).await.unwrap();
+            assert!(matches!(
+                nfs.lookup(ROOT_INODE, &nfs_name("docs")).await,
+                Err(nfsstat3::NFS3ERR_NOENT)
+            ));
+            nfs.close_all().await;
+        })
+        .await;
+    }
+}
@@ -0,0 +1,961 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::raw::c_int;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use secrecy::{ExposeSecret, SecretString};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tracing::{debug, error};
+
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, SetFileAttr, ROOT_INODE,
+};
+use crate::serve::{bind, OpenHandles};
+
+const VERSION: &str = "9P2000.L";
+/// Largest message, clients can ask for less.
+const MAX_MSIZE: u32 = 1024 * 1024;
+/// Header of `Rread` and `Twrite`, what's left of `msize` is for data.
+const IO_HEADER_SIZE: u32 = 24;
+
+// types of the requests, the reply is the next one
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const AT_REMOVEDIR: u32 = 0x200;
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+const V9FS_MAGIC: u32 = 0x0102_1997;
+const BLOCK_SIZE: u32 = 4096;
+
+// errno and open flags are sent with their values on Linux, whatever the platform we run on
+const EPERM: c_int = 1;
+const ENOENT: c_int = 2;
+const EIO: c_int = 5;
+const EBADF: c_int = 9;
+const EEXIST: c_int = 17;
+const ENOTDIR: c_int = 20;
+const EISDIR: c_int = 21;
+const EINVAL: c_int = 22;
+const EFBIG: c_int = 27;
+const ENOSPC: c_int = 28;
+const EROFS: c_int = 30;
+const ENOTEMPTY: c_int = 39;
+const EOPNOTSUPP: c_int = 95;
+const O_RDONLY: c_int = 0;
+const O_ACCMODE: c_int = 0o3;
+const O_TRUNC: c_int = 0o1000;
+const O_APPEND: c_int = 0o2000;
+
+/// All that `Tgetattr` can ask for, without `btime`, `gen` and `data_version`.
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Errors are sent as `errno`.
+type P9Result<T> = Result<T, c_int>;
+
+/// Export the files over `9P2000.L` on `addr`, until `shutdown` completes, for VMs and WSL to mount them with the 9p
+/// driver of their kernel. Files are identified by their inodes. There is no authentication, anyone who can connect
+/// can read and change the files, so listen on an address only the guests can reach.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub async fn serve_9p(
+    fs: Arc<EncryptedFs>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send,
+) -> io::Result<()> {
+    let listener = bind(addr).await?;
+    let open = OpenHandles::default();
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            () = &mut shutdown => break,
+        };
+        debug!(%peer, "9p connection");
+        let session = P9Session::new(fs.clone(), open.clone());
+        connections.spawn(async move {
+            if let Err(err) = session.run(stream).await {
+                debug!(%peer, err = %err, "9p connection failed");
+            }
+        });
+        // forget the ones that ended
+        while connections.try_join_next().is_some() {}
+    }
+    connections.shutdown().await;
+    open.release_all(&fs).await;
+    Ok(())
+}
+
+/// What a fid of the client points to.
+struct Fid {
+    ino: u64,
+    /// The dir it was walked from and the name in it, to rename or remove it.
+    parent: Option<(u64, SecretString)>,
+    /// After it's opened, only for files.
+    handle: Option<u64>,
+    flags: c_int,
+    /// Written since it was opened.
+    dirty: bool,
+    /// Listed on the first read, so the offsets stay the same while it's read.
+    entries: Option<Vec<(FileAttr, String)>>,
+}
+
+impl Fid {
+    const fn new(ino: u64, parent: Option<(u64, SecretString)>) -> Self {
+        Self {
+            ino,
+            parent,
+            handle: None,
+            flags: O_RDONLY,
+            dirty: false,
+            entries: None,
+        }
+    }
+
+    const fn can_write(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+}
+
+/// Fids are per connection, requests are handled one after the other.
+struct P9Session {
+    fs: Arc<EncryptedFs>,
+    open: OpenHandles,
+    fids: HashMap<u32, Fid>,
+    msize: u32,
+}
+
+impl P9Session {
+    fn new(fs: Arc<EncryptedFs>, open: OpenHandles) -> Self {
+        Self {
+            fs,
+            open,
+            fids: HashMap::new(),
+            msize: MAX_MSIZE,
+        }
+    }
+
+    async fn run(mut self, mut stream: impl AsyncRead + AsyncWrite + Unpin) -> io::Result<()> {
+        let res = self.serve(&mut stream).await;
+        self.clunk_all().await;
+        res
+    }
+
+    async fn serve(
+        &mut self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        loop {
+            let size = match stream.read_u32_le().await {
+                Ok(size) => size,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            if !(7..=self.msize).contains(&size) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message size"));
+            }
+            let mut msg = vec![0; size as usize - 4];
+            stream.read_exact(&mut msg).await?;
+            let tag = u16::from_le_bytes([msg[1], msg[2]]);
+            let (kind, body) = match self.handle(msg[0], &mut Decoder(&msg[3..])).await {
+                Ok(reply) => reply,
+                Err(errno) => (RLERROR, Encoder::default().u32(errno.unsigned_abs())),
+            };
+            let size = u32::try_from(body.0.len() + 7)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "reply size"))?;
+            let mut reply = Encoder::default().u32(size).u8(kind).u16(tag);
+            reply.0.extend_from_slice(&body.0);
+            stream.write_all(&reply.0).await?;
+        }
+    }
+
+    /// The type and body of the reply.
+    async fn handle(&mut self, kind: u8, msg: &mut Decoder<'_>) -> P9Result<(u8, Encoder)> {
+        let reply = Encoder::default();
+        let reply = match kind {
+            TVERSION => self.version(msg, reply).await?,
+            TATTACH => {
+                let fid = msg.u32()?;
+                let attr = self.fs.get_attr(ROOT_INODE).await.map_err(errno)?;
+                self.insert_fid(fid, Fid::new(ROOT_INODE, None))?;
+                reply.qid(&attr)
+            }
+            // what it flushes was already answered
+            TFLUSH => reply,
+            TWALK => self.walk(msg, reply).await?,
+            TLOPEN => self.lopen(msg, reply).await?,
+            TLCREATE => self.lcreate(msg, reply).await?,
+            TREAD => self.read(msg, reply).await?,
+            TWRITE => self.write(msg, reply).await?,
+            TREADDIR => self.readdir(msg, reply).await?,
+            TGETATTR => {
+                let ino = self.fid(msg.u32()?)?.ino;
+                let attr = self.fs.get_attr(ino).await.map_err(errno)?;
+                reply.u64(GETATTR_BASIC).getattr(&attr)
+            }
+            TSETATTR => self.setattr(msg, reply).await?,
+            TMKDIR => {
+                let dir = self.fid(msg.u32()?)?.ino;
+                let name = msg.string()?;
+                let mode = msg.u32()?;
+                let attr = self.create(dir, name, FileType::Directory, mode).await?;
+                reply.qid(&attr)
+            }
+            TRENAME => self.rename(msg, reply).await?,
+            TRENAMEAT => self.renameat(msg, reply).await?,
+            TUNLINKAT => {
+                let parent = self.fid(msg.u32()?)?.ino;
+                let name = SecretString::from(msg.string()?);
+                let flags = msg.u32()?;
+                self.remove(parent, &name, flags & AT_REMOVEDIR != 0)
+                    .await?;
+                reply
+            }
+            TREMOVE => {
+                let fid = self.fids.remove(&msg.u32()?).ok_or(EBADF)?;
+                let parent = fid.parent.clone();
+                let is_dir = self.fs.is_dir(fid.ino);
+                // it's clunked even if it can't be removed
+                self.clunk(fid).await;
+                let (parent, name) = parent.ok_or(EPERM)?;
+                self.remove(parent, &name, is_dir).await?;
+                reply
+            }
+            TCLUNK => {
+                let fid = self.fids.remove(&msg.u32()?).ok_or(EBADF)?;
+                self.clunk(fid).await;
+                reply
+            }
+            TFSYNC => {
+                if let Some(handle) = self.fid(msg.u32()?)?.handle {
+                    self.fs.flush(handle).await.map_err(errno)?;
+                }
+                reply
+            }
+            TSTATFS => self.statfs(msg, reply)?,
+            // locks are only between the clients of a host, there's a single one mostly
+            TLOCK => reply.u8(LOCK_SUCCESS),
+            TGETLOCK => {
+                self.fid(msg.u32()?)?;
+                let _kind = msg.u8()?;
+                let (start, len, proc_id) = (msg.u64()?, msg.u64()?, msg.u32()?);
+                let client_id = msg.string()?;
+                reply
+                    .u8(LOCK_TYPE_UNLCK)
+                    .u64(start)
+                    .u64(len)
+                    .u32(proc_id)
+                    .string(&client_id)
+            }
+            TREADLINK => return Err(EINVAL),
+            TAUTH | TSYMLINK | TMKNOD | TLINK | TXATTRWALK | TXATTRCREATE => {
+                return Err(EOPNOTSUPP)
+            }
+            kind => {
+                debug!(kind, "unknown 9p request");
+                return Err(EOPNOTSUPP);
+            }
+        };
+        Ok((kind + 1, reply))
+    }
+
+    async fn version(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let msize = msg.u32()?;
+        let version = msg.string()?;
+        if msize <= IO_HEADER_SIZE {
+            return Err(EINVAL);
+        }
+        self.msize = msize.min(MAX_MSIZE);
+        // it starts over
+        self.clunk_all().await;
+        Ok(reply.u32(self.msize).string(if version == VERSION {
+            VERSION
+        } else {
+            "unknown"
+        }))
+    }
+
+    async fn walk(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let (fid, newfid) = (msg.u32()?, msg.u32()?);
+        let names = (0..msg.u16()?)
+            .map(|_| msg.string())
+            .collect::<P9Result<Vec<_>>>()?;
+        let from = self.fid(fid)?;
+        let mut walked = Fid::new(from.ino, from.parent.clone());
+        let mut attrs = vec![];
+        for name in &names {
+            let name = SecretString::from(name.clone());
+            let attr = match self.fs.find_by_name(walked.ino, &name).await {
+                Ok(Some(attr)) => attr,
+                Ok(None) | Err(FsError::InvalidInodeType) => break,
+                Err(err) => return Err(errno(err)),
+            };
+            walked = Fid::new(attr.ino, Some((walked.ino, name)));
+            attrs.push(attr);
+        }
+        if attrs.len() < names.len() {
+            // only the first has to be found, then it says how far it got
+            if attrs.is_empty() {
+                return Err(ENOENT);
+            }
+        } else if newfid == fid {
+            if let Some(old) = self.fids.insert(fid, walked) {
+                self.clunk(old).await;
+            }
+        } else {
+            self.insert_fid(newfid, walked)?;
+        }
+        let mut reply = reply.u16(u16::try_from(attrs.len()).map_err(|_| EINVAL)?);
+        for attr in &attrs {
+            reply = reply.qid(attr);
+        }
+        Ok(reply)
+    }
+
+    async fn lopen(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        #[allow(clippy::cast_possible_wrap)]
+        let flags = msg.u32()? as c_int;
+        let ino = self.fid(fid)?.ino;
+        self.open_fid(fid, ino, flags).await?;
+        let attr = self.fs.get_attr(ino).await.map_err(errno)?;
+        Ok(reply.qid(&attr).u32(self.msize - IO_HEADER_SIZE))
+    }
+
+    /// Creates the file in the dir of the fid, then the fid is the file, opened.
+    async fn lcreate(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        let name = msg.string()?;
+        #[allow(clippy::cast_possible_wrap)]
+        let flags = msg.u32()? as c_int;
+        let mode = msg.u32()?;
+        let dir = self.fid(fid)?.ino;
+        let attr = self
+            .create(dir, name.clone(), FileType::RegularFile, mode)
+            .await?;
+        *self.fid_mut(fid)? = Fid::new(attr.ino, Some((dir, SecretString::from(name))));
+        // it's new, nothing to truncate
+        self.open_fid(fid, attr.ino, flags & !O_TRUNC).await?;
+        Ok(reply.qid(&attr).u32(self.msize - IO_HEADER_SIZE))
+    }
+
+    async fn open_fid(&mut self, fid: u32, ino: u64, flags: c_int) -> P9Result<()> {
+        let fs = self.fs.clone();
+        let open = self.open.clone();
+        let fid = self.fid_mut(fid)?;
+        if fid.handle.is_some() {
+            return Err(EINVAL);
+        }
+        fid.flags = flags;
+        if fs.is_dir(ino) {
+            return if fid.can_write() { Err(EISDIR) } else { Ok(()) };
+        }
+        if fid.can_write() && flags & O_TRUNC != 0 {
+            fs.set_len(ino, 0).await.map_err(errno)?;
+        }
+        let handle = fs.open(ino, true, fid.can_write()).await.map_err(errno)?;
+        open.add(handle);
+        fid.handle = Some(handle);
+        fid.dirty = false;
+        Ok(())
+    }
+
+    async fn read(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        let (offset, count) = (msg.u64()?, msg.u32()?);
+        let count = count.min(self.msize - IO_HEADER_SIZE);
+        let fs = self.fs.clone();
+        let open = self.open.clone();
+        let fid = self.fid_mut(fid)?;
+        let mut handle = fid.handle.ok_or(EBADF)?;
+        if fid.dirty {
+            // the handle reads what the file had when it was opened
+            open.release(&fs, handle).await.map_err(errno)?;
+            fid.handle = None;
+            handle = fs.open(fid.ino, true, true).await.map_err(errno)?;
+            open.add(handle);
+            fid.handle = Some(handle);
+            fid.dirty = false;
+        }
+        let mut buf = vec![0; count as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let len = fs
+                .read(fid.ino, offset + filled as u64, &mut buf[filled..], handle)
+                .await
+                .map_err(errno)?;
+            if len == 0 {
+                break;
+            }
+            filled += len;
+        }
+        buf.truncate(filled);
+        Ok(reply.data(&buf))
+    }
+
+    async fn write(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        let offset = msg.u64()?;
+        let data = msg.data()?;
+        let fs = self.fs.clone();
+        let fid = self.fid_mut(fid)?;
+        let handle = fid.handle.ok_or(EBADF)?;
+        if !fid.can_write() {
+            return Err(EBADF);
+        }
+        let append = fid.flags & O_APPEND != 0;
+        let mut written = 0;
+        while written < data.len() {
+            let buf = &data[written..];
+            written += if append {
+                fs.append(fid.ino, buf, handle).await
+            } else {
+                fs.write(fid.ino, offset + written as u64, buf, handle)
+                    .await
+            }
+            .map_err(errno)?;
+        }
+        fid.dirty = true;
+        Ok(reply.u32(u32::try_from(written).map_err(|_| EINVAL)?))
+    }
+
+    /// The offset of an entry is where the next one is, the first is at 0.
+    async fn readdir(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        let (offset, count) = (msg.u64()?, msg.u32()?);
+        let count = count.min(self.msize - IO_HEADER_SIZE) as usize;
+        let fs = self.fs.clone();
+        let fid = self.fid_mut(fid)?;
+        if !fs.is_dir(fid.ino) {
+            return Err(ENOTDIR);
+        }
+        if offset == 0 || fid.entries.is_none() {
+            let mut entries = vec![];
+            for entry in fs.read_dir_plus(fid.ino).await.map_err(errno)? {
+                let entry = entry.map_err(errno)?;
+                entries.push((entry.attr, entry.name.expose_secret().clone()));
+            }
+            fid.entries = Some(entries);
+        }
+        let mut data = Encoder::default();
+        let entries = fid.entries.as_ref().unwrap();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        for (i, (attr, name)) in entries.iter().enumerate().skip(start) {
+            // qid, offset, type and name
+            if data.0.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                break;
+            }
+            data = data
+                .qid(attr)
+                .u64(i as u64 + 1)
+                .u8(if attr.kind == FileType::Directory {
+                    DT_DIR
+                } else {
+                    DT_REG
+                })
+                .string(name);
+        }
+        Ok(reply.data(&data.0))
+    }
+
+    async fn rename(&mut self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let fid = msg.u32()?;
+        let new_parent = self.fid(msg.u32()?)?.ino;
+        let new_name = SecretString::from(msg.string()?);
+        let (parent, name) = self.fid(fid)?.parent.clone().ok_or(EPERM)?;
+        self.fs
+            .rename(parent, &name, new_parent, &new_name)
+            .await
+            .map_err(errno)?;
+        self.fid_mut(fid)?.parent = Some((new_parent, new_name));
+        Ok(reply)
+    }
+
+    async fn renameat(&self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let parent = self.fid(msg.u32()?)?.ino;
+        let name = SecretString::from(msg.string()?);
+        let new_parent = self.fid(msg.u32()?)?.ino;
+        let new_name = SecretString::from(msg.string()?);
+        self.fs
+            .rename(parent, &name, new_parent, &new_name)
+            .await
+            .map_err(errno)?;
+        Ok(reply)
+    }
+
+    fn statfs(&self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        self.fid(msg.u32()?)?;
+        let (blocks, free) = self.fs.quota().map_or((0, 0), |quota| {
+            (
+                quota.max_size / u64::from(BLOCK_SIZE),
+                quota.max_size.saturating_sub(quota.used) / u64::from(BLOCK_SIZE),
+            )
+        });
+        Ok(reply
+            .u32(V9FS_MAGIC)
+            .u32(BLOCK_SIZE)
+            .u64(blocks)
+            .u64(free)
+            .u64(free)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u32(255))
+    }
+
+    async fn setattr(&self, msg: &mut Decoder<'_>, reply: Encoder) -> P9Result<Encoder> {
+        let ino = self.fid(msg.u32()?)?.ino;
+        let valid = msg.u32()?;
+        let (mode, uid, gid) = (msg.u32()?, msg.u32()?, msg.u32()?);
+        let size = msg.u64()?;
+        let atime = time(msg.u64()?, msg.u64()?);
+        let mtime = time(msg.u64()?, msg.u64()?);
+        if valid & SETATTR_SIZE != 0 {
+            self.fs.set_len(ino, size).await.map_err(errno)?;
+        }
+        let mut set_attr = SetFileAttr::default();
+        if valid & SETATTR_MODE != 0 {
+            set_attr = set_attr.with_perm(perm(mode));
+        }
+        if valid & SETATTR_UID != 0 {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if valid & SETATTR_GID != 0 {
+            set_attr = set_attr.with_gid(gid);
+        }
+        if valid & SETATTR_ATIME != 0 {
+            set_attr = set_attr.with_atime(if valid & SETATTR_ATIME_SET == 0 {
+                SystemTime::now()
+            } else {
+                atime
+            });
+        }
+        if valid & SETATTR_MTIME != 0 {
+            set_attr = set_attr.with_mtime(if valid & SETATTR_MTIME_SET == 0 {
+                SystemTime::now()
+            } else {
+                mtime
+            });
+        }
+        self.fs.set_attr(ino, set_attr).await.map_err(errno)?;
+        Ok(reply)
+    }
+
+    async fn create(
+        &self,
+        dir: u64,
+        name: String,
+        kind: FileType,
+        mode: u32,
+    ) -> P9Result<FileAttr> {
+        let (_, attr) = self
+            .fs
+            .create(
+                dir,
+                &SecretString::from(name),
+                CreateFileAttr {
+                    kind,
+                    perm: perm(mode),
+                    uid: *crate::UID,
+                    gid: *crate::GID,
+                    rdev: 0,
+                    flags: 0,
+                },
+                false,
+                false,
+            )
+            .await
+            .map_err(errno)?;
+        Ok(attr)
+    }
+
+    async fn remove(&self, parent: u64, name: &SecretString, is_dir: bool) -> P9Result<()> {
+        if is_dir {
+            self.fs.remove_dir(parent, name).await
+        } else {
+            self.fs.remove_file(parent, name).await
+        }
+        .map_err(errno)
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> P9Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn insert_fid(&mut self, fid: u32, value: Fid) -> P9Result<()> {
+        if self.fids.contains_key(&fid) {
+            return Err(EBADF);
+        }
+        self.fids.insert(fid, value);
+        Ok(())
+    }
+
+    async fn clunk(&self, fid: Fid) {
+        if let Some(handle) = fid.handle {
+            if let Err(err) = self.open.release(&self.fs, handle).await {
+                error!(err = %err, "releasing file");
+            }
+        }
+    }
+
+    async fn clunk_all(&mut self) {
+        for fid in std::mem::take(&mut self.fids).into_values() {
+            self.clunk(fid).await;
+        }
+    }
+}
+
+/// Reads the fields of a request, all little endian.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn bytes(&mut self, len: usize) -> P9Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(EINVAL);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> P9Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> P9Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> P9Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> P9Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> P9Result<String> {
+        let len = self.u16()?;
+        String::from_utf8(self.bytes(len as usize)?.to_vec()).map_err(|_| EINVAL)
+    }
+
+    fn data(&mut self) -> P9Result<Vec<u8>> {
+        let len = self.u32()?;
+        Ok(self.bytes(len as usize)?.to_vec())
+    }
+}
+
+/// Writes the fields of a reply.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Names are at most 255 bytes, so the length fits.
+    #[allow(clippy::cast_possible_truncation)]
+    fn string(mut self, value: &str) -> Self {
+        self = self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    /// Not more than `msize`, so the length fits.
+    #[allow(clippy::cast_possible_truncation)]
+    fn data(mut self, value: &[u8]) -> Self {
+        self = self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    /// The type, version and path, which is the inode.
+    fn qid(self, attr: &FileAttr) -> Self {
+        let kind = if attr.kind == FileType::Directory {
+            QTDIR
+        } else {
+            QTFILE
+        };
+        self.u8(kind).u32(0).u64(attr.ino)
+    }
+
+    fn getattr(self, attr: &FileAttr) -> Self {
+        let kind = if attr.kind == FileType::Directory {
+            S_IFDIR
+        } else {
+            S_IFREG
+        };
+        let (atime, atime_nsec) = secs_nsecs(attr.atime);
+        let (mtime, mtime_nsec) = secs_nsecs(attr.mtime);
+        let (ctime, ctime_nsec) = secs_nsecs(attr.ctime);
+        self.qid(attr)
+            .u32(kind | u32::from(attr.perm))
+            .u32(attr.uid)
+            .u32(attr.gid)
+            .u64(u64::from(attr.nlink))
+            .u64(u64::from(attr.rdev))
+            .u64(attr.size)
+            .u64(u64::from(BLOCK_SIZE))
+            .u64(attr.size.div_ceil(512))
+            .u64(atime)
+            .u64(atime_nsec)
+            .u64(mtime)
+            .u64(mtime_nsec)
+            .u64(ctime)
+            .u64(ctime_nsec)
+            // btime, gen and data_version, not asked for
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn perm(mode: u32) -> u16 {
+    (mode & 0o7777) as u16
+}
+
+fn secs_nsecs(time: SystemTime) -> (u64, u64) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), u64::from(since_epoch.subsec_nanos()))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn time(secs: u64, nsecs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(secs, (nsecs % 1_000_000_000) as u32)
+}
+
+fn errno(err: FsError) -> c_int {
+    match err {
+        FsError::NotFound(_) | FsError::InodeNotFound => ENOENT,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::InvalidInodeType => ENOTDIR,
+        FsError::ReadOnly => EROFS,
+        FsError::Excluded => EPERM,
+        FsError::QuotaExceeded(_) => ENOSPC,
+        FsError::MaxFilesizeExceeded(_) => EFBIG,
+        FsError::InvalidInput(_) => EINVAL,
+        err => {
+            error!(err = %err);
+            EIO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::test_common::{get_fs, run_test, TestSetup};
+
+    /// Sends the request and returns the type and body of the reply.
+    async fn call(stream: &mut DuplexStream, kind: u8, body: Encoder) -> (u8, Vec<u8>) {
+        let mut msg = Encoder::default()
+            .u32(u32::try_from(body.0.len()).unwrap() + 7)
+            .u8(kind)
+            .u16(1);
+        msg.0.extend_from_slice(&body.0);
+        stream.write_all(&msg.0).await.unwrap();
+        let size = stream.read_u32_le().await.unwrap();
+        let mut reply = vec![0; size as usize - 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(u16::from_le_bytes([reply[1], reply[2]]), 1);
+        (reply[0], reply[3..].to_vec())
+    }
+
+    async fn call_ok(stream: &mut DuplexStream, kind: u8, body: Encoder) -> Vec<u8> {
+        let (reply_kind, reply) = call(stream, kind, body).await;
+        assert_eq!(reply_kind, kind + 1, "error {:?}", Decoder(&reply).u32());
+        reply
+    }
+
+    async fn call_err(stream: &mut DuplexStream, kind: u8, body: Encoder) -> c_int {
+        let (reply_kind, reply) = call(stream, kind, body).await;
+        assert_eq!(reply_kind, RLERROR);
+        c_int::try_from(Decoder(&reply).u32().unwrap()).unwrap()
+    }
+
+    fn walk(fid: u32, newfid: u32, names: &[&str]) -> Encoder {
+        let mut msg = Encoder::default()
+            .u32(fid)
+            .u32(newfid)
+            .u16(u16::try_from(names.len()).unwrap());
+        for name in names {
+            msg = msg.string(name);
+        }
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_9p() {
+        run_test(TestSetup { key: "test_9p" }, async {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let session = P9Session::new(get_fs().await, OpenHandles::default());
+            tokio::spawn(session.run(server));
+            let s = &mut client;
+
+            let reply = call_ok(s, TVERSION, Encoder::default().u32(8192).string(VERSION)).await;
+            let mut reply = Decoder(&reply);
+            assert_eq!(
+                (reply.u32(), reply.string()),
+                (Ok(8192), Ok(VERSION.to_string()))
+            );
+            let attach = Encoder::default()
+                .u32(1)
+                .u32(u32::MAX)
+                .string("")
+                .string("")
+                .u32(0);
+            let reply = call_ok(s, TATTACH, attach).await;
+            assert_eq!(reply[0], QTDIR);
+
+            let mkdir = Encoder::default().u32(1).string("docs").u32(0o755).u32(0);
+            call_ok(s, TMKDIR, mkdir).await;
+            call_ok(s, TWALK, walk(1, 2, &["docs"])).await;
+            let create = Encoder::default()
+                .u32(2)
+                .string("a.txt")
+                .u32(libc::O_RDWR.unsigned_abs())
+                .u32(0o644)
+                .u32(0);
+            call_ok(s, TLCREATE, create).await;
+            let write = Encoder::default().u32(2).u64(0).data(b"hello world");
+            let reply = call_ok(s, TWRITE, write).await;
+            assert_eq!(Decoder(&reply).u32(), Ok(11));
+            // what's written is seen before it's clunked
+            let reply = call_ok(s, TREAD, Encoder::default().u32(2).u64(6).u32(100)).await;
+            assert_eq!(Decoder(&reply).data(), Ok(b"world".to_vec()));
+            call_ok(s, TCLUNK, Encoder::default().u32(2)).await;
+
+            // only the first name has to be there, then it tells how far it got
+            assert_eq!(call_err(s, TWALK, walk(1, 3, &["missing"])).await, ENOENT);
+            let reply = call_ok(s, TWALK, walk(1, 3, &["docs", "missing"])).await;
+            assert_eq!(Decoder(&reply).u16(), Ok(1));
+            assert_eq!(
+                call_err(s, TGETATTR, Encoder::default().u32(3).u64(GETATTR_BASIC)).await,
+                EBADF
+            );
+
+            call_ok(s, TWALK, walk(1, 3, &["docs", "a.txt"])).await;
+            let setattr = Encoder::default()
+                .u32(3)
+                .u32(SETATTR_SIZE | SETATTR_MODE)
+                .u32(0o600)
+                .u32(0)
+                .u32(0)
+                .u64(5)
+                .u64(0)
+                .u64(0)
+                .u64(0)
+                .u64(0);
+            call_ok(s, TSETATTR, setattr).await;
+            let reply = call_ok(s, TGETATTR, Encoder::default().u32(3).u64(GETATTR_BASIC)).await;
+            let mut reply = Decoder(&reply);
+            assert_eq!(reply.u64(), Ok(GETATTR_BASIC));
+            reply.bytes(13).unwrap();
+            assert_eq!(reply.u32(), Ok(S_IFREG | 0o600));
+            reply.bytes(4 + 4 + 8 + 8).unwrap();
+            assert_eq!(reply.u64(), Ok(5));
+
+            call_ok(s, TWALK, walk(1, 4, &["docs"])).await;
+            call_ok(s, TLOPEN, Encoder::default().u32(4).u32(0)).await;
+            let reply = call_ok(s, TREADDIR, Encoder::default().u32(4).u64(0).u32(8192)).await;
+            let data = Decoder(&reply).data().unwrap();
+            let mut entries = Decoder(&data);
+            let mut names = vec![];
+            let mut offset = 0;
+            while !entries.0.is_empty() {
+                entries.bytes(13).unwrap();
+                offset = entries.u64().unwrap();
+                entries.u8().unwrap();
+                names.push(entries.string().unwrap());
+            }
+            names.sort();
+            assert_eq!(names, vec![".", "..", "a.txt"]);
+            let reply = call_ok(s, TREADDIR, Encoder::default().u32(4).u64(offset).u32(8192)).await;
+            assert_eq!(Decoder(&reply).data(), Ok(vec![]));
+
+            let rename = Encoder::default().u32(3).u32(4).string("b.txt");
+            call_ok(s, TRENAME, rename).await;
+            let reply = call_ok(s, TWALK, walk(1, 5, &["docs", "b.txt"])).await;
+            assert_eq!(Decoder(&reply).u16(), Ok(2));
+            let unlink = Encoder::default().u32(1).string("docs").u32(AT_REMOVEDIR);
+            assert_eq!(call_err(s, TUNLINKAT, unlink).await, ENOTEMPTY);
+            call_ok(s, TREMOVE, Encoder::default().u32(5)).await;
+            let unlink = Encoder::default().u32(1).string("docs").u32(AT_REMOVEDIR);
+            call_ok(s, TUNLINKAT, unlink).await;
+            assert_eq!(call_err(s, TWALK, walk(1, 6, &["docs"])).await, ENOENT);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use russh::server::{Auth, Config, Msg, Session};
+use russh::{Channel, ChannelId, MethodSet};
+use russh_keys::key::{KeyPair, PublicKey};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+};
+use secrecy::{ExposeSecret, SecretString};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, SetFileAttr};
+use crate::serve::{bind, OpenHandles};
+
+/// Mode bits of the file type, as in `stat`.
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// Serve the files over SFTP on `addr`, until `shutdown` completes. Clients log in with any user name and one of the
+/// keys in `authorized_keys`, a file in the format of `~/.ssh/authorized_keys`. The server is identified by the key in
+/// `host_key`, which is generated if the file doesn't exist.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub async fn serve_sftp(
+    fs: Arc<EncryptedFs>,
+    addr: SocketAddr,
+    host_key: &Path,
+    authorized_keys: &Path,
+    shutdown: impl Future<Output = ()> + Send,
+) -> io::Result<()> {
+    let config = Arc::new(Config {
+        methods: MethodSet::PUBLICKEY,
+        // so it can't be guessed from how long it takes if a key is authorized
+        auth_rejection_time: Duration::from_secs(1),
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        keys: vec![load_host_key(host_key)?],
+        ..Config::default()
+    });
+    let authorized_keys = Arc::new(load_authorized_keys(authorized_keys)?);
+    let open = OpenHandles::default();
+    let listener = bind(addr).await?;
+    let mut sessions = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => res?,
+            () = &mut shutdown => break,
+        };
+        debug!(%peer, "sftp connection");
+        let ssh = SshSession {
+            fs: fs.clone(),
+            open: open.clone(),
+            authorized_keys: authorized_keys.clone(),
+            channels: HashMap::new(),
+        };
+        let config = config.clone();
+        sessions.spawn(async move {
+            let res = match russh::server::run_stream(config, stream, ssh).await {
+                Ok(session) => session.await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                debug!(%peer, err = %err, "sftp connection failed");
+            }
+        });
+        // forget the ones that ended
+        while sessions.try_join_next().is_some() {}
+    }
+    sessions.shutdown().await;
+    open.release_all(&fs).await;
+    Ok(())
+}
+
+/// The host key from `path`, or a new one saved there.
+fn load_host_key(path: &Path) -> io::Result<KeyPair> {
+    if path.exists() {
+        return russh_keys::load_secret_key(path, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+    let key =
+        KeyPair::generate_ed25519().ok_or_else(|| io::Error::other("cannot generate host key"))?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    russh_keys::encode_pkcs8_pem(&key, &mut file).map_err(io::Error::other)?;
+    file.flush()?;
+    info!(path = %path.display(), "generated SFTP host key");
+    Ok(key)
+}
+
+/// Keys in a file like `~/.ssh/authorized_keys`, one per line, after the options if any, with an optional comment.
+fn load_authorized_keys(path: &Path) -> io::Result<Vec<PublicKey>> {
+    let keys = parse_authorized_keys(&std::fs::read_to_string(path)?);
+    if keys.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no keys in {}", path.display()),
+        ));
+    }
+    Ok(keys)
+}
+
+fn parse_authorized_keys(content: &str) -> Vec<PublicKey> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let key = line
+                .split_whitespace()
+                .find_map(|part| russh_keys::parse_public_key_base64(part).ok());
+            if key.is_none() {
+                warn!(line, "not a key in authorized keys");
+            }
+            key
+        })
+        .collect()
+}
+
+struct SshSession {
+    fs: Arc<EncryptedFs>,
+    open: OpenHandles,
+    authorized_keys: Arc<Vec<PublicKey>>,
+    /// Opened, until the client asks for the `sftp` subsystem on them.
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+#[async_trait]
+impl russh::server::Handler for SshSession {
+    type Error = russh::Error;
+
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        self.auth_publickey(user, public_key).await
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if self.authorized_keys.contains(public_key) {
+            debug!(user, "sftp login");
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.close(channel);
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        // no shell or commands, only files
+        let Some(channel) = self.channels.remove(&channel_id).filter(|_| name == "sftp") else {
+            session.channel_failure(channel_id);
+            return Ok(());
+        };
+        session.channel_success(channel_id);
+        let sftp = SftpSession::new(self.fs.clone(), self.open.clone());
+        russh_sftp::server::run(channel.into_stream(), sftp).await;
+        Ok(())
+    }
+}
+
+enum SftpHandle {
+    File(OpenFile),
+    /// What's left to list, taken on the first read.
+    Dir(Option<Vec<File>>),
+}
+
+struct OpenFile {
+    ino: u64,
+    handle: u64,
+    flags: OpenFlags,
+    /// Written since it was opened.
+    dirty: bool,
+}
+
+/// Paths are from the root of the volume, relative ones too, there's no home dir.
+struct SftpSession {
+    fs: Arc<EncryptedFs>,
+    open: OpenHandles,
+    handles: HashMap<String, SftpHandle>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn new(fs: Arc<EncryptedFs>, open: OpenHandles) -> Self {
+        Self {
+            fs,
+            open,
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    fn add_handle(&mut self, handle: SftpHandle) -> String {
+        self.next_handle += 1;
+        let id = self.next_handle.to_string();
+        self.handles.insert(id.clone(), handle);
+        id
+    }
+
+    async fn attr(&self, path: &str) -> Result<FileAttr, StatusCode> {
+        self.fs
+            .resolve_path(&normalize(path))
+            .await
+            .map_err(sftp_error)
+    }
+
+    /// The dir the entry at `path` is in, and its name.
+    async fn parent(&self, path: &str) -> Result<(u64, SecretString), StatusCode> {
+        let path = normalize(path);
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+        if name.is_empty() {
+            return Err(StatusCode::PermissionDenied);
+        }
+        let parent = self.fs.resolve_path(parent).await.map_err(sftp_error)?;
+        if parent.kind != FileType::Directory {
+            return Err(StatusCode::NoSuchFile);
+        }
+        Ok((parent.ino, SecretString::from(name.to_string())))
+    }
+
+    async fn create(
+        &self,
+        path: &str,
+        kind: FileType,
+        attrs: &FileAttributes,
+    ) -> Result<FileAttr, StatusCode> {
+        let (parent, name) = self.parent(path).await?;
+        let mut create_attr = create_attr(kind);
+        if let Some(permissions) = attrs.permissions {
+            // clients ask for 0777 and leave the umask to the server, this is the usual one
+            create_attr.perm = perm(permissions) & !0o022;
+        }
+        let (_, attr) = self
+            .fs
+            .create(parent, &name, create_attr, false, false)
+            .await
+            .map_err(sftp_error)?;
+        Ok(attr)
+    }
+
+    async fn set_attrs(&self, ino: u64, attrs: &FileAttributes) -> Result<(), StatusCode> {
+        if let Some(size) = attrs.size {
+            self.fs.set_len(ino, size).await.map_err(sftp_error)?;
+        }
+        let mut set_attr = SetFileAttr::default();
+        if let Some(permissions) = attrs.permissions {
+            set_attr = set_attr.with_perm(perm(permissions));
+        }
+        if let Some(uid) = attrs.uid {
+            set_attr = set_attr.with_uid(uid);
+        }
+        if let Some(gid) = attrs.gid {
+            set_attr = set_attr.with_gid(gid);
+        }
+        if let Some(atime) = attrs.atime {
+            set_attr = set_attr.with_atime(system_time(atime));
+        }
+        if let Some(mtime) = attrs.mtime {
+            set_attr = set_attr.with_mtime(system_time(mtime));
+        }
+        self.fs.set_attr(ino, set_attr).await.map_err(sftp_error)
+    }
+
+    fn file(&mut self, handle: &str) -> Result<&mut OpenFile, StatusCode> {
+        match self.handles.get_mut(handle) {
+            Some(SftpHandle::File(file)) => Ok(file),
+            Some(SftpHandle::Dir(_)) => Err(StatusCode::Failure),
+            None => Err(StatusCode::NoSuchFile),
+        }
+    }
+}
+
+impl Drop for SftpSession {
+    fn drop(&mut self) {
+        let handles: Vec<u64> = self
+            .handles
+            .values()
+            .filter_map(|handle| match handle {
+                SftpHandle::File(file) => Some(file.handle),
+                SftpHandle::Dir(_) => None,
+            })
+            .collect();
+        if handles.is_empty() {
+            return;
+        }
+        // the client went away without closing them
+        let fs = self.fs.clone();
+        let open = self.open.clone();
+        tokio::spawn(async move {
+            for handle in handles {
+                if let Err(err) = open.release(&fs, handle).await {
+                    error!(err = %err, "releasing file");
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn init(
+        &mut self,
+        _version: u32,
+        _extensions: HashMap<String, String>,
+    ) -> Result<Version, Self::Error> {
+        Ok(Version::new())
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let write = can_write(pflags);
+        let attr = match self.attr(&filename).await {
+            Ok(_) if pflags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) => {
+                return Err(StatusCode::Failure);
+            }
+            Ok(attr) if attr.kind == FileType::Directory => return Err(StatusCode::Failure),
+            Ok(attr) => {
+                if write && pflags.contains(OpenFlags::TRUNCATE) {
+                    self.fs.set_len(attr.ino, 0).await.map_err(sftp_error)?;
+                }
+                attr
+            }
+            Err(StatusCode::NoSuchFile) if pflags.contains(OpenFlags::CREATE) => {
+                self.create(&filename, FileType::RegularFile, &attrs)
+                    .await?
+            }
+            Err(err) => return Err(err),
+        };
+        let handle = self
+            .fs
+            .open(attr.ino, true, write)
+            .await
+            .map_err(sftp_error)?;
+        self.open.add(handle);
+        let handle = self.add_handle(SftpHandle::File(OpenFile {
+            ino: attr.ino,
+            handle,
+            flags: pflags,
+            dirty: false,
+        }));
+        Ok(Handle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        match self.handles.remove(&handle) {
+            Some(SftpHandle::File(file)) => self
+                .open
+                .release(&self.fs, file.handle)
+                .await
+                .map_err(sftp_error)?,
+            Some(SftpHandle::Dir(_)) => {}
+            None => return Err(StatusCode::NoSuchFile),
+        }
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let fs = self.fs.clone();
+        let open = self.open.clone();
+        let file = self.file(&handle)?;
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(StatusCode::PermissionDenied);
+        }
+        if file.dirty {
+            // the handle reads what the file had when it was opened
+            open.release(&fs, file.handle).await.map_err(sftp_error)?;
+            file.handle = fs
+                .open(file.ino, true, can_write(file.flags))
+                .await
+                .map_err(sftp_error)?;
+            open.add(file.handle);
+            file.dirty = false;
+        }
+        let mut buf = vec![0; len as usize];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = fs
+                .read(
+                    file.ino,
+                    offset + filled as u64,
+                    &mut buf[filled..],
+                    file.handle,
+                )
+                .await
+                .map_err(sftp_error)?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        buf.truncate(filled);
+        Ok(Data { id, data: buf })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let fs = self.fs.clone();
+        let file = self.file(&handle)?;
+        if !can_write(file.flags) {
+            return Err(StatusCode::PermissionDenied);
+        }
+        let offset = if file.flags.contains(OpenFlags::APPEND) {
+            fs.get_attr(file.ino).await.map_err(sftp_error)?.size
+        } else {
+            offset
+        };
+        let mut written = 0;
+        while written < data.len() {
+            written += fs
+                .write(
+                    file.ino,
+                    offset + written as u64,
+                    &data[written..],
+                    file.handle,
+                )
+                .await
+                .map_err(sftp_error)?;
+        }
+        file.dirty = true;
+        Ok(ok(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        self.stat(id, path).await
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let ino = self.file(&handle)?.ino;
+        let attr = self.fs.get_attr(ino).await.map_err(sftp_error)?;
+        Ok(Attrs {
+            id,
+            attrs: file_attributes(&attr),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let attr = self.attr(&path).await?;
+        self.set_attrs(attr.ino, &attrs).await?;
+        Ok(ok(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let ino = self.file(&handle)?.ino;
+        self.set_attrs(ino, &attrs).await?;
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let attr = self.attr(&path).await?;
+        if attr.kind != FileType::Directory {
+            return Err(StatusCode::NoSuchFile);
+        }
+        let mut files = vec![];
+        for entry in self.fs.read_dir_plus(attr.ino).await.map_err(sftp_error)? {
+            let entry = entry.map_err(sftp_error)?;
+            let mut file = File {
+                filename: entry.name.expose_secret().clone(),
+                longname: String::new(),
+                attrs: file_attributes(&entry.attr),
+            };
+            file.longname = file.longname();
+            files.push(file);
+        }
+        let handle = self.add_handle(SftpHandle::Dir(Some(files)));
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        match self.handles.get_mut(&handle) {
+            Some(SftpHandle::Dir(files)) => files
+                .take()
+                .map(|files| Name { id, files })
+                .ok_or(StatusCode::Eof),
+            Some(SftpHandle::File(_)) => Err(StatusCode::Failure),
+            None => Err(StatusCode::NoSuchFile),
+        }
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        let (parent, name) = self.parent(&filename).await?;
+        self.fs
+            .remove_file(parent, &name)
+            .await
+            .map_err(sftp_error)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        self.create(&path, FileType::Directory, &attrs).await?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        let (parent, name) = self.parent(&path).await?;
+        self.fs
+            .remove_dir(parent, &name)
+            .await
+            .map_err(sftp_error)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        Ok(Name {
+            id,
+            files: vec![File {
+                filename: normalize(&path),
+                longname: String::new(),
+                attrs: FileAttributes::default(),
+            }],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let attr = self.attr(&path).await?;
+        Ok(Attrs {
+            id,
+            attrs: file_attributes(&attr),
+        })
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        let (parent, name) = self.parent(&oldpath).await?;
+        let (new_parent, new_name) = self.parent(&newpath).await?;
+        self.fs
+            .rename(parent, &name, new_parent, &new_name)
+            .await
+            .map_err(sftp_error)?;
+        Ok(ok(id))
+    }
+}
+
+fn can_write(flags: OpenFlags) -> bool {
+    flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND)
+}
+
+/// Absolute, without `.` and `..`.
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = vec![];
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Owned by who serves it, with the usual permissions.
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if kind == FileType::Directory {
+            0o755
+        } else {
+            0o644
+        },
+        uid: *crate::UID,
+        gid: *crate::GID,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn perm(permissions: u32) -> u16 {
+    (permissions & 0o7777) as u16
+}
+
+fn file_attributes(attr: &FileAttr) -> FileAttributes {
+    let kind = if attr.kind == FileType::Directory {
+        S_IFDIR
+    } else {
+        S_IFREG
+    };
+    FileAttributes {
+        size: Some(attr.size),
+        uid: Some(attr.uid),
+        user: None,
+        gid: Some(attr.gid),
+        group: None,
+        permissions: Some(kind | u32::from(attr.perm)),
+        atime: Some(sftp_time(attr.atime)),
+        mtime: Some(sftp_time(attr.mtime)),
+    }
+}
+
+fn sftp_time(time: SystemTime) -> u32 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    u32::try_from(since_epoch.as_secs()).unwrap_or(u32::MAX)
+}
+
+fn system_time(time: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::from(time))
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn sftp_error(err: FsError) -> StatusCode {
+    match err {
+        FsError::NotFound(_) | FsError::InodeNotFound => StatusCode::NoSuchFile,
+        FsError::ReadOnly | FsError::Excluded => StatusCode::PermissionDenied,
+        // SFTP v3 has no code for these, the client only shows it failed
+        FsError::AlreadyExists
+        | FsError::NotEmpty
+        | FsError::InvalidInodeType
+        | FsError::QuotaExceeded(_)
+        | FsError::MaxFilesizeExceeded(_) => StatusCode::Failure,
+        err => {
+            error!(err = %err);
+            StatusCode::Failure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use russh_sftp::server::Handler;
+
+    use super::*;
+    use crate::test_common::{get_fs, run_test, TestSetup};
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAICqJ248FbghiufucNdnErmmo7klSLslPfMhxe9wX594I";
+
+    fn no_attrs() -> FileAttributes {
+        FileAttributes {
+            size: None,
+            uid: None,
+            user: None,
+            gid: None,
+            group: None,
+            permissions: None,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    async fn open(sftp: &mut SftpSession, path: &str, flags: OpenFlags) -> String {
+        sftp.open(0, path.to_string(), flags, no_attrs())
+            .await
+            .unwrap()
+            .handle
+    }
+
+    #[test]
+    fn test_authorized_keys() {
+        let keys = parse_authorized_keys(&format!(
+            "# comment\n\nssh-ed25519 {KEY} user@host\nno-pty,from=\"10.0.0.1\" ssh-ed25519 {KEY}\nnot a key\n"
+        ));
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], russh_keys::parse_public_key_base64(KEY).unwrap());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("."), "/");
+        assert_eq!(normalize("a/./b//c/"), "/a/b/c");
+        assert_eq!(normalize("/a/../../b"), "/b");
+    }
+
+    #[tokio::test]
+    async fn test_sftp() {
+        run_test(TestSetup { key: "test_sftp" }, async {
+            let mut sftp = SftpSession::new(get_fs().await, OpenHandles::default());
+
+            sftp.mkdir(0, "docs".to_string(), no_attrs()).await.unwrap();
+            let handle = open(
+                &mut sftp,
+                "/docs/a.txt",
+                OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+            )
+            .await;
+            sftp.write(0, handle.clone(), 0, b"hello ".to_vec())
+                .await
+                .unwrap();
+            sftp.write(0, handle.clone(), 6, b"world".to_vec())
+                .await
+                .unwrap();
+            // what's written is seen before it's closed
+            let data = sftp.read(0, handle.clone(), 0, 100).await.unwrap().data;
+            assert_eq!(data, b"hello world");
+            assert_eq!(
+                sftp.read(0, handle.clone(), 11, 100).await.unwrap_err(),
+                StatusCode::Eof
+            );
+            assert_eq!(
+                sftp.fstat(0, handle.clone()).await.unwrap().attrs.size,
+                Some(11)
+            );
+            sftp.close(0, handle).await.unwrap();
+
+            let handle = open(&mut sftp, "docs/a.txt", OpenFlags::APPEND).await;
+            sftp.write(0, handle.clone(), 0, b"!".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(
+                sftp.read(0, handle.clone(), 0, 100).await.unwrap_err(),
+                StatusCode::PermissionDenied
+            );
+            sftp.close(0, handle).await.unwrap();
+            assert!(sftp.open.0.lock().unwrap().is_empty());
+
+            let mut attrs = no_attrs();
+            attrs.permissions = Some(0o600);
+            sftp.setstat(0, "/docs/a.txt".to_string(), attrs)
+                .await
+                .unwrap();
+            let attrs = sftp.stat(0, "/docs/a.txt".to_string()).await.unwrap().attrs;
+            assert_eq!(attrs.size, Some(12));
+            assert_eq!(attrs.permissions, Some(S_IFREG | 0o600));
+
+            sftp.rename(0, "/docs/a.txt".to_string(), "/docs/b.txt".to_string())
+                .await
+                .unwrap();
+            let handle = sftp.opendir(0, "/docs".to_string()).await.unwrap().handle;
+            let mut names: Vec<String> = sftp
+                .readdir(0, handle.clone())
+                .await
+                .unwrap()
+                .files
+                .into_iter()
+                .map(|file| file.filename)
+                .collect();
+            names.sort();
+            assert_eq!(names, vec![".", "..", "b.txt"]);
+            assert_eq!(
+                sftp.readdir(0, handle.clone()).await.unwrap_err(),
+                StatusCode::Eof
+            );
+            sftp.close(0, handle).await.unwrap();
+
+            assert_eq!(
+                sftp.open(
+                    0,
+                    "/docs/b.txt".to_string(),
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUDE,
+                    no_attrs(),
+                )
+                .await
+                .unwrap_err(),
+                StatusCode::Failure
+            );
+            sftp.remove(0, "/docs/b.txt".to_string()).await.unwrap();
+            sftp.rmdir(0, "/docs".to_string()).await.unwrap();
+            assert_eq!(
+                sftp.stat(0, "/docs".to_string()).await.unwrap_err(),
+                StatusCode::NoSuchFile
+            );
+        })
+        .await;
+    }
+}
@@ -0,0 +1,601 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes};
+use dav_server::davpath::DavPath;
+use dav_server::fakels::FakeLs;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError as DavError, FsFuture,
+    FsResult as DavResult, FsStream, OpenOptions, ReadDirMeta,
+};
+use dav_server::DavHandler;
+use futures_util::{future, stream};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, error};
+
+use crate::encryptedfs::{
+    CreateFileAttr, EncryptedFs, FileAttr, FileType, FsError, FsResult, SetFileAttr,
+};
+use crate::serve::bind;
+
+/// Files are copied in parts this big.
+const COPY_BUF_SIZE: usize = 1024 * 1024;
+
+/// Serve the files over `WebDAV` on `addr`, until `shutdown` completes. There is no authentication, anyone who can
+/// connect can read and change the files, so listen on localhost.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::module_name_repetitions)]
+pub async fn serve_webdav(
+    fs: Arc<EncryptedFs>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send,
+) -> io::Result<()> {
+    let listener = bind(addr).await?;
+    let handler = DavHandler::builder()
+        .filesystem(Box::new(WebDavFs { fs }))
+        // Windows and macOS clients mount it read-only without locks
+        .locksystem(FakeLs::new())
+        .build_handler();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            () = &mut shutdown => return Ok(()),
+        };
+        debug!(%addr, "webdav connection");
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let handler = handler.clone();
+                async move { Ok::<_, Infallible>(handler.handle(req).await) }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(%addr, err = %err, "webdav connection failed");
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+struct WebDavFs {
+    fs: Arc<EncryptedFs>,
+}
+
+impl WebDavFs {
+    async fn attr(&self, path: &DavPath) -> DavResult<FileAttr> {
+        self.fs
+            .resolve_path(path_str(path)?)
+            .await
+            .map_err(dav_error)
+    }
+
+    /// The dir the entry at `path` is in, and its name.
+    async fn parent(&self, path: &DavPath) -> DavResult<(u64, SecretString)> {
+        let path = path_str(path)?.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() {
+            return Err(DavError::Forbidden);
+        }
+        let parent = self.fs.resolve_path(parent).await.map_err(dav_error)?;
+        if parent.kind != FileType::Directory {
+            return Err(DavError::Forbidden);
+        }
+        Ok((parent.ino, SecretString::from(name.to_string())))
+    }
+
+    async fn create(&self, path: &DavPath, kind: FileType) -> DavResult<FileAttr> {
+        let (parent, name) = self.parent(path).await?;
+        let (_, attr) = self
+            .fs
+            .create(parent, &name, create_attr(kind), false, false)
+            .await
+            .map_err(dav_error)?;
+        Ok(attr)
+    }
+
+    /// The file at `path`, empty, created if needed.
+    async fn truncated(&self, path: &DavPath) -> DavResult<FileAttr> {
+        match self.fs.resolve_path(path_str(path)?).await {
+            Ok(attr) if attr.kind == FileType::Directory => Err(DavError::Forbidden),
+            Ok(attr) => {
+                self.fs.set_len(attr.ino, 0).await.map_err(dav_error)?;
+                Ok(attr)
+            }
+            Err(FsError::NotFound(_)) => self.create(path, FileType::RegularFile).await,
+            Err(err) => Err(dav_error(err)),
+        }
+    }
+
+    async fn copy_content(&self, from: u64, to: u64) -> DavResult<()> {
+        let src_fh = self.fs.open(from, true, false).await.map_err(dav_error)?;
+        let dest_fh = match self.fs.open(to, false, true).await {
+            Ok(fh) => fh,
+            Err(err) => {
+                let _ = self.fs.release(src_fh).await;
+                return Err(dav_error(err));
+            }
+        };
+        let res = self.copy_handles(from, src_fh, to, dest_fh).await;
+        let released = self.fs.release(src_fh).await;
+        let res = res.and(self.fs.release(dest_fh).await).and(released);
+        res.map_err(dav_error)
+    }
+
+    async fn copy_handles(&self, from: u64, src_fh: u64, to: u64, dest_fh: u64) -> FsResult<()> {
+        let mut buf = vec![0; COPY_BUF_SIZE];
+        let mut offset = 0;
+        loop {
+            let len = self.fs.read(from, offset, &mut buf, src_fh).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            let mut written = 0;
+            while written < len {
+                written += self
+                    .fs
+                    .write(to, offset + written as u64, &buf[written..len], dest_fh)
+                    .await?;
+            }
+            offset += len as u64;
+        }
+    }
+}
+
+impl DavFileSystem for WebDavFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        Box::pin(async move {
+            let attr = match self.fs.resolve_path(path_str(path)?).await {
+                Ok(_) if options.create_new => return Err(DavError::Exists),
+                Ok(attr) if attr.kind == FileType::Directory => return Err(DavError::Forbidden),
+                Ok(attr) => attr,
+                Err(FsError::NotFound(_)) if options.create || options.create_new => {
+                    self.create(path, FileType::RegularFile).await?
+                }
+                Err(err) => return Err(dav_error(err)),
+            };
+            if options.truncate && attr.size > 0 {
+                self.fs.set_len(attr.ino, 0).await.map_err(dav_error)?;
+            }
+            Ok(Box::new(WebDavFile {
+                fs: self.fs.clone(),
+                ino: attr.ino,
+                write: options.write || options.append,
+                handle: None,
+                pos: if options.append && !options.truncate {
+                    attr.size
+                } else {
+                    0
+                },
+            }) as Box<dyn DavFile>)
+        })
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        Box::pin(async move {
+            let attr = self.attr(path).await?;
+            if attr.kind != FileType::Directory {
+                return Err(DavError::Forbidden);
+            }
+            let entries: Vec<DavResult<Box<dyn DavDirEntry>>> = self
+                .fs
+                .read_dir_plus(attr.ino)
+                .await
+                .map_err(dav_error)?
+                .filter_map(|entry| match entry {
+                    Ok(entry)
+                        if entry.name.expose_secret() == "."
+                            || entry.name.expose_secret() == ".." =>
+                    {
+                        None
+                    }
+                    Ok(entry) => Some(Ok(Box::new(WebDavEntry {
+                        name: entry.name.expose_secret().clone(),
+                        attr: entry.attr,
+                    }) as Box<dyn DavDirEntry>)),
+                    Err(err) => Some(Err(dav_error(err))),
+                })
+                .collect();
+            Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        Box::pin(
+            async move { Ok(Box::new(WebDavMeta(self.attr(path).await?)) as Box<dyn DavMetaData>) },
+        )
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        Box::pin(async move {
+            self.create(path, FileType::Directory).await?;
+            Ok(())
+        })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        Box::pin(async move {
+            let (parent, name) = self.parent(path).await?;
+            self.fs.remove_dir(parent, &name).await.map_err(dav_error)
+        })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        Box::pin(async move {
+            let (parent, name) = self.parent(path).await?;
+            self.fs.remove_file(parent, &name).await.map_err(dav_error)
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
+        Box::pin(async move {
+            let (parent, name) = self.parent(from).await?;
+            let (new_parent, new_name) = self.parent(to).await?;
+            self.fs
+                .rename(parent, &name, new_parent, &new_name)
+                .await
+                .map_err(dav_error)
+        })
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
+        Box::pin(async move {
+            let attr = self.attr(from).await?;
+            if attr.kind == FileType::Directory {
+                return Err(DavError::Forbidden);
+            }
+            let dest = self.truncated(to).await?;
+            self.copy_content(attr.ino, dest.ino).await
+        })
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
+        Box::pin(async move {
+            let attr = self.attr(path).await?;
+            self.fs
+                .set_attr(attr.ino, SetFileAttr::default().with_atime(tm))
+                .await
+                .map_err(dav_error)
+        })
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
+        Box::pin(async move {
+            let attr = self.attr(path).await?;
+            self.fs
+                .set_attr(attr.ino, SetFileAttr::default().with_mtime(tm))
+                .await
+                .map_err(dav_error)
+        })
+    }
+
+    fn get_quota(&self) -> FsFuture<(u64, Option<u64>)> {
+        Box::pin(future::ready(
+            self.fs
+                .quota()
+                .map(|quota| (quota.used, Some(quota.max_size)))
+                .ok_or(DavError::NotImplemented),
+        ))
+    }
+}
+
+/// Opened when it's first read or written, and released when it's dropped or flushed.
+#[derive(Debug)]
+struct WebDavFile {
+    fs: Arc<EncryptedFs>,
+    ino: u64,
+    write: bool,
+    handle: Option<u64>,
+    pos: u64,
+}
+
+impl WebDavFile {
+    async fn handle(&mut self) -> DavResult<u64> {
+        if let Some(handle) = self.handle {
+            return Ok(handle);
+        }
+        let handle = self
+            .fs
+            .open(self.ino, true, self.write)
+            .await
+            .map_err(dav_error)?;
+        self.handle = Some(handle);
+        Ok(handle)
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> DavResult<()> {
+        let handle = self.handle().await?;
+        while !buf.is_empty() {
+            let len = self
+                .fs
+                .write(self.ino, self.pos, buf, handle)
+                .await
+                .map_err(dav_error)?;
+            buf = &buf[len..];
+            self.pos += len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl DavFile for WebDavFile {
+    fn metadata(&mut self) -> FsFuture<Box<dyn DavMetaData>> {
+        Box::pin(async move {
+            let attr = self.fs.get_attr(self.ino).await.map_err(dav_error)?;
+            Ok(Box::new(WebDavMeta(attr)) as Box<dyn DavMetaData>)
+        })
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<()> {
+        Box::pin(async move {
+            while buf.has_remaining() {
+                let chunk = buf.chunk().to_vec();
+                self.write_all(&chunk).await?;
+                buf.advance(chunk.len());
+            }
+            Ok(())
+        })
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
+        Box::pin(async move { self.write_all(&buf).await })
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+        Box::pin(async move {
+            let handle = self.handle().await?;
+            let mut buf = vec![0; count];
+            let mut read = 0;
+            while read < count {
+                let len = self
+                    .fs
+                    .read(self.ino, self.pos, &mut buf[read..], handle)
+                    .await
+                    .map_err(dav_error)?;
+                if len == 0 {
+                    break;
+                }
+                read += len;
+                self.pos += len as u64;
+            }
+            buf.truncate(read);
+            Ok(Bytes::from(buf))
+        })
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+        Box::pin(async move {
+            let (from, offset) = match pos {
+                SeekFrom::Start(offset) => {
+                    self.pos = offset;
+                    return Ok(offset);
+                }
+                SeekFrom::Current(offset) => (self.pos, offset),
+                SeekFrom::End(offset) => (
+                    self.fs.get_attr(self.ino).await.map_err(dav_error)?.size,
+                    offset,
+                ),
+            };
+            self.pos = from
+                .checked_add_signed(offset)
+                .ok_or(DavError::GeneralFailure)?;
+            Ok(self.pos)
+        })
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        Box::pin(async move {
+            // so the content is saved before we reply, and the next request can open it for write
+            if let Some(handle) = self.handle.take_if(|_| self.write) {
+                self.fs.release(handle).await.map_err(dav_error)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for WebDavFile {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let fs = self.fs.clone();
+            tokio::spawn(async move {
+                if let Err(err) = fs.release(handle).await {
+                    error!(err = %err, "releasing file");
+                }
+            });
+        }
+    }
+}
+
+struct WebDavEntry {
+    name: String,
+    attr: FileAttr,
+}
+
+impl DavDirEntry for WebDavEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<Box<dyn DavMetaData>> {
+        Box::pin(future::ready(Ok(
+            Box::new(WebDavMeta(self.attr)) as Box<dyn DavMetaData>
+        )))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WebDavMeta(FileAttr);
+
+impl DavMetaData for WebDavMeta {
+    fn len(&self) -> u64 {
+        self.0.size
+    }
+
+    fn modified(&self) -> DavResult<SystemTime> {
+        Ok(self.0.mtime)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.0.kind == FileType::Directory
+    }
+
+    fn accessed(&self) -> DavResult<SystemTime> {
+        Ok(self.0.atime)
+    }
+
+    fn created(&self) -> DavResult<SystemTime> {
+        Ok(self.0.crtime)
+    }
+
+    fn status_changed(&self) -> DavResult<SystemTime> {
+        Ok(self.0.ctime)
+    }
+
+    fn executable(&self) -> DavResult<bool> {
+        Ok(self.0.perm & 0o100 != 0)
+    }
+}
+
+/// Relative to root, decoded.
+fn path_str(path: &DavPath) -> DavResult<&str> {
+    path.as_rel_ospath().to_str().ok_or(DavError::NotFound)
+}
+
+/// Owned by who serves it, with the usual permissions.
+fn create_attr(kind: FileType) -> CreateFileAttr {
+    CreateFileAttr {
+        kind,
+        perm: if kind == FileType::Directory {
+            0o755
+        } else {
+            0o644
+        },
+        uid: *crate::UID,
+        gid: *crate::GID,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+fn dav_error(err: FsError) -> DavError {
+    match err {
+        FsError::NotFound(_) | FsError::InodeNotFound => DavError::NotFound,
+        FsError::AlreadyExists | FsError::NotEmpty => DavError::Exists,
+        FsError::ReadOnly | FsError::Excluded | FsError::InvalidInodeType => DavError::Forbidden,
+        FsError::QuotaExceeded(_) => DavError::InsufficientStorage,
+        FsError::MaxFilesizeExceeded(_) => DavError::TooLarge,
+        err => {
+            error!(err = %err);
+            DavError::GeneralFailure
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dav_server::fs::{DavFileSystem, OpenOptions, ReadDirMeta};
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::test_common::{get_fs, run_test, TestSetup};
+
+    fn path(path: &str) -> DavPath {
+        DavPath::new(path).unwrap()
+    }
+
+    async fn names(dav: &WebDavFs, dir: &str) -> Vec<String> {
+        let mut names: Vec<String> = dav
+            .read_dir(&path(dir), ReadDirMeta::Data)
+            .await
+            .unwrap()
+            .map(|entry| String::from_utf8(entry.unwrap().name()).unwrap())
+            .collect()
+            .await;
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_webdav() {
+        run_test(TestSetup { key: "test_webdav" }, async {
+            let dav = WebDavFs { fs: get_fs().await };
+
+            dav.create_dir(&path("/docs/")).await.unwrap();
+            assert_eq!(
+                dav.create_dir(&path("/docs/")).await.unwrap_err(),
+                DavError::Exists
+            );
+            let mut file = dav
+                .open(
+                    &path("/docs/a.txt"),
+                    OpenOptions {
+                        write: true,
+                        create: true,
+                        truncate: true,
+                        ..OpenOptions::default()
+                    },
+                )
+                .await
+                .unwrap();
+            file.write_bytes(Bytes::from("hello ")).await.unwrap();
+            file.write_buf(Box::new(Bytes::from("world")))
+                .await
+                .unwrap();
+            file.flush().await.unwrap();
+            drop(file);
+            assert_eq!(names(&dav, "/docs/").await, vec!["a.txt"]);
+            let meta = dav.metadata(&path("/docs/a.txt")).await.unwrap();
+            assert_eq!(meta.len(), 11);
+            assert!(!meta.is_dir());
+
+            let mut file = dav
+                .open(
+                    &path("/docs/a.txt"),
+                    OpenOptions {
+                        read: true,
+                        ..OpenOptions::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert_eq!(file.seek(SeekFrom::End(-5)).await.unwrap(), 6);
+            assert_eq!(file.read_bytes(100).await.unwrap(), Bytes::from("world"));
+            drop(file);
+
+            dav.copy(&path("/docs/a.txt"), &path("/b.txt"))
+                .await
+                .unwrap();
+            dav.rename(&path("/docs/a.txt"), &path("/docs/c.txt"))
+                .await
+                .unwrap();
+            assert_eq!(names(&dav, "/docs/").await, vec!["c.txt"]);
+            assert_eq!(dav.metadata(&path("/b.txt")).await.unwrap().len(), 11);
+            assert_eq!(
+                dav.remove_dir(&path("/docs/")).await.unwrap_err(),
+                DavError::Exists
+            );
+            dav.remove_file(&path("/docs/c.txt")).await.unwrap();
+            dav.remove_dir(&path("/docs/")).await.unwrap();
+            assert_eq!(names(&dav, "/").await, vec!["b.txt"]);
+            assert_eq!(
+                dav.metadata(&path("/docs/")).await.unwrap_err(),
+                DavError::NotFound
+            );
+        })
+        .await;
+    }
+}
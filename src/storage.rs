@@ -0,0 +1,156 @@
+use std::fmt::{self, Debug, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::fs_util;
+
+mod s3;
+mod sftp;
+
+#[allow(clippy::module_name_repetitions)]
+pub use s3::S3Storage;
+#[allow(clippy::module_name_repetitions)]
+pub use sftp::SftpStorage;
+
+/// Where the objects of a data dir are kept, so backends other than a local directory can be plugged in, see
+/// [`FsOptions::storage`](crate::encryptedfs::FsOptions::storage). Objects are already encrypted, the storage
+/// never sees plaintext or keys.
+///
+/// Keys are paths relative to the data dir, with `/` between names.
+pub trait Storage: Send + Sync {
+    /// Write the content of the object to `w`. Fails with [`io::ErrorKind::NotFound`] if there is none.
+    fn get(&self, key: &str, w: &mut (dyn Write + Send)) -> io::Result<()>;
+
+    /// Create the object or replace it, atomically, readers get either the old or the new content. Returns the
+    /// version it has now.
+    fn put(&self, key: &str, r: &mut dyn Read) -> io::Result<String>;
+
+    /// Succeeds if there is no such object.
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// All objects with keys starting with `prefix`, in no particular order.
+    fn list(&self, prefix: &str) -> io::Result<Vec<Object>>;
+}
+
+impl Debug for dyn Storage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("Storage")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    /// Changes each time the object is replaced.
+    pub version: String,
+}
+
+/// Open the storage at `location`, a path of a local directory, `file://PATH`, `s3://BUCKET/PREFIX` or
+/// `sftp://[USER@]HOST[:PORT]/PATH`, see [`S3Storage`] and [`SftpStorage`].
+#[allow(clippy::missing_errors_doc)]
+pub fn open(location: &str) -> io::Result<Arc<dyn Storage>> {
+    if let Some(location) = location.strip_prefix("s3://") {
+        return Ok(Arc::new(S3Storage::new(location)?));
+    }
+    if let Some(location) = location.strip_prefix("sftp://") {
+        return Ok(Arc::new(SftpStorage::new(location)?));
+    }
+    let path = location.strip_prefix("file://").unwrap_or(location);
+    Ok(Arc::new(LocalStorage::new(PathBuf::from(path))?))
+}
+
+/// Objects are files under a directory, like a network mount or a disk that's synced elsewhere.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    /// The directory is created if it doesn't exist.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        if key.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid key"));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get(&self, key: &str, w: &mut (dyn Write + Send)) -> io::Result<()> {
+        io::copy(&mut File::open(self.path(key)?)?, w)?;
+        Ok(())
+    }
+
+    fn put(&self, key: &str, r: &mut dyn Read) -> io::Result<String> {
+        let path = self.path(key)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = fs_util::open_atomic_write(&path)?;
+        io::copy(r, &mut file)?;
+        file.commit()?;
+        Ok(local_version(&fs::metadata(path)?))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<Object>> {
+        let mut objects = vec![];
+        let mut stack = vec![(self.root.clone(), String::new())];
+        while let Some((dir, key)) = stack.pop() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                // temp files of atomic writes
+                if name.starts_with('.') {
+                    continue;
+                }
+                let key = if key.is_empty() {
+                    name
+                } else {
+                    format!("{key}/{name}")
+                };
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    stack.push((entry.path(), key));
+                } else if key.starts_with(prefix) {
+                    objects.push(Object {
+                        key,
+                        size: metadata.len(),
+                        version: local_version(&metadata),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
+
+fn local_version(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}-{modified}", metadata.len())
+}
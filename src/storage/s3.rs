@@ -0,0 +1,233 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use tracing::{debug, warn};
+
+use crate::storage::{Object, Storage};
+
+/// Objects smaller than this are put with a single request, bigger ones in parts of this size. S3 needs parts of at
+/// least 5 MiB, except the last one.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+/// For each request, before giving up.
+const ATTEMPTS: u32 = 5;
+/// Doubled after each failed attempt.
+const BACKOFF: Duration = Duration::from_millis(200);
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Objects are in a bucket of S3 or of a service compatible with it, under a prefix.
+///
+/// Credentials are taken from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, else from the
+/// `AWS_PROFILE` profile, or the default one, in `~/.aws/credentials`. The region is `AWS_REGION`, for other
+/// services set `AWS_ENDPOINT` too.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct S3Storage {
+    bucket: Bucket,
+    /// Empty or ending with `/`.
+    prefix: String,
+}
+
+impl S3Storage {
+    /// `location` is `BUCKET` or `BUCKET/PREFIX`.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new(location: &str) -> io::Result<Self> {
+        let (name, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bucket is missing",
+            ));
+        }
+        let prefix = prefix.trim_matches('/');
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{prefix}/")
+        };
+        let region = Region::from_default_env().map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("AWS_REGION: {err}"))
+        })?;
+        // services other than S3 usually don't have a host for each bucket
+        let path_style = matches!(region, Region::Custom { .. });
+        let credentials = Credentials::from_env()
+            .or_else(|_| Credentials::from_profile(std::env::var("AWS_PROFILE").ok().as_deref()))
+            .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, err.to_string()))?;
+        let mut bucket = Bucket::new(name, region, credentials).map_err(to_io_error)?;
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Self { bucket, prefix })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    fn put_parts(&self, path: &str, first: Vec<u8>, r: &mut dyn Read) -> io::Result<()> {
+        let upload = retry(path, || {
+            self.bucket.initiate_multipart_upload(path, CONTENT_TYPE)
+        })?;
+        let res = (|| {
+            let mut parts = vec![];
+            let mut chunk = first;
+            for part_number in 1.. {
+                let part = retry(path, || {
+                    self.bucket.put_multipart_chunk(
+                        chunk.clone(),
+                        path,
+                        part_number,
+                        &upload.upload_id,
+                        CONTENT_TYPE,
+                    )
+                })?;
+                parts.push(part);
+                chunk = read_part(r)?;
+                if chunk.is_empty() {
+                    break;
+                }
+            }
+            retry(path, || {
+                self.bucket
+                    .complete_multipart_upload(path, &upload.upload_id, parts.clone())
+            })
+        })();
+        if let Err(err) = res {
+            // else the parts are kept, and paid for
+            if let Err(err) = self.bucket.abort_upload(path, &upload.upload_id) {
+                warn!(path, err = %err, "aborting upload");
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+impl Storage for S3Storage {
+    fn get(&self, key: &str, w: &mut (dyn Write + Send)) -> io::Result<()> {
+        let path = self.path(key);
+        let mut w = CountingWriter { inner: w, count: 0 };
+        retry_while(
+            &path,
+            |w| self.bucket.get_object_to_writer(&path, w),
+            // what was written can't be taken back
+            |w| w.count == 0,
+            &mut w,
+        )?;
+        Ok(())
+    }
+
+    fn put(&self, key: &str, r: &mut dyn Read) -> io::Result<String> {
+        let path = self.path(key);
+        let first = read_part(r)?;
+        if first.len() < PART_SIZE {
+            retry(&path, || self.bucket.put_object(&path, &first))?;
+        } else {
+            self.put_parts(&path, first, r)?;
+        }
+        // as it's listed, the ETag is not always there and HEAD formats times differently
+        self.list(key)?
+            .into_iter()
+            .find(|object| object.key == key)
+            .map(|object| object.version)
+            .ok_or_else(|| io::Error::other("object not listed after put"))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        match retry(&path, || self.bucket.delete_object(&path)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<Object>> {
+        let path = self.path(prefix);
+        let pages = retry(&path, || self.bucket.list(path.clone(), None))?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| {
+                Some(Object {
+                    key: object.key.strip_prefix(&self.prefix)?.to_string(),
+                    size: object.size,
+                    version: object.e_tag.unwrap_or(object.last_modified),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Up to [`PART_SIZE`] bytes, less only at the end.
+fn read_part(r: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    r.take(PART_SIZE as u64).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+fn retry<T>(path: &str, mut f: impl FnMut() -> Result<T, S3Error>) -> io::Result<T> {
+    retry_while(path, |()| f(), |()| true, &mut ())
+}
+
+/// Try again after errors that may go away, like the service being busy or the connection being lost, as long as
+/// `can_retry` allows it.
+fn retry_while<T, S>(
+    path: &str,
+    mut f: impl FnMut(&mut S) -> Result<T, S3Error>,
+    can_retry: impl Fn(&S) -> bool,
+    state: &mut S,
+) -> io::Result<T> {
+    let mut backoff = BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match f(state) {
+            Ok(res) => return Ok(res),
+            Err(err) if attempt < ATTEMPTS && is_transient(&err) && can_retry(state) => {
+                debug!(path, attempt, err = %err, "retrying request");
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(to_io_error(err)),
+        }
+    }
+}
+
+fn is_transient(err: &S3Error) -> bool {
+    match err {
+        S3Error::HttpFailWithBody(status, _) => *status >= 500 || *status == 429,
+        S3Error::Io(_) | S3Error::Atto(_) => true,
+        _ => false,
+    }
+}
+
+fn to_io_error(err: S3Error) -> io::Error {
+    match err {
+        S3Error::HttpFailWithBody(404, _) => io::Error::new(io::ErrorKind::NotFound, err),
+        S3Error::HttpFailWithBody(401 | 403, _) => {
+            io::Error::new(io::ErrorKind::PermissionDenied, err)
+        }
+        S3Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut (dyn Write + Send),
+    count: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use argon2::password_hash::rand_core::RngCore;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, RenameFlags, Session, Sftp};
+use tracing::debug;
+
+use crate::crypto;
+use crate::storage::{Object, Storage};
+
+const DEFAULT_PORT: u16 = 22;
+/// Keys tried after the agent, in `~/.ssh`.
+const IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+/// Of each request.
+const TIMEOUT_MS: u32 = 30_000;
+/// Connections kept open when not used.
+const MAX_IDLE: usize = 4;
+/// Writes are sent in parts this big, not as they come.
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Objects are files under a directory of a remote host, reached over SFTP, which only ever sees encrypted files.
+///
+/// The host must be in `~/.ssh/known_hosts`. It authenticates with the SSH agent, else with the keys without
+/// passphrase in `~/.ssh`. Connections are reused, and writes are buffered locally and sent in big parts.
+#[allow(clippy::module_name_repetitions)]
+pub struct SftpStorage {
+    user: String,
+    host: String,
+    port: u16,
+    root: PathBuf,
+    idle: Mutex<Vec<Sftp>>,
+    /// Created already, so they're not checked again.
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl fmt::Debug for SftpStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpStorage")
+            .field("user", &self.user)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SftpStorage {
+    /// `location` is `[USER@]HOST[:PORT]/PATH`, the user is the local one if not given. One connection is opened
+    /// to check it can be reached.
+    #[allow(clippy::missing_errors_doc)]
+    pub fn new(location: &str) -> io::Result<Self> {
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let (user, host) = match authority.split_once('@') {
+            Some((user, host)) => (user.to_string(), host),
+            None => (
+                std::env::var("USER").map_err(|_| invalid_input("user is missing"))?,
+                authority,
+            ),
+        };
+        let (host, port) = match host.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse().map_err(|_| invalid_input("invalid port"))?,
+            ),
+            None => (host, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(invalid_input("host is missing"));
+        }
+        let mut storage = Self {
+            user,
+            host: host.to_string(),
+            port,
+            root: Path::new("/").join(path),
+            idle: Mutex::new(vec![]),
+            dirs: Mutex::new(HashSet::new()),
+        };
+        let sftp = storage.connect()?;
+        storage.create_dir_all(&sftp, &storage.root)?;
+        storage.idle = Mutex::new(vec![sftp]);
+        Ok(storage)
+    }
+
+    fn connect(&self) -> io::Result<Sftp> {
+        debug!(host = self.host, port = self.port, "connecting");
+        let mut session = Session::new()?;
+        session.set_tcp_stream(TcpStream::connect((self.host.as_str(), self.port))?);
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake()?;
+        self.check_host_key(&session)?;
+        if session.userauth_agent(&self.user).is_err() {
+            let ssh_dir = home_dir()?.join(".ssh");
+            for identity in IDENTITIES {
+                let key = ssh_dir.join(identity);
+                if key.is_file()
+                    && session
+                        .userauth_pubkey_file(&self.user, None, &key, None)
+                        .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        if !session.authenticated() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "authentication failed",
+            ));
+        }
+        Ok(session.sftp()?)
+    }
+
+    fn check_host_key(&self, session: &Session) -> io::Result<()> {
+        let mut known_hosts = session.known_hosts()?;
+        let path = home_dir()?.join(".ssh").join("known_hosts");
+        if path.is_file() {
+            known_hosts.read_file(&path, KnownHostFileKind::OpenSSH)?;
+        }
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| io::Error::other("host has no key"))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "host key changed",
+            )),
+            CheckResult::NotFound | CheckResult::Failure => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "host is not in known_hosts",
+            )),
+        }
+    }
+
+    /// Run `f` with an idle connection, or a new one. It's kept for later only if it didn't fail, else it could
+    /// be broken.
+    fn with_connection<T>(&self, f: impl FnOnce(&Sftp) -> io::Result<T>) -> io::Result<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let sftp = match idle {
+            Some(sftp) => sftp,
+            None => self.connect()?,
+        };
+        let res = f(&sftp);
+        let usable = match &res {
+            Ok(_) => true,
+            Err(err) => err.kind() == io::ErrorKind::NotFound,
+        };
+        if usable {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(sftp);
+            }
+        }
+        res
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty()
+            || key
+                .split('/')
+                .any(|name| name.is_empty() || name == "." || name == "..")
+        {
+            return Err(invalid_input("invalid key"));
+        }
+        Ok(self.root.join(key))
+    }
+
+    fn create_dir_all(&self, sftp: &Sftp, dir: &Path) -> io::Result<()> {
+        if self.dirs.lock().unwrap().contains(dir) {
+            return Ok(());
+        }
+        if sftp.stat(dir).is_err() {
+            if let Some(parent) = dir.parent() {
+                self.create_dir_all(sftp, parent)?;
+            }
+            if let Err(err) = sftp.mkdir(dir, 0o700) {
+                // created meanwhile
+                if sftp.stat(dir).is_err() {
+                    return Err(err.into());
+                }
+            }
+        }
+        self.dirs.lock().unwrap().insert(dir.to_path_buf());
+        Ok(())
+    }
+}
+
+impl Storage for SftpStorage {
+    fn get(&self, key: &str, w: &mut (dyn Write + Send)) -> io::Result<()> {
+        let path = self.path(key)?;
+        self.with_connection(|sftp| {
+            io::copy(&mut sftp.open(&path)?, w)?;
+            Ok(())
+        })
+    }
+
+    fn put(&self, key: &str, r: &mut dyn Read) -> io::Result<String> {
+        let path = self.path(key)?;
+        let name = path.file_name().unwrap().to_string_lossy();
+        // starts with `.` so it's not listed
+        let tmp = path.with_file_name(format!(".{name}.{:016x}", crypto::create_rng().next_u64()));
+        self.with_connection(|sftp| {
+            self.create_dir_all(sftp, path.parent().unwrap())?;
+            let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, sftp.create(&tmp)?);
+            if let Err(err) = io::copy(r, &mut file).and_then(|_| file.flush()) {
+                drop(file);
+                let _ = sftp.unlink(&tmp);
+                return Err(err);
+            }
+            drop(file);
+            let old = sftp.stat(&path).ok();
+            let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+            if sftp.rename(&tmp, &path, Some(flags)).is_err() {
+                // servers with SFTP version 3, like OpenSSH, don't replace files, there is a short time without it
+                let _ = sftp.unlink(&path);
+                sftp.rename(&tmp, &path, None)?;
+            }
+            let mut stat = sftp.stat(&path)?;
+            // times have seconds only, the version must change even if it was put in the same second
+            if old.is_some_and(|old| version(&old) == version(&stat)) {
+                let mtime = stat.mtime.unwrap_or_default() + 1;
+                sftp.setstat(
+                    &path,
+                    FileStat {
+                        size: None,
+                        uid: None,
+                        gid: None,
+                        perm: None,
+                        atime: Some(mtime),
+                        mtime: Some(mtime),
+                    },
+                )?;
+                stat = sftp.stat(&path)?;
+            }
+            Ok(version(&stat))
+        })
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+        match self.with_connection(|sftp| Ok(sftp.unlink(&path)?)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<Object>> {
+        self.with_connection(|sftp| {
+            let mut objects = vec![];
+            let mut stack = vec![(self.root.clone(), String::new())];
+            while let Some((dir, key)) = stack.pop() {
+                for (path, stat) in sftp.readdir(&dir)? {
+                    let name = path.file_name().unwrap().to_string_lossy().to_string();
+                    // temp files of puts
+                    if name.starts_with('.') {
+                        continue;
+                    }
+                    let key = if key.is_empty() {
+                        name
+                    } else {
+                        format!("{key}/{name}")
+                    };
+                    if stat.is_dir() {
+                        stack.push((path, key));
+                    } else if key.starts_with(prefix) {
+                        objects.push(Object {
+                            key,
+                            size: stat.size.unwrap_or_default(),
+                            version: version(&stat),
+                        });
+                    }
+                }
+            }
+            Ok(objects)
+        })
+    }
+}
+
+fn version(stat: &FileStat) -> String {
+    format!(
+        "{}-{}",
+        stat.size.unwrap_or_default(),
+        stat.mtime.unwrap_or_default()
+    )
+}
+
+fn home_dir() -> io::Result<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| io::Error::other("HOME is not set"))
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
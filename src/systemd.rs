@@ -0,0 +1,71 @@
+//! Tell systemd how we're doing when running as a `Type=notify` service, see `sd_notify(3)`. Nothing is sent when
+//! not started by systemd.
+
+use std::future::Future;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use std::{env, process};
+
+use tracing::warn;
+
+/// Send `state`, like `READY=1`, to the socket in `$NOTIFY_SOCKET`.
+pub(crate) fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let res = UnixDatagram::unbound().and_then(|socket| {
+        // abstract socket
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(err) = res {
+        warn!(err = %err, state, "cannot notify systemd");
+    }
+}
+
+/// We're ready, `--daemon` changes our pid so it's sent too, then systemd needs `NotifyAccess=all`.
+pub(crate) fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", process::id()));
+}
+
+/// Send `WATCHDOG=1` as long as `alive` says so, if systemd asks for it with `WatchdogSec=`. Returns right away if
+/// it doesn't.
+pub(crate) async fn watchdog<F: Future<Output = bool>>(mut alive: impl FnMut() -> F) {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    // with room for a slow check
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        if tokio::time::timeout(timeout / 2, alive())
+            .await
+            .unwrap_or(false)
+        {
+            notify("WATCHDOG=1");
+        } else {
+            warn!("not alive, skip notifying the watchdog");
+        }
+    }
+}
+
+/// From `$WATCHDOG_USEC`, if it's for us.
+fn watchdog_timeout() -> Option<Duration> {
+    let for_us = env::var("WATCHDOG_PID")
+        .map_or(Ok(true), |pid| {
+            pid.parse::<u32>().map(|pid| pid == process::id())
+        })
+        .unwrap_or(false);
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| for_us && *usec > 0)
+        .map(Duration::from_micros)
+}
@@ -5,13 +5,12 @@ use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
 use std::{fs, io};
 
-use secrecy::SecretString;
 use tempfile::NamedTempFile;
 use thread_local::ThreadLocal;
 use tokio::sync::Mutex;
 
-use crate::crypto::Cipher;
-use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, PasswordProvider};
+use crate::crypto::{Cipher, LockedString};
+use crate::encryptedfs::{CreateFileAttr, EncryptedFs, FileType, FsOptions, PasswordProvider};
 
 #[allow(dead_code)]
 pub static TESTS_DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
@@ -53,12 +52,12 @@ pub struct SetupResult {
 #[allow(dead_code)]
 struct PasswordProviderImpl {}
 impl PasswordProvider for PasswordProviderImpl {
-    fn get_password(&self) -> Option<SecretString> {
-        Some(SecretString::from_str("password").unwrap())
+    fn get_password(&self) -> Option<LockedString> {
+        Some(LockedString::from_str("password").unwrap())
     }
 }
 #[allow(dead_code)]
-async fn setup(setup: TestSetup) -> SetupResult {
+async fn setup(setup: TestSetup, options: FsOptions) -> SetupResult {
     let path = TESTS_DATA_DIR.join(setup.key);
     let data_dir_str = path.to_str().unwrap();
     let _ = fs::remove_dir_all(data_dir_str);
@@ -68,6 +67,7 @@ async fn setup(setup: TestSetup) -> SetupResult {
         Path::new(data_dir_str).to_path_buf(),
         Box::new(PasswordProviderImpl {}),
         Cipher::ChaCha20Poly1305,
+        options.with_create(true),
     )
     .await
     .unwrap();
@@ -92,13 +92,22 @@ async fn teardown() -> Result<(), io::Error> {
 #[allow(dead_code)]
 #[allow(clippy::future_not_send)]
 pub async fn run_test<T>(init: TestSetup, t: T)
+where
+    T: Future,
+{
+    run_test_with_options(init, FsOptions::default(), t).await;
+}
+
+#[allow(dead_code)]
+#[allow(clippy::future_not_send)]
+pub async fn run_test_with_options<T>(init: TestSetup, options: FsOptions, t: T)
 where
     T: Future,
 {
     {
         let s = SETUP_RESULT.get_or(|| Mutex::new(None));
         let mut s = s.lock().await;
-        *s = Some(setup(init).await);
+        *s = Some(setup(init, options).await);
     }
     t.await;
     teardown().await.unwrap();
@@ -171,7 +180,7 @@ pub async fn read_exact(fs: &EncryptedFs, ino: u64, offset: u64, buf: &mut [u8],
 }
 
 #[allow(dead_code)]
-pub fn bench<F: Future + Send + Sync>(key: &'static str, worker_threads: usize, f: F) {
+pub fn bench<F: Future + Send>(key: &'static str, worker_threads: usize, f: F) {
     block_on(
         async {
             run_test(TestSetup { key }, f).await;
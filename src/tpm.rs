@@ -0,0 +1,126 @@
+//! Seal secrets to the TPM 2.0 of the machine, through the `tpm2-tools`.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand_core::RngCore;
+use secrecy::ExposeSecret;
+use tempfile::TempDir;
+
+use rencfs::crypto;
+use rencfs::crypto::LockedString;
+use rencfs::encryptedfs::KeySlotKind;
+
+/// Create a random secret and seal it to the TPM, optionally bound to the current values of `pcrs`,
+/// like `sha256:0,7`.
+pub(crate) fn seal(pcrs: Option<&str>) -> Result<(KeySlotKind, LockedString)> {
+    let mut secret = [0_u8; 32];
+    crypto::create_rng().fill_bytes(&mut secret);
+    let secret = LockedString::new(hex::encode(secret));
+
+    let dir = TempDir::new()?;
+    let dir = dir.path();
+    create_primary(dir)?;
+    let mut args = vec![
+        "-C",
+        "primary.ctx",
+        "-u",
+        "seal.pub",
+        "-r",
+        "seal.priv",
+        "-i",
+        "-",
+    ];
+    if let Some(pcrs) = pcrs {
+        run(
+            dir,
+            "tpm2_createpolicy",
+            &["--policy-pcr", "-l", pcrs, "-L", "policy.digest"],
+            None,
+        )?;
+        args.extend(["-L", "policy.digest"]);
+    }
+    run(dir, "tpm2_create", &args, Some(secret.expose_secret()))?;
+
+    let kind = KeySlotKind::Tpm {
+        pcrs: pcrs.map(ToString::to_string),
+        public: STANDARD.encode(fs::read(dir.join("seal.pub"))?),
+        private: STANDARD.encode(fs::read(dir.join("seal.priv"))?),
+    };
+    Ok((kind, secret))
+}
+
+/// Unseal the secret of the slot, it fails on another machine or if the PCRs changed.
+pub(crate) fn unseal(kind: &KeySlotKind) -> Result<LockedString> {
+    let KeySlotKind::Tpm {
+        pcrs,
+        public,
+        private,
+    } = kind
+    else {
+        return Err(anyhow!("not a TPM key slot"));
+    };
+    let dir = TempDir::new()?;
+    let dir = dir.path();
+    fs::write(dir.join("seal.pub"), STANDARD.decode(public)?)?;
+    fs::write(dir.join("seal.priv"), STANDARD.decode(private)?)?;
+    create_primary(dir)?;
+    run(
+        dir,
+        "tpm2_load",
+        &[
+            "-C",
+            "primary.ctx",
+            "-u",
+            "seal.pub",
+            "-r",
+            "seal.priv",
+            "-c",
+            "seal.ctx",
+        ],
+        None,
+    )?;
+    let auth = pcrs.as_ref().map(|pcrs| format!("pcr:{pcrs}"));
+    let mut args = vec!["-c", "seal.ctx"];
+    if let Some(auth) = &auth {
+        args.extend(["-p", auth]);
+    }
+    let secret = run(dir, "tpm2_unseal", &args, None)?;
+    Ok(LockedString::new(secret.trim().to_string()))
+}
+
+/// The primary key is derived by the TPM from its seed, so it's the same each time we create it.
+fn create_primary(dir: &Path) -> Result<()> {
+    run(
+        dir,
+        "tpm2_createprimary",
+        &["-C", "o", "-c", "primary.ctx"],
+        None,
+    )?;
+    Ok(())
+}
+
+fn run(dir: &Path, program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new(program)
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| anyhow!("cannot run {program}, is tpm2-tools installed? {err}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(input) = input {
+        stdin.write_all(input.as_bytes())?;
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{program} failed with {}", output.status));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}